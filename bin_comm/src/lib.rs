@@ -5,3 +5,5 @@
 
 /// Utilities for framing data in a stream.
 pub mod stream_utils;
+/// Replay protection for authenticated links.
+pub mod replay;
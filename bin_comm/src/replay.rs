@@ -0,0 +1,342 @@
+//! Replay protection for authenticated links.
+//!
+//! Every frame on an authenticated link is wrapped in an [Envelope] that
+//! carries the session nonce agreed during the handshake, a per-direction
+//! monotonic counter, and an HMAC-SHA256 over both and the frame, keyed with
+//! the session key of its direction.  The receiving side checks the MAC
+//! first, so neither the counter nor the frame can be changed on the way,
+//! then tracks the counters it has seen in a [ReplayWindow], so a captured
+//! frame can't be replayed later, either in the same session (duplicate
+//! counter) or in a new one (wrong nonce or key).
+//!
+//! No wall clock is involved, so the protection is unaffected by clock skew
+//! between the gateway and the leaf.
+
+use hmac::{Hmac, Mac as _};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+/// An HMAC-SHA256.
+pub type Mac = [u8; 32];
+
+/// Number of counters behind the highest seen counter that are still
+/// accepted.  Frames older than this are rejected even if they were never seen.
+pub const WINDOW_SIZE: u64 = 64;
+
+/// A frame wrapped with the counters used for replay protection.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    /// The nonce agreed during the handshake, binding the frame to one session.
+    pub session_nonce: u64,
+    /// Monotonic counter of the frame within the session.
+    pub counter: u64,
+    /// The wrapped frame.
+    pub payload: Vec<u8>,
+    /// HMAC of the session nonce, counter and frame.
+    pub mac: Mac,
+}
+
+/// Reasons a frame is rejected by the replay protection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplayError {
+    /// The frame belongs to a different session.
+    WrongSession {
+        /// The nonce of the current session
+        expected: u64,
+        /// The nonce carried by the frame
+        actual: u64,
+    },
+    /// The MAC doesn't match, so the frame wasn't sealed with the key of
+    /// the session or was changed on the way.
+    Forged(u64),
+    /// The counter has already been seen in this session.
+    Duplicate(u64),
+    /// The counter is too far behind the window to be checked.
+    TooOld(u64),
+    /// The sending side ran out of counters.  The session must be renegotiated.
+    Exhausted,
+}
+
+impl std::fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplayError::WrongSession { expected, actual } => write!(
+                f,
+                "frame for session {actual:#x} received on session {expected:#x}"
+            ),
+            ReplayError::Forged(counter) => write!(f, "frame {counter} failed its MAC"),
+            ReplayError::Duplicate(counter) => write!(f, "frame {counter} replayed"),
+            ReplayError::TooOld(counter) => write!(f, "frame {counter} is outside the replay window"),
+            ReplayError::Exhausted => write!(f, "frame counter exhausted"),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+/// Sliding window over the counters received in a session.
+///
+/// Counters may arrive slightly out of order, so rather than requiring each
+/// counter to be larger than the last, the window remembers which of the last
+/// [WINDOW_SIZE] counters have been seen.
+#[derive(Debug, Default, Clone)]
+pub struct ReplayWindow {
+    /// Highest counter accepted so far, None until the first frame.
+    highest: Option<u64>,
+    /// Bit n is set if counter `highest - n` has been accepted.
+    seen: u64,
+}
+
+impl ReplayWindow {
+    /// Create an empty window.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check a counter against the window and mark it as seen if it is new.
+    pub fn check(&mut self, counter: u64) -> Result<(), ReplayError> {
+        let highest = match self.highest {
+            None => {
+                self.highest = Some(counter);
+                self.seen = 1;
+                return Ok(());
+            }
+            Some(highest) => highest,
+        };
+
+        if counter > highest {
+            let shift = counter - highest;
+            self.seen = if shift >= WINDOW_SIZE {
+                1
+            } else {
+                (self.seen << shift) | 1
+            };
+            self.highest = Some(counter);
+            return Ok(());
+        }
+
+        let offset = highest - counter;
+        if offset >= WINDOW_SIZE {
+            return Err(ReplayError::TooOld(counter));
+        }
+        let bit = 1u64 << offset;
+        if self.seen & bit != 0 {
+            return Err(ReplayError::Duplicate(counter));
+        }
+        self.seen |= bit;
+        Ok(())
+    }
+}
+
+/// An HMAC keyed with key, fed the session nonce and counter of a frame.
+fn keyed(key: &[u8], session_nonce: u64, counter: u64) -> Hmac<Sha256> {
+    // HMAC takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes any key");
+    mac.update(&session_nonce.to_be_bytes());
+    mac.update(&counter.to_be_bytes());
+    mac
+}
+
+/// Wraps outgoing frames in envelopes with an increasing counter.
+#[derive(Clone)]
+pub struct Sealer {
+    session_nonce: u64,
+    key: Mac,
+    next_counter: u64,
+}
+
+/// The key stays out of logs.
+impl std::fmt::Debug for Sealer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sealer")
+            .field("session_nonce", &self.session_nonce)
+            .field("next_counter", &self.next_counter)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Sealer {
+    /// Create a sealer for the session agreed during the handshake, with the
+    /// session key of the direction it seals.
+    pub fn new(session_nonce: u64, key: Mac) -> Self {
+        Self {
+            session_nonce,
+            key,
+            next_counter: 0,
+        }
+    }
+
+    /// A sealer for another stream of the same session, for links whose
+    /// streams keep no order between them.  Each stream has a session nonce
+    /// of its own, so a frame can't be moved from one stream to another.
+    pub fn for_stream(&self, stream: u64) -> Self {
+        Self::new(self.session_nonce.wrapping_add(stream), self.key)
+    }
+
+    /// Wrap a frame in an envelope, consuming the next counter.
+    pub fn seal(&mut self, payload: Vec<u8>) -> Result<Envelope, ReplayError> {
+        let counter = self.next_counter;
+        self.next_counter = counter.checked_add(1).ok_or(ReplayError::Exhausted)?;
+        let mut mac = keyed(&self.key, self.session_nonce, counter);
+        mac.update(&payload);
+        Ok(Envelope {
+            session_nonce: self.session_nonce,
+            counter,
+            payload,
+            mac: mac.finalize().into_bytes().into(),
+        })
+    }
+}
+
+/// Unwraps incoming envelopes, rejecting forged and replayed frames.
+#[derive(Clone)]
+pub struct Opener {
+    session_nonce: u64,
+    key: Mac,
+    window: ReplayWindow,
+}
+
+/// The key stays out of logs.
+impl std::fmt::Debug for Opener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Opener")
+            .field("session_nonce", &self.session_nonce)
+            .field("window", &self.window)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Opener {
+    /// Create an opener for the session agreed during the handshake, with
+    /// the session key of the direction it opens.
+    pub fn new(session_nonce: u64, key: Mac) -> Self {
+        Self {
+            session_nonce,
+            key,
+            window: ReplayWindow::new(),
+        }
+    }
+
+    /// An opener for the frames [Sealer::for_stream] seals for stream.
+    pub fn for_stream(&self, stream: u64) -> Self {
+        Self::new(self.session_nonce.wrapping_add(stream), self.key)
+    }
+
+    /// Validate an envelope and return the frame inside it.  The MAC is
+    /// checked, in constant time, before the counter is taken as seen, so a
+    /// forged envelope can't use up the counter of one still to come.
+    pub fn open(&mut self, envelope: Envelope) -> Result<Vec<u8>, ReplayError> {
+        if envelope.session_nonce != self.session_nonce {
+            return Err(ReplayError::WrongSession {
+                expected: self.session_nonce,
+                actual: envelope.session_nonce,
+            });
+        }
+        let mut mac = keyed(&self.key, envelope.session_nonce, envelope.counter);
+        mac.update(&envelope.payload);
+        mac.verify_slice(&envelope.mac)
+            .map_err(|_| ReplayError::Forged(envelope.counter))?;
+        self.window.check(envelope.counter)?;
+        Ok(envelope.payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_order_counters_accepted() {
+        let mut window = ReplayWindow::new();
+        for counter in 0..200 {
+            assert_eq!(window.check(counter), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_duplicate_rejected() {
+        let mut window = ReplayWindow::new();
+        window.check(5).unwrap();
+        window.check(6).unwrap();
+        assert_eq!(window.check(5), Err(ReplayError::Duplicate(5)));
+        assert_eq!(window.check(6), Err(ReplayError::Duplicate(6)));
+    }
+
+    #[test]
+    fn test_out_of_order_within_window() {
+        let mut window = ReplayWindow::new();
+        window.check(10).unwrap();
+        window.check(8).unwrap();
+        window.check(9).unwrap();
+        assert_eq!(window.check(8), Err(ReplayError::Duplicate(8)));
+    }
+
+    #[test]
+    fn test_too_old_rejected() {
+        let mut window = ReplayWindow::new();
+        window.check(0).unwrap();
+        window.check(WINDOW_SIZE + 10).unwrap();
+        assert_eq!(window.check(1), Err(ReplayError::TooOld(1)));
+        // Just inside the window is still fine
+        assert_eq!(window.check(11), Ok(()));
+    }
+
+    const KEY: Mac = [7; 32];
+
+    #[test]
+    fn test_opener_rejects_other_session() {
+        let mut sealer = Sealer::new(1, KEY);
+        let mut opener = Opener::new(2, KEY);
+        let envelope = sealer.seal(b"frame".to_vec()).unwrap();
+        assert_eq!(
+            opener.open(envelope),
+            Err(ReplayError::WrongSession {
+                expected: 2,
+                actual: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_replayed_envelope_rejected() {
+        let mut sealer = Sealer::new(42, KEY);
+        let mut opener = Opener::new(42, KEY);
+        let envelope = sealer.seal(b"frame".to_vec()).unwrap();
+        assert_eq!(opener.open(envelope.clone()), Ok(b"frame".to_vec()));
+        assert_eq!(opener.open(envelope), Err(ReplayError::Duplicate(0)));
+    }
+
+    #[test]
+    fn test_forged_envelope_rejected() {
+        let mut sealer = Sealer::new(42, KEY);
+        let mut opener = Opener::new(42, KEY);
+        let envelope = sealer.seal(b"frame".to_vec()).unwrap();
+
+        // A replay moved to a counter not seen yet
+        let recounted = Envelope {
+            counter: 1,
+            ..envelope.clone()
+        };
+        assert_eq!(opener.open(recounted), Err(ReplayError::Forged(1)));
+        let mut changed = envelope.clone();
+        changed.payload[0] ^= 1;
+        assert_eq!(opener.open(changed), Err(ReplayError::Forged(0)));
+        // Sealed with the key of the other direction
+        let reflected = Sealer::new(42, [8; 32]).seal(b"frame".to_vec()).unwrap();
+        assert_eq!(opener.open(reflected), Err(ReplayError::Forged(0)));
+
+        // None of them used up the counter of the real frame
+        assert_eq!(opener.open(envelope), Ok(b"frame".to_vec()));
+    }
+
+    #[test]
+    fn test_streams_sealed_apart() {
+        let sealer = Sealer::new(42, KEY);
+        let opener = Opener::new(42, KEY);
+        let mut button = sealer.for_stream(1);
+        let envelope = button.seal(b"frame".to_vec()).unwrap();
+        assert!(opener.clone().open(envelope.clone()).is_err());
+        assert!(opener.for_stream(2).open(envelope.clone()).is_err());
+        assert_eq!(opener.for_stream(1).open(envelope), Ok(b"frame".to_vec()));
+    }
+}
@@ -1,61 +1,252 @@
+//! Utilities for framing data in a stream.
+//!
+//! Values are encoded with postcard, the codec the no_std leaves use, and
+//! framed with [leaf_comm::framing], so a frame written here is read by a
+//! leaf unchanged and the other way round.
+//!
+//! A stream read frame after frame is read through a [FrameReader], which
+//! decodes with the same [Decoder] as the leaves, so it resynchronizes after
+//! corruption just as they do.  [receive_length_prefix] reads a single frame
+//! without reading past it, for handshakes before a stream is handed on.
+//!
+//! A gateway reads leaves through [FrameReader::detecting], which tells the
+//! [Format] by the first byte, and writes back in the same format with
+//! [write_length_prefix_as], so leaves from before frames were checksummed
+//! keep working.
+
+use crate::replay::{Envelope, Opener, Sealer};
+use leaf_comm::framing::{
+    self, Decoder, Format, FrameError, CRC_LEN, HEADER_LEN, LENGTH_LEN, MAX_FRAME_LEN, PREAMBLE,
+};
 use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 
-/// Read a message from the stream, prefixed with a u32 length.
+/// Bytes a [FrameReader] asks the stream for at a time.
+const READ_LEN: usize = 8 * 1024;
+
+/// Reads frame after frame from a stream through a [Decoder].  A frame
+/// refused, as its checksum doesn't match or its length is damaged past
+/// [MAX_FRAME_LEN], fails the read, and the next read looks for a frame again
+/// from the byte after the preamble of the refused one.  The frames a damaged
+/// length took in are so found again rather than thrown away with it.
+#[derive(Debug)]
+pub struct FrameReader<R> {
+    stream: R,
+    decoder: Decoder,
+    /// Bytes read from the stream and not yet decoded, from decoded on
+    read: Vec<u8>,
+    decoded: usize,
+}
+
+impl<R> FrameReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    /// Read frames no longer than [MAX_FRAME_LEN] from stream.
+    pub fn new(stream: R) -> Self {
+        Self::from_decoder(stream, Decoder::new(MAX_FRAME_LEN))
+    }
+
+    /// Read frames no longer than [MAX_FRAME_LEN] from stream, in the
+    /// [Format] told by its first byte.
+    pub fn detecting(stream: R) -> Self {
+        Self::from_decoder(stream, Decoder::detecting(MAX_FRAME_LEN))
+    }
+
+    fn from_decoder(stream: R, decoder: Decoder) -> Self {
+        Self {
+            stream,
+            decoder,
+            read: Vec::new(),
+            decoded: 0,
+        }
+    }
+
+    /// The format frames are read in, unless nothing has been read yet to
+    /// tell it by.
+    pub fn format(&self) -> Option<Format> {
+        self.decoder.format()
+    }
+
+    /// Read the next frame into buf.  A refused frame fails with
+    /// [std::io::ErrorKind::InvalidData] carrying the [FrameError], as told
+    /// apart by [frame_error], after which reading can carry on.  Cancel
+    /// safe: bytes read are kept for the next call.
+    pub async fn receive(&mut self, mut buf: Vec<u8>) -> std::io::Result<Vec<u8>> {
+        loop {
+            let mut data = &self.read[self.decoded..];
+            let decoded = self.decoder.decode(&mut data);
+            self.decoded = self.read.len() - data.len();
+            match decoded {
+                Ok(Some(frame)) => {
+                    buf.clear();
+                    buf.extend_from_slice(frame);
+                    return Ok(buf);
+                }
+                // Every byte read is in the decoder
+                Ok(None) => {}
+                Err(e) => return Err(invalid(e)),
+            }
+            self.read.clear();
+            self.decoded = 0;
+            self.read.reserve(READ_LEN);
+            if self.stream.read_buf(&mut self.read).await? == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+
+    /// The stream frames are read from.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.stream
+    }
+}
+
+/// Read a message from the stream, framed by [leaf_comm::framing], without
+/// reading past it.  Bytes before the preamble of the frame are skipped.
+/// Messages longer than [MAX_FRAME_LEN], or whose checksum doesn't match,
+/// fail with [std::io::ErrorKind::InvalidData] carrying the [FrameError], as
+/// told apart by [frame_error].  The bytes of a refused frame are lost, so
+/// streams read frame after frame are better read with a [FrameReader].
+/// Only reads [Format::Checked] frames, as only ends that checksum frames
+/// take part in handshakes.
 pub async fn receive_length_prefix(
     stream: &mut (impl AsyncRead + Unpin),
     mut buf: Vec<u8>,
 ) -> std::io::Result<Vec<u8>> {
+    // Find the preamble
+    let mut preamble = [0u8; PREAMBLE.len()];
+    stream.read_exact(&mut preamble).await?;
+    while preamble != PREAMBLE {
+        preamble.rotate_left(1);
+        stream
+            .read_exact(&mut preamble[PREAMBLE.len() - 1..])
+            .await?;
+    }
+
     // Read the message length (u32)
-    let mut length_buffer = [0u8; 4];
+    let mut length_buffer = [0u8; LENGTH_LEN];
     stream.read_exact(&mut length_buffer).await?;
-    let length = u32::from_be_bytes(length_buffer);
+    let length = framing::decode_length(length_buffer, MAX_FRAME_LEN).map_err(invalid)?;
 
-    println!("length: {}", length);
-
-    // Read the actual message
-    buf.resize(length as usize, Default::default());
+    // Read the actual message, and check it against its checksum
+    buf.resize(length, Default::default());
     stream.read_exact(&mut buf).await?;
+    let mut trailer = [0u8; CRC_LEN];
+    stream.read_exact(&mut trailer).await?;
+    framing::verify(&buf, trailer).map_err(|e| invalid(e.into()))?;
 
     Ok(buf)
 }
 
-/// Serialize a serde value using bincode and write it to a stream
+/// Serialize a serde value using postcard and write it to a stream
 /// using a length prefix.
 pub async fn write_struct(
     stream: &mut (impl AsyncWrite + Unpin),
     data: &impl serde::Serialize,
 ) -> anyhow::Result<()> {
-    //let buf = bincode::serialize(data).unwrap();
     let buf = postcard::to_stdvec(data)?;
     Ok(write_length_prefix(stream, buf).await?)
 }
 
-/// Write a message to the stream, prefixed with a u32 length.
+/// Write a message to the stream, framed by [leaf_comm::framing].  Messages
+/// longer than [MAX_FRAME_LEN] fail with [std::io::ErrorKind::InvalidData].
 pub async fn write_length_prefix(
     stream: &mut (impl AsyncWrite + Unpin),
     buf: impl AsRef<[u8]>,
+) -> std::io::Result<()> {
+    write_length_prefix_as(stream, buf, Format::Checked).await
+}
+
+/// Write a message to the stream like [write_length_prefix], laid out in
+/// format, such as the one a leaf was read in.
+pub async fn write_length_prefix_as(
+    stream: &mut (impl AsyncWrite + Unpin),
+    buf: impl AsRef<[u8]>,
+    format: Format,
 ) -> std::io::Result<()> {
     let buf = buf.as_ref();
 
-    // Write the message length (u32)
-    let length = buf.len() as u32;
-    stream.write_all(&length.to_be_bytes()).await?;
+    // Write the preamble, if any, and message length (u32)
+    match format {
+        Format::Legacy => {
+            let length = framing::encode_length(buf.len(), MAX_FRAME_LEN).map_err(invalid)?;
+            stream.write_all(&length).await?;
+        }
+        Format::Checked => {
+            let header = framing::encode_header(buf.len(), MAX_FRAME_LEN).map_err(invalid)?;
+            stream.write_all(&header).await?;
+        }
+    }
 
-    // Write the actual message
+    // Write the actual message, and its checksum if any
     stream.write_all(buf).await?;
+    if format == Format::Checked {
+        stream.write_all(&framing::checksum([buf])).await?;
+    }
     stream.flush().await?;
     Ok(())
 }
 
+/// A frame that can't be framed, as an I/O error.
+fn invalid(e: FrameError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
+
+/// The framing error an I/O error from this module was made from, if any.
+pub fn frame_error(e: &std::io::Error) -> Option<&FrameError> {
+    e.get_ref()?.downcast_ref()
+}
+
 /// Read a struct from a stream that is prefixed with a u32 length deserialized
-/// using bincode and serde.
+/// using postcard and serde.
 pub async fn read_struct<T>(stream: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<T>
 where
     T: serde::de::DeserializeOwned,
 {
     let buf = receive_length_prefix(stream, Vec::new()).await?;
-    //let data = bincode::deserialize(&buf)?;
     let data = postcard::from_bytes(&buf)?;
     Ok(data)
 }
+
+/// Read a struct from a stream that is prefixed with a u32 length, borrowing
+/// from the frame instead of copying out of it.  The frame is read into buf,
+/// which keeps its allocation from one frame to the next when the caller
+/// holds on to it.
+pub async fn read_struct_ref<'a, T>(
+    stream: &mut (impl AsyncRead + Unpin),
+    buf: &'a mut Vec<u8>,
+) -> anyhow::Result<T>
+where
+    T: serde::Deserialize<'a>,
+{
+    *buf = receive_length_prefix(stream, std::mem::take(buf)).await?;
+    let data = postcard::from_bytes(buf)?;
+    Ok(data)
+}
+
+/// Wrap a frame in a replay protected envelope and write it to a stream.
+/// Returns the bytes written, envelope and framing included.
+pub async fn write_sealed(
+    stream: &mut (impl AsyncWrite + Unpin),
+    sealer: &mut Sealer,
+    frame: Vec<u8>,
+) -> anyhow::Result<usize> {
+    let envelope = postcard::to_stdvec(&sealer.seal(frame)?)?;
+    let len = HEADER_LEN + envelope.len() + CRC_LEN;
+    write_length_prefix(stream, envelope).await?;
+    Ok(len)
+}
+
+/// Read a replay protected envelope from frames, returning the frame inside
+/// it only if it was sealed for this session and hasn't been seen before.
+/// The envelope is read into buf.
+pub async fn read_sealed(
+    frames: &mut FrameReader<impl AsyncRead + Unpin>,
+    opener: &mut Opener,
+    buf: Vec<u8>,
+) -> anyhow::Result<Vec<u8>> {
+    let buf = frames.receive(buf).await?;
+    let envelope: Envelope = postcard::from_bytes(&buf)?;
+    Ok(opener.open(envelope)?)
+}
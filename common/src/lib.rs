@@ -5,8 +5,12 @@
 #![warn(missing_docs)]
 
 extern crate alloc;
+use alloc::borrow::{Cow, ToOwned};
 use alloc::str::FromStr;
 use alloc::string::String;
+use core::borrow::Borrow;
+use core::fmt;
+use core::hash::{Hash, Hasher};
 
 
 /// A string that can be either a String or a &str
@@ -32,6 +36,30 @@ impl From<String> for StringOrStr<'_> {
         Self::String(s)
     }
 }
+/// Convert from a Cow, borrowing what it borrows
+impl<'a> From<Cow<'a, str>> for StringOrStr<'a> {
+    fn from(s: Cow<'a, str>) -> Self {
+        match s {
+            Cow::Borrowed(s) => Self::Str(s),
+            Cow::Owned(s) => Self::String(s),
+        }
+    }
+}
+/// Convert into a Cow, borrowing what it borrows
+/// ```
+/// # use common::StringOrStr;
+/// # use std::borrow::Cow;
+/// let value = StringOrStr::Str("John");
+/// assert!(matches!(Cow::from(value), Cow::Borrowed("John")));
+/// ```
+impl<'a> From<StringOrStr<'a>> for Cow<'a, str> {
+    fn from(s: StringOrStr<'a>) -> Self {
+        match s {
+            StringOrStr::String(s) => Cow::Owned(s),
+            StringOrStr::Str(s) => Cow::Borrowed(s),
+        }
+    }
+}
 /// Get the underlying string reference
 impl AsRef<str> for StringOrStr<'_> {
     fn as_ref(&self) -> &str {
@@ -67,6 +95,73 @@ impl StringOrStr<'_> {
     {
         self.as_ref().parse()
     }
+
+    /// Copy a string reference into a string of its own, so it no longer
+    /// borrows what it was parsed from and can be kept or sent to another
+    /// task.  A String is moved, not copied.
+    /// ```
+    /// # use common::StringOrStr;
+    /// let line = String::from("DEVICEID=John");
+    /// let owned: StringOrStr<'static> = StringOrStr::Str(&line[9..]).into_owned();
+    /// drop(line);
+    /// assert_eq!(owned.as_str(), "John");
+    /// ```
+    pub fn into_owned(self) -> StringOrStr<'static> {
+        match self {
+            Self::String(s) => StringOrStr::String(s),
+            Self::Str(s) => StringOrStr::String(s.to_owned()),
+        }
+    }
+}
+
+/// Display the internal string
+impl fmt::Display for StringOrStr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+/// Borrow the internal string, so a map keyed by these can be looked up by
+/// string reference
+impl Borrow<str> for StringOrStr<'_> {
+    fn borrow(&self) -> &str {
+        self.as_ref()
+    }
+}
+
+/// Hash the internal string, the same as the string reference it borrows as
+impl Hash for StringOrStr<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+/// Serialize as a plain string
+impl serde::Serialize for StringOrStr<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// Deserialize from a plain string, always into a String as Cow does, so a
+/// value can be read from a buffer that doesn't outlive it
+/// ```
+/// # use common::StringOrStr;
+/// let value = StringOrStr::Str("John \"J\" Aughey");
+/// let json = serde_json::to_string(&value).unwrap();
+/// let read: StringOrStr<'static> = serde_json::from_str(&json).unwrap();
+/// assert_eq!(read, value);
+/// ```
+impl<'de> serde::Deserialize<'de> for StringOrStr<'_> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer).map(Self::String)
+    }
 }
 
 /// PartialEq compares the references because we
@@ -0,0 +1,39 @@
+//! Every line Companion could send, through the whole of [Command::parse_with]
+//! and what is done with the command it gives.  Failing to parse is fine,
+//! panicking isn't.  Values are also encoded and parsed back, which has to
+//! give the same value.
+
+#![no_main]
+
+use companion::{encode_value, Command, ValueEncoding};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(line) = std::str::from_utf8(data) else {
+        return;
+    };
+    for encoding in [ValueEncoding::Quoted, ValueEncoding::Percent] {
+        if let Ok(command) = Command::parse_with(line, encoding) {
+            let _ = command.blanks_device();
+            match command {
+                Command::KeyState(keystate) => {
+                    let _ = keystate.bitmap();
+                    let _ = keystate.rgb();
+                }
+                Command::Begin(versions) => {
+                    let _ = versions.negotiate();
+                    let _ = versions.value_encoding();
+                }
+                _ => {}
+            }
+        }
+        let _ = Command::parse_strict(line, encoding);
+
+        let encoded = encode_value(line, encoding);
+        let brightness = format!("BRIGHTNESS DEVICEID={encoded} VALUE=1");
+        match Command::parse_with(&brightness, encoding) {
+            Ok(Command::Brightness(brightness)) => assert_eq!(brightness.device.as_str(), line),
+            parsed => panic!("{brightness:?} parsed as {parsed:?}"),
+        }
+    }
+});
@@ -0,0 +1,323 @@
+//! Cache of converted images.
+//!
+//! Converting the bitmaps Companion sends into the format of the device is
+//! the most expensive thing the receiver does, and Companion sends the same
+//! images over and over as pages are switched.  The cache maps what Companion
+//! sent to the converted action, optionally expiring entries after a while so
+//! a long running gateway doesn't hold on to stale page art.
+//!
+//! An [ImageCache] is cheap to clone and every clone shares the same entries,
+//! so a gateway serving many leaf devices showing the same pages converts each
+//! image once.  It is bounded by the bytes it holds rather than by its number
+//! of entries, as a key image and a whole LCD strip differ a lot in size.
+//!
+//! With a [DiskCache] behind it, images are also kept across restarts.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use traits::device::DeviceActions;
+
+use crate::disk_cache::DiskCache;
+
+/// Bytes of images kept unless told otherwise.
+pub const DEFAULT_MAX_BYTES: usize = 16 * 1024 * 1024;
+
+/// Counters describing how well the cache is doing, shared with whoever
+/// reports on it.
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    hits: AtomicU64,
+    disk_hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    expirations: AtomicU64,
+    bytes: AtomicU64,
+}
+
+/// A point in time copy of [CacheStats].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CacheSnapshot {
+    /// Lookups answered from the cache
+    pub hits: u64,
+    /// Of the hits, those read back from disk
+    pub disk_hits: u64,
+    /// Lookups that needed a conversion
+    pub misses: u64,
+    /// Entries dropped to make room for new ones
+    pub evictions: u64,
+    /// Entries dropped because they were too old
+    pub expirations: u64,
+    /// Bytes held by the cache
+    pub bytes: u64,
+}
+
+impl CacheStats {
+    /// Take a point in time copy of the counters.
+    pub fn snapshot(&self) -> CacheSnapshot {
+        CacheSnapshot {
+            hits: self.hits.load(Ordering::Relaxed),
+            disk_hits: self.disk_hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            expirations: self.expirations.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    fn add(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The entries of a cache, in least recently used order.
+struct Entries {
+    lru: lru::LruCache<String, (Instant, DeviceActions)>,
+    bytes: usize,
+}
+
+impl Entries {
+    fn pop(&mut self, key: &str) -> bool {
+        match self.lru.pop_entry(key) {
+            Some((key, (_, action))) => {
+                self.bytes -= entry_len(&key, &action);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn pop_lru(&mut self) {
+        if let Some((key, (_, action))) = self.lru.pop_lru() {
+            self.bytes -= entry_len(&key, &action);
+        }
+    }
+}
+
+/// A shared LRU cache of converted images, bounded in bytes, with optional
+/// time based expiry.
+#[derive(Clone)]
+pub struct ImageCache {
+    entries: Arc<Mutex<Entries>>,
+    max_bytes: usize,
+    ttl: Option<Duration>,
+    disk: Option<DiskCache>,
+    stats: Arc<CacheStats>,
+}
+
+impl Default for ImageCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_BYTES, None)
+    }
+}
+
+impl ImageCache {
+    /// Create a cache holding up to max_bytes of images, each for at most
+    /// ttl.
+    pub fn new(max_bytes: usize, ttl: Option<Duration>) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(Entries {
+                lru: lru::LruCache::unbounded(),
+                bytes: 0,
+            })),
+            max_bytes,
+            ttl,
+            disk: None,
+            stats: Default::default(),
+        }
+    }
+
+    /// Record the counters of the cache in the provided stats.
+    pub fn with_stats(mut self, stats: Arc<CacheStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Keep converted images on disk as well, looking there for those not
+    /// in memory.
+    pub fn with_disk(mut self, disk: DiskCache) -> Self {
+        self.disk = Some(disk);
+        self
+    }
+
+    /// The counters of the cache.
+    pub fn stats(&self) -> Arc<CacheStats> {
+        self.stats.clone()
+    }
+
+    fn expired(&self, inserted: Instant) -> bool {
+        self.ttl.is_some_and(|ttl| inserted.elapsed() >= ttl)
+    }
+
+    /// Look up the action for a key.
+    pub fn get(&self, key: &str) -> Option<DeviceActions> {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(inserted) = entries.lru.get(key).map(|(inserted, _)| *inserted) else {
+            drop(entries);
+            return self.get_from_disk(key);
+        };
+        if self.expired(inserted) {
+            entries.pop(key);
+            self.record_bytes(&entries);
+            CacheStats::add(&self.stats.expirations);
+            CacheStats::add(&self.stats.misses);
+            return None;
+        }
+        CacheStats::add(&self.stats.hits);
+        entries.lru.peek(key).map(|(_, action)| action.clone())
+    }
+
+    /// Whether an action is held for a key, without counting as a lookup.
+    /// An action held is kept as if it was just used, and brought back into
+    /// memory if it was only on disk.
+    pub fn contains(&self, key: &str) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(inserted) = entries.lru.get(key).map(|(inserted, _)| *inserted) {
+            return !self.expired(inserted);
+        }
+        drop(entries);
+        match self.disk.as_ref().and_then(|disk| disk.get(key)) {
+            Some(action) => {
+                self.insert(key.to_string(), action);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Look up an action missing from memory on disk, bringing it back into
+    /// memory if it is there.
+    fn get_from_disk(&self, key: &str) -> Option<DeviceActions> {
+        let action = self.disk.as_ref().and_then(|disk| disk.get(key));
+        match &action {
+            Some(action) => {
+                CacheStats::add(&self.stats.hits);
+                CacheStats::add(&self.stats.disk_hits);
+                self.insert(key.to_string(), action.clone());
+            }
+            None => CacheStats::add(&self.stats.misses),
+        }
+        action
+    }
+
+    /// Remember the action for a key.  Actions too big to ever fit are not
+    /// kept in memory.
+    pub fn put(&self, key: String, action: DeviceActions) {
+        if let Some(disk) = &self.disk {
+            disk.put(&key, &action);
+        }
+        self.insert(key, action);
+    }
+
+    fn insert(&self, key: String, action: DeviceActions) {
+        let len = entry_len(&key, &action);
+        if len > self.max_bytes {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        self.purge_expired(&mut entries);
+        entries.pop(&key);
+        while entries.bytes + len > self.max_bytes {
+            entries.pop_lru();
+            CacheStats::add(&self.stats.evictions);
+        }
+        entries.lru.push(key, (Instant::now(), action));
+        entries.bytes += len;
+        self.record_bytes(&entries);
+    }
+
+    /// Forget everything, e.g. when the conversion itself changes.
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.lru.clear();
+        entries.bytes = 0;
+        self.record_bytes(&entries);
+    }
+
+    /// Drop expired entries from the least recently used end.
+    fn purge_expired(&self, entries: &mut Entries) {
+        while let Some((_, (inserted, _))) = entries.lru.peek_lru() {
+            if !self.expired(*inserted) {
+                break;
+            }
+            entries.pop_lru();
+            CacheStats::add(&self.stats.expirations);
+        }
+    }
+
+    fn record_bytes(&self, entries: &Entries) {
+        self.stats
+            .bytes
+            .store(entries.bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Bytes an entry is accounted for: its key and the image it holds.
+fn entry_len(key: &str, action: &DeviceActions) -> usize {
+    let image = match action {
+        DeviceActions::SetButtonImage(image) | DeviceActions::PrepareButtonImage(image) => {
+            image.image.len()
+        }
+        DeviceActions::SetLCDImage(image) => image.image.len(),
+        DeviceActions::SetBrightness(_) | DeviceActions::Commit | DeviceActions::ClearAll => 0,
+    };
+    key.len() + image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traits::device::{SetBrightness, SetButtonImage};
+
+    fn action(brightness: u8) -> DeviceActions {
+        DeviceActions::SetBrightness(SetBrightness { brightness })
+    }
+
+    #[test]
+    fn test_counters() {
+        // Room for one entry of a single byte key
+        let cache = ImageCache::new(1, None);
+        assert!(cache.get("a").is_none());
+        cache.put("a".into(), action(1));
+        assert!(cache.get("a").is_some());
+        cache.put("b".into(), action(2));
+        assert!(cache.get("a").is_none());
+
+        let stats = cache.stats().snapshot();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 2);
+        assert_eq!(stats.evictions, 1);
+    }
+
+    #[test]
+    fn test_ttl_expires_entries() {
+        let cache = ImageCache::new(10, Some(Duration::ZERO));
+        cache.put("a".into(), action(1));
+        assert!(cache.get("a").is_none());
+        assert_eq!(cache.stats().snapshot().expirations, 1);
+    }
+
+    #[test]
+    fn test_clones_share_entries_bounded_in_bytes() {
+        let image = |button| {
+            DeviceActions::SetButtonImage(SetButtonImage {
+                button,
+                image: vec![0; 100],
+            })
+        };
+        let cache = ImageCache::new(250, None);
+        let other = cache.clone();
+        cache.put("a".into(), image(0));
+        cache.put("b".into(), image(1));
+        assert!(other.get("a").is_some());
+        assert_eq!(other.stats().snapshot().bytes, 202);
+
+        // "b" is the least recently used, and has to go to make room
+        other.put("c".into(), image(2));
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some());
+        assert_eq!(cache.stats().snapshot().bytes, 202);
+    }
+}
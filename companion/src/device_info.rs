@@ -0,0 +1,92 @@
+//! What Companion is told about a device when it is added.
+//!
+//! The [DeviceMsg](crate::DeviceMsg) of an ADD-DEVICE is built from a
+//! [DeviceInfo], so every kind of device describes itself in one place and
+//! the optional fields newer Companions take are filled in the same way as
+//! the required ones.
+
+use elgato_streamdeck::info::Kind;
+
+use crate::layout;
+
+/// A device as Companion lays it out.
+pub trait DeviceInfo {
+    /// Name shown for the device in Companion
+    fn product_name(&self) -> String;
+    /// Number of keys Companion lays out, including the LCD strip and
+    /// encoder rows
+    fn keys_total(&self) -> u8;
+    /// Number of keys in a row
+    fn keys_per_row(&self) -> u8;
+    /// Size in pixels of the square key images, or 0 if the keys show none
+    fn resolution(&self) -> u16;
+    /// Whether Companion can set the brightness of the device
+    fn supports_brightness(&self) -> bool {
+        true
+    }
+    /// Width and height in pixels of the LCD strip, if there is one
+    fn lcd_strip(&self) -> Option<(u16, u16)> {
+        None
+    }
+}
+
+impl DeviceInfo for Kind {
+    fn product_name(&self) -> String {
+        format!("RustSatellite StreamDeck: {}", self.to_string())
+    }
+    fn keys_total(&self) -> u8 {
+        layout::keys_total(*self)
+    }
+    fn keys_per_row(&self) -> u8 {
+        self.column_count()
+    }
+    fn resolution(&self) -> u16 {
+        self.key_image_format()
+            .size
+            .0
+            .try_into()
+            .unwrap_or_default()
+    }
+    fn supports_brightness(&self) -> bool {
+        // The pedal has no lights to dim
+        self.is_visual()
+    }
+    fn lcd_strip(&self) -> Option<(u16, u16)> {
+        let (width, height) = self.lcd_strip_size()?;
+        Some((width.try_into().ok()?, height.try_into().ok()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::version::ProtocolVersion;
+    use crate::DeviceMsg;
+
+    #[test]
+    fn test_add_device_lines() {
+        let plus = DeviceMsg::new("deck", &Kind::Plus)
+            .with_module("rust_satellite")
+            .with_version("0.1.0");
+        let pedal = DeviceMsg::new("feet", &Kind::Pedal);
+        let features = |major, minor| ProtocolVersion::new(major, minor, 0).features();
+
+        // Older Companions only get the fields they know
+        assert_eq!(
+            plus.device_msg_with(features(1, 5)),
+            "DEVICEID=deck PRODUCT_NAME=\"RustSatellite StreamDeck: Plus\" KEYS_TOTAL=16, \
+             KEYS_PER_ROW=4 BITMAPS=120 COLORS=0 TEXT=0"
+        );
+        assert_eq!(
+            plus.device_msg_with(features(2, 1)),
+            "DEVICEID=deck PRODUCT_NAME=\"RustSatellite StreamDeck: Plus\" KEYS_TOTAL=16, \
+             KEYS_PER_ROW=4 BITMAPS=120 COLORS=1 TEXT=1 BRIGHTNESS=1 LCD_STRIP=800x100 \
+             MODULE=rust_satellite VERSION=0.1.0"
+        );
+        assert_eq!(
+            pedal.device_msg_with(features(2, 1)),
+            "DEVICEID=feet PRODUCT_NAME=\"RustSatellite StreamDeck: Pedal\" KEYS_TOTAL=3, \
+             KEYS_PER_ROW=3 BITMAPS=0 COLORS=0 TEXT=0 BRIGHTNESS=0"
+        );
+    }
+}
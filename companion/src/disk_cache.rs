@@ -0,0 +1,138 @@
+//! Converted images kept on disk across restarts.
+//!
+//! On something as small as a Pi Zero, resizing and encoding the images of a
+//! page takes long enough to notice.  The [ImageCache](crate::cache::ImageCache)
+//! only remembers them while running, so a [DiskCache] behind it lets the
+//! pages Companion shows over and over render instantly after a restart too.
+//!
+//! Every entry is a file named after a hash of its key, holding the key
+//! itself and the converted action.  A file that can't be read, was written
+//! by another version or turns out to hold another key is treated as a miss,
+//! so the directory can be cleared (or shared with an older build) at any
+//! time.
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+use traits::{device::DeviceActions, Result};
+
+/// Changed whenever the contents of the files, or the conversion of the
+/// images they hold, changes.
+const FORMAT_VERSION: u32 = 1;
+
+/// What a cache file holds.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    version: u32,
+    key: String,
+    action: DeviceActions,
+}
+
+/// A directory of converted images.
+#[derive(Clone, Debug)]
+pub struct DiskCache {
+    dir: PathBuf,
+}
+
+impl DiskCache {
+    /// Keep the cache in dir, creating it if needed.
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{:016x}.bin", fnv1a(key.as_bytes())))
+    }
+
+    /// Look up the action for a key.
+    pub fn get(&self, key: &str) -> Option<DeviceActions> {
+        let path = self.path(key);
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                debug!("Couldn't read {}: {}", path.display(), e);
+                return None;
+            }
+        };
+        match postcard::from_bytes::<Entry>(&bytes) {
+            Ok(entry) if entry.version == FORMAT_VERSION && entry.key == key => Some(entry.action),
+            Ok(_) => None,
+            Err(e) => {
+                debug!("Ignoring unreadable {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Remember the action for a key.  Only images are worth keeping, and
+    /// failing to write them isn't fatal.
+    pub fn put(&self, key: &str, action: &DeviceActions) {
+        if matches!(action, DeviceActions::SetBrightness(_)) {
+            return;
+        }
+        let entry = Entry {
+            version: FORMAT_VERSION,
+            key: key.to_string(),
+            action: action.clone(),
+        };
+        if let Err(e) = self.write(key, &entry) {
+            warn!(
+                "Couldn't write to the image cache in {}: {}",
+                self.dir.display(),
+                e
+            );
+        }
+    }
+
+    /// Write an entry, going through a temporary file so a crash never leaves
+    /// half an image behind.
+    fn write(&self, key: &str, entry: &Entry) -> Result<()> {
+        let path = self.path(key);
+        let partial = path.with_extension("partial");
+        fs::write(&partial, postcard::to_allocvec(entry)?)?;
+        fs::rename(&partial, &path)?;
+        Ok(())
+    }
+}
+
+/// 64 bit FNV-1a, which unlike the hasher of std is the same in every build.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traits::device::SetButtonImage;
+
+    #[test]
+    fn test_entries_survive_reopening() {
+        let dir = std::env::temp_dir().join(format!("companion-disk-cache-{}", std::process::id()));
+        let action = DeviceActions::SetButtonImage(SetButtonImage {
+            button: 3,
+            image: vec![0xff, 0xd8, 0xff, 0xd9],
+        });
+
+        DiskCache::new(&dir).unwrap().put("Mk2 KEY=3", &action);
+        let cache = DiskCache::new(&dir).unwrap();
+        match cache.get("Mk2 KEY=3") {
+            Some(DeviceActions::SetButtonImage(image)) => assert_eq!(image.button, 3),
+            action => panic!("Unexpected action {action:?}"),
+        }
+        assert!(cache.get("Mk2 KEY=4").is_none());
+
+        // A file holding another key is a miss, not the wrong image
+        fs::rename(cache.path("Mk2 KEY=3"), cache.path("Xl KEY=3")).unwrap();
+        assert!(cache.get("Xl KEY=3").is_none());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
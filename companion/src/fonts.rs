@@ -0,0 +1,269 @@
+//! Drawing the text of keys in font files, for labels that aren't ASCII.
+//!
+//! The font built into [render](crate::render) only has ASCII, while
+//! operators label keys in their own language.  [Fonts] draws the text of
+//! keys in TrueType or OpenType fonts instead.  Each character is drawn in
+//! the first of the fonts that has it, so a Latin font can be followed by a
+//! CJK font and a monochrome emoji font, such as Noto Sans CJK and Noto
+//! Emoji, to cover what the first lacks.  Color emoji fonts keep their
+//! glyphs as images rather than outlines and can't be drawn.  Characters
+//! none of the fonts have are drawn as the missing glyph box of the first.
+//!
+//! Characters are laid out one after another, without the shaping scripts
+//! like Arabic need.  Text wraps at spaces where it can and between any two
+//! characters where it can't, which is where CJK text wraps anyway.
+//!
+//! Rasterizing outlines is most of the cost of drawing a key, and the same
+//! few characters make up most labels, so rasterized glyphs are kept in a
+//! cache shared by every key drawn.
+
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use ab_glyph::{point, Font, FontVec, GlyphId, ScaleFont};
+use lru::LruCache;
+use traits::{anyhow, Result};
+
+use crate::render::fill_key;
+
+/// Rasterized glyphs kept for reuse.
+pub const GLYPH_CACHE_SIZE: usize = 1024;
+/// Share of the key side the text starts at, before it is shrunk to fit.
+const START_SCALE: f32 = 0.2;
+/// Smallest size in pixels text is shrunk to.
+const MIN_PX: f32 = 8.0;
+
+/// A glyph of a font, rasterized at a size.
+#[derive(Debug)]
+struct Raster {
+    /// Offset of the top left corner from the pen position on the baseline
+    left: i32,
+    top: i32,
+    width: usize,
+    /// Coverage of each pixel, row by row
+    coverage: Vec<u8>,
+}
+
+/// A glyph, a size, and the font they are of.
+type GlyphKey = (usize, GlyphId, u32);
+
+/// Fonts to draw the text of keys in, each taking the characters the fonts
+/// before it lack.  Cheap to share between devices through an [Arc].
+pub struct Fonts {
+    faces: Vec<FontVec>,
+    /// Names the fonts, keeping their images apart in a shared cache
+    name: String,
+    glyphs: Mutex<LruCache<GlyphKey, Arc<Raster>>>,
+}
+
+impl std::fmt::Debug for Fonts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Fonts").field("name", &self.name).finish()
+    }
+}
+
+impl Fonts {
+    /// Load the font files at paths, in the order characters are looked up
+    /// in them.
+    pub fn load(paths: &[impl AsRef<Path>]) -> Result<Self> {
+        let mut faces = Vec::new();
+        let mut names = Vec::new();
+        for path in paths {
+            let path = path.as_ref();
+            let data = std::fs::read(path)
+                .map_err(|e| anyhow::anyhow!("Can't read font {}: {e}", path.display()))?;
+            faces.push(
+                FontVec::try_from_vec(data)
+                    .map_err(|e| anyhow::anyhow!("Can't load font {}: {e}", path.display()))?,
+            );
+            names.push(path.file_name().unwrap_or_default().to_string_lossy());
+        }
+        anyhow::ensure!(!faces.is_empty(), "No fonts given");
+        Ok(Self {
+            faces,
+            name: names.join(","),
+            glyphs: Mutex::new(LruCache::new(
+                NonZeroUsize::new(GLYPH_CACHE_SIZE).unwrap_or(NonZeroUsize::MIN),
+            )),
+        })
+    }
+
+    /// Names the fonts.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The font and glyph c is drawn with, or None for characters that take
+    /// no room, like joiners and variation selectors.
+    fn glyph(&self, c: char) -> Option<(usize, GlyphId)> {
+        let found = self
+            .faces
+            .iter()
+            .enumerate()
+            .map(|(face, font)| (face, font.glyph_id(c)))
+            .find(|(_, id)| id.0 != 0);
+        match found {
+            Some(found) => Some(found),
+            None if invisible(c) => None,
+            // The missing glyph box of the first font
+            None => Some((0, GlyphId(0))),
+        }
+    }
+
+    /// How far the pen moves after drawing c at px pixels.
+    fn advance(&self, c: char, px: f32) -> f32 {
+        self.glyph(c).map_or(0.0, |(face, id)| {
+            self.faces[face].as_scaled(px).h_advance(id)
+        })
+    }
+
+    /// The glyph rasterized at px pixels, from the cache if it was drawn
+    /// before.
+    fn raster(&self, (face, id): (usize, GlyphId), px: f32) -> Option<Arc<Raster>> {
+        // Sizes are cached in quarter pixels
+        let key = (face, id, (px * 4.0).round() as u32);
+        if let Some(raster) = self.glyphs.lock().unwrap().get(&key) {
+            return Some(raster.clone());
+        }
+        let glyph = id.with_scale_and_position(px, point(0.0, 0.0));
+        let outlined = self.faces[face].outline_glyph(glyph)?;
+        let bounds = outlined.px_bounds();
+        let (width, height) = (bounds.width() as usize, bounds.height() as usize);
+        let mut coverage = vec![0; width * height];
+        outlined.draw(|x, y, c| {
+            let (x, y) = (x as usize, y as usize);
+            if x < width && y < height {
+                coverage[y * width + x] = (c.clamp(0.0, 1.0) * 255.0) as u8;
+            }
+        });
+        let raster = Arc::new(Raster {
+            left: bounds.min.x as i32,
+            top: bounds.min.y as i32,
+            width,
+            coverage,
+        });
+        self.glyphs.lock().unwrap().put(key, raster.clone());
+        Some(raster)
+    }
+
+    /// Draw a key of side by side pixels as packed RGB into out, replacing
+    /// what it held, as [draw_key](crate::render::draw_key) does but in these
+    /// fonts.  The text is shrunk until it fits, down to a size still
+    /// readable, and lines that don't fit then are left out.
+    pub fn draw_key(&self, side: usize, color: Option<[u8; 3]>, text: &str, out: &mut Vec<u8>) {
+        let ink = fill_key(side, color, out);
+        let first = self.faces[0].as_scaled(1.0);
+        let line_height = first.height() - first.line_gap().min(0.0);
+        let max_width = side as f32 * 0.9;
+
+        let mut px = side as f32 * START_SCALE;
+        let mut lines = wrap(text, max_width, |c| self.advance(c, px));
+        while lines.len() as f32 * line_height * px > side as f32 && px > MIN_PX {
+            px = (px * 0.85).max(MIN_PX);
+            lines = wrap(text, max_width, |c| self.advance(c, px));
+        }
+        let height = line_height * px;
+        lines.truncate(((side as f32 / height) as usize).max(1));
+
+        let top = (side as f32 - lines.len() as f32 * height).max(0.0) / 2.0;
+        for (row, line) in lines.iter().enumerate() {
+            let width: f32 = line.iter().map(|&c| self.advance(c, px)).sum();
+            let mut x = (side as f32 - width).max(0.0) / 2.0;
+            let baseline = top + row as f32 * height + first.ascent() * px;
+            for &c in line {
+                let Some(glyph) = self.glyph(c) else {
+                    continue;
+                };
+                if let Some(raster) = self.raster(glyph, px) {
+                    let origin = (x.round() as i32, baseline.round() as i32);
+                    blend(side, &raster, origin, ink, out);
+                }
+                x += self.advance(c, px);
+            }
+        }
+    }
+}
+
+/// Characters that take no room, which aren't worth a missing glyph box.
+fn invisible(c: char) -> bool {
+    c.is_control() || matches!(c, '\u{200b}'..='\u{200f}' | '\u{fe00}'..='\u{fe0f}')
+}
+
+/// Break text into lines no wider than width, at spaces where possible and
+/// between characters where not.  Companion writes line breaks as `\n`.
+fn wrap(text: &str, width: f32, advance: impl Fn(char) -> f32) -> Vec<Vec<char>> {
+    let measure = |chars: &[char]| chars.iter().map(|&c| advance(c)).sum::<f32>();
+    let space = advance(' ');
+    let mut lines = Vec::new();
+    for paragraph in text.replace("\\n", "\n").split('\n') {
+        let mut line: Vec<char> = Vec::new();
+        let mut line_width = 0.0;
+        for word in paragraph.split(' ').filter(|word| !word.is_empty()) {
+            let word: Vec<char> = word.chars().collect();
+            let word_width = measure(&word);
+            if !line.is_empty() && line_width + space + word_width <= width {
+                line.push(' ');
+                line.extend(word);
+                line_width += space + word_width;
+                continue;
+            }
+            if !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+            }
+            // Words too wide for a line are broken between characters
+            line_width = 0.0;
+            for c in word {
+                let c_width = advance(c);
+                if !line.is_empty() && line_width + c_width > width {
+                    lines.push(std::mem::take(&mut line));
+                    line_width = 0.0;
+                }
+                line.push(c);
+                line_width += c_width;
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Blend ink into the key of side by side pixels where raster covers it,
+/// with the pen at origin, clipping whatever falls outside the key.
+fn blend(side: usize, raster: &Raster, (x0, y0): (i32, i32), ink: [u8; 3], rgb: &mut [u8]) {
+    for (dy, row) in raster.coverage.chunks(raster.width.max(1)).enumerate() {
+        for (dx, &coverage) in row.iter().enumerate().filter(|(_, c)| **c != 0) {
+            let x = x0 + raster.left + dx as i32;
+            let y = y0 + raster.top + dy as i32;
+            let (Ok(x), Ok(y)) = (usize::try_from(x), usize::try_from(y)) else {
+                continue;
+            };
+            if x >= side || y >= side {
+                continue;
+            }
+            let index = (y * side + x) * 3;
+            for (channel, ink) in rgb[index..index + 3].iter_mut().zip(ink) {
+                let (old, ink, coverage) = (*channel as u32, ink as u32, coverage as u32);
+                *channel = ((old * (255 - coverage) + ink * coverage) / 255) as u8;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wide_text_wrapped_between_characters() {
+        // CJK characters twice as wide as Latin ones
+        let advance = |c: char| if c.is_ascii() { 1.0 } else { 2.0 };
+        let lines: Vec<String> = wrap("ab cd 東京都庁 e\\nf", 5.0, advance)
+            .into_iter()
+            .map(String::from_iter)
+            .collect();
+        assert_eq!(lines, ["ab cd", "東京", "都庁", "e", "f"]);
+        assert!(invisible('\u{fe0f}'));
+        assert!(!invisible('東'));
+    }
+}
@@ -0,0 +1,251 @@
+//! One Companion connection shared by several gateway processes.
+//!
+//! Large installations run many gateways, and each would otherwise hold a
+//! connection to Companion of its own.  In front end mode a single process
+//! holds the connection in a [Multiplexer] and serves gateways over
+//! bin_comm frames instead, with [serve_gateway].  A gateway [connect]s to
+//! the front end rather than to Companion and gets back a reader and writer
+//! that behave like a connection to Companion, so the [Multiplexer] of the
+//! gateway doesn't know the difference.
+//!
+//! A gateway is sent the BEGIN of Companion when it connects, then the lines
+//! of the satellite API pass through as they are.  A device is added to the
+//! Companion connection the first time a gateway writes a line naming it,
+//! and removed once the gateway that added it goes away.  When Companion
+//! closes, every gateway is disconnected so they connect again, opening a
+//! new connection to Companion.
+
+use std::collections::HashMap;
+
+use bin_comm::stream_utils::{read_struct, write_struct};
+use serde::{Deserialize, Serialize};
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream, ReadHalf,
+    WriteHalf,
+};
+use tokio::net::ToSocketAddrs;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::{debug, info, trace};
+use traits::Result;
+
+use crate::mux::{device_id_of, Multiplexer};
+
+/// Bytes buffered between a gateway connection and its [Multiplexer].
+const BUFFER_SIZE: usize = 256 * 1024;
+/// Frames queued for a gateway before devices wait for it to catch up.
+const QUEUED_FRAMES: usize = 64;
+
+/// A message between a gateway and the front end.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// The BEGIN Companion opened with, sent to a gateway as it connects
+    Begin(String),
+    /// A line of the satellite API, passed on as it is
+    Line(String),
+}
+
+/// Serve a gateway connected to the front end, adding its devices to the
+/// Companion connection shared in mux.  Returns once either the gateway or
+/// Companion closes.
+pub async fn serve_gateway<S, W>(stream: S, mux: &Multiplexer<W>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let begin = mux.begin().await?;
+    let encoding = mux.features().await?.value_encoding;
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (frames, mut to_gateway) = mpsc::channel(QUEUED_FRAMES);
+    frames.send(Frame::Begin(begin)).await?;
+    // Frames are written from one place, so lines are never interleaved
+    let mut tasks = JoinSet::new();
+    tasks.spawn(async move {
+        while let Some(frame) = to_gateway.recv().await {
+            write_struct(&mut writer, &frame).await?;
+        }
+        Ok(())
+    });
+
+    // Dropping the writer of a device removes it from Companion
+    let mut devices: HashMap<String, DuplexStream> = HashMap::new();
+    let res = async {
+        loop {
+            let frame = tokio::select! {
+                frame = read_struct::<Frame>(&mut reader) => frame?,
+                _ = mux.closed() => {
+                    info!("Companion closed, disconnecting the gateway");
+                    return Ok(());
+                }
+                Some(res) = tasks.join_next() => {
+                    // Only the writer ends on its own, when the gateway does
+                    return res?;
+                }
+            };
+            let Frame::Line(line) = frame else {
+                debug!("Ignoring {:?} from a gateway", frame);
+                continue;
+            };
+            // PINGs don't name a device, so they go with any of them
+            let device_writer = match device_id_of(&line, encoding) {
+                Some(device_id) => match devices.get_mut(device_id.as_ref()) {
+                    Some(device_writer) => device_writer,
+                    None => {
+                        debug!("Gateway adding device {}", device_id);
+                        let (device_reader, device_writer) = mux.add_device(&device_id)?;
+                        tasks.spawn(forward_lines(device_reader, frames.clone()));
+                        devices
+                            .entry(device_id.into_owned())
+                            .or_insert(device_writer)
+                    }
+                },
+                None => match devices.values_mut().next() {
+                    Some(device_writer) => device_writer,
+                    None => {
+                        trace!("Dropping {} from a gateway without devices", line);
+                        continue;
+                    }
+                },
+            };
+            device_writer
+                .write_all(format!("{line}\n").as_bytes())
+                .await?;
+        }
+    }
+    .await;
+    info!("Gateway closed: {:?}", res);
+    res
+}
+
+/// Send the lines Companion has for a device on to its gateway.
+async fn forward_lines(device_reader: DuplexStream, frames: mpsc::Sender<Frame>) -> Result<()> {
+    let mut lines = BufReader::new(device_reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if frames.send(Frame::Line(line)).await.is_err() {
+            break;
+        }
+    }
+    // Companion closing is noticed by the gateway connection, and another
+    // gateway adding the device takes it over
+    std::future::pending().await
+}
+
+/// The reader and writer of a front end connected over stream, passing
+/// lines as a connection to Companion would.
+pub fn client<S>(stream: S) -> (ReadHalf<DuplexStream>, WriteHalf<DuplexStream>)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (local, remote) = tokio::io::duplex(BUFFER_SIZE);
+    tokio::spawn(async move {
+        let res = bridge(stream, remote).await;
+        info!("Front end connection closed: {:?}", res);
+    });
+    tokio::io::split(local)
+}
+
+/// Connect to the front end at addr, returning a reader and writer that pass
+/// lines as a connection to Companion would.
+pub async fn connect(
+    addr: impl ToSocketAddrs,
+) -> Result<(ReadHalf<DuplexStream>, WriteHalf<DuplexStream>)> {
+    let stream = tokio::net::TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+    Ok(client(stream))
+}
+
+/// Pass frames from the front end to the local end of a [Multiplexer] as
+/// lines, and lines from it back as frames, until either side closes.
+async fn bridge<S>(stream: S, local: DuplexStream) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (local_reader, mut local_writer) = tokio::io::split(local);
+    let from_front_end = async {
+        loop {
+            let (Frame::Begin(line) | Frame::Line(line)) = read_struct(&mut reader).await?;
+            local_writer
+                .write_all(format!("{line}\n").as_bytes())
+                .await?;
+        }
+    };
+    let to_front_end = async {
+        let mut lines = BufReader::new(local_reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            write_struct(&mut writer, &Frame::Line(line)).await?;
+        }
+        Ok(())
+    };
+    tokio::select! {
+        res = from_front_end => res,
+        res = to_front_end => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_gateways_share_companion_through_front_end() {
+        let (companion, front_end) = tokio::io::duplex(1024);
+        let (front_end_reader, front_end_writer) = tokio::io::split(front_end);
+        let mux = std::sync::Arc::new(Multiplexer::new(front_end_reader, front_end_writer));
+        let (companion_reader, mut companion_writer) = tokio::io::split(companion);
+        companion_writer
+            .write_all(b"BEGIN CompanionVersion=3.1.2 ApiVersion=1.5.1\n")
+            .await
+            .unwrap();
+
+        // Two gateways, each adding a device
+        let mut gateways = Vec::new();
+        for device_id in ["a", "b"] {
+            let (gateway, served) = tokio::io::duplex(1024);
+            let served_mux = mux.clone();
+            tokio::spawn(async move { serve_gateway(served, &served_mux).await });
+            let (reader, writer) = client(gateway);
+            let gateway = Multiplexer::new(reader, writer);
+            assert!(gateway.features().await.is_ok());
+            let (reader, mut writer) = gateway.add_device(device_id).unwrap();
+            writer
+                .write_all(format!("ADD-DEVICE DEVICEID={device_id}\n").as_bytes())
+                .await
+                .unwrap();
+            gateways.push((gateway, BufReader::new(reader).lines(), writer));
+        }
+        let mut companion_lines = BufReader::new(companion_reader).lines();
+        let mut added = vec![
+            companion_lines.next_line().await.unwrap().unwrap(),
+            companion_lines.next_line().await.unwrap().unwrap(),
+        ];
+        added.sort();
+        assert_eq!(added, ["ADD-DEVICE DEVICEID=a", "ADD-DEVICE DEVICEID=b"]);
+
+        // Lines from Companion reach the gateway of the device
+        companion_writer
+            .write_all(b"KEY-STATE DEVICEID=b KEY=1\nKEY-STATE DEVICEID=a KEY=2\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            gateways[0].1.next_line().await.unwrap().unwrap(),
+            "KEY-STATE DEVICEID=a KEY=2"
+        );
+        assert_eq!(
+            gateways[1].1.next_line().await.unwrap().unwrap(),
+            "KEY-STATE DEVICEID=b KEY=1"
+        );
+
+        // A gateway going away removes its devices from Companion
+        drop(gateways.remove(0));
+        assert_eq!(
+            companion_lines.next_line().await.unwrap().unwrap(),
+            "REMOVE-DEVICE DEVICEID=a"
+        );
+
+        // Companion closing disconnects the gateways
+        drop(companion_writer);
+        drop(companion_lines);
+        assert_eq!(gateways[0].1.next_line().await.unwrap(), None);
+    }
+}
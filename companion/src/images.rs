@@ -0,0 +1,370 @@
+//! Converting key images from the bitmaps Companion sends, copying them no
+//! more than needed.
+//!
+//! A page change brings dozens of KEY-STATE lines at once.  Each bitmap is
+//! decoded a single time into a buffer taken from a [BufferPool], and every
+//! step after that works on borrowed slices of it.  Buffers go back to the
+//! pool when dropped, so a steady stream of page flips settles into reusing
+//! the same few allocations.
+//!
+//! How long conversions take is counted in [ConversionTimes], for seeing
+//! whether a gateway keeps up with its leaves.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use elgato_streamdeck::info::{ImageFormat, ImageMirroring, ImageMode, ImageRotation, Kind};
+use image::codecs::bmp::BmpEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::ColorType;
+use traits::{anyhow, Result};
+
+/// Most buffers kept for reuse unless told otherwise.  More than that are
+/// freed when dropped.
+pub const MAX_POOLED: usize = 32;
+
+/// Byte buffers to decode and transform images in.  Can be shared between
+/// the threads converting images.
+pub struct BufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+    max_pooled: usize,
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(MAX_POOLED)
+    }
+}
+
+impl BufferPool {
+    /// A pool keeping at most max_pooled buffers for reuse.
+    pub fn new(max_pooled: usize) -> Self {
+        Self {
+            free: Default::default(),
+            max_pooled,
+        }
+    }
+
+    /// An empty buffer, reusing the allocation of one dropped before if
+    /// there is one.
+    pub fn take(&self) -> PooledBuffer<'_> {
+        let buf = self.free.lock().unwrap().pop().unwrap_or_default();
+        PooledBuffer { pool: self, buf }
+    }
+
+    /// An empty buffer like [take](Self::take), that holds on to the pool
+    /// rather than borrowing it so it can be passed between threads.
+    pub fn take_owned(self: &Arc<Self>) -> OwnedBuffer {
+        let buf = self.free.lock().unwrap().pop().unwrap_or_default();
+        OwnedBuffer {
+            pool: self.clone(),
+            buf,
+        }
+    }
+
+    /// Keep buf for reuse, if there is room for it.
+    fn put_back(&self, mut buf: Vec<u8>) {
+        buf.clear();
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.max_pooled {
+            free.push(buf);
+        }
+    }
+}
+
+/// A buffer that goes back to its [BufferPool] when dropped.
+pub struct PooledBuffer<'a> {
+    pool: &'a BufferPool,
+    buf: Vec<u8>,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        self.pool.put_back(std::mem::take(&mut self.buf));
+    }
+}
+
+/// A buffer that goes back to its [BufferPool] when dropped, wherever that
+/// happens.
+pub struct OwnedBuffer {
+    pool: Arc<BufferPool>,
+    buf: Vec<u8>,
+}
+
+impl Deref for OwnedBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for OwnedBuffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl Drop for OwnedBuffer {
+    fn drop(&mut self) {
+        self.pool.put_back(std::mem::take(&mut self.buf));
+    }
+}
+
+/// Encode a key image of packed 8 bit RGB into the data the kind of device
+/// takes, the same as `elgato_streamdeck::images::convert_image` but without
+/// taking ownership of the pixels.  The image must be the key size of the
+/// device.
+pub fn encode_key(kind: Kind, rgb: &[u8], pool: &BufferPool) -> Result<Vec<u8>> {
+    let format = kind.key_image_format();
+    let (width, height) = format.size;
+    anyhow::ensure!(
+        rgb.len() == width * height * 3,
+        "Expected a key image of {} bytes, got {}",
+        width * height * 3,
+        rgb.len()
+    );
+
+    let mut oriented = pool.take();
+    let rgb = match (format.rotation, format.mirror) {
+        (ImageRotation::Rot0, ImageMirroring::None) => rgb,
+        _ => {
+            orient(format, rgb, &mut oriented);
+            &oriented[..]
+        }
+    };
+    // Keys are square, so rotating doesn't change the size
+    let (width, height) = (width.try_into()?, height.try_into()?);
+    let mut data = Vec::new();
+    match format.mode {
+        ImageMode::None => {}
+        ImageMode::BMP => BmpEncoder::new(&mut data).encode(rgb, width, height, ColorType::Rgb8)?,
+        ImageMode::JPEG => JpegEncoder::new_with_quality(&mut data, 90).encode(
+            rgb,
+            width,
+            height,
+            ColorType::Rgb8,
+        )?,
+    }
+    Ok(data)
+}
+
+/// The step turning images of packed 8 bit RGB into the bytes a device
+/// takes.  A [Receiver](crate::receiver::Receiver) uses [StreamDeckConverter]
+/// unless given another, so hardware taking other formats (RGB565 TFTs,
+/// monochrome OLEDs) only needs a converter of its own, not another pipeline.
+pub trait ImageConverter: Send + Sync {
+    /// Names the conversion, keeping its images apart from those of other
+    /// converters in a shared [ImageCache](crate::cache::ImageCache).
+    fn name(&self) -> &str;
+    /// Convert a key image, the key size of kind square, into what the
+    /// device takes.
+    fn convert_key(&self, kind: Kind, rgb: &[u8], pool: &BufferPool) -> Result<Vec<u8>>;
+    /// Convert an image of width by height pixels for the LCD strip.  Sent as
+    /// it is unless overridden.
+    fn convert_lcd(&self, _kind: Kind, _width: u32, _height: u32, rgb: Vec<u8>) -> Result<Vec<u8>> {
+        Ok(rgb)
+    }
+}
+
+/// Converts images for a Stream Deck, keys with [encode_key].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StreamDeckConverter;
+
+impl ImageConverter for StreamDeckConverter {
+    fn name(&self) -> &str {
+        "streamdeck"
+    }
+
+    fn convert_key(&self, kind: Kind, rgb: &[u8], pool: &BufferPool) -> Result<Vec<u8>> {
+        encode_key(kind, rgb, pool)
+    }
+
+    /// The LCD strip of the Plus takes JPEG, as its keys do.
+    fn convert_lcd(&self, kind: Kind, width: u32, height: u32, rgb: Vec<u8>) -> Result<Vec<u8>> {
+        if kind.lcd_strip_size().is_none() {
+            return Ok(rgb);
+        }
+        let mut data = Vec::new();
+        JpegEncoder::new_with_quality(&mut data, 90).encode(
+            &rgb,
+            width,
+            height,
+            ColorType::Rgb8,
+        )?;
+        Ok(data)
+    }
+}
+
+/// Upper bounds, in microseconds, of the buckets conversions are counted in.
+pub const CONVERSION_BUCKETS_US: [u64; 10] = [
+    500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 1_000_000,
+];
+
+/// A histogram of how long images took to convert, shared by the receivers
+/// of every device.
+#[derive(Debug, Default)]
+pub struct ConversionTimes {
+    /// Conversions no longer than each of [CONVERSION_BUCKETS_US], and the
+    /// rest
+    buckets: [AtomicU64; CONVERSION_BUCKETS_US.len() + 1],
+    total_us: AtomicU64,
+}
+
+impl ConversionTimes {
+    /// An image took elapsed to convert.
+    pub fn record(&self, elapsed: Duration) {
+        let us = elapsed.as_micros().try_into().unwrap_or(u64::MAX);
+        let bucket = CONVERSION_BUCKETS_US.partition_point(|&bound| bound < us);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.total_us.fetch_add(us, Ordering::Relaxed);
+    }
+
+    /// Take a point in time copy of the histogram.
+    pub fn snapshot(&self) -> ConversionSnapshot {
+        let mut count = 0;
+        let mut cumulative = [0; CONVERSION_BUCKETS_US.len()];
+        for (bucket, total) in self.buckets.iter().zip(&mut cumulative) {
+            count += bucket.load(Ordering::Relaxed);
+            *total = count;
+        }
+        ConversionSnapshot {
+            cumulative,
+            count: count + self.buckets[CONVERSION_BUCKETS_US.len()].load(Ordering::Relaxed),
+            total_us: self.total_us.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A point in time copy of [ConversionTimes].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ConversionSnapshot {
+    /// Conversions no longer than each of [CONVERSION_BUCKETS_US]
+    pub cumulative: [u64; CONVERSION_BUCKETS_US.len()],
+    /// Conversions in all
+    pub count: u64,
+    /// Microseconds all conversions took together
+    pub total_us: u64,
+}
+
+/// Encode an image of packed 8 bit RGB as a BMP file, for serving images to
+/// browsers.
+pub fn encode_bmp(width: u32, height: u32, rgb: &[u8]) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    BmpEncoder::new(&mut data).encode(rgb, width, height, ColorType::Rgb8)?;
+    Ok(data)
+}
+
+/// Rotate and then mirror an image of packed RGB the way the device expects,
+/// appending the result to out.
+fn orient(format: ImageFormat, rgb: &[u8], out: &mut Vec<u8>) {
+    let (width, height) = format.size;
+    let (out_width, out_height) = match format.rotation {
+        ImageRotation::Rot90 | ImageRotation::Rot270 => (height, width),
+        ImageRotation::Rot0 | ImageRotation::Rot180 => (width, height),
+    };
+    let (flip_x, flip_y) = match format.mirror {
+        ImageMirroring::None => (false, false),
+        ImageMirroring::X => (true, false),
+        ImageMirroring::Y => (false, true),
+        ImageMirroring::Both => (true, true),
+    };
+
+    out.reserve(rgb.len());
+    for y in 0..out_height {
+        for x in 0..out_width {
+            // Mirroring is applied after rotating, so it is undone first
+            let x = if flip_x { out_width - 1 - x } else { x };
+            let y = if flip_y { out_height - 1 - y } else { y };
+            let (x, y) = match format.rotation {
+                ImageRotation::Rot0 => (x, y),
+                ImageRotation::Rot90 => (y, height - 1 - x),
+                ImageRotation::Rot180 => (width - 1 - x, height - 1 - y),
+                ImageRotation::Rot270 => (width - 1 - y, x),
+            };
+            let index = (y * width + x) * 3;
+            out.extend_from_slice(&rgb[index..index + 3]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::DynamicImage;
+
+    #[test]
+    fn test_orientation_matches_image_crate() {
+        let pool = BufferPool::default();
+        for kind in [Kind::Original, Kind::Mini, Kind::Xl, Kind::Plus] {
+            let format = kind.key_image_format();
+            let (width, height) = format.size;
+            let rgb: Vec<u8> = (0..width * height * 3).map(|i| (i % 251) as u8).collect();
+            let image = image::RgbImage::from_raw(width as u32, height as u32, rgb.clone())
+                .map(DynamicImage::ImageRgb8)
+                .unwrap();
+
+            let image = match format.rotation {
+                ImageRotation::Rot0 => image,
+                ImageRotation::Rot90 => image.rotate90(),
+                ImageRotation::Rot180 => image.rotate180(),
+                ImageRotation::Rot270 => image.rotate270(),
+            };
+            let image = match format.mirror {
+                ImageMirroring::None => image,
+                ImageMirroring::X => image.fliph(),
+                ImageMirroring::Y => image.flipv(),
+                ImageMirroring::Both => image.fliph().flipv(),
+            };
+            let mut oriented = pool.take();
+            orient(format, &rgb, &mut oriented);
+            assert_eq!(*oriented, image.into_rgb8().into_raw());
+        }
+
+        // The buffer of the last kind is reused
+        assert!(pool.take().capacity() > 0);
+        assert!(encode_key(Kind::Plus, &[0; 3], &pool).is_err());
+    }
+
+    #[test]
+    fn test_lcd_images_encoded_for_the_plus() {
+        let rgb: Vec<u8> = (0..200 * 100 * 3).map(|i| (i % 251) as u8).collect();
+        let jpeg = StreamDeckConverter
+            .convert_lcd(Kind::Plus, 200, 100, rgb.clone())
+            .unwrap();
+        let decoded = image::load_from_memory_with_format(&jpeg, image::ImageFormat::Jpeg).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (200, 100));
+        // Nothing else has an LCD to take any particular format
+        let passed = StreamDeckConverter.convert_lcd(Kind::Mk2, 200, 100, rgb.clone());
+        assert_eq!(passed.unwrap(), rgb);
+    }
+
+    #[test]
+    fn test_conversions_bucketed() {
+        let times = ConversionTimes::default();
+        for us in [100, 500, 501, 3_000, 2_000_000] {
+            times.record(Duration::from_micros(us));
+        }
+        let snapshot = times.snapshot();
+        assert_eq!(snapshot.cumulative[..4], [2, 3, 3, 4]);
+        assert_eq!(snapshot.cumulative[9], 4);
+        assert_eq!(snapshot.count, 5);
+        assert_eq!(snapshot.total_us, 2_004_101);
+    }
+}
@@ -1,13 +1,131 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
 
 use anyhow::Result;
 use common::StringOrStr;
 use nom::{
-    bytes::complete::{tag, take, take_while},
+    bytes::complete::{tag, take, take_while, take_while1},
     character::complete::multispace0,
+    error::{Error, ErrorKind},
     Finish, IResult,
 };
 
+/// How values are written in a line of key=value pairs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ValueEncoding {
+    /// Values with spaces, quotes or control characters are quoted, with
+    /// backslash escapes: `\n`, `\r`, `\t` and `\0` for those characters,
+    /// `\u{...}` for any character by its hex code point, and a backslash
+    /// before any other character for that character.
+    #[default]
+    Quoted,
+    /// Every byte other than letters, digits and `-_.~` is percent-encoded,
+    /// so any string survives.
+    Percent,
+}
+
+/// Write a value so that parsing it with the same encoding reads it back
+/// unchanged.
+pub fn encode_value(value: &str, encoding: ValueEncoding) -> Cow<'_, str> {
+    if value.is_empty() {
+        return Cow::Borrowed("\"\"");
+    }
+    match encoding {
+        ValueEncoding::Quoted => {
+            if !value
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control() || c == '"' || c == '\\')
+            {
+                return Cow::Borrowed(value);
+            }
+            let mut quoted = String::with_capacity(value.len() + 2);
+            quoted.push('"');
+            for c in value.chars() {
+                match c {
+                    '"' | '\\' => {
+                        quoted.push('\\');
+                        quoted.push(c);
+                    }
+                    '\n' => quoted.push_str("\\n"),
+                    '\r' => quoted.push_str("\\r"),
+                    '\t' => quoted.push_str("\\t"),
+                    c if c.is_control() => quoted.push_str(&format!("\\u{{{:x}}}", u32::from(c))),
+                    c => quoted.push(c),
+                }
+            }
+            quoted.push('"');
+            Cow::Owned(quoted)
+        }
+        ValueEncoding::Percent => {
+            if value.bytes().all(unreserved) {
+                return Cow::Borrowed(value);
+            }
+            let mut encoded = String::with_capacity(value.len() * 3);
+            for byte in value.bytes() {
+                if unreserved(byte) {
+                    encoded.push(char::from(byte));
+                } else {
+                    encoded.push_str(&format!("%{byte:02X}"));
+                }
+            }
+            Cow::Owned(encoded)
+        }
+    }
+}
+
+/// Bytes left alone by percent-encoding.
+fn unreserved(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~')
+}
+
+/// The character a backslash escape in a quoted value stands for, given what
+/// follows the backslash, and how many bytes of rest the escape takes up.
+/// None if the escape is cut short or names no character.
+pub(crate) fn unescape(rest: &str) -> Option<(char, usize)> {
+    let c = rest.chars().next()?;
+    let unescaped = match c {
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        '0' => '\0',
+        'u' => {
+            let hex = rest.strip_prefix("u{")?;
+            let end = hex.find('}')?;
+            // from_str_radix would also take a sign
+            if !(1..=6).contains(&end) || !hex[..end].bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+            let c = char::from_u32(u32::from_str_radix(&hex[..end], 16).ok()?)?;
+            return Some((c, "u{".len() + end + "}".len()));
+        }
+        // Quotes, backslashes and anything else stand for themselves
+        c => c,
+    };
+    Some((unescaped, c.len_utf8()))
+}
+
+/// Undo percent-encoding, None if it is malformed or isn't UTF-8.
+pub(crate) fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            // from_str_radix would also take a sign
+            let hex = tail
+                .get(..2)
+                .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+            bytes.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// The key=value pairs of a line.  A key given more than once makes the line
+/// ambiguous, so it fails to parse rather than one of the values winning.
 #[derive(Debug)]
 pub struct ParseMap<'a> {
     map: HashMap<&'a str, StringOrStr<'a>>,
@@ -36,15 +154,25 @@ impl<'a> ParseMap<'a> {
     }
 }
 
+impl<'a> ParseMap<'a> {
+    /// Parse a line of key=value pairs whose values are encoded as said.
+    pub fn parse(
+        value: &'a str,
+        encoding: ValueEncoding,
+    ) -> std::result::Result<Self, Error<&'a str>> {
+        Ok(str_to_key_value(value, encoding).finish()?.1)
+    }
+}
+
 impl<'a> TryFrom<&'a str> for ParseMap<'a> {
-    type Error = nom::error::Error<&'a str>;
+    type Error = Error<&'a str>;
 
     fn try_from(value: &'a str) -> std::result::Result<Self, Self::Error> {
-        Ok(str_to_key_value(value).finish()?.1)
+        Self::parse(value, ValueEncoding::Quoted)
     }
 }
 
-// parse a quoted string, with escaped characters
+// parse a quoted string, decoding escaped characters
 fn quoted_string(data: &str) -> IResult<&str, StringOrStr> {
     // initial quote
     let (data, _) = tag("\"")(data)?;
@@ -75,12 +203,13 @@ fn quoted_string(data: &str) -> IResult<&str, StringOrStr> {
         let to_append = accum.get_or_insert_with(String::new);
         to_append.push_str(value);
 
-        // since we have a backslash, we need to parse the next character and append that too
-        let (data, escaped_char) = take(1usize)(data)?;
-        to_append.push_str(escaped_char);
+        // since we have a backslash, we need to decode the escape after it
+        let (escaped, len) =
+            unescape(data).ok_or_else(|| nom::Err::Error(Error::new(data, ErrorKind::Escaped)))?;
+        to_append.push(escaped);
 
         // Move the head forward and look for the next one.
-        head = data;
+        head = &data[len..];
     }
 }
 
@@ -89,7 +218,7 @@ fn unquoted_string(data: &str) -> IResult<&str, StringOrStr> {
     Ok((data, value.into()))
 }
 
-fn str_to_key_value(data: &str) -> IResult<&str, ParseMap> {
+fn str_to_key_value(data: &str, encoding: ValueEncoding) -> IResult<&str, ParseMap> {
     let mut key_values = HashMap::new();
 
     let mut head = data;
@@ -105,7 +234,7 @@ fn str_to_key_value(data: &str) -> IResult<&str, ParseMap> {
 
         // parse key, letters, numbers, underscores, dashes
         let (data, key) =
-            take_while(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-')(data)?;
+            take_while1(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-')(data)?;
 
         // parse =
         let (data, _) = multispace0(data)?;
@@ -119,8 +248,22 @@ fn str_to_key_value(data: &str) -> IResult<&str, ParseMap> {
             _ => unquoted_string(data),
         }?;
 
-        // insert into map
-        key_values.insert(key, value);
+        // Percent-encoded values are never quoted
+        let value = match value {
+            StringOrStr::Str(encoded)
+                if encoding == ValueEncoding::Percent && encoded.contains('%') =>
+            {
+                percent_decode(encoded)
+                    .ok_or_else(|| nom::Err::Error(Error::new(encoded, ErrorKind::Escaped)))?
+                    .into()
+            }
+            value => value,
+        };
+
+        // insert into map, unless the key was already given
+        if key_values.insert(key, value).is_some() {
+            return Err(nom::Err::Error(Error::new(key, ErrorKind::Verify)));
+        }
         head = data;
     }
 
@@ -231,4 +374,92 @@ mod tests {
             key_values
         );
     }
+
+    /// Strings of up to three characters of an alphabet picked to trip up
+    /// the encodings.
+    fn strings(alphabet: &[char]) -> Vec<String> {
+        let mut strings = vec![String::new()];
+        let mut shorter = strings.clone();
+        for _ in 0..3 {
+            shorter = shorter
+                .iter()
+                .flat_map(|s| alphabet.iter().map(move |c| format!("{s}{c}")))
+                .collect();
+            strings.extend(shorter.iter().cloned());
+        }
+        strings
+    }
+
+    fn round_trip(value: &str, encoding: ValueEncoding) {
+        let line = format!(
+            "key={} next={}",
+            encode_value(value, encoding),
+            encode_value(value, encoding)
+        );
+        let mut key_values = ParseMap::parse(&line, encoding)
+            .unwrap_or_else(|e| panic!("Failed to parse {line:?}: {e:?}"));
+        assert_eq!(key_values.len(), 2, "{line:?}");
+        assert_eq!(key_values.get("key").unwrap().as_str(), value, "{line:?}");
+        assert_eq!(key_values.get("next").unwrap().as_str(), value, "{line:?}");
+    }
+
+    #[test]
+    fn test_quoted_round_trip() {
+        let alphabet = [' ', '\t', '\n', '\u{1}', '"', '\\', '=', '%', 'u', '{', 'é'];
+        for value in strings(&alphabet) {
+            round_trip(&value, ValueEncoding::Quoted);
+        }
+    }
+
+    #[test]
+    fn test_percent_round_trip() {
+        let alphabet = [
+            ' ', '\n', '\r', '"', '\\', '=', '%', 'a', '0', '~', 'é', '€',
+        ];
+        for value in strings(&alphabet) {
+            round_trip(&value, ValueEncoding::Percent);
+        }
+        for c in (0..=0x7f)
+            .filter_map(char::from_u32)
+            .chain(['\u{a0}', '😀'])
+        {
+            let value = c.to_string();
+            let encoded = encode_value(&value, ValueEncoding::Percent);
+            assert!(!encoded.contains(char::is_whitespace), "{encoded:?}");
+            round_trip(&value, ValueEncoding::Percent);
+        }
+    }
+
+    #[test]
+    fn test_escapes_and_duplicate_keys() {
+        let data = r#"a="x\ny\t\u{e9}\u{1F600}\q" b="k=v" c=d=e"#;
+        let mut key_values = ParseMap::try_from(data).unwrap();
+        assert_eq!(key_values.get("a").unwrap().as_str(), "x\ny\té😀q");
+        assert_eq!(key_values.get("b").unwrap().as_str(), "k=v");
+        assert_eq!(key_values.get("c").unwrap().as_str(), "d=e");
+
+        for data in [
+            "key=1 key=2",
+            "=value",
+            r#"key="\u{d800}""#,
+            r#"key="\u{}""#,
+            r#"key="\u{+41}""#,
+            r#"key="\u{41""#,
+            r#"key="\"#,
+        ] {
+            assert!(ParseMap::try_from(data).is_err(), "{data}");
+        }
+    }
+
+    #[test]
+    fn test_bad_percent_encoding_fails() {
+        for data in ["key=%4", "key=%zz", "key=%ff"] {
+            assert!(
+                ParseMap::parse(data, ValueEncoding::Percent).is_err(),
+                "{data}"
+            );
+        }
+        // Without percent encoding these are just values
+        assert!(ParseMap::try_from("key=%4").is_ok());
+    }
 }
@@ -0,0 +1,69 @@
+//! How the buttons, LCD strip and encoders of a deck map to Companion keys.
+//!
+//! Companion sees every device as a grid of keys.  The buttons of the deck
+//! come first, followed by a row of keys for the segments of the LCD strip
+//! (if it has one) and a row for the encoders (if it has any).  On a Plus that
+//! puts the strip at keys 8 to 11 and the encoders at keys 12 to 15.
+
+use elgato_streamdeck::info::Kind;
+
+/// Number of keys in the LCD strip row.
+fn lcd_row(kind: Kind) -> u8 {
+    match kind.lcd_strip_size() {
+        Some(_) => kind.column_count(),
+        None => 0,
+    }
+}
+
+/// Number of keys in the encoder row.
+fn encoder_row(kind: Kind) -> u8 {
+    match kind.encoder_count() {
+        0 => 0,
+        encoders => encoders.max(kind.column_count()),
+    }
+}
+
+/// Total number of keys Companion should lay out for the deck.
+pub fn keys_total(kind: Kind) -> u8 {
+    kind.key_count() + lcd_row(kind) + encoder_row(kind)
+}
+
+/// The segment of the LCD strip shown by a Companion key, if any.
+pub fn lcd_segment(kind: Kind, key: u8) -> Option<u8> {
+    key.checked_sub(kind.key_count())
+        .filter(|segment| *segment < lcd_row(kind))
+}
+
+/// The Companion key of the LCD strip segment at horizontal position x.
+pub fn touch_key(kind: Kind, x: u16) -> Option<u8> {
+    let (width, _) = kind.lcd_strip_size()?;
+    let segment = usize::from(x) * usize::from(lcd_row(kind)) / width.max(1);
+    let segment = u8::try_from(segment)
+        .ok()?
+        .min(lcd_row(kind).checked_sub(1)?);
+    Some(kind.key_count() + segment)
+}
+
+/// The Companion key an encoder is pressed and rotated as.
+pub fn encoder_key(kind: Kind, encoder: u8) -> u8 {
+    kind.key_count() + lcd_row(kind) + encoder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plus_layout() {
+        assert_eq!(keys_total(Kind::Plus), 16);
+        assert_eq!(lcd_segment(Kind::Plus, 9), Some(1));
+        assert_eq!(lcd_segment(Kind::Plus, 12), None);
+        assert_eq!(encoder_key(Kind::Plus, 0), 12);
+        assert_eq!(touch_key(Kind::Plus, 0), Some(8));
+        assert_eq!(touch_key(Kind::Plus, 799), Some(11));
+
+        assert_eq!(keys_total(Kind::Original), Kind::Original.key_count());
+        assert_eq!(lcd_segment(Kind::Original, 15), None);
+        assert_eq!(touch_key(Kind::Original, 0), None);
+    }
+}
@@ -1,9 +1,31 @@
 use anyhow::Result;
 use common::StringOrStr;
 mod keyvalue;
+use device_info::DeviceInfo;
+pub use keyvalue::{encode_value, ValueEncoding};
+use version::{Features, ProtocolVersion, UnsupportedVersion};
 
+pub mod cache;
+pub mod device_info;
+pub mod disk_cache;
+pub mod fonts;
+pub mod frontend;
+pub mod images;
+pub mod layout;
+pub mod lint;
+pub mod liveness;
+pub mod messages;
+pub mod mux;
+#[cfg(windows)]
+#[cfg_attr(docsrs, doc(cfg(windows)))]
+pub mod pipe;
 pub mod receiver;
+pub mod render;
 pub mod sender;
+pub mod version;
+#[cfg(feature = "websocket")]
+#[cfg_attr(docsrs, doc(cfg(feature = "websocket")))]
+pub mod websocket;
 
 use tokio::net::ToSocketAddrs;
 
@@ -13,14 +35,124 @@ pub async fn connect(
 ) -> Result<(
     impl traits::companion::Sender,
     impl traits::companion::Receiver,
+)> {
+    connect_with_config(addr, config, sender::SenderConfig::default()).await
+}
+
+/// Connect to Companion, pinging and flushing input as sender_config says.
+pub async fn connect_with_config(
+    addr: impl ToSocketAddrs,
+    config: traits::device::RemoteConfig,
+    sender_config: sender::SenderConfig,
+) -> Result<(
+    impl traits::companion::Sender,
+    impl traits::companion::Receiver,
+)> {
+    connect_with_cache(addr, config, sender_config, cache::ImageCache::default()).await
+}
+
+/// Connect to Companion, looking up converted images in the provided cache.
+/// Passing a clone of the same cache to every connection keeps it warm
+/// across reconnects.
+pub async fn connect_with_cache(
+    addr: impl ToSocketAddrs,
+    config: traits::device::RemoteConfig,
+    sender_config: sender::SenderConfig,
+    image_cache: cache::ImageCache,
+) -> Result<(
+    impl traits::companion::Sender,
+    impl traits::companion::Receiver,
+)> {
+    let (companion_reader, companion_writer) =
+        tokio::net::TcpStream::connect(addr).await?.into_split();
+    connect_over(
+        companion_reader,
+        companion_writer,
+        config,
+        sender_config,
+        image_cache,
+        None,
+    )
+    .await
+}
+
+/// Connect to Companion as [connect_with_cache] does, applying settings to
+/// the images and brightness sent to the device.
+pub async fn connect_with_settings(
+    addr: impl ToSocketAddrs,
+    config: traits::device::RemoteConfig,
+    sender_config: sender::SenderConfig,
+    image_cache: cache::ImageCache,
+    settings: tokio::sync::watch::Receiver<traits::device::DeviceSettings>,
+) -> Result<(
+    impl traits::companion::Sender,
+    impl traits::companion::Receiver,
 )> {
     let (companion_reader, companion_writer) =
         tokio::net::TcpStream::connect(addr).await?.into_split();
+    connect_over(
+        companion_reader,
+        companion_writer,
+        config,
+        sender_config,
+        image_cache,
+        Some(settings),
+    )
+    .await
+}
 
+/// Connect to Companion through a WebSocket at url, tunnelling the lines of
+/// the satellite API as [websocket] messages.  Otherwise the same as
+/// [connect_with_cache].
+#[cfg(feature = "websocket")]
+#[cfg_attr(docsrs, doc(cfg(feature = "websocket")))]
+pub async fn connect_ws(
+    url: &str,
+    config: traits::device::RemoteConfig,
+    sender_config: sender::SenderConfig,
+    image_cache: cache::ImageCache,
+) -> Result<(
+    impl traits::companion::Sender,
+    impl traits::companion::Receiver,
+)> {
+    let (companion_reader, companion_writer) = websocket::connect(url).await?;
+    connect_over(
+        companion_reader,
+        companion_writer,
+        config,
+        sender_config,
+        image_cache,
+        None,
+    )
+    .await
+}
+
+/// Talk to Companion over an already open connection, such as a
+/// named pipe on Windows, applying settings to the images and
+/// brightness sent to the device if given.  The receiver is returned as
+/// it is, for callers to tune further.
+pub async fn connect_over<R>(
+    companion_reader: R,
+    companion_writer: impl tokio::io::AsyncWrite + Unpin + Send + 'static,
+    config: traits::device::RemoteConfig,
+    sender_config: sender::SenderConfig,
+    image_cache: cache::ImageCache,
+    settings: Option<tokio::sync::watch::Receiver<traits::device::DeviceSettings>>,
+) -> Result<(impl traits::companion::Sender, receiver::Receiver<R>)>
+where
+    R: tokio::io::AsyncRead + Unpin + Send,
+{
     let kind = elgato_streamdeck::info::Kind::from_pid(config.pid)
         .ok_or_else(|| anyhow::anyhow!("Unknown pid {}", config.pid))?;
-    let companion_receiver = receiver::Receiver::new(companion_reader, kind);
-    let companion_sender = sender::Sender::new(companion_writer, config).await?;
+    let companion_receiver = receiver::Receiver::new(companion_reader, kind)
+        .with_liveness(liveness::Liveness::default())
+        .with_cache(image_cache);
+    let companion_receiver = match settings {
+        Some(settings) => companion_receiver.with_settings(settings),
+        None => companion_receiver,
+    };
+    let companion_sender =
+        sender::Sender::with_config(companion_writer, config, sender_config, String::new).await?;
     Ok((companion_sender, companion_receiver))
 }
 
@@ -34,18 +166,56 @@ pub enum Command<'a> {
     AddDevice(AddDevice<'a>),
     KeyState(KeyState<'a>),
     Brightness(Brightness<'a>),
+    KeysClear(KeysClear<'a>),
+    LockedState(LockedState<'a>),
     Unknown(&'a str),
 }
+
+/// Commands of key=value pairs that are parsed, rather than passed over.
+const KEYED_COMMANDS: [&str; 6] = [
+    "BEGIN",
+    "ADD-DEVICE",
+    "KEY-STATE",
+    "BRIGHTNESS",
+    "KEYS-CLEAR",
+    "LOCKED-STATE",
+];
+
 /// Parse the incoming line of data into a command.
 /// This will return an error if the command is not
 /// formatted as expected.
 impl Command<'_> {
     pub fn parse(in_data: &str) -> Result<Command<'_>> {
+        Self::parse_with(in_data, ValueEncoding::Quoted)
+    }
+
+    /// Whether the command leaves what the device shows stale, so it is to
+    /// be blanked: Companion cleared its keys, or just locked it.  Locking
+    /// is said again as each digit of the PIN is entered, over the keypad
+    /// Companion draws, which is left alone.
+    pub fn blanks_device(&self) -> bool {
+        match self {
+            Command::KeysClear(_) => true,
+            Command::LockedState(state) => state.locked && state.characters.unwrap_or(0) == 0,
+            _ => false,
+        }
+    }
+
+    /// Parse a line as [Self::parse_with] does, but fail on anything outside
+    /// the grammar of the satellite API with a [lint::Diagnostic] saying
+    /// where and what was expected.
+    pub fn parse_strict(in_data: &str, encoding: ValueEncoding) -> Result<Command<'_>> {
+        lint::check(in_data, encoding)?;
+        Self::parse_with(in_data, encoding)
+    }
+
+    /// Parse a line whose values are encoded as negotiated with Companion.
+    pub fn parse_with(in_data: &str, encoding: ValueEncoding) -> Result<Command<'_>> {
         let data = in_data;
-        // command is up to the first space.  Don't use split_once because
-        // there may not be a space to split on.
+        // command is up to the first space, or the end of the line.  Don't
+        // use split_once because there may not be a space to split on.
         let command = data
-            .split(' ')
+            .split(|c: char| c.is_ascii_whitespace())
             .next()
             .ok_or_else(|| anyhow::anyhow!("No command"))?;
 
@@ -59,7 +229,10 @@ impl Command<'_> {
             "PONG" => return Ok(Command::Pong),
             "KEY-PRESS" => return Ok(Command::KeyPress(data)),
             "KEY-ROTATE" => return Ok(Command::KeyRotate(data)),
-            _ => {}
+            _ if KEYED_COMMANDS.contains(&command) => {}
+            // Whatever a command added by a newer Companion carries is left
+            // alone, so it is ignored rather than failing the connection
+            _ => return Ok(Command::Unknown(command)),
         }
 
         // annoying!, ADD-DEVICE has an extra OK value that doesn't match the key=value format
@@ -81,7 +254,7 @@ impl Command<'_> {
         // parse key values specially.  This handles quotes, escapes,
         // and other nonsense.  Returns a map of key value pairs (but
         // optimized to be as zero-copy as possible).
-        let mut key_values = keyvalue::ParseMap::try_from(data)
+        let mut key_values = keyvalue::ParseMap::parse(data, encoding)
             .map_err(|e| anyhow::anyhow!("Error parsing key values: {}", e))?;
 
         // helper function to get a value from the key value map (reduces code-noise below)
@@ -104,7 +277,10 @@ impl Command<'_> {
                     .parse()
                     .map_err(|_| anyhow::anyhow!("Could not parse key"))?,
                 button_type: get("TYPE")?,
-                bitmap_base64: get("BITMAP")?,
+                // Devices without a screen are added without bitmaps
+                bitmap_base64: get("BITMAP").unwrap_or(StringOrStr::Str("")),
+                color: get("COLOR").ok(),
+                text: get("TEXT").ok(),
                 pressed: get("PRESSED")?.as_str() == "true",
             }),
             "ADD-DEVICE" => Command::AddDevice(AddDevice {
@@ -118,6 +294,18 @@ impl Command<'_> {
                     .parse()
                     .map_err(|_| anyhow::anyhow!("Could not parse brightness"))?,
             }),
+            "KEYS-CLEAR" => Command::KeysClear(KeysClear {
+                device: get("DEVICEID")?,
+            }),
+            "LOCKED-STATE" => Command::LockedState(LockedState {
+                device: get("DEVICEID")?,
+                locked: get("LOCKED")?.as_str() == "true",
+                characters: get("CHARACTERS")
+                    .ok()
+                    .map(|characters| characters.as_str().parse())
+                    .transpose()
+                    .map_err(|_| anyhow::anyhow!("Could not parse characters"))?,
+            }),
             _ => Command::Unknown(command),
         };
 
@@ -140,19 +328,48 @@ pub struct KeyState<'a> {
     pub key: u8,
     pub button_type: StringOrStr<'a>,
     pub bitmap_base64: StringOrStr<'a>,
+    /// Background color as `#rrggbb`, if the device asked for colors
+    pub color: Option<StringOrStr<'a>>,
+    /// Text on the key, if the device asked for text
+    pub text: Option<StringOrStr<'a>>,
     pub pressed: bool,
 }
 impl KeyState<'_> {
     pub fn bitmap(&self) -> Result<Vec<u8>> {
-        use base64::Engine as _;
         let mut buf = Vec::new();
+        self.bitmap_into(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Decode the bitmap into buf, replacing what it held but reusing its
+    /// allocation.
+    pub fn bitmap_into(&self, buf: &mut Vec<u8>) -> Result<()> {
+        use base64::Engine as _;
+        buf.clear();
         match base64::engine::general_purpose::STANDARD_NO_PAD
-            .decode_vec(self.bitmap_base64.as_ref().as_bytes(), &mut buf)
+            .decode_vec(self.bitmap_base64.as_ref().as_bytes(), buf)
         {
-            Ok(_) => Ok(buf),
+            Ok(_) => Ok(()),
             Err(_) => anyhow::bail!("Error decoding bitmap"),
         }
     }
+
+    /// The background color as RGB, if there is one.
+    pub fn rgb(&self) -> Result<Option<[u8; 3]>> {
+        let Some(color) = &self.color else {
+            return Ok(None);
+        };
+        let hex = color
+            .as_str()
+            .strip_prefix('#')
+            .filter(|hex| hex.len() == 6)
+            .ok_or_else(|| anyhow::anyhow!("Expected a color as #rrggbb, got {:?}", color))?;
+        let channel = |i: usize| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| anyhow::anyhow!("Expected a color as #rrggbb, got {:?}", color))
+        };
+        Ok(Some([channel(0)?, channel(2)?, channel(4)?]))
+    }
 }
 
 impl std::fmt::Debug for KeyState<'_> {
@@ -162,6 +379,8 @@ impl std::fmt::Debug for KeyState<'_> {
             .field("key", &self.key)
             .field("button_type", &self.button_type)
             .field("len(bitmap_base64)", &self.bitmap_base64.len())
+            .field("color", &self.color)
+            .field("text", &self.text)
             .field("pressed", &self.pressed)
             .finish()
     }
@@ -173,6 +392,21 @@ pub struct Brightness<'a> {
     pub brightness: u8,
 }
 
+/// Companion blanked every key of the device.
+#[derive(Debug, PartialEq, Eq)]
+pub struct KeysClear<'a> {
+    pub device: StringOrStr<'a>,
+}
+
+/// Companion locked or unlocked the device behind its PIN.
+#[derive(Debug, PartialEq, Eq)]
+pub struct LockedState<'a> {
+    pub device: StringOrStr<'a>,
+    pub locked: bool,
+    /// Digits of the PIN entered so far, while locked
+    pub characters: Option<u8>,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct AddDevice<'a> {
     pub success: bool,
@@ -185,6 +419,32 @@ pub struct Versions<'a> {
     pub api_version: StringOrStr<'a>,
 }
 
+impl Versions<'_> {
+    /// The satellite API Companion speaks, failing if it can't be talked to.
+    pub fn protocol_version(&self) -> Result<ProtocolVersion, UnsupportedVersion> {
+        self.api_version
+            .as_str()
+            .parse::<ProtocolVersion>()?
+            .check()
+    }
+
+    /// What Companion understands, failing fast if it is too old or too new.
+    pub fn negotiate(&self) -> Result<Features, UnsupportedVersion> {
+        Ok(self.protocol_version()?.features())
+    }
+
+    /// The encoding of values Companion can be asked for, going by its
+    /// ApiVersion.  Versions that don't parse get the quoting every version
+    /// understands.
+    pub fn value_encoding(&self) -> ValueEncoding {
+        match self.api_version.as_str().parse::<ProtocolVersion>() {
+            Ok(version) => version.features().value_encoding,
+            Err(_) => ValueEncoding::Quoted,
+        }
+    }
+}
+
+/// The fields of an ADD-DEVICE, built from the [DeviceInfo] of the device.
 #[derive(Debug, PartialEq, Eq)]
 pub struct DeviceMsg {
     pub device_id: String,
@@ -192,97 +452,79 @@ pub struct DeviceMsg {
     pub keys_total: u8,
     pub keys_per_row: u8,
     pub resolution: u16,
+    /// Whether Companion can set the brightness of the device
+    pub brightness: bool,
+    /// Width and height of the LCD strip, if the device has one
+    pub lcd_strip: Option<(u16, u16)>,
+    /// Name of the software the device is connected through
+    pub module: Option<String>,
+    /// Version of that software
+    pub version: Option<String>,
 }
 impl DeviceMsg {
+    /// The message adding device_id, described by info.
+    pub fn new(device_id: &str, info: &impl DeviceInfo) -> Self {
+        Self {
+            device_id: device_id.to_string(),
+            product_name: info.product_name(),
+            keys_total: info.keys_total(),
+            keys_per_row: info.keys_per_row(),
+            resolution: info.resolution(),
+            brightness: info.supports_brightness(),
+            lcd_strip: info.lcd_strip(),
+            module: None,
+            version: None,
+        }
+    }
+
+    /// Name the software the device is connected through.
+    pub fn with_module(mut self, module: &str) -> Self {
+        self.module = Some(module.to_string());
+        self
+    }
+
+    /// Give the version of the software the device is connected through.
+    pub fn with_version(mut self, version: &str) -> Self {
+        self.version = Some(version.to_string());
+        self
+    }
+
     pub fn device_msg(&self) -> String {
-        format!("DEVICEID={} PRODUCT_NAME=\"{}\" KEYS_TOTAL={}, KEYS_PER_ROW={} BITMAPS={} COLORS=0 TEXT=0",
-            self.device_id, self.product_name, self.keys_total, self.keys_per_row, self.resolution)
+        self.device_msg_with(Features::default())
     }
-}
 
-// match command {
-//     Command::Pong => {}
-//     _ => debug!("Received companion command: {:?}", command),
-// }
-
-// match command {
-//     Command::Pong => {}
-//     Command::KeyPress(_) => {}
-//     Command::KeyRotate(_) => {}
-//     Command::Begin(_) => {}
-//     Command::AddDevice(_) => {}
-//     Command::KeyState(keystate) => {
-//         let in_button_range = (keystate.key < kind.key_count()).then_some(keystate.key);
-
-//         let in_lcd_button = if in_button_range.is_some() {
-//             None
-//         } else {
-//             kind.lcd_strip_size()
-//                 .map(|_| kind.key_count() - keystate.key)
-//                 .filter(|index| index < &kind.column_count())
-//         };
-//         // Run this in a separate task because it can be CPU intensive
-//         // and we want to allow many image processing tasks to happen in
-//         // parallel if it can.
-//         match (in_button_range, in_lcd_button) {
-//             (Some(key), _) => {
-//                 debug!("Writing image to button");
-//                 let image = image::DynamicImage::ImageRgb8(
-//                     image::ImageBuffer::from_vec(120, 120, keystate.bitmap()?)
-//                         .ok_or_else(|| anyhow::anyhow!("Couldn't extract image buffer"))?,
-//                 );
-//                 let image = image.resize_exact(
-//                     image_format.size.0 as u32,
-//                     image_format.size.1 as u32,
-//                     image::imageops::FilterType::Lanczos3,
-//                 );
-//                 // Convert the image into the EXACT format needed for the remote device
-//                 let image = elgato_streamdeck::images::convert_image(kind, image)?;
-//                 // Send this to the satellite
-//                 streamdeck_device.set_button_image(key, image).await?;
-//             }
-//             (None, Some(lcd_key)) => {
-//                 debug!("Writing image to LCD panel");
-
-//                 let (lcd_width, lcd_height) = kind.lcd_strip_size().unwrap_or((0, 0));
-//                 let (lcd_width, lcd_height) = (lcd_width as u32, lcd_height as u32);
-
-//                 let image = image::DynamicImage::ImageRgb8(
-//                     image::ImageBuffer::from_vec(120, 120, keystate.bitmap()?).unwrap(),
-//                 );
-//                 // resize image to the height
-//                 let image = image.resize(
-//                     lcd_height,
-//                     lcd_height,
-//                     image::imageops::FilterType::Gaussian,
-//                 );
-//                 let button_x_offset = (lcd_key as u32 - 8) * ((lcd_width - image.width()) / 3);
-
-//                 // Convert the image into the EXACT format needed for the remote device
-//                 let image = elgato_streamdeck::images::convert_image(kind, image)?;
-//                 streamdeck_device
-//                     .set_lcd_image(
-//                         button_x_offset.try_into()?,
-//                         lcd_height.try_into()?,
-//                         lcd_height.try_into()?,
-//                         image,
-//                     )
-//                     .await?;
-//             }
-//             _ => {
-//                 debug!("Key out of range {:?}", keystate);
-//             }
-//         }
-//     }
-//     Command::Brightness(brightness) => {
-//         device
-//             .set_brightness(traits::device::SetBrightness {
-//                 brightness: brightness.brightness,
-//             })
-//             .await?;
-//     }
-//     Command::Unknown(_) => todo!(),
-// }
+    /// The message for a Companion with features.  Images are only asked
+    /// for if it has them and the device has a resolution to show them at,
+    /// and so are colors and text, which are drawn on keys without images.
+    /// The optional fields are only sent to Companions that take them.
+    pub fn device_msg_with(&self, features: Features) -> String {
+        let bitmaps = if features.bitmaps { self.resolution } else { 0 };
+        let drawn = self.resolution > 0;
+        let mut msg = format!(
+            "DEVICEID={} PRODUCT_NAME={} KEYS_TOTAL={}, KEYS_PER_ROW={} BITMAPS={} COLORS={} TEXT={}",
+            encode_value(&self.device_id, ValueEncoding::Quoted),
+            encode_value(&self.product_name, ValueEncoding::Quoted),
+            self.keys_total,
+            self.keys_per_row,
+            bitmaps,
+            u8::from(features.colors && drawn),
+            u8::from(features.text && drawn),
+        );
+        if features.device_details {
+            msg += &format!(" BRIGHTNESS={}", u8::from(self.brightness));
+            if let Some((width, height)) = self.lcd_strip {
+                msg += &format!(" LCD_STRIP={width}x{height}");
+            }
+            if let Some(module) = &self.module {
+                msg += &format!(" MODULE={}", encode_value(module, ValueEncoding::Quoted));
+            }
+            if let Some(version) = &self.version {
+                msg += &format!(" VERSION={}", encode_value(version, ValueEncoding::Quoted));
+            }
+        }
+        msg
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -293,6 +535,7 @@ mod tests {
         const DATA: &str = "PONG";
         let command = Command::parse(DATA).unwrap();
         assert_eq!(command, Command::Pong);
+        assert_eq!(Command::parse("PONG\n").unwrap(), Command::Pong);
     }
 
     #[test]
@@ -333,9 +576,56 @@ mod tests {
                 key: 14,
                 button_type: "BUTTON".into(),
                 bitmap_base64: "rawdata".into(),
+                color: None,
+                text: None,
                 pressed: false
             })
         );
+
+        // Drawn here from the color and text instead of a bitmap
+        let command = Command::parse(
+            "KEY-STATE DEVICEID=deck KEY=2 TYPE=BUTTON COLOR=#ff8000 TEXT=\"Mic 1\" PRESSED=false",
+        );
+        match command.unwrap() {
+            Command::KeyState(keystate) => {
+                assert_eq!(keystate.rgb().unwrap(), Some([0xff, 0x80, 0x00]));
+                assert_eq!(keystate.text.unwrap().as_str(), "Mic 1");
+            }
+            command => panic!("Unexpected command {command:?}"),
+        }
+
+        // A Pedal is added without bitmaps
+        let command = Command::parse("KEY-STATE DEVICEID=pedal KEY=1 TYPE=BUTTON PRESSED=true");
+        match command.unwrap() {
+            Command::KeyState(keystate) => assert!(keystate.bitmap_base64.is_empty()),
+            command => panic!("Unexpected command {command:?}"),
+        }
+    }
+
+    #[test]
+    fn test_lock_and_clear() {
+        assert_eq!(
+            Command::parse("KEYS-CLEAR DEVICEID=deck").unwrap(),
+            Command::KeysClear(KeysClear {
+                device: "deck".into()
+            })
+        );
+        assert_eq!(
+            Command::parse("LOCKED-STATE DEVICEID=deck LOCKED=true CHARACTERS=2").unwrap(),
+            Command::LockedState(LockedState {
+                device: "deck".into(),
+                locked: true,
+                characters: Some(2),
+            })
+        );
+        let unlocked = Command::parse("LOCKED-STATE DEVICEID=deck LOCKED=false").unwrap();
+        assert!(!unlocked.blanks_device());
+
+        // Commands of newer Companions are passed over, whatever they carry
+        assert_eq!(
+            Command::parse("VARIABLE-VALUE DEVICEID=deck VARIABLE=tally VALUE=\"on air\"").unwrap(),
+            Command::Unknown("VARIABLE-VALUE")
+        );
     }
 
     #[test]
@@ -360,4 +650,31 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn test_value_encoding_negotiated() {
+        let encoding = |api_version: &'static str| {
+            let versions = Versions {
+                companion_version: "3.99.0".into(),
+                api_version: api_version.into(),
+            };
+            versions.value_encoding()
+        };
+        assert_eq!(encoding("1.5.1"), ValueEncoding::Quoted);
+        assert_eq!(encoding("1.8.0"), ValueEncoding::Percent);
+        assert_eq!(encoding("2.0.0-beta"), ValueEncoding::Percent);
+        assert_eq!(encoding("garbage"), ValueEncoding::Quoted);
+
+        let command = Command::parse_with(
+            "BRIGHTNESS DEVICEID=My%20Deck VALUE=50",
+            ValueEncoding::Percent,
+        );
+        assert_eq!(
+            command.unwrap(),
+            Command::Brightness(Brightness {
+                device: "My Deck".into(),
+                brightness: 50
+            })
+        );
+    }
 }
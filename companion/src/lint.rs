@@ -0,0 +1,511 @@
+//! Strict checking of the lines Companion sends.
+//!
+//! [Command::parse_with] is forgiving: commands it doesn't know come back as
+//! [Command::Unknown], and when a line doesn't parse all it can say is that
+//! nom gave up somewhere.  That is what a satellite wants, but not what
+//! someone working out why a new build of Companion upsets it wants.
+//! [check] holds a line to the grammar of the satellite API instead, and
+//! fails with a [Diagnostic] naming the byte offset, the offending token and
+//! the production that was expected there.  A [Linter] does the same for a
+//! captured log, following the value encoding its BEGIN negotiates.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::io::BufRead;
+
+use anyhow::Result;
+use base64::Engine as _;
+
+use crate::{Command, ValueEncoding};
+
+/// Longest token kept in a diagnostic, in characters.  Bitmaps run to
+/// kilobytes.
+const MAX_TOKEN: usize = 32;
+
+const COMMAND: &str = "command = PONG | BEGIN | ADD-DEVICE | KEY-STATE | BRIGHTNESS | KEYS-CLEAR \
+    | LOCKED-STATE | KEY-PRESS | KEY-ROTATE";
+const STATUS: &str = "status = OK | ERROR";
+const KEY: &str = "key = (letter | digit | '_' | '-')+";
+const EQUALS: &str = "'=' after the key";
+const SEPARATOR: &str = "whitespace between pairs";
+const CLOSING_QUOTE: &str = "quoted = '\"' (char | escape)* '\"'";
+const ESCAPE: &str = "escape = '\\' (char | 'u{' hex+ '}')";
+const PERCENT_ESCAPE: &str = "escape = '%' hex hex";
+const UTF8: &str = "UTF-8 once percent-decoded";
+const END: &str = "end of line";
+
+/// Where a line strays from the grammar, and what was expected there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Line of the log, counting from 1, or 0 for a line checked on its own
+    pub line: usize,
+    /// Byte offset of the token into the line
+    pub offset: usize,
+    /// The offending token, empty at the end of the line
+    pub token: String,
+    /// The production expected at the offset
+    pub expected: Cow<'static, str>,
+}
+
+impl Diagnostic {
+    fn new(line: &str, offset: usize, expected: impl Into<Cow<'static, str>>) -> Self {
+        let rest = &line[offset..];
+        let token = match rest.find(char::is_whitespace) {
+            Some(0) => &rest[..rest.chars().next().map_or(0, char::len_utf8)],
+            Some(end) => &rest[..end],
+            None => rest,
+        };
+        let mut token: String = token.chars().take(MAX_TOKEN).collect();
+        if token.len() < rest.len() && !rest[token.len()..].starts_with(char::is_whitespace) {
+            token.push_str("...");
+        }
+        Self {
+            line: 0,
+            offset,
+            token,
+            expected: expected.into(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line > 0 {
+            write!(f, "line {}, ", self.line)?;
+        }
+        write!(
+            f,
+            "byte {}: expected {}, found ",
+            self.offset, self.expected
+        )?;
+        if self.token.is_empty() {
+            write!(f, "end of line")
+        } else {
+            write!(f, "{:?}", self.token)
+        }
+    }
+}
+
+impl std::error::Error for Diagnostic {}
+
+/// What follows the name of a command.
+enum Args {
+    /// Nothing at all
+    Nothing,
+    /// Free text, as in the answers to our input
+    Text,
+    /// key=value pairs, after an OK or ERROR if there is a status
+    Pairs { status: bool, keys: &'static [Key] },
+}
+
+/// A key a command may have.
+struct Key {
+    name: &'static str,
+    value: Value,
+    required: bool,
+}
+
+/// What a value must look like, once unquoted or decoded.
+#[derive(Clone, Copy)]
+enum Value {
+    Text,
+    Byte,
+    Bool,
+    Color,
+    Base64,
+}
+
+impl Value {
+    fn production(self) -> &'static str {
+        match self {
+            Value::Text => "text",
+            Value::Byte => "digit+ in 0..=255",
+            Value::Bool => "true | false",
+            Value::Color => "'#' hex{6}",
+            Value::Base64 => "base64 without padding",
+        }
+    }
+
+    fn matches(self, value: &str) -> bool {
+        match self {
+            Value::Text => true,
+            Value::Byte => value.bytes().all(|b| b.is_ascii_digit()) && value.parse::<u8>().is_ok(),
+            Value::Bool => value == "true" || value == "false",
+            Value::Color => value
+                .strip_prefix('#')
+                .is_some_and(|hex| hex.len() == 6 && hex.bytes().all(|b| b.is_ascii_hexdigit())),
+            Value::Base64 => base64::engine::general_purpose::STANDARD_NO_PAD
+                .decode(value)
+                .is_ok(),
+        }
+    }
+}
+
+const fn key(name: &'static str, value: Value, required: bool) -> Key {
+    Key {
+        name,
+        value,
+        required,
+    }
+}
+
+/// Every command Companion sends a satellite, and what follows it.
+const GRAMMAR: &[(&str, Args)] = &[
+    ("PONG", Args::Nothing),
+    ("KEY-PRESS", Args::Text),
+    ("KEY-ROTATE", Args::Text),
+    (
+        "BEGIN",
+        Args::Pairs {
+            status: false,
+            keys: &[
+                key("CompanionVersion", Value::Text, true),
+                key("ApiVersion", Value::Text, true),
+            ],
+        },
+    ),
+    (
+        "ADD-DEVICE",
+        Args::Pairs {
+            status: true,
+            keys: &[key("DEVICEID", Value::Text, true)],
+        },
+    ),
+    (
+        "KEY-STATE",
+        Args::Pairs {
+            status: false,
+            keys: &[
+                key("DEVICEID", Value::Text, true),
+                key("KEY", Value::Byte, true),
+                key("TYPE", Value::Text, true),
+                key("BITMAP", Value::Base64, false),
+                key("COLOR", Value::Color, false),
+                key("TEXT", Value::Text, false),
+                key("PRESSED", Value::Bool, true),
+            ],
+        },
+    ),
+    (
+        "BRIGHTNESS",
+        Args::Pairs {
+            status: false,
+            keys: &[
+                key("DEVICEID", Value::Text, true),
+                key("VALUE", Value::Byte, true),
+            ],
+        },
+    ),
+    (
+        "KEYS-CLEAR",
+        Args::Pairs {
+            status: false,
+            keys: &[key("DEVICEID", Value::Text, true)],
+        },
+    ),
+    (
+        "LOCKED-STATE",
+        Args::Pairs {
+            status: false,
+            keys: &[
+                key("DEVICEID", Value::Text, true),
+                key("LOCKED", Value::Bool, true),
+                key("CHARACTERS", Value::Byte, false),
+            ],
+        },
+    ),
+];
+
+/// A key=value pair, with the offsets of its parts.
+struct Pair<'a> {
+    key: &'a str,
+    key_offset: usize,
+    value: Cow<'a, str>,
+    value_offset: usize,
+}
+
+/// Skip the whitespace the key=value parser skips.
+fn skip_space(line: &str, pos: usize) -> usize {
+    line[pos..]
+        .find(|c| !matches!(c, ' ' | '\t' | '\r' | '\n'))
+        .map_or(line.len(), |skipped| pos + skipped)
+}
+
+/// The end of the token at pos, and its value with the quotes and escapes
+/// taken out.
+fn quoted(line: &str, pos: usize) -> Result<(usize, Cow<'_, str>), Diagnostic> {
+    let mut value = String::new();
+    let mut i = pos + 1;
+    while let Some(c) = line[i..].chars().next() {
+        match c {
+            '"' => return Ok((i + 1, value.into())),
+            '\\' => match crate::keyvalue::unescape(&line[i + 1..]) {
+                Some((escaped, len)) => {
+                    value.push(escaped);
+                    i += 1 + len;
+                    continue;
+                }
+                None => return Err(Diagnostic::new(line, i, ESCAPE)),
+            },
+            c => value.push(c),
+        }
+        i += c.len_utf8();
+    }
+    Err(Diagnostic::new(line, pos, CLOSING_QUOTE))
+}
+
+/// The end of the token at pos, and its value decoded as encoding says.
+fn unquoted(
+    line: &str,
+    pos: usize,
+    encoding: ValueEncoding,
+) -> Result<(usize, Cow<'_, str>), Diagnostic> {
+    let end = line[pos..]
+        .find(char::is_whitespace)
+        .map_or(line.len(), |len| pos + len);
+    let raw = &line[pos..end];
+    if encoding != ValueEncoding::Percent || !raw.contains('%') {
+        return Ok((end, raw.into()));
+    }
+    for (i, _) in raw.match_indices('%') {
+        let hex = raw.as_bytes().get(i + 1..i + 3);
+        if !hex.is_some_and(|hex| hex.iter().all(u8::is_ascii_hexdigit)) {
+            return Err(Diagnostic::new(line, pos + i, PERCENT_ESCAPE));
+        }
+    }
+    let value =
+        crate::keyvalue::percent_decode(raw).ok_or_else(|| Diagnostic::new(line, pos, UTF8))?;
+    Ok((end, value.into()))
+}
+
+/// Split the key=value pairs from pos to the end of the line.
+fn pairs(line: &str, mut pos: usize, encoding: ValueEncoding) -> Result<Vec<Pair<'_>>, Diagnostic> {
+    let mut pairs = Vec::new();
+    loop {
+        pos = skip_space(line, pos);
+        if pos == line.len() {
+            return Ok(pairs);
+        }
+        let key_offset = pos;
+        let key_len = line[pos..]
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+            .unwrap_or(line.len() - pos);
+        if key_len == 0 {
+            return Err(Diagnostic::new(line, pos, KEY));
+        }
+        pos = skip_space(line, pos + key_len);
+        if !line[pos..].starts_with('=') {
+            return Err(Diagnostic::new(line, pos, EQUALS));
+        }
+        pos = skip_space(line, pos + 1);
+        let (end, value) = if line[pos..].starts_with('"') {
+            quoted(line, pos)?
+        } else {
+            unquoted(line, pos, encoding)?
+        };
+        if end < line.len() && !line[end..].starts_with(char::is_whitespace) {
+            return Err(Diagnostic::new(line, end, SEPARATOR));
+        }
+        pairs.push(Pair {
+            key: &line[key_offset..key_offset + key_len],
+            key_offset,
+            value,
+            value_offset: pos,
+        });
+        pos = end;
+    }
+}
+
+/// Hold a line from Companion to the grammar of the satellite API, its
+/// values encoded as said.  A trailing line break is allowed.
+pub fn check(line: &str, encoding: ValueEncoding) -> Result<(), Diagnostic> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let name_len = line
+        .find(|c: char| c.is_ascii_whitespace())
+        .unwrap_or(line.len());
+    let name = &line[..name_len];
+    let (_, args) = GRAMMAR
+        .iter()
+        .find(|(command, _)| *command == name)
+        .ok_or_else(|| Diagnostic::new(line, 0, COMMAND))?;
+
+    let mut pos = name_len;
+    let keys = match args {
+        Args::Nothing => {
+            pos = skip_space(line, pos);
+            if pos < line.len() {
+                return Err(Diagnostic::new(line, pos, END));
+            }
+            return Ok(());
+        }
+        Args::Text => return Ok(()),
+        Args::Pairs { status, keys } => {
+            if *status {
+                pos = skip_space(line, pos);
+                let end = line[pos..].find(' ').map_or(line.len(), |len| pos + len);
+                if !matches!(&line[pos..end], "OK" | "ERROR") {
+                    return Err(Diagnostic::new(line, pos, STATUS));
+                }
+                pos = end;
+            }
+            keys
+        }
+    };
+
+    let pairs = pairs(line, pos, encoding)?;
+    let mut seen = Vec::new();
+    for pair in &pairs {
+        let Some(key) = keys.iter().find(|key| key.name == pair.key) else {
+            let names: Vec<_> = keys.iter().map(|key| key.name).collect();
+            let expected = format!("a key of {name}: {}", names.join(" | "));
+            return Err(Diagnostic::new(line, pair.key_offset, expected));
+        };
+        if seen.contains(&key.name) {
+            let expected = format!("{} only once", key.name);
+            return Err(Diagnostic::new(line, pair.key_offset, expected));
+        }
+        seen.push(key.name);
+        if !key.value.matches(&pair.value) {
+            let expected = format!("{} = {}", key.name, key.value.production());
+            return Err(Diagnostic::new(line, pair.value_offset, expected));
+        }
+    }
+    if let Some(missing) = keys
+        .iter()
+        .find(|key| key.required && !seen.contains(&key.name))
+    {
+        let expected = format!("{} = {}", missing.name, missing.value.production());
+        return Err(Diagnostic::new(line, line.len(), expected));
+    }
+    Ok(())
+}
+
+/// Checks the lines of a captured log in turn, reading values as the BEGIN
+/// in it negotiates.  Blank lines are skipped.
+#[derive(Debug, Default)]
+pub struct Linter {
+    line: usize,
+    encoding: ValueEncoding,
+}
+
+impl Linter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check the next line of the log.
+    pub fn check(&mut self, line: &str) -> Result<(), Diagnostic> {
+        self.line += 1;
+        if line.trim().is_empty() {
+            return Ok(());
+        }
+        check(line, self.encoding).map_err(|diagnostic| Diagnostic {
+            line: self.line,
+            ..diagnostic
+        })?;
+        if let Ok(Command::Begin(versions)) = Command::parse_with(line, self.encoding) {
+            self.encoding = versions.value_encoding();
+        }
+        Ok(())
+    }
+}
+
+/// Check every line of a captured log, returning what was wrong with the
+/// lines that don't follow the grammar.
+pub fn lint_log(log: impl BufRead) -> Result<Vec<Diagnostic>> {
+    let mut linter = Linter::new();
+    let mut diagnostics = Vec::new();
+    for line in log.lines() {
+        if let Err(diagnostic) = linter.check(&line?) {
+            diagnostics.push(diagnostic);
+        }
+    }
+    Ok(diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnose(line: &str) -> (usize, String, String) {
+        let diagnostic = check(line, ValueEncoding::Quoted).unwrap_err();
+        (
+            diagnostic.offset,
+            diagnostic.token,
+            diagnostic.expected.into_owned(),
+        )
+    }
+
+    #[test]
+    fn test_strict_diagnostics() {
+        for line in [
+            "PONG\n",
+            "KEY-PRESS OK",
+            "ADD-DEVICE OK DEVICEID=\"deck\"",
+            "LOCKED-STATE DEVICEID=deck LOCKED=true CHARACTERS=2",
+            "KEY-STATE DEVICEID=deck KEY=2 TYPE=BUTTON COLOR=#ff8000 TEXT=\"Mic 1\" PRESSED=false",
+        ] {
+            check(line, ValueEncoding::Quoted).unwrap_or_else(|e| panic!("{line:?}: {e}"));
+        }
+
+        assert_eq!(
+            diagnose("VARIABLE-VALUE"),
+            (0, "VARIABLE-VALUE".into(), COMMAND.into())
+        );
+        assert_eq!(
+            diagnose("ADD-DEVICE Err DEVICEID=deck"),
+            (11, "Err".into(), STATUS.into())
+        );
+        assert_eq!(
+            diagnose("BRIGHTNESS DEVICEID=deck VALUE=300"),
+            (31, "300".into(), "VALUE = digit+ in 0..=255".into())
+        );
+        assert_eq!(
+            diagnose("KEY-STATE DEVICEID=deck KEY=1 TYPE=BUTTON PRESSED={true,false}"),
+            (50, "{true,false}".into(), "PRESSED = true | false".into())
+        );
+        assert_eq!(
+            diagnose("KEY-STATE DEVICEID=deck KEY=1 TYPE=BUTTON"),
+            (41, String::new(), "PRESSED = true | false".into())
+        );
+        assert_eq!(
+            diagnose("BRIGHTNESS DEVICEID=deck VALUE=1 LEVEL=2"),
+            (
+                33,
+                "LEVEL=2".into(),
+                "a key of BRIGHTNESS: DEVICEID | VALUE".into()
+            )
+        );
+        assert_eq!(
+            diagnose("BEGIN CompanionVersion=\"3.1 ApiVersion=1.5.1"),
+            (23, "\"3.1".into(), CLOSING_QUOTE.into())
+        );
+        assert_eq!(
+            diagnose("BEGIN CompanionVersion=\"3.1\"ApiVersion=1.5.1"),
+            (28, "ApiVersion=1.5.1".into(), SEPARATOR.into())
+        );
+
+        let diagnostic = check("BRIGHTNESS DEVICEID=My%2 VALUE=5", ValueEncoding::Percent);
+        assert_eq!(diagnostic.unwrap_err().offset, 22);
+        let long = format!(
+            "KEY-STATE DEVICEID=deck KEY=0 TYPE=BUTTON BITMAP={} PRESSED=false",
+            "A=".repeat(100)
+        );
+        let diagnostic = check(&long, ValueEncoding::Quoted).unwrap_err();
+        assert_eq!(diagnostic.token, format!("{}...", "A=".repeat(16)));
+    }
+
+    #[test]
+    fn test_lint_log_follows_begin() {
+        const LOG: &str = "BEGIN CompanionVersion=3.99.0 ApiVersion=1.8.0\n\
+            \n\
+            BRIGHTNESS DEVICEID=My%20Deck VALUE=50\n\
+            BRIGHTNESS DEVICEID=My%2 VALUE=50\n";
+        let diagnostics = lint_log(LOG.as_bytes()).unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "line 4, byte 22: expected escape = '%' hex hex, found \"%2\""
+        );
+    }
+}
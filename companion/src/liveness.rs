@@ -0,0 +1,86 @@
+//! Detection of a Companion that stopped answering.
+//!
+//! The [Sender](crate::sender::Sender) pings Companion constantly, but a
+//! connection can go half open (a pulled cable, a suspended host) without
+//! either end noticing for a long time.  The receiver records every PONG in a
+//! [Liveness], and gives up on the connection with a [CompanionTimeout] once
+//! none has arrived for too long.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// How long to wait for a PONG unless told otherwise.
+pub const DEFAULT_PONG_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Companion didn't answer a PING in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompanionTimeout {
+    /// How long Companion had to answer
+    pub timeout: Duration,
+}
+
+impl fmt::Display for CompanionTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No PONG from Companion in {:?}", self.timeout)
+    }
+}
+
+impl std::error::Error for CompanionTimeout {}
+
+/// When Companion last answered, shared by everything interested in it.
+#[derive(Clone, Debug)]
+pub struct Liveness {
+    last_pong: Arc<Mutex<Instant>>,
+    timeout: Duration,
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Self::new(DEFAULT_PONG_TIMEOUT)
+    }
+}
+
+impl Liveness {
+    /// Start watching a new connection, which has timeout to send its first
+    /// PONG.
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            last_pong: Arc::new(Mutex::new(Instant::now())),
+            timeout,
+        }
+    }
+
+    /// Record a PONG arriving now.
+    pub fn pong(&self) {
+        *self.last_pong.lock().unwrap() = Instant::now();
+    }
+
+    /// Time since the last PONG.
+    pub fn since_pong(&self) -> Duration {
+        self.last_pong.lock().unwrap().elapsed()
+    }
+
+    /// Fail if Companion hasn't answered in time.
+    pub fn check(&self) -> Result<(), CompanionTimeout> {
+        if self.since_pong() >= self.timeout {
+            return Err(CompanionTimeout {
+                timeout: self.timeout,
+            });
+        }
+        Ok(())
+    }
+
+    /// Wait until Companion hasn't answered in time.
+    pub async fn expired(&self) -> CompanionTimeout {
+        loop {
+            let deadline = *self.last_pong.lock().unwrap() + self.timeout;
+            tokio::time::sleep_until(deadline).await;
+            if let Err(timeout) = self.check() {
+                return timeout;
+            }
+        }
+    }
+}
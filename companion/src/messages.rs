@@ -0,0 +1,241 @@
+//! Lines a satellite sends Companion, built rather than formatted by hand.
+//!
+//! Each message is a struct whose `Display` writes it the way Companion reads
+//! it, without the line break.  Values that could hold anything, such as
+//! device ids, are quoted and escaped as needed, so a device id with a space
+//! in it can't split into pairs Companion misreads.  [Message::to_line] adds
+//! the line break.  Companion reads every satellite the same way, so values
+//! are always written with [ValueEncoding::Quoted].
+
+use std::fmt;
+
+use crate::version::Features;
+use crate::{encode_value, DeviceMsg, ValueEncoding};
+
+/// A line sent to Companion.
+pub trait Message: fmt::Display {
+    /// The message as a line, ready to be written.
+    fn to_line(&self) -> String {
+        format!("{self}\n")
+    }
+}
+
+/// Adds a device to Companion.
+#[derive(Debug, PartialEq, Eq)]
+pub struct AddDeviceMsg {
+    device: DeviceMsg,
+    features: Features,
+}
+
+impl AddDeviceMsg {
+    /// The message adding device to a Companion with the features every
+    /// supported version has.
+    pub fn new(device: DeviceMsg) -> Self {
+        Self {
+            device,
+            features: Features::default(),
+        }
+    }
+
+    /// Ask for what a Companion with features can do.
+    pub fn with_features(mut self, features: Features) -> Self {
+        self.features = features;
+        self
+    }
+}
+
+impl fmt::Display for AddDeviceMsg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ADD-DEVICE {}",
+            self.device.device_msg_with(self.features)
+        )
+    }
+}
+
+impl Message for AddDeviceMsg {}
+
+/// Tells Companion to forget a device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoveDeviceMsg {
+    device_id: String,
+    reason: Option<String>,
+}
+
+impl RemoveDeviceMsg {
+    /// The message removing device_id.
+    pub fn new(device_id: &str) -> Self {
+        Self {
+            device_id: device_id.to_string(),
+            reason: None,
+        }
+    }
+
+    /// Give the reason the device is removed, for Companion's logs.
+    /// Companion ignores parameters it doesn't know, so the reason is safe
+    /// to send to any version.
+    pub fn with_reason(mut self, reason: impl fmt::Display) -> Self {
+        self.reason = Some(reason.to_string());
+        self
+    }
+}
+
+impl fmt::Display for RemoveDeviceMsg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "REMOVE-DEVICE DEVICEID={}", quoted(&self.device_id))?;
+        if let Some(reason) = &self.reason {
+            write!(f, " REASON={}", quoted(reason))?;
+        }
+        Ok(())
+    }
+}
+
+impl Message for RemoveDeviceMsg {}
+
+/// A key of a device going down or up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyPressMsg {
+    device_id: String,
+    key: u8,
+    pressed: bool,
+}
+
+impl KeyPressMsg {
+    /// The message for key of device_id going down if pressed, or up.
+    pub fn new(device_id: &str, key: u8, pressed: bool) -> Self {
+        Self {
+            device_id: device_id.to_string(),
+            key,
+            pressed,
+        }
+    }
+}
+
+impl fmt::Display for KeyPressMsg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "KEY-PRESS DEVICEID={} KEY={} PRESSED={}",
+            quoted(&self.device_id),
+            self.key,
+            u8::from(self.pressed)
+        )
+    }
+}
+
+impl Message for KeyPressMsg {}
+
+/// A key of a device rotated one step, as encoders are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRotateMsg {
+    device_id: String,
+    key: u8,
+    clockwise: bool,
+}
+
+impl KeyRotateMsg {
+    /// The message for key of device_id rotating a step clockwise, or
+    /// counterclockwise.
+    pub fn new(device_id: &str, key: u8, clockwise: bool) -> Self {
+        Self {
+            device_id: device_id.to_string(),
+            key,
+            clockwise,
+        }
+    }
+}
+
+impl fmt::Display for KeyRotateMsg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "KEY-ROTATE DEVICEID={} KEY={} DIRECTION={}",
+            quoted(&self.device_id),
+            self.key,
+            u8::from(self.clockwise)
+        )
+    }
+}
+
+impl Message for KeyRotateMsg {}
+
+/// Keeps the connection alive, with a payload Companion echoes in its PONG.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PingMsg {
+    payload: String,
+}
+
+impl PingMsg {
+    /// A PING without a payload.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Carry payload, which is free text rather than a value.  Line breaks
+    /// would end the line early, so they are written as spaces.
+    pub fn with_payload(mut self, payload: &str) -> Self {
+        self.payload = payload.replace(['\r', '\n'], " ");
+        self
+    }
+}
+
+impl fmt::Display for PingMsg {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.payload.is_empty() {
+            write!(f, "PING")
+        } else {
+            write!(f, "PING {}", self.payload)
+        }
+    }
+}
+
+impl Message for PingMsg {}
+
+/// value as Companion reads it from a satellite.
+fn quoted(value: &str) -> std::borrow::Cow<'_, str> {
+    encode_value(value, ValueEncoding::Quoted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyvalue::ParseMap;
+    use elgato_streamdeck::info::Kind;
+
+    /// The pairs of a message, read back as the receiver reads them.
+    fn pairs<'a>(line: &'a str, command: &str) -> ParseMap<'a> {
+        let rest = line.strip_prefix(command).unwrap();
+        ParseMap::parse(rest, ValueEncoding::Quoted).unwrap()
+    }
+
+    #[test]
+    fn test_messages_read_back() {
+        let device_id = "desk \"left\" deck";
+        let line = KeyPressMsg::new(device_id, 3, true).to_line();
+        assert!(line.ends_with(" KEY=3 PRESSED=1\n"));
+        let mut press = pairs(&line, "KEY-PRESS");
+        assert_eq!(press.get("DEVICEID").unwrap().as_str(), device_id);
+        assert_eq!(press.get("PRESSED").unwrap().as_str(), "1");
+
+        let line = KeyRotateMsg::new(device_id, 4, false).to_string();
+        let mut rotate = pairs(&line, "KEY-ROTATE");
+        assert_eq!(rotate.get("DEVICEID").unwrap().as_str(), device_id);
+        assert_eq!(rotate.get("KEY").unwrap().as_str(), "4");
+        assert_eq!(rotate.get("DIRECTION").unwrap().as_str(), "0");
+
+        let line = RemoveDeviceMsg::new(device_id)
+            .with_reason("shutting down")
+            .to_string();
+        let mut remove = pairs(&line, "REMOVE-DEVICE");
+        assert_eq!(remove.get("DEVICEID").unwrap().as_str(), device_id);
+        assert_eq!(remove.get("REASON").unwrap().as_str(), "shutting down");
+
+        let add = AddDeviceMsg::new(DeviceMsg::new(device_id, &Kind::Mk2)).to_string();
+        let mut add = pairs(&add, "ADD-DEVICE");
+        assert_eq!(add.get("DEVICEID").unwrap().as_str(), device_id);
+
+        assert_eq!(PingMsg::new().to_line(), "PING\n");
+        assert_eq!(PingMsg::new().with_payload("a\nb").to_line(), "PING a b\n");
+    }
+}
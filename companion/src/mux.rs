@@ -0,0 +1,438 @@
+//! Sharing one Companion connection between several devices.
+//!
+//! Companion tells devices apart by the DEVICEID of every message, so there
+//! is no need for a connection per device.  The [Multiplexer] owns the
+//! connection and hands each device a pair of in-memory streams that behave
+//! like a connection of its own: lines from Companion naming the device are
+//! routed to its reader, and lines the device writes are put on the
+//! connection whole, never interleaved with another device's.  Each device
+//! has a queue of lines of its own, so one that stops reading only loses its
+//! own lines, never holding up the others.  When a device closes its writer
+//! it is removed from Companion.  PONGs don't name a device, so every device
+//! gets them.
+//!
+//! The BEGIN Companion opens with is checked once for the connection, which
+//! is closed straight away if Companion is too old or too new.  Devices ask
+//! the multiplexer for the [Features] it negotiated before adding themselves.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, trace, warn};
+use traits::{anyhow, Result};
+
+use crate::keyvalue::ParseMap;
+use crate::version::{Features, UnsupportedVersion};
+use crate::ValueEncoding;
+
+/// Bytes buffered between the connection and each device, enough for a
+/// burst of images.
+const DEVICE_BUFFER_SIZE: usize = 256 * 1024;
+/// Lines queued for a device on top of its buffer before its lines are
+/// dropped.
+const DEVICE_QUEUED_LINES: usize = 256;
+/// How long Companion has to send its BEGIN.
+const BEGIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the lines for a device go.
+struct Route {
+    id: u64,
+    lines: mpsc::Sender<String>,
+    /// Lines are being dropped as the device isn't keeping up
+    dropping: bool,
+}
+
+type Routes = Arc<Mutex<HashMap<String, Route>>>;
+
+/// The BEGIN line and its outcome, once it came.
+type Negotiated = Option<(String, std::result::Result<Features, UnsupportedVersion>)>;
+
+/// A Companion connection shared by several devices.
+pub struct Multiplexer<W> {
+    writer: Arc<tokio::sync::Mutex<W>>,
+    routes: Routes,
+    next_id: AtomicU64,
+    negotiated: watch::Receiver<Negotiated>,
+    reader: JoinHandle<Result<()>>,
+}
+
+impl<W> Multiplexer<W>
+where
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    /// Share the connection made of reader and writer.
+    pub fn new<R>(reader: R, writer: W) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let routes = Routes::default();
+        let (negotiate, negotiated) = watch::channel(None);
+        let reader = tokio::spawn(route_lines(reader, routes.clone(), negotiate));
+        Self {
+            writer: Arc::new(tokio::sync::Mutex::new(writer)),
+            routes,
+            next_id: AtomicU64::new(0),
+            negotiated,
+            reader,
+        }
+    }
+
+    /// The BEGIN and what Companion understands, waiting for it if it
+    /// hasn't come yet.  Fails if Companion can't be talked to.
+    async fn negotiated(&self) -> Result<(String, Features)> {
+        let mut negotiated = self.negotiated.clone();
+        let (begin, features) =
+            tokio::time::timeout(BEGIN_TIMEOUT, negotiated.wait_for(Option::is_some))
+                .await
+                .map_err(|_| anyhow::anyhow!("No BEGIN from Companion"))?
+                .map_err(|_| anyhow::anyhow!("Companion connection closed before BEGIN"))?
+                .clone()
+                .expect("waited for the BEGIN");
+        Ok((begin, features?))
+    }
+
+    /// What Companion understands, waiting for its BEGIN if it hasn't come
+    /// yet.  Fails if Companion can't be talked to.
+    pub async fn features(&self) -> Result<Features> {
+        Ok(self.negotiated().await?.1)
+    }
+
+    /// The BEGIN line Companion opened with, for passing on to whatever
+    /// shares the connection from further away.  Fails as
+    /// [features](Self::features) does.
+    pub async fn begin(&self) -> Result<String> {
+        Ok(self.negotiated().await?.0)
+    }
+
+    /// Wait for the connection to Companion to close.
+    pub async fn closed(&self) {
+        let mut negotiated = self.negotiated.clone();
+        // The sender goes with the task reading from Companion
+        while negotiated.changed().await.is_ok() {}
+    }
+
+    /// Whether the connection to Companion has closed.  Devices added to a
+    /// closed multiplexer see their reader end straight away.
+    pub fn is_closed(&self) -> bool {
+        self.reader.is_finished()
+    }
+
+    /// Add a device, returning the reader and writer it should use in place
+    /// of a connection of its own.  A device added again replaces the
+    /// earlier one.
+    pub fn add_device(&self, device_id: &str) -> Result<(DuplexStream, DuplexStream)> {
+        if self.is_closed() {
+            anyhow::bail!("Companion connection is closed");
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (to_device, device_reader) = tokio::io::duplex(DEVICE_BUFFER_SIZE);
+        let (device_writer, from_device) = tokio::io::duplex(DEVICE_BUFFER_SIZE);
+        let (lines, queued) = mpsc::channel(DEVICE_QUEUED_LINES);
+        self.routes.lock().unwrap().insert(
+            device_id.to_string(),
+            Route {
+                id,
+                lines,
+                dropping: false,
+            },
+        );
+        tokio::spawn(deliver_lines(queued, to_device));
+        tokio::spawn(forward_lines(
+            device_id.to_string(),
+            id,
+            from_device,
+            self.writer.clone(),
+            self.routes.clone(),
+        ));
+        Ok((device_reader, device_writer))
+    }
+}
+
+impl<W> Drop for Multiplexer<W> {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// The DEVICEID a line is about, if any, its value decoded as encoding says.
+pub(crate) fn device_id_of(line: &str, encoding: ValueEncoding) -> Option<Cow<'_, str>> {
+    let (_, values) = line.split_once(|c: char| c.is_ascii_whitespace())?;
+    let mut values = ParseMap::parse(values, encoding).ok()?;
+    Some(values.get("DEVICEID").ok()?.into())
+}
+
+/// Route the lines read from Companion to the devices they name.
+async fn route_lines<R>(
+    reader: R,
+    routes: Routes,
+    negotiate: watch::Sender<Negotiated>,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut lines = BufReader::new(reader).lines();
+    let mut encoding = ValueEncoding::Quoted;
+    let res = async {
+        while let Some(line) = lines.next_line().await? {
+            if line.trim_end() == "PONG" {
+                broadcast(&routes, &line);
+                continue;
+            }
+            if line.starts_with("BEGIN") {
+                let negotiated = begin(&line);
+                info!("Companion began: {:?}", negotiated);
+                negotiate.send_replace(Some((line.clone(), negotiated.clone())));
+                encoding = negotiated?.value_encoding;
+                continue;
+            }
+            let Some(device_id) = device_id_of(&line, encoding) else {
+                trace!("Not routing {}", line);
+                continue;
+            };
+            let mut current = routes.lock().unwrap();
+            let Some(route) = current.get_mut(device_id.as_ref()) else {
+                debug!("Message for unknown device {}", device_id);
+                continue;
+            };
+            if !route_line(&device_id, route, &line) {
+                current.remove(device_id.as_ref());
+            }
+        }
+        Ok(())
+    }
+    .await;
+    info!("Companion connection closed: {:?}", res);
+    // Dropping the routes ends the reader of every device
+    routes.lock().unwrap().clear();
+    res
+}
+
+/// Negotiate the features of the connection from the BEGIN line.  A BEGIN
+/// without an ApiVersion comes from before the satellite API had versions.
+fn begin(line: &str) -> std::result::Result<Features, UnsupportedVersion> {
+    match crate::Command::parse(line) {
+        Ok(crate::Command::Begin(versions)) => versions.negotiate(),
+        _ => Err(UnsupportedVersion::Unparseable(String::new())),
+    }
+}
+
+/// Queue a line for the device of route, without waiting for it.  A device
+/// that isn't keeping up has the line dropped.  Returns false once the device
+/// stopped reading, so the route is to be removed.
+fn route_line(device_id: &str, route: &mut Route, line: &str) -> bool {
+    match route.lines.try_send(format!("{line}\n")) {
+        Ok(()) => {
+            if std::mem::take(&mut route.dropping) {
+                info!("Device {} caught up", device_id);
+            }
+            true
+        }
+        Err(mpsc::error::TrySendError::Full(_)) => {
+            if !std::mem::replace(&mut route.dropping, true) {
+                warn!("Device {} isn't keeping up, dropping its lines", device_id);
+            }
+            trace!("Dropped {} for device {}", line, device_id);
+            true
+        }
+        Err(mpsc::error::TrySendError::Closed(_)) => {
+            debug!("Device {} stopped reading", device_id);
+            false
+        }
+    }
+}
+
+/// Send a line to every device.
+fn broadcast(routes: &Routes, line: &str) {
+    routes
+        .lock()
+        .unwrap()
+        .retain(|device_id, route| route_line(device_id, route, line));
+}
+
+/// Write the lines queued for a device to it, until it stops reading or
+/// its route is removed.
+async fn deliver_lines(mut queued: mpsc::Receiver<String>, mut to_device: DuplexStream) {
+    while let Some(line) = queued.recv().await {
+        if to_device.write_all(line.as_bytes()).await.is_err() {
+            // Closing the queue has the route removed with the next line
+            return;
+        }
+    }
+}
+
+/// Put the lines a device writes on the connection, removing the device
+/// from Companion once it is done.
+async fn forward_lines<W>(
+    device_id: String,
+    id: u64,
+    from_device: DuplexStream,
+    writer: Arc<tokio::sync::Mutex<W>>,
+    routes: Routes,
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut lines = BufReader::new(from_device).lines();
+    // A device that removed itself isn't removed again
+    let mut removed = false;
+    while let Some(line) = lines.next_line().await? {
+        removed = line.starts_with("REMOVE-DEVICE");
+        let mut writer = writer.lock().await;
+        writer.write_all(format!("{line}\n").as_bytes()).await?;
+        writer.flush().await?;
+    }
+    // Only remove the device if it wasn't added again in the meantime
+    if remove_route(&routes, &device_id, id) && !removed {
+        debug!("Removing device {} from Companion", device_id);
+        crate::sender::remove_device(&mut *writer.lock().await, &device_id).await?;
+    }
+    Ok(())
+}
+
+/// Remove the route of a device if it is still the one with id.
+fn remove_route(routes: &Routes, device_id: &str, id: u64) -> bool {
+    let mut routes = routes.lock().unwrap();
+    let current = routes.get(device_id).is_some_and(|route| route.id == id);
+    if current {
+        routes.remove(device_id);
+    }
+    current
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lines_routed_by_device() {
+        let (companion, gateway) = tokio::io::duplex(1024);
+        let (gateway_reader, gateway_writer) = tokio::io::split(gateway);
+        let mux = Multiplexer::new(gateway_reader, gateway_writer);
+        let (a_reader, mut a_writer) = mux.add_device("a").unwrap();
+        let (b_reader, b_writer) = mux.add_device("b").unwrap();
+
+        let (companion_reader, mut companion_writer) = tokio::io::split(companion);
+        companion_writer
+            .write_all(b"BEGIN CompanionVersion=3.1.2 ApiVersion=1.5.1\nPONG\nKEY-STATE DEVICEID=b KEY=1\nKEY-STATE DEVICEID=a KEY=2\n")
+            .await
+            .unwrap();
+        let features = mux.features().await.unwrap();
+        assert_eq!(features.value_encoding, crate::ValueEncoding::Quoted);
+        let mut a_lines = BufReader::new(a_reader).lines();
+        let mut b_lines = BufReader::new(b_reader).lines();
+        assert_eq!(a_lines.next_line().await.unwrap().unwrap(), "PONG");
+        assert_eq!(b_lines.next_line().await.unwrap().unwrap(), "PONG");
+        assert_eq!(
+            a_lines.next_line().await.unwrap().unwrap(),
+            "KEY-STATE DEVICEID=a KEY=2"
+        );
+        assert_eq!(
+            b_lines.next_line().await.unwrap().unwrap(),
+            "KEY-STATE DEVICEID=b KEY=1"
+        );
+
+        // Closing a device removes it from Companion
+        a_writer
+            .write_all(b"KEY-PRESS DEVICEID=a KEY=0 PRESSED=1\n")
+            .await
+            .unwrap();
+        drop(a_writer);
+        let mut companion_lines = BufReader::new(companion_reader).lines();
+        assert_eq!(
+            companion_lines.next_line().await.unwrap().unwrap(),
+            "KEY-PRESS DEVICEID=a KEY=0 PRESSED=1"
+        );
+        assert_eq!(
+            companion_lines.next_line().await.unwrap().unwrap(),
+            "REMOVE-DEVICE DEVICEID=a"
+        );
+        drop(b_writer);
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_companion_closes_connection() {
+        let (mut companion, gateway) = tokio::io::duplex(1024);
+        let (gateway_reader, gateway_writer) = tokio::io::split(gateway);
+        let mux = Multiplexer::new(gateway_reader, gateway_writer);
+        let (reader, _writer) = mux.add_device("a").unwrap();
+
+        companion
+            .write_all(b"BEGIN CompanionVersion=9.0.0 ApiVersion=3.0.0\nPONG\n")
+            .await
+            .unwrap();
+        let error = mux.features().await.unwrap_err();
+        assert!(error.to_string().contains("Upgrade this satellite"));
+        // The device never hears from a Companion it can't talk to
+        assert_eq!(
+            BufReader::new(reader).lines().next_line().await.unwrap(),
+            None
+        );
+        assert!(mux.add_device("b").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_device_not_reading_holds_up_no_other() {
+        let (companion, gateway) = tokio::io::duplex(1024);
+        let (gateway_reader, gateway_writer) = tokio::io::split(gateway);
+        let mux = Multiplexer::new(gateway_reader, gateway_writer);
+        let (_stuck_reader, _stuck_writer) = mux.add_device("stuck").unwrap();
+        let (reader, _writer) = mux.add_device("b").unwrap();
+
+        let (_companion_reader, mut companion_writer) = tokio::io::split(companion);
+        companion_writer
+            .write_all(b"BEGIN CompanionVersion=3.1.2 ApiVersion=1.5.1\n")
+            .await
+            .unwrap();
+        // Far more than the buffer and queue of the device hold
+        let image = "A".repeat(4096);
+        for key in 0..DEVICE_QUEUED_LINES * 2 {
+            let line = format!("KEY-STATE DEVICEID=stuck KEY={key} BITMAP={image}\n");
+            companion_writer.write_all(line.as_bytes()).await.unwrap();
+        }
+        companion_writer
+            .write_all(b"PONG\nKEY-STATE DEVICEID=b KEY=1\n")
+            .await
+            .unwrap();
+        let mut lines = BufReader::new(reader).lines();
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "PONG");
+        assert_eq!(
+            lines.next_line().await.unwrap().unwrap(),
+            "KEY-STATE DEVICEID=b KEY=1"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_encoded_device_ids_routed() {
+        let (companion, gateway) = tokio::io::duplex(1024);
+        let (gateway_reader, gateway_writer) = tokio::io::split(gateway);
+        let mux = Multiplexer::new(gateway_reader, gateway_writer);
+        let (reader, _writer) = mux.add_device("desk deck").unwrap();
+
+        let (_companion_reader, mut companion_writer) = tokio::io::split(companion);
+        companion_writer
+            .write_all(b"BEGIN CompanionVersion=3.1.2 ApiVersion=1.8.0\nKEY-STATE DEVICEID=desk%20deck KEY=1\n")
+            .await
+            .unwrap();
+        let mut lines = BufReader::new(reader).lines();
+        assert_eq!(
+            lines.next_line().await.unwrap().unwrap(),
+            "KEY-STATE DEVICEID=desk%20deck KEY=1"
+        );
+
+        assert_eq!(
+            device_id_of(
+                "KEY-STATE DEVICEID=\"desk deck\" KEY=1",
+                ValueEncoding::Quoted
+            )
+            .as_deref(),
+            Some("desk deck")
+        );
+        assert_eq!(device_id_of("PONG", ValueEncoding::Quoted), None);
+    }
+}
@@ -0,0 +1,76 @@
+//! The satellite API over a Windows named pipe.
+//!
+//! A satellite on the same Windows host as Companion would otherwise go
+//! through the TCP stack, and on some hosts set off a firewall prompt for
+//! what never leaves the machine.  [connect] opens a named pipe instead,
+//! returning a reader and writer the [Sender](crate::sender::Sender) and
+//! [Receiver](crate::receiver::Receiver) work over unchanged.
+//!
+//! Companion itself only listens on TCP, so the pipe is served on its side
+//! by [proxy], which passes each connection to the pipe on to the satellite
+//! port of Companion over loopback.
+
+use std::time::Duration;
+
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient, ServerOptions};
+use tracing::{debug, info, warn};
+use traits::Result;
+
+/// The pipe satellites connect to unless told otherwise.
+pub const DEFAULT_PIPE: &str = r"\\.\pipe\companion-satellite";
+
+/// Error opening a pipe whose every instance is taken, until the server
+/// creates another.
+const ERROR_PIPE_BUSY: i32 = 231;
+
+/// How long to wait before trying a busy pipe again.
+const BUSY_RETRY: Duration = Duration::from_millis(50);
+
+/// Connect to Companion through the pipe named name, returning a reader and
+/// writer of lines as a TCP connection to it would.
+pub async fn connect(
+    name: &str,
+) -> Result<(ReadHalf<NamedPipeClient>, WriteHalf<NamedPipeClient>)> {
+    let client = loop {
+        match ClientOptions::new().open(name) {
+            Ok(client) => break client,
+            Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) => {
+                tokio::time::sleep(BUSY_RETRY).await
+            }
+            Err(e) => return Err(e.into()),
+        }
+    };
+    debug!("Connected to pipe {}", name);
+    Ok(tokio::io::split(client))
+}
+
+/// Serve the pipe named name, passing each connection to it on to the
+/// Companion at companion, until serving the pipe fails.
+pub async fn proxy(name: &str, companion: String) -> Result<()> {
+    info!("Serving {} for the Companion at {}", name, companion);
+    let mut server = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(name)?;
+    loop {
+        server.connect().await?;
+        // The next instance is created before passing this one on, so
+        // satellites connecting meanwhile are kept waiting rather than
+        // refused
+        let mut connected = std::mem::replace(&mut server, ServerOptions::new().create(name)?);
+        let companion = companion.clone();
+        tokio::spawn(async move {
+            let res = async {
+                let mut upstream = tokio::net::TcpStream::connect(&companion).await?;
+                upstream.set_nodelay(true)?;
+                tokio::io::copy_bidirectional(&mut connected, &mut upstream).await?;
+                Ok::<_, anyhow::Error>(())
+            }
+            .await;
+            match res {
+                Ok(()) => debug!("Pipe connection closed"),
+                Err(e) => warn!("Pipe connection to {} failed: {:#}", companion, e),
+            }
+        });
+    }
+}
@@ -1,38 +1,95 @@
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::num::NonZeroUsize;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
 
-use crate::Command;
+use crate::cache::ImageCache;
+use crate::disk_cache::fnv1a;
+use crate::fonts::Fonts;
+use crate::images::{
+    BufferPool, ConversionTimes, ImageConverter, OwnedBuffer, StreamDeckConverter,
+};
+use crate::liveness::{CompanionTimeout, Liveness};
+use crate::{Command, ValueEncoding};
 use elgato_streamdeck::info::Kind;
-use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
-use tracing::{debug, trace};
+use image::imageops::{rotate180, rotate270, rotate90};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::sync::watch;
+use tokio::task::{JoinError, JoinHandle};
+use tracing::{debug, trace, warn, Span};
 use traits::{
     anyhow, async_trait,
-    device::{DeviceActions, SetBrightness, SetButtonImage, SetLCDImage},
+    device::{
+        DeviceActions, DeviceSettings, Orientation, SetBrightness, SetButtonImage, SetLCDImage,
+    },
     Result,
 };
 
+/// Size of the read buffer unless told otherwise.  Large enough that a burst
+/// of KEY-STATE lines from a page change lands in it together and can be
+/// handled as a batch.
+pub const READ_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Most actions queued for the device unless told otherwise.  Once reached,
+/// nothing more is read from Companion until the device catches up.
+pub const MAX_PENDING: usize = 256;
+
+/// Characters of base64 decoded at once while reading a bitmap.  A multiple
+/// of 4, so no group of characters is split between chunks.
+const BITMAP_CHUNK: usize = 4 * 1024;
+
+/// Unknown commands warned about by name, once each.  Any more are only
+/// logged at debug, so a Companion sending garbage can't grow the set.
+const UNKNOWN_NAMES_WARNED: usize = 16;
+
 trait CommandProcessor {
+    /// The action for command, given the bitmap of its line if it was
+    /// decoded while reading.
     fn process(
-        &mut self,
+        &self,
         kind: Kind,
         command: Command,
+        decoded: Option<&[u8]>,
     ) -> Result<Option<traits::device::DeviceActions>>;
 }
 
-#[derive(Default)]
-struct DefaultCommandProcessor {}
+struct DefaultCommandProcessor {
+    settings: DeviceSettings,
+    buffers: Arc<BufferPool>,
+    converter: Arc<dyn ImageConverter>,
+    /// Draw keys from their color and text even when they have an image
+    colors_only: bool,
+    /// Draw the text of keys in these rather than the built in font
+    fonts: Option<Arc<Fonts>>,
+}
+impl Default for DefaultCommandProcessor {
+    fn default() -> Self {
+        Self {
+            settings: Default::default(),
+            buffers: Default::default(),
+            converter: Arc::new(StreamDeckConverter),
+            colors_only: false,
+            fonts: None,
+        }
+    }
+}
 impl CommandProcessor for DefaultCommandProcessor {
     fn process(
-        &mut self,
+        &self,
         kind: Kind,
         command: Command,
+        decoded: Option<&[u8]>,
     ) -> Result<Option<traits::device::DeviceActions>> {
         let ret = match command {
-            Command::KeyPress(data) => {
-                debug!("Received key press: {data}");
-                None
-            }
-            Command::KeyRotate(data) => {
-                debug!("Received key rotate: {data}");
+            // Companion answers the input we send it, only failures are
+            // interesting
+            Command::KeyPress(data) | Command::KeyRotate(data) => {
+                if data.trim_start().starts_with("ERROR") {
+                    warn!("Companion rejected input: {}", data.trim());
+                } else {
+                    debug!("Input acknowledged: {data}");
+                }
                 None
             }
             Command::Pong => {
@@ -47,9 +104,41 @@ impl CommandProcessor for DefaultCommandProcessor {
                 debug!("Adding device: {:?}", device);
                 None
             }
+            Command::KeyState(keystate) if !kind.is_visual() => {
+                trace!(
+                    "Ignoring key state for a device without a screen: {:?}",
+                    keystate
+                );
+                None
+            }
             Command::KeyState(keystate) => {
                 debug!("Received key state: {:?}", keystate);
-                debug!("  bitmap size: {}", keystate.bitmap()?.len());
+                // Decoded once, and only borrowed from here on
+                let size = kind.key_image_format().size.0;
+                let mut bitmap = self.buffers.take();
+                let described = keystate.color.is_some() || keystate.text.is_some();
+                let has_bitmap = decoded.is_some() || !keystate.bitmap_base64.is_empty();
+                if !has_bitmap || (self.colors_only && described) {
+                    // Keys Companion doesn't draw are drawn from their color
+                    // and text, as are all keys of a device told to save
+                    // bandwidth
+                    let text = keystate.text.as_ref().map_or("", |text| text.as_str());
+                    match &self.fonts {
+                        Some(fonts) => fonts.draw_key(size, keystate.rgb()?, text, &mut bitmap),
+                        None => crate::render::draw_key(size, keystate.rgb()?, text, &mut bitmap),
+                    }
+                } else if let Some(decoded) = decoded {
+                    bitmap.extend_from_slice(decoded);
+                } else {
+                    keystate.bitmap_into(&mut bitmap)?;
+                }
+                debug!("  bitmap size: {}", bitmap.len());
+                if self.settings.key_overlay && bitmap.len() == size * size * 3 {
+                    crate::render::draw_label(size, &keystate.key.to_string(), &mut bitmap);
+                }
+                let side = size.try_into()?;
+                let view =
+                    image::ImageBuffer::<image::Rgb<u8>, &[u8]>::from_raw(side, side, &bitmap[..]);
 
                 let (lcd_width, lcd_height) = kind.lcd_strip_size().unwrap_or((0, 0));
                 let (lcd_width, lcd_height) = (lcd_width as u32, lcd_height as u32);
@@ -59,17 +148,13 @@ impl CommandProcessor for DefaultCommandProcessor {
                 let in_lcd_button = if in_button_range.is_some() {
                     None
                 } else {
-                    kind.lcd_strip_size()
-                        .map(|_| kind.key_count() - keystate.key)
-                        .filter(|index| index < &kind.column_count())
+                    crate::layout::lcd_segment(kind, keystate.key)
                 };
 
                 match (in_button_range, in_lcd_button) {
                     (Some(key), _) => {
                         trace!("Writing image to button");
 
-                        let size = kind.key_image_format().size.0;
-                        let bitmap = keystate.bitmap()?;
                         if bitmap.len() != size * size * 3 {
                             anyhow::bail!(
                                 "Expected bitmap to be len {}, but was {}",
@@ -77,42 +162,72 @@ impl CommandProcessor for DefaultCommandProcessor {
                                 bitmap.len()
                             );
                         }
-                        let image = image::DynamicImage::ImageRgb8(
-                            image::ImageBuffer::from_vec(
-                                size.try_into()?,
-                                size.try_into()?,
-                                keystate.bitmap()?,
-                            )
-                            .ok_or_else(|| anyhow::anyhow!("Couldn't extract image buffer"))?,
-                        );
 
-                        let image = elgato_streamdeck::images::convert_image(kind, image)?;
+                        // Keys in a dimmed zone have their image scaled down
+                        let zone_brightness = self
+                            .settings
+                            .zone_of(key)
+                            .and_then(|(_, zone)| zone.brightness);
+                        let dimmed = zone_brightness.map(|brightness| {
+                            let brightness = u16::from(brightness.min(100));
+                            let mut dimmed = self.buffers.take();
+                            dimmed.extend(
+                                bitmap
+                                    .iter()
+                                    .map(|value| (u16::from(*value) * brightness / 100) as u8),
+                            );
+                            dimmed
+                        });
+                        let view = match &dimmed {
+                            Some(dimmed) => image::ImageBuffer::from_raw(side, side, &dimmed[..]),
+                            None => view,
+                        }
+                        .ok_or_else(|| anyhow::anyhow!("Couldn't extract image buffer"))?;
+
+                        let rotated = match self.settings.orientation {
+                            Orientation::Normal => None,
+                            Orientation::Rotated90 => Some(rotate270(&view)),
+                            Orientation::Rotated180 => Some(rotate180(&view)),
+                            Orientation::Rotated270 => Some(rotate90(&view)),
+                        };
+                        let rgb: &[u8] = match &rotated {
+                            Some(image) => image,
+                            None => &view,
+                        };
+
+                        let image = self.converter.convert_key(kind, rgb, &self.buffers)?;
 
                         let ret =
                             DeviceActions::SetButtonImage(SetButtonImage { button: key, image });
 
                         Some(ret)
                     }
-                    (None, Some(lcd_key)) => {
+                    (None, Some(segment)) => {
                         debug!("Writing image to LCD panel");
-                        let size = kind.key_image_format().size.0.try_into()?;
-                        let image = image::DynamicImage::ImageRgb8(
-                            image::ImageBuffer::from_vec(size, size, keystate.bitmap()?).unwrap(),
-                        );
+                        let view =
+                            view.ok_or_else(|| anyhow::anyhow!("Couldn't extract image buffer"))?;
                         // resize image to the height
-                        let image = image.resize(
-                            image.width(),
-                            lcd_height,
+                        let height = side.min(lcd_height);
+                        let image = image::imageops::resize(
+                            &view,
+                            height,
+                            height,
                             image::imageops::FilterType::Gaussian,
                         );
-                        let button_x_offset =
-                            (lcd_key as u32 - 8) * ((lcd_width - image.width()) / 3);
+                        let segments = u32::from(kind.column_count()).max(2);
+                        let button_x_offset = u32::from(segment)
+                            * ((lcd_width - image.width()) / (segments - 1));
+
+                        let (width, height) = image.dimensions();
+                        let image =
+                            self.converter
+                                .convert_lcd(kind, width, height, image.into_raw())?;
 
                         Some(DeviceActions::SetLCDImage(SetLCDImage {
                             x_offset: button_x_offset.try_into()?,
                             x_size: lcd_height.try_into()?,
                             y_size: lcd_height.try_into()?,
-                            image: image.into_bytes(),
+                            image,
                         }))
                     }
                     _ => {
@@ -127,6 +242,17 @@ impl CommandProcessor for DefaultCommandProcessor {
                     brightness: brightness.brightness,
                 }))
             }
+            // What the keys show is stale until Companion sends them again
+            command @ (Command::KeysClear(_) | Command::LockedState(_))
+                if command.blanks_device() =>
+            {
+                debug!("Blanking the device: {:?}", command);
+                Some(DeviceActions::ClearAll)
+            }
+            command @ (Command::KeysClear(_) | Command::LockedState(_)) => {
+                debug!("Lock state: {:?}", command);
+                None
+            }
             Command::Unknown(command) => {
                 debug!("Unknown command: {}", command);
                 None
@@ -137,26 +263,898 @@ impl CommandProcessor for DefaultCommandProcessor {
     }
 }
 
+/// An image to convert on a worker.
+struct Job {
+    line: Line,
+    encoding: ValueEncoding,
+    kind: Kind,
+    processor: Arc<DefaultCommandProcessor>,
+    times: Arc<ConversionTimes>,
+}
+
+impl Job {
+    fn convert(self) -> Result<Option<DeviceActions>> {
+        let start = std::time::Instant::now();
+        let command = Command::parse_with(&self.line.text, self.encoding)?;
+        let action = self
+            .processor
+            .process(self.kind, command, self.line.bitmap());
+        self.times.record(start.elapsed());
+        action
+    }
+}
+
+/// An action on its way to the device, in the order Companion sent it.
+enum Pending {
+    /// Ready to send
+    Ready(DeviceActions),
+    /// An image waiting for a free worker, cached under key once converted
+    Queued { key: String, job: Job },
+    /// An image being converted on a worker
+    Converting {
+        key: String,
+        task: JoinHandle<Result<Option<DeviceActions>>>,
+    },
+}
+
+/// What a batch does with one of its lines.
+enum Step {
+    Skip,
+    Send(DeviceActions),
+    Convert,
+}
+
+impl Pending {
+    /// Whether this is an image a worker is still converting.
+    fn busy(&self) -> bool {
+        matches!(self, Pending::Converting { task, .. } if !task.is_finished())
+    }
+}
+
+/// A line from Companion, with its bitmap decoded as it was read.
+#[derive(Clone)]
+struct Line {
+    /// The line, without the BITMAP of a KEY-STATE
+    text: String,
+    /// The BITMAP, if the line had one
+    bitmap: Option<Arc<Bitmap>>,
+}
+
+/// A bitmap decoded from a line.
+struct Bitmap {
+    pixels: OwnedBuffer,
+    /// Stands in for the pixels in cache keys
+    digest: u64,
+}
+
+impl Line {
+    /// The decoded bitmap, if the line had one.
+    fn bitmap(&self) -> Option<&[u8]> {
+        self.bitmap.as_deref().map(|bitmap| &bitmap.pixels[..])
+    }
+}
+
+/// Where a [LineReader] is in the line it is reading.
+#[derive(Debug, Default, Clone, Copy)]
+enum Scan {
+    /// In the text of the line
+    #[default]
+    Text,
+    /// In a quoted value, after a backslash if escaped
+    Quoted { escaped: bool },
+    /// Right after `BITMAP=`
+    BitmapStart,
+    /// In the base64 of a bitmap
+    Bitmap,
+    /// In a percent escape in the base64 of a bitmap, with the value of the
+    /// digits read so far
+    Escape { value: u8, digits: u8 },
+}
+
+/// Splits what Companion sends into [Line]s, one read buffer at a time.
+///
+/// A KEY-STATE line carries its bitmap as some 58 KB of base64.  Rather than
+/// holding the whole line, the base64 is decoded a chunk at a time as it is
+/// read, into a buffer from the pool, so no more than the pixels and the short
+/// text around them is ever kept of a line.  Quoted bitmaps are left in the
+/// text for the parser.
+struct LineReader {
+    pool: Arc<BufferPool>,
+    text: Vec<u8>,
+    scan: Scan,
+    /// base64 of the bitmap read but not decoded yet
+    base64: Vec<u8>,
+    pixels: Option<OwnedBuffer>,
+}
+
+impl LineReader {
+    fn new(pool: Arc<BufferPool>) -> Self {
+        Self {
+            pool,
+            text: Vec::new(),
+            scan: Scan::Text,
+            base64: Vec::new(),
+            pixels: None,
+        }
+    }
+
+    /// Read text as a whole line, such as a line in standby.
+    fn whole(pool: Arc<BufferPool>, text: &str) -> Result<Line> {
+        let mut reader = Self::new(pool);
+        match reader.feed(text.as_bytes())? {
+            (_, Some(line)) => Ok(line),
+            (_, None) => reader.finish(),
+        }
+    }
+
+    /// Whether nothing of a line has been read.
+    fn is_empty(&self) -> bool {
+        self.text.is_empty() && matches!(self.scan, Scan::Text)
+    }
+
+    /// Read buf up to the end of the first line in it, returning how much
+    /// of buf was read, and the line if it ended.  What is read of a line
+    /// that hasn't ended is kept for the next buf.
+    fn feed(&mut self, buf: &[u8]) -> Result<(usize, Option<Line>)> {
+        let mut read = 0;
+        while read < buf.len() {
+            if let Scan::Bitmap = self.scan {
+                // The bulk of the line, taken a run of base64 at a time
+                let rest = &buf[read..];
+                let run = rest
+                    .iter()
+                    .position(|b| b.is_ascii_whitespace() || *b == b'%')
+                    .unwrap_or(rest.len());
+                if run > 0 {
+                    self.push_base64(&rest[..run])?;
+                    read += run;
+                    continue;
+                }
+            }
+            read += 1;
+            if self.byte(buf[read - 1])? {
+                return Ok((read, Some(self.finish()?)));
+            }
+        }
+        Ok((read, None))
+    }
+
+    /// Read one byte, returning whether it ended the line.
+    fn byte(&mut self, byte: u8) -> Result<bool> {
+        match self.scan {
+            Scan::Text | Scan::Quoted { .. } => {
+                self.text.push(byte);
+                if byte == b'\n' {
+                    return Ok(true);
+                }
+                self.scan = match self.scan {
+                    Scan::Quoted { escaped: false } if byte == b'\\' => {
+                        Scan::Quoted { escaped: true }
+                    }
+                    Scan::Quoted { escaped: false } if byte == b'"' => Scan::Text,
+                    Scan::Quoted { .. } => Scan::Quoted { escaped: false },
+                    _ if self.text.ends_with(b"=\"") => Scan::Quoted { escaped: false },
+                    _ if self.text.ends_with(b" BITMAP=") => {
+                        self.text.truncate(self.text.len() - b" BITMAP=".len());
+                        Scan::BitmapStart
+                    }
+                    scan => scan,
+                };
+            }
+            Scan::BitmapStart if byte == b'"' => {
+                self.text.extend_from_slice(b" BITMAP=\"");
+                self.scan = Scan::Quoted { escaped: false };
+            }
+            Scan::BitmapStart | Scan::Bitmap if byte.is_ascii_whitespace() => {
+                self.end_bitmap()?;
+                return self.byte(byte);
+            }
+            // Percent encoded values escape the + and / of base64
+            Scan::BitmapStart | Scan::Bitmap if byte == b'%' => {
+                self.scan = Scan::Escape {
+                    value: 0,
+                    digits: 0,
+                };
+            }
+            Scan::BitmapStart | Scan::Bitmap => {
+                self.push_base64(&[byte])?;
+                self.scan = Scan::Bitmap;
+            }
+            Scan::Escape { value, digits } => {
+                let digit = char::from(byte)
+                    .to_digit(16)
+                    .ok_or_else(|| anyhow::anyhow!("Error decoding bitmap"))?;
+                let value = (value << 4) | digit as u8;
+                if digits == 0 {
+                    self.scan = Scan::Escape { value, digits: 1 };
+                } else {
+                    self.push_base64(&[value])?;
+                    self.scan = Scan::Bitmap;
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Take base64 of the bitmap, decoding whole chunks of it.
+    fn push_base64(&mut self, base64: &[u8]) -> Result<()> {
+        for piece in base64.chunks(BITMAP_CHUNK) {
+            self.base64.extend_from_slice(piece);
+            if self.base64.len() >= BITMAP_CHUNK {
+                self.decode(self.base64.len() / 4 * 4)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode the first len characters of base64 read.
+    fn decode(&mut self, len: usize) -> Result<()> {
+        use base64::Engine as _;
+        let pixels = self.pixels.get_or_insert_with(|| self.pool.take_owned());
+        base64::engine::general_purpose::STANDARD_NO_PAD
+            .decode_vec(&self.base64[..len], pixels)
+            .map_err(|_| anyhow::anyhow!("Error decoding bitmap"))?;
+        self.base64.drain(..len);
+        Ok(())
+    }
+
+    /// Decode what is left of the bitmap.
+    fn end_bitmap(&mut self) -> Result<()> {
+        if let Scan::Escape { .. } = self.scan {
+            anyhow::bail!("Error decoding bitmap");
+        }
+        if !self.base64.is_empty() {
+            self.decode(self.base64.len())?;
+        }
+        self.scan = Scan::Text;
+        Ok(())
+    }
+
+    /// Take the line read, whether or not it ended.
+    fn finish(&mut self) -> Result<Line> {
+        if let Scan::BitmapStart | Scan::Bitmap | Scan::Escape { .. } = self.scan {
+            self.end_bitmap()?;
+        }
+        self.scan = Scan::Text;
+        let text = String::from_utf8(std::mem::take(&mut self.text))?;
+        let bitmap = self
+            .pixels
+            .take()
+            .filter(|pixels| !pixels.is_empty())
+            .map(|pixels| {
+                let digest = fnv1a(&pixels);
+                Arc::new(Bitmap { pixels, digest })
+            });
+        Ok(Line { text, bitmap })
+    }
+}
+
+/// Read the next line from reader, or None once it is closed with nothing of
+/// a line left.  Cancel safe, as what is read of a line is kept in lines.
+async fn read_line<R>(reader: &mut BufReader<R>, lines: &mut LineReader) -> Result<Option<Line>>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            if lines.is_empty() {
+                return Ok(None);
+            }
+            return lines.finish().map(Some);
+        }
+        let (read, line) = lines.feed(buf)?;
+        reader.consume(read);
+        if line.is_some() {
+            return Ok(line);
+        }
+    }
+}
+
 pub struct Receiver<R> {
     reader: BufReader<R>,
+    lines: LineReader,
+    closed: bool,
     kind: Kind,
-    processor: DefaultCommandProcessor,
-    cache: lru::LruCache<String, traits::device::DeviceActions>,
+    processor: Arc<DefaultCommandProcessor>,
+    parallelism: usize,
+    cache: ImageCache,
+    cache_context: String,
+    conversion_times: Arc<ConversionTimes>,
+    /// Commands from Companion that were passed over for not being known
+    unknown_commands: Arc<AtomicU64>,
+    /// Names of the unknown commands warned about
+    unknown_names: HashSet<String>,
+    settings: Option<watch::Receiver<DeviceSettings>>,
+    default_brightness: Option<u8>,
+    liveness: Option<Liveness>,
+    percent_encoding: bool,
+    value_encoding: ValueEncoding,
+    strict: bool,
+    pending: VecDeque<Pending>,
+    max_pending: usize,
+    /// Kept up to date with the length of pending, for reporting
+    queue_depth: Arc<AtomicUsize>,
+    standby: Option<watch::Receiver<Vec<String>>>,
+    /// Set to stop the images in standby being converted, once they are
+    /// replaced or converted for other settings
+    warming: Arc<AtomicBool>,
+    colors_only: Option<watch::Receiver<bool>>,
+    /// The last KEY-STATE line of each key, drawn again when switching
+    /// between images and colors.  Only kept with colors_only.
+    shown: HashMap<u8, Line>,
 }
 impl<R> Receiver<R>
 where
     R: AsyncRead + Unpin + Send,
 {
     pub fn new(reader: R, kind: Kind) -> Self {
+        let processor: Arc<DefaultCommandProcessor> = Default::default();
         Self {
-            reader: tokio::io::BufReader::new(reader),
+            reader: tokio::io::BufReader::with_capacity(READ_BUFFER_SIZE, reader),
+            lines: LineReader::new(processor.buffers.clone()),
+            closed: false,
             kind,
-            processor: Default::default(),
-            cache: lru::LruCache::new(NonZeroUsize::new(100).unwrap()),
+            processor,
+            parallelism: std::thread::available_parallelism()
+                .map(NonZeroUsize::get)
+                .unwrap_or(1),
+            cache: ImageCache::default(),
+            cache_context: cache_context(kind, &DefaultCommandProcessor::default()),
+            conversion_times: Default::default(),
+            unknown_commands: Default::default(),
+            unknown_names: HashSet::new(),
+            settings: None,
+            default_brightness: None,
+            liveness: None,
+            percent_encoding: false,
+            value_encoding: ValueEncoding::Quoted,
+            strict: false,
+            pending: VecDeque::new(),
+            max_pending: MAX_PENDING,
+            queue_depth: Default::default(),
+            standby: None,
+            warming: Arc::new(AtomicBool::new(false)),
+            colors_only: None,
+            shown: HashMap::new(),
+        }
+    }
+
+    /// Use the provided cache for converted images.  The cache can be shared
+    /// with the receivers of other devices.
+    pub fn with_cache(mut self, cache: ImageCache) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Count how long images take to convert in times, which can be shared
+    /// with the receivers of other devices.
+    pub fn with_conversion_times(mut self, times: Arc<ConversionTimes>) -> Self {
+        self.conversion_times = times;
+        self
+    }
+
+    /// Count the commands from Companion passed over for not being known in
+    /// unknown_commands, which can be shared with the receivers of other
+    /// devices.
+    pub fn with_unknown_commands(mut self, unknown_commands: Arc<AtomicU64>) -> Self {
+        self.unknown_commands = unknown_commands;
+        self
+    }
+
+    /// Convert images for the device with converter rather than for a
+    /// Stream Deck.
+    pub fn with_converter(mut self, converter: Arc<dyn ImageConverter>) -> Self {
+        self.processor = Arc::new(DefaultCommandProcessor {
+            settings: self.processor.settings.clone(),
+            buffers: self.processor.buffers.clone(),
+            converter,
+            colors_only: self.processor.colors_only,
+            fonts: self.processor.fonts.clone(),
+        });
+        self.cache_context = cache_context(self.kind, &self.processor);
+        self
+    }
+
+    /// Draw the text of keys in fonts, which can be shared with the receivers
+    /// of other devices, rather than in the built in font that only has
+    /// ASCII.
+    pub fn with_fonts(mut self, fonts: Arc<Fonts>) -> Self {
+        self.processor = Arc::new(DefaultCommandProcessor {
+            settings: self.processor.settings.clone(),
+            buffers: self.processor.buffers.clone(),
+            converter: self.processor.converter.clone(),
+            colors_only: self.processor.colors_only,
+            fonts: Some(fonts),
+        });
+        self.cache_context = cache_context(self.kind, &self.processor);
+        self
+    }
+
+    /// Apply runtime settings to the images and brightness sent to the device.
+    /// The settings are applied immediately and again every time they change.
+    pub fn with_settings(mut self, mut settings: watch::Receiver<DeviceSettings>) -> Self {
+        settings.mark_changed();
+        self.settings = Some(settings);
+        self
+    }
+
+    /// Record the PONGs from Companion in liveness, failing with a
+    /// [CompanionTimeout] once they stop coming.
+    pub fn with_liveness(mut self, liveness: Liveness) -> Self {
+        self.liveness = Some(liveness);
+        self
+    }
+
+    /// Read from Companion through a buffer of this many bytes.  A smaller
+    /// buffer takes less memory, but splits the burst of a page change into
+    /// more batches.  Must be set before anything is received.
+    pub fn with_read_buffer(mut self, bytes: usize) -> Self {
+        self.reader = BufReader::with_capacity(bytes, self.reader.into_inner());
+        self
+    }
+
+    /// Keep at most this many buffers for converting images in between
+    /// images.
+    pub fn with_pooled_buffers(mut self, max_pooled: usize) -> Self {
+        let buffers = Arc::new(BufferPool::new(max_pooled));
+        self.lines = LineReader::new(buffers.clone());
+        self.processor = Arc::new(DefaultCommandProcessor {
+            settings: self.processor.settings.clone(),
+            buffers,
+            converter: self.processor.converter.clone(),
+            colors_only: self.processor.colors_only,
+            fonts: self.processor.fonts.clone(),
+        });
+        self
+    }
+
+    /// Convert at most this many images at once, on blocking tasks off the
+    /// read loop.  Defaults to the number of cores.
+    pub fn with_parallelism(mut self, parallelism: NonZeroUsize) -> Self {
+        self.parallelism = parallelism.get();
+        self
+    }
+
+    /// Stop reading from Companion while this many actions are queued for the
+    /// device, converted or not, so a storm of images waits in the socket
+    /// rather than in memory.  A batch already read is always queued whole.
+    pub fn with_max_pending(mut self, max_pending: NonZeroUsize) -> Self {
+        self.max_pending = max_pending.get();
+        self
+    }
+
+    /// Number of actions queued for the device, converted or not.
+    pub fn queue_depth(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Keep queue_depth at the [queue_depth](Self::queue_depth) as it
+    /// changes, so it can be reported while the receiver is busy.
+    pub fn with_queue_depth(mut self, queue_depth: Arc<AtomicUsize>) -> Self {
+        self.queue_depth = queue_depth;
+        self
+    }
+
+    /// Read percent-encoded values once the ApiVersion in the BEGIN from
+    /// Companion says they are supported.
+    pub fn with_percent_encoding(mut self) -> Self {
+        self.percent_encoding = true;
+        self
+    }
+
+    /// Fail on any line from Companion that strays from the grammar of the
+    /// satellite API, saying where, rather than skipping what isn't known.
+    /// Meant for debugging against new builds of Companion.
+    pub fn with_strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    /// Keep the images in standby converted in the cache, ahead of Companion
+    /// sending them.  Standby is KEY-STATE lines exactly as Companion would
+    /// send them, such as the pages either side of the one shown, so that
+    /// switching to one of those pages is only a lookup.  Companion doesn't
+    /// say what is on other pages, so they come from elsewhere, like a script
+    /// driving the admin socket of the gateway.  Standby images are converted
+    /// one at a time on a blocking task, whenever standby or the settings
+    /// change.
+    pub fn with_standby(mut self, mut standby: watch::Receiver<Vec<String>>) -> Self {
+        standby.mark_changed();
+        self.standby = Some(standby);
+        self
+    }
+
+    /// Draw keys from their color and text rather than their images while
+    /// colors_only is set, as for a leaf starved of bandwidth.  Flat colors
+    /// with a little text encode far smaller than most images, and change
+    /// less often.  Keys are drawn again whenever it changes.  Only keys
+    /// Companion sends the color or text of are drawn from them, so the
+    /// device must be added with an API that has colors and text.
+    pub fn with_colors_only(mut self, mut colors_only: watch::Receiver<bool>) -> Self {
+        colors_only.mark_changed();
+        self.colors_only = Some(colors_only);
+        self
+    }
+
+    /// Switch between drawing keys from images and from colors, queueing
+    /// every key shown to be drawn again.
+    fn set_colors_only(&mut self, colors_only: bool) -> Result<()> {
+        if colors_only == self.processor.colors_only {
+            return Ok(());
+        }
+        debug!(
+            "Drawing keys from {}",
+            if colors_only { "colors" } else { "images" }
+        );
+        self.processor = Arc::new(DefaultCommandProcessor {
+            settings: self.processor.settings.clone(),
+            buffers: self.processor.buffers.clone(),
+            converter: self.processor.converter.clone(),
+            colors_only,
+            fonts: self.processor.fonts.clone(),
+        });
+        self.cache_context = cache_context(self.kind, &self.processor);
+        let mut shown: Vec<_> = self.shown.drain().collect();
+        shown.sort_by_key(|(key, _)| *key);
+        self.process_batch(shown.into_iter().map(|(_, line)| line).collect())
+    }
+
+    /// Convert the images in lines that aren't cached yet, in the background,
+    /// giving up on any images still being converted from before.
+    fn warm(&mut self, lines: &[String]) {
+        self.warming.store(true, Ordering::Relaxed);
+        let jobs: Vec<_> = lines
+            .iter()
+            .filter(|line| {
+                let command = Command::parse_with(line, self.value_encoding);
+                matches!(command, Ok(Command::KeyState(_)))
+            })
+            .filter_map(|line| LineReader::whole(self.processor.buffers.clone(), line).ok())
+            .map(|line| (self.cache_key(&line), self.job(line)))
+            .filter(|(key, _)| !self.cache.contains(key))
+            .collect();
+        if jobs.is_empty() || !self.kind.is_visual() {
+            return;
         }
+        debug!("Converting {} images in standby", jobs.len());
+        let warming = Arc::new(AtomicBool::new(false));
+        self.warming = warming.clone();
+        let cache = self.cache.clone();
+        let span = Span::current();
+        tokio::task::spawn_blocking(move || {
+            let _entered = span.enter();
+            for (key, job) in jobs {
+                if warming.load(Ordering::Relaxed) {
+                    trace!("Images in standby replaced");
+                    return;
+                }
+                match job.convert() {
+                    Ok(Some(action)) => cache.put(key, action),
+                    Ok(None) => {}
+                    Err(e) => debug!("Couldn't convert an image in standby: {e}"),
+                }
+            }
+        });
+    }
+
+    /// The job converting the image in line with the current settings.
+    fn job(&self, line: Line) -> Job {
+        Job {
+            line,
+            encoding: self.value_encoding,
+            kind: self.kind,
+            processor: self.processor.clone(),
+            times: self.conversion_times.clone(),
+        }
+    }
+
+    /// Take on new settings, returning an action to send to the device if
+    /// one is needed for the change to be visible.
+    fn apply_settings(&mut self, settings: DeviceSettings) -> Option<DeviceActions> {
+        debug!("Applying device settings: {:?}", settings);
+        let brightness = settings.brightness;
+        // Images already queued are converted with the settings they were
+        // queued with
+        self.processor = Arc::new(DefaultCommandProcessor {
+            settings,
+            buffers: self.processor.buffers.clone(),
+            converter: self.processor.converter.clone(),
+            colors_only: self.processor.colors_only,
+            fonts: self.processor.fonts.clone(),
+        });
+        // Images converted for the old orientation, zones or overlay are looked up
+        // under another key from now on
+        self.cache_context = cache_context(self.kind, &self.processor);
+        if brightness == self.default_brightness {
+            return None;
+        }
+        self.default_brightness = brightness;
+        brightness.map(|brightness| DeviceActions::SetBrightness(SetBrightness { brightness }))
+    }
+
+    /// The key a line is cached under.  The DEVICEID is left out so devices
+    /// sharing the cache and showing the same image share its conversion,
+    /// and a decoded bitmap is stood in for by its digest.
+    fn cache_key(&self, line: &Line) -> String {
+        let mut key = self.cache_context.clone();
+        for token in line.text.trim_end().split(' ') {
+            if !token.starts_with("DEVICEID=") {
+                key.push(' ');
+                key.push_str(token);
+            }
+        }
+        if let Some(bitmap) = &line.bitmap {
+            key += &format!(" BITMAP#{:016x}", bitmap.digest);
+        }
+        key
+    }
+
+    /// Take every complete line already sitting in the read buffer, without
+    /// waiting for more.
+    fn buffered_lines(&mut self) -> Result<Vec<Line>> {
+        let mut lines = Vec::new();
+        while !self.reader.buffer().is_empty() {
+            let (read, line) = self.lines.feed(self.reader.buffer())?;
+            Pin::new(&mut self.reader).consume(read);
+            match line {
+                Some(line) => lines.push(line),
+                None => break,
+            }
+        }
+        Ok(lines)
+    }
+
+    /// Turn a batch of lines into device actions, queueing them in order.
+    /// Only the last image of each key in the batch is converted, and images
+    /// that aren't cached are left to the workers.
+    fn process_batch(&mut self, lines: Vec<Line>) -> Result<()> {
+        let mut commands = Vec::with_capacity(lines.len());
+        for line in &lines {
+            let command = if self.strict {
+                Command::parse_strict(&line.text, self.value_encoding)?
+            } else {
+                Command::parse_with(&line.text, self.value_encoding)?
+            };
+            if let Command::Unknown(name) = &command {
+                // Sent by a newer Companion, and nothing to lose the
+                // connection over
+                self.unknown_commands.fetch_add(1, Ordering::Relaxed);
+                if self.unknown_names.len() < UNKNOWN_NAMES_WARNED
+                    && self.unknown_names.insert(name.to_string())
+                {
+                    warn!("Passing over unknown command {name} from Companion");
+                } else {
+                    debug!("Passing over unknown command {name} from Companion");
+                }
+            }
+            if let Command::Begin(versions) = &command {
+                // Nothing Companion sends after this can be trusted to mean
+                // the same to both sides
+                let features = versions.negotiate()?;
+                if self.percent_encoding {
+                    self.value_encoding = features.value_encoding;
+                    debug!("Reading values {:?}", self.value_encoding);
+                }
+            }
+            commands.push(command);
+        }
+        if let Some(liveness) = &self.liveness {
+            if commands.contains(&Command::Pong) {
+                liveness.pong();
+            }
+        }
+
+        // An image for a key is superseded by a later image for the same key
+        let mut keep = vec![true; commands.len()];
+        let mut last_for_key = HashMap::new();
+        for (index, command) in commands.iter().enumerate() {
+            if let Command::KeyState(keystate) = command {
+                if let Some(previous) = last_for_key.insert(keystate.key, index) {
+                    keep[previous] = false;
+                }
+            }
+        }
+        if self.colors_only.is_some() {
+            // Keys blanked aren't drawn again when switching to colors
+            if let Some(blanked) = commands.iter().rposition(Command::blanks_device) {
+                self.shown.clear();
+                last_for_key.retain(|_, index| *index > blanked);
+            }
+            for (key, index) in last_for_key {
+                self.shown.insert(key, lines[index].clone());
+            }
+        }
+        let superseded = keep.iter().filter(|keep| !**keep).count();
+        if superseded > 0 {
+            debug!(
+                "Skipping {superseded} superseded images in a batch of {}",
+                lines.len()
+            );
+        }
+
+        let mut steps: Vec<Step> = (0..lines.len()).map(|_| Step::Skip).collect();
+        for (index, (command, keep)) in commands.into_iter().zip(keep).enumerate() {
+            if !keep {
+                continue;
+            }
+            let key = self.cache_key(&lines[index]);
+            steps[index] = match self.cache.get(&key) {
+                Some(action) => Step::Send(action),
+                None if matches!(command, Command::KeyState(_)) && self.kind.is_visual() => {
+                    Step::Convert
+                }
+                None => match self
+                    .processor
+                    .process(self.kind, command, lines[index].bitmap())?
+                {
+                    Some(action) => {
+                        self.cache.put(key, action.clone());
+                        Step::Send(action)
+                    }
+                    None => Step::Skip,
+                },
+            };
+        }
+
+        for (line, step) in lines.into_iter().zip(steps) {
+            match step {
+                Step::Skip => {}
+                Step::Send(action) => self.pending.push_back(Pending::Ready(action)),
+                Step::Convert => {
+                    let key = self.cache_key(&line);
+                    let job = self.job(line);
+                    self.pending.push_back(Pending::Queued { key, job });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Hand queued images to workers while fewer than the parallelism are
+    /// busy.  Images are started in order, so the one at the front is always
+    /// started first.
+    fn start_conversions(&mut self) {
+        let mut busy = self.pending.iter().filter(|pending| pending.busy()).count();
+        for index in 0..self.pending.len() {
+            if busy >= self.parallelism {
+                break;
+            }
+            if !matches!(self.pending[index], Pending::Queued { .. }) {
+                continue;
+            }
+            let Some(Pending::Queued { key, job }) = self.pending.remove(index) else {
+                unreachable!("checked to be queued");
+            };
+            let span = Span::current();
+            let task = tokio::task::spawn_blocking(move || span.in_scope(|| job.convert()));
+            let converting = Pending::Converting { key, task };
+            self.pending.insert(index, converting);
+            busy += 1;
+        }
+    }
+
+    /// The action at the front of the queue, if it is ready.
+    fn pop_ready(&mut self) -> Option<DeviceActions> {
+        if !matches!(self.pending.front(), Some(Pending::Ready(_))) {
+            return None;
+        }
+        let action = match self.pending.pop_front() {
+            Some(Pending::Ready(action)) => Some(action),
+            _ => None,
+        };
+        self.report_queue_depth();
+        action
+    }
+
+    /// Publish how many actions are queued.
+    fn report_queue_depth(&self) {
+        self.queue_depth
+            .store(self.pending.len(), Ordering::Relaxed);
+    }
+
+    /// Take the finished conversion at the front of the queue, caching it.
+    fn converted(
+        &mut self,
+        result: std::result::Result<Result<Option<DeviceActions>>, JoinError>,
+    ) -> Result<Option<DeviceActions>> {
+        let Some(Pending::Converting { key, .. }) = self.pending.pop_front() else {
+            anyhow::bail!("No conversion at the front of the queue");
+        };
+        self.report_queue_depth();
+        let action = result.map_err(|e| anyhow::anyhow!("Image conversion failed: {e}"))??;
+        if let Some(action) = &action {
+            self.cache.put(key, action.clone());
+        }
+        Ok(action)
+    }
+}
+
+/// Everything besides the line from Companion that the conversion of an
+/// image depends on.
+fn cache_context(kind: Kind, processor: &DefaultCommandProcessor) -> String {
+    let settings = &processor.settings;
+    let mut context = format!(
+        "{kind:?} {} {:?} {:?} {}",
+        processor.converter.name(),
+        settings.orientation,
+        settings.zones,
+        settings.key_overlay
+    );
+    if processor.colors_only {
+        context += " colors";
+    }
+    if let Some(fonts) = &processor.fonts {
+        context += " fonts=";
+        context += fonts.name();
+    }
+    context
+}
+
+/// Wait for the settings to change.  Never completes if there are no settings
+/// or nobody is left to change them.
+async fn settings_changed(
+    settings: &mut Option<watch::Receiver<DeviceSettings>>,
+) -> DeviceSettings {
+    if let Some(settings) = settings {
+        if settings.changed().await.is_ok() {
+            return settings.borrow_and_update().clone();
+        }
+    }
+    std::future::pending().await
+}
+
+/// Wait for the images in standby to change.  Never completes if there is no
+/// standby or nobody is left to change it.
+async fn standby_changed(standby: &mut Option<watch::Receiver<Vec<String>>>) -> Vec<String> {
+    if let Some(standby) = standby {
+        if standby.changed().await.is_ok() {
+            return standby.borrow_and_update().clone();
+        }
+    }
+    std::future::pending().await
+}
+
+/// Wait for colors only to be switched.  Never completes if there is no
+/// switch or nobody is left to flip it.
+async fn colors_only_changed(colors_only: &mut Option<watch::Receiver<bool>>) -> bool {
+    if let Some(colors_only) = colors_only {
+        if colors_only.changed().await.is_ok() {
+            return *colors_only.borrow_and_update();
+        }
+    }
+    std::future::pending().await
+}
+
+/// Wait for Companion to stop answering.  Never completes if nobody is
+/// watching.
+async fn liveness_expired(liveness: &Option<Liveness>) -> CompanionTimeout {
+    match liveness {
+        Some(liveness) => liveness.expired().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait for the image at the front of the queue to be converted.  Never
+/// completes if the front isn't being converted.
+async fn front_converted(
+    pending: &mut VecDeque<Pending>,
+) -> std::result::Result<Result<Option<DeviceActions>>, JoinError> {
+    match pending.front_mut() {
+        Some(Pending::Converting { task, .. }) => task.await,
+        _ => std::future::pending().await,
     }
 }
 
+/// Things the receiver can wake up for.
+enum Event {
+    Line(Option<Line>),
+    Settings(DeviceSettings),
+    Standby(Vec<String>),
+    ColorsOnly(bool),
+    Converted(std::result::Result<Result<Option<DeviceActions>>, JoinError>),
+}
+
 #[async_trait]
 impl<R> traits::companion::Receiver for Receiver<R>
 where
@@ -165,20 +1163,500 @@ where
     async fn receive(&mut self) -> Result<traits::device::DeviceActions> {
         // read a line from the stream
         loop {
-            let mut line = String::new();
-            self.reader.read_line(&mut line).await?;
+            self.start_conversions();
+            if let Some(action) = self.pop_ready() {
+                return Ok(action);
+            }
+            if self.closed && self.pending.is_empty() {
+                anyhow::bail!("Companion closed the connection")
+            }
+            self.report_queue_depth();
+
+            // What is read of a line is kept in self.lines, so a partially
+            // read line survives a settings change or conversion interrupting
+            // the read.
+            let event = tokio::select! {
+                line = read_line(&mut self.reader, &mut self.lines),
+                    if !self.closed && self.pending.len() < self.max_pending =>
+                {
+                    Event::Line(line?)
+                }
+                settings = settings_changed(&mut self.settings) => Event::Settings(settings),
+                standby = standby_changed(&mut self.standby) => Event::Standby(standby),
+                colors_only = colors_only_changed(&mut self.colors_only) => {
+                    Event::ColorsOnly(colors_only)
+                }
+                timeout = liveness_expired(&self.liveness) => return Err(timeout.into()),
+                converted = front_converted(&mut self.pending) => Event::Converted(converted),
+            };
+            let line = match event {
+                Event::Line(None) => {
+                    // Images still being converted are sent before failing
+                    self.closed = true;
+                    continue;
+                }
+                Event::Line(Some(line)) => line,
+                Event::Settings(settings) => {
+                    let action = self.apply_settings(settings);
+                    // Images in standby are wanted for the new settings
+                    if let Some(standby) = &self.standby {
+                        let lines = standby.borrow().clone();
+                        self.warm(&lines);
+                    }
+                    match action {
+                        Some(action) => return Ok(action),
+                        None => continue,
+                    }
+                }
+                Event::Standby(lines) => {
+                    self.warm(&lines);
+                    continue;
+                }
+                Event::ColorsOnly(colors_only) => {
+                    self.set_colors_only(colors_only)?;
+                    continue;
+                }
+                Event::Converted(result) => match self.converted(result)? {
+                    Some(action) => return Ok(action),
+                    None => continue,
+                },
+            };
+            // Companion sends bursts of lines (page changes), so handle
+            // everything that has already arrived together.
+            let mut lines = vec![line];
+            lines.extend(self.buffered_lines()?);
+            self.process_batch(lines)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine as _;
+    use traits::companion::Receiver as _;
+
+    fn key_state(key: u8, value: u8) -> String {
+        let size = Kind::Mk2.key_image_format().size.0;
+        let bitmap = vec![value; size * size * 3];
+        format!(
+            "KEY-STATE DEVICEID=deck KEY={key} TYPE=BUTTON BITMAP={} PRESSED=false\n",
+            base64::engine::general_purpose::STANDARD_NO_PAD.encode(bitmap)
+        )
+    }
+
+    #[tokio::test]
+    async fn test_batch_keeps_last_image_per_key() {
+        let data = [
+            key_state(1, 0),
+            key_state(2, 0),
+            key_state(1, 255),
+            String::from("BRIGHTNESS DEVICEID=deck VALUE=50\n"),
+        ]
+        .concat();
+        let mut receiver = Receiver::new(data.as_bytes(), Kind::Mk2);
+
+        let mut buttons = Vec::new();
+        loop {
+            match receiver.receive().await.unwrap() {
+                DeviceActions::SetButtonImage(image) => buttons.push(image.button),
+                DeviceActions::SetBrightness(brightness) => {
+                    assert_eq!(brightness.brightness, 50);
+                    break;
+                }
+                action => panic!("Unexpected action {action:?}"),
+            }
+        }
+        assert_eq!(buttons, vec![2, 1]);
+        assert!(receiver.receive().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bitmap_decoded_while_read() {
+        async fn image(data: String, read_buffer: usize) -> Vec<u8> {
+            let mut receiver =
+                Receiver::new(data.as_bytes(), Kind::Mk2).with_read_buffer(read_buffer);
+            match receiver.receive().await.unwrap() {
+                DeviceActions::SetButtonImage(image) => image.image,
+                action => panic!("Unexpected action {action:?}"),
+            }
+        }
+
+        // Bytes of 251 are all + and / in base64, which percent encoded
+        // values escape
+        let line = key_state(1, 251);
+        let escaped = line.replace('+', "%2B").replace('/', "%2F");
+        // Read a few bytes at a time, splitting escapes and groups of base64
+        let whole = image(line.clone(), READ_BUFFER_SIZE).await;
+        assert_eq!(image(escaped, 7).await, whole);
+
+        let read = LineReader::whole(Default::default(), &line).unwrap();
+        assert_eq!(
+            read.text,
+            "KEY-STATE DEVICEID=deck KEY=1 TYPE=BUTTON PRESSED=false\n"
+        );
+        assert_eq!(read.bitmap().map(<[u8]>::len), Some(72 * 72 * 3));
+    }
+
+    #[tokio::test]
+    async fn test_locking_blanks_device() {
+        let data = [
+            key_state(1, 0),
+            String::from("LOCKED-STATE DEVICEID=deck LOCKED=true CHARACTERS=0\n"),
+            String::from("LOCKED-STATE DEVICEID=deck LOCKED=true CHARACTERS=1\n"),
+            String::from("LOCKED-STATE DEVICEID=deck LOCKED=false\n"),
+            String::from("KEYS-CLEAR DEVICEID=deck\n"),
+        ]
+        .concat();
+        let mut receiver = Receiver::new(data.as_bytes(), Kind::Mk2);
+
+        let mut actions = Vec::new();
+        while let Ok(action) = receiver.receive().await {
+            actions.push(match action {
+                DeviceActions::SetButtonImage(image) => Some(image.button),
+                DeviceActions::ClearAll => None,
+                action => panic!("Unexpected action {action:?}"),
+            });
+        }
+        // Blanked after the image, once when locked and once when cleared
+        assert_eq!(actions, [Some(1), None, None]);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_commands_counted() {
+        let data = [
+            String::from("VARIABLE-VALUE DEVICEID=deck VARIABLE=a VALUE=1\n"),
+            String::from("VARIABLE-VALUE DEVICEID=deck VARIABLE=b VALUE=2\n"),
+            String::from("SURFACE-NEW DEVICEID=deck\n"),
+            String::from("BRIGHTNESS DEVICEID=deck VALUE=50\n"),
+        ]
+        .concat();
+        let unknown_commands = Arc::new(AtomicU64::new(0));
+        let mut receiver = Receiver::new(data.as_bytes(), Kind::Mk2)
+            .with_unknown_commands(unknown_commands.clone());
+        assert!(matches!(
+            receiver.receive().await.unwrap(),
+            DeviceActions::SetBrightness(_)
+        ));
+        assert_eq!(unknown_commands.load(Ordering::Relaxed), 3);
+        // Each unknown command is warned about by name, once
+        let mut names: Vec<_> = receiver.unknown_names.iter().cloned().collect();
+        names.sort();
+        assert_eq!(names, ["SURFACE-NEW", "VARIABLE-VALUE"]);
+
+        // Strict, the same line fails the connection
+        let mut receiver = Receiver::new(data.as_bytes(), Kind::Mk2).with_strict();
+        assert!(receiver.receive().await.is_err());
+    }
+
+    /// Packs keys as RGB565, as a small TFT would take them.
+    struct Rgb565;
+
+    impl ImageConverter for Rgb565 {
+        fn name(&self) -> &str {
+            "rgb565"
+        }
+
+        fn convert_key(&self, _kind: Kind, rgb: &[u8], _pool: &BufferPool) -> Result<Vec<u8>> {
+            Ok(rgb
+                .chunks_exact(3)
+                .flat_map(|p| {
+                    let (r, g, b) = (u16::from(p[0]), u16::from(p[1]), u16::from(p[2]));
+                    ((r >> 3) << 11 | (g >> 2) << 5 | b >> 3).to_be_bytes()
+                })
+                .collect())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_converter() {
+        let data = key_state(3, 255);
+        let cache = ImageCache::default();
+        let mut receiver = Receiver::new(data.as_bytes(), Kind::Mk2)
+            .with_cache(cache.clone())
+            .with_converter(Arc::new(Rgb565));
+        let DeviceActions::SetButtonImage(image) = receiver.receive().await.unwrap() else {
+            panic!("Expected a button image");
+        };
+        let size = Kind::Mk2.key_image_format().size.0;
+        assert_eq!(image.image, vec![0xff; size * size * 2]);
+
+        // Another converter doesn't get its images from the cache
+        let mut receiver = Receiver::new(data.as_bytes(), Kind::Mk2).with_cache(cache);
+        let DeviceActions::SetButtonImage(image) = receiver.receive().await.unwrap() else {
+            panic!("Expected a button image");
+        };
+        assert_ne!(image.image.len(), size * size * 2);
+    }
+
+    #[tokio::test]
+    async fn test_pedal_key_states_ignored() {
+        // A Pedal has no screen, so Companion sends its keys without images
+        let data = [
+            "KEY-STATE DEVICEID=pedal KEY=1 TYPE=BUTTON PRESSED=true\n",
+            "BRIGHTNESS DEVICEID=pedal VALUE=50\n",
+        ]
+        .concat();
+        let mut receiver = Receiver::new(data.as_bytes(), Kind::Pedal);
+        assert!(matches!(
+            receiver.receive().await.unwrap(),
+            DeviceActions::SetBrightness(SetBrightness { brightness: 50 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_key_index_overlaid() {
+        use tokio::io::AsyncWriteExt;
+
+        async fn corners<R>(receiver: &mut Receiver<R>) -> [[u8; 2]; 2]
+        where
+            R: AsyncRead + Unpin + Send,
+        {
+            let DeviceActions::SetButtonImage(image) = receiver.receive().await.unwrap() else {
+                panic!("Expected a button image");
+            };
+            let (first, last) = image.image.split_at(image.image.len() - 2);
+            [[first[0], first[1]], [last[0], last[1]]]
+        }
+
+        let line = key_state(12, 255);
+        let cache = ImageCache::default();
+        let overlay = DeviceSettings {
+            brightness: Some(50),
+            key_overlay: true,
+            ..Default::default()
+        };
+        let (_settings, settings) = watch::channel(overlay);
+        let (reader, mut writer) = tokio::io::duplex(1024 * 1024);
+        let mut receiver = Receiver::new(reader, Kind::Mk2)
+            .with_cache(cache.clone())
+            .with_converter(Arc::new(Rgb565))
+            .with_settings(settings);
+        // Taking on the settings before the image comes
+        assert!(matches!(
+            receiver.receive().await.unwrap(),
+            DeviceActions::SetBrightness(_)
+        ));
+        writer.write_all(line.as_bytes()).await.unwrap();
+        // The index is labelled in the top left corner only
+        assert_eq!(corners(&mut receiver).await, [[0, 0], [0xff, 0xff]]);
+
+        // Without the overlay, the labelled image isn't taken from the cache
+        let mut receiver = Receiver::new(line.as_bytes(), Kind::Mk2)
+            .with_cache(cache)
+            .with_converter(Arc::new(Rgb565));
+        assert_eq!(corners(&mut receiver).await, [[0xff, 0xff], [0xff, 0xff]]);
+    }
+
+    #[tokio::test]
+    async fn test_standby_converted_ahead() {
+        use tokio::io::AsyncWriteExt;
+
+        let cache = ImageCache::default();
+        let (reader, mut writer) = tokio::io::duplex(1024 * 1024);
+        let (_standby, standby) = watch::channel(vec![key_state(3, 7)]);
+        let mut receiver = Receiver::new(reader, Kind::Mk2)
+            .with_cache(cache.clone())
+            .with_standby(standby);
+        let stats = cache.stats();
+        tokio::spawn(async move {
+            while stats.snapshot().bytes == 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            }
+            // Companion switches to the page, for another device id
+            let line = key_state(3, 7).replace("DEVICEID=deck", "DEVICEID=other");
+            writer.write_all(line.as_bytes()).await.unwrap();
+        });
+
+        let DeviceActions::SetButtonImage(image) = receiver.receive().await.unwrap() else {
+            panic!("Expected a button image");
+        };
+        assert_eq!(image.button, 3);
+        let stats = cache.stats().snapshot();
+        assert_eq!((stats.hits, stats.misses), (1, 0));
+    }
+
+    #[tokio::test]
+    async fn test_colors_only_drawn_again() {
+        use tokio::io::AsyncWriteExt;
+
+        let (reader, mut writer) = tokio::io::duplex(1024 * 1024);
+        let (colors_only, colors_only_receiver) = watch::channel(false);
+        let mut receiver = Receiver::new(reader, Kind::Mk2)
+            .with_converter(Arc::new(Rgb565))
+            .with_colors_only(colors_only_receiver);
+        let line = key_state(3, 255).replace(" PRESSED", " COLOR=#ff0000 PRESSED");
+        writer.write_all(line.as_bytes()).await.unwrap();
+        async fn first_pixel(receiver: &mut Receiver<tokio::io::DuplexStream>) -> [u8; 2] {
+            let DeviceActions::SetButtonImage(image) = receiver.receive().await.unwrap() else {
+                panic!("Expected a button image");
+            };
+            assert_eq!(image.button, 3);
+            [image.image[0], image.image[1]]
+        }
+        assert_eq!(first_pixel(&mut receiver).await, [0xff, 0xff]);
+
+        // The key shown is drawn from its color, and from its image again
+        colors_only.send_replace(true);
+        assert_eq!(first_pixel(&mut receiver).await, [0xf8, 0x00]);
+        colors_only.send_replace(false);
+        assert_eq!(first_pixel(&mut receiver).await, [0xff, 0xff]);
+    }
+
+    #[tokio::test]
+    async fn test_times_out_without_pong() {
+        use tokio::io::AsyncWriteExt;
+
+        let start = tokio::time::Instant::now();
+        let timeout = std::time::Duration::from_millis(50);
+        let (reader, mut writer) = tokio::io::duplex(1024);
+        let mut receiver = Receiver::new(reader, Kind::Mk2).with_liveness(Liveness::new(timeout));
+        tokio::time::sleep(timeout / 2).await;
+        writer.write_all(b"PONG\n").await.unwrap();
+
+        let error = receiver.receive().await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<CompanionTimeout>(),
+            Some(&CompanionTimeout { timeout })
+        );
+        // The PONG bought the connection more time
+        assert!(start.elapsed() >= timeout * 3 / 2);
+    }
+
+    /// Holds every conversion until opened, then sends each key its first
+    /// pixel.
+    struct Gated(Arc<AtomicBool>);
+
+    impl ImageConverter for Gated {
+        fn name(&self) -> &str {
+            "gated"
+        }
+
+        fn convert_key(&self, _kind: Kind, rgb: &[u8], _pool: &BufferPool) -> Result<Vec<u8>> {
+            while !self.0.load(Ordering::Relaxed) {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            Ok(rgb[..3].to_vec())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reading_stops_while_queue_full() {
+        use tokio::io::AsyncWriteExt;
+
+        let open = Arc::new(AtomicBool::new(false));
+        let queue_depth = Arc::new(AtomicUsize::new(0));
+        let (reader, mut writer) = tokio::io::duplex(1024 * 1024);
+        let mut receiver = Receiver::new(reader, Kind::Mk2)
+            .with_converter(Arc::new(Gated(open.clone())))
+            .with_max_pending(NonZeroUsize::new(2).unwrap())
+            .with_queue_depth(queue_depth.clone());
+        let (actions, mut received) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok(action) = receiver.receive().await {
+                let _ = actions.send(action);
+            }
+        });
 
-            if let Some(command) = self.cache.get(&line) {
-                return Ok(command.clone());
+        let lines = [key_state(1, 1), key_state(2, 2)].concat();
+        writer.write_all(lines.as_bytes()).await.unwrap();
+        while queue_depth.load(Ordering::Relaxed) < 2 {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        writer.write_all(key_state(3, 3).as_bytes()).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        // The third image waits unread until the queue drains
+        assert_eq!(queue_depth.load(Ordering::Relaxed), 2);
+        assert!(received.try_recv().is_err());
+
+        open.store(true, Ordering::Relaxed);
+        let mut shown = Vec::new();
+        while shown.len() < 3 {
+            match received.recv().await.unwrap() {
+                DeviceActions::SetButtonImage(image) => shown.push((image.button, image.image[0])),
+                action => panic!("Unexpected action {action:?}"),
             }
+        }
+        assert_eq!(shown, [(1, 1), (2, 2), (3, 3)]);
+        assert_eq!(queue_depth.load(Ordering::Relaxed), 0);
+    }
 
-            let command = Command::parse(&line)?;
+    /// Takes as many milliseconds to convert each key as its first byte,
+    /// counting the most conversions running at once.
+    #[derive(Default)]
+    struct Timed {
+        running: AtomicUsize,
+        most: AtomicUsize,
+    }
 
-            let processor = &mut self.processor;
-            if let Some(commands) = processor.process(self.kind, command)? {
-                self.cache.put(line, commands.clone());
-                return Ok(commands);
+    impl ImageConverter for Timed {
+        fn name(&self) -> &str {
+            "timed"
+        }
+
+        fn convert_key(&self, _kind: Kind, rgb: &[u8], _pool: &BufferPool) -> Result<Vec<u8>> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most.fetch_max(running, Ordering::SeqCst);
+            std::thread::sleep(std::time::Duration::from_millis(rgb[0].into()));
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            Ok(rgb[..3].to_vec())
+        }
+    }
+
+    async fn shown(receiver: &mut Receiver<impl AsyncRead + Unpin + Send>) -> (u8, u8) {
+        match receiver.receive().await.unwrap() {
+            DeviceActions::SetButtonImage(image) => (image.button, image.image[0]),
+            action => panic!("Unexpected action {action:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_conversions_limited_to_parallelism() {
+        let converter = Arc::new(Timed::default());
+        let data: String = (0..6).map(|key| key_state(key, 20)).collect();
+        let mut receiver = Receiver::new(data.as_bytes(), Kind::Mk2)
+            .with_converter(converter.clone())
+            .with_parallelism(NonZeroUsize::new(2).unwrap());
+
+        for key in 0..6 {
+            assert_eq!(shown(&mut receiver).await, (key, 20));
+        }
+        assert_eq!(converter.most.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_key_order_kept_when_later_image_faster() {
+        use tokio::io::AsyncWriteExt;
+
+        let converter = Arc::new(Timed::default());
+        let queue_depth = Arc::new(AtomicUsize::new(0));
+        let (reader, mut writer) = tokio::io::duplex(1024 * 1024);
+        let mut receiver = Receiver::new(reader, Kind::Mk2)
+            .with_converter(converter.clone())
+            .with_parallelism(NonZeroUsize::new(4).unwrap())
+            .with_queue_depth(queue_depth.clone());
+        let (actions, mut received) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let _ = actions.send(shown(&mut receiver).await);
             }
+        });
+
+        // The slow image is read before the fast ones, so isn't replaced
+        // by them
+        let slow = key_state(1, 100);
+        writer.write_all(slow.as_bytes()).await.unwrap();
+        while queue_depth.load(Ordering::Relaxed) < 1 {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        let lines = [key_state(2, 1), key_state(1, 2)].concat();
+        writer.write_all(lines.as_bytes()).await.unwrap();
+
+        let mut order = Vec::new();
+        while order.len() < 3 {
+            order.push(received.recv().await.unwrap());
         }
+        assert_eq!(order, [(1, 100), (2, 1), (1, 2)]);
+        assert!(converter.most.load(Ordering::SeqCst) > 1);
     }
 }
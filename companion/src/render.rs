@@ -0,0 +1,215 @@
+//! Drawing keys from their color and text.
+//!
+//! Companion can send the background color and text of a key instead of an
+//! image of it, which is a few bytes rather than kilobytes of base64.  Keys
+//! are then drawn here: filled with the color, with the text in a small
+//! built in font centered on top, wrapped onto as many lines as fit.  It
+//! looks plainer than what Companion draws but is readable on every key.
+//!
+//! The same font labels keys with their index when debugging.  Text that
+//! isn't ASCII is drawn in [Fonts](crate::fonts::Fonts) loaded from font
+//! files, if the receiver is given any.
+
+/// Width of a glyph in pixels, before scaling.
+const GLYPH_WIDTH: usize = 5;
+/// Height of a glyph in pixels, before scaling, with room for descenders.
+const GLYPH_HEIGHT: usize = 8;
+/// Pixels of key side for every time the font is scaled up.
+const PIXELS_PER_SCALE: usize = 36;
+
+/// Columns of the printable ASCII characters from space to tilde, the lowest
+/// bit being the top row.
+#[rustfmt::skip]
+const FONT: [[u8; GLYPH_WIDTH]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], [0x00, 0x00, 0x5f, 0x00, 0x00], [0x00, 0x07, 0x00, 0x07, 0x00],
+    [0x14, 0x7f, 0x14, 0x7f, 0x14], [0x24, 0x2a, 0x7f, 0x2a, 0x12], [0x23, 0x13, 0x08, 0x64, 0x62],
+    [0x36, 0x49, 0x56, 0x20, 0x50], [0x00, 0x08, 0x07, 0x03, 0x00], [0x00, 0x1c, 0x22, 0x41, 0x00],
+    [0x00, 0x41, 0x22, 0x1c, 0x00], [0x2a, 0x1c, 0x7f, 0x1c, 0x2a], [0x08, 0x08, 0x3e, 0x08, 0x08],
+    [0x00, 0x80, 0x70, 0x30, 0x00], [0x08, 0x08, 0x08, 0x08, 0x08], [0x00, 0x00, 0x60, 0x60, 0x00],
+    [0x20, 0x10, 0x08, 0x04, 0x02], [0x3e, 0x51, 0x49, 0x45, 0x3e], [0x00, 0x42, 0x7f, 0x40, 0x00],
+    [0x72, 0x49, 0x49, 0x49, 0x46], [0x21, 0x41, 0x49, 0x4d, 0x33], [0x18, 0x14, 0x12, 0x7f, 0x10],
+    [0x27, 0x45, 0x45, 0x45, 0x39], [0x3c, 0x4a, 0x49, 0x49, 0x31], [0x41, 0x21, 0x11, 0x09, 0x07],
+    [0x36, 0x49, 0x49, 0x49, 0x36], [0x46, 0x49, 0x49, 0x29, 0x1e], [0x00, 0x00, 0x14, 0x00, 0x00],
+    [0x00, 0x40, 0x34, 0x00, 0x00], [0x00, 0x08, 0x14, 0x22, 0x41], [0x14, 0x14, 0x14, 0x14, 0x14],
+    [0x00, 0x41, 0x22, 0x14, 0x08], [0x02, 0x01, 0x59, 0x09, 0x06], [0x3e, 0x41, 0x5d, 0x59, 0x4e],
+    [0x7c, 0x12, 0x11, 0x12, 0x7c], [0x7f, 0x49, 0x49, 0x49, 0x36], [0x3e, 0x41, 0x41, 0x41, 0x22],
+    [0x7f, 0x41, 0x41, 0x41, 0x3e], [0x7f, 0x49, 0x49, 0x49, 0x41], [0x7f, 0x09, 0x09, 0x09, 0x01],
+    [0x3e, 0x41, 0x41, 0x51, 0x73], [0x7f, 0x08, 0x08, 0x08, 0x7f], [0x00, 0x41, 0x7f, 0x41, 0x00],
+    [0x20, 0x40, 0x41, 0x3f, 0x01], [0x7f, 0x08, 0x14, 0x22, 0x41], [0x7f, 0x40, 0x40, 0x40, 0x40],
+    [0x7f, 0x02, 0x1c, 0x02, 0x7f], [0x7f, 0x04, 0x08, 0x10, 0x7f], [0x3e, 0x41, 0x41, 0x41, 0x3e],
+    [0x7f, 0x09, 0x09, 0x09, 0x06], [0x3e, 0x41, 0x51, 0x21, 0x5e], [0x7f, 0x09, 0x19, 0x29, 0x46],
+    [0x26, 0x49, 0x49, 0x49, 0x32], [0x03, 0x01, 0x7f, 0x01, 0x03], [0x3f, 0x40, 0x40, 0x40, 0x3f],
+    [0x1f, 0x20, 0x40, 0x20, 0x1f], [0x3f, 0x40, 0x38, 0x40, 0x3f], [0x63, 0x14, 0x08, 0x14, 0x63],
+    [0x03, 0x04, 0x78, 0x04, 0x03], [0x61, 0x59, 0x49, 0x4d, 0x43], [0x00, 0x7f, 0x41, 0x41, 0x41],
+    [0x02, 0x04, 0x08, 0x10, 0x20], [0x00, 0x41, 0x41, 0x41, 0x7f], [0x04, 0x02, 0x01, 0x02, 0x04],
+    [0x40, 0x40, 0x40, 0x40, 0x40], [0x00, 0x03, 0x07, 0x08, 0x00], [0x20, 0x54, 0x54, 0x78, 0x40],
+    [0x7f, 0x28, 0x44, 0x44, 0x38], [0x38, 0x44, 0x44, 0x44, 0x28], [0x38, 0x44, 0x44, 0x28, 0x7f],
+    [0x38, 0x54, 0x54, 0x54, 0x18], [0x00, 0x08, 0x7e, 0x09, 0x02], [0x18, 0xa4, 0xa4, 0x9c, 0x78],
+    [0x7f, 0x08, 0x04, 0x04, 0x78], [0x00, 0x44, 0x7d, 0x40, 0x00], [0x20, 0x40, 0x40, 0x3d, 0x00],
+    [0x7f, 0x10, 0x28, 0x44, 0x00], [0x00, 0x41, 0x7f, 0x40, 0x00], [0x7c, 0x04, 0x78, 0x04, 0x78],
+    [0x7c, 0x08, 0x04, 0x04, 0x78], [0x38, 0x44, 0x44, 0x44, 0x38], [0xfc, 0x18, 0x24, 0x24, 0x18],
+    [0x18, 0x24, 0x24, 0x18, 0xfc], [0x7c, 0x08, 0x04, 0x04, 0x08], [0x48, 0x54, 0x54, 0x54, 0x24],
+    [0x04, 0x04, 0x3f, 0x44, 0x24], [0x3c, 0x40, 0x40, 0x20, 0x7c], [0x1c, 0x20, 0x40, 0x20, 0x1c],
+    [0x3c, 0x40, 0x30, 0x40, 0x3c], [0x44, 0x28, 0x10, 0x28, 0x44], [0x4c, 0x90, 0x90, 0x90, 0x7c],
+    [0x44, 0x64, 0x54, 0x4c, 0x44], [0x00, 0x08, 0x36, 0x41, 0x00], [0x00, 0x00, 0x77, 0x00, 0x00],
+    [0x00, 0x41, 0x36, 0x08, 0x00], [0x02, 0x01, 0x02, 0x04, 0x02],
+];
+
+/// The columns of a character, characters the font doesn't have being drawn
+/// as a question mark.
+fn glyph(c: char) -> &'static [u8; GLYPH_WIDTH] {
+    let index = match c {
+        ' '..='~' => c as usize - ' ' as usize,
+        _ => '?' as usize - ' ' as usize,
+    };
+    &FONT[index]
+}
+
+/// Break text into lines of at most width characters, at spaces where
+/// possible.  Companion writes line breaks as `\n`.
+fn wrap(text: &str, width: usize) -> Vec<Vec<char>> {
+    let mut lines = Vec::new();
+    for paragraph in text.replace("\\n", "\n").split('\n') {
+        let mut line: Vec<char> = Vec::new();
+        for word in paragraph.split(' ').filter(|word| !word.is_empty()) {
+            let word: Vec<char> = word.chars().collect();
+            if !line.is_empty() && line.len() + 1 + word.len() <= width {
+                line.push(' ');
+                line.extend(word);
+                continue;
+            }
+            if !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+            }
+            // Words too long for a line are broken up
+            let mut chunks = word.chunks(width.max(1)).peekable();
+            while let Some(chunk) = chunks.next() {
+                if chunks.peek().is_some() {
+                    lines.push(chunk.to_vec());
+                } else {
+                    line = chunk.to_vec();
+                }
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Draw a key of side by side pixels as packed RGB into out, replacing what
+/// it held.  Keys without a color are black, and text is drawn in black or
+/// white, whichever stands out from the background.
+pub fn draw_key(side: usize, color: Option<[u8; 3]>, text: &str, out: &mut Vec<u8>) {
+    let ink = fill_key(side, color, out);
+    let scale = (side / PIXELS_PER_SCALE).max(1);
+    let advance = (GLYPH_WIDTH + 1) * scale;
+    let line_height = (GLYPH_HEIGHT + 1) * scale;
+    let mut lines = wrap(text, side / advance);
+    lines.truncate((side / line_height).max(1));
+
+    let top = side.saturating_sub(lines.len() * line_height) / 2;
+    for (row, line) in lines.iter().enumerate() {
+        let left = side.saturating_sub(line.len() * advance) / 2;
+        for (column, c) in line.iter().enumerate() {
+            let origin = (left + column * advance, top + row * line_height);
+            draw_glyph(side, *c, origin, scale, ink, out);
+        }
+    }
+}
+
+/// Fill out with a key of side by side pixels in color, or black without
+/// one, returning the color of text that stands out from it.
+pub(crate) fn fill_key(side: usize, color: Option<[u8; 3]>, out: &mut Vec<u8>) -> [u8; 3] {
+    let background = color.unwrap_or([0, 0, 0]);
+    out.clear();
+    out.reserve(side * side * 3);
+    for _ in 0..side * side {
+        out.extend_from_slice(&background);
+    }
+
+    let [r, g, b] = background.map(u32::from);
+    let luma = (r * 299 + g * 587 + b * 114) / 1000;
+    if luma > 128 {
+        [0, 0, 0]
+    } else {
+        [0xff; 3]
+    }
+}
+
+/// Draw label in the top left corner of a key image of side by side pixels,
+/// white on a black box so it can be read on any image.
+pub fn draw_label(side: usize, label: &str, rgb: &mut [u8]) {
+    let scale = (side / (2 * PIXELS_PER_SCALE)).max(1);
+    let advance = (GLYPH_WIDTH + 1) * scale;
+    let width = (label.chars().count() * advance + scale).min(side);
+    let height = ((GLYPH_HEIGHT + 1) * scale).min(side);
+    for y in 0..height {
+        let row = y * side * 3;
+        rgb[row..row + width * 3].fill(0);
+    }
+    for (column, c) in label.chars().enumerate() {
+        let origin = (scale + column * advance, scale);
+        draw_glyph(side, c, origin, scale, [0xff; 3], rgb);
+    }
+}
+
+/// Draw a character scaled up with its top left corner at origin, clipping
+/// whatever falls outside the key.
+fn draw_glyph(
+    side: usize,
+    c: char,
+    (x0, y0): (usize, usize),
+    scale: usize,
+    ink: [u8; 3],
+    rgb: &mut [u8],
+) {
+    for (dx, bits) in glyph(c).iter().enumerate() {
+        for dy in (0..GLYPH_HEIGHT).filter(|dy| bits & (1 << dy) != 0) {
+            for sy in 0..scale {
+                for sx in 0..scale {
+                    let (x, y) = (x0 + dx * scale + sx, y0 + dy * scale + sy);
+                    if x < side && y < side {
+                        let index = (y * side + x) * 3;
+                        rgb[index..index + 3].copy_from_slice(&ink);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_color_filled_and_text_centered() {
+        assert_eq!(
+            wrap("Mic 1 mute", 6),
+            [vec!['M', 'i', 'c', ' ', '1'], "mute".chars().collect()]
+        );
+        assert_eq!(wrap("Lights\\nOn", 6).len(), 2);
+        assert_eq!(wrap("Overture", 6).len(), 2);
+
+        let mut out = Vec::new();
+        draw_key(72, Some([0xff, 0x80, 0x00]), "", &mut out);
+        assert_eq!(out.len(), 72 * 72 * 3);
+        assert!(out.chunks(3).all(|pixel| pixel == [0xff, 0x80, 0x00]));
+
+        // Dark text on a light background, and nothing drawn at the edges
+        draw_key(72, Some([0xff; 3]), "I", &mut out);
+        let ink = out.chunks(3).filter(|pixel| *pixel == [0, 0, 0]).count();
+        assert!(ink > 0);
+        let pixel = |x: usize, y: usize| &out[(y * 72 + x) * 3..(y * 72 + x) * 3 + 3];
+        assert_eq!(pixel(0, 0), [0xff; 3]);
+        assert_eq!(pixel(34, 36), [0, 0, 0]);
+
+        // Labels go in the corner, leaving the rest of the image alone
+        draw_label(72, "12", &mut out);
+        let pixel = |x: usize, y: usize| &out[(y * 72 + x) * 3..(y * 72 + x) * 3 + 3];
+        assert_eq!(pixel(0, 0), [0, 0, 0]);
+        assert_eq!(pixel(12, 8), [0, 0, 0]);
+        assert_eq!(pixel(3, 4), [0xff; 3]);
+        assert_eq!(pixel(71, 71), [0xff; 3]);
+    }
+}
@@ -1,25 +1,104 @@
 use std::sync::Arc;
+use std::time::Duration;
 
-use leaf_comm::{RemoteConfig, ButtonChange, EncoderTwist};
+use elgato_streamdeck::info::Kind;
+use leaf_comm::{
+    ButtonChange, EncoderPress, EncoderTwist, PowerState, RemoteConfig, ShutdownReason,
+    StatusImage, Touch, TouchGesture,
+};
 use tokio::{
     io::{AsyncWrite, AsyncWriteExt},
-    sync::Mutex,
+    sync::{Mutex, Notify},
 };
-use tracing::debug;
+use tracing::{debug, Instrument};
 use traits::anyhow;
 use traits::async_trait;
 use traits::Result;
 
+use crate::messages::{AddDeviceMsg, KeyPressMsg, KeyRotateMsg, Message, PingMsg, RemoveDeviceMsg};
+use crate::version::Features;
+
+/// The software devices are added to Companion through.
+const MODULE: &str = "rust_satellite";
+
+/// When the lines of a [Sender] are written to Companion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushPolicy {
+    /// Every input event is written, in a single write, as soon as it happens
+    #[default]
+    Immediate,
+    /// Input events are held for up to the window and written together,
+    /// trading a little latency for fewer writes
+    Batched(Duration),
+}
+
+/// How a [Sender] talks to Companion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SenderConfig {
+    /// Time between PINGs.  Should be well below the PONG timeout of the
+    /// receiver.
+    pub ping_interval: Duration,
+    /// When input events are written
+    pub flush_policy: FlushPolicy,
+    /// What the Companion being talked to understands
+    pub features: Features,
+}
+
+impl Default for SenderConfig {
+    fn default() -> Self {
+        Self {
+            ping_interval: Duration::from_millis(10),
+            flush_policy: FlushPolicy::Immediate,
+            features: Features::default(),
+        }
+    }
+}
+
+/// Lines waiting to be written by a batching sender.
+#[derive(Default)]
+struct Pending {
+    lines: std::sync::Mutex<String>,
+    added: Notify,
+}
+
 pub struct Sender<W> {
     device_id: String,
+    kind: Kind,
     writer: Arc<Mutex<W>>,
+    flush_policy: FlushPolicy,
+    pending: Arc<Pending>,
     ping: tokio::task::JoinHandle<Result<()>>,
+    /// Why the device is about to be removed, if it was said
+    goodbye: Option<ShutdownReason>,
 }
 impl<W> Sender<W>
 where
     W: AsyncWrite + Unpin + Send + 'static,
 {
-    pub async fn new(mut writer: W, config: RemoteConfig) -> Result<Self> {
+    pub async fn new(writer: W, config: RemoteConfig) -> Result<Self> {
+        Self::with_ping_payload(writer, config, String::new).await
+    }
+
+    /// Create a sender whose periodic PING messages carry the string returned
+    /// by ping_payload.  Companion echoes the payload back in its PONG, so this
+    /// is a cheap way to make internal state (such as pump health counters)
+    /// visible in Companion's connection logs.
+    pub async fn with_ping_payload(
+        writer: W,
+        config: RemoteConfig,
+        ping_payload: impl Fn() -> String + Send + 'static,
+    ) -> Result<Self> {
+        Self::with_config(writer, config, SenderConfig::default(), ping_payload).await
+    }
+
+    /// Create a sender pinging and flushing as sender_config says, with the
+    /// PING payload returned by ping_payload.
+    pub async fn with_config(
+        mut writer: W,
+        config: RemoteConfig,
+        sender_config: SenderConfig,
+        ping_payload: impl Fn() -> String + Send + 'static,
+    ) -> Result<Self> {
         // Get our kind from the config
         let kind = elgato_streamdeck::info::Kind::from_pid(config.pid)
             .ok_or_else(|| anyhow::anyhow!("Unknown pid {}", config.pid))?;
@@ -30,33 +109,89 @@ where
             kind, image_format
         );
 
-        writer
-            .write_all(
-                format!(
-                    "ADD-DEVICE {}\n",
-                    crate::DeviceMsg {
-                        device_id: config.device_id.clone(),
-                        product_name: format!("RustSatellite StreamDeck: {}", kind.to_string()),
-                        keys_total: kind.key_count(),
-                        keys_per_row: kind.column_count(),
-                        resolution: kind.key_image_format().size.0.try_into()?,
-                    }
-                    .device_msg()
-                )
-                .as_bytes(),
-            )
-            .await?;
+        let device = crate::DeviceMsg::new(&config.device_id, &kind)
+            .with_module(MODULE)
+            .with_version(env!("CARGO_PKG_VERSION"));
+        let add = AddDeviceMsg::new(device).with_features(sender_config.features);
+        writer.write_all(add.to_line().as_bytes()).await?;
 
         let writer = Arc::new(Mutex::new(writer));
-        let ping = tokio::spawn(companion_ping(writer.clone()));
+        let pending = Arc::new(Pending::default());
+        let ping = tokio::spawn(
+            companion_ping(writer.clone(), pending.clone(), sender_config, ping_payload)
+                .in_current_span(),
+        );
 
         Ok(Self {
             ping,
             device_id: config.device_id.clone(),
+            kind,
             writer,
+            flush_policy: sender_config.flush_policy,
+            pending,
+            goodbye: None,
         })
     }
 }
+
+impl<W> Sender<W>
+where
+    W: AsyncWrite + Unpin + Send,
+{
+    /// Send lines to Companion as the flush policy says.
+    async fn send(&self, lines: String) -> Result<()> {
+        if lines.is_empty() {
+            return Ok(());
+        }
+        debug!("Sending: {}", lines.trim_end());
+        match self.flush_policy {
+            FlushPolicy::Immediate => {
+                let mut writer = self.writer.lock().await;
+                writer.write_all(lines.as_bytes()).await?;
+                writer.flush().await?;
+            }
+            FlushPolicy::Batched(_) => {
+                // The ping task does the writing, and stops when it fails
+                if self.ping.is_finished() {
+                    anyhow::bail!("Companion connection is closed");
+                }
+                self.pending.lines.lock().unwrap().push_str(&lines);
+                self.pending.added.notify_one();
+            }
+        }
+        Ok(())
+    }
+}
+/// Tell Companion to forget a device, so it can be added again with different
+/// capabilities.
+pub async fn remove_device<W>(writer: &mut W, device_id: &str) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    remove_device_because(writer, device_id, None).await
+}
+
+/// Tell Companion to forget a device, giving the reason in Companion's logs
+/// if there is one.  Companion ignores parameters it doesn't know, so the
+/// reason is safe to send to any version.
+pub async fn remove_device_because<W>(
+    writer: &mut W,
+    device_id: &str,
+    reason: Option<ShutdownReason>,
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let msg = match reason {
+        Some(reason) => RemoveDeviceMsg::new(device_id).with_reason(reason),
+        None => RemoveDeviceMsg::new(device_id),
+    }
+    .to_line();
+    debug!("Sending: {}", msg);
+    writer.write_all(msg.as_bytes()).await?;
+    Ok(())
+}
+
 impl<T> Drop for Sender<T> {
     fn drop(&mut self) {
         // Abort the ping task
@@ -64,15 +199,44 @@ impl<T> Drop for Sender<T> {
     }
 }
 
-async fn companion_ping<W>(companion_write_stream: Arc<Mutex<W>>) -> Result<()>
+/// Ping Companion every ping interval, writing the lines of a batching
+/// sender once their window is over (or along with a PING, if that is
+/// sooner).
+async fn companion_ping<W>(
+    companion_write_stream: Arc<Mutex<W>>,
+    pending: Arc<Pending>,
+    config: SenderConfig,
+    ping_payload: impl Fn() -> String,
+) -> Result<()>
 where
     W: AsyncWrite + Unpin + Send + 'static,
 {
     debug!("Starting ping task");
+    let mut next_ping = tokio::time::Instant::now() + config.ping_interval;
     loop {
-        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        let ping = tokio::select! {
+            _ = tokio::time::sleep_until(next_ping) => true,
+            _ = pending.added.notified() => false,
+        };
+        let mut msg = String::new();
+        if ping {
+            next_ping += config.ping_interval;
+        } else if let FlushPolicy::Batched(window) = config.flush_policy {
+            // Give the rest of the batch a chance to arrive
+            let until_ping = next_ping.saturating_duration_since(tokio::time::Instant::now());
+            tokio::time::sleep(window.min(until_ping)).await;
+        }
+        // Lines are only taken with the writer held, so closing the sender
+        // never loses any
         let mut companion_write_stream = companion_write_stream.lock().await;
-        companion_write_stream.write_all(b"PING\n").await?;
+        msg.push_str(&std::mem::take(&mut *pending.lines.lock().unwrap()));
+        if ping {
+            msg.push_str(&PingMsg::new().with_payload(&ping_payload()).to_line());
+        }
+        if msg.is_empty() {
+            continue;
+        }
+        companion_write_stream.write_all(msg.as_bytes()).await?;
         companion_write_stream.flush().await?;
     }
 }
@@ -86,37 +250,189 @@ where
         Ok(())
     }
     async fn button_change(&mut self, buttons: ButtonChange) -> Result<()> {
-        let mut writer = self.writer.lock().await;
+        let mut lines = String::new();
         for (index, pressed) in buttons.buttons {
-            let pressed = if pressed { 1 } else { 0 };
-
-            let msg = format!(
-                "KEY-PRESS DEVICEID={} KEY={index} PRESSED={pressed}\n",
-                self.device_id
-            );
-            debug!("Sending: {}", msg);
-            writer.write_all(msg.as_bytes()).await?;
+            lines.push_str(&KeyPressMsg::new(&self.device_id, index, pressed).to_line());
         }
-        writer.flush().await?;
-        Ok(())
+        self.send(lines).await
     }
     async fn encoder_twist(&mut self, encoders: EncoderTwist) -> Result<()> {
-        let mut writer = self.writer.lock().await;
+        let mut lines = String::new();
         for (index, value) in encoders.encoders {
-            let count = value.abs();
-            let direction = if value < 0 { 0 } else { 1 };
-            let button_id = index;
-            let msg = format!(
-                "KEY-ROTATE DEVICEID={} KEY={button_id} DIRECTION={direction}\n",
-                self.device_id
-            );
-            debug!("Sending: {}", msg);
-            let msg = msg.as_bytes();
-            for _ in 0..count {
-                writer.write_all(msg).await?;
-            }
+            let count = usize::from(value.unsigned_abs());
+            let button_id = crate::layout::encoder_key(self.kind, index);
+            let msg = KeyRotateMsg::new(&self.device_id, button_id, value >= 0).to_line();
+            lines.push_str(&msg.repeat(count));
         }
-        writer.flush().await?;
+        self.send(lines).await
+    }
+    async fn encoder_press(&mut self, encoders: EncoderPress) -> Result<()> {
+        let mut lines = String::new();
+        for (index, pressed) in encoders.encoders {
+            let button_id = crate::layout::encoder_key(self.kind, index);
+            lines.push_str(&KeyPressMsg::new(&self.device_id, button_id, pressed).to_line());
+        }
+        self.send(lines).await
+    }
+    /// Companion has no notion of a touch screen, the LCD strip is a row of
+    /// keys.  Taps press and release the key under the finger and swipes
+    /// rotate it, right being clockwise.
+    async fn touch(&mut self, touch: Touch) -> Result<()> {
+        let Some(key) = crate::layout::touch_key(self.kind, touch.x) else {
+            debug!("Touch on a device without a touch screen: {:?}", touch);
+            return Ok(());
+        };
+        let lines = match touch.gesture {
+            TouchGesture::Tap | TouchGesture::LongPress => [true, false]
+                .map(|pressed| KeyPressMsg::new(&self.device_id, key, pressed).to_line())
+                .concat(),
+            TouchGesture::Swipe(to_x, _) if to_x != touch.x => {
+                KeyRotateMsg::new(&self.device_id, key, to_x > touch.x).to_line()
+            }
+            TouchGesture::Swipe(..) => String::new(),
+        };
+        self.send(lines).await
+    }
+    /// Companion has no notion of power, the gateway acts on it instead.
+    async fn power(&mut self, power: PowerState) -> Result<()> {
+        debug!("Not telling Companion about power: {:?}", power);
+        Ok(())
+    }
+    /// Companion has nowhere to show it, the gateway serves it instead.
+    async fn status_image(&mut self, image: StatusImage) -> Result<()> {
+        debug!(
+            "Not sending Companion a {}x{} status image",
+            image.width, image.height
+        );
+        Ok(())
+    }
+    /// Held for the REMOVE-DEVICE written on closing.
+    async fn goodbye(&mut self, reason: ShutdownReason) -> Result<()> {
+        self.goodbye = Some(reason);
         Ok(())
     }
+    /// Write the input still held for a batch, remove the device from
+    /// Companion, with the reason if there is one, and shut the connection
+    /// down.
+    async fn close(&mut self) -> Result<()> {
+        let mut writer = self.writer.lock().await;
+        self.ping.abort();
+        let lines = std::mem::take(&mut *self.pending.lines.lock().unwrap());
+        writer.write_all(lines.as_bytes()).await?;
+        remove_device_because(&mut *writer, &self.device_id, self.goodbye).await?;
+        writer.flush().await?;
+        Ok(writer.shutdown().await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncBufReadExt;
+    use traits::companion::Sender as _;
+
+    #[tokio::test]
+    async fn test_batched_input_written_together() {
+        let (writer, reader) = tokio::io::duplex(1024);
+        let config = RemoteConfig {
+            pid: 0x0080,
+            device_id: String::from("deck"),
+            fingerprint: Default::default(),
+        };
+        let sender_config = SenderConfig {
+            ping_interval: Duration::from_secs(3600),
+            flush_policy: FlushPolicy::Batched(Duration::from_millis(20)),
+            ..Default::default()
+        };
+        let mut sender = Sender::with_config(writer, config, sender_config, String::new)
+            .await
+            .unwrap();
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        let add_device = lines.next_line().await.unwrap().unwrap();
+        assert!(add_device.starts_with("ADD-DEVICE"));
+
+        for pressed in [true, false] {
+            sender
+                .button_change(ButtonChange {
+                    buttons: vec![(3, pressed)],
+                })
+                .await
+                .unwrap();
+        }
+        // Nothing is written until the window is over
+        let early = tokio::time::timeout(Duration::from_millis(5), lines.next_line()).await;
+        assert!(early.is_err());
+        assert_eq!(
+            lines.next_line().await.unwrap().unwrap(),
+            "KEY-PRESS DEVICEID=deck KEY=3 PRESSED=1"
+        );
+        assert_eq!(
+            lines.next_line().await.unwrap().unwrap(),
+            "KEY-PRESS DEVICEID=deck KEY=3 PRESSED=0"
+        );
+
+        // Closing writes what is held at once and removes the device
+        sender
+            .button_change(ButtonChange {
+                buttons: vec![(4, true)],
+            })
+            .await
+            .unwrap();
+        sender.goodbye(ShutdownReason::Kicked).await.unwrap();
+        sender.close().await.unwrap();
+        assert_eq!(
+            lines.next_line().await.unwrap().unwrap(),
+            "KEY-PRESS DEVICEID=deck KEY=4 PRESSED=1"
+        );
+        assert_eq!(
+            lines.next_line().await.unwrap().unwrap(),
+            "REMOVE-DEVICE DEVICEID=deck REASON=kicked"
+        );
+        assert_eq!(lines.next_line().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_touches_sent_as_strip_keys() {
+        let (writer, reader) = tokio::io::duplex(1024);
+        let config = RemoteConfig {
+            pid: elgato_streamdeck::info::PID_STREAMDECK_PLUS,
+            device_id: String::from("plus"),
+            fingerprint: Default::default(),
+        };
+        let sender_config = SenderConfig {
+            ping_interval: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let mut sender = Sender::with_config(writer, config, sender_config, String::new)
+            .await
+            .unwrap();
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        lines.next_line().await.unwrap().unwrap();
+
+        let touch = |x, gesture| Touch { x, y: 50, gesture };
+        // A tap presses and releases the strip key under it
+        sender.touch(touch(10, TouchGesture::Tap)).await.unwrap();
+        // Swiping on the spot does nothing, else rotates the key swiped on
+        let swipes = [(650, 650), (650, 790), (300, 100)];
+        for (from, to) in swipes {
+            let swipe = touch(from, TouchGesture::Swipe(to, 50));
+            sender.touch(swipe).await.unwrap();
+        }
+        sender.close().await.unwrap();
+
+        let mut sent = Vec::new();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            sent.push(line);
+        }
+        assert_eq!(
+            sent,
+            [
+                "KEY-PRESS DEVICEID=plus KEY=8 PRESSED=1",
+                "KEY-PRESS DEVICEID=plus KEY=8 PRESSED=0",
+                "KEY-ROTATE DEVICEID=plus KEY=11 DIRECTION=1",
+                "KEY-ROTATE DEVICEID=plus KEY=9 DIRECTION=0",
+                "REMOVE-DEVICE DEVICEID=plus",
+            ]
+        );
+    }
 }
@@ -0,0 +1,191 @@
+//! The satellite API versions this crate can talk to.
+//!
+//! Companion names the version of the satellite API it speaks in the
+//! ApiVersion of its BEGIN.  Anything from [MIN_API_VERSION] up to
+//! [UNSUPPORTED_API_VERSION] is understood, using the [Features] that version
+//! has.  Anything else fails with an
+//! [UnsupportedVersion] saying which side needs upgrading, rather than with
+//! lines that make no sense to one of them later on.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::ValueEncoding;
+
+/// A semver version of the satellite API.  Pre-release and build suffixes
+/// are ignored, and a missing patch is 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProtocolVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+/// The oldest satellite API understood.
+pub const MIN_API_VERSION: ProtocolVersion = ProtocolVersion::new(1, 0, 0);
+/// The first satellite API too new to be understood.
+pub const UNSUPPORTED_API_VERSION: ProtocolVersion = ProtocolVersion::new(3, 0, 0);
+/// The first satellite API whose values may be percent-encoded.
+pub const PERCENT_ENCODING_API_VERSION: ProtocolVersion = ProtocolVersion::new(1, 8, 0);
+/// The first satellite API that can send the color and text of keys.
+pub const COLOR_TEXT_API_VERSION: ProtocolVersion = ProtocolVersion::new(2, 0, 0);
+/// The first satellite API taking the brightness support, LCD strip, module
+/// and version of a device in its ADD-DEVICE.
+pub const DEVICE_DETAILS_API_VERSION: ProtocolVersion = ProtocolVersion::new(2, 1, 0);
+
+impl ProtocolVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Whether the version is one this crate can talk to.
+    pub fn check(self) -> Result<Self, UnsupportedVersion> {
+        if self < MIN_API_VERSION {
+            Err(UnsupportedVersion::TooOld(self))
+        } else if self >= UNSUPPORTED_API_VERSION {
+            Err(UnsupportedVersion::TooNew(self))
+        } else {
+            Ok(self)
+        }
+    }
+
+    /// What Companion speaking this version understands.
+    pub fn features(self) -> Features {
+        Features {
+            value_encoding: if self >= PERCENT_ENCODING_API_VERSION {
+                ValueEncoding::Percent
+            } else {
+                ValueEncoding::Quoted
+            },
+            // Every version takes images of the size in BITMAPS
+            bitmaps: true,
+            colors: self >= COLOR_TEXT_API_VERSION,
+            text: self >= COLOR_TEXT_API_VERSION,
+            device_details: self >= DEVICE_DETAILS_API_VERSION,
+        }
+    }
+}
+
+impl FromStr for ProtocolVersion {
+    type Err = UnsupportedVersion;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let unparseable = || UnsupportedVersion::Unparseable(s.to_string());
+        let core = s.split(['-', '+']).next().unwrap_or_default();
+        let mut parts = core.split('.').map(str::parse::<u32>);
+        let version = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(major)), Some(Ok(minor)), None, None) => Self::new(major, minor, 0),
+            (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch)), None) => {
+                Self::new(major, minor, patch)
+            }
+            _ => return Err(unparseable()),
+        };
+        Ok(version)
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// What a version of Companion understands, and so what it is sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Features {
+    /// How values Companion sends are encoded
+    pub value_encoding: ValueEncoding,
+    /// Whether devices may ask for key images of their own size
+    pub bitmaps: bool,
+    /// Whether devices may ask for the background color of keys
+    pub colors: bool,
+    /// Whether devices may ask for the text of keys
+    pub text: bool,
+    /// Whether devices may say if they have brightness and an LCD strip, and
+    /// what they are connected through
+    pub device_details: bool,
+}
+
+impl Default for Features {
+    /// The features every supported version has.
+    fn default() -> Self {
+        MIN_API_VERSION.features()
+    }
+}
+
+/// A version of Companion that can't be talked to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnsupportedVersion {
+    /// The ApiVersion wasn't a version at all
+    Unparseable(String),
+    /// Companion is older than [MIN_API_VERSION]
+    TooOld(ProtocolVersion),
+    /// Companion is at or past [UNSUPPORTED_API_VERSION]
+    TooNew(ProtocolVersion),
+}
+
+impl fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnsupportedVersion::Unparseable(version) => {
+                write!(
+                    f,
+                    "Companion sent an ApiVersion of {version:?}, which isn't a version"
+                )
+            }
+            UnsupportedVersion::TooOld(version) => write!(
+                f,
+                "Companion speaks satellite API {version}, but at least {MIN_API_VERSION} is \
+                 needed.  Upgrade Companion"
+            ),
+            UnsupportedVersion::TooNew(version) => write!(
+                f,
+                "Companion speaks satellite API {version}, but only versions below \
+                 {UNSUPPORTED_API_VERSION} are supported.  Upgrade this satellite"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for UnsupportedVersion {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_versions_checked_against_range() {
+        let version = |s: &str| s.parse::<ProtocolVersion>();
+        assert_eq!(version("1.5.1"), Ok(ProtocolVersion::new(1, 5, 1)));
+        assert_eq!(version("1.8"), Ok(ProtocolVersion::new(1, 8, 0)));
+        assert_eq!(version("1.9.0-beta+42"), Ok(ProtocolVersion::new(1, 9, 0)));
+        assert!(version("1.10.0").unwrap() > version("1.9.9").unwrap());
+        for garbage in ["", "1", "1.x.0", "1.2.3.4", "v1.2.3"] {
+            assert!(
+                matches!(version(garbage), Err(UnsupportedVersion::Unparseable(_))),
+                "{garbage}"
+            );
+        }
+
+        assert!(version("1.0.0").unwrap().check().is_ok());
+        assert!(version("2.1.0").unwrap().check().is_ok());
+        let too_old = version("0.9.0").unwrap().check().unwrap_err();
+        assert!(too_old.to_string().contains("Upgrade Companion"));
+        let too_new = version("3.0.0-beta").unwrap().check().unwrap_err();
+        assert_eq!(
+            too_new,
+            UnsupportedVersion::TooNew(ProtocolVersion::new(3, 0, 0))
+        );
+        assert!(too_new.to_string().contains("Upgrade this satellite"));
+
+        let features = version("1.8.0").unwrap().features();
+        assert_eq!(features.value_encoding, ValueEncoding::Percent);
+        assert!(!features.colors && !features.text);
+        assert!(version("2.0.0").unwrap().features().text);
+        assert_eq!(Features::default().value_encoding, ValueEncoding::Quoted);
+    }
+}
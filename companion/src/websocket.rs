@@ -0,0 +1,99 @@
+//! The satellite API tunnelled over WebSocket.
+//!
+//! Some deployments can only reach Companion through an HTTP reverse proxy.
+//! There the lines of the satellite API travel as WebSocket text messages,
+//! a line per message on the way out, and [connect] turns the WebSocket back into a reader
+//! and writer of lines, so the [Sender](crate::sender::Sender) and
+//! [Receiver](crate::receiver::Receiver) work over it unchanged.
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream, ReadHalf, WriteHalf};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, info};
+use traits::Result;
+
+/// Bytes buffered between the WebSocket and the reader and writer.
+const BUFFER_SIZE: usize = 256 * 1024;
+
+/// Connect to Companion at url (`ws://` or `wss://`), returning a reader and
+/// writer of lines as a TCP connection to it would.
+pub async fn connect(url: &str) -> Result<(ReadHalf<DuplexStream>, WriteHalf<DuplexStream>)> {
+    let (socket, response) = tokio_tungstenite::connect_async(url).await?;
+    debug!("WebSocket upgraded: {:?}", response.status());
+    let (local, remote) = tokio::io::duplex(BUFFER_SIZE);
+    tokio::spawn(async move {
+        let res = bridge(socket, remote).await;
+        info!("WebSocket connection closed: {:?}", res);
+    });
+    Ok(tokio::io::split(local))
+}
+
+/// Pass messages from the WebSocket to the local end as lines, and lines
+/// from it back as messages, until either side closes.
+async fn bridge<S>(socket: S, local: DuplexStream) -> Result<()>
+where
+    S: futures_util::Stream<Item = tokio_tungstenite::tungstenite::Result<Message>>
+        + futures_util::Sink<Message, Error = tokio_tungstenite::tungstenite::Error>
+        + Unpin,
+{
+    let (mut to_socket, mut from_socket) = socket.split();
+    let (local_reader, mut local_writer) = tokio::io::split(local);
+    let from_companion = async {
+        while let Some(message) = from_socket.next().await {
+            let text = match message? {
+                Message::Text(text) => text,
+                Message::Binary(data) => String::from_utf8(data)?,
+                Message::Close(_) => break,
+                // Pings are answered by the socket
+                _ => continue,
+            };
+            // A message may hold one line or several
+            local_writer.write_all(text.as_bytes()).await?;
+            if !text.ends_with('\n') {
+                local_writer.write_all(b"\n").await?;
+            }
+        }
+        Ok(())
+    };
+    let to_companion = async {
+        let mut lines = BufReader::new(local_reader).lines();
+        while let Some(line) = lines.next_line().await? {
+            to_socket.send(Message::Text(line)).await?;
+        }
+        to_socket.close().await?;
+        Ok(())
+    };
+    tokio::select! {
+        res = from_companion => res,
+        res = to_companion => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_lines_tunnelled_as_messages() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let companion = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let text = "BEGIN CompanionVersion=3.1.2 ApiVersion=1.5.1\nPONG\n";
+            socket.send(Message::Text(text.into())).await.unwrap();
+            socket.next().await.unwrap().unwrap()
+        });
+
+        let (reader, mut writer) = connect(&url).await.unwrap();
+        let mut lines = BufReader::new(reader).lines();
+        let begin = lines.next_line().await.unwrap().unwrap();
+        assert!(begin.starts_with("BEGIN"));
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "PONG");
+        writer.write_all(b"PING 1\n").await.unwrap();
+        assert_eq!(
+            companion.await.unwrap(),
+            Message::Text(String::from("PING 1"))
+        );
+    }
+}
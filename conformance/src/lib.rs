@@ -0,0 +1,143 @@
+//! Wire compatibility checks of every protocol the satellite speaks.
+//!
+//! The tests of this crate hold the ASCII Companion protocol, the bin_comm
+//! framing and the leaf_comm schema to the golden vectors in `vectors/`.  The
+//! other end of each protocol (Companion itself, or a leaf flashed with an
+//! older build) doesn't change along with this repository, so when a test
+//! here fails the change is what needs another look, not the vectors.
+//!
+//! The vectors also seed the fuzz targets of `tests/fuzz.rs`, which feed the
+//! parsers every truncation of them and randomly corrupted copies, and only
+//! ask that nothing panics.  The property tests of `tests/sessions.rs` go
+//! the other way, generating random valid sessions and asking that each
+//! comes out of the serializers, the parsers and the pump as it went in.
+//!
+//! Vector files are plain text.  Blank lines and lines starting with `#` are
+//! ignored.  A transcript of Companion traffic has one line per message,
+//! starting with `<` if Companion sent it and `>` if it was sent to
+//! Companion.  A file of binary frames has one `name = hex` line per frame,
+//! where the hex may be split up with spaces for readability.
+
+#![warn(missing_docs)]
+
+use std::path::PathBuf;
+
+/// Which way a line of Companion traffic went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Sent by Companion
+    FromCompanion,
+    /// Sent to Companion
+    ToCompanion,
+}
+
+/// The lines of a vector file that aren't blank or comments.
+fn vector_lines(name: &str) -> Vec<String> {
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "vectors", name]
+        .iter()
+        .collect();
+    let contents = std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Could not read {}: {e}", path.display()));
+    contents
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+/// Read a transcript of Companion traffic, in the order it was recorded.
+pub fn transcript(name: &str) -> Vec<(Direction, String)> {
+    vector_lines(name)
+        .into_iter()
+        .map(|line| match line.split_at(1) {
+            ("<", message) => (Direction::FromCompanion, message.trim_start().into()),
+            (">", message) => (Direction::ToCompanion, message.trim_start().into()),
+            _ => panic!("Transcript line without a direction: {line:?}"),
+        })
+        .collect()
+}
+
+/// Read the lines of a transcript that went one way.
+pub fn transcript_lines(name: &str, direction: Direction) -> Vec<String> {
+    transcript(name)
+        .into_iter()
+        .filter(|(line_direction, _)| *line_direction == direction)
+        .map(|(_, line)| line)
+        .collect()
+}
+
+/// Read named binary frames, in the order they appear.
+pub fn frames(name: &str) -> Vec<(String, Vec<u8>)> {
+    vector_lines(name)
+        .into_iter()
+        .map(|line| {
+            let (frame, hex) = line
+                .split_once('=')
+                .unwrap_or_else(|| panic!("Frame line without a name: {line:?}"));
+            (frame.trim().into(), from_hex(hex))
+        })
+        .collect()
+}
+
+/// Read the frame called name.
+pub fn frame(file: &str, name: &str) -> Vec<u8> {
+    frames(file)
+        .into_iter()
+        .find(|(frame, _)| frame == name)
+        .unwrap_or_else(|| panic!("No frame {name:?} in {file}"))
+        .1
+}
+
+/// Parse hex digits, ignoring whitespace between bytes.
+fn from_hex(hex: &str) -> Vec<u8> {
+    let digits: String = hex.split_whitespace().collect();
+    digits
+        .as_bytes()
+        .chunks(2)
+        .map(|byte| {
+            std::str::from_utf8(byte)
+                .ok()
+                .filter(|byte| byte.len() == 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .unwrap_or_else(|| panic!("Bad hex in {hex:?}"))
+        })
+        .collect()
+}
+
+/// Inputs for fuzzing a parser, derived from seed: every prefix of it, then
+/// copies with a few bytes overwritten, then bytes that are all noise.  The
+/// same seed always gives the same inputs, so a failure can be replayed.
+pub fn fuzz_inputs(seed: &[u8], corrupted: usize) -> Vec<Vec<u8>> {
+    let mut inputs: Vec<Vec<u8>> = (0..=seed.len()).map(|len| seed[..len].to_vec()).collect();
+    let mut random = XorShift(seed.iter().fold(0x9e37_79b9_7f4a_7c15, |state, byte| {
+        (state ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    }));
+    for _ in 0..corrupted {
+        let mut input = seed.to_vec();
+        if !input.is_empty() {
+            for _ in 0..=random.next() % 4 {
+                let index = random.next() as usize % input.len();
+                input[index] = random.next() as u8;
+            }
+        }
+        inputs.push(input);
+    }
+    for _ in 0..corrupted {
+        let len = random.next() as usize % (seed.len() + 1);
+        inputs.push((0..len).map(|_| random.next() as u8).collect());
+    }
+    inputs
+}
+
+/// A small deterministic pseudo-random sequence.
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
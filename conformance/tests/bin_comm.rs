@@ -0,0 +1,251 @@
+//! The bin_comm framing of the link between gateway and leaf.
+
+use bin_comm::replay::{Opener, Sealer};
+use bin_comm::stream_utils::{
+    frame_error, read_sealed, read_struct, receive_length_prefix, write_length_prefix,
+    write_length_prefix_as, write_sealed, write_struct, FrameReader,
+};
+use conformance::frame;
+use leaf_comm::framing::{self, Decoder, Format, FrameError};
+use leaf_comm::wire::{self, PROTOCOL_VERSION, UNVERSIONED};
+use leaf_comm::{
+    BorrowedDeviceActions, ButtonChange, Command, DeviceActions, SetBrightness, SetButtonImage,
+};
+
+const VECTORS: &str = "bin_comm.txt";
+
+/// The session nonce the sealed vectors were recorded with.
+const SESSION_NONCE: u64 = 0x0123_4567_89ab_cdef;
+/// The session key the sealed vectors were recorded with.
+const SESSION_KEY: [u8; 32] = [0x42; 32];
+
+#[tokio::test]
+async fn test_length_prefix_matches_vectors() {
+    for (name, message) in [("checked_empty", &b""[..]), ("checked_ping", &b"PING"[..])] {
+        let expected = frame(VECTORS, name);
+        let mut written = Vec::new();
+        write_length_prefix(&mut written, message).await.unwrap();
+        assert_eq!(written, expected, "{name}");
+        let read = receive_length_prefix(&mut &expected[..], Vec::new())
+            .await
+            .unwrap();
+        assert_eq!(read, message, "{name}");
+    }
+
+    // Either format is written as asked, and told apart when read
+    for (format, prefix) in [(Format::Legacy, ""), (Format::Checked, "checked_")] {
+        for (name, message) in [("empty", &b""[..]), ("ping", &b"PING"[..])] {
+            let name = format!("{prefix}{name}");
+            let expected = frame(VECTORS, &name);
+            let mut written = Vec::new();
+            write_length_prefix_as(&mut written, message, format)
+                .await
+                .unwrap();
+            assert_eq!(written, expected, "{name}");
+            let mut reader = FrameReader::detecting(&expected[..]);
+            assert_eq!(reader.receive(Vec::new()).await.unwrap(), message, "{name}");
+            assert_eq!(reader.format(), Some(format), "{name}");
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_legacy_leaf_decodes_on_gateway() {
+    // As sent by a leaf from before versions and checksums
+    let stream = [frame(VECTORS, "config"), frame(VECTORS, "button_change")].concat();
+    let mut reader = FrameReader::detecting(&stream[..]);
+    let frame = reader.receive(Vec::new()).await.unwrap();
+    match wire::decode_command(&frame).unwrap() {
+        (UNVERSIONED, Command::Config(config)) => {
+            assert_eq!((config.pid, config.device_id.as_str()), (0x0084, "deck"))
+        }
+        command => panic!("Unexpected command {command:?}"),
+    }
+    let frame = reader.receive(Vec::new()).await.unwrap();
+    assert!(matches!(
+        wire::decode_command(&frame).unwrap(),
+        (UNVERSIONED, Command::ButtonChange(_))
+    ));
+    assert_eq!(reader.format(), Some(Format::Legacy));
+}
+
+#[tokio::test]
+async fn test_struct_matches_vectors() {
+    let expected = frame(VECTORS, "checked_button_change");
+    let command = Command::ButtonChange(ButtonChange {
+        buttons: vec![(0, true)],
+    });
+    let mut written = Vec::new();
+    write_struct(&mut written, &command).await.unwrap();
+    assert_eq!(written, expected);
+    let read: Command = read_struct(&mut &expected[..]).await.unwrap();
+    assert_eq!(format!("{read:?}"), format!("{command:?}"));
+}
+
+#[tokio::test]
+async fn test_sealed_matches_vectors() {
+    let expected = [frame(VECTORS, "sealed_0"), frame(VECTORS, "sealed_1")].concat();
+    let mut sealer = Sealer::new(SESSION_NONCE, SESSION_KEY);
+    let mut written = Vec::new();
+    for brightness in [60, 80] {
+        let action = DeviceActions::SetBrightness(SetBrightness { brightness });
+        let frame = wire::encode(PROTOCOL_VERSION, &action).unwrap();
+        write_sealed(&mut written, &mut sealer, frame)
+            .await
+            .unwrap();
+    }
+    assert_eq!(written, expected);
+
+    let mut opener = Opener::new(SESSION_NONCE, SESSION_KEY);
+    let mut stream = FrameReader::new(&expected[..]);
+    for brightness in [60, 80] {
+        let frame = read_sealed(&mut stream, &mut opener, Vec::new())
+            .await
+            .unwrap();
+        match wire::decode_actions(&frame).unwrap() {
+            BorrowedDeviceActions::SetBrightness(read) => assert_eq!(read.brightness, brightness),
+            action => panic!("Unexpected action {action:?}"),
+        }
+    }
+
+    // Replayed, or with the counter or frame changed, the frames are refused
+    let mut opener = Opener::new(SESSION_NONCE, SESSION_KEY);
+    let first = frame(VECTORS, "sealed_0");
+    let mut forged = Vec::new();
+    for at in [15, 17] {
+        let mut frame = frame(VECTORS, "sealed_1");
+        frame[at] ^= 1;
+        let trailer = frame.len() - 4;
+        let crc = framing::checksum([&frame[6..trailer]]);
+        frame[trailer..].copy_from_slice(&crc);
+        forged.push(frame);
+    }
+    let stream = [&first[..], &first, &forged[0], &forged[1]].concat();
+    let mut stream = FrameReader::new(&stream[..]);
+    read_sealed(&mut stream, &mut opener, Vec::new())
+        .await
+        .unwrap();
+    for _ in 0..3 {
+        assert!(read_sealed(&mut stream, &mut opener, Vec::new())
+            .await
+            .is_err());
+    }
+}
+
+#[tokio::test]
+async fn test_gateway_frames_decode_on_leaf() {
+    let actions = [
+        DeviceActions::SetButtonImage(SetButtonImage {
+            button: 7,
+            image: (0..=255).collect(),
+        }),
+        DeviceActions::SetBrightness(SetBrightness { brightness: 40 }),
+    ];
+    let mut stream = Vec::new();
+    for action in &actions {
+        let frame = wire::encode(PROTOCOL_VERSION, action).unwrap();
+        write_length_prefix(&mut stream, frame).await.unwrap();
+    }
+
+    // The leaf reads a byte at a time, as from its serial port
+    let mut decoder = Decoder::default();
+    let mut read = Vec::new();
+    for byte in stream {
+        if let Some(frame) = decoder.push(byte).unwrap() {
+            read.push(match wire::decode_actions(frame).unwrap() {
+                BorrowedDeviceActions::SetButtonImage(image) => (image.button, image.image.len()),
+                BorrowedDeviceActions::SetBrightness(b) => (0, usize::from(b.brightness)),
+                action => panic!("Unexpected action {action:?}"),
+            });
+        }
+    }
+    assert_eq!(read, [(7, 256), (0, 40)]);
+}
+
+#[tokio::test]
+async fn test_leaf_frames_decode_on_gateway() {
+    let command = Command::ButtonChange(ButtonChange {
+        buttons: vec![(2, true), (3, false)],
+    });
+    let mut stream = Vec::new();
+    let frame = wire::encode(PROTOCOL_VERSION, &command).unwrap();
+    framing::encode(&frame, &mut stream).unwrap();
+
+    let frame = receive_length_prefix(&mut &stream[..], Vec::new())
+        .await
+        .unwrap();
+    let (version, read) = wire::decode_command(&frame).unwrap();
+    assert_eq!(version, PROTOCOL_VERSION);
+    assert_eq!(format!("{read:?}"), format!("{command:?}"));
+
+    // Structs are postcard on both sides too
+    let mut stream = Vec::new();
+    framing::encode(&postcard::to_allocvec(&command).unwrap(), &mut stream).unwrap();
+    let read: Command = read_struct(&mut &stream[..]).await.unwrap();
+    assert_eq!(format!("{read:?}"), format!("{command:?}"));
+}
+
+#[tokio::test]
+async fn test_corrupt_frames_refused_on_both_sides() {
+    let mut ping = frame(VECTORS, "checked_ping");
+    ping[7] ^= 0x20;
+    let stream = [
+        &[0x00, 0x5a][..],
+        &ping,
+        &frame(VECTORS, "checked_button_change"),
+    ]
+    .concat();
+
+    let mut reader = &stream[..];
+    let e = receive_length_prefix(&mut reader, Vec::new())
+        .await
+        .unwrap_err();
+    assert!(matches!(frame_error(&e), Some(FrameError::Corrupt(_))));
+    let read: Command = read_struct(&mut reader).await.unwrap();
+    assert!(matches!(read, Command::ButtonChange(_)));
+
+    let mut decoder = Decoder::default();
+    let mut data = &stream[..];
+    assert!(matches!(
+        decoder.decode(&mut data),
+        Err(FrameError::Corrupt(_))
+    ));
+    let frame = decoder.decode(&mut data).unwrap().unwrap();
+    assert!(matches!(
+        postcard::from_bytes(frame).unwrap(),
+        Command::ButtonChange(_)
+    ));
+}
+
+#[tokio::test]
+async fn test_damaged_lengths_resynchronized() {
+    let button_change = frame(VECTORS, "checked_button_change");
+    for (at, damage) in [
+        // Past the longest frame
+        (2, 0x80),
+        // Short, so the frame ends part way through its message
+        (5, 0x04),
+        // Long, taking in the frames after it
+        (5, 0x10),
+    ] {
+        let mut ping = frame(VECTORS, "checked_ping");
+        ping[at] ^= damage;
+        let stream = [&ping[..], &button_change, &button_change].concat();
+
+        let mut reader = FrameReader::new(&stream[..]);
+        let mut read = Vec::new();
+        let mut refused = 0;
+        loop {
+            match reader.receive(Vec::new()).await {
+                Ok(frame) => read.push(frame),
+                Err(e) if frame_error(&e).is_some() => refused += 1,
+                Err(e) => {
+                    assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+                    break;
+                }
+            }
+        }
+        assert!(refused >= 1, "{at} {damage:#x}");
+        assert_eq!(read, [&button_change[6..10]; 2], "{at} {damage:#x}");
+    }
+}
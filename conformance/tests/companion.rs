@@ -0,0 +1,136 @@
+//! The ASCII protocol spoken with Companion, against a recorded session.
+
+use std::time::Duration;
+
+use companion::lint::Linter;
+use companion::sender::{Sender, SenderConfig};
+use companion::{AddDevice, Brightness, Command, Versions};
+use conformance::{transcript_lines, Direction};
+use leaf_comm::{ButtonChange, EncoderPress, EncoderTwist, RemoteConfig, Touch, TouchGesture};
+use tokio::io::AsyncBufReadExt;
+use traits::companion::Sender as _;
+
+const TRANSCRIPT: &str = "companion_plus.txt";
+
+#[test]
+fn test_parses_companion_traffic() {
+    let lines = transcript_lines(TRANSCRIPT, Direction::FromCompanion);
+    let commands: Vec<_> = lines
+        .iter()
+        .map(|line| Command::parse(line).unwrap_or_else(|e| panic!("{line:?}: {e}")))
+        .collect();
+    for (line, command) in lines.iter().zip(&commands) {
+        assert!(
+            !matches!(command, Command::Unknown(_)),
+            "Unknown command {line:?}"
+        );
+    }
+
+    let mut linter = Linter::new();
+    for line in &lines {
+        linter
+            .check(line)
+            .unwrap_or_else(|e| panic!("{line:?}: {e}"));
+    }
+
+    assert_eq!(
+        commands[0],
+        Command::Begin(Versions {
+            companion_version: "3.1.2+6301-stable-1e8a4d2b".into(),
+            api_version: "1.5.1".into(),
+        })
+    );
+    assert_eq!(
+        commands[1],
+        Command::AddDevice(AddDevice {
+            success: true,
+            device_id: "deck".into(),
+        })
+    );
+    assert_eq!(
+        commands[2],
+        Command::Brightness(Brightness {
+            device: "deck".into(),
+            brightness: 60,
+        })
+    );
+    match &commands[3] {
+        Command::KeyState(keystate) => {
+            assert_eq!(keystate.key, 0);
+            assert!(!keystate.pressed);
+            assert_eq!(keystate.bitmap().unwrap(), [0, 0, 0, 255, 0, 0]);
+        }
+        command => panic!("Unexpected command {command:?}"),
+    }
+    assert_eq!(commands.last(), Some(&Command::Pong));
+}
+
+#[tokio::test]
+async fn test_sends_companion_traffic() {
+    let expected = transcript_lines(TRANSCRIPT, Direction::ToCompanion);
+    let (writer, reader) = tokio::io::duplex(4096);
+    let config = RemoteConfig {
+        pid: 0x0084,
+        device_id: String::from("deck"),
+        fingerprint: Default::default(),
+    };
+    // PINGs aren't part of the transcript
+    let sender_config = SenderConfig {
+        ping_interval: Duration::from_secs(3600),
+        ..Default::default()
+    };
+    let mut sender = Sender::with_config(writer, config, sender_config, String::new)
+        .await
+        .unwrap();
+
+    // What was done on the deck while the transcript was recorded
+    for pressed in [true, false] {
+        sender
+            .button_change(ButtonChange {
+                buttons: vec![(0, pressed)],
+            })
+            .await
+            .unwrap();
+    }
+    sender
+        .encoder_twist(EncoderTwist {
+            encoders: vec![(0, 2)],
+        })
+        .await
+        .unwrap();
+    sender
+        .encoder_twist(EncoderTwist {
+            encoders: vec![(1, -1)],
+        })
+        .await
+        .unwrap();
+    for pressed in [true, false] {
+        sender
+            .encoder_press(EncoderPress {
+                encoders: vec![(0, pressed)],
+            })
+            .await
+            .unwrap();
+    }
+    sender
+        .touch(Touch {
+            x: 100,
+            y: 50,
+            gesture: TouchGesture::Tap,
+        })
+        .await
+        .unwrap();
+    sender
+        .touch(Touch {
+            x: 100,
+            y: 50,
+            gesture: TouchGesture::Swipe(700, 50),
+        })
+        .await
+        .unwrap();
+
+    let mut lines = tokio::io::BufReader::new(reader).lines();
+    for expected in expected {
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), expected);
+    }
+}
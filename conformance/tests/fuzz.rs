@@ -0,0 +1,120 @@
+//! Fuzz targets for every parser of untrusted input, run over inputs derived
+//! from the vectors.  A target only fails by panicking: errors are the right
+//! answer to most of what it is fed.
+
+use companion::{encode_value, Command, ValueEncoding};
+use conformance::{frames, fuzz_inputs, transcript, Direction};
+use elgato_streamdeck_local::info::Kind;
+use elgato_streamdeck_local::util::{read_button_states, read_encoder_input, read_lcd_input};
+use leaf_comm::framing::{Decoder, MAX_FRAME_LEN};
+use leaf_comm::wire;
+
+/// Corrupted copies of each seed, on top of its truncations.
+const CORRUPTED: usize = 200;
+
+/// A line from Companion, as `companion/fuzz` takes it.
+fn companion_line(data: &[u8]) {
+    let Ok(line) = std::str::from_utf8(data) else {
+        return;
+    };
+    for encoding in [ValueEncoding::Quoted, ValueEncoding::Percent] {
+        match Command::parse_with(line, encoding) {
+            Ok(Command::KeyState(keystate)) => {
+                let _ = keystate.bitmap();
+                let _ = keystate.rgb();
+            }
+            Ok(Command::Begin(versions)) => {
+                let _ = versions.negotiate();
+            }
+            _ => {}
+        }
+        let _ = Command::parse_strict(line, encoding);
+
+        // Any value reads back as it was written
+        let encoded = encode_value(line, encoding);
+        let brightness = format!("BRIGHTNESS DEVICEID={encoded} VALUE=1");
+        match Command::parse_with(&brightness, encoding) {
+            Ok(Command::Brightness(brightness)) => assert_eq!(brightness.device.as_str(), line),
+            parsed => panic!("{brightness:?} parsed as {parsed:?}"),
+        }
+    }
+}
+
+/// A frame from a leaf or the gateway.
+fn leaf_frame(data: &[u8]) {
+    let _ = wire::decode_command(data);
+    let _ = wire::decode_actions(data);
+    let _ = leaf_comm::hello::decode(data);
+    if let Some(Ok(inner)) = leaf_comm::compress::decompress(data, 1 << 20) {
+        let _ = wire::decode_actions(&inner);
+    }
+}
+
+/// Bytes from the link between gateway and leaf, read by a leaf, and by a
+/// gateway telling their format by the first byte.
+fn link_bytes(data: &[u8]) {
+    for mut decoder in [Decoder::default(), Decoder::detecting(MAX_FRAME_LEN)] {
+        let mut data = data;
+        while !data.is_empty() {
+            if let Ok(Some(frame)) = decoder.decode(&mut data) {
+                leaf_frame(frame);
+            }
+        }
+    }
+}
+
+/// An input report of a Stream Deck.
+fn hid_report(data: &[u8]) {
+    for kind in [Kind::Original, Kind::Mini, Kind::Mk2, Kind::Xl, Kind::Plus] {
+        read_button_states(&kind, data);
+        let _ = read_lcd_input(data);
+        let _ = read_encoder_input(&kind, data);
+    }
+}
+
+#[test]
+fn test_companion_lines_never_panic() {
+    for (direction, line) in transcript("companion_plus.txt") {
+        if direction == Direction::FromCompanion {
+            for input in fuzz_inputs(line.as_bytes(), CORRUPTED) {
+                companion_line(&input);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_leaf_frames_never_panic() {
+    for (_, seed) in frames("leaf_comm.txt") {
+        for input in fuzz_inputs(&seed, CORRUPTED) {
+            leaf_frame(&input);
+        }
+    }
+}
+
+#[test]
+fn test_link_bytes_never_panic() {
+    let stream: Vec<u8> = frames("bin_comm.txt")
+        .into_iter()
+        .flat_map(|(_, frame)| frame)
+        .collect();
+    for input in fuzz_inputs(&stream, CORRUPTED) {
+        link_bytes(&input);
+    }
+}
+
+#[test]
+fn test_hid_reports_never_panic() {
+    // A swipe on the touch strip of a Plus, and the press of its first key
+    let swipe = [
+        0x01, 0x02, 0x0e, 0x00, 0x03, 0x00, 0x64, 0x00, 0x32, 0x00, 0xbc, 0x02, 0x32, 0x00,
+    ];
+    let press = [0x01, 0x00, 0x08, 0x00, 0x01, 0x00, 0x00, 0x00];
+    for seed in [&swipe[..], &press] {
+        for input in fuzz_inputs(seed, CORRUPTED) {
+            hid_report(&input);
+        }
+    }
+    // A truncated swipe is an error rather than a panic
+    assert!(read_lcd_input(&swipe[..8]).is_err());
+}
@@ -0,0 +1,229 @@
+//! The leaf_comm schema, as postcard encodes it between gateway and leaf.
+
+use conformance::frame;
+use leaf_comm::auth::{self, Auth, Challenge, NONCE_LEN};
+use leaf_comm::hello::{self, Capabilities, Hello};
+use leaf_comm::wire::{self, PROTOCOL_VERSION, UNVERSIONED};
+use leaf_comm::{
+    BorrowedDeviceActions, ButtonChange, Command, DeviceActions, EncoderPress, EncoderTwist,
+    Fingerprint, PowerSource, PowerState, RemoteConfig, SetBrightness, SetButtonImage, SetLCDImage,
+    StatusImage, Touch, TouchGesture,
+};
+
+const VECTORS: &str = "leaf_comm.txt";
+
+/// A JPEG with nothing in it, standing in for a real key image.
+const IMAGE: [u8; 4] = [0xff, 0xd8, 0xff, 0xd9];
+
+/// Every command a leaf sends, named as in the vectors.
+fn commands() -> Vec<(&'static str, Command)> {
+    vec![
+        (
+            "config",
+            Command::Config(RemoteConfig {
+                pid: 0x0084,
+                device_id: String::from("deck"),
+                fingerprint: Fingerprint {
+                    key_count: 8,
+                    image_format: 0x1234_5678,
+                },
+            }),
+        ),
+        (
+            "button_change",
+            Command::ButtonChange(ButtonChange {
+                buttons: vec![(0, true), (7, false)],
+            }),
+        ),
+        (
+            "encoder_twist",
+            Command::EncoderTwist(EncoderTwist {
+                encoders: vec![(1, -3), (2, 1)],
+            }),
+        ),
+        (
+            "encoder_press",
+            Command::EncoderPress(EncoderPress {
+                encoders: vec![(3, true)],
+            }),
+        ),
+        (
+            "touch_tap",
+            Command::Touch(Touch {
+                x: 100,
+                y: 50,
+                gesture: TouchGesture::Tap,
+            }),
+        ),
+        (
+            "touch_long_press",
+            Command::Touch(Touch {
+                x: 100,
+                y: 50,
+                gesture: TouchGesture::LongPress,
+            }),
+        ),
+        (
+            "touch_swipe",
+            Command::Touch(Touch {
+                x: 100,
+                y: 50,
+                gesture: TouchGesture::Swipe(700, 50),
+            }),
+        ),
+        (
+            "power_low_battery",
+            Command::Power(PowerState {
+                source: PowerSource::LowBattery,
+                charge: Some(12),
+            }),
+        ),
+        (
+            "status_image",
+            Command::StatusImage(StatusImage {
+                width: 2,
+                height: 1,
+                rgb: vec![0xff, 0, 0, 0, 0xff, 0],
+            }),
+        ),
+    ]
+}
+
+/// Every action a gateway sends, named as in the vectors.
+fn actions() -> Vec<(&'static str, DeviceActions)> {
+    vec![
+        (
+            "set_button_image",
+            DeviceActions::SetButtonImage(SetButtonImage {
+                button: 5,
+                image: IMAGE.to_vec(),
+            }),
+        ),
+        (
+            "set_lcd_image",
+            DeviceActions::SetLCDImage(SetLCDImage {
+                x_offset: 200,
+                x_size: 100,
+                y_size: 100,
+                image: IMAGE.to_vec(),
+            }),
+        ),
+        (
+            "set_brightness",
+            DeviceActions::SetBrightness(SetBrightness { brightness: 60 }),
+        ),
+    ]
+}
+
+#[test]
+fn test_commands_match_vectors() {
+    for (name, command) in commands() {
+        let expected = frame(VECTORS, name);
+        assert_eq!(postcard::to_allocvec(&command).unwrap(), expected, "{name}");
+        let decoded: Command = postcard::from_bytes(&expected).unwrap();
+        assert_eq!(format!("{decoded:?}"), format!("{command:?}"), "{name}");
+    }
+}
+
+#[test]
+fn test_actions_match_vectors() {
+    for (name, action) in actions() {
+        let expected = frame(VECTORS, name);
+        assert_eq!(postcard::to_allocvec(&action).unwrap(), expected, "{name}");
+        let decoded: DeviceActions = postcard::from_bytes(&expected).unwrap();
+        assert_eq!(format!("{decoded:?}"), format!("{action:?}"), "{name}");
+
+        // The leaf reads the same frames without copying the images
+        let borrowed: BorrowedDeviceActions = postcard::from_bytes(&expected).unwrap();
+        assert_eq!(
+            postcard::to_allocvec(&borrowed).unwrap(),
+            expected,
+            "{name}"
+        );
+    }
+}
+
+#[test]
+fn test_versions_read_and_written() {
+    for (name, command) in commands() {
+        let bare = frame(VECTORS, name);
+        let versioned = wire::encode(PROTOCOL_VERSION, &command).unwrap();
+        assert_eq!(versioned, [&wire::HEADER[..], &bare].concat(), "{name}");
+        for (version, frame) in [(UNVERSIONED, &bare), (PROTOCOL_VERSION, &versioned)] {
+            let decoded = wire::decode_command(frame).unwrap();
+            assert_eq!(decoded.0, version, "{name}");
+            assert_eq!(format!("{:?}", decoded.1), format!("{command:?}"), "{name}");
+        }
+    }
+    assert_eq!(
+        wire::encode(PROTOCOL_VERSION, &commands()[0].1).unwrap(),
+        frame(VECTORS, "config_v1")
+    );
+
+    // The first leaves are migrated to an unknown fingerprint
+    match wire::decode_command(&frame(VECTORS, "config_v0")).unwrap() {
+        (UNVERSIONED, Command::Config(config)) => {
+            assert_eq!(config.device_id, "deck");
+            assert_eq!(config.fingerprint, Fingerprint::default());
+        }
+        command => panic!("Unexpected command {command:?}"),
+    }
+
+    // Older leaves are answered with bare frames
+    let (_, brightness) = &actions()[2];
+    assert_eq!(
+        wire::encode(UNVERSIONED, brightness).unwrap(),
+        frame(VECTORS, "set_brightness")
+    );
+    let versioned = frame(VECTORS, "set_brightness_v1");
+    assert_eq!(
+        wire::encode(PROTOCOL_VERSION, brightness).unwrap(),
+        versioned
+    );
+    assert!(matches!(
+        wire::decode_actions(&versioned).unwrap(),
+        BorrowedDeviceActions::SetBrightness(SetBrightness { brightness: 60 })
+    ));
+}
+
+#[test]
+fn test_handshake_matches_vectors() {
+    let challenge = Challenge {
+        nonce: core::array::from_fn(|i| i as u8),
+    };
+    let expected = frame(VECTORS, "challenge");
+    assert_eq!(
+        wire::encode(PROTOCOL_VERSION, &challenge).unwrap(),
+        expected
+    );
+    assert_eq!(auth::decode::<Challenge>(&expected).unwrap(), challenge);
+
+    let answer = Auth::new(&challenge, [0x10; NONCE_LEN], "deck", b"secret");
+    let expected = frame(VECTORS, "auth");
+    assert_eq!(wire::encode(PROTOCOL_VERSION, &answer).unwrap(), expected);
+    assert!(auth::decode::<Auth>(&expected)
+        .unwrap()
+        .verify(&challenge, b"secret"));
+}
+
+#[test]
+fn test_hello_matches_vectors() {
+    for (name, capabilities) in [
+        ("hello_leaf", Capabilities::BRIGHTNESS),
+        (
+            "hello_gateway",
+            Capabilities::POWER | Capabilities::STATUS_IMAGES,
+        ),
+    ] {
+        let expected = frame(VECTORS, name);
+        let hello = Hello {
+            protocol_version: 1,
+            capabilities,
+        };
+        assert_eq!(hello.encode().unwrap(), expected, "{name}");
+        assert_eq!(hello::decode(&expected).unwrap().unwrap(), hello, "{name}");
+    }
+    // Leaves from before the hello start with their config
+    assert!(hello::decode(&frame(VECTORS, "config")).is_none());
+    assert!(hello::decode(&frame(VECTORS, "config_v1")).is_none());
+}
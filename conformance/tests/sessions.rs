@@ -0,0 +1,474 @@
+//! Random sessions, run through the serializers, the parsers and the pump.
+//!
+//! Where the other tests hold each protocol to the vectors, these generate
+//! sessions that are valid for it: what a leaf sends, what the gateway sends
+//! back, and the lines between Companion and a device.  Each is written out,
+//! read back in pieces of random size, and has to come out as it went in.
+//! Run through the pump between in-memory ends, every input reaches
+//! Companion in order, and every image either reaches the device or is
+//! counted as dropped for a newer image of the same key.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use base64::Engine as _;
+use companion::sender::{Sender, SenderConfig};
+use leaf_comm::framing::{self, Decoder};
+use leaf_comm::wire::{self, PROTOCOL_VERSION};
+use leaf_comm::{
+    ButtonChange, Command, DeviceActions, EncoderPress, EncoderTwist, PowerSource, PowerState,
+    RemoteConfig, SetBrightness, SetButtonImage, SetLCDImage, ShutdownReason, StatusImage, Touch,
+    TouchGesture,
+};
+use proptest::collection::vec;
+use proptest::prelude::*;
+use pumps::shutdown::ShutdownHandle;
+use pumps::stats::PumpStats;
+use tokio::io::AsyncBufReadExt;
+use tokio::sync::watch;
+use traits::companion::Sender as _;
+use traits::{async_trait, Result};
+
+/// Longest the pump is given to deliver a session.
+const SETTLE: Duration = Duration::from_secs(5);
+
+fn config() -> impl Strategy<Value = RemoteConfig> {
+    (any::<u16>(), "[a-z0-9]{1,12}").prop_map(|(pid, device_id)| RemoteConfig {
+        pid,
+        device_id,
+        fingerprint: Default::default(),
+    })
+}
+
+fn gesture() -> impl Strategy<Value = TouchGesture> {
+    prop_oneof![
+        Just(TouchGesture::Tap),
+        Just(TouchGesture::LongPress),
+        (any::<u16>(), any::<u16>()).prop_map(|(x, y)| TouchGesture::Swipe(x, y)),
+    ]
+}
+
+fn power() -> impl Strategy<Value = PowerState> {
+    let source = prop_oneof![
+        Just(PowerSource::External),
+        Just(PowerSource::Battery),
+        Just(PowerSource::LowBattery),
+    ];
+    (source, proptest::option::of(0u8..=100))
+        .prop_map(|(source, charge)| PowerState { source, charge })
+}
+
+fn status_image() -> impl Strategy<Value = StatusImage> {
+    (0u16..8, 0u16..8).prop_flat_map(|(width, height)| {
+        let len = usize::from(width) * usize::from(height) * 3;
+        vec(any::<u8>(), len).prop_map(move |rgb| StatusImage { width, height, rgb })
+    })
+}
+
+fn reason() -> impl Strategy<Value = ShutdownReason> {
+    prop_oneof![
+        Just(ShutdownReason::Shutdown),
+        Just(ShutdownReason::Kicked),
+        Just(ShutdownReason::ConfigReload),
+        Just(ShutdownReason::Repointed),
+    ]
+}
+
+/// Input a leaf reads from its deck, as the pump passes it on.
+fn input() -> impl Strategy<Value = Command> {
+    prop_oneof![
+        vec((0u8..32, any::<bool>()), 1..4)
+            .prop_map(|buttons| Command::ButtonChange(ButtonChange { buttons })),
+        vec((0u8..4, any::<i8>()), 1..4)
+            .prop_map(|encoders| Command::EncoderTwist(EncoderTwist { encoders })),
+        vec((0u8..4, any::<bool>()), 1..4)
+            .prop_map(|encoders| Command::EncoderPress(EncoderPress { encoders })),
+        (any::<u16>(), any::<u16>(), gesture()).prop_map(|(x, y, gesture)| Command::Touch(Touch {
+            x,
+            y,
+            gesture
+        })),
+        power().prop_map(Command::Power),
+        status_image().prop_map(Command::StatusImage),
+    ]
+}
+
+/// Anything a leaf sends the gateway.
+fn command() -> impl Strategy<Value = Command> {
+    prop_oneof![
+        4 => input(),
+        1 => config().prop_map(Command::Config),
+        1 => reason().prop_map(Command::Goodbye),
+    ]
+}
+
+/// Anything the gateway sends a leaf, with images compressible or not.
+fn action() -> impl Strategy<Value = DeviceActions> {
+    let image = prop_oneof![
+        vec(any::<u8>(), 0..200),
+        (any::<u8>(), 0usize..400).prop_map(|(byte, len)| vec![byte; len])
+    ];
+    prop_oneof![
+        (0u8..32, image.clone()).prop_map(|(button, image)| DeviceActions::SetButtonImage(
+            SetButtonImage { button, image }
+        )),
+        (0u8..32, image.clone()).prop_map(|(button, image)| {
+            DeviceActions::PrepareButtonImage(SetButtonImage { button, image })
+        }),
+        (any::<u16>(), any::<u16>(), any::<u16>(), image).prop_map(
+            |(x_offset, x_size, y_size, image)| {
+                DeviceActions::SetLCDImage(SetLCDImage {
+                    x_offset,
+                    x_size,
+                    y_size,
+                    image,
+                })
+            }
+        ),
+        (0u8..=100)
+            .prop_map(|brightness| DeviceActions::SetBrightness(SetBrightness { brightness })),
+        Just(DeviceActions::Commit),
+        Just(DeviceActions::ClearAll),
+    ]
+}
+
+/// Write frames out as they go over a link, then read them back in pieces
+/// of the given sizes.
+fn over_link(frames: &[Vec<u8>], pieces: &[usize]) -> Vec<Vec<u8>> {
+    let mut out = Vec::new();
+    for frame in frames {
+        framing::encode(frame, &mut out).unwrap();
+    }
+    let mut decoder = Decoder::default();
+    let mut read = Vec::new();
+    let mut rest = out.as_slice();
+    for &len in pieces.iter().cycle() {
+        if rest.is_empty() {
+            break;
+        }
+        let (mut piece, after) = rest.split_at(len.min(rest.len()));
+        rest = after;
+        while let Some(frame) = decoder.decode(&mut piece).unwrap() {
+            read.push(frame.to_vec());
+        }
+    }
+    read
+}
+
+proptest! {
+    #[test]
+    fn test_leaf_commands_round_trip(
+        commands in vec(command(), 0..32),
+        pieces in vec(1usize..64, 1..8),
+    ) {
+        let frames: Vec<_> = commands
+            .iter()
+            .map(|command| wire::encode(PROTOCOL_VERSION, command).unwrap())
+            .collect();
+        let read = over_link(&frames, &pieces);
+        prop_assert_eq!(read.len(), commands.len());
+        for (frame, command) in read.iter().zip(&commands) {
+            let (version, decoded) = wire::decode_command(frame).unwrap();
+            prop_assert_eq!(version, PROTOCOL_VERSION);
+            prop_assert_eq!(format!("{decoded:?}"), format!("{command:?}"));
+        }
+    }
+
+    #[test]
+    fn test_device_actions_round_trip(
+        actions in vec((action(), any::<bool>()), 0..32),
+        pieces in vec(1usize..256, 1..8),
+    ) {
+        let frames: Vec<_> = actions
+            .iter()
+            .map(|(action, compressed)| {
+                let frame = wire::encode(PROTOCOL_VERSION, action).unwrap();
+                if *compressed {
+                    leaf_comm::compress::compress(frame)
+                } else {
+                    frame
+                }
+            })
+            .collect();
+        let read = over_link(&frames, &pieces);
+        prop_assert_eq!(read.len(), actions.len());
+        for (frame, (action, _)) in read.iter().zip(&actions) {
+            let frame = match leaf_comm::compress::decompress(frame, framing::MAX_FRAME_LEN) {
+                Some(inner) => inner.unwrap(),
+                None => frame.clone(),
+            };
+            let decoded = DeviceActions::from(wire::decode_actions(&frame).unwrap());
+            prop_assert_eq!(format!("{decoded:?}"), format!("{action:?}"));
+        }
+    }
+
+    #[test]
+    fn test_key_states_from_companion_parse(
+        states in vec((any::<u8>(), vec(any::<u8>(), 1..64), any::<bool>()), 0..16),
+    ) {
+        for (key, bitmap, pressed) in states {
+            let line = format!(
+                "KEY-STATE DEVICEID=\"deck\" KEY={key} TYPE=BUTTON BITMAP={} PRESSED={pressed}",
+                base64::engine::general_purpose::STANDARD_NO_PAD.encode(&bitmap)
+            );
+            match companion::Command::parse(&line).unwrap() {
+                companion::Command::KeyState(keystate) => {
+                    prop_assert_eq!(keystate.key, key);
+                    prop_assert_eq!(keystate.pressed, pressed);
+                    prop_assert_eq!(keystate.bitmap().unwrap(), bitmap);
+                }
+                command => prop_assert!(false, "Unexpected command {:?}", command),
+            }
+        }
+    }
+
+    #[test]
+    fn test_key_presses_to_companion_in_order(
+        changes in vec(vec((0u8..15, any::<bool>()), 1..4), 0..32),
+    ) {
+        let sent: Vec<_> = changes.iter().flatten().copied().collect();
+        let received = runtime().block_on(async {
+            let (writer, reader) = tokio::io::duplex(1 << 16);
+            let config = RemoteConfig {
+                pid: 0x0080,
+                device_id: String::from("deck"),
+                fingerprint: Default::default(),
+            };
+            let sender_config = SenderConfig {
+                ping_interval: Duration::from_secs(3600),
+                ..Default::default()
+            };
+            let mut sender = Sender::with_config(writer, config, sender_config, String::new)
+                .await
+                .unwrap();
+            for buttons in changes {
+                sender.button_change(ButtonChange { buttons }).await.unwrap();
+            }
+            sender.close().await.unwrap();
+            let mut lines = tokio::io::BufReader::new(reader).lines();
+            let mut received = Vec::new();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                // Only the input, not the device being added
+                if !line.starts_with("KEY-PRESS") {
+                    continue;
+                }
+                match companion::Command::parse(&line).unwrap() {
+                    companion::Command::KeyPress(press) => received.push(key_press(press)),
+                    command => prop_assert!(false, "Unexpected command {:?}", command),
+                }
+            }
+            Ok::<_, TestCaseError>(received)
+        })?;
+        prop_assert_eq!(received, sent);
+    }
+
+    #[test]
+    fn test_pump_delivers_sessions(
+        inputs in vec(input(), 0..32),
+        actions in vec(pump_action(), 0..64),
+    ) {
+        runtime().block_on(pump_session(inputs, actions))?;
+    }
+}
+
+/// The key and state of the data of a KEY-PRESS line.
+fn key_press(data: &str) -> (u8, bool) {
+    let value = |name: &str| {
+        data.split(' ')
+            .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+            .unwrap()
+    };
+    (value("KEY").parse().unwrap(), value("PRESSED") == "1")
+}
+
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap()
+}
+
+/// What Companion sends a device, each image told apart from the others.
+fn pump_action() -> impl Strategy<Value = DeviceActions> {
+    prop_oneof![
+        4 => (0u8..8).prop_map(|button| DeviceActions::SetButtonImage(SetButtonImage {
+            button,
+            image: Vec::new(),
+        })),
+        1 => (0u8..=100).prop_map(|brightness| DeviceActions::SetBrightness(SetBrightness { brightness })),
+    ]
+}
+
+/// Hands out a script, then waits forever as an idle end would.
+struct Script<T>(VecDeque<T>);
+
+impl<T> Script<T> {
+    async fn next(&mut self) -> Result<T> {
+        match self.0.pop_front() {
+            Some(next) => Ok(next),
+            None => std::future::pending().await,
+        }
+    }
+}
+
+#[async_trait]
+impl traits::device::Receiver for Script<Command> {
+    async fn receive(&mut self) -> Result<Command> {
+        self.next().await
+    }
+}
+
+#[async_trait]
+impl traits::companion::Receiver for Script<DeviceActions> {
+    async fn receive(&mut self) -> Result<DeviceActions> {
+        self.next().await
+    }
+}
+
+/// What reached each end of the pump.
+#[derive(Clone, Default)]
+struct Recorded(Arc<Mutex<Vec<String>>>);
+
+impl Recorded {
+    fn push(&self, what: impl std::fmt::Debug) -> Result<()> {
+        self.0.lock().unwrap().push(format!("{what:?}"));
+        Ok(())
+    }
+
+    fn get(&self) -> Vec<String> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl traits::companion::Sender for Recorded {
+    async fn config(&mut self, config: RemoteConfig) -> Result<()> {
+        self.push(Command::Config(config))
+    }
+    async fn button_change(&mut self, change: ButtonChange) -> Result<()> {
+        self.push(Command::ButtonChange(change))
+    }
+    async fn encoder_twist(&mut self, twist: EncoderTwist) -> Result<()> {
+        self.push(Command::EncoderTwist(twist))
+    }
+    async fn encoder_press(&mut self, press: EncoderPress) -> Result<()> {
+        self.push(Command::EncoderPress(press))
+    }
+    async fn touch(&mut self, touch: Touch) -> Result<()> {
+        self.push(Command::Touch(touch))
+    }
+    async fn power(&mut self, power: PowerState) -> Result<()> {
+        self.push(Command::Power(power))
+    }
+    async fn status_image(&mut self, image: StatusImage) -> Result<()> {
+        self.push(Command::StatusImage(image))
+    }
+    async fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The images of each key, in the order sent.
+type Images = BTreeMap<u8, Vec<Vec<u8>>>;
+
+/// The images and brightness that reached the device.
+#[derive(Clone, Default)]
+struct Device(Arc<Mutex<(Images, Vec<u8>)>>);
+
+#[async_trait]
+impl traits::device::Sender for Device {
+    async fn set_brightness(&mut self, brightness: SetBrightness) -> Result<()> {
+        self.0.lock().unwrap().1.push(brightness.brightness);
+        Ok(())
+    }
+    async fn set_button_image(&mut self, image: SetButtonImage) -> Result<()> {
+        let mut device = self.0.lock().unwrap();
+        device.0.entry(image.button).or_default().push(image.image);
+        Ok(())
+    }
+    async fn set_lcd_image(&mut self, _: SetLCDImage) -> Result<()> {
+        Ok(())
+    }
+    async fn clear(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Run inputs and actions through the pump at once, checking what comes out
+/// of either end.
+async fn pump_session(
+    inputs: Vec<Command>,
+    mut actions: Vec<DeviceActions>,
+) -> std::result::Result<(), TestCaseError> {
+    // Numbered, so each image delivered is told apart
+    for (i, action) in actions.iter_mut().enumerate() {
+        if let DeviceActions::SetButtonImage(image) = action {
+            image.image = (i as u32).to_le_bytes().to_vec();
+        }
+    }
+    let mut sent = Images::new();
+    let mut brightness = None;
+    for action in &actions {
+        match action {
+            DeviceActions::SetButtonImage(image) => sent
+                .entry(image.button)
+                .or_default()
+                .push(image.image.clone()),
+            DeviceActions::SetBrightness(b) => brightness = Some(b.brightness),
+            _ => unreachable!(),
+        }
+    }
+
+    let (companion, device) = (Recorded::default(), Device::default());
+    let stats = Arc::new(PumpStats::default());
+    let shutdown = ShutdownHandle::new();
+    let expected: Vec<_> = inputs.iter().map(|input| format!("{input:?}")).collect();
+    let count = actions.len() as u64;
+    let pump = pumps::message_pump_until(
+        device.clone(),
+        Script(inputs.into()),
+        companion.clone(),
+        Script(actions.into()),
+        stats.clone(),
+        watch::channel(Default::default()).1,
+        shutdown.clone(),
+    );
+    let settled = async {
+        loop {
+            let snapshot = stats.snapshot();
+            if companion.get().len() == expected.len()
+                && snapshot.frames_to_device + snapshot.dropped_frames == count
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        shutdown.shutdown();
+    };
+    let (res, settled) = tokio::join!(pump, tokio::time::timeout(SETTLE, settled));
+    prop_assert!(
+        settled.is_ok(),
+        "Session not delivered: {:?}",
+        stats.snapshot()
+    );
+    prop_assert!(res.is_ok());
+
+    // All input reaches Companion, in order
+    prop_assert_eq!(companion.get(), expected.clone());
+    prop_assert_eq!(stats.snapshot().frames_to_companion, expected.len() as u64);
+
+    // Each key shows its images in the order sent, ending with the last
+    let (delivered, brightnesses) = device.0.lock().unwrap().clone();
+    for (button, sent) in &sent {
+        let delivered = delivered.get(button).map(Vec::as_slice).unwrap_or_default();
+        prop_assert_eq!(delivered.last(), sent.last());
+        let mut rest = sent.iter();
+        for image in delivered {
+            prop_assert!(rest.any(|s| s == image), "Key {} out of order", button);
+        }
+    }
+    prop_assert!(delivered.keys().all(|button| sent.contains_key(button)));
+    prop_assert_eq!(brightnesses.last().copied(), brightness);
+    Ok(())
+}
@@ -1,58 +1,101 @@
+//! Conversion of key images into the data a device takes.
+//!
+//! Rotating and mirroring raw RGB works everywhere.  Encoding it into the
+//! JPEG or BMP the device expects needs the `images` feature, which pulls in
+//! the [image](https://crates.io/crates/image) crate and with it std.
+
+use alloc::vec::Vec;
+
+use crate::info::{ImageMirroring, ImageRotation};
+use crate::{Kind, StreamDeckError};
+
+#[cfg(feature = "images")]
 use image::codecs::bmp::BmpEncoder;
+#[cfg(feature = "images")]
 use image::codecs::jpeg::JpegEncoder;
+#[cfg(feature = "images")]
 use image::imageops::FilterType;
+#[cfg(feature = "images")]
 use image::{ColorType, DynamicImage, GenericImageView, ImageError};
-#[allow(unused_imports)]
-use std::sync::Arc;
 
-use crate::info::{ImageMirroring, ImageMode, ImageRotation};
-use crate::{Kind, StreamDeckError};
+#[cfg(feature = "images")]
+use crate::info::ImageMode;
 
-/// Converts image into image data depending on provided kind of device
-pub fn convert_image(kind: Kind, image: DynamicImage) -> Result<Vec<u8>, ImageError> {
+/// Rotates and mirrors a key image of packed 8 bit RGB the way the kind of
+/// device expects it.  The image must be the key size of the device.
+pub fn orient_rgb(kind: Kind, rgb: &[u8]) -> Result<Vec<u8>, StreamDeckError> {
     let image_format = kind.key_image_format();
+    let (width, height) = image_format.size;
+    if rgb.len() != width * height * 3 {
+        return Err(StreamDeckError::InvalidImageSize);
+    }
 
-    // Ensuring size of the image
-    let (ws, hs) = image_format.size;
-
-    let image = image.resize_exact(ws as u32, hs as u32, FilterType::Nearest);
-
-    // Applying rotation
-    let image = match image_format.rotation {
-        ImageRotation::Rot0 => image,
-        ImageRotation::Rot90 => image.rotate90(),
-        ImageRotation::Rot180 => image.rotate180(),
-        ImageRotation::Rot270 => image.rotate270(),
+    let (out_width, out_height) = match image_format.rotation {
+        ImageRotation::Rot90 | ImageRotation::Rot270 => (height, width),
+        ImageRotation::Rot0 | ImageRotation::Rot180 => (width, height),
     };
-
-    // Applying mirroring
-    let image = match image_format.mirror {
-        ImageMirroring::None => image,
-        ImageMirroring::X => image.fliph(),
-        ImageMirroring::Y => image.flipv(),
-        ImageMirroring::Both => image.fliph().flipv(),
+    let (flip_x, flip_y) = match image_format.mirror {
+        ImageMirroring::None => (false, false),
+        ImageMirroring::X => (true, false),
+        ImageMirroring::Y => (false, true),
+        ImageMirroring::Both => (true, true),
     };
 
-    let image_data = image.into_rgb8().to_vec();
+    let mut oriented = Vec::with_capacity(rgb.len());
+    for y in 0..out_height {
+        for x in 0..out_width {
+            // Mirroring is applied after rotating, so it is undone first
+            let x = if flip_x { out_width - 1 - x } else { x };
+            let y = if flip_y { out_height - 1 - y } else { y };
+            let (x, y) = match image_format.rotation {
+                ImageRotation::Rot0 => (x, y),
+                ImageRotation::Rot90 => (y, height - 1 - x),
+                ImageRotation::Rot180 => (width - 1 - x, height - 1 - y),
+                ImageRotation::Rot270 => (width - 1 - y, x),
+            };
+            let index = (y * width + x) * 3;
+            oriented.extend_from_slice(&rgb[index..index + 3]);
+        }
+    }
+    Ok(oriented)
+}
+
+/// Converts a key image of packed 8 bit RGB into image data for the kind of
+/// device.  The image must be the key size of the device.
+#[cfg(feature = "images")]
+#[cfg_attr(docsrs, doc(cfg(feature = "images")))]
+pub fn convert_rgb(kind: Kind, rgb: &[u8]) -> Result<Vec<u8>, StreamDeckError> {
+    let image_format = kind.key_image_format();
+    let oriented = orient_rgb(kind, rgb)?;
+    // Keys are square, so rotating doesn't change the size
+    let (width, height) = image_format.size;
 
-    // Encoding image
     match image_format.mode {
-        ImageMode::None => Ok(vec![]),
+        ImageMode::None => Ok(Vec::new()),
         ImageMode::BMP => {
             let mut buf = Vec::new();
             let mut encoder = BmpEncoder::new(&mut buf);
-            encoder.encode(&image_data, ws as u32, hs as u32, ColorType::Rgb8)?;
+            encoder.encode(&oriented, width as u32, height as u32, ColorType::Rgb8)?;
             Ok(buf)
         }
         ImageMode::JPEG => {
             let mut buf = Vec::new();
             let mut encoder = JpegEncoder::new_with_quality(&mut buf, 90);
-            encoder.encode(&image_data, ws as u32, hs as u32, ColorType::Rgb8)?;
+            encoder.encode(&oriented, width as u32, height as u32, ColorType::Rgb8)?;
             Ok(buf)
         }
     }
 }
 
+/// Converts image into image data depending on provided kind of device
+#[cfg(feature = "images")]
+#[cfg_attr(docsrs, doc(cfg(feature = "images")))]
+pub fn convert_image(kind: Kind, image: DynamicImage) -> Result<Vec<u8>, StreamDeckError> {
+    let (width, height) = kind.key_image_format().size;
+    let image = image.resize_exact(width as u32, height as u32, FilterType::Nearest);
+    convert_rgb(kind, &image.into_rgb8().into_raw())
+}
+
 /// Converts image into image data depending on provided kind of device, can be safely ran inside [multi_thread](tokio::runtime::Builder::new_multi_thread) runtime
 #[cfg(feature = "async")]
 #[cfg_attr(docsrs, doc(cfg(feature = "async")))]
@@ -60,29 +103,18 @@ pub fn convert_image_async(
     kind: Kind,
     image: DynamicImage,
 ) -> Result<Vec<u8>, crate::StreamDeckError> {
-    Ok(tokio::task::block_in_place(move || {
-        convert_image(kind, image)
-    })?)
+    tokio::task::block_in_place(move || convert_image(kind, image))
 }
 
-/// Rect to be used when trying to send image to lcd screen
-pub struct ImageRect {
-    /// Width of the image
-    pub w: u16,
-
-    /// Height of the image
-    pub h: u16,
-
-    /// Data of the image row by row as RGB
-    pub data: Vec<u8>,
-}
+pub use crate::ImageRect;
 
+#[cfg(feature = "images")]
 impl ImageRect {
     /// Converts image to image rect
     pub fn from_image(image: DynamicImage) -> Result<ImageRect, StreamDeckError> {
         let (image_w, image_h) = image.dimensions();
 
-        let image_data = image.into_rgb8().to_vec();
+        let image_data = image.into_rgb8().into_raw();
 
         let mut buf = Vec::new();
         let mut encoder = JpegEncoder::new_with_quality(&mut buf, 90);
@@ -95,11 +127,6 @@ impl ImageRect {
         })
     }
 
-    /// Constructs an ImageRect from a image buffer already formatted for the device
-    pub fn from_device_image(w: u16, h: u16, data: Vec<u8>) -> Self {
-        ImageRect { w, h, data }
-    }
-
     /// Converts image to image rect, can be safely ran inside [multi_thread](tokio::runtime::Builder::new_multi_thread) runtime
     #[cfg(feature = "async")]
     #[cfg_attr(docsrs, doc(cfg(feature = "async")))]
@@ -107,3 +134,115 @@ impl ImageRect {
         tokio::task::block_in_place(move || ImageRect::from_image(image))
     }
 }
+
+#[cfg(feature = "images")]
+impl From<ImageError> for StreamDeckError {
+    fn from(e: ImageError) -> Self {
+        Self::ImageError(e)
+    }
+}
+
+#[cfg(all(test, feature = "images"))]
+mod tests {
+    use super::*;
+    use crate::{HidDevice, HidError, StreamDeck};
+
+    #[test]
+    fn test_orientation_matches_image_crate() {
+        for kind in [Kind::Original, Kind::Mini, Kind::Xl, Kind::Plus] {
+            let (width, height) = kind.key_image_format().size;
+            let rgb: Vec<u8> = (0..width * height * 3).map(|i| (i % 251) as u8).collect();
+            let image = image::RgbImage::from_raw(width as u32, height as u32, rgb.clone())
+                .map(DynamicImage::ImageRgb8)
+                .unwrap();
+
+            let image_format = kind.key_image_format();
+            let image = match image_format.rotation {
+                ImageRotation::Rot0 => image,
+                ImageRotation::Rot90 => image.rotate90(),
+                ImageRotation::Rot180 => image.rotate180(),
+                ImageRotation::Rot270 => image.rotate270(),
+            };
+            let image = match image_format.mirror {
+                ImageMirroring::None => image,
+                ImageMirroring::X => image.fliph(),
+                ImageMirroring::Y => image.flipv(),
+                ImageMirroring::Both => image.fliph().flipv(),
+            };
+            assert_eq!(
+                orient_rgb(kind, &rgb).unwrap(),
+                image.into_rgb8().into_raw()
+            );
+        }
+        assert!(orient_rgb(Kind::Plus, &[0; 3]).is_err());
+    }
+
+    /// Keeps the reports written to it.
+    #[derive(Default)]
+    struct Recorder {
+        reports: core::cell::RefCell<Vec<Vec<u8>>>,
+    }
+
+    impl HidDevice for Recorder {
+        fn read_timeout(&self, _buf: &mut [u8], _timeout: i32) -> Result<(), HidError> {
+            Ok(())
+        }
+        fn read(&self, _buf: &mut [u8]) -> Result<(), HidError> {
+            Ok(())
+        }
+        fn write(&self, payload: &[u8]) -> Result<usize, HidError> {
+            self.reports.borrow_mut().push(payload.to_vec());
+            Ok(payload.len())
+        }
+        fn get_feature_report(&self, _buf: &mut [u8]) -> Result<(), HidError> {
+            Ok(())
+        }
+        fn send_feature_report(&self, _payload: &[u8]) -> Result<(), HidError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_lcd_rect_written_in_pages() {
+        let rgb: Vec<u8> = (0..200 * 100 * 3).map(|i| (i % 251) as u8).collect();
+        let image = image::RgbImage::from_raw(200, 100, rgb).unwrap();
+        let rect = ImageRect::from_image(DynamicImage::ImageRgb8(image)).unwrap();
+        assert_eq!(rect.data[..2], [0xff, 0xd8]);
+
+        let deck = StreamDeck::new(Recorder::default(), Kind::Plus);
+        deck.write_lcd(400, 0, &rect).unwrap();
+        let reports = deck.device.reports.take();
+        assert!(reports.len() > 1);
+        let mut data = Vec::new();
+        for (page, report) in reports.iter().enumerate() {
+            assert_eq!(report.len(), 1024);
+            // At x 400 (0x190), 200 by 100, the last page flagged
+            assert_eq!(report[..10], [0x02, 0x0c, 0x90, 0x01, 0, 0, 200, 0, 100, 0]);
+            assert_eq!(report[10], u8::from(page == reports.len() - 1));
+            assert_eq!(
+                usize::from(u16::from_le_bytes([report[11], report[12]])),
+                page
+            );
+            let length = usize::from(u16::from_le_bytes([report[13], report[14]]));
+            data.extend_from_slice(&report[16..16 + length]);
+        }
+        assert_eq!(data, rect.data);
+
+        let mini = StreamDeck::new(Recorder::default(), Kind::Mini);
+        assert!(mini.write_lcd(0, 0, &rect).is_err());
+    }
+
+    #[test]
+    fn test_xl_image_written_in_pages() {
+        let image: Vec<u8> = (0..2000).map(|i| (i % 251) as u8).collect();
+        let deck = StreamDeck::new(Recorder::default(), Kind::Xl);
+        deck.write_image(5, &image).unwrap();
+        let reports = deck.device.reports.take();
+        // 1016 bytes after the header of each page of 1024
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0][..8], [0x02, 0x07, 5, 0, 0xf8, 0x03, 0, 0]);
+        assert_eq!(reports[1][..8], [0x02, 0x07, 5, 1, 0xd8, 0x03, 1, 0]);
+        assert!(reports.iter().all(|report| report.len() == 1024));
+        assert_eq!([&reports[0][8..], &reports[1][8..992]].concat(), image);
+    }
+}
@@ -35,15 +35,20 @@ pub trait HidDevice {
 
 //use crate::info::{Kind, ELGATO_VENDOR_ID};
 use crate::info::Kind;
+use crate::mapping::KeyMap;
 use crate::util::{
-    extract_str, flip_key_index, get_feature_report, read_button_states, read_data,
-    read_encoder_input, read_lcd_input, send_feature_report, write_data,
+    extract_str, get_feature_report, read_button_states, read_data, read_encoder_input,
+    read_lcd_input, send_feature_report, write_data,
 };
 
 /// Various information about Stream Deck devices
 pub mod info;
 /// Utility functions for working with Stream Deck devices
 pub mod util;
+/// Image processing functions
+pub mod images;
+/// Mapping of keys onto the key indices of devices
+pub mod mapping;
 
 /// Async Stream Deck
 #[cfg(feature = "async")]
@@ -86,6 +91,26 @@ impl StreamDeckInput {
     }
 }
 
+/// Rect to be used when trying to send image to lcd screen
+#[derive(Clone, Debug)]
+pub struct ImageRect {
+    /// Width of the image
+    pub w: u16,
+
+    /// Height of the image
+    pub h: u16,
+
+    /// Data of the image, already encoded for the device
+    pub data: Vec<u8>,
+}
+
+impl ImageRect {
+    /// Constructs an ImageRect from a image buffer already formatted for the device
+    pub fn from_device_image(w: u16, h: u16, data: Vec<u8>) -> Self {
+        ImageRect { w, h, data }
+    }
+}
+
 /// Interface for a Stream Deck device
 pub struct StreamDeck<DEV: HidDevice> {
     /// Kind of the device
@@ -125,7 +150,7 @@ impl<DEV: HidDevice> StreamDeck<DEV> {
                 Ok(extract_str(&bytes[5..])?)
             }
 
-            _ => {
+            Kind::OriginalV2 | Kind::Mk2 | Kind::Xl | Kind::XlV2 | Kind::Pedal | Kind::Plus => {
                 let bytes = get_feature_report(&self.device, 0x06, 32)?;
                 Ok(extract_str(&bytes[2..])?)
             }
@@ -140,7 +165,7 @@ impl<DEV: HidDevice> StreamDeck<DEV> {
                 Ok(extract_str(&bytes[5..])?)
             }
 
-            _ => {
+            Kind::OriginalV2 | Kind::Mk2 | Kind::Xl | Kind::XlV2 | Kind::Pedal | Kind::Plus => {
                 let bytes = get_feature_report(&self.device, 0x05, 32)?;
                 Ok(extract_str(&bytes[6..])?)
             }
@@ -182,7 +207,14 @@ impl<DEV: HidDevice> StreamDeck<DEV> {
                     Kind::Original | Kind::Mini | Kind::MiniMk2 => {
                         read_data(&self.device, 1 + self.kind.key_count() as usize, timeout)
                     }
-                    _ => read_data(&self.device, 4 + self.kind.key_count() as usize, timeout),
+                    Kind::OriginalV2
+                    | Kind::Mk2
+                    | Kind::Xl
+                    | Kind::XlV2
+                    | Kind::Pedal
+                    | Kind::Plus => {
+                        read_data(&self.device, 4 + self.kind.key_count() as usize, timeout)
+                    }
                 }?;
 
                 if data[0] == 0 {
@@ -207,7 +239,7 @@ impl<DEV: HidDevice> StreamDeck<DEV> {
                 Ok(send_feature_report(&self.device, buf.as_slice())?)
             }
 
-            _ => {
+            Kind::OriginalV2 | Kind::Mk2 | Kind::Xl | Kind::XlV2 | Kind::Pedal | Kind::Plus => {
                 let mut buf = vec![0x03, 0x02];
 
                 buf.extend(vec![0u8; 30]);
@@ -217,10 +249,15 @@ impl<DEV: HidDevice> StreamDeck<DEV> {
         }
     }
 
-    /// Sets brightness of the device, value range is 0 - 100
+    /// Sets brightness of the device, value range is 0 - 100.  Devices
+    /// without a screen have nothing to dim, so this does nothing.
     pub fn set_brightness(&self, percent: u8) -> Result<(), StreamDeckError> {
         let percent = percent.max(0).min(100);
 
+        if !self.kind.is_visual() {
+            return Ok(());
+        }
+
         match self.kind {
             Kind::Original | Kind::Mini | Kind::MiniMk2 => {
                 let mut buf = vec![0x05, 0x55, 0xaa, 0xd1, 0x01, percent];
@@ -230,7 +267,7 @@ impl<DEV: HidDevice> StreamDeck<DEV> {
                 Ok(send_feature_report(&self.device, buf.as_slice())?)
             }
 
-            _ => {
+            Kind::OriginalV2 | Kind::Mk2 | Kind::Xl | Kind::XlV2 | Kind::Pedal | Kind::Plus => {
                 let mut buf = vec![0x03, 0x08, percent];
 
                 buf.extend(vec![0u8; 29]);
@@ -242,15 +279,9 @@ impl<DEV: HidDevice> StreamDeck<DEV> {
 
     /// Writes image data to Stream Deck device
     pub fn write_image(&self, key: u8, image_data: &[u8]) -> Result<(), StreamDeckError> {
-        if key >= self.kind.key_count() {
-            return Err(StreamDeckError::InvalidKeyIndex);
-        }
-
-        let key = if let Kind::Original = self.kind {
-            flip_key_index(&self.kind, key)
-        } else {
-            key
-        };
+        let key = KeyMap::new(self.kind)
+            .to_device(key)
+            .ok_or(StreamDeckError::InvalidKeyIndex)?;
 
         if !self.kind.is_visual() {
             return Err(StreamDeckError::NoScreen);
@@ -263,7 +294,7 @@ impl<DEV: HidDevice> StreamDeck<DEV> {
 
         let image_report_header_length = match self.kind {
             Kind::Original | Kind::Mini | Kind::MiniMk2 => 16,
-            _ => 8,
+            Kind::OriginalV2 | Kind::Mk2 | Kind::Xl | Kind::XlV2 | Kind::Pedal | Kind::Plus => 8,
         };
 
         let image_report_payload_length = match self.kind {
@@ -318,16 +349,18 @@ impl<DEV: HidDevice> StreamDeck<DEV> {
                     0,
                 ],
 
-                _ => vec![
-                    0x02,
-                    0x07,
-                    key,
-                    if this_length == bytes_remaining { 1 } else { 0 },
-                    (this_length & 0xff) as u8,
-                    (this_length >> 8) as u8,
-                    (page_number & 0xff) as u8,
-                    (page_number >> 8) as u8,
-                ],
+                Kind::OriginalV2 | Kind::Mk2 | Kind::Xl | Kind::XlV2 | Kind::Pedal | Kind::Plus => {
+                    vec![
+                        0x02,
+                        0x07,
+                        key,
+                        if this_length == bytes_remaining { 1 } else { 0 },
+                        (this_length & 0xff) as u8,
+                        (this_length >> 8) as u8,
+                        (page_number & 0xff) as u8,
+                        (page_number >> 8) as u8,
+                    ]
+                }
             };
 
             buf.extend(&image_data[bytes_sent..bytes_sent + this_length]);
@@ -344,73 +377,81 @@ impl<DEV: HidDevice> StreamDeck<DEV> {
         Ok(())
     }
 
-    /// Writes image data to Stream Deck device's lcd strip/screen
-    // pub fn write_lcd(&self, x: u16, y: u16, rect: &ImageRect) -> Result<(), StreamDeckError> {
-    //     match self.kind {
-    //         Kind::Plus => {}
-    //         _ => return Err(StreamDeckError::UnsupportedOperation),
-    //     }
-
-    //     let image_report_length = 1024;
-
-    //     let image_report_header_length = 16;
-
-    //     let image_report_payload_length = image_report_length - image_report_header_length;
-
-    //     let mut page_number = 0;
-    //     let mut bytes_remaining = rect.data.len();
-
-    //     while bytes_remaining > 0 {
-    //         let this_length = bytes_remaining.min(image_report_payload_length);
-    //         let bytes_sent = page_number * image_report_payload_length;
-
-    //         // Selecting header based on device
-    //         let mut buf: Vec<u8> = vec![
-    //             0x02,
-    //             0x0c,
-    //             (x & 0xff) as u8,
-    //             (x >> 8) as u8,
-    //             (y & 0xff) as u8,
-    //             (y >> 8) as u8,
-    //             (rect.w & 0xff) as u8,
-    //             (rect.w >> 8) as u8,
-    //             (rect.h & 0xff) as u8,
-    //             (rect.h >> 8) as u8,
-    //             if bytes_remaining <= image_report_payload_length {
-    //                 1
-    //             } else {
-    //                 0
-    //             },
-    //             (page_number & 0xff) as u8,
-    //             (page_number >> 8) as u8,
-    //             (this_length & 0xff) as u8,
-    //             (this_length >> 8) as u8,
-    //             0,
-    //         ];
-
-    //         buf.extend(&rect.data[bytes_sent..bytes_sent + this_length]);
-
-    //         // Adding padding
-    //         buf.extend(vec![0u8; image_report_length - buf.len()]);
-
-    //         write_data(&self.device, &buf)?;
-
-    //         bytes_remaining -= this_length;
-    //         page_number += 1;
-    //     }
-
-    //     Ok(())
-    // }
+    /// Writes image data to Stream Deck device's lcd strip/screen.  The data
+    /// of the rect must already be encoded for the device (JPEG on the Plus).
+    pub fn write_lcd(&self, x: u16, y: u16, rect: &ImageRect) -> Result<(), StreamDeckError> {
+        match self.kind {
+            Kind::Plus => {}
+            _ => return Err(StreamDeckError::UnsupportedOperation),
+        }
+
+        let image_report_length = 1024;
+
+        let image_report_header_length = 16;
+
+        let image_report_payload_length = image_report_length - image_report_header_length;
+
+        let mut page_number = 0;
+        let mut bytes_remaining = rect.data.len();
+
+        while bytes_remaining > 0 {
+            let this_length = bytes_remaining.min(image_report_payload_length);
+            let bytes_sent = page_number * image_report_payload_length;
+
+            // Selecting header based on device
+            let mut buf: Vec<u8> = vec![
+                0x02,
+                0x0c,
+                (x & 0xff) as u8,
+                (x >> 8) as u8,
+                (y & 0xff) as u8,
+                (y >> 8) as u8,
+                (rect.w & 0xff) as u8,
+                (rect.w >> 8) as u8,
+                (rect.h & 0xff) as u8,
+                (rect.h >> 8) as u8,
+                if bytes_remaining <= image_report_payload_length {
+                    1
+                } else {
+                    0
+                },
+                (page_number & 0xff) as u8,
+                (page_number >> 8) as u8,
+                (this_length & 0xff) as u8,
+                (this_length >> 8) as u8,
+                0,
+            ];
+
+            buf.extend(&rect.data[bytes_sent..bytes_sent + this_length]);
+
+            // Adding padding
+            buf.extend(vec![0u8; image_report_length - buf.len()]);
+
+            write_data(&self.device, &buf)?;
+
+            bytes_remaining -= this_length;
+            page_number += 1;
+        }
+
+        Ok(())
+    }
 
     /// Sets button's image to blank
     pub fn clear_button_image(&self, key: u8) -> Result<(), StreamDeckError> {
         self.write_image(key, &self.kind.blank_image())
     }
 
-    // pub fn set_button_image(&self, key: u8, image: DynamicImage) -> Result<(), StreamDeckError> {
-    //     let image_data = convert_image(self.kind, image)?;
-    //     self.write_image(key, &image_data)
-    // }
+    /// Sets button's image, converting it for the device
+    #[cfg(feature = "images")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "images")))]
+    pub fn set_button_image(
+        &self,
+        key: u8,
+        image: image::DynamicImage,
+    ) -> Result<(), StreamDeckError> {
+        let image_data = images::convert_image(self.kind, image)?;
+        self.write_image(key, &image_data)
+    }
 }
 
 /// Errors that can occur while working with Stream Decks
@@ -427,6 +468,14 @@ pub enum StreamDeckError {
     /// Tokio join error
     JoinError(tokio::task::JoinError),
 
+    #[cfg(feature = "images")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "images")))]
+    /// Failed to encode image
+    ImageError(image::ImageError),
+
+    /// Image data isn't the size the device takes
+    InvalidImageSize,
+
     /// There's literally nowhere to write the image
     NoScreen,
 
@@ -441,6 +490,14 @@ pub enum StreamDeckError {
 
     /// Stream Deck sent unexpected data
     BadData,
+
+    /// A report from the Stream Deck ended before the data it should hold
+    ShortReport {
+        /// Length the report needed to be
+        needed: usize,
+        /// Length the report was
+        len: usize,
+    },
 }
 
 impl Display for StreamDeckError {
@@ -1,3 +1,4 @@
+use crate::mapping::KeyMap;
 use crate::{Kind, StreamDeckError, StreamDeckInput};
 use alloc::str::{from_utf8, Utf8Error};
 use crate::{HidDevice,HidError};
@@ -5,6 +6,7 @@ use crate::{HidDevice,HidError};
 use alloc::vec::Vec;
 use alloc::vec;
 use alloc::string::String;
+use core::ops::Range;
 
 /// Performs get_feature_report on [HidDevice]
 pub fn get_feature_report(
@@ -54,49 +56,68 @@ pub fn extract_str(bytes: &[u8]) -> Result<String, Utf8Error> {
     Ok(from_utf8(bytes)?.replace('\0', ""))
 }
 
-/// Flips key index horizontally, for use with Original v1 Stream Deck
-pub fn flip_key_index(kind: &Kind, key: u8) -> u8 {
-    let col = key % kind.column_count();
-    (key - col) + ((kind.column_count() - 1) - col)
+/// The bytes of a report in range, failing if the report ends before it.
+fn report_range(data: &[u8], range: Range<usize>) -> Result<&[u8], StreamDeckError> {
+    let needed = range.end;
+    data.get(range).ok_or(StreamDeckError::ShortReport {
+        needed,
+        len: data.len(),
+    })
 }
 
-/// Reads button states, empty vector if no data
+/// The byte of a report at index, failing if the report ends before it.
+fn report_byte(data: &[u8], index: usize) -> Result<u8, StreamDeckError> {
+    Ok(report_range(data, index..index + 1)?[0])
+}
+
+/// The little endian u16 of a report at index, failing if the report ends
+/// before it.
+fn report_u16(data: &[u8], index: usize) -> Result<u16, StreamDeckError> {
+    let bytes = report_range(data, index..index + 2)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+/// Reads button states, empty vector if no data.  Reports can be longer than
+/// the device has keys (the Plus shares its report size with other input), so
+/// only the states of actual keys are returned.  Keys past the end of a short
+/// report read as released.
 pub fn read_button_states(kind: &Kind, states: &[u8]) -> Vec<bool> {
-    if states[0] == 0 {
+    if states.first().copied().unwrap_or(0) == 0 {
         return vec![];
     }
+    let key_count = kind.key_count() as usize;
+    let pressed = |states: &[u8]| states.iter().take(key_count).map(|s| *s != 0).collect();
 
     match kind {
         Kind::Original => {
-            let mut bools = vec![];
-
-            for i in 0..kind.key_count() {
-                let flipped_i = flip_key_index(kind, i) as usize;
-
-                bools.push(states[flipped_i + 1] != 0);
-            }
-
-            bools
+            let map = KeyMap::new(*kind);
+            (0..kind.key_count())
+                .filter_map(|key| map.to_device(key))
+                .map(|index| states.get(usize::from(index) + 1).copied().unwrap_or(0) != 0)
+                .collect()
         }
 
-        Kind::Mini | Kind::MiniMk2 => states[1..].iter().map(|s| *s != 0).collect(),
+        Kind::Mini | Kind::MiniMk2 => pressed(states.get(1..).unwrap_or_default()),
 
-        _ => states[4..].iter().map(|s| *s != 0).collect(),
+        // The Pedal reports its three switches like the other v2 devices
+        Kind::OriginalV2 | Kind::Mk2 | Kind::Xl | Kind::XlV2 | Kind::Pedal | Kind::Plus => {
+            pressed(states.get(4..).unwrap_or_default())
+        }
     }
 }
 
 /// Reads lcd screen input
 pub fn read_lcd_input(data: &[u8]) -> Result<StreamDeckInput, StreamDeckError> {
-    let start_x = u16::from_le_bytes([data[6], data[7]]);
-    let start_y = u16::from_le_bytes([data[8], data[9]]);
+    let start_x = report_u16(data, 6)?;
+    let start_y = report_u16(data, 8)?;
 
-    match &data[4] {
+    match report_byte(data, 4)? {
         0x1 => Ok(StreamDeckInput::TouchScreenPress(start_x, start_y)),
         0x2 => Ok(StreamDeckInput::TouchScreenLongPress(start_x, start_y)),
 
         0x3 => {
-            let end_x = u16::from_le_bytes([data[10], data[11]]);
-            let end_y = u16::from_le_bytes([data[12], data[13]]);
+            let end_x = report_u16(data, 10)?;
+            let end_y = report_u16(data, 12)?;
 
             Ok(StreamDeckInput::TouchScreenSwipe(
                 (start_x, start_y),
@@ -110,16 +131,17 @@ pub fn read_lcd_input(data: &[u8]) -> Result<StreamDeckInput, StreamDeckError> {
 
 /// Reads encoder input
 pub fn read_encoder_input(kind: &Kind, data: &[u8]) -> Result<StreamDeckInput, StreamDeckError> {
-    match &data[4] {
+    let encoders = 5..5 + kind.encoder_count() as usize;
+    match report_byte(data, 4)? {
         0x0 => Ok(StreamDeckInput::EncoderStateChange(
-            data[5..5 + kind.encoder_count() as usize]
+            report_range(data, encoders)?
                 .iter()
                 .map(|s| *s != 0)
                 .collect(),
         )),
 
         0x1 => Ok(StreamDeckInput::EncoderTwist(
-            data[5..5 + kind.encoder_count() as usize]
+            report_range(data, encoders)?
                 .iter()
                 .map(|s| i8::from_le_bytes([*s]))
                 .collect(),
@@ -128,3 +150,42 @@ pub fn read_encoder_input(kind: &Kind, data: &[u8]) -> Result<StreamDeckInput, S
         _ => Err(StreamDeckError::BadData),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pedal_switches_read() {
+        // Three switches after the header, in a report padded past them
+        let mut report = vec![0u8; 4 + 32];
+        report[0] = 1;
+        report[4 + 1] = 1;
+        report[4 + 5] = 1;
+        assert_eq!(
+            read_button_states(&Kind::Pedal, &report),
+            [false, true, false]
+        );
+        report[0] = 0;
+        assert!(read_button_states(&Kind::Pedal, &report).is_empty());
+    }
+
+    #[test]
+    fn test_xl_keys_read() {
+        for kind in [Kind::Xl, Kind::XlV2] {
+            // Four rows of eight keys of 96 pixels
+            assert_eq!(kind.key_count(), 32);
+            assert_eq!((kind.row_count(), kind.column_count()), (4, 8));
+            assert_eq!(kind.key_image_format().size, (96, 96));
+
+            let mut report = vec![0u8; 4 + 32];
+            report[0] = 1;
+            report[4] = 1;
+            report[4 + 31] = 1;
+            let states = read_button_states(&kind, &report);
+            assert_eq!(states.len(), 32);
+            let pressed: Vec<_> = (0..32).filter(|key| states[*key]).collect();
+            assert_eq!(pressed, [0, 31], "{kind:?}");
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! Line oriented admin socket.
+//!
+//! Operators (or scripts) connect with something like `nc` and send one
+//! command per line.  Every command is answered with a single line starting
+//! with `OK` or `ERR`.
+//!
+//! ```text
+//! LIST
+//! GET <device_id>
+//! SET <device_id> brightness <0-100|none>
+//! SET <device_id> orientation <0|90|180|270>
+//! SET <device_id> fps <frames per second, at least 0.01|none>
+//! SET <device_id> coalesce <on|off>
+//! SET <device_id> zones <json array of zones>
+//! SET <device_id> faders <comma separated encoders|none>
+//! SET <device_id> repeat <json key repeat|none>
+//! SET <device_id> long_press <milliseconds|none>
+//! SET <device_id> brightness_policy <json brightness policy|none>
+//! SET <device_id> overlay <on|off>
+//! PRESS <device_id> <key>
+//! RELEASE <device_id> <key>
+//! TWIST <device_id> <encoder> <delta>
+//! STANDBY <device_id> <json array of KEY-STATE lines|none>
+//! COMPANION [<host:port>]
+//! KICK <device_id>
+//! ```
+//!
+//! PRESS, RELEASE and TWIST inject input as if it came from the device, which
+//! is how scripts are replayed by [play](crate::play).  rust_satellite takes
+//! them on an admin socket of its own too.
+//!
+//! STANDBY gives the images of pages Companion isn't showing yet, such as
+//! those either side of the current page, as the KEY-STATE lines Companion
+//! would send for them.  They are converted for the device in the background,
+//! so switching to one of those pages is near instant on a slow leaf.
+//! Companion doesn't tell satellites what is on its other pages, so a script
+//! that knows has to send them.
+//!
+//! COMPANION shows where devices are added to Companion and, given an
+//! address, repoints every connected leaf there.  The leaves stay connected
+//! while their devices are removed from the old Companion and added to the
+//! new one.
+//!
+//! KICK disconnects a leaf, removing its device from Companion with the
+//! reason `kicked` and blanking it.  The disconnect shows as intentional in
+//! the status of the device once it connects again.
+
+use std::sync::Arc;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+use traits::{anyhow, device::ShutdownReason, Result};
+
+use crate::state::Registry;
+
+/// The lowest FPS cap taken, a frame every 100 seconds.  Lower caps hold
+/// images back for so long they are more likely a typo.
+const MIN_FPS_CAP: f32 = 0.01;
+
+/// Accept admin connections forever.
+pub async fn serve(listener: TcpListener, registry: Arc<Registry>) -> Result<()> {
+    info!("Admin socket listening on {:?}", listener.local_addr()?);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            let res = handle_connection(stream, &registry).await;
+            debug!("Admin connection from {:?} closed: {:?}", peer, res);
+        });
+    }
+}
+
+async fn handle_connection(stream: TcpStream, registry: &Registry) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let response = match handle_command(line, registry) {
+            Ok(response) => format!("OK {response}\n"),
+            Err(e) => format!("ERR {e}\n"),
+        };
+        writer.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Execute a single admin command, returning the text of the response.
+fn handle_command(line: &str, registry: &Registry) -> Result<String> {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let mut arg = |name: &str| {
+        words
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Missing argument: {name}"))
+    };
+
+    match command.to_ascii_uppercase().as_str() {
+        "LIST" => Ok(serde_json::to_string(&registry.devices())?),
+        "GET" => Ok(serde_json::to_string(
+            &registry.settings(arg("device_id")?),
+        )?),
+        "SET" => {
+            let device_id = arg("device_id")?;
+            let setting = arg("setting")?;
+            let value = arg("value")?;
+            let settings = match setting {
+                "zones" => {
+                    // The zones are a JSON array, which may contain spaces, so
+                    // use the whole rest of the line.
+                    let value = line.find('[').map_or(value, |start| &line[start..]);
+                    let zones: Vec<traits::device::Zone> = serde_json::from_str(value)?;
+                    for zone in &zones {
+                        check_fps_cap(zone.fps_cap)?;
+                    }
+                    registry.update_settings(device_id, |s| s.zones = zones)?
+                }
+                "brightness" => {
+                    let brightness = parse_optional::<u8>(value)?;
+                    if brightness.is_some_and(|b| b > 100) {
+                        anyhow::bail!("Brightness must be between 0 and 100");
+                    }
+                    registry.update_settings(device_id, |s| s.brightness = brightness)?
+                }
+                "orientation" => {
+                    let orientation = value.parse()?;
+                    registry.update_settings(device_id, |s| s.orientation = orientation)?
+                }
+                "fps" => {
+                    let fps_cap = parse_optional::<f32>(value)?;
+                    check_fps_cap(fps_cap)?;
+                    registry.update_settings(device_id, |s| s.fps_cap = fps_cap)?
+                }
+                "coalesce" => {
+                    let coalesce = parse_switch("Coalesce", value)?;
+                    registry.update_settings(device_id, |s| s.coalesce = coalesce)?
+                }
+                "faders" => {
+                    let faders = if value.eq_ignore_ascii_case("none") {
+                        Vec::new()
+                    } else {
+                        value
+                            .split(',')
+                            .map(str::parse)
+                            .collect::<std::result::Result<_, _>>()?
+                    };
+                    registry.update_settings(device_id, |s| s.faders = faders)?
+                }
+                "repeat" => {
+                    // Like zones, the JSON may contain spaces
+                    let value = line.find('{').map_or(value, |start| &line[start..]);
+                    let key_repeat = if value.eq_ignore_ascii_case("none") {
+                        None
+                    } else {
+                        Some(serde_json::from_str(value)?)
+                    };
+                    registry.update_settings(device_id, |s| s.key_repeat = key_repeat)?
+                }
+                "long_press" => {
+                    let long_press_ms = parse_optional::<u32>(value)?;
+                    registry.update_settings(device_id, |s| s.long_press_ms = long_press_ms)?
+                }
+                "brightness_policy" => {
+                    // Like zones, the JSON may contain spaces
+                    let value = line.find('{').map_or(value, |start| &line[start..]);
+                    let policy = if value.eq_ignore_ascii_case("none") {
+                        Default::default()
+                    } else {
+                        serde_json::from_str(value)?
+                    };
+                    registry.update_settings(device_id, |s| s.brightness_policy = policy)?
+                }
+                "overlay" => {
+                    let key_overlay = parse_switch("Overlay", value)?;
+                    registry.update_settings(device_id, |s| s.key_overlay = key_overlay)?
+                }
+                _ => anyhow::bail!("Unknown setting {setting}"),
+            };
+            Ok(serde_json::to_string(&settings)?)
+        }
+        "PRESS" | "RELEASE" | "TWIST" => {
+            let (device_id, input) = pumps::inject::parse_input(line)?;
+            registry.inject(&device_id, input)?;
+            Ok(String::from("{}"))
+        }
+        "STANDBY" => {
+            let device_id = arg("device_id")?;
+            let value = arg("lines")?;
+            // The lines contain spaces
+            let value = line.find('[').map_or(value, |start| &line[start..]);
+            let lines = if value.eq_ignore_ascii_case("none") {
+                Vec::new()
+            } else {
+                serde_json::from_str(value)?
+            };
+            registry.set_standby(device_id, lines)?;
+            Ok(String::from("{}"))
+        }
+        "COMPANION" => {
+            let endpoint = registry
+                .companion_endpoint()
+                .ok_or_else(|| anyhow::anyhow!("Companion can't be repointed"))?;
+            if let Some(addr) = words.next() {
+                let port = addr.rsplit_once(':').map(|(_, port)| port.parse::<u16>());
+                if !matches!(port, Some(Ok(_))) {
+                    anyhow::bail!("Companion must be given as host:port, got {addr}");
+                }
+                endpoint.set(addr);
+            }
+            Ok(serde_json::json!({ "companion": endpoint.get() }).to_string())
+        }
+        "KICK" => {
+            registry.disconnect(arg("device_id")?, ShutdownReason::Kicked)?;
+            Ok(String::from("{}"))
+        }
+        _ => anyhow::bail!("Unknown command {command}"),
+    }
+}
+
+/// Parse a value that can be cleared with `none`.
+fn parse_optional<T>(value: &str) -> Result<Option<T>>
+where
+    T: std::str::FromStr,
+{
+    if value.eq_ignore_ascii_case("none") {
+        return Ok(None);
+    }
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| anyhow::anyhow!("Invalid value {value}"))
+}
+
+/// Refuse FPS caps that are not a number of frames a second worth capping to.
+fn check_fps_cap(fps_cap: Option<f32>) -> Result<()> {
+    match fps_cap {
+        Some(fps) if !fps.is_finite() || fps < MIN_FPS_CAP => {
+            anyhow::bail!("FPS cap must be at least {MIN_FPS_CAP}, got {fps}")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Parse the on or off of the setting called name.
+fn parse_switch(name: &str, value: &str) -> Result<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => anyhow::bail!("{name} must be on or off, got {value}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fps_caps_below_the_minimum_refused() {
+        let registry = Registry::default();
+        for fps in ["NaN", "inf", "-1", "0", "0.001", "1e-30"] {
+            let error = handle_command(&format!("SET deck fps {fps}"), &registry).unwrap_err();
+            assert!(error.to_string().contains("FPS cap"), "{fps}: {error}");
+        }
+        let zones = r#"SET deck zones [{"name": "row", "keys": [0], "fps_cap": 0.0}]"#;
+        assert!(handle_command(zones, &registry).is_err());
+        assert_eq!(registry.settings("deck").fps_cap, None);
+
+        handle_command("SET deck fps 0.5", &registry).unwrap();
+        assert_eq!(registry.settings("deck").fps_cap, Some(0.5));
+        handle_command("SET deck fps none", &registry).unwrap();
+        assert_eq!(registry.settings("deck").fps_cap, None);
+    }
+}
@@ -0,0 +1,25 @@
+//! Replay a script of button presses and encoder twists into a running
+//! gateway or rust_satellite through its admin socket.
+
+use clap::Parser;
+use gateway::{play::Script, Result};
+
+/// Command line arguments for satellite-play
+#[derive(Parser)]
+struct Args {
+    /// The script to play
+    script: std::path::PathBuf,
+    /// Address of the admin socket of the gateway or satellite, e.g.
+    /// 127.0.0.1:9999
+    #[arg(long)]
+    admin: String,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let args = Args::parse();
+    let script = Script::parse(&std::fs::read_to_string(&args.script)?)?;
+    script.play(&args.admin).await
+}
@@ -0,0 +1,135 @@
+//! Sizing of the memory the gateway holds on to.
+//!
+//! The defaults of the gateway are sized for [REFERENCE_BYTES] of memory to
+//! itself.  Given a smaller [MemoryBudget], the caches, queues and buffer
+//! pools are all shrunk in proportion, down to a floor each still works
+//! with, so a gateway on a 256MB single board computer shares it with the
+//! rest of the system.  A low budget also trades latency for memory: images
+//! are converted by fewer workers and input to Companion is batched.
+//!
+//! Sizes given on the command line are taken as they are, the budget only
+//! sizes what is left at its default.
+
+use std::num::NonZeroUsize;
+use std::time::Duration;
+
+use companion::cache::DEFAULT_MAX_BYTES;
+use companion::images::MAX_POOLED;
+use companion::receiver::{MAX_PENDING, READ_BUFFER_SIZE};
+
+use crate::events::EVENT_CAPACITY;
+
+/// Memory the defaults are sized for.
+pub const REFERENCE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Input is batched for this long when the budget is low.
+const LOW_BUDGET_INPUT_BATCH: Duration = Duration::from_millis(20);
+
+/// How much memory the gateway may hold on to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryBudget {
+    /// Bytes of the budget, or None for the defaults
+    bytes: Option<usize>,
+}
+
+impl MemoryBudget {
+    /// A budget of bytes.
+    pub fn new(bytes: usize) -> Self {
+        Self { bytes: Some(bytes) }
+    }
+
+    /// A budget of megabytes, if given.
+    pub fn from_mb(mb: Option<usize>) -> Self {
+        Self {
+            bytes: mb.map(|mb| mb.saturating_mul(1024 * 1024)),
+        }
+    }
+
+    /// The share of [REFERENCE_BYTES] the budget is, at most all of it.
+    fn scale(&self) -> f64 {
+        self.bytes.map_or(1.0, |bytes| {
+            (bytes as f64 / REFERENCE_BYTES as f64).min(1.0)
+        })
+    }
+
+    /// full, scaled to the budget but no smaller than floor.
+    fn scaled(&self, full: usize, floor: usize) -> usize {
+        ((full as f64 * self.scale()) as usize).max(floor)
+    }
+
+    /// Whether the budget is low enough to trade latency for memory.
+    pub fn is_low(&self) -> bool {
+        self.scale() < 0.5
+    }
+
+    /// Bytes of converted images cached, shared by every device.
+    pub fn image_cache_bytes(&self) -> usize {
+        self.scaled(DEFAULT_MAX_BYTES, 1024 * 1024)
+    }
+
+    /// Bytes of the buffer each device reads from Companion through.
+    pub fn read_buffer_bytes(&self) -> usize {
+        self.scaled(READ_BUFFER_SIZE, 16 * 1024)
+    }
+
+    /// Actions queued for each device before reading from Companion stops.
+    pub fn pending_actions(&self) -> NonZeroUsize {
+        NonZeroUsize::new(self.scaled(MAX_PENDING, 16)).unwrap_or(NonZeroUsize::MIN)
+    }
+
+    /// Buffers kept for converting the images of each device.
+    pub fn pooled_buffers(&self) -> usize {
+        self.scaled(MAX_POOLED, 2)
+    }
+
+    /// Images of a device converted at once, or None for one per core.
+    pub fn image_workers(&self) -> Option<NonZeroUsize> {
+        self.bytes?;
+        let cores = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        NonZeroUsize::new(self.scaled(cores, 1))
+    }
+
+    /// Events kept for subscribers of the registry that are slow to take
+    /// them.
+    pub fn event_capacity(&self) -> usize {
+        self.scaled(EVENT_CAPACITY, 8)
+    }
+
+    /// How long input is held to be written to Companion in batches, if it
+    /// is.
+    pub fn input_batch(&self) -> Option<Duration> {
+        self.is_low().then_some(LOW_BUDGET_INPUT_BATCH)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sizes_follow_budget() {
+        let unbounded = MemoryBudget::default();
+        assert_eq!(unbounded.image_cache_bytes(), DEFAULT_MAX_BYTES);
+        assert_eq!(unbounded.read_buffer_bytes(), READ_BUFFER_SIZE);
+        assert_eq!(unbounded.image_workers(), None);
+        assert_eq!(unbounded.input_batch(), None);
+        // More than the reference changes nothing
+        assert_eq!(
+            MemoryBudget::from_mb(Some(1024)).image_cache_bytes(),
+            DEFAULT_MAX_BYTES
+        );
+
+        let quarter = MemoryBudget::new(REFERENCE_BYTES / 4);
+        assert_eq!(quarter.image_cache_bytes(), DEFAULT_MAX_BYTES / 4);
+        assert_eq!(quarter.pooled_buffers(), MAX_POOLED / 4);
+        assert_eq!(quarter.input_batch(), Some(LOW_BUDGET_INPUT_BATCH));
+
+        // Nothing shrinks to where it stops working
+        let tiny = MemoryBudget::new(1024);
+        assert_eq!(tiny.image_cache_bytes(), 1024 * 1024);
+        assert_eq!(tiny.read_buffer_bytes(), 16 * 1024);
+        assert_eq!(tiny.image_workers(), NonZeroUsize::new(1));
+        assert_eq!(tiny.event_capacity(), 8);
+        assert_eq!(tiny.pending_actions().get(), 16);
+    }
+}
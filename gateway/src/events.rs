@@ -0,0 +1,83 @@
+//! Events published by the [Registry](crate::state::Registry).
+//!
+//! Embedders (and the `/events` stream of the [status](crate::status)
+//! endpoint) subscribe to the registry to hear about leaves and Companion
+//! connections coming and going, instead of polling the status endpoint.
+//!
+//! Subscribers that fall behind miss the oldest events; the registry itself
+//! can always be queried for the current state.
+
+use serde::{Deserialize, Serialize};
+use traits::device::{Fingerprint, ShutdownReason};
+
+/// Number of events kept for subscribers that are slow to receive them.
+pub(crate) const EVENT_CAPACITY: usize = 64;
+
+/// Why the connection of a leaf closed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Disconnect {
+    /// The reason either end gave for closing it, or what went wrong
+    pub reason: String,
+    /// Whether it was closed on purpose, rather than crashing or being lost
+    pub intentional: bool,
+}
+
+impl Disconnect {
+    /// Closed on purpose by either end, for reason.
+    pub fn intentional(reason: ShutdownReason) -> Self {
+        Self {
+            reason: reason.to_string(),
+            intentional: true,
+        }
+    }
+
+    /// Closed without saying why, error being what went wrong.
+    pub fn lost(error: impl ToString) -> Self {
+        Self {
+            reason: error.to_string(),
+            intentional: false,
+        }
+    }
+}
+
+/// Something that happened to a device of the gateway.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RegistryEvent {
+    /// A leaf connected and was registered
+    LeafConnected {
+        /// The unique device id reported by the leaf
+        device_id: String,
+        /// The hardware product id of the device
+        pid: u16,
+        /// The capabilities of the hardware behind the device id
+        fingerprint: Fingerprint,
+    },
+    /// The connection of a leaf closed
+    LeafDisconnected {
+        /// The unique device id reported by the leaf
+        device_id: String,
+        /// Why it closed
+        #[serde(flatten)]
+        disconnect: Disconnect,
+    },
+    /// The device was added to Companion
+    CompanionUp {
+        /// The unique device id reported by the leaf
+        device_id: String,
+    },
+    /// The connection to Companion serving the device closed
+    CompanionDown {
+        /// The unique device id reported by the leaf
+        device_id: String,
+        /// Why the connection closed
+        reason: String,
+    },
+    /// A leaf connected but couldn't be served
+    RegistrationFailed {
+        /// The device id, if the leaf got as far as sending it
+        device_id: Option<String>,
+        /// What went wrong
+        error: String,
+    },
+}
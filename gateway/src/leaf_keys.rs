@@ -0,0 +1,47 @@
+//! Pre-shared keys leaves authenticate with.
+//!
+//! Keys are read from a TOML file mapping device ids to their keys:
+//!
+//! ```toml
+//! "CL12345" = "a long random secret"
+//! "desk-left" = "another long random secret"
+//! ```
+//!
+//! A leaf connecting as a device id that isn't in the file is turned away.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use tracing::info;
+use traits::Result;
+
+/// The key of every device allowed to connect.
+#[derive(Default, PartialEq)]
+pub struct LeafKeys {
+    keys: BTreeMap<String, String>,
+}
+
+impl LeafKeys {
+    /// Load the keys from the file at path.  With no path, no device has a
+    /// key.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let keys = match path {
+            Some(path) => {
+                info!("Loading leaf keys from {}", path.display());
+                toml::from_str(&std::fs::read_to_string(path)?)?
+            }
+            None => BTreeMap::new(),
+        };
+        Ok(Self { keys })
+    }
+
+    /// The key of a device, if it has one.
+    pub fn key(&self, device_id: &str) -> Option<&[u8]> {
+        self.keys.get(device_id).map(|key| key.as_bytes())
+    }
+
+    /// Whether no device has a key.
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
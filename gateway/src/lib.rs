@@ -17,16 +17,65 @@
 
 pub use traits::Result;
 use clap::Parser;
+use budget::MemoryBudget;
+use companion::sender::{FlushPolicy, SenderConfig};
+use pumps::bandwidth::BandwidthPolicy;
+use pumps::low_impact;
+use pumps::power::PowerPolicy;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Line oriented admin socket
+pub mod admin;
+/// Sizing of the memory the gateway holds on to
+pub mod budget;
+/// Events published by the registry
+pub mod events;
+/// Pre-shared keys of leaves
+pub mod leaf_keys;
+/// Prometheus metrics of the gateway
+#[cfg(feature = "metrics")]
+#[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+pub mod metrics;
+/// Keeping the state of the registry across restarts
+pub mod persist;
+/// Scripted input replay
+pub mod play;
+/// Applying changes to the config file without restarting
+pub mod reload;
+/// Replication of the registry to a secondary gateway
+pub mod replication;
+/// Persistent per-device runtime settings
+pub mod settings;
+/// Shared state of the gateway
+pub mod state;
+/// HTTP status endpoint
+pub mod status;
 
 /// The command line arguments for the gateway
 #[derive(Parser)]
+#[command(args_override_self = true)]
 pub struct Cli {
-    /// The host to connect to for the companion app
+    /// TOML file of options, keyed by their long names, for the command
+    /// line to override.  Changes to the power policy, starved_kbps,
+    /// log_filter and leaf_keys are applied without restarting.  Device
+    /// settings changed at runtime are written to its `[device_settings]`.
     #[arg(long)]
-    pub companion_host: String,
+    pub config: Option<PathBuf>,
+    /// The host to connect to for the companion app
+    #[arg(long, required_unless_present = "front_end")]
+    pub companion_host: Option<String>,
     /// The port to connect to for the companion app
-    #[arg(short, long)]
-    pub companion_port: u16,
+    #[arg(short, long, required_unless_present = "front_end")]
+    pub companion_port: Option<u16>,
+    /// host:port of a front end to add devices to Companion through, in place
+    /// of a connection to Companion of this gateway's own
+    #[arg(long, conflicts_with_all = ["companion_host", "companion_port"])]
+    pub front_end: Option<String>,
+    /// Port to take other gateways on as their front end, sharing the
+    /// connection to Companion with them.  Disabled if not provided.
+    #[arg(long)]
+    pub front_end_port: Option<u16>,
     /// The port to listen on for leaf satellite connections
     #[arg(long)]
     pub listen_port: u16,
@@ -34,4 +83,208 @@ pub struct Cli {
     #[arg(long)]
     #[clap(default_value = "0.0.0.0")]
     pub listen_address: String,
+    /// Port to serve the JSON status endpoint on.  Disabled if not provided.
+    #[arg(long)]
+    pub status_port: Option<u16>,
+    /// Port to accept admin commands on.  Disabled if not provided.
+    #[arg(long)]
+    pub admin_port: Option<u16>,
+    /// Address to listen on for admin connections
+    #[arg(long)]
+    #[clap(default_value = "127.0.0.1")]
+    pub admin_address: String,
+    /// TOML file of the pre-shared key of each device id allowed to connect.
+    /// Leaves naming a device without a key are turned away.
+    #[arg(long)]
+    pub leaf_keys: Option<PathBuf>,
+    /// Take leaves without challenging them for a key, as gateways did
+    /// before leaves authenticated
+    #[arg(long, conflicts_with = "leaf_keys")]
+    pub allow_unauthenticated_leaves: bool,
+    /// JSON file to keep the state of leaves in across restarts: their
+    /// brightness, faders, key images and connection history.  Only kept in
+    /// memory if not provided.
+    #[arg(long)]
+    pub state_file: Option<PathBuf>,
+    /// sled database to keep the state of leaves in, in place of a state
+    /// file
+    #[cfg(feature = "sled")]
+    #[arg(long, conflicts_with = "state_file")]
+    pub state_db: Option<PathBuf>,
+    /// Megabytes of memory the gateway may hold on to, shrinking its caches,
+    /// queues and buffers to fit.  Sized for 64MB if not provided.
+    #[arg(long)]
+    pub memory_budget_mb: Option<usize>,
+    /// Bytes of converted images cached, shared by every device.  Defaults
+    /// to 16MB, or less with a small memory budget.
+    #[arg(long)]
+    pub image_cache_bytes: Option<usize>,
+    /// Drop cached images after this many seconds.  Kept until evicted if
+    /// not provided.
+    #[arg(long)]
+    pub image_cache_ttl: Option<u64>,
+    /// Directory to keep converted images in across restarts.  Only kept in
+    /// memory if not provided.
+    #[arg(long)]
+    pub image_cache_dir: Option<PathBuf>,
+    /// Images of a device converted at once.  Defaults to the number of
+    /// cores, or fewer with a small memory budget or one with --low-impact.
+    #[arg(long)]
+    pub image_workers: Option<std::num::NonZeroUsize>,
+    /// Take as little CPU as the gateway can run on, for a host busy with
+    /// something that matters more, such as streaming a show.  Images are
+    /// converted one at a time and written to leaves paced, and Companion is
+    /// pinged less often, at the cost of latency.
+    #[arg(long)]
+    pub low_impact: bool,
+    /// Font file to draw the text of keys in, for labels that aren't ASCII.
+    /// Repeat for fallbacks, such as a CJK or emoji font, each drawing the
+    /// characters the fonts before it lack.  Keys are drawn in a built in
+    /// ASCII font if not provided.
+    #[arg(long = "font")]
+    pub fonts: Vec<PathBuf>,
+    /// Ask Companion for the color and text of keys rather than images of
+    /// them, drawing keys here.  Needs satellite API 2.0 or newer, falling
+    /// back to images with older Companions.
+    #[arg(long)]
+    pub render_keys: bool,
+    /// Experimental: accept video frames for the LCD strip through the status
+    /// endpoint.
+    #[arg(long)]
+    pub lcd_video: bool,
+    /// Maximum frames per second streamed to the LCD strip
+    #[arg(long)]
+    #[clap(default_value = "15")]
+    pub lcd_video_fps: f32,
+    /// Give up on the Companion connection of a device after this many
+    /// milliseconds without a PONG
+    #[arg(long)]
+    #[clap(default_value = "5000")]
+    pub pong_timeout_ms: u64,
+    /// Milliseconds between PINGs to Companion
+    #[arg(long)]
+    #[clap(default_value = "10")]
+    pub ping_interval_ms: u64,
+    /// Fail on any line from Companion that strays from the grammar of the
+    /// satellite API rather than passing over commands it doesn't know, for
+    /// testing against new builds of Companion
+    #[arg(long)]
+    pub strict_companion: bool,
+    /// Hold input for up to this many milliseconds so it is written to
+    /// Companion in batches.  Written immediately if not provided, unless
+    /// the memory budget is low.
+    #[arg(long)]
+    pub input_batch_ms: Option<u64>,
+    /// Maximum images per second sent to a leaf running on its battery
+    #[arg(long)]
+    #[clap(default_value = "15")]
+    pub battery_fps_cap: f32,
+    /// Maximum images per second sent to a leaf low on battery
+    #[arg(long)]
+    #[clap(default_value = "5")]
+    pub low_battery_fps_cap: f32,
+    /// Highest brightness of a leaf low on battery
+    #[arg(long)]
+    #[clap(default_value = "20")]
+    pub low_battery_brightness: u8,
+    /// Draw the keys of a leaf from their color and text rather than their
+    /// images while it takes images slower than this many kilobytes per
+    /// second, going back to images once it takes them at twice the rate.
+    /// Needs satellite API 2.0 or newer.  Leaves always get images if not
+    /// provided.
+    #[arg(long)]
+    pub starved_kbps: Option<u64>,
+    /// Port to accept secondary gateways on, replicating the registry to
+    /// them.  Needs a cluster token.
+    #[arg(long, requires = "cluster_token")]
+    pub replication_port: Option<u16>,
+    /// host:port of the replication port of the primary gateway, making this
+    /// a secondary that takes over the leaves of the primary.  Needs a
+    /// cluster token.
+    #[arg(long, requires = "cluster_token")]
+    pub replicate_from: Option<String>,
+    /// Token shared by the gateways of a cluster, proving a secondary may
+    /// follow the primary
+    #[arg(long)]
+    pub cluster_token: Option<String>,
+    /// Record a transcript of the traffic of every leaf to this file, as
+    /// JSON lines with images reduced to their size and hash, to attach to
+    /// bug reports
+    #[arg(long)]
+    pub transcript: Option<PathBuf>,
+    /// Write logs as JSON lines, for log collectors to ingest, rather than
+    /// as text
+    #[arg(long)]
+    pub log_json: bool,
+    /// Which logs are written, as RUST_LOG takes them, such as
+    /// "info,pumps=debug".  Taken from RUST_LOG if not provided.
+    #[arg(long)]
+    pub log_filter: Option<String>,
+    /// Name to advertise the gateway under over mDNS, so leaves can find it
+    /// without being told its address
+    #[cfg(feature = "discovery")]
+    #[arg(long)]
+    pub advertise_as: Option<String>,
+}
+
+impl Cli {
+    /// The memory the gateway may hold on to.
+    pub fn memory_budget(&self) -> MemoryBudget {
+        MemoryBudget::from_mb(self.memory_budget_mb)
+    }
+
+    /// Bytes of converted images to cache.
+    pub fn image_cache_bytes(&self) -> usize {
+        self.image_cache_bytes
+            .unwrap_or_else(|| self.memory_budget().image_cache_bytes())
+    }
+
+    /// Images of a device to convert at once, or None for one per core.
+    pub fn image_workers(&self) -> Option<std::num::NonZeroUsize> {
+        self.image_workers
+            .or_else(|| self.low_impact.then_some(low_impact::IMAGE_WORKERS))
+            .or_else(|| self.memory_budget().image_workers())
+    }
+
+    /// Least time between the images written to a leaf, zero if they
+    /// aren't paced.
+    pub fn write_interval(&self) -> Duration {
+        if self.low_impact {
+            low_impact::WRITE_INTERVAL
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    /// How the Companion sender should ping and flush.
+    pub fn sender_config(&self) -> SenderConfig {
+        let input_batch = self.input_batch_ms.map(Duration::from_millis);
+        let mut ping_interval = Duration::from_millis(self.ping_interval_ms);
+        if self.low_impact {
+            ping_interval = ping_interval.max(low_impact::PING_INTERVAL);
+        }
+        SenderConfig {
+            ping_interval,
+            flush_policy: match input_batch.or_else(|| self.memory_budget().input_batch()) {
+                Some(window) => FlushPolicy::Batched(window),
+                None => FlushPolicy::Immediate,
+            },
+            ..Default::default()
+        }
+    }
+
+    /// How leaves running on a battery save power.
+    pub fn power_policy(&self) -> PowerPolicy {
+        PowerPolicy {
+            battery_fps_cap: Some(self.battery_fps_cap),
+            low_battery_fps_cap: self.low_battery_fps_cap,
+            low_battery_brightness: self.low_battery_brightness,
+        }
+    }
+
+    /// When leaves are starved of bandwidth, if they ever are.
+    pub fn bandwidth_policy(&self) -> Option<BandwidthPolicy> {
+        self.starved_kbps
+            .map(|kbps| BandwidthPolicy::new(kbps.saturating_mul(1024)))
+    }
 }
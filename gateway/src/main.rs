@@ -1,68 +1,714 @@
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::time::Duration;
+
 use clap::Parser;
+use companion::cache::ImageCache;
+use companion::disk_cache::DiskCache;
+use companion::fonts::Fonts;
+use companion::images::{ConversionTimes, ImageConverter};
+use companion::liveness::Liveness;
+use companion::mux::Multiplexer;
+use companion::version::Features;
 use elgato_streamdeck::info::Kind;
-use gateway::{Cli, Result};
-use tracing::{debug, info};
-use traits::device::{Receiver, RemoteConfig};
+use gateway::{
+    events::{Disconnect, RegistryEvent},
+    leaf_keys::LeafKeys,
+    reload::{ConfigUpdate, RuntimeConfig},
+    settings::SettingsStore,
+    state::Registry,
+    Cli, Result,
+};
+use gateway_devices::Sealing;
+use pumps::endpoint::{shutdown_when_repointed, CompanionEndpoint};
+use pumps::fader::Faders;
+use pumps::power::PowerPolicy;
+use pumps::reconnect::DeviceSide;
+use pumps::settings::KeyTransformSender;
+use pumps::shutdown::{BlankingSender, ShutdownHandle};
+use pumps::stats::PumpStats;
+use pumps::transcript::{Transcript, TranscriptReceiver, TranscriptSender};
+use pumps::video::LcdFrame;
+use tokio::io::DuplexStream;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tracing::{debug, info, info_span, warn, Instrument, Span};
+use traits::device::{Command, DeviceSettings, PowerState, RemoteConfig, ShutdownReason};
 use traits::anyhow;
 
+/// Where lines for Companion go, either Companion itself or a front end.
+type CompanionWriter = Box<dyn tokio::io::AsyncWrite + Unpin + Send>;
+
+/// The Companion connection shared by every leaf, once there is one.
+struct CompanionSlot {
+    /// Where Companion (or the front end) is, changed to repoint the leaves
+    endpoint: CompanionEndpoint,
+    /// The connection, and the address it was made to
+    mux: tokio::sync::Mutex<Option<(String, Arc<Multiplexer<CompanionWriter>>)>>,
+}
+
+/// How long leaves are given to close Companion and blank themselves when
+/// shutting down.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// How long a leaf is given to answer the key challenge.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-
-    let args = Cli::parse();
+    let args = Cli::parse_from(traits::config::args("gateway", std::env::args_os())?);
+    let log = gateway::reload::init_logging(args.log_json, args.log_filter.as_deref())?;
 
     // Create an async tcp listener
-    let listener = tokio::net::TcpListener::bind((args.listen_address, args.listen_port)).await?;
+    let listener = tokio::net::TcpListener::bind((args.listen_address.as_str(), args.listen_port)).await?;
     info!("Listening on port {}", args.listen_port);
+    #[cfg(feature = "discovery")]
+    let _advertisement = args
+        .advertise_as
+        .as_deref()
+        .map(|name| pumps::discovery::advertise_gateway(name, args.listen_port))
+        .transpose()?;
+
+    let budget = args.memory_budget();
+    if let Some(mb) = args.memory_budget_mb {
+        info!("Sizing caches and buffers for {}MB of memory", mb);
+    }
+    let mut image_cache = ImageCache::new(
+        args.image_cache_bytes(),
+        args.image_cache_ttl.map(Duration::from_secs),
+    );
+    if let Some(dir) = &args.image_cache_dir {
+        image_cache = image_cache.with_disk(DiskCache::new(dir)?);
+    }
+    let endpoint = CompanionEndpoint::new(
+        match (&args.front_end, &args.companion_host, args.companion_port) {
+            (Some(front_end), _, _) => front_end.clone(),
+            (None, Some(host), Some(port)) => format!("{host}:{port}"),
+            // clap requires the address of Companion without a front end
+            _ => unreachable!("companion address is required"),
+        },
+    );
+    let registry = Registry::new(SettingsStore::load(args.config.clone())?);
+    let registry = match args.fonts.as_slice() {
+        [] => registry,
+        fonts => registry.with_fonts(Arc::new(Fonts::load(fonts)?)),
+    };
+    let registry = Arc::new(
+        registry
+            .with_image_cache(image_cache)
+            .with_event_capacity(budget.event_capacity())
+            .with_companion_endpoint(endpoint.clone())
+            .with_transcript(match &args.transcript {
+                Some(path) => Transcript::create(path)?,
+                None => Transcript::default(),
+            }),
+    );
+    let state_store = gateway::persist::open(&args)?;
+    if let Some(store) = &state_store {
+        if let Some(state) = store.load()? {
+            registry.restore_persisted(state)?;
+        }
+        tokio::spawn(gateway::persist::keep(store.clone(), registry.clone()));
+    }
+    if let Some(status_port) = args.status_port {
+        let status_listener =
+            tokio::net::TcpListener::bind((args.listen_address.as_str(), status_port)).await?;
+        tokio::spawn(gateway::status::serve(
+            status_listener,
+            registry.clone(),
+            args.lcd_video,
+        ));
+    }
+    if let (Some(replication_port), Some(token)) = (args.replication_port, &args.cluster_token) {
+        let replication_listener =
+            tokio::net::TcpListener::bind((args.listen_address.as_str(), replication_port)).await?;
+        tokio::spawn(gateway::replication::serve(
+            replication_listener,
+            registry.clone(),
+            token.clone(),
+        ));
+    }
+    if let (Some(primary), Some(token)) = (&args.replicate_from, &args.cluster_token) {
+        info!("Following the primary gateway at {}", primary);
+        tokio::spawn(gateway::replication::follow(
+            primary.clone(),
+            registry.clone(),
+            token.clone(),
+        ));
+    }
+    if let Some(admin_port) = args.admin_port {
+        let admin_listener =
+            tokio::net::TcpListener::bind((args.admin_address.as_str(), admin_port)).await?;
+        tokio::spawn(gateway::admin::serve(admin_listener, registry.clone()));
+    }
+
+    // Leaves are challenged for a key unless explicitly allowed not to be
+    if args.allow_unauthenticated_leaves {
+        warn!("Taking leaves without authenticating them");
+    }
+    let runtime = Arc::new(RuntimeConfig::new(
+        ConfigUpdate::from_cli(&args)?,
+        Some(log),
+    ));
+    if let Some(path) = args.config.clone() {
+        let (runtime, registry) = (runtime.clone(), registry.clone());
+        tokio::spawn(async move {
+            if let Err(e) = gateway::reload::watch(path, runtime, registry).await {
+                warn!("Changes to the config file need a restart: {:#}", e);
+            }
+        });
+    }
 
+    let args = Arc::new(args);
+    let companion = Arc::new(CompanionSlot {
+        endpoint,
+        mux: Default::default(),
+    });
+    if let Some(front_end_port) = args.front_end_port {
+        let front_end_listener =
+            tokio::net::TcpListener::bind((args.listen_address.as_str(), front_end_port)).await?;
+        tokio::spawn(serve_front_end(
+            front_end_listener,
+            args.clone(),
+            companion.clone(),
+        ));
+    }
+    let shutdown = ShutdownHandle::new().on_ctrl_c();
+    let mut leaves = tokio::task::JoinSet::new();
     loop {
         // Wait for a connection
-        let (stream, _) = listener.accept().await?;
-        info!(
-            "Satellite Connection established from: {:?}",
-            stream.peer_addr()
+        let (mut stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            Some(_) = leaves.join_next() => continue,
+            _ = shutdown.wait() => break,
+        };
+        // Everything logged for the leaf carries where it connected from,
+        // and once it sent its config, which device it is
+        let span = info_span!(
+            "leaf",
+            %peer,
+            device_id = tracing::field::Empty,
+            kind = tracing::field::Empty
         );
+        span.in_scope(|| info!("Satellite Connection established"));
 
-        let (device_sender, mut device_receiver) =
-            gateway_devices::device_from_socket(stream).await?;
-
-        // Read the first message from the satellite to get the config
-        let config_msg = device_receiver.receive().await?;
-        let config_msg = match config_msg {
-            traits::device::Command::Config(c) => RemoteConfig {
-                pid: c.pid.try_into()?,
-                device_id: c.device_id,
-            },
-            _ => anyhow::bail!("Expected config msg to be first")
+        // Spawn off a task to handle the connection
+        let args = args.clone();
+        let registry = registry.clone();
+        let companion = companion.clone();
+        let shutdown = shutdown.clone();
+        let runtime = runtime.clone();
+        let leaf = async move {
+            let res = async {
+                let authenticated = match runtime.leaf_keys() {
+                    Some(keys) => Some(
+                        authenticate(&mut stream, &keys)
+                            .await
+                            .map_err(|e| registration_failed(&registry, None, e))?,
+                    ),
+                    None => None,
+                };
+                serve_leaf(
+                    stream,
+                    authenticated,
+                    &args,
+                    &runtime,
+                    &registry,
+                    &companion,
+                    &shutdown,
+                )
+                .await
+            }
+            .await;
+            info!("Connection closed: {:?}", res);
         };
-        debug!("Received config: {:?}", config_msg);
+        leaves.spawn(leaf.instrument(span));
+    }
 
-        info!(
-            "Connecting to companion app: {}:{}",
-            args.companion_host.as_str(),
-            args.companion_port
-        );
-        let (companion_reader, companion_writer) =
-            tokio::net::TcpStream::connect((args.companion_host.as_str(), args.companion_port))
-                .await?
-                .into_split();
+    // Leaves still being set up don't watch for the shutdown, so they are
+    // only waited on for a while
+    let closed = async { while leaves.join_next().await.is_some() {} };
+    if tokio::time::timeout(SHUTDOWN_GRACE, closed).await.is_err() {
+        warn!("{} leaves didn't close in time", leaves.len());
+    }
+    if let Some(store) = &state_store {
+        gateway::persist::save_changes(&**store, &registry, &mut String::new());
+    }
+    Ok(())
+}
 
-        let kind = Kind::from_pid(config_msg.pid)
-            .ok_or_else(|| anyhow::anyhow!("Unknown pid {}", config_msg.pid))?;
+/// Tell subscribers a leaf couldn't be served, passing the error on.
+fn registration_failed(
+    registry: &Registry,
+    device_id: Option<&str>,
+    error: anyhow::Error,
+) -> anyhow::Error {
+    registry.publish(RegistryEvent::RegistrationFailed {
+        device_id: device_id.map(str::to_string),
+        error: error.to_string(),
+    });
+    error
+}
 
-        let companion_receiver = companion::receiver::Receiver::new(companion_reader, kind);
-        let companion_sender = companion::sender::Sender::new(companion_writer, config_msg).await?;
+/// Challenge a leaf for the key of the device it names, returning the device
+/// id it proved and the sealing of its frames from then on.
+async fn authenticate(stream: &mut TcpStream, keys: &LeafKeys) -> Result<(String, Sealing)> {
+    let authenticated = gateway_devices::authenticate_leaf(stream, |device_id| keys.key(device_id));
+    tokio::time::timeout(AUTH_TIMEOUT, authenticated)
+        .await
+        .map_err(|_| anyhow::anyhow!("Leaf didn't answer the key challenge in time"))?
+}
 
-        // Spawn off a task to handle the connection
+/// The shared Companion connection, connecting first if there is no
+/// connection, it closed, or Companion has been repointed since.
+async fn companion_mux(
+    args: &Cli,
+    companion: &CompanionSlot,
+) -> Result<Arc<Multiplexer<CompanionWriter>>> {
+    let mut slot = companion.mux.lock().await;
+    let addr = companion.endpoint.get();
+    let current = slot
+        .as_ref()
+        .filter(|(connected, mux)| *connected == addr && !mux.is_closed());
+    if let Some((_, mux)) = current {
+        return Ok(mux.clone());
+    }
+    let mux = if args.front_end.is_some() {
+        info!("Connecting to front end: {}", addr);
+        let (reader, writer) = companion::frontend::connect(addr.as_str()).await?;
+        Multiplexer::new(reader, Box::new(writer) as CompanionWriter)
+    } else {
+        info!("Connecting to companion app: {}", addr);
+        let (reader, writer) = TcpStream::connect(addr.as_str()).await?.into_split();
+        Multiplexer::new(reader, Box::new(writer) as CompanionWriter)
+    };
+    Ok(slot.insert((addr, Arc::new(mux))).1.clone())
+}
+
+/// Take other gateways on the listener as their front end, sharing the
+/// Companion connection with them.
+async fn serve_front_end(
+    listener: tokio::net::TcpListener,
+    args: Arc<Cli>,
+    companion: Arc<CompanionSlot>,
+) -> Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        info!("Gateway connected from {}", addr);
+        stream.set_nodelay(true)?;
+        let args = args.clone();
+        let companion = companion.clone();
         tokio::spawn(async move {
-            let res = pumps::message_pump(
-                device_sender,
-                device_receiver,
-                companion_sender,
-                companion_receiver,
-            )
+            let res = async {
+                // Gateways connect again to be moved to a new Companion
+                let mut repointed = companion.endpoint.subscribe();
+                let mux = companion_mux(&args, &companion).await?;
+                tokio::select! {
+                    res = companion::frontend::serve_gateway(stream, &mux) => res,
+                    _ = repointed.changed() => Ok(()),
+                }
+            }
             .await;
-            info!("Connection closed: {:?}", res);
+            info!("Gateway connection closed: {:?}", res);
         });
     }
 }
+
+/// Add a device to the shared Companion connection.  Also returns what
+/// Companion understands, failing if it is a version that can't be talked to.
+async fn companion_streams(
+    args: &Cli,
+    companion: &CompanionSlot,
+    device_id: &str,
+) -> Result<(Features, DuplexStream, DuplexStream)> {
+    let mux = companion_mux(args, companion).await?;
+    let features = mux.features().await?;
+    let (reader, writer) = mux.add_device(device_id)?;
+    Ok((features, reader, writer))
+}
+
+/// Serve a leaf connection until either the leaf or Companion closes it.
+/// A leaf that authenticated must send the config of the device it proved,
+/// and every frame of it is sealed.
+async fn serve_leaf(
+    stream: TcpStream,
+    authenticated: Option<(String, Sealing)>,
+    args: &Cli,
+    runtime: &RuntimeConfig,
+    registry: &Registry,
+    companion: &CompanionSlot,
+    shutdown: &ShutdownHandle,
+) -> Result<()> {
+    let (authenticated, sealing) = authenticated.unzip();
+    let (device_sender, device_receiver) = gateway_devices::device_from_socket(stream, sealing)
+        .await
+        .map_err(|e| registration_failed(registry, None, e))?;
+
+    // Read the config from the satellite, holding on to any input sent before it
+    let mut device_receiver = pumps::session::Session::new(device_receiver);
+    let config_msg = device_receiver
+        .config()
+        .await
+        .map_err(|e| registration_failed(registry, None, e))?;
+    debug!("Received config: {:?}", config_msg);
+    let device_id = config_msg.device_id.clone();
+    Span::current().record("device_id", device_id.as_str());
+    if let Some(authenticated) = authenticated {
+        if authenticated != device_id {
+            let e =
+                anyhow::anyhow!("Authenticated as {authenticated} but configured as {device_id}");
+            return Err(registration_failed(registry, Some(&device_id), e));
+        }
+    }
+
+    let kind = Kind::from_pid(config_msg.pid)
+        .ok_or_else(|| anyhow::anyhow!("Unknown pid {}", config_msg.pid))
+        .map_err(|e| registration_failed(registry, Some(&device_id), e))?;
+    Span::current().record("kind", tracing::field::debug(kind));
+
+    let repointed = companion.endpoint.subscribe();
+    let companion_streams = companion_streams(args, companion, &device_id)
+        .await
+        .map_err(|e| registration_failed(registry, Some(&device_id), e))?;
+
+    device_receiver
+        .activate()
+        .map_err(|e| registration_failed(registry, Some(&device_id), e))?;
+
+    let registration = registry.register(&config_msg);
+    let stats = registration.stats.clone();
+    let device_sender = device_sender.with_bytes_sent(stats.bytes_to_device());
+    let res = serve_registered(
+        args,
+        runtime,
+        registry,
+        kind,
+        config_msg,
+        registration,
+        (device_sender, device_receiver),
+        companion_streams,
+        (companion, repointed),
+        shutdown,
+    )
+    .await;
+    let disconnect = match &res {
+        Ok(Some(reason)) => Disconnect::intentional(*reason),
+        Ok(None) => Disconnect::lost("closed"),
+        Err(e) => Disconnect::lost(e),
+    };
+    registry.unregister(&device_id, &stats, disconnect);
+    res.map(drop)
+}
+
+/// Run the message pump of a registered leaf until it closes, is
+/// disconnected or the gateway shuts down, returning the reason if it was
+/// closed on purpose.  When Companion is repointed, the device is removed
+/// from the old Companion and added to the new one, keeping the leaf
+/// connected.
+#[allow(clippy::too_many_arguments)]
+async fn serve_registered(
+    args: &Cli,
+    runtime: &RuntimeConfig,
+    registry: &Registry,
+    kind: Kind,
+    config_msg: RemoteConfig,
+    registration: gateway::state::Registration,
+    (device_sender, device_receiver): (
+        impl traits::device::Sender + Send + 'static,
+        impl traits::device::Receiver + Send + 'static,
+    ),
+    (features, companion_reader, mut companion_writer): (Features, DuplexStream, DuplexStream),
+    (companion, mut repointed): (&CompanionSlot, watch::Receiver<String>),
+    shutdown: &ShutdownHandle,
+) -> Result<Option<ShutdownReason>> {
+    let device_id = config_msg.device_id.clone();
+    // Disconnecting the leaf shuts down it alone
+    let shutdown = &shutdown.child();
+    let mut disconnect = registration.disconnect;
+    let failed = |e| registration_failed(registry, Some(&device_id), e);
+    let (stats, settings) = (registration.stats, registration.settings);
+    // Recorded as the leaf sees it, before anything is done to it here
+    let transcript = registration.transcript;
+    transcript.record_command(&Command::Config(config_msg.clone()));
+    let device_sender = TranscriptSender::new(device_sender, transcript.clone());
+    let (starved, starved_receiver) = pumps::bandwidth::channel();
+    let device_sender = pumps::bandwidth::ThroughputMonitor::new(
+        device_sender,
+        starved,
+        runtime.bandwidth_policy(),
+    );
+    // Paced outside the monitor, so waiting isn't taken for a slow leaf
+    let device_sender = pumps::low_impact::PacedSender::new(device_sender, args.write_interval());
+    // Keys are numbered as Companion numbers them from here on
+    let device_sender = KeyTransformSender::new(
+        device_sender,
+        settings.clone(),
+        kind.row_count(),
+        kind.column_count(),
+    );
+    let device_receiver = TranscriptReceiver::new(device_receiver, transcript);
+    if registration.hardware_changed {
+        warn!(
+            "{} reconnected with different hardware {:?}, adding it to Companion again",
+            device_id, config_msg.fingerprint
+        );
+        companion::sender::remove_device(&mut companion_writer, &device_id)
+            .await
+            .map_err(failed)?;
+    }
+
+    // Leaves are blanked with an image encoded for them when shutting down
+    let (width, height) = kind.key_image_format().size;
+    let blank = registration.image_converter.convert_key(
+        kind,
+        &vec![0; width * height * 3],
+        &Default::default(),
+    )?;
+    let device_sender = BlankingSender::new(device_sender, kind.key_count(), blank);
+    let device_sender = pumps::brightness::BrightnessSender::new(
+        device_sender,
+        registration.brightness,
+        settings.clone(),
+    )
+    .await
+    .map_err(failed)?;
+    let mut device_sender = pumps::panel::PanelSender::new(device_sender, registration.images)
+        .await
+        .map_err(failed)?;
+    let device_receiver = pumps::settings::SettingsReceiver::new(
+        device_receiver,
+        settings.clone(),
+        kind.row_count(),
+        kind.column_count(),
+    );
+    let device_receiver =
+        pumps::inject::InjectingReceiver::new(device_receiver, registration.injected);
+    let device_receiver = pumps::fader::FaderReceiver::new(
+        device_receiver,
+        registration.faders.clone(),
+        settings.clone(),
+    );
+    let device_receiver = pumps::repeat::RepeatReceiver::new(
+        device_receiver,
+        settings.clone(),
+        Box::new(move |x| companion::layout::touch_key(kind, x)),
+    );
+    let power = registration.power.subscribe();
+    let device_receiver = pumps::power::PowerMonitor::new(device_receiver, registration.power);
+    let mut device_receiver =
+        pumps::upstream::StatusImageMonitor::new(device_receiver, registration.status_image);
+    let leaf = Leaf {
+        kind,
+        config: config_msg,
+        stats,
+        settings,
+        power,
+        power_policy: runtime.power_policy(),
+        image_cache: registration.image_cache,
+        image_converter: registration.image_converter,
+        conversion_times: registration.conversion_times,
+        unknown_commands: registration.unknown_commands,
+        fonts: registration.fonts,
+        faders: registration.faders,
+        lcd_frames: registration.lcd_frames,
+        standby: registration.standby,
+        // Only leaves of a gateway started with a policy are ever starved
+        starved: args.bandwidth_policy().map(|_| starved_receiver),
+    };
+
+    let mut streams = Some((features, companion_reader, companion_writer));
+    loop {
+        let (features, companion_reader, companion_writer) = match streams.take() {
+            Some(streams) => streams,
+            None => {
+                repointed.mark_unchanged();
+                companion_streams(args, companion, &device_id)
+                    .await
+                    .map_err(failed)?
+            }
+        };
+        let connection = shutdown.child();
+        let res = tokio::select! {
+            res = serve_companion(
+                args,
+                registry,
+                &leaf,
+                (DeviceSide(&mut device_sender), DeviceSide(&mut device_receiver)),
+                (features, companion_reader, companion_writer),
+                &connection,
+            ) => res,
+            _ = shutdown_when_repointed(&mut repointed, &connection) => unreachable!(),
+            _ = shutdown_when_disconnected(&mut disconnect, shutdown) => unreachable!(),
+        };
+        match shutdown.reason().or_else(|| connection.reason()) {
+            // Only a repointed connection is made again
+            Some(ShutdownReason::Repointed) => {}
+            Some(reason) => {
+                // A leaf leaving may be gone before it is blanked
+                if let Err(e) = res {
+                    debug!("Closing for {} failed: {:#}", reason, e);
+                }
+                return Ok(Some(reason));
+            }
+            None => return res.map(|()| None),
+        }
+        info!(
+            "Moving {} to Companion at {}",
+            device_id,
+            companion.endpoint.get()
+        );
+    }
+}
+
+/// Shut the leaf down once the registry is asked to disconnect it, then wait
+/// forever so it can close cleanly.
+async fn shutdown_when_disconnected(
+    disconnect: &mut watch::Receiver<Option<ShutdownReason>>,
+    shutdown: &ShutdownHandle,
+) {
+    let reason = disconnect
+        .wait_for(Option::is_some)
+        .await
+        .map(|reason| *reason);
+    if let Ok(Some(reason)) = reason {
+        info!("Disconnecting the leaf: {}", reason);
+        shutdown.shutdown_because(reason);
+    }
+    std::future::pending().await
+}
+
+/// What every connection to Companion of a registered leaf is served with.
+struct Leaf {
+    kind: Kind,
+    config: RemoteConfig,
+    stats: Arc<PumpStats>,
+    settings: watch::Receiver<DeviceSettings>,
+    power: watch::Receiver<PowerState>,
+    power_policy: watch::Receiver<PowerPolicy>,
+    image_cache: ImageCache,
+    image_converter: Arc<dyn ImageConverter>,
+    conversion_times: Arc<ConversionTimes>,
+    unknown_commands: Arc<AtomicU64>,
+    fonts: Option<Arc<Fonts>>,
+    faders: Faders,
+    lcd_frames: watch::Receiver<Option<LcdFrame>>,
+    standby: watch::Receiver<Vec<String>>,
+    /// Whether the leaf is starved of bandwidth, if it may ever be
+    starved: Option<watch::Receiver<bool>>,
+}
+
+/// Pump messages between a registered leaf and one connection to Companion,
+/// until either closes or connection is shut down.
+async fn serve_companion(
+    args: &Cli,
+    registry: &Registry,
+    leaf: &Leaf,
+    (device_sender, device_receiver): (
+        impl traits::device::Sender + Send,
+        impl traits::device::Receiver + Send,
+    ),
+    (features, companion_reader, companion_writer): (Features, DuplexStream, DuplexStream),
+    connection: &ShutdownHandle,
+) -> Result<()> {
+    let kind = leaf.kind;
+    let device_id = leaf.config.device_id.clone();
+    let failed = |e| registration_failed(registry, Some(&device_id), e);
+    let stats = leaf.stats.clone();
+    let (settings, power) = (leaf.settings.clone(), leaf.power.clone());
+
+    let companion_receiver = companion::receiver::Receiver::new(companion_reader, kind)
+        .with_settings(settings.clone())
+        .with_liveness(Liveness::new(Duration::from_millis(args.pong_timeout_ms)))
+        .with_cache(leaf.image_cache.clone())
+        .with_converter(leaf.image_converter.clone())
+        .with_conversion_times(leaf.conversion_times.clone())
+        .with_unknown_commands(leaf.unknown_commands.clone())
+        .with_standby(leaf.standby.clone())
+        .with_read_buffer(args.memory_budget().read_buffer_bytes())
+        .with_pooled_buffers(args.memory_budget().pooled_buffers())
+        .with_max_pending(args.memory_budget().pending_actions())
+        .with_queue_depth(stats.companion_queue_depth());
+    let companion_receiver = if args.strict_companion {
+        companion_receiver.with_strict()
+    } else {
+        companion_receiver
+    };
+    let companion_receiver = match args.image_workers() {
+        Some(workers) => companion_receiver.with_parallelism(workers),
+        None => companion_receiver,
+    };
+    let companion_receiver = match &leaf.fonts {
+        Some(fonts) => companion_receiver.with_fonts(fonts.clone()),
+        None => companion_receiver,
+    };
+    let companion_receiver = match &leaf.starved {
+        Some(starved) => companion_receiver.with_colors_only(starved.clone()),
+        None => companion_receiver,
+    };
+    let lcd_size = kind.lcd_strip_size().unwrap_or((0, 0));
+    let lcd_size = (lcd_size.0.try_into()?, lcd_size.1.try_into()?);
+    let converter = leaf.image_converter.clone();
+    let encode_lcd: pumps::video::EncodeLcd = Arc::new(move |width, height, rgb| {
+        converter.convert_lcd(kind, width.into(), height.into(), rgb)
+    });
+    let companion_receiver = pumps::fader::FaderDisplay::new(
+        companion_receiver,
+        &leaf.faders,
+        settings.clone(),
+        lcd_size,
+        kind.encoder_count(),
+    )
+    .with_encoder(encode_lcd.clone());
+    let companion_receiver = pumps::video::LcdVideo::new(
+        companion_receiver,
+        leaf.lcd_frames.clone(),
+        lcd_size,
+        args.lcd_video_fps,
+    )
+    .with_encoder(encode_lcd);
+    let companion_receiver =
+        pumps::power::PowerSaver::new(companion_receiver, power.clone(), leaf.power_policy.clone());
+    let mut features = features;
+    if args.render_keys {
+        if features.colors && features.text {
+            features.bitmaps = false;
+        } else {
+            warn!("Companion can't send the color and text of keys, asking for images instead");
+        }
+    }
+    let ping_stats = stats.clone();
+    let companion_sender = companion::sender::Sender::with_config(
+        companion_writer,
+        leaf.config.clone(),
+        companion::sender::SenderConfig {
+            features,
+            ..args.sender_config()
+        },
+        move || ping_stats.snapshot().to_string(),
+    )
+    .await
+    .map_err(failed)?;
+    registry.publish(RegistryEvent::CompanionUp {
+        device_id: device_id.clone(),
+    });
+
+    let res = pumps::message_pump_until(
+        device_sender,
+        device_receiver,
+        companion_sender,
+        companion_receiver,
+        stats,
+        pumps::power::throttle(settings, power, leaf.power_policy.clone()),
+        connection.clone(),
+    )
+    .await;
+    registry.publish(RegistryEvent::CompanionDown {
+        device_id,
+        reason: match (&res, connection.reason()) {
+            (Ok(()), Some(reason)) => reason.to_string(),
+            (Ok(()), None) => String::from("closed"),
+            (Err(e), _) => e.to_string(),
+        },
+    });
+    res
+}
@@ -0,0 +1,209 @@
+//! Prometheus metrics of the gateway.
+//!
+//! With the `metrics` feature, `GET /metrics` of the [status
+//! endpoint](crate::status) answers with the state of the [Registry] in the
+//! Prometheus text format.  Traffic is exported as counters, so frames per
+//! second of each device are what Prometheus makes of them with `rate()`.
+//! Each device is labelled with its device id.
+//!
+//! Nothing is kept here: the pumps, the receivers converting images and the
+//! senders writing to leaves count as they go, and each scrape reads what
+//! they counted.
+
+use std::fmt::Write;
+
+use companion::images::CONVERSION_BUCKETS_US;
+use pumps::stats::StatsSnapshot;
+
+use crate::state::{DeviceStatus, Registry};
+
+/// Content type of the text format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Render the metrics of the registry in the Prometheus text format.
+pub fn render(registry: &Registry) -> String {
+    let mut out = String::new();
+    let devices = registry.devices();
+
+    metric(&mut out, "gateway_leaves", "gauge", "Leaves connected");
+    let _ = writeln!(out, "gateway_leaves {}", devices.len());
+    metric(
+        &mut out,
+        "gateway_leaf_connections_total",
+        "counter",
+        "Leaves registered since the gateway started",
+    );
+    let _ = writeln!(
+        out,
+        "gateway_leaf_connections_total {}",
+        registry.connections()
+    );
+
+    per_device(
+        &mut out,
+        &devices,
+        ("gateway_frames_to_device_total", "counter"),
+        "Frames sent from Companion to the device",
+        |stats| stats.frames_to_device,
+    );
+    per_device(
+        &mut out,
+        &devices,
+        ("gateway_frames_to_companion_total", "counter"),
+        "Frames sent from the device to Companion",
+        |stats| stats.frames_to_companion,
+    );
+    per_device(
+        &mut out,
+        &devices,
+        ("gateway_dropped_frames_total", "counter"),
+        "Frames coalesced or otherwise left out",
+        |stats| stats.dropped_frames,
+    );
+    per_device(
+        &mut out,
+        &devices,
+        ("gateway_bytes_to_device_total", "counter"),
+        "Bytes written to the leaf",
+        |stats| stats.bytes_to_device,
+    );
+    per_device(
+        &mut out,
+        &devices,
+        ("gateway_queue_depth", "gauge"),
+        "Frames waiting to be sent to the device",
+        |stats| stats.queue_depth as u64,
+    );
+    per_device(
+        &mut out,
+        &devices,
+        ("gateway_companion_queue_depth", "gauge"),
+        "Actions read from Companion waiting to be converted or sent on",
+        |stats| stats.companion_queue_depth as u64,
+    );
+
+    let cache = registry.cache_stats();
+    metric(
+        &mut out,
+        "gateway_image_cache_hits_total",
+        "counter",
+        "Images found converted in the cache",
+    );
+    let _ = writeln!(out, "gateway_image_cache_hits_total {}", cache.hits);
+    metric(
+        &mut out,
+        "gateway_image_cache_misses_total",
+        "counter",
+        "Images not found converted in the cache",
+    );
+    let _ = writeln!(out, "gateway_image_cache_misses_total {}", cache.misses);
+    metric(
+        &mut out,
+        "gateway_image_cache_hit_ratio",
+        "gauge",
+        "Share of lookups found in the cache since the gateway started",
+    );
+    let lookups = cache.hits + cache.misses;
+    let ratio = if lookups == 0 {
+        0.0
+    } else {
+        cache.hits as f64 / lookups as f64
+    };
+    let _ = writeln!(out, "gateway_image_cache_hit_ratio {ratio}");
+
+    let conversions = registry.conversion_times();
+    let name = "gateway_image_conversion_seconds";
+    metric(
+        &mut out,
+        name,
+        "histogram",
+        "Time taken to convert an image",
+    );
+    for (bound, count) in CONVERSION_BUCKETS_US.iter().zip(conversions.cumulative) {
+        let le = *bound as f64 / 1_000_000.0;
+        let _ = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {count}");
+    }
+    let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", conversions.count);
+    let sum = conversions.total_us as f64 / 1_000_000.0;
+    let _ = writeln!(out, "{name}_sum {sum}");
+    let _ = writeln!(out, "{name}_count {}", conversions.count);
+
+    metric(
+        &mut out,
+        "gateway_companion_unknown_commands_total",
+        "counter",
+        "Commands from Companion passed over for not being known",
+    );
+    let _ = writeln!(
+        out,
+        "gateway_companion_unknown_commands_total {}",
+        registry.unknown_commands()
+    );
+    out
+}
+
+/// Write the HELP and TYPE lines of a metric.
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Write a metric with the value of each device.
+fn per_device(
+    out: &mut String,
+    devices: &[DeviceStatus],
+    (name, kind): (&str, &str),
+    help: &str,
+    value: impl Fn(&StatsSnapshot) -> u64,
+) {
+    metric(out, name, kind, help);
+    for device in devices {
+        let _ = writeln!(
+            out,
+            "{name}{{device=\"{}\"}} {}",
+            escape(&device.device_id),
+            value(&device.stats)
+        );
+    }
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traits::device::{Fingerprint, RemoteConfig};
+
+    #[test]
+    fn test_devices_labelled() {
+        let registry = Registry::default();
+        let registration = registry.register(&RemoteConfig {
+            pid: 0x0080,
+            device_id: String::from("deck \"one\""),
+            fingerprint: Fingerprint::default(),
+        });
+        registration.stats.record_to_device();
+        registration
+            .stats
+            .bytes_to_device()
+            .fetch_add(1234, std::sync::atomic::Ordering::Relaxed);
+
+        let metrics = render(&registry);
+        assert!(metrics.contains("gateway_leaves 1\n"));
+        let label = r#"{device="deck \"one\""}"#;
+        assert!(metrics.contains(&format!("gateway_frames_to_device_total{label} 1\n")));
+        assert!(metrics.contains(&format!("gateway_bytes_to_device_total{label} 1234\n")));
+        assert!(metrics.contains("gateway_image_conversion_seconds_bucket{le=\"+Inf\"} 0\n"));
+        // Every metric is described once
+        for line in metrics.lines().filter(|line| line.starts_with("# TYPE")) {
+            let name = line.split(' ').nth(2).unwrap();
+            assert_eq!(metrics.matches(&format!("# TYPE {name} ")).count(), 1);
+        }
+    }
+}
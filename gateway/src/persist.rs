@@ -0,0 +1,239 @@
+//! Keeping the state of the registry across restarts.
+//!
+//! Without it, a gateway that restarts forgets the brightness, faders and
+//! images of every leaf, so decks come back blank until Companion sends them
+//! everything again.  A gateway given a [StateStore] saves a [PersistedState]
+//! to it whenever the registry changes, and restores it on startup, so each
+//! leaf shows what it did the moment it connects again.  How many times each
+//! leaf connected, and why it last disconnected, are kept along with it.
+//!
+//! Images are kept once each by their hash, as leaves showing the same page
+//! share most of them.  The store is a trait so the state can be kept
+//! wherever suits the gateway: a [FileStore] keeps it in a JSON file, and
+//! with the `sled` feature a [SledStore] keeps it in a sled database, only
+//! writing the images that are new.  A gateway given neither keeps nothing.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use companion::disk_cache::fnv1a;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use traits::Result;
+
+use crate::events::Disconnect;
+use crate::replication::ReplicatedState;
+use crate::state::Registry;
+use crate::Cli;
+
+/// How often the registry is checked for changes to save.
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Everything a restarted gateway needs to pick up where it left off.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PersistedState {
+    /// The settings, hardware, brightness and faders of every device, as
+    /// replicated to a secondary gateway
+    #[serde(flatten)]
+    pub registry: ReplicatedState,
+    /// The hash of the image last shown on each key of each device id
+    pub key_images: BTreeMap<String, BTreeMap<u8, String>>,
+    /// The images shown, by their hash
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub images: BTreeMap<String, Vec<u8>>,
+    /// Times each device id registered
+    pub registrations: BTreeMap<String, u64>,
+    /// Why each device id last disconnected
+    pub disconnects: BTreeMap<String, Disconnect>,
+}
+
+/// The hash an image is kept by.
+pub fn image_hash(image: &[u8]) -> String {
+    format!("{:016x}", fnv1a(image))
+}
+
+/// Where the state of the registry is kept across restarts.
+pub trait StateStore: Send + Sync {
+    /// The state saved last, or None if none was.
+    fn load(&self) -> Result<Option<PersistedState>>;
+
+    /// Save state in place of the state saved before.
+    fn save(&self, state: &PersistedState) -> Result<()>;
+}
+
+/// Keeps the state in a JSON file, written whole on every save.
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    /// Keep the state in the file at path, created on the first save.
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl StateStore for FileStore {
+    fn load(&self) -> Result<Option<PersistedState>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+        let state = std::fs::read_to_string(&self.path)?;
+        Ok(Some(serde_json::from_str(&state)?))
+    }
+
+    fn save(&self, state: &PersistedState) -> Result<()> {
+        // Written aside first, so a gateway killed while saving keeps the
+        // state saved before
+        let written = self.path.with_extension("tmp");
+        std::fs::write(&written, serde_json::to_vec(state)?)?;
+        std::fs::rename(written, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Keeps the state in a sled database, each image under its hash.
+#[cfg(feature = "sled")]
+#[cfg_attr(docsrs, doc(cfg(feature = "sled")))]
+pub struct SledStore {
+    db: sled::Db,
+}
+
+#[cfg(feature = "sled")]
+impl SledStore {
+    /// The key the state is kept under, less its images.
+    const STATE: &'static str = "state";
+    /// The tree the images are kept in.
+    const IMAGES: &'static str = "images";
+
+    /// Open the database at path, creating it if needed.
+    pub fn open(path: &std::path::Path) -> Result<Self> {
+        Ok(Self {
+            db: sled::open(path)?,
+        })
+    }
+}
+
+#[cfg(feature = "sled")]
+impl StateStore for SledStore {
+    fn load(&self) -> Result<Option<PersistedState>> {
+        let Some(state) = self.db.get(Self::STATE)? else {
+            return Ok(None);
+        };
+        let mut state: PersistedState = serde_json::from_slice(&state)?;
+        let images = self.db.open_tree(Self::IMAGES)?;
+        for hash in state.key_images.values().flat_map(BTreeMap::values) {
+            if let Some(image) = images.get(hash)? {
+                state.images.insert(hash.clone(), image.to_vec());
+            }
+        }
+        Ok(Some(state))
+    }
+
+    fn save(&self, state: &PersistedState) -> Result<()> {
+        let images = self.db.open_tree(Self::IMAGES)?;
+        for (hash, image) in &state.images {
+            if !images.contains_key(hash)? {
+                images.insert(hash, image.as_slice())?;
+            }
+        }
+        for hash in images.iter().keys() {
+            let hash = hash?;
+            if !state.images.contains_key(&*String::from_utf8_lossy(&hash)) {
+                images.remove(hash)?;
+            }
+        }
+        let state = PersistedState {
+            images: BTreeMap::new(),
+            ..state.clone()
+        };
+        self.db.insert(Self::STATE, serde_json::to_vec(&state)?)?;
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+/// The store args name, if any.
+pub fn open(args: &Cli) -> Result<Option<Arc<dyn StateStore>>> {
+    #[cfg(feature = "sled")]
+    if let Some(path) = &args.state_db {
+        info!("Keeping the state of leaves in {}", path.display());
+        return Ok(Some(Arc::new(SledStore::open(path)?)));
+    }
+    Ok(args.state_file.clone().map(|path| {
+        info!("Keeping the state of leaves in {}", path.display());
+        Arc::new(FileStore::new(path)) as Arc<dyn StateStore>
+    }))
+}
+
+/// Save the state of registry to store if it changed since saved, the state
+/// saved last.
+pub fn save_changes(store: &dyn StateStore, registry: &Registry, saved: &mut String) {
+    let state = registry.persisted();
+    let res = serde_json::to_string(&state)
+        .map_err(traits::anyhow::Error::from)
+        .and_then(|json| {
+            if json != *saved {
+                store.save(&state)?;
+                *saved = json;
+            }
+            Ok(())
+        });
+    if let Err(e) = res {
+        warn!("Couldn't save the state of leaves: {:#}", e);
+    }
+}
+
+/// Save the state of registry to store whenever it changes, forever.
+pub async fn keep(store: Arc<dyn StateStore>, registry: Arc<Registry>) {
+    let mut saved = String::new();
+    loop {
+        tokio::time::sleep(SAVE_INTERVAL).await;
+        save_changes(&*store, &registry, &mut saved);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traits::device::{Fingerprint, RemoteConfig, ShutdownReason};
+
+    #[test]
+    fn test_state_restored_after_restart() {
+        let path = std::env::temp_dir().join(format!("gateway-state-{}.json", std::process::id()));
+        let store = FileStore::new(path.clone());
+        assert!(store.load().unwrap().is_none());
+
+        let registry = Registry::default();
+        let config = RemoteConfig {
+            pid: 0x0080,
+            device_id: String::from("deck"),
+            fingerprint: Fingerprint::default(),
+        };
+        let registration = registry.register(&config);
+        let images = BTreeMap::from([(0, vec![1; 8]), (1, vec![1; 8]), (2, vec![2; 8])]);
+        registration.images.restore(images.clone());
+        registry.unregister(
+            "deck",
+            &registration.stats,
+            Disconnect::intentional(ShutdownReason::Shutdown),
+        );
+        let mut saved = String::new();
+        save_changes(&store, &registry, &mut saved);
+        let state = store.load().unwrap().unwrap();
+        // Images shared by keys are kept once
+        assert_eq!(state.images.len(), 2);
+        assert_eq!(state.key_images["deck"][&1], image_hash(&[1; 8]));
+
+        let restarted = Registry::default();
+        restarted.restore_persisted(state).unwrap();
+        let registration = restarted.register(&config);
+        assert_eq!(registration.images.get(), images);
+        let device = &restarted.devices()[0];
+        assert_eq!(device.reconnects, 1);
+        assert_eq!(device.last_disconnect.as_ref().unwrap().reason, "shutdown");
+        std::fs::remove_file(path).unwrap();
+    }
+}
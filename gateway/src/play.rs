@@ -0,0 +1,190 @@
+//! Scripted input replay.
+//!
+//! A script is a TOML file listing button presses and encoder twists with
+//! their timing.  It is played into a running gateway through the
+//! [admin](crate::admin) socket, or into a running rust_satellite through
+//! the one `--admin-port` opens, so Companion sees the input exactly as if a
+//! user pressed the keys, which makes Companion pages regression testable.
+//!
+//! ```toml
+//! device = "AL12H1A00000"
+//!
+//! [[step]]
+//! tap = 0            # press and release key 0
+//!
+//! [[step]]
+//! wait_ms = 500      # wait before pressing
+//! press = 3
+//!
+//! [[step]]
+//! wait_ms = 1000
+//! release = 3
+//!
+//! [[step]]
+//! twist = [0, -2]    # encoder 0, two clicks to the left
+//! ```
+
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tracing::info;
+use traits::{anyhow, Result};
+
+/// How long a tap holds the key down unless told otherwise.
+const DEFAULT_HOLD_MS: u64 = 100;
+
+/// A sequence of input to replay into a device.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Script {
+    /// The device the input is injected into
+    pub device: String,
+    /// The input, in order
+    #[serde(default, rename = "step")]
+    pub steps: Vec<Step>,
+}
+
+/// A single step of a script.  A step waits for wait_ms and then performs at
+/// most one action.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    /// Time to wait before the action
+    #[serde(default)]
+    pub wait_ms: u64,
+    /// Press a key
+    pub press: Option<u8>,
+    /// Release a key
+    pub release: Option<u8>,
+    /// Press a key and release it after hold_ms
+    pub tap: Option<u8>,
+    /// How long to hold the key of a tap
+    pub hold_ms: Option<u64>,
+    /// Twist an encoder by a number of clicks
+    pub twist: Option<(u8, i8)>,
+}
+
+impl Script {
+    /// Parse a script, checking every step is valid.
+    pub fn parse(text: &str) -> Result<Self> {
+        let script: Script = toml::from_str(text)?;
+        for (index, step) in script.steps.iter().enumerate() {
+            let actions = [
+                step.press.is_some(),
+                step.release.is_some(),
+                step.tap.is_some(),
+                step.twist.is_some(),
+            ];
+            if actions.into_iter().filter(|a| *a).count() > 1 {
+                anyhow::bail!("Step {} has more than one action", index + 1);
+            }
+            if step.hold_ms.is_some() && step.tap.is_none() {
+                anyhow::bail!("Step {} has hold_ms without a tap", index + 1);
+            }
+        }
+        Ok(script)
+    }
+
+    /// Play the script into the gateway or satellite whose admin socket is at
+    /// address.
+    pub async fn play(&self, address: &str) -> Result<()> {
+        let mut admin = Admin::connect(address).await?;
+        for (index, step) in self.steps.iter().enumerate() {
+            tokio::time::sleep(Duration::from_millis(step.wait_ms)).await;
+            info!("Step {}: {:?}", index + 1, step);
+            let device = &self.device;
+            if let Some(key) = step.press {
+                admin.command(&format!("PRESS {device} {key}")).await?;
+            }
+            if let Some(key) = step.release {
+                admin.command(&format!("RELEASE {device} {key}")).await?;
+            }
+            if let Some(key) = step.tap {
+                admin.command(&format!("PRESS {device} {key}")).await?;
+                let hold = step.hold_ms.unwrap_or(DEFAULT_HOLD_MS);
+                tokio::time::sleep(Duration::from_millis(hold)).await;
+                admin.command(&format!("RELEASE {device} {key}")).await?;
+            }
+            if let Some((encoder, delta)) = step.twist {
+                admin
+                    .command(&format!("TWIST {device} {encoder} {delta}"))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A connection to the admin socket of a gateway or satellite.
+struct Admin {
+    reader: BufReader<tokio::net::tcp::OwnedReadHalf>,
+    writer: tokio::net::tcp::OwnedWriteHalf,
+}
+
+impl Admin {
+    async fn connect(address: &str) -> Result<Self> {
+        let (reader, writer) = TcpStream::connect(address).await?.into_split();
+        Ok(Self {
+            reader: BufReader::new(reader),
+            writer,
+        })
+    }
+
+    /// Send a command, failing if the gateway doesn't answer OK.
+    async fn command(&mut self, command: &str) -> Result<()> {
+        self.writer
+            .write_all(format!("{command}\n").as_bytes())
+            .await?;
+        let mut response = String::new();
+        if self.reader.read_line(&mut response).await? == 0 {
+            anyhow::bail!("Admin socket closed");
+        }
+        match response.trim_end().split_once(' ') {
+            Some(("OK", _)) => Ok(()),
+            _ => anyhow::bail!("{command} failed: {}", response.trim_end()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_script() {
+        let script = Script::parse(
+            r#"
+            device = "deck"
+
+            [[step]]
+            tap = 1
+            hold_ms = 50
+
+            [[step]]
+            wait_ms = 200
+            twist = [0, -2]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(script.device, "deck");
+        assert_eq!(script.steps.len(), 2);
+        assert_eq!(script.steps[0].tap, Some(1));
+        assert_eq!(script.steps[1].twist, Some((0, -2)));
+    }
+
+    #[test]
+    fn test_one_action_per_step() {
+        let script = Script::parse(
+            r#"
+            device = "deck"
+
+            [[step]]
+            press = 1
+            release = 1
+            "#,
+        );
+        assert!(script.is_err());
+    }
+}
@@ -0,0 +1,325 @@
+//! Applying changes to the config file without restarting.
+//!
+//! Restarting the gateway blanks every deck until its leaf connects again, so
+//! a gateway given a `--config` file [watch]es it, reading its options again
+//! whenever it changes.  Those that are safe to change while leaves are
+//! served are taken into a [ConfigUpdate], which [RuntimeConfig] broadcasts
+//! on the watch channels the pump tasks follow:
+//!
+//! - the [PowerPolicy], capping the frame rate and brightness of leaves on
+//!   battery
+//! - the [BandwidthPolicy], for leaves drawn from colors when starved, if
+//!   the gateway was started with one
+//! - which logs are written
+//! - the keys of the device ids allowed to connect, which leaves connecting
+//!   from then on are challenged for.  Leaves whose key was taken out are
+//!   disconnected.
+//!
+//! Any other option only takes effect on a restart.  A file that no longer
+//! reads is logged and left alone, keeping the gateway as it was.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use clap::Parser;
+use notify::{EventKind, RecursiveMode, Watcher};
+use pumps::bandwidth::BandwidthPolicy;
+use pumps::power::PowerPolicy;
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+use traits::device::ShutdownReason;
+
+use crate::leaf_keys::LeafKeys;
+use crate::state;
+use crate::{Cli, Result};
+
+/// How long a changed file is left to settle before reading it, as editors
+/// write files in several steps.
+const SETTLE: Duration = Duration::from_millis(250);
+
+/// Changes which logs are written.
+pub type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Start writing logs, as JSON lines or text, through filter or RUST_LOG,
+/// returning the handle to change the filter with.
+pub fn init_logging(json: bool, filter: Option<&str>) -> Result<LogHandle> {
+    let (filter, handle) = reload::Layer::new(log_filter(filter)?);
+    let registry = tracing_subscriber::registry().with(filter);
+    if json {
+        registry
+            .with(tracing_subscriber::fmt::layer().json())
+            .init();
+    } else {
+        registry.with(tracing_subscriber::fmt::layer()).init();
+    }
+    Ok(handle)
+}
+
+/// The filter of logs written, from RUST_LOG if not given.
+fn log_filter(filter: Option<&str>) -> Result<EnvFilter> {
+    Ok(match filter {
+        Some(filter) => EnvFilter::try_new(filter)?,
+        None => EnvFilter::from_default_env(),
+    })
+}
+
+/// The options that can change while leaves are served.
+#[derive(Clone, PartialEq)]
+pub struct ConfigUpdate {
+    /// How leaves running on a battery save power
+    pub power_policy: PowerPolicy,
+    /// When leaves are starved of bandwidth, if they ever are
+    pub bandwidth_policy: Option<BandwidthPolicy>,
+    /// Which logs are written, or None for RUST_LOG
+    pub log_filter: Option<String>,
+    /// The keys leaves are challenged for, or None to take them unchallenged
+    pub leaf_keys: Option<Arc<LeafKeys>>,
+}
+
+impl ConfigUpdate {
+    /// The options of args that can change, loading the leaf keys.
+    pub fn from_cli(args: &Cli) -> Result<Self> {
+        // Checked here so a filter that doesn't parse is refused with the
+        // rest of the file
+        log_filter(args.log_filter.as_deref())?;
+        let leaf_keys = if args.allow_unauthenticated_leaves {
+            None
+        } else {
+            let keys = LeafKeys::load(args.leaf_keys.as_deref())?;
+            if keys.is_empty() {
+                warn!("No leaf keys configured, every leaf will be turned away");
+            }
+            Some(Arc::new(keys))
+        };
+        Ok(Self {
+            power_policy: args.power_policy(),
+            bandwidth_policy: args.bandwidth_policy(),
+            log_filter: args.log_filter.clone(),
+            leaf_keys,
+        })
+    }
+
+    /// The options that differ in other.
+    pub fn changed(&self, other: &Self) -> Vec<&'static str> {
+        [
+            ("power policy", self.power_policy != other.power_policy),
+            (
+                "bandwidth policy",
+                self.bandwidth_policy != other.bandwidth_policy,
+            ),
+            ("log filter", self.log_filter != other.log_filter),
+            ("leaf keys", self.leaf_keys != other.leaf_keys),
+        ]
+        .into_iter()
+        .filter_map(|(option, changed)| changed.then_some(option))
+        .collect()
+    }
+}
+
+/// The options that can change, broadcast to whatever follows them.
+pub struct RuntimeConfig {
+    /// The update last applied
+    current: Mutex<ConfigUpdate>,
+    power_policy: watch::Sender<PowerPolicy>,
+    bandwidth_policy: watch::Sender<BandwidthPolicy>,
+    log: Option<LogHandle>,
+}
+
+impl RuntimeConfig {
+    /// Start out with update, changing the filter of logs through log if
+    /// given.
+    pub fn new(update: ConfigUpdate, log: Option<LogHandle>) -> Self {
+        Self {
+            power_policy: watch::Sender::new(update.power_policy),
+            bandwidth_policy: watch::Sender::new(Self::bandwidth(&update)),
+            current: Mutex::new(update),
+            log,
+        }
+    }
+
+    /// The bandwidth policy leaves follow, one that never starves them if
+    /// there is none.
+    fn bandwidth(update: &ConfigUpdate) -> BandwidthPolicy {
+        update.bandwidth_policy.unwrap_or(BandwidthPolicy::new(0))
+    }
+
+    /// The power policy, as it changes.
+    pub fn power_policy(&self) -> watch::Receiver<PowerPolicy> {
+        self.power_policy.subscribe()
+    }
+
+    /// The bandwidth policy, as it changes.
+    pub fn bandwidth_policy(&self) -> watch::Receiver<BandwidthPolicy> {
+        self.bandwidth_policy.subscribe()
+    }
+
+    /// The keys leaves connecting now are challenged for, or None to take
+    /// them unchallenged.
+    pub fn leaf_keys(&self) -> Option<Arc<LeafKeys>> {
+        self.current.lock().unwrap().leaf_keys.clone()
+    }
+
+    /// Apply the options of update that changed.  Whether leaves are
+    /// challenged for a key at all stays as the gateway was started.
+    pub fn apply(&self, mut update: ConfigUpdate) {
+        let mut current = self.current.lock().unwrap();
+        if update.leaf_keys.is_some() != current.leaf_keys.is_some() {
+            warn!("Whether leaves are challenged for a key only changes on a restart");
+            update.leaf_keys = current.leaf_keys.clone();
+        }
+        let changed = current.changed(&update);
+        if changed.is_empty() {
+            debug!("No options changed that apply without a restart");
+            return;
+        }
+        info!("Applying the changed {}", changed.join(", "));
+        if update.log_filter != current.log_filter {
+            if let Some(log) = &self.log {
+                let reloaded = log_filter(update.log_filter.as_deref())
+                    .and_then(|filter| Ok(log.reload(filter)?));
+                if let Err(e) = reloaded {
+                    warn!("Keeping the log filter: {:#}", e);
+                }
+            }
+        }
+        self.power_policy.send_if_modified(|policy| {
+            let modified = *policy != update.power_policy;
+            *policy = update.power_policy;
+            modified
+        });
+        let bandwidth = Self::bandwidth(&update);
+        self.bandwidth_policy.send_if_modified(|policy| {
+            let modified = *policy != bandwidth;
+            *policy = bandwidth;
+            modified
+        });
+        *current = update;
+    }
+}
+
+/// Read the options of the gateway again, from its command line and the
+/// config file it names.
+fn read() -> Result<ConfigUpdate> {
+    let args = Cli::try_parse_from(traits::config::args("gateway", std::env::args_os())?)?;
+    ConfigUpdate::from_cli(&args)
+}
+
+/// Disconnect the leaves of registry that no longer have a key in config.
+fn disconnect_revoked(config: &RuntimeConfig, registry: &state::Registry) {
+    let Some(keys) = config.leaf_keys() else {
+        return;
+    };
+    for device in registry.devices() {
+        if keys.key(&device.device_id).is_none() {
+            info!("The key of {} was taken out", device.device_id);
+            // Gone already if it fails
+            let _ = registry.disconnect(&device.device_id, ShutdownReason::ConfigReload);
+        }
+    }
+}
+
+/// Watch the config file at path, applying the options read again to config
+/// and disconnecting leaves of registry whose key was taken out whenever it
+/// changes.  Runs until the gateway exits.
+pub async fn watch(
+    path: PathBuf,
+    config: Arc<RuntimeConfig>,
+    registry: Arc<state::Registry>,
+) -> Result<()> {
+    let (events, mut changes) = tokio::sync::mpsc::channel(16);
+    let mut watcher = notify::recommended_watcher(move |event| {
+        // A full channel already has a change to read the file for
+        let _ = events.try_send(event);
+    })?;
+    // The directory is watched, as editors replace the file rather than
+    // write to it
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    info!("Watching {} for changes", path.display());
+    while let Some(event) = changes.recv().await {
+        let event: notify::Event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("Watching {} failed: {}", path.display(), e);
+                continue;
+            }
+        };
+        let touched = event
+            .paths
+            .iter()
+            .any(|changed| changed.file_name() == path.file_name());
+        if !touched || matches!(event.kind, EventKind::Access(_)) {
+            continue;
+        }
+        tokio::time::sleep(SETTLE).await;
+        while changes.try_recv().is_ok() {}
+        match read() {
+            Ok(update) => {
+                config.apply(update);
+                disconnect_revoked(&config, &registry);
+            }
+            Err(e) => warn!("Ignoring the changed {}: {:#}", path.display(), e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(options: &[&str]) -> Result<ConfigUpdate> {
+        let args = [
+            "gateway",
+            "--companion-host",
+            "localhost",
+            "--companion-port",
+            "16622",
+        ]
+        .iter()
+        .chain(["--listen-port", "16623"].iter())
+        .chain(options);
+        ConfigUpdate::from_cli(&Cli::try_parse_from(args)?)
+    }
+
+    fn update(options: &[&str]) -> ConfigUpdate {
+        read(options).unwrap()
+    }
+
+    #[test]
+    fn test_only_changed_options_broadcast() {
+        let config = RuntimeConfig::new(update(&[]), None);
+        let (mut power, bandwidth) = (config.power_policy(), config.bandwidth_policy());
+        assert_eq!(bandwidth.borrow().starved_bytes_per_sec, 0);
+
+        let changed = update(&["--battery-fps-cap", "10", "--log-filter", "debug"]);
+        assert_eq!(
+            update(&[]).changed(&changed),
+            ["power policy", "log filter"]
+        );
+        config.apply(changed);
+        assert!(power.has_changed().unwrap());
+        assert_eq!(power.borrow_and_update().battery_fps_cap, Some(10.0));
+        assert!(!bandwidth.has_changed().unwrap());
+
+        // Leaves stay challenged for a key
+        config.apply(update(&[
+            "--battery-fps-cap",
+            "10",
+            "--log-filter",
+            "debug",
+            "--allow-unauthenticated-leaves",
+        ]));
+        assert!(config.leaf_keys().is_some());
+        assert!(!power.has_changed().unwrap());
+        // A filter that doesn't parse is refused with the rest
+        assert!(read(&["--log-filter", "pumps=loud"]).is_err());
+    }
+}
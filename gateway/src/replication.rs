@@ -0,0 +1,218 @@
+//! Replication of the registry to a secondary gateway.
+//!
+//! A primary gateway with a replication port accepts secondary gateways on
+//! it.  A secondary connects with the cluster token both were started with,
+//! and from then on is sent a [ReplicatedState] whenever the state of the
+//! primary changes.  The secondary listens for leaves all along, so leaves
+//! that know about both gateways connect to it when the primary goes away,
+//! and are added to Companion again with the settings, brightness and faders
+//! they had.  Images aren't replicated, as Companion sends every key of a
+//! device again when it is added.
+//!
+//! The link is newline delimited JSON, one [Message] per line:
+//!
+//! ```text
+//! {"type":"hello","token":"..."}          secondary to primary, once
+//! {"type":"state","settings":{...},...}   primary to secondary, on changes
+//! ```
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use pumps::reconnect::Backoff;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tracing::{debug, info, warn};
+use traits::{
+    anyhow,
+    device::{DeviceSettings, RemoteConfig},
+    Result,
+};
+
+use crate::state::Registry;
+
+/// How often the primary checks its state for changes to send.
+const REPLICATION_INTERVAL: Duration = Duration::from_millis(250);
+/// How long a secondary has to introduce itself.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Everything a secondary gateway needs to take over the devices of the
+/// primary.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ReplicatedState {
+    /// Runtime settings of every device
+    pub settings: BTreeMap<String, DeviceSettings>,
+    /// The config each device id last connected with
+    pub hardware: BTreeMap<String, RemoteConfig>,
+    /// The brightness Companion last asked for each device id
+    pub brightness: BTreeMap<String, u8>,
+    /// The values of the faders of each device id
+    pub faders: BTreeMap<String, BTreeMap<u8, u8>>,
+}
+
+/// A line of the replication link.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+    /// The secondary introducing itself
+    Hello {
+        /// The cluster token the secondary was started with
+        token: String,
+    },
+    /// The current state of the primary
+    State(ReplicatedState),
+}
+
+/// Accept secondary gateways forever, replicating the registry to those that
+/// know the cluster token.
+pub async fn serve(listener: TcpListener, registry: Arc<Registry>, token: String) -> Result<()> {
+    info!("Replication listening on {:?}", listener.local_addr()?);
+    let token = Arc::new(token);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let (registry, token) = (registry.clone(), token.clone());
+        tokio::spawn(async move {
+            let res = replicate_to(stream, &registry, &token).await;
+            info!("Secondary gateway {:?} gone: {:?}", peer, res);
+        });
+    }
+}
+
+/// Send the state of the registry to a secondary until it goes away.
+async fn replicate_to(stream: TcpStream, registry: &Registry, token: &str) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let hello = tokio::time::timeout(HELLO_TIMEOUT, lines.next_line())
+        .await
+        .map_err(|_| anyhow::anyhow!("No hello from the secondary"))??
+        .ok_or_else(|| anyhow::anyhow!("Secondary closed the link"))?;
+    match serde_json::from_str(&hello)? {
+        Message::Hello { token: theirs } if same_token(&theirs, token) => {}
+        Message::Hello { .. } => anyhow::bail!("Secondary has the wrong cluster token"),
+        message => anyhow::bail!("Expected hello, got {message:?}"),
+    }
+    info!("Replicating to a secondary gateway");
+
+    let mut sent = String::new();
+    loop {
+        let mut line = serde_json::to_string(&Message::State(registry.snapshot()))?;
+        line.push('\n');
+        if line != sent {
+            writer.write_all(line.as_bytes()).await?;
+            sent = line;
+        }
+        // Also notice the secondary going away while nothing changes
+        tokio::select! {
+            _ = tokio::time::sleep(REPLICATION_INTERVAL) => {}
+            line = lines.next_line() => match line? {
+                Some(line) => debug!("Ignoring from secondary: {}", line),
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+/// Follow a primary gateway forever, restoring the state it sends into the
+/// registry.  Whenever the primary can't be reached, the registry keeps the
+/// state last received, ready for the leaves to come over.
+pub async fn follow(
+    primary: impl ToSocketAddrs + Clone,
+    registry: Arc<Registry>,
+    token: String,
+) -> Result<()> {
+    let mut backoff = Backoff::default();
+    loop {
+        let res = follow_once(primary.clone(), &registry, &token, &mut backoff).await;
+        let delay = backoff.next_delay();
+        warn!(
+            "Lost the primary gateway ({:?}), taking over its leaves.  Retrying in {:?}",
+            res, delay
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+async fn follow_once(
+    primary: impl ToSocketAddrs,
+    registry: &Registry,
+    token: &str,
+    backoff: &mut Backoff,
+) -> Result<()> {
+    let (reader, mut writer) = TcpStream::connect(primary).await?.into_split();
+    let mut hello = serde_json::to_string(&Message::Hello {
+        token: token.to_string(),
+    })?;
+    hello.push('\n');
+    writer.write_all(hello.as_bytes()).await?;
+
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        match serde_json::from_str(&line)? {
+            Message::State(state) => {
+                debug!("Replicated state of {} devices", state.settings.len());
+                registry.restore(state)?;
+                backoff.reset();
+            }
+            message => warn!("Unexpected message from the primary: {:?}", message),
+        }
+    }
+    anyhow::bail!("Primary closed the link")
+}
+
+/// Compare tokens without giving away how much of them matched.
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_secondary_follows_primary() {
+        let primary = Arc::new(Registry::default());
+        primary
+            .update_settings("deck", |s| s.brightness = Some(40))
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, primary.clone(), String::from("secret")));
+
+        // A secondary with the wrong token learns nothing
+        let intruder = Arc::new(Registry::default());
+        let mut backoff = Backoff::default();
+        let res = follow_once(addr, &intruder, "guess", &mut backoff).await;
+        assert!(res.is_err());
+        assert_eq!(intruder.settings("deck").brightness, None);
+
+        let secondary = Arc::new(Registry::default());
+        tokio::spawn(follow(addr, secondary.clone(), String::from("secret")));
+        let replicated = async {
+            while secondary.settings("deck").brightness != Some(40) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), replicated)
+            .await
+            .unwrap();
+
+        // Later changes follow
+        primary
+            .update_settings("deck", |s| s.faders = vec![0])
+            .unwrap();
+        let replicated = async {
+            while secondary.settings("deck").faders.is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), replicated)
+            .await
+            .unwrap();
+    }
+}
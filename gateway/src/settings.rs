@@ -0,0 +1,134 @@
+//! Persistent per-device runtime settings.
+//!
+//! Settings changed through the admin socket are written back to the
+//! `--config` file, in a `[device_settings]` table keyed by device id, so
+//! they survive a restart of the gateway.  The rest of the file is left as
+//! it was written:
+//!
+//! ```toml
+//! [gateway]
+//! companion_host = "10.0.0.5"
+//!
+//! [device_settings.CL12345]
+//! brightness = 60
+//! fps_cap = 30.0
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use traits::{anyhow::Context, device::DeviceSettings, Result};
+
+/// The table of the config file the settings are kept in.
+const TABLE: &str = "device_settings";
+
+/// The part of the config file holding the settings.
+#[derive(Default, Serialize, Deserialize)]
+struct ConfigSettings {
+    #[serde(default)]
+    device_settings: BTreeMap<String, DeviceSettings>,
+}
+
+/// Runtime settings of every device the gateway knows about, optionally
+/// backed by the config file.
+#[derive(Default)]
+pub struct SettingsStore {
+    path: Option<PathBuf>,
+    devices: BTreeMap<String, DeviceSettings>,
+}
+
+impl SettingsStore {
+    /// Load the settings from the config file at path.  A missing file is
+    /// treated as empty and will be created when the settings are first
+    /// changed.  With no path, settings are kept in memory only.
+    pub fn load(path: Option<PathBuf>) -> Result<Self> {
+        let devices = match &path {
+            Some(path) if path.exists() => {
+                info!("Loading device settings from {}", path.display());
+                let text = std::fs::read_to_string(path)?;
+                toml::from_str::<ConfigSettings>(&text)
+                    .with_context(|| format!("Bad [{TABLE}] in {}", path.display()))?
+                    .device_settings
+            }
+            _ => BTreeMap::new(),
+        };
+        Ok(Self { path, devices })
+    }
+
+    /// Settings of a device, or the defaults if it has none.
+    pub fn get(&self, device_id: &str) -> DeviceSettings {
+        self.devices.get(device_id).cloned().unwrap_or_default()
+    }
+
+    /// Settings of every device that has any.
+    pub fn all(&self) -> &BTreeMap<String, DeviceSettings> {
+        &self.devices
+    }
+
+    /// Replace the settings of a device and persist the change.
+    pub fn set(&mut self, device_id: &str, settings: DeviceSettings) -> Result<()> {
+        self.devices.insert(device_id.to_string(), settings);
+        self.save()
+    }
+
+    /// Rewrite the settings table of the config file, keeping the rest.
+    fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut config: toml_edit::DocumentMut = match path.exists() {
+            true => std::fs::read_to_string(path)?.parse()?,
+            false => Default::default(),
+        };
+        let settings: toml_edit::DocumentMut = toml::to_string(&ConfigSettings {
+            device_settings: self.devices.clone(),
+        })?
+        .parse()?;
+        if let Some(table) = settings.get(TABLE) {
+            config.insert(TABLE, table.clone());
+        }
+        std::fs::write(path, config.to_string())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings_kept_with_the_rest_of_the_config() {
+        let path = std::env::temp_dir().join(format!("settings-{}.toml", std::process::id()));
+        let config = "# Shared by the gateway and the leaves\nlog_json = true\n\n\
+                      [gateway]\ncompanion_port = 16622\n";
+        std::fs::write(&path, config).unwrap();
+
+        let mut store = SettingsStore::load(Some(path.clone())).unwrap();
+        assert_eq!(store.get("deck"), DeviceSettings::default());
+        let settings = DeviceSettings {
+            brightness: Some(60),
+            fps_cap: Some(30.0),
+            ..Default::default()
+        };
+        store.set("deck", settings.clone()).unwrap();
+
+        store.set("pedal", DeviceSettings::default()).unwrap();
+        store.set("deck", settings.clone()).unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.starts_with(config), "{written}");
+        let loaded = SettingsStore::load(Some(path.clone())).unwrap();
+        assert_eq!(loaded.all().len(), 2);
+        assert_eq!(loaded.get("deck"), settings);
+        // The gateway still reads its options from the file
+        let given = [
+            "gateway".into(),
+            format!("--config={}", path.display()).into(),
+        ];
+        let args = traits::config::args("gateway", given).unwrap();
+        assert!(args.contains(&"--companion-port=16622".into()));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,756 @@
+//! Shared state of the gateway.
+//!
+//! Each leaf connection runs in its own task.  The registry is the one place
+//! those tasks publish what they are doing so it can be reported on, and the
+//! place runtime changes (settings) are routed back to them.  Changes are also
+//! published as [RegistryEvent]s to anyone who [subscribes](Registry::subscribe).
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use companion::cache::{CacheSnapshot, ImageCache};
+use companion::fonts::Fonts;
+use companion::images::{ConversionSnapshot, ConversionTimes, ImageConverter, StreamDeckConverter};
+use elgato_streamdeck::info::Kind;
+use pumps::brightness::LastBrightness;
+use pumps::endpoint::CompanionEndpoint;
+use pumps::fader::Faders;
+use pumps::panel::LastImages;
+use pumps::stats::{KeyStats, PumpStats, StatsSnapshot};
+use pumps::transcript::{DeviceTranscript, Transcript};
+use pumps::video::LcdFrame;
+use serde::Serialize;
+use tokio::sync::{broadcast, mpsc, watch};
+use tracing::debug;
+use traits::{
+    anyhow,
+    device::{
+        Command, DeviceSettings, Fingerprint, PowerState, RemoteConfig, ShutdownReason, StatusImage,
+    },
+    Result,
+};
+
+use crate::events::{Disconnect, RegistryEvent, EVENT_CAPACITY};
+use crate::persist::{image_hash, PersistedState};
+use crate::replication::ReplicatedState;
+use crate::settings::SettingsStore;
+
+/// Registry of the leaf devices connected to the gateway.
+pub struct Registry {
+    devices: Mutex<BTreeMap<String, DeviceEntry>>,
+    settings: Mutex<SettingsStore>,
+    /// The config each device id last connected with, connected or not
+    hardware: Mutex<BTreeMap<String, RemoteConfig>>,
+    /// The brightness Companion last asked for each device id
+    brightness: Mutex<BTreeMap<String, LastBrightness>>,
+    /// The faders of each device id
+    faders: Mutex<BTreeMap<String, Faders>>,
+    /// The images last shown on the keys of each device id
+    images: Mutex<BTreeMap<String, LastImages>>,
+    /// Converted images, shared by every device
+    image_cache: ImageCache,
+    /// Converts images for the devices
+    image_converter: Arc<dyn ImageConverter>,
+    /// How long images took to convert, for every device
+    conversion_times: Arc<ConversionTimes>,
+    /// Commands from Companion passed over for not being known, for every
+    /// device
+    unknown_commands: Arc<AtomicU64>,
+    /// Fonts the text of keys is drawn in, if not the built in one
+    fonts: Option<Arc<Fonts>>,
+    /// Devices registered since the gateway started
+    connections: AtomicU64,
+    /// Times each device id registered since the gateway started
+    registrations: Mutex<BTreeMap<String, u64>>,
+    /// Why each device id last disconnected
+    disconnects: Mutex<BTreeMap<String, Disconnect>>,
+    /// When the gateway started
+    started: Instant,
+    /// Where the devices are added to Companion, if it can be repointed
+    companion: Option<CompanionEndpoint>,
+    /// Where the traffic of the devices is recorded, if anywhere
+    transcript: Transcript,
+    events: broadcast::Sender<RegistryEvent>,
+}
+
+/// A device connected to the gateway.
+struct DeviceEntry {
+    config: RemoteConfig,
+    stats: Arc<PumpStats>,
+    settings: watch::Sender<DeviceSettings>,
+    lcd_frames: watch::Sender<Option<LcdFrame>>,
+    standby: watch::Sender<Vec<String>>,
+    injected: mpsc::Sender<Command>,
+    power: watch::Receiver<PowerState>,
+    status_image: watch::Receiver<Option<StatusImage>>,
+    disconnect: watch::Sender<Option<ShutdownReason>>,
+    /// Whether the device is added to Companion
+    companion_connected: bool,
+}
+
+/// What the message pump serving a newly registered device needs to stay in
+/// touch with the registry.
+pub struct Registration {
+    /// Health counters to record the traffic of the pump in
+    pub stats: Arc<PumpStats>,
+    /// The converted image cache shared by every device
+    pub image_cache: ImageCache,
+    /// Converts images for the device
+    pub image_converter: Arc<dyn ImageConverter>,
+    /// Where how long images take to convert is counted, for every device
+    pub conversion_times: Arc<ConversionTimes>,
+    /// Where commands from Companion passed over for not being known are
+    /// counted, for every device
+    pub unknown_commands: Arc<AtomicU64>,
+    /// Fonts to draw the text of keys in, if not the built in one
+    pub fonts: Option<Arc<Fonts>>,
+    /// Runtime settings of the device
+    pub settings: watch::Receiver<DeviceSettings>,
+    /// Frames streamed to the LCD strip of the device
+    pub lcd_frames: watch::Receiver<Option<LcdFrame>>,
+    /// KEY-STATE lines to have converted before Companion sends them
+    pub standby: watch::Receiver<Vec<String>>,
+    /// Synthetic input to forward to Companion as if it came from the device
+    pub injected: mpsc::Receiver<Command>,
+    /// The device id was last seen on different hardware, so Companion has to
+    /// forget what it knows about the device before it is added again
+    pub hardware_changed: bool,
+    /// The brightness Companion last asked for, kept across connections
+    pub brightness: LastBrightness,
+    /// The values of the encoders acting as faders, kept across connections
+    pub faders: Faders,
+    /// The images last shown on the keys, kept across connections
+    pub images: LastImages,
+    /// Where the power of the leaf comes from, as it last reported
+    pub power: watch::Sender<PowerState>,
+    /// The status image the leaf sent last
+    pub status_image: watch::Sender<Option<StatusImage>>,
+    /// Where the traffic of the device is recorded, if anywhere
+    pub transcript: DeviceTranscript,
+    /// Set once the leaf is to be disconnected, and why
+    pub disconnect: watch::Receiver<Option<ShutdownReason>>,
+}
+
+/// Status of a single device, as reported by the status endpoint.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceStatus {
+    /// The unique device id reported by the leaf
+    pub device_id: String,
+    /// The hardware product id of the device
+    pub pid: u16,
+    /// The kind of Stream Deck the product id is, if it is one
+    pub kind: Option<String>,
+    /// The capabilities of the hardware behind the device id
+    pub fingerprint: Fingerprint,
+    /// Whether the device is added to Companion
+    pub companion_connected: bool,
+    /// Times the leaf connected again, since the gateway started or since
+    /// its state was first kept
+    pub reconnects: u64,
+    /// Health counters of the message pump serving the device
+    pub stats: StatsSnapshot,
+    /// Presses and hold times of the keys pressed so far
+    pub keys: Vec<KeyStats>,
+    /// Runtime settings of the device
+    pub settings: DeviceSettings,
+    /// Where the power of the leaf comes from
+    pub power: PowerState,
+    /// The size of the status image the leaf sent last, if any, served at
+    /// `/devices/<device_id>/status_image`
+    pub status_image: Option<(u16, u16)>,
+    /// Why the leaf disconnected last time, if it has before
+    pub last_disconnect: Option<Disconnect>,
+}
+
+/// Health of the gateway, as reported by the health check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Health {
+    /// Whether the gateway is serving its leaves
+    pub healthy: bool,
+    /// Seconds since the gateway started
+    pub uptime_secs: u64,
+    /// Leaves connected
+    pub leaves: usize,
+    /// Of the leaves, those added to Companion
+    pub companion_connected: usize,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new(SettingsStore::default())
+    }
+}
+
+impl Registry {
+    /// Create a registry using the provided settings.
+    pub fn new(settings: SettingsStore) -> Self {
+        Self {
+            devices: Default::default(),
+            settings: Mutex::new(settings),
+            hardware: Default::default(),
+            brightness: Default::default(),
+            faders: Default::default(),
+            images: Default::default(),
+            image_cache: Default::default(),
+            image_converter: Arc::new(StreamDeckConverter),
+            conversion_times: Default::default(),
+            unknown_commands: Default::default(),
+            fonts: None,
+            connections: AtomicU64::new(0),
+            registrations: Default::default(),
+            disconnects: Default::default(),
+            started: Instant::now(),
+            companion: None,
+            transcript: Default::default(),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+        }
+    }
+
+    /// Share the provided cache of converted images between the devices.
+    pub fn with_image_cache(mut self, image_cache: ImageCache) -> Self {
+        self.image_cache = image_cache;
+        self
+    }
+
+    /// Keep this many events for subscribers that are slow to receive them.
+    /// Must be set before anyone subscribes.
+    pub fn with_event_capacity(mut self, capacity: usize) -> Self {
+        self.events = broadcast::channel(capacity).0;
+        self
+    }
+
+    /// Convert images for the devices with converter rather than for Stream
+    /// Decks, for leaves driving other hardware.
+    pub fn with_image_converter(mut self, converter: Arc<dyn ImageConverter>) -> Self {
+        self.image_converter = converter;
+        self
+    }
+
+    /// Draw the text of keys in fonts rather than the built in font, which
+    /// only has ASCII.
+    pub fn with_fonts(mut self, fonts: Arc<Fonts>) -> Self {
+        self.fonts = Some(fonts);
+        self
+    }
+
+    /// Let the devices be repointed at another Companion through endpoint.
+    pub fn with_companion_endpoint(mut self, endpoint: CompanionEndpoint) -> Self {
+        self.companion = Some(endpoint);
+        self
+    }
+
+    /// Record the traffic of the devices in transcript.
+    pub fn with_transcript(mut self, transcript: Transcript) -> Self {
+        self.transcript = transcript;
+        self
+    }
+
+    /// Where the devices are added to Companion, if it can be repointed.
+    pub fn companion_endpoint(&self) -> Option<&CompanionEndpoint> {
+        self.companion.as_ref()
+    }
+
+    /// Counters of the converted image cache shared by the devices.
+    pub fn cache_stats(&self) -> CacheSnapshot {
+        self.image_cache.stats().snapshot()
+    }
+
+    /// How long images took to convert, for every device.
+    pub fn conversion_times(&self) -> ConversionSnapshot {
+        self.conversion_times.snapshot()
+    }
+
+    /// Commands from Companion passed over for not being known, for every
+    /// device.
+    pub fn unknown_commands(&self) -> u64 {
+        self.unknown_commands.load(Ordering::Relaxed)
+    }
+
+    /// Devices registered since the gateway started, connected or not.
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Seconds since the gateway started.
+    pub fn uptime_secs(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    /// Whether the gateway is serving its leaves: either none are
+    /// connected, or at least one of them is added to Companion.
+    pub fn health(&self) -> Health {
+        let devices = self.devices.lock().unwrap();
+        let companion_connected = devices
+            .values()
+            .filter(|entry| entry.companion_connected)
+            .count();
+        Health {
+            healthy: devices.is_empty() || companion_connected > 0,
+            uptime_secs: self.uptime_secs(),
+            leaves: devices.len(),
+            companion_connected,
+        }
+    }
+
+    /// Receive every event published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<RegistryEvent> {
+        self.events.subscribe()
+    }
+
+    /// Publish an event to the subscribers, if there are any.
+    pub fn publish(&self, event: RegistryEvent) {
+        debug!("Registry event: {:?}", event);
+        // The connections to Companion are only known from their events
+        let companion_connected = match &event {
+            RegistryEvent::CompanionUp { device_id } => Some((device_id, true)),
+            RegistryEvent::CompanionDown { device_id, .. } => Some((device_id, false)),
+            _ => None,
+        };
+        if let Some((device_id, connected)) = companion_connected {
+            if let Some(entry) = self.devices.lock().unwrap().get_mut(device_id) {
+                entry.companion_connected = connected;
+            }
+        }
+        // No subscribers is not an error
+        let _ = self.events.send(event);
+    }
+
+    /// Register a newly connected device.  The returned registration should
+    /// be handed to the message pump serving the device.
+    pub fn register(&self, config: &RemoteConfig) -> Registration {
+        let device_id = config.device_id.as_str();
+        let hardware_changed = self
+            .hardware
+            .lock()
+            .unwrap()
+            .insert(device_id.to_string(), config.clone())
+            .is_some_and(|previous| !previous.same_hardware(config));
+        let brightness = self
+            .brightness
+            .lock()
+            .unwrap()
+            .entry(device_id.to_string())
+            .or_default()
+            .clone();
+        let faders = self
+            .faders
+            .lock()
+            .unwrap()
+            .entry(device_id.to_string())
+            .or_default()
+            .clone();
+        let images = self
+            .images
+            .lock()
+            .unwrap()
+            .entry(device_id.to_string())
+            .or_default()
+            .clone();
+        if hardware_changed {
+            images.forget();
+        }
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        *self
+            .registrations
+            .lock()
+            .unwrap()
+            .entry(device_id.to_string())
+            .or_default() += 1;
+        let stats = Arc::new(PumpStats::default());
+        let (settings, settings_receiver) = watch::channel(self.settings(device_id));
+        let (lcd_frames, lcd_frames_receiver) = pumps::video::channel();
+        let (standby, standby_receiver) = watch::channel(Vec::new());
+        let (injected, injected_receiver) = pumps::inject::channel();
+        let (power, power_receiver) = pumps::power::channel();
+        let (status_image, status_image_receiver) = pumps::upstream::channel();
+        let (disconnect, disconnect_receiver) = watch::channel(None);
+        self.devices.lock().unwrap().insert(
+            device_id.to_string(),
+            DeviceEntry {
+                config: config.clone(),
+                stats: stats.clone(),
+                settings,
+                lcd_frames,
+                standby,
+                injected,
+                power: power_receiver,
+                status_image: status_image_receiver,
+                disconnect,
+                companion_connected: false,
+            },
+        );
+        self.publish(RegistryEvent::LeafConnected {
+            device_id: device_id.to_string(),
+            pid: config.pid,
+            fingerprint: config.fingerprint,
+        });
+        Registration {
+            stats,
+            image_cache: self.image_cache.clone(),
+            image_converter: self.image_converter.clone(),
+            conversion_times: self.conversion_times.clone(),
+            unknown_commands: self.unknown_commands.clone(),
+            fonts: self.fonts.clone(),
+            settings: settings_receiver,
+            lcd_frames: lcd_frames_receiver,
+            standby: standby_receiver,
+            injected: injected_receiver,
+            hardware_changed,
+            brightness,
+            faders,
+            images,
+            power,
+            status_image,
+            transcript: self.transcript.device(Some(device_id)),
+            disconnect: disconnect_receiver,
+        }
+    }
+
+    /// Product id of a connected device.
+    pub fn pid(&self, device_id: &str) -> Option<u16> {
+        self.devices
+            .lock()
+            .unwrap()
+            .get(device_id)
+            .map(|entry| entry.config.pid)
+    }
+
+    /// The status image a connected device sent last, if any.
+    pub fn status_image(&self, device_id: &str) -> Option<StatusImage> {
+        self.devices
+            .lock()
+            .unwrap()
+            .get(device_id)
+            .and_then(|entry| entry.status_image.borrow().clone())
+    }
+
+    /// Publish a frame to the LCD strip of a connected device, or end the
+    /// stream with None.
+    pub fn send_lcd_frame(&self, device_id: &str, frame: Option<LcdFrame>) -> Result<()> {
+        let devices = self.devices.lock().unwrap();
+        let entry = devices
+            .get(device_id)
+            .ok_or_else(|| anyhow::anyhow!("Device {device_id} is not connected"))?;
+        entry.lcd_frames.send_replace(frame);
+        Ok(())
+    }
+
+    /// Keep the images of lines, KEY-STATE lines as Companion sends them,
+    /// converted for a connected device ahead of Companion sending them.
+    /// Replaces the lines given before.
+    pub fn set_standby(&self, device_id: &str, lines: Vec<String>) -> Result<()> {
+        let devices = self.devices.lock().unwrap();
+        let entry = devices
+            .get(device_id)
+            .ok_or_else(|| anyhow::anyhow!("Device {device_id} is not connected"))?;
+        debug!("{} lines in standby for {}", lines.len(), device_id);
+        entry.standby.send_replace(lines);
+        Ok(())
+    }
+
+    /// Remove a device once its connection is closed.  The stats of the
+    /// registration tell the connection apart from a newer one of the same
+    /// device, which is left alone.
+    pub fn unregister(&self, device_id: &str, stats: &Arc<PumpStats>, disconnect: Disconnect) {
+        let removed = {
+            let mut devices = self.devices.lock().unwrap();
+            let current = devices
+                .get(device_id)
+                .is_some_and(|entry| Arc::ptr_eq(&entry.stats, stats));
+            current && devices.remove(device_id).is_some()
+        };
+        if removed {
+            self.disconnects
+                .lock()
+                .unwrap()
+                .insert(device_id.to_string(), disconnect.clone());
+            self.publish(RegistryEvent::LeafDisconnected {
+                device_id: device_id.to_string(),
+                disconnect,
+            });
+        }
+    }
+
+    /// Disconnect a connected device on purpose, for reason.  Companion is
+    /// told why as the device is removed from it.
+    pub fn disconnect(&self, device_id: &str, reason: ShutdownReason) -> Result<()> {
+        let devices = self.devices.lock().unwrap();
+        let entry = devices
+            .get(device_id)
+            .ok_or_else(|| anyhow::anyhow!("Device {device_id} is not connected"))?;
+        entry.disconnect.send_replace(Some(reason));
+        Ok(())
+    }
+
+    /// Current runtime settings of a device, connected or not.
+    pub fn settings(&self, device_id: &str) -> DeviceSettings {
+        self.settings.lock().unwrap().get(device_id)
+    }
+
+    /// Send synthetic input from a connected device to Companion.
+    pub fn inject(&self, device_id: &str, command: Command) -> Result<()> {
+        let devices = self.devices.lock().unwrap();
+        let entry = devices
+            .get(device_id)
+            .ok_or_else(|| anyhow::anyhow!("Device {device_id} is not connected"))?;
+        entry
+            .injected
+            .try_send(command)
+            .map_err(|e| anyhow::anyhow!("Couldn't inject input: {e}"))
+    }
+
+    /// Change the runtime settings of a device.  The change is persisted and,
+    /// if the device is connected, applied immediately.
+    pub fn update_settings(
+        &self,
+        device_id: &str,
+        update: impl FnOnce(&mut DeviceSettings),
+    ) -> Result<DeviceSettings> {
+        // Held until the change is stored, so concurrent updates build on
+        // each other rather than one undoing another
+        let mut store = self.settings.lock().unwrap();
+        let mut settings = store.get(device_id);
+        update(&mut settings);
+        store.set(device_id, settings.clone())?;
+        drop(store);
+        if let Some(entry) = self.devices.lock().unwrap().get(device_id) {
+            entry.settings.send_replace(settings.clone());
+        }
+        Ok(settings)
+    }
+
+    /// The state a secondary gateway needs to take over from this one.
+    pub fn snapshot(&self) -> ReplicatedState {
+        ReplicatedState {
+            settings: self.settings.lock().unwrap().all().clone(),
+            hardware: self.hardware.lock().unwrap().clone(),
+            brightness: self
+                .brightness
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(|(device_id, last)| Some((device_id.clone(), last.get()?)))
+                .collect(),
+            faders: self
+                .faders
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(device_id, faders)| (device_id.clone(), faders.values()))
+                .collect(),
+        }
+    }
+
+    /// Take on the state replicated from another gateway.  Devices connected
+    /// here get changed settings right away, and the rest when they connect.
+    pub fn restore(&self, state: ReplicatedState) -> Result<()> {
+        for (device_id, settings) in state.settings {
+            if self.settings(&device_id) != settings {
+                self.update_settings(&device_id, |current| *current = settings)?;
+            }
+        }
+        self.hardware.lock().unwrap().extend(state.hardware);
+        let mut brightness = self.brightness.lock().unwrap();
+        for (device_id, value) in state.brightness {
+            brightness
+                .entry(device_id)
+                .or_default()
+                .restore(Some(value));
+        }
+        let mut faders = self.faders.lock().unwrap();
+        for (device_id, values) in state.faders {
+            faders.entry(device_id).or_default().restore(values);
+        }
+        Ok(())
+    }
+
+    /// The state to keep across restarts of the gateway.
+    pub fn persisted(&self) -> PersistedState {
+        let mut images = BTreeMap::new();
+        let key_images = self
+            .images
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(device_id, last)| {
+                let keys = last
+                    .get()
+                    .into_iter()
+                    .map(|(button, image)| {
+                        let hash = image_hash(&image);
+                        images.insert(hash.clone(), image);
+                        (button, hash)
+                    })
+                    .collect();
+                (device_id.clone(), keys)
+            })
+            .collect();
+        PersistedState {
+            registry: self.snapshot(),
+            key_images,
+            images,
+            registrations: self.registrations.lock().unwrap().clone(),
+            disconnects: self.disconnects.lock().unwrap().clone(),
+        }
+    }
+
+    /// Take on the state kept from before the gateway restarted.  Devices
+    /// get it when they connect.
+    pub fn restore_persisted(&self, state: PersistedState) -> Result<()> {
+        self.restore(state.registry)?;
+        let mut last_images = self.images.lock().unwrap();
+        for (device_id, keys) in state.key_images {
+            // Keys whose image wasn't kept are left for Companion to fill
+            let images = keys
+                .into_iter()
+                .filter_map(|(button, hash)| Some((button, state.images.get(&hash)?.clone())))
+                .collect();
+            last_images.entry(device_id).or_default().restore(images);
+        }
+        self.registrations
+            .lock()
+            .unwrap()
+            .extend(state.registrations);
+        self.disconnects.lock().unwrap().extend(state.disconnects);
+        Ok(())
+    }
+
+    /// Current status of every registered device.
+    pub fn devices(&self) -> Vec<DeviceStatus> {
+        let registrations = self.registrations.lock().unwrap();
+        let disconnects = self.disconnects.lock().unwrap();
+        self.devices
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(device_id, entry)| DeviceStatus {
+                device_id: device_id.clone(),
+                pid: entry.config.pid,
+                kind: Kind::from_pid(entry.config.pid).map(|kind| format!("{kind:?}")),
+                fingerprint: entry.config.fingerprint,
+                companion_connected: entry.companion_connected,
+                reconnects: registrations
+                    .get(device_id)
+                    .map_or(0, |times| times.saturating_sub(1)),
+                stats: entry.stats.snapshot(),
+                keys: entry.stats.keys(),
+                settings: entry.settings.borrow().clone(),
+                power: *entry.power.borrow(),
+                status_image: entry
+                    .status_image
+                    .borrow()
+                    .as_ref()
+                    .map(|image| (image.width, image.height)),
+                last_disconnect: disconnects.get(device_id).cloned(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_events() {
+        let registry = Registry::default();
+        let mut events = registry.subscribe();
+        let config = RemoteConfig {
+            pid: 0x0080,
+            device_id: String::from("deck"),
+            fingerprint: Fingerprint::default(),
+        };
+
+        let first = registry.register(&config);
+        let second = registry.register(&config);
+        // Closing an old connection of the device leaves the new one alone
+        registry.unregister("deck", &first.stats, Disconnect::lost("replaced"));
+        registry.unregister(
+            "deck",
+            &second.stats,
+            Disconnect::intentional(ShutdownReason::Kicked),
+        );
+
+        let connected = RegistryEvent::LeafConnected {
+            device_id: String::from("deck"),
+            pid: 0x0080,
+            fingerprint: Fingerprint::default(),
+        };
+        assert_eq!(events.try_recv().unwrap(), connected);
+        assert_eq!(events.try_recv().unwrap(), connected);
+        assert_eq!(
+            events.try_recv().unwrap(),
+            RegistryEvent::LeafDisconnected {
+                device_id: String::from("deck"),
+                disconnect: Disconnect::intentional(ShutdownReason::Kicked),
+            }
+        );
+        assert!(events.try_recv().is_err());
+
+        // Only connected devices are disconnected, and the next connection
+        // shows why the last one closed
+        assert!(registry.disconnect("deck", ShutdownReason::Kicked).is_err());
+        let third = registry.register(&config);
+        registry.disconnect("deck", ShutdownReason::Kicked).unwrap();
+        assert_eq!(*third.disconnect.borrow(), Some(ShutdownReason::Kicked));
+        let last = registry.devices()[0].last_disconnect.clone().unwrap();
+        assert_eq!(last, Disconnect::intentional(ShutdownReason::Kicked));
+        assert_eq!(last.reason, "kicked");
+    }
+
+    #[test]
+    fn test_concurrent_settings_updates_kept() {
+        let registry = Registry::default();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..25 {
+                        registry
+                            .update_settings("deck", |settings| {
+                                settings.brightness = Some(settings.brightness.unwrap_or(0) + 1)
+                            })
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(registry.settings("deck").brightness, Some(100));
+    }
+
+    #[test]
+    fn test_health_follows_companion() {
+        let registry = Registry::default();
+        assert!(registry.health().healthy);
+        let config = RemoteConfig {
+            pid: 0x0080,
+            device_id: String::from("deck"),
+            fingerprint: Fingerprint::default(),
+        };
+        registry.register(&config);
+        let registration = registry.register(&config);
+
+        // A leaf that isn't added to Companion isn't served
+        assert!(!registry.health().healthy);
+        registry.publish(RegistryEvent::CompanionUp {
+            device_id: String::from("deck"),
+        });
+        let health = registry.health();
+        assert!(health.healthy);
+        assert_eq!((health.leaves, health.companion_connected), (1, 1));
+        let device = &registry.devices()[0];
+        assert_eq!(device.kind.as_deref(), Some("Mk2"));
+        assert_eq!(device.reconnects, 1);
+        assert!(device.companion_connected);
+        assert_eq!(device.stats.last_to_device_unix_ms, None);
+        registration.stats.record_to_device();
+        assert!(registry.devices()[0].stats.last_to_device_unix_ms.is_some());
+
+        registry.publish(RegistryEvent::CompanionDown {
+            device_id: String::from("deck"),
+            reason: String::from("closed"),
+        });
+        assert!(!registry.health().healthy);
+    }
+}
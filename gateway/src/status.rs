@@ -0,0 +1,269 @@
+//! A minimal HTTP status endpoint.
+//!
+//! Any GET request to `/` or `/status` is answered with a JSON document
+//! describing the health of the gateway, the devices in the [Registry] and
+//! the image cache they share.  This is deliberately tiny so
+//! the gateway doesn't need a full web framework just to be observable.
+//!
+//! `GET /health` is the health check for load balancers and monitoring.  It
+//! answers 200 while the gateway serves its leaves, and 503 once none of the
+//! leaves connected are added to Companion, with the [Health] of the gateway
+//! either way.
+//!
+//! `GET /events` streams [RegistryEvent]s as server-sent events, one JSON
+//! document per event, for dashboards that want to react to devices coming
+//! and going.
+//!
+//! With the `metrics` feature, `GET /metrics` serves the
+//! [metrics](crate::metrics) of the gateway for Prometheus to scrape.
+//!
+//! `GET /devices/<device_id>/status_image` serves the status image the leaf
+//! sent last as a BMP, for dashboards to show next to the device.
+//!
+//! `GET /kinds` describes what every kind of deck expects, as
+//! [KindDescription](streamdeck::describe::KindDescription)s: how images are
+//! encoded, sized, rotated and mirrored, and how large its reports are.
+//! `GET /kinds/<kind>` describes one kind, and
+//! `GET /devices/<device_id>/kind` the kind of a connected device, for those
+//! building leaves for new hardware.
+//!
+//! When LCD video is enabled, frames for the LCD strip of a device can be
+//! streamed with `POST /devices/<device_id>/lcd`, the body being the whole
+//! strip as packed RGB.  `DELETE /devices/<device_id>/lcd` ends the stream.
+
+use std::sync::Arc;
+
+use companion::cache::CacheSnapshot;
+use elgato_streamdeck::info::Kind;
+use pumps::video::LcdFrame;
+use serde::Serialize;
+use streamdeck::describe::Describe;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info};
+use traits::{anyhow, device::StatusImage, Result};
+
+use crate::events::RegistryEvent;
+use crate::state::{DeviceStatus, Health, Registry};
+
+/// Largest request header accepted.
+const MAX_HEADER_LEN: usize = 8 * 1024;
+/// Largest request body accepted, enough for a frame of any LCD strip.
+const MAX_BODY_LEN: usize = 4 * 1024 * 1024;
+
+/// Document served by the status endpoint.
+#[derive(Serialize)]
+struct StatusReport {
+    health: Health,
+    devices: Vec<DeviceStatus>,
+    cache: CacheSnapshot,
+}
+
+/// A parsed HTTP request.
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+/// Accept connections on the listener forever, answering each with the
+/// current status of the registry.  lcd_video enables streaming frames to
+/// LCD strips.
+pub async fn serve(listener: TcpListener, registry: Arc<Registry>, lcd_video: bool) -> Result<()> {
+    info!("Status endpoint listening on {:?}", listener.local_addr()?);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            let res = handle_connection(stream, &registry, lcd_video).await;
+            debug!("Status request from {:?} finished: {:?}", peer, res);
+        });
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    registry: &Registry,
+    lcd_video: bool,
+) -> Result<()> {
+    let request = read_request(&mut stream).await?;
+
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    let (status, body) = match (request.method.as_str(), segments.as_slice()) {
+        ("GET", [""] | ["status"]) => (
+            "200 OK",
+            serde_json::to_string(&StatusReport {
+                health: registry.health(),
+                devices: registry.devices(),
+                cache: registry.cache_stats(),
+            })?,
+        ),
+        ("GET", ["health"]) => {
+            let health = registry.health();
+            let status = if health.healthy {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            (status, serde_json::to_string(&health)?)
+        }
+        ("GET", ["events"]) => return stream_events(stream, registry).await,
+        #[cfg(feature = "metrics")]
+        ("GET", ["metrics"]) => return send_metrics(stream, registry).await,
+        ("GET", ["devices", device_id, "status_image"]) => match registry.status_image(device_id) {
+            Some(image) => return send_status_image(stream, image).await,
+            None => ("404 Not Found", String::from("{}")),
+        },
+        ("GET", ["kinds"]) => (
+            "200 OK",
+            serde_json::to_string(&streamdeck::describe::describe_all())?,
+        ),
+        ("GET", ["kinds", name]) => match streamdeck::selector::parse_kind(name) {
+            Ok(kind) => ("200 OK", serde_json::to_string(&kind.describe())?),
+            Err(e) => ("404 Not Found", error_body(anyhow::anyhow!(e))?),
+        },
+        ("GET", ["devices", device_id, "kind"]) => {
+            match registry.pid(device_id).and_then(Kind::from_pid) {
+                Some(kind) => ("200 OK", serde_json::to_string(&kind.describe())?),
+                None => ("404 Not Found", String::from("{}")),
+            }
+        }
+        ("POST", ["devices", device_id, "lcd"]) if lcd_video => {
+            let frame = lcd_frame(registry, device_id, request.body)
+                .and_then(|frame| registry.send_lcd_frame(device_id, Some(frame)));
+            match frame {
+                Ok(()) => ("202 Accepted", String::from("{}")),
+                Err(e) => ("400 Bad Request", error_body(e)?),
+            }
+        }
+        ("DELETE", ["devices", device_id, "lcd"]) if lcd_video => {
+            match registry.send_lcd_frame(device_id, None) {
+                Ok(()) => ("200 OK", String::from("{}")),
+                Err(e) => ("400 Bad Request", error_body(e)?),
+            }
+        }
+        _ => ("404 Not Found", String::from("{}")),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Send registry events to the client until it goes away.
+async fn stream_events(mut stream: TcpStream, registry: &Registry) -> Result<()> {
+    let mut events = registry.subscribe();
+    stream
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        )
+        .await?;
+    loop {
+        let event: RegistryEvent = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => {
+                debug!("Event stream fell behind, missed {} events", missed);
+                continue;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        let data = serde_json::to_string(&event)?;
+        stream
+            .write_all(format!("data: {data}\n\n").as_bytes())
+            .await?;
+    }
+}
+
+/// Send the metrics of the registry in the Prometheus text format.
+#[cfg(feature = "metrics")]
+async fn send_metrics(mut stream: TcpStream, registry: &Registry) -> Result<()> {
+    let body = crate::metrics::render(registry);
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        crate::metrics::CONTENT_TYPE,
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Send a status image as a BMP.
+async fn send_status_image(mut stream: TcpStream, image: StatusImage) -> Result<()> {
+    let bmp = companion::images::encode_bmp(image.width.into(), image.height.into(), &image.rgb)?;
+    let header = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: image/bmp\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        bmp.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(&bmp).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Read the request line, headers, and body (if it has a Content-Length).
+async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let mut buf = Vec::new();
+    let header_len = loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if buf.len() > MAX_HEADER_LEN {
+            anyhow::bail!("Request header too long");
+        }
+        let mut chunk = [0u8; 1024];
+        let len = stream.read(&mut chunk).await?;
+        if len == 0 {
+            // Treat whatever we got as the whole request
+            break buf.len();
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    };
+
+    let header = String::from_utf8_lossy(&buf[..header_len]).into_owned();
+    let mut lines = header.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    let content_length = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .map(|(_, value)| value.trim().parse::<usize>())
+        .transpose()?
+        .unwrap_or(0);
+    if content_length > MAX_BODY_LEN {
+        anyhow::bail!("Request body too long");
+    }
+
+    let mut body = buf.split_off(header_len);
+    if body.len() < content_length {
+        let already = body.len();
+        body.resize(content_length, 0);
+        stream.read_exact(&mut body[already..]).await?;
+    }
+    body.truncate(content_length);
+
+    Ok(Request { method, path, body })
+}
+
+/// Build a frame for the LCD strip of a device from the body of a request.
+fn lcd_frame(registry: &Registry, device_id: &str, rgb: Vec<u8>) -> Result<LcdFrame> {
+    let pid = registry
+        .pid(device_id)
+        .ok_or_else(|| anyhow::anyhow!("Device {device_id} is not connected"))?;
+    let (width, height) = Kind::from_pid(pid)
+        .and_then(|kind| kind.lcd_strip_size())
+        .ok_or_else(|| anyhow::anyhow!("Device {device_id} has no LCD strip"))?;
+    LcdFrame::new(width.try_into()?, height.try_into()?, rgb)
+}
+
+fn error_body(e: anyhow::Error) -> Result<String> {
+    Ok(serde_json::to_string(
+        &serde_json::json!({ "error": e.to_string() }),
+    )?)
+}
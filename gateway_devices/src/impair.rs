@@ -0,0 +1,202 @@
+//! Simulation of a bad network between the gateway and a leaf.
+//!
+//! Leaves usually sit on a wired LAN, but keepalives, resyncs and twist
+//! coalescing are there for the times they don't.  [impair] puts a relay in
+//! front of a connection that delays, jitters, reorders and drops messages so
+//! that logic can be exercised without a flaky network at hand.
+//!
+//! The relay works on whole length prefixed messages, so a dropped message
+//! never corrupts the ones after it.  The randomness is seeded, so a failing
+//! run can be repeated.
+//!
+//! ```no_run
+//! # async fn example(socket: tokio::net::TcpStream) {
+//! use gateway_devices::impair::{impair, Impairment};
+//! use std::time::Duration;
+//!
+//! let impairment = Impairment {
+//!     latency: Duration::from_millis(50),
+//!     drop: 0.01,
+//!     ..Default::default()
+//! };
+//! let (reader, writer) = tokio::io::split(impair(socket, impairment));
+//! let sender = gateway_devices::GatewayDeviceSender::new(writer);
+//! let receiver = gateway_devices::GatewayDeviceReceiver::new(reader);
+//! # }
+//! ```
+
+use std::time::Duration;
+
+use bin_comm::stream_utils::{receive_length_prefix, write_length_prefix};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tracing::{debug, trace, Instrument};
+
+/// Bytes buffered between the relay and the impaired end of the connection.
+const BUFFER_SIZE: usize = 256 * 1024;
+
+/// How badly the network behaves, applied to each direction separately.
+#[derive(Clone, Debug)]
+pub struct Impairment {
+    /// Delay added to every message
+    pub latency: Duration,
+    /// Up to this much more delay is added at random to each message
+    pub jitter: Duration,
+    /// Chance, from 0 to 1, of a message being held back and delivered after
+    /// the one following it
+    pub reorder: f64,
+    /// Chance, from 0 to 1, of a message being lost
+    pub drop: f64,
+    /// Seed of the random choices
+    pub seed: u64,
+}
+
+impl Default for Impairment {
+    fn default() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            reorder: 0.0,
+            drop: 0.0,
+            seed: 1,
+        }
+    }
+}
+
+/// Relay the messages of a connection through an impaired network, returning
+/// the end to use in place of the connection.
+pub fn impair<S>(stream: S, impairment: Impairment) -> DuplexStream
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (impaired, relay) = tokio::io::duplex(BUFFER_SIZE);
+    let (stream_reader, stream_writer) = tokio::io::split(stream);
+    let (relay_reader, relay_writer) = tokio::io::split(relay);
+    let mut outgoing = impairment.clone();
+    // The directions shouldn't lose the same messages
+    outgoing.seed = !outgoing.seed;
+    tokio::spawn(relay_messages(stream_reader, relay_writer, impairment).in_current_span());
+    tokio::spawn(relay_messages(relay_reader, stream_writer, outgoing).in_current_span());
+    impaired
+}
+
+/// Relay messages in one direction until either end closes.
+async fn relay_messages<R, W>(mut reader: R, writer: W, impairment: Impairment)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let (deliveries, pending) = mpsc::unbounded_channel();
+    let delivering = tokio::spawn(deliver_messages(pending, writer).in_current_span());
+    let mut random = Random::new(impairment.seed);
+    let mut held: Option<Vec<u8>> = None;
+    let mut last_delivery = Instant::now();
+    while let Ok(message) = receive_length_prefix(&mut reader, Vec::new()).await {
+        if random.chance(impairment.drop) {
+            trace!("Dropping a message of {} bytes", message.len());
+            continue;
+        }
+        let jitter = impairment.jitter.mul_f64(random.fraction());
+        // Jitter alone doesn't reorder, just like it wouldn't on a TCP link
+        let deliver_at = (Instant::now() + impairment.latency + jitter).max(last_delivery);
+        last_delivery = deliver_at;
+        if held.is_none() && random.chance(impairment.reorder) {
+            trace!("Holding back a message of {} bytes", message.len());
+            held = Some(message);
+            continue;
+        }
+        let messages = std::iter::once(message).chain(held.take());
+        if messages
+            .map(|message| deliveries.send((deliver_at, message)))
+            .any(|sent| sent.is_err())
+        {
+            break;
+        }
+    }
+    if let Some(message) = held {
+        let _ = deliveries.send((last_delivery, message));
+    }
+    drop(deliveries);
+    let _ = delivering.await;
+    debug!("Impaired relay closed");
+}
+
+/// Write messages once their delivery time comes, closing the writer after
+/// the last one.
+async fn deliver_messages<W>(
+    mut pending: mpsc::UnboundedReceiver<(Instant, Vec<u8>)>,
+    mut writer: W,
+) where
+    W: AsyncWrite + Unpin,
+{
+    while let Some((deliver_at, message)) = pending.recv().await {
+        tokio::time::sleep_until(deliver_at).await;
+        if write_length_prefix(&mut writer, message).await.is_err() {
+            return;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+/// A small seeded generator (xorshift), good enough to pick what to impair.
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Self {
+        // Xorshift gets stuck on zero
+        Self(seed.max(1))
+    }
+
+    /// A number from 0 up to, but not including, 1.
+    fn fraction(&mut self) -> f64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.fraction() < probability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reorder_and_drop() {
+        let (near, far) = tokio::io::duplex(1024);
+        let impairment = Impairment {
+            latency: Duration::from_millis(1),
+            reorder: 1.0,
+            ..Default::default()
+        };
+        let (_far_reader, mut far_writer) = tokio::io::split(far);
+        let (mut reader, _writer) = tokio::io::split(impair(near, impairment));
+        for message in [b"1", b"2", b"3"] {
+            write_length_prefix(&mut far_writer, message).await.unwrap();
+        }
+        far_writer.shutdown().await.unwrap();
+        // Every other message is held back behind the next one
+        let mut received = Vec::new();
+        while let Ok(message) = receive_length_prefix(&mut reader, Vec::new()).await {
+            received.push(message);
+        }
+        assert_eq!(received, vec![b"2".to_vec(), b"1".to_vec(), b"3".to_vec()]);
+
+        let (near, far) = tokio::io::duplex(1024);
+        let impairment = Impairment {
+            drop: 1.0,
+            ..Default::default()
+        };
+        let (mut far_reader, _far_writer) = tokio::io::split(far);
+        let (_reader, mut writer) = tokio::io::split(impair(near, impairment));
+        write_length_prefix(&mut writer, b"lost").await.unwrap();
+        writer.shutdown().await.unwrap();
+        assert!(receive_length_prefix(&mut far_reader, Vec::new())
+            .await
+            .is_err());
+    }
+}
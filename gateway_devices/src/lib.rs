@@ -5,21 +5,57 @@
 //! binary format specifically formatted for that device.  This eliminates
 //! the need for the leaf device to do ascii parsing, image scaling, and image
 //! conversion.
+//!
+//! With the `quic` feature, leaves on lossy networks can be linked over QUIC
+//! instead, as in the `quic` module.
 
 #![cfg_attr(docsrs, feature(doc_cfg))]
 #![warn(missing_docs)]
 
+/// Simulation of a bad network between the gateway and a leaf.
+#[cfg(feature = "impair")]
+#[cfg_attr(docsrs, doc(cfg(feature = "impair")))]
+pub mod impair;
+
+/// Links to leaves over QUIC, with a stream for each button.
+#[cfg(feature = "quic")]
+#[cfg_attr(docsrs, doc(cfg(feature = "quic")))]
+pub mod quic;
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+
+use bin_comm::replay::{Opener, Sealer};
+use bin_comm::stream_utils::{
+    frame_error, read_sealed, receive_length_prefix, write_length_prefix, write_length_prefix_as,
+    write_sealed, FrameReader,
+};
+use leaf_comm::auth::{self, Auth, Challenge, SessionKeys, NONCE_LEN};
+use leaf_comm::compress;
+use leaf_comm::framing::{Format, MAX_FRAME_LEN};
+use leaf_comm::hello::{self, Capabilities, Hello};
+use leaf_comm::wire::{self, WireError, PROTOCOL_VERSION};
 use tokio::{
-    io::{AsyncRead, AsyncWrite},
-    net::{TcpStream, ToSocketAddrs},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream, ToSocketAddrs,
+    },
 };
-use tracing::trace;
+use tracing::{debug, info, trace, warn};
 use traits::{
-    async_trait,
-    device::{DeviceActions, SetBrightness, SetButtonImage, SetLCDImage},
+    anyhow, async_trait,
+    device::{BorrowedDeviceActions, DeviceActions, SetBrightness, SetButtonImage, SetLCDImage},
     Result,
 };
 
+/// What the gateway takes from leaves beyond their input.
+pub const GATEWAY_CAPABILITIES: Capabilities =
+    Capabilities(Capabilities::POWER.0 | Capabilities::STATUS_IMAGES.0 | Capabilities::GOODBYE.0);
+
 /// Create a connection to the gateway and return objects implementing
 /// the companion sender and receiver traits.
 pub async fn connect_to_gateway(
@@ -36,22 +72,246 @@ pub async fn connect_to_gateway(
     Ok((companion_sender, companion_receiver))
 }
 
-/// Create a set of devices objects from an already connected socket.
+/// Connect to the gateway like [connect_to_gateway], answering the
+/// challenge of the gateway as device_id with key, if there is one, then
+/// exchanging a [Hello] saying the leaf has capabilities before returning.
+/// Needs a gateway that takes a hello.  Once authenticated, every frame
+/// either way is sealed.
+pub async fn connect_to_gateway_with_key(
+    addr: impl ToSocketAddrs,
+    device_id: &str,
+    key: Option<&[u8]>,
+    capabilities: Capabilities,
+) -> Result<(
+    impl traits::companion::Sender,
+    impl traits::companion::Receiver,
+)> {
+    let (companion_reader, companion_writer) =
+        tokio::net::TcpStream::connect(addr).await?.into_split();
+    open_link(
+        companion_reader,
+        companion_writer,
+        device_id,
+        key,
+        capabilities,
+    )
+    .await
+}
+
+/// The leaf end of [connect_to_gateway_with_key] on a link already open.
+async fn open_link<R, W>(
+    mut companion_reader: R,
+    mut companion_writer: W,
+    device_id: &str,
+    key: Option<&[u8]>,
+    capabilities: Capabilities,
+) -> Result<(GatewayCompanionSender<W>, GatewayCompanionReceiver<R>)>
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    let sealing = match key {
+        Some(key) => Some(
+            answer_challenge(&mut companion_reader, &mut companion_writer, device_id, key).await?,
+        ),
+        None => None,
+    };
+    let (mut sealer, mut opener) = sealing
+        .map(|sealing| (sealing.sealer, sealing.opener))
+        .unzip();
+    let mut frames = FrameReader::new(companion_reader);
+    let ours = Hello::new(capabilities);
+    let gateway = say_hello(
+        &mut frames,
+        &mut companion_writer,
+        ours,
+        (sealer.as_mut(), opener.as_mut()),
+    )
+    .await?;
+
+    let mut companion_receiver = GatewayCompanionReceiver::from_frames(frames);
+    companion_receiver.opener = opener;
+    let mut companion_sender = GatewayCompanionSender::new(companion_writer)
+        .with_version(ours.negotiate(&gateway))
+        .with_capabilities(gateway.capabilities);
+    companion_sender.sealer = sealer;
+    Ok((companion_sender, companion_receiver))
+}
+
+/// Send the hello of a leaf on writer and read the answer of the gateway
+/// from frames, both sealed if the leaf authenticated.
+async fn say_hello(
+    frames: &mut FrameReader<impl AsyncRead + Unpin>,
+    writer: &mut (impl AsyncWrite + Unpin),
+    hello: Hello,
+    (sealer, opener): (Option<&mut Sealer>, Option<&mut Opener>),
+) -> Result<Hello> {
+    write_frame(writer, sealer, hello.encode().map_err(wire_error)?).await?;
+    let frame = receive_intact(frames, opener, Vec::new()).await?;
+    let answer = hello::decode(&frame)
+        .ok_or_else(|| anyhow::anyhow!("Gateway didn't answer the hello"))?
+        .map_err(wire_error)?;
+    debug!("Gateway said {:?}", answer);
+    Ok(answer)
+}
+
+/// The sealing of the frames of a link once the leaf authenticated, with
+/// the session key of each direction.
+#[derive(Debug)]
+pub struct Sealing {
+    /// Seals the frames sent
+    pub sealer: Sealer,
+    /// Opens the frames received, refusing forged and replayed ones
+    pub opener: Opener,
+}
+
+impl Sealing {
+    /// The sealing of the gateway end of a session.
+    fn gateway(keys: &SessionKeys) -> Self {
+        Self {
+            sealer: Sealer::new(keys.session_nonce, keys.to_leaf),
+            opener: Opener::new(keys.session_nonce, keys.to_gateway),
+        }
+    }
+
+    /// The sealing of the leaf end of a session.
+    fn leaf(keys: &SessionKeys) -> Self {
+        Self {
+            sealer: Sealer::new(keys.session_nonce, keys.to_gateway),
+            opener: Opener::new(keys.session_nonce, keys.to_leaf),
+        }
+    }
+}
+
+/// Random bytes for a nonce.
+fn nonce() -> Result<[u8; NONCE_LEN]> {
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::getrandom(&mut nonce).map_err(|e| anyhow::anyhow!("No nonce: {e}"))?;
+    Ok(nonce)
+}
+
+/// Read the challenge of the gateway from reader and answer it on writer,
+/// returning the sealing of the session it opens.  Doesn't read past the
+/// challenge.
+async fn answer_challenge(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut (impl AsyncWrite + Unpin),
+    device_id: &str,
+    key: &[u8],
+) -> Result<Sealing> {
+    let frame = receive_length_prefix(reader, Vec::new()).await?;
+    let challenge: Challenge = auth::decode(&frame).map_err(wire_error)?;
+    let answer = Auth::new(&challenge, nonce()?, device_id, key);
+    let frame = wire::encode(PROTOCOL_VERSION, &answer).map_err(wire_error)?;
+    write_length_prefix(writer, frame).await?;
+    Ok(Sealing::leaf(&answer.session(&challenge, key)))
+}
+
+/// Challenge a leaf connected on socket to prove it holds the key of the
+/// device id it names, key_of giving the key of a device id.  Returns the
+/// device id proven with the sealing every later frame of the leaf goes
+/// through, or fails if the leaf names a device without a key or its answer
+/// doesn't verify.  Doesn't read past the answer.
+pub async fn authenticate_leaf<'k>(
+    socket: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    key_of: impl Fn(&str) -> Option<&'k [u8]>,
+) -> Result<(String, Sealing)> {
+    let challenge = Challenge { nonce: nonce()? };
+    let frame = wire::encode(PROTOCOL_VERSION, &challenge).map_err(wire_error)?;
+    write_length_prefix(socket, frame).await?;
+
+    let frame = receive_length_prefix(socket, Vec::new()).await?;
+    let answer: Auth = auth::decode(&frame).map_err(wire_error)?;
+    let Some(key) = key_of(&answer.device_id) else {
+        anyhow::bail!("No key for device {}", answer.device_id);
+    };
+    anyhow::ensure!(
+        answer.verify(&challenge, key),
+        "Device {} failed authentication",
+        answer.device_id
+    );
+    debug!("Authenticated device {}", answer.device_id);
+    let sealing = Sealing::gateway(&answer.session(&challenge, key));
+    Ok((answer.device_id, sealing))
+}
+
+/// Create a set of devices objects from an already connected socket,
+/// sealing its frames if the leaf authenticated.
 pub async fn device_from_socket(
     socket: TcpStream,
-) -> Result<(impl traits::device::Sender, impl traits::device::Receiver)> {
+    sealing: Option<Sealing>,
+) -> Result<(
+    GatewayDeviceSender<OwnedWriteHalf>,
+    GatewayDeviceReceiver<OwnedReadHalf>,
+)> {
     let (companion_reader, companion_writer) = socket.into_split();
+    greet_leaf(companion_reader, companion_writer, sealing).await
+}
 
-    let sender = GatewayDeviceSender::new(companion_writer);
-    let receiver = GatewayDeviceReceiver::new(companion_reader);
-    Ok((sender, receiver))
+/// Read the first frame of a leaf.  If it is a [Hello] it is answered, and
+/// the leaf is written to in the version agreed and sent only what it can
+/// take.  Otherwise the leaf is from before the hello and the frame is its
+/// config: it is answered in the version it writes in, and assumed to have
+/// [Capabilities::LEGACY].  Either way the leaf is written to in the
+/// [Format] its first frame came in, so leaves from before frames were
+/// checksummed are answered without them.
+async fn greet_leaf<R, W>(
+    reader: R,
+    mut writer: W,
+    sealing: Option<Sealing>,
+) -> Result<(GatewayDeviceSender<W>, GatewayDeviceReceiver<R>)>
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
+    let (mut sealer, mut opener) = sealing
+        .map(|sealing| (sealing.sealer, sealing.opener))
+        .unzip();
+    let mut frames = FrameReader::detecting(reader);
+    let first = receive_intact(&mut frames, opener.as_mut(), Vec::new()).await?;
+    let format = frames.format().unwrap_or(Format::Checked);
+    if format == Format::Legacy {
+        debug!("Leaf from before frames were checksummed");
+    }
+    let mut receiver = GatewayDeviceReceiver::from_frames(frames);
+    receiver.opener = opener;
+    let mut sender = match hello::decode(&first) {
+        Some(leaf) => {
+            let leaf = leaf.map_err(wire_error)?;
+            let ours = Hello::new(GATEWAY_CAPABILITIES);
+            let hello = ours.encode().map_err(wire_error)?;
+            write_frame_as(&mut writer, sealer.as_mut(), format, hello).await?;
+            let version = ours.negotiate(&leaf);
+            info!(
+                "Leaf speaks wire version {} with capabilities {:?}",
+                version, leaf.capabilities
+            );
+            receiver.version.store(version, Ordering::Relaxed);
+            GatewayDeviceSender::new(writer).with_capabilities(leaf.capabilities)
+        }
+        None => {
+            debug!("Leaf from before the hello");
+            receiver.pending = Some(first);
+            GatewayDeviceSender::new(writer)
+        }
+    };
+    sender.sealer = sealer;
+    // The leaf is answered in the version and format it writes in
+    Ok((
+        sender.with_version(receiver.version()).with_format(format),
+        receiver,
+    ))
 }
 
 /// GatewayCompanionReceiver implements the companion receiver trait.  The
 /// The operations are received from the provided reader, deserialized,
 /// and provided to the caller in the receive method.
 pub struct GatewayCompanionReceiver<R> {
-    reader: R,
+    reader: FrameReader<R>,
+    /// Opens the frames of an authenticated link
+    opener: Option<Opener>,
+    /// Buffer frames are read into, reused for every frame
+    frame: Vec<u8>,
 }
 impl<R> GatewayCompanionReceiver<R>
 where
@@ -59,7 +319,35 @@ where
 {
     /// Create a new GatewayCompanionReceiver from the provided reader.
     pub fn new(reader: R) -> Self {
-        Self { reader }
+        Self::from_frames(FrameReader::new(reader))
+    }
+
+    /// Carry on reading frames from a reader already read from.
+    fn from_frames(reader: FrameReader<R>) -> Self {
+        Self {
+            reader,
+            opener: None,
+            frame: Vec::new(),
+        }
+    }
+
+    /// Only take frames sealed for the session opener opens.
+    pub fn with_opener(mut self, opener: Opener) -> Self {
+        self.opener = Some(opener);
+        self
+    }
+
+    /// Receive a command with its image borrowed from the frame it arrived
+    /// in, for callers that can use it without taking ownership.
+    pub async fn receive_ref(&mut self) -> Result<BorrowedDeviceActions<'_>> {
+        let buf = std::mem::take(&mut self.frame);
+        self.frame = receive_intact(&mut self.reader, self.opener.as_mut(), buf).await?;
+        if let Some(inner) = compress::decompress(&self.frame, MAX_FRAME_LEN) {
+            self.frame = inner.map_err(wire_error)?;
+        }
+        let command = wire::decode_actions(&self.frame).map_err(wire_error)?;
+        trace!("GatewayCompanionReceiver::Receiver: {:?}", command);
+        Ok(command)
     }
 }
 
@@ -70,9 +358,7 @@ where
 {
     /// Receive a command from the reader and return it to the caller.
     async fn receive(&mut self) -> Result<DeviceActions> {
-        let command: DeviceActions = bin_comm::stream_utils::read_struct(&mut self.reader).await?;
-        trace!("GatewayCompanionReceiver::Receiver: {:?}", command);
-        Ok(command)
+        Ok(self.receive_ref().await?.into())
     }
 }
 
@@ -80,15 +366,42 @@ where
 /// operations are received from the provided reader, deserialized,
 /// and provided to the caller in the receive method.
 pub struct GatewayDeviceReceiver<R> {
-    reader: R,
+    reader: FrameReader<R>,
+    /// Opens the frames of an authenticated link
+    opener: Option<Opener>,
+    version: Arc<AtomicU8>,
+    /// A frame read before the receiver was made, returned first
+    pending: Option<Vec<u8>>,
 }
 impl<R> GatewayDeviceReceiver<R>
 where
     R: AsyncRead + Unpin + Send,
 {
-    /// Create a new GatewayDeviceReceiver from the provided reader.
+    /// Create a new GatewayDeviceReceiver from the provided reader, taking
+    /// frames in the [Format] the leaf writes in.
     pub fn new(reader: R) -> Self {
-        Self { reader }
+        Self::from_frames(FrameReader::detecting(reader))
+    }
+
+    /// Carry on reading frames from a reader already read from.
+    fn from_frames(reader: FrameReader<R>) -> Self {
+        Self {
+            reader,
+            opener: None,
+            version: Arc::new(AtomicU8::new(PROTOCOL_VERSION)),
+            pending: None,
+        }
+    }
+
+    /// Only take frames sealed for the session opener opens.
+    pub fn with_opener(mut self, opener: Opener) -> Self {
+        self.opener = Some(opener);
+        self
+    }
+
+    /// The wire version the leaf last wrote in, updated as frames arrive.
+    pub fn version(&self) -> Arc<AtomicU8> {
+        self.version.clone()
     }
 }
 
@@ -99,8 +412,14 @@ where
 {
     /// read the command from the provided reader and return it to the caller.
     async fn receive(&mut self) -> Result<leaf_comm::Command> {
-        let command: leaf_comm::Command =
-            bin_comm::stream_utils::read_struct(&mut self.reader).await?;
+        let frame = match self.pending.take() {
+            Some(frame) => frame,
+            None => receive_intact(&mut self.reader, self.opener.as_mut(), Vec::new()).await?,
+        };
+        let (version, command) = wire::decode_command(&frame).map_err(wire_error)?;
+        if self.version.swap(version, Ordering::Relaxed) != version {
+            debug!("Leaf writes wire version {}", version);
+        }
         trace!("GatewayDeviceReceiver::Receiver: {:?}", command);
         Ok(command)
     }
@@ -111,6 +430,10 @@ where
 /// writer.
 pub struct GatewayCompanionSender<W> {
     writer: W,
+    /// Seals the frames of an authenticated link
+    sealer: Option<Sealer>,
+    version: u8,
+    capabilities: Capabilities,
 }
 impl<W> GatewayCompanionSender<W>
 where
@@ -118,7 +441,39 @@ where
 {
     /// Create a new GatewayCompanionSender from the provided writer.
     pub fn new(writer: W) -> Self {
-        Self { writer }
+        Self {
+            writer,
+            sealer: None,
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::LEGACY,
+        }
+    }
+
+    /// Seal every frame with sealer.
+    pub fn with_sealer(mut self, sealer: Sealer) -> Self {
+        self.sealer = Some(sealer);
+        self
+    }
+
+    /// Write in version, the one agreed with the gateway.
+    pub fn with_version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Only send what a gateway with capabilities takes.
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Whether the gateway takes what needs capability, tracing it if not.
+    fn takes(&self, capability: Capabilities) -> bool {
+        let takes = self.capabilities.contains(capability);
+        if !takes {
+            trace!("Gateway doesn't take {:?}", capability);
+        }
+        takes
     }
 }
 
@@ -130,6 +485,8 @@ where
     async fn config(&mut self, config: leaf_comm::RemoteConfig) -> Result<()> {
         GatewayCompanionSender::send_companion_command(
             &mut self.writer,
+            self.sealer.as_mut(),
+            self.version,
             leaf_comm::Command::Config(config),
         )
         .await
@@ -137,6 +494,8 @@ where
     async fn button_change(&mut self, change: leaf_comm::ButtonChange) -> Result<()> {
         GatewayCompanionSender::send_companion_command(
             &mut self.writer,
+            self.sealer.as_mut(),
+            self.version,
             leaf_comm::Command::ButtonChange(change),
         )
         .await
@@ -144,17 +503,83 @@ where
     async fn encoder_twist(&mut self, twist: leaf_comm::EncoderTwist) -> Result<()> {
         GatewayCompanionSender::send_companion_command(
             &mut self.writer,
+            self.sealer.as_mut(),
+            self.version,
             leaf_comm::Command::EncoderTwist(twist),
         )
         .await
     }
+    async fn encoder_press(&mut self, press: leaf_comm::EncoderPress) -> Result<()> {
+        GatewayCompanionSender::send_companion_command(
+            &mut self.writer,
+            self.sealer.as_mut(),
+            self.version,
+            leaf_comm::Command::EncoderPress(press),
+        )
+        .await
+    }
+    async fn touch(&mut self, touch: leaf_comm::Touch) -> Result<()> {
+        GatewayCompanionSender::send_companion_command(
+            &mut self.writer,
+            self.sealer.as_mut(),
+            self.version,
+            leaf_comm::Command::Touch(touch),
+        )
+        .await
+    }
+    async fn power(&mut self, power: leaf_comm::PowerState) -> Result<()> {
+        if !self.takes(Capabilities::POWER) {
+            return Ok(());
+        }
+        GatewayCompanionSender::send_companion_command(
+            &mut self.writer,
+            self.sealer.as_mut(),
+            self.version,
+            leaf_comm::Command::Power(power),
+        )
+        .await
+    }
+    async fn status_image(&mut self, image: leaf_comm::StatusImage) -> Result<()> {
+        if !self.takes(Capabilities::STATUS_IMAGES) {
+            return Ok(());
+        }
+        GatewayCompanionSender::send_companion_command(
+            &mut self.writer,
+            self.sealer.as_mut(),
+            self.version,
+            leaf_comm::Command::StatusImage(image),
+        )
+        .await
+    }
+    async fn goodbye(&mut self, reason: leaf_comm::ShutdownReason) -> Result<()> {
+        if !self.takes(Capabilities::GOODBYE) {
+            return Ok(());
+        }
+        GatewayCompanionSender::send_companion_command(
+            &mut self.writer,
+            self.sealer.as_mut(),
+            self.version,
+            leaf_comm::Command::Goodbye(reason),
+        )
+        .await
+    }
+    /// Frames are flushed as they are written, so there is only the
+    /// connection to shut down.
+    async fn close(&mut self) -> Result<()> {
+        Ok(self.writer.shutdown().await?)
+    }
 }
 
 impl<W> GatewayCompanionSender<W>
 where
     W: AsyncWrite + Unpin + Send,
 {
-    async fn send_companion_command(stream: &mut W, command: leaf_comm::Command) -> Result<()>
+    async fn send_companion_command(
+        stream: &mut W,
+        sealer: Option<&mut Sealer>,
+        version: u8,
+        command: leaf_comm::Command,
+    ) -> Result<()>
     where
         W: AsyncWrite + Unpin,
     {
@@ -162,15 +587,31 @@ where
             "GatewayDeviceSender::send_companion_command: {:?}",
             command
         );
-        Ok(bin_comm::stream_utils::write_struct(stream, &command).await?)
+        let frame = wire::encode(version, &command).map_err(wire_error)?;
+        write_frame(stream, sealer, frame).await?;
+        Ok(())
     }
 }
 
 /// GatewayDeviceSender implements the device sender trait.  Methods
 /// called on the device sender are serialized and sent to the provided
-/// writer.
+/// writer.  Companion sends every image again on each refresh, so an image
+/// the button already shows isn't sent again.  Leaves that take
+/// [Capabilities::COMMIT] are sent images as prepared, to be shown once
+/// [commit](traits::device::Sender::commit) is called.
 pub struct GatewayDeviceSender<W> {
     writer: W,
+    /// Seals the frames of an authenticated link
+    sealer: Option<Sealer>,
+    version: Arc<AtomicU8>,
+    capabilities: Capabilities,
+    format: Format,
+    /// Hash of the image last sent to each button
+    sent: HashMap<u8, u64>,
+    /// Images were prepared since the last commit
+    uncommitted: bool,
+    /// Bytes written to the leaf, frames and all
+    bytes_sent: Arc<AtomicU64>,
 }
 impl<W> GatewayDeviceSender<W>
 where
@@ -178,8 +619,72 @@ where
 {
     /// Create a new GatewayDeviceSender from the provided writer.
     pub fn new(writer: W) -> Self {
-        Self { writer }
+        Self {
+            writer,
+            sealer: None,
+            version: Arc::new(AtomicU8::new(PROTOCOL_VERSION)),
+            capabilities: Capabilities::LEGACY,
+            format: Format::Checked,
+            sent: HashMap::new(),
+            uncommitted: false,
+            bytes_sent: Default::default(),
+        }
+    }
+
+    /// Write in the wire version the leaf is known to speak, usually the
+    /// [GatewayDeviceReceiver::version] of the same leaf.
+    pub fn with_version(mut self, version: Arc<AtomicU8>) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Only send what a leaf with capabilities takes.
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Lay frames out in format, the one the leaf writes in.
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Seal every frame with sealer.
+    pub fn with_sealer(mut self, sealer: Sealer) -> Self {
+        self.sealer = Some(sealer);
+        self
+    }
+
+    /// Add the bytes written to the leaf to counter, which can be read
+    /// from other tasks.
+    pub fn with_bytes_sent(mut self, counter: Arc<AtomicU64>) -> Self {
+        self.bytes_sent = counter;
+        self
     }
+
+    /// Whether the leaf takes what needs capability, tracing it if not.
+    fn takes(&self, capability: Capabilities) -> bool {
+        let takes = self.capabilities.contains(capability);
+        if !takes {
+            trace!("Leaf doesn't take {:?}", capability);
+        }
+        takes
+    }
+
+    /// Forget which images were sent, so each is sent again.  For when the
+    /// leaf may have lost what it shows, as after it reconnects; a sender
+    /// made for a new connection starts with nothing sent.
+    pub fn invalidate_all(&mut self) {
+        self.sent.clear();
+    }
+}
+
+/// Hash of the bytes of an image, to tell whether it changed.
+fn image_hash(image: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    image.hash(&mut hasher);
+    hasher.finish()
 }
 
 #[async_trait]
@@ -188,35 +693,117 @@ where
     W: AsyncWrite + Unpin + Send,
 {
     async fn set_brightness(&mut self, brightness: SetBrightness) -> Result<()> {
+        if !self.takes(Capabilities::BRIGHTNESS) {
+            return Ok(());
+        }
         GatewayDeviceSender::send_device_command(
             &mut self.writer,
+            (self.sealer.as_mut(), self.format),
+            self.version.load(Ordering::Relaxed),
             DeviceActions::SetBrightness(brightness),
+            false,
+            &self.bytes_sent,
         )
         .await
     }
     async fn set_button_image(&mut self, image: SetButtonImage) -> Result<()> {
+        let (button, hash) = (image.button, image_hash(&image.image));
+        if self.sent.get(&button) == Some(&hash) {
+            trace!("Button {} unchanged", button);
+            return Ok(());
+        }
+        // Forgotten until written whole, as a failed write may leave the
+        // leaf without it
+        self.sent.remove(&button);
+        let prepared = self.capabilities.contains(Capabilities::COMMIT);
+        let action = if prepared {
+            DeviceActions::PrepareButtonImage(image)
+        } else {
+            DeviceActions::SetButtonImage(image)
+        };
         GatewayDeviceSender::send_device_command(
             &mut self.writer,
-            DeviceActions::SetButtonImage(image),
+            (self.sealer.as_mut(), self.format),
+            self.version.load(Ordering::Relaxed),
+            action,
+            self.capabilities.contains(Capabilities::LZ4_IMAGES),
+            &self.bytes_sent,
         )
-        .await
+        .await?;
+        self.sent.insert(button, hash);
+        self.uncommitted |= prepared;
+        Ok(())
     }
     async fn set_lcd_image(&mut self, image: SetLCDImage) -> Result<()> {
+        if !self.takes(Capabilities::LCD_IMAGES) {
+            return Ok(());
+        }
         GatewayDeviceSender::send_device_command(
             &mut self.writer,
+            (self.sealer.as_mut(), self.format),
+            self.version.load(Ordering::Relaxed),
             DeviceActions::SetLCDImage(image),
+            self.capabilities.contains(Capabilities::LZ4_IMAGES),
+            &self.bytes_sent,
         )
         .await
     }
+    async fn commit(&mut self) -> Result<()> {
+        if !self.uncommitted {
+            return Ok(());
+        }
+        GatewayDeviceSender::send_device_command(
+            &mut self.writer,
+            (self.sealer.as_mut(), self.format),
+            self.version.load(Ordering::Relaxed),
+            DeviceActions::Commit,
+            false,
+            &self.bytes_sent,
+        )
+        .await?;
+        self.uncommitted = false;
+        Ok(())
+    }
+    /// Leaves take images already encoded for them, so they are blanked by
+    /// whoever knows their format, such as a `pumps::shutdown::BlankingSender`.
+    async fn clear(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Leaves that take [Capabilities::CLEAR_ALL] blank themselves, dropping
+    /// what they hold for a commit.  Either way every image is sent again
+    /// once set, as the leaf no longer shows it.
+    async fn clear_all(&mut self) -> Result<()> {
+        self.sent.clear();
+        if !self.takes(Capabilities::CLEAR_ALL) {
+            return Ok(());
+        }
+        GatewayDeviceSender::send_device_command(
+            &mut self.writer,
+            (self.sealer.as_mut(), self.format),
+            self.version.load(Ordering::Relaxed),
+            DeviceActions::ClearAll,
+            false,
+            &self.bytes_sent,
+        )
+        .await?;
+        self.uncommitted = false;
+        Ok(())
+    }
 }
 
 impl<W> GatewayDeviceSender<W>
 where
     W: AsyncWrite + Unpin + Send,
 {
+    /// Send command in the wire version given, compressed if asked and it
+    /// makes the frame smaller, counting the bytes written in bytes_sent.
     async fn send_device_command(
         satellite_write_stream: &mut W,
+        (sealer, format): (Option<&mut Sealer>, Format),
+        version: u8,
         command: DeviceActions,
+        compressed: bool,
+        bytes_sent: &AtomicU64,
     ) -> Result<()>
     where
         W: AsyncWrite + Unpin,
@@ -225,6 +812,431 @@ where
             "GatewayDeviceSender::send_device_command: {:?}",
             command
         );
-        Ok(bin_comm::stream_utils::write_struct(satellite_write_stream, &command).await?)
+        let mut frame = wire::encode(version, &command).map_err(wire_error)?;
+        if compressed {
+            frame = compress::compress(frame);
+        }
+        let len = write_frame_as(satellite_write_stream, sealer, format, frame).await?;
+        bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+/// Write frame to writer, sealed by sealer if the link is authenticated.
+/// Returns the bytes written, framing included.
+async fn write_frame(
+    writer: &mut (impl AsyncWrite + Unpin),
+    sealer: Option<&mut Sealer>,
+    frame: Vec<u8>,
+) -> Result<usize> {
+    write_frame_as(writer, sealer, Format::Checked, frame).await
+}
+
+/// Write frame to writer like [write_frame], laid out in format unless it
+/// is sealed.  Only leaves that checksum frames authenticate, so sealed
+/// frames are always [Format::Checked].
+async fn write_frame_as(
+    writer: &mut (impl AsyncWrite + Unpin),
+    sealer: Option<&mut Sealer>,
+    format: Format,
+    frame: Vec<u8>,
+) -> Result<usize> {
+    match sealer {
+        Some(sealer) => write_sealed(writer, sealer, frame).await,
+        None => {
+            let len = format.header_len() + frame.len() + format.trailer_len();
+            write_length_prefix_as(writer, frame, format).await?;
+            Ok(len)
+        }
+    }
+}
+
+fn wire_error(e: WireError) -> anyhow::Error {
+    anyhow::anyhow!("{e}")
+}
+
+/// Read the next frame from reader that arrives intact, into buf, opened by
+/// opener if the link is authenticated.  Frames refused as corrupt, or as
+/// too large for a damaged length, are skipped, as reading finds the next
+/// frame after them.  A frame that is intact but forged or replayed fails,
+/// as does any refused [Format::Legacy] frame, there being no next frame to
+/// find.
+async fn receive_intact(
+    reader: &mut FrameReader<impl AsyncRead + Unpin>,
+    mut opener: Option<&mut Opener>,
+    buf: Vec<u8>,
+) -> Result<Vec<u8>> {
+    let mut buf = Some(buf);
+    loop {
+        let buf = buf.take().unwrap_or_default();
+        let res = match opener.as_deref_mut() {
+            Some(opener) => read_sealed(reader, opener, buf).await,
+            None => reader.receive(buf).await.map_err(Into::into),
+        };
+        match res {
+            Err(e)
+                if reader.format() != Some(Format::Legacy)
+                    && e.downcast_ref().and_then(frame_error).is_some() =>
+            {
+                warn!("Skipping a frame: {}", e);
+            }
+            res => return res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use leaf_comm::framing::{CRC_LEN, HEADER_LEN};
+
+    #[tokio::test]
+    async fn test_leaves_authenticate_with_their_key() {
+        let key_of = |device_id: &str| (device_id == "deck").then_some(&b"secret"[..]);
+        for (device_id, key, ok) in [
+            ("deck", &b"secret"[..], true),
+            ("deck", b"guess", false),
+            ("other", b"secret", false),
+        ] {
+            let (mut gateway, leaf) = tokio::io::duplex(64);
+            let (mut reader, mut writer) = tokio::io::split(leaf);
+            let leaf = answer_challenge(&mut reader, &mut writer, device_id, key);
+            let (authenticated, answered) =
+                tokio::join!(authenticate_leaf(&mut gateway, key_of), leaf);
+            answered.unwrap();
+            let authenticated = authenticated.ok().map(|(device_id, _)| device_id);
+            assert_eq!(authenticated.as_deref(), ok.then_some(device_id));
+        }
+    }
+
+    /// The gateway end of a link a leaf authenticated as "deck", with the
+    /// writer of the leaf and the sealer of its frames.
+    async fn sealed_link() -> (
+        GatewayDeviceReceiver<tokio::io::ReadHalf<tokio::io::DuplexStream>>,
+        tokio::io::WriteHalf<tokio::io::DuplexStream>,
+        Sealer,
+    ) {
+        let key_of = |device_id: &str| (device_id == "deck").then_some(&b"secret"[..]);
+        let (mut gateway, leaf) = tokio::io::duplex(1024);
+        let (mut reader, mut writer) = tokio::io::split(leaf);
+        let gateway = async {
+            let (device_id, sealing) = authenticate_leaf(&mut gateway, key_of).await.unwrap();
+            assert_eq!(device_id, "deck");
+            let (reader, writer) = tokio::io::split(gateway);
+            greet_leaf(reader, writer, Some(sealing)).await.unwrap()
+        };
+        let leaf = async {
+            let Sealing {
+                mut sealer,
+                mut opener,
+            } = answer_challenge(&mut reader, &mut writer, "deck", b"secret")
+                .await
+                .unwrap();
+            let mut frames = FrameReader::new(&mut reader);
+            let hello = Hello::new(Capabilities::NONE);
+            let sealing = (Some(&mut sealer), Some(&mut opener));
+            say_hello(&mut frames, &mut writer, hello, sealing)
+                .await
+                .unwrap();
+            sealer
+        };
+        let ((_, receiver), sealer) = tokio::join!(gateway, leaf);
+        (receiver, writer, sealer)
+    }
+
+    #[tokio::test]
+    async fn test_replayed_and_forged_frames_refused() {
+        use traits::device::Receiver as _;
+
+        let press = |key| {
+            let command = leaf_comm::Command::ButtonChange(leaf_comm::ButtonChange {
+                buttons: vec![(key, true)],
+            });
+            wire::encode(PROTOCOL_VERSION, &command).unwrap()
+        };
+        let (mut receiver, mut writer, mut sealer) = sealed_link().await;
+        let mut captured = Vec::new();
+        write_frame(&mut captured, Some(&mut sealer), press(1))
+            .await
+            .unwrap();
+        writer.write_all(&captured).await.unwrap();
+        assert!(matches!(
+            receiver.receive().await.unwrap(),
+            leaf_comm::Command::ButtonChange(_)
+        ));
+        // Captured and sent again
+        writer.write_all(&captured).await.unwrap();
+        let e = receiver.receive().await.unwrap_err();
+        assert!(e.to_string().contains("replayed"), "{e}");
+
+        // Injected without the session key
+        let (mut receiver, mut writer, _) = sealed_link().await;
+        write_frame(&mut writer, None, press(2)).await.unwrap();
+        assert!(receiver.receive().await.is_err());
+        // A sealed frame given another key
+        let (mut receiver, mut writer, mut sealer) = sealed_link().await;
+        let mut envelope = sealer.seal(press(1)).unwrap();
+        envelope.payload = press(2);
+        bin_comm::stream_utils::write_struct(&mut writer, &envelope)
+            .await
+            .unwrap();
+        let e = receiver.receive().await.unwrap_err();
+        assert!(e.to_string().contains("failed its MAC"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn test_leaves_sent_what_they_take() {
+        use traits::device::{Receiver as _, Sender as _};
+
+        let (gateway, leaf) = tokio::io::duplex(1024);
+        let (gateway_reader, gateway_writer) = tokio::io::split(gateway);
+        let (reader, mut writer) = tokio::io::split(leaf);
+        let mut reader = FrameReader::new(reader);
+        let leaf = Hello::new(Capabilities::BRIGHTNESS);
+        let (greeted, answer) = tokio::join!(
+            greet_leaf(gateway_reader, gateway_writer, None),
+            say_hello(&mut reader, &mut writer, leaf, (None, None))
+        );
+        assert_eq!(answer.unwrap(), Hello::new(GATEWAY_CAPABILITIES));
+        let (mut sender, _) = greeted.unwrap();
+        let image = SetLCDImage {
+            x_offset: 0,
+            x_size: 100,
+            y_size: 100,
+            image: vec![0xff, 0xd8, 0xff, 0xd9],
+        };
+        sender.set_lcd_image(image).await.unwrap();
+        sender
+            .set_brightness(SetBrightness { brightness: 60 })
+            .await
+            .unwrap();
+        // The leaf has no LCD images to skip
+        let frame = reader.receive(Vec::new()).await.unwrap();
+        assert!(matches!(
+            wire::decode_actions(&frame).unwrap(),
+            BorrowedDeviceActions::SetBrightness(SetBrightness { brightness: 60 })
+        ));
+
+        // Leaves from before the hello start with their config, which isn't lost
+        let (gateway, leaf) = tokio::io::duplex(1024);
+        let (gateway_reader, gateway_writer) = tokio::io::split(gateway);
+        let config = leaf_comm::Command::Config(leaf_comm::RemoteConfig {
+            pid: 0x0084,
+            device_id: "deck".into(),
+            fingerprint: Default::default(),
+        });
+        let frame = wire::encode(wire::UNVERSIONED, &config).unwrap();
+        write_length_prefix(&mut tokio::io::split(leaf).1, frame)
+            .await
+            .unwrap();
+        let (sender, mut receiver) = greet_leaf(gateway_reader, gateway_writer, None)
+            .await
+            .unwrap();
+        assert!(matches!(
+            receiver.receive().await.unwrap(),
+            leaf_comm::Command::Config(_)
+        ));
+        assert_eq!(sender.capabilities, Capabilities::LEGACY);
+        assert_eq!(sender.version.load(Ordering::Relaxed), wire::UNVERSIONED);
+    }
+
+    #[tokio::test]
+    async fn test_leaves_from_before_checksums_answered_in_kind() {
+        use tokio::io::AsyncReadExt;
+        use traits::device::{Receiver as _, Sender as _};
+
+        let (gateway, mut leaf) = tokio::io::duplex(1024);
+        let (gateway_reader, gateway_writer) = tokio::io::split(gateway);
+        // The config and a key press of a leaf from before versions and
+        // checksums, each behind its bare length
+        let config = [0x00, 0x84, 0x01, 0x04, b'd', b'e', b'c', b'k'];
+        let press = [0x01, 0x01, 0x00, 0x01];
+        for frame in [&config[..], &press] {
+            leaf.write_all(&(frame.len() as u32).to_be_bytes())
+                .await
+                .unwrap();
+            leaf.write_all(frame).await.unwrap();
+        }
+        let (mut sender, mut receiver) = greet_leaf(gateway_reader, gateway_writer, None)
+            .await
+            .unwrap();
+        assert!(matches!(
+            receiver.receive().await.unwrap(),
+            leaf_comm::Command::Config(config) if config.device_id == "deck"
+        ));
+        assert!(matches!(
+            receiver.receive().await.unwrap(),
+            leaf_comm::Command::ButtonChange(change) if change.buttons == [(0, true)]
+        ));
+
+        // Answered bare, without a version or checksum
+        sender
+            .set_brightness(SetBrightness { brightness: 60 })
+            .await
+            .unwrap();
+        let brightness = DeviceActions::SetBrightness(SetBrightness { brightness: 60 });
+        let frame = wire::encode(wire::UNVERSIONED, &brightness).unwrap();
+        let mut read = vec![0; 4 + frame.len()];
+        leaf.read_exact(&mut read).await.unwrap();
+        assert_eq!(read[..4], (frame.len() as u32).to_be_bytes());
+        assert_eq!(read[4..], frame);
+    }
+
+    #[tokio::test]
+    async fn test_damaged_frames_skipped() {
+        use traits::device::Receiver as _;
+
+        let (mut leaf, gateway) = tokio::io::duplex(1024);
+        let mut receiver = GatewayDeviceReceiver::new(gateway);
+        let command = leaf_comm::Command::ButtonChange(leaf_comm::ButtonChange {
+            buttons: vec![(1, true)],
+        });
+        let frame = wire::encode(PROTOCOL_VERSION, &command).unwrap();
+        // A frame whose length is damaged past the longest frame
+        let mut damaged = Vec::new();
+        write_length_prefix(&mut damaged, &frame).await.unwrap();
+        damaged[2] ^= 0x80;
+        leaf.write_all(&damaged).await.unwrap();
+        write_length_prefix(&mut leaf, frame).await.unwrap();
+        assert!(matches!(
+            receiver.receive().await.unwrap(),
+            leaf_comm::Command::ButtonChange(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_images_compressed_for_leaves_that_take_them() {
+        use traits::companion::Receiver as _;
+        use traits::device::Sender as _;
+
+        let (gateway, leaf) = tokio::io::duplex(1024);
+        let (gateway_reader, gateway_writer) = tokio::io::split(gateway);
+        let (reader, mut writer) = tokio::io::split(leaf);
+        let mut reader = FrameReader::new(reader);
+        let leaf = Hello::new(Capabilities::LZ4_IMAGES);
+        let (greeted, answer) = tokio::join!(
+            greet_leaf(gateway_reader, gateway_writer, None),
+            say_hello(&mut reader, &mut writer, leaf, (None, None))
+        );
+        answer.unwrap();
+        let (mut sender, _) = greeted.unwrap();
+        let image = vec![0x42; 72 * 72 * 3];
+        sender
+            .set_button_image(SetButtonImage {
+                button: 3,
+                image: image.clone(),
+            })
+            .await
+            .unwrap();
+        // Far smaller than the image, and too large for the duplex otherwise
+        let frame = reader.receive(Vec::new()).await.unwrap();
+        assert!(frame.starts_with(&compress::COMPRESSED_HEADER));
+        assert!(frame.len() < 1024);
+
+        // Read back as any other frame
+        let (mut gateway, leaf) = tokio::io::duplex(1024);
+        write_length_prefix(&mut gateway, frame).await.unwrap();
+        let mut receiver = GatewayCompanionReceiver::new(leaf);
+        match receiver.receive().await.unwrap() {
+            DeviceActions::SetButtonImage(received) => {
+                assert_eq!((received.button, received.image), (3, image))
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_images_prepared_until_committed() {
+        use traits::device::Sender as _;
+
+        let (gateway, mut leaf) = tokio::io::duplex(1024);
+        let mut sender = GatewayDeviceSender::new(gateway)
+            .with_capabilities(Capabilities::LEGACY | Capabilities::COMMIT);
+        // Nothing to commit yet
+        sender.commit().await.unwrap();
+        for button in [0, 1] {
+            let image = SetButtonImage {
+                button,
+                image: vec![button; 16],
+            };
+            sender.set_button_image(image).await.unwrap();
+        }
+        sender.commit().await.unwrap();
+        sender.commit().await.unwrap();
+        drop(sender);
+
+        let mut sent = Vec::new();
+        while let Ok(frame) = receive_length_prefix(&mut leaf, Vec::new()).await {
+            sent.push(match wire::decode_actions(&frame).unwrap() {
+                BorrowedDeviceActions::PrepareButtonImage(image) => Some(image.button),
+                BorrowedDeviceActions::Commit => None,
+                other => panic!("unexpected {other:?}"),
+            });
+        }
+        assert_eq!(sent, [Some(0), Some(1), None]);
+    }
+
+    #[tokio::test]
+    async fn test_clear_all_sent_to_leaves_taking_it() {
+        use traits::device::Sender as _;
+
+        let (gateway, mut leaf) = tokio::io::duplex(1024);
+        let mut sender = GatewayDeviceSender::new(gateway).with_capabilities(
+            Capabilities::LEGACY | Capabilities::COMMIT | Capabilities::CLEAR_ALL,
+        );
+        let image = SetButtonImage {
+            button: 0,
+            image: vec![1; 16],
+        };
+        sender.set_button_image(image.clone()).await.unwrap();
+        sender.clear_all().await.unwrap();
+        // Dropped by the leaf along with the blanking
+        sender.commit().await.unwrap();
+        // No longer shown, so sent again
+        sender.set_button_image(image).await.unwrap();
+        drop(sender);
+
+        let mut sent = Vec::new();
+        while let Ok(frame) = receive_length_prefix(&mut leaf, Vec::new()).await {
+            sent.push(match wire::decode_actions(&frame).unwrap() {
+                BorrowedDeviceActions::PrepareButtonImage(image) => Some(image.button),
+                BorrowedDeviceActions::ClearAll => None,
+                other => panic!("unexpected {other:?}"),
+            });
+        }
+        assert_eq!(sent, [Some(0), None, Some(0)]);
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_images_not_sent_again() {
+        use traits::device::Sender as _;
+
+        let (gateway, mut leaf) = tokio::io::duplex(1024);
+        let bytes_sent = Arc::new(AtomicU64::new(0));
+        let mut sender = GatewayDeviceSender::new(gateway).with_bytes_sent(bytes_sent.clone());
+        let image = |button, byte| SetButtonImage {
+            button,
+            image: vec![byte; 16],
+        };
+        for (button, byte) in [(0, 1), (1, 1), (0, 1), (0, 2), (1, 1)] {
+            sender.set_button_image(image(button, byte)).await.unwrap();
+        }
+        sender.invalidate_all();
+        sender.set_button_image(image(1, 1)).await.unwrap();
+        drop(sender);
+
+        let (mut sent, mut bytes) = (Vec::new(), 0);
+        while let Ok(frame) = receive_length_prefix(&mut leaf, Vec::new()).await {
+            bytes += HEADER_LEN + frame.len() + CRC_LEN;
+            match wire::decode_actions(&frame).unwrap() {
+                BorrowedDeviceActions::SetButtonImage(image) => {
+                    sent.push((image.button, image.image[0]))
+                }
+                other => panic!("unexpected {other:?}"),
+            }
+        }
+        assert_eq!(sent, [(0, 1), (1, 1), (0, 2), (1, 1)]);
+        // Only what was written is counted
+        assert_eq!(bytes_sent.load(Ordering::Relaxed), bytes as u64);
     }
 }
@@ -0,0 +1,460 @@
+//! Links to leaves over QUIC, for leaves on networks that lose packets.
+//!
+//! Over TCP a lost packet holds up everything written after it, so a large
+//! image caught by a flaky Wi-Fi link stalls brightness changes and key
+//! presses too.  QUIC streams are delivered independently, so here the images
+//! of each button go on a stream of their own, opened by the gateway the
+//! first time the button is set, and so do images for the LCD strip.
+//! Everything else goes either way on the control stream the leaf opens,
+//! which the handshake runs on as it does over TCP.  A stalled image holds up
+//! only the images after it on the same button.
+//!
+//! Streams keep no order between them, so images are set as they arrive
+//! rather than prepared for a commit.  Once the leaf authenticated, the
+//! frames of each stream are sealed with a session nonce of their own.
+//!
+//! The gateway shows leaves a certificate, which leaves have to be given to
+//! trust it; [self_signed] makes one.
+//!
+//! ```no_run
+//! # async fn example() -> traits::Result<()> {
+//! use gateway_devices::quic::{accept_leaf, device_from_leaf, gateway_endpoint, self_signed};
+//!
+//! let (cert, key) = self_signed(vec!["gateway.local".into()])?;
+//! let endpoint = gateway_endpoint("0.0.0.0:9999".parse()?, cert, key)?;
+//! while let Some(incoming) = endpoint.accept().await {
+//!     let leaf = accept_leaf(incoming).await?;
+//!     let (sender, receiver) = device_from_leaf(leaf, None).await?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use quinn::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use quinn::rustls::RootCertStore;
+use quinn::{
+    ClientConfig, Connection, Endpoint, Incoming, RecvStream, SendStream, ServerConfig,
+    TransportConfig,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::debug;
+use traits::{
+    anyhow, async_trait,
+    device::{DeviceActions, SetBrightness, SetButtonImage, SetLCDImage},
+    Result,
+};
+
+use crate::{
+    greet_leaf, open_link, GatewayCompanionReceiver, GatewayCompanionSender, GatewayDeviceReceiver,
+    GatewayDeviceSender, Sealing,
+};
+use bin_comm::replay::{Opener, Sealer};
+use leaf_comm::hello::Capabilities;
+
+/// Written by the leaf to open the control stream, as a QUIC stream isn't
+/// seen by the other end until something is written to it.
+const CONTROL: u8 = 0xc0;
+
+/// The stream of the LCD strip.  Streams below it are those of the button
+/// with their number.
+const LCD_STREAM: u16 = 0x100;
+
+/// Actions read from the streams of the gateway but not yet received.
+const ACTIONS_QUEUED: usize = 16;
+
+/// How often an idle link is pinged, well within the idle timeout.
+const KEEP_ALIVE: Duration = Duration::from_secs(5);
+
+/// A certificate for names, with its private key, for a gateway without one.
+pub fn self_signed(
+    names: Vec<String>,
+) -> Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+    let certified = rcgen::generate_simple_self_signed(names)?;
+    let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+    Ok((certified.cert.der().clone(), key.into()))
+}
+
+/// The endpoint a gateway accepts QUIC leaves on at addr, showing them cert.
+pub fn gateway_endpoint(
+    addr: SocketAddr,
+    cert: CertificateDer<'static>,
+    key: PrivateKeyDer<'static>,
+) -> Result<Endpoint> {
+    let config = ServerConfig::with_single_cert(vec![cert], key)?;
+    Ok(Endpoint::server(config, addr)?)
+}
+
+/// The control stream of a leaf, which the handshake runs on.
+pub struct Control {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl AsyncRead for Control {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Control {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+/// A leaf connected over QUIC, whose control stream is open.
+pub struct QuicLeaf {
+    connection: Connection,
+    /// The control stream, for [authenticate_leaf](crate::authenticate_leaf)
+    pub control: Control,
+}
+
+impl QuicLeaf {
+    /// The address the leaf connected from.
+    pub fn remote_address(&self) -> SocketAddr {
+        self.connection.remote_address()
+    }
+}
+
+/// Accept the connection of a leaf and the control stream it opens.
+pub async fn accept_leaf(incoming: Incoming) -> Result<QuicLeaf> {
+    let connection = incoming.await?;
+    let (send, mut recv) = connection.accept_bi().await?;
+    let opened = recv.read_u8().await?;
+    anyhow::ensure!(opened == CONTROL, "Leaf opened stream {opened:#x}");
+    Ok(QuicLeaf {
+        connection,
+        control: Control { send, recv },
+    })
+}
+
+/// Create a set of devices objects for a leaf connected over QUIC, like
+/// [device_from_socket](crate::device_from_socket).
+pub async fn device_from_leaf(
+    leaf: QuicLeaf,
+    sealing: Option<Sealing>,
+) -> Result<(QuicDeviceSender, GatewayDeviceReceiver<RecvStream>)> {
+    let QuicLeaf {
+        connection,
+        control: Control { send, recv },
+    } = leaf;
+    let sealer = sealing.as_ref().map(|sealing| sealing.sealer.clone());
+    let (control, receiver) = greet_leaf(recv, send, sealing).await?;
+    let sender = QuicDeviceSender {
+        connection,
+        control,
+        streams: HashMap::new(),
+        sealer,
+    };
+    Ok((sender, receiver))
+}
+
+/// The device sender of a leaf connected over QUIC.  Images go on the
+/// stream of the button or LCD strip they are for, the rest on the control
+/// stream.
+pub struct QuicDeviceSender {
+    connection: Connection,
+    control: GatewayDeviceSender<SendStream>,
+    /// The stream of each button, and of the LCD strip, set so far
+    streams: HashMap<u16, GatewayDeviceSender<SendStream>>,
+    /// Seals the control stream, which the other streams are sealed from
+    sealer: Option<Sealer>,
+}
+
+impl QuicDeviceSender {
+    /// Add the bytes written to the leaf to counter, which can be read
+    /// from other tasks.
+    pub fn with_bytes_sent(mut self, counter: Arc<AtomicU64>) -> Self {
+        for sender in self.streams.values_mut() {
+            sender.bytes_sent = counter.clone();
+        }
+        self.control = self.control.with_bytes_sent(counter);
+        self
+    }
+
+    /// Forget which images were sent, so each is sent again, as
+    /// [GatewayDeviceSender::invalidate_all].
+    pub fn invalidate_all(&mut self) {
+        for sender in self.streams.values_mut() {
+            sender.invalidate_all();
+        }
+    }
+
+    /// The sender of stream, opening it the first time.
+    async fn stream(&mut self, stream: u16) -> Result<&mut GatewayDeviceSender<SendStream>> {
+        let entry = match self.streams.entry(stream) {
+            Entry::Occupied(entry) => return Ok(entry.into_mut()),
+            Entry::Vacant(entry) => entry,
+        };
+        let mut writer = self.connection.open_uni().await?;
+        writer.write_u16(stream).await?;
+        debug!("Opened stream {:#x}", stream);
+        let control = &self.control;
+        let mut sender = GatewayDeviceSender::new(writer)
+            .with_version(control.version.clone())
+            .with_capabilities(Capabilities(
+                control.capabilities.0 & !Capabilities::COMMIT.0,
+            ))
+            .with_bytes_sent(control.bytes_sent.clone());
+        sender.sealer = self
+            .sealer
+            .as_ref()
+            .map(|sealer| sealer.for_stream(sealed(stream)));
+        Ok(entry.insert(sender))
+    }
+}
+
+/// The stream stream is sealed for, the sealing of the link itself being
+/// that of the control stream.
+fn sealed(stream: u16) -> u64 {
+    u64::from(stream) + 1
+}
+
+#[async_trait]
+impl traits::device::Sender for QuicDeviceSender {
+    async fn set_brightness(&mut self, brightness: SetBrightness) -> Result<()> {
+        self.control.set_brightness(brightness).await
+    }
+    async fn set_button_image(&mut self, image: SetButtonImage) -> Result<()> {
+        let stream = self.stream(u16::from(image.button)).await?;
+        stream.set_button_image(image).await
+    }
+    async fn set_lcd_image(&mut self, image: SetLCDImage) -> Result<()> {
+        if !self.control.takes(Capabilities::LCD_IMAGES) {
+            return Ok(());
+        }
+        let stream = self.stream(LCD_STREAM).await?;
+        stream.set_lcd_image(image).await
+    }
+    /// Images are set as they arrive, so there is nothing to commit.
+    async fn commit(&mut self) -> Result<()> {
+        Ok(())
+    }
+    async fn clear(&mut self) -> Result<()> {
+        self.control.clear().await
+    }
+    async fn clear_all(&mut self) -> Result<()> {
+        self.invalidate_all();
+        self.control.clear_all().await
+    }
+}
+
+/// Connect to the gateway at addr over QUIC like
+/// [connect_to_gateway_with_key](crate::connect_to_gateway_with_key),
+/// trusting the gateway if it shows gateway_cert for server_name.
+pub async fn connect_to_gateway_quic(
+    addr: SocketAddr,
+    server_name: &str,
+    gateway_cert: CertificateDer<'static>,
+    device_id: &str,
+    key: Option<&[u8]>,
+    capabilities: Capabilities,
+) -> Result<(GatewayCompanionSender<SendStream>, QuicCompanionReceiver)> {
+    let mut roots = RootCertStore::empty();
+    roots.add(gateway_cert)?;
+    let mut transport = TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE));
+    let mut config = ClientConfig::with_root_certificates(Arc::new(roots))?;
+    config.transport_config(Arc::new(transport));
+
+    let local: SocketAddr = if addr.is_ipv6() {
+        (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+    } else {
+        (std::net::Ipv4Addr::UNSPECIFIED, 0).into()
+    };
+    let endpoint = Endpoint::client(local)?;
+    let connection = endpoint.connect_with(config, addr, server_name)?.await?;
+    let (mut send, recv) = connection.open_bi().await?;
+    send.write_u8(CONTROL).await?;
+    let (sender, control) = open_link(recv, send, device_id, key, capabilities).await?;
+
+    let opener = control.opener.clone();
+    let (actions, receiver) = mpsc::channel(ACTIONS_QUEUED);
+    let mut readers = JoinSet::new();
+    readers.spawn(forward(control, actions.clone()));
+    readers.spawn(accept_streams(connection, opener, actions));
+    let receiver = QuicCompanionReceiver {
+        actions: receiver,
+        _readers: readers,
+        _endpoint: endpoint,
+    };
+    Ok((sender, receiver))
+}
+
+/// The companion receiver of a leaf connected over QUIC, taking commands
+/// from every stream of the gateway as they arrive.
+pub struct QuicCompanionReceiver {
+    actions: mpsc::Receiver<Result<DeviceActions>>,
+    /// Read the streams into actions, until dropped
+    _readers: JoinSet<()>,
+    /// Kept for as long as the link is read
+    _endpoint: Endpoint,
+}
+
+#[async_trait]
+impl traits::companion::Receiver for QuicCompanionReceiver {
+    async fn receive(&mut self) -> Result<DeviceActions> {
+        self.actions
+            .recv()
+            .await
+            .unwrap_or_else(|| Err(anyhow::anyhow!("Gateway link closed")))
+    }
+}
+
+/// Accept the streams the gateway opens, forwarding what each carries to
+/// actions, opened by opener if the leaf authenticated.
+async fn accept_streams(
+    connection: Connection,
+    opener: Option<Opener>,
+    actions: mpsc::Sender<Result<DeviceActions>>,
+) {
+    // Dropped with this task, so are the streams
+    let mut streams = JoinSet::new();
+    loop {
+        let mut reader = match connection.accept_uni().await {
+            Ok(reader) => reader,
+            Err(e) => {
+                let _ = actions.send(Err(e.into())).await;
+                return;
+            }
+        };
+        let opener = opener.clone();
+        let actions = actions.clone();
+        streams.spawn(async move {
+            let stream = match reader.read_u16().await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    let _ = actions.send(Err(e.into())).await;
+                    return;
+                }
+            };
+            debug!("Gateway opened stream {:#x}", stream);
+            let mut receiver = GatewayCompanionReceiver::new(reader);
+            receiver.opener = opener.map(|opener| opener.for_stream(sealed(stream)));
+            forward(receiver, actions).await
+        });
+    }
+}
+
+/// Forward what receiver receives to actions, until it fails or nothing
+/// takes them.
+async fn forward(
+    mut receiver: GatewayCompanionReceiver<RecvStream>,
+    actions: mpsc::Sender<Result<DeviceActions>>,
+) {
+    loop {
+        let action = traits::companion::Receiver::receive(&mut receiver).await;
+        let failed = action.is_err();
+        if actions.send(action).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traits::companion::{Receiver as _, Sender as _};
+    use traits::device::{Receiver as _, Sender as _};
+
+    #[tokio::test]
+    async fn test_images_on_streams_of_their_own() {
+        let (cert, key) = self_signed(vec!["localhost".into()]).unwrap();
+        let endpoint = gateway_endpoint("127.0.0.1:0".parse().unwrap(), cert.clone(), key).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        let key_of = |device_id: &str| (device_id == "deck").then_some(&b"secret"[..]);
+        let gateway = async {
+            let mut leaf = accept_leaf(endpoint.accept().await.unwrap()).await.unwrap();
+            let (device_id, sealing) = crate::authenticate_leaf(&mut leaf.control, key_of)
+                .await
+                .unwrap();
+            assert_eq!(device_id, "deck");
+            device_from_leaf(leaf, Some(sealing)).await.unwrap()
+        };
+        let capabilities = Capabilities(Capabilities::BRIGHTNESS.0 | Capabilities::COMMIT.0);
+        let leaf = connect_to_gateway_quic(
+            addr,
+            "localhost",
+            cert,
+            "deck",
+            Some(b"secret"),
+            capabilities,
+        );
+        let ((mut sender, mut receiver), leaf) = tokio::join!(gateway, leaf);
+        let (mut companion_sender, mut companion_receiver) = leaf.unwrap();
+
+        for (button, shade) in [(0, 1), (1, 2), (0, 3)] {
+            let image = SetButtonImage {
+                button,
+                image: vec![shade; 1000],
+            };
+            sender.set_button_image(image).await.unwrap();
+        }
+        sender
+            .set_brightness(SetBrightness { brightness: 50 })
+            .await
+            .unwrap();
+        assert_eq!(sender.streams.len(), 2);
+
+        let mut received = Vec::new();
+        for _ in 0..4 {
+            received.push(companion_receiver.receive().await.unwrap());
+        }
+        // Only the order within a stream is kept
+        let images: Vec<_> = received
+            .iter()
+            .filter_map(|action| match action {
+                DeviceActions::SetButtonImage(image) => Some((image.button, image.image[0])),
+                DeviceActions::PrepareButtonImage(_) => panic!("Images are set as they arrive"),
+                _ => None,
+            })
+            .collect();
+        let shades = |button| {
+            images
+                .iter()
+                .filter_map(|&(b, shade)| (b == button).then_some(shade))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(shades(0), [1, 3]);
+        assert_eq!(shades(1), [2]);
+        assert!(received
+            .iter()
+            .any(|action| matches!(action, DeviceActions::SetBrightness(b) if b.brightness == 50)));
+
+        let change = leaf_comm::ButtonChange {
+            buttons: vec![(3, true)],
+        };
+        companion_sender.button_change(change).await.unwrap();
+        assert!(matches!(
+            receiver.receive().await.unwrap(),
+            leaf_comm::Command::ButtonChange(change) if change.buttons == [(3, true)]
+        ));
+    }
+}
@@ -1,33 +1,142 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
 use leaf::Result;
 use clap::Parser;
-use tracing::info;
+use leaf_comm::hello::Capabilities;
+use streamdeck::selector::{DeviceSelector, Kind};
+use tracing::{info, warn};
+
+/// What the leaf takes from the gateway.  The deck doesn't write to its LCD
+/// strip, so the gateway is spared sending it images.  Key images are taken
+/// compressed, and held until committed so page switches are shown at once.
+/// The deck blanks itself when told, rather than being sent blank images.
+const CAPABILITIES: Capabilities = Capabilities(
+    Capabilities::BRIGHTNESS.0
+        | Capabilities::LZ4_IMAGES.0
+        | Capabilities::COMMIT.0
+        | Capabilities::CLEAR_ALL.0,
+);
 
 /// Command line options for a leaf program
 #[derive(Parser)]
+#[command(args_override_self = true)]
 pub struct Cli {
-    /// IP address of the gateway
+    /// TOML file of options, keyed by their long names, for the command
+    /// line to override
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// IP address of the gateway.  Found over mDNS if built with discovery
+    /// and not provided.
     #[arg(long)]
-    pub gateway_host: String,
+    #[cfg_attr(not(feature = "discovery"), arg(required_unless_present = "probe"))]
+    #[cfg_attr(feature = "discovery", arg(requires = "gateway_port"))]
+    pub gateway_host: Option<String>,
     /// Port number of the gateway
     #[arg(short, long)]
-    pub gateway_port: u16,
+    #[cfg_attr(not(feature = "discovery"), arg(required_unless_present = "probe"))]
+    #[cfg_attr(feature = "discovery", arg(requires = "gateway_host"))]
+    pub gateway_port: Option<u16>,
+    /// host:port of a secondary gateway, connected to whenever the gateway
+    /// can't be
+    #[arg(long)]
+    pub secondary_gateway: Option<String>,
+    /// Print a report of the attached devices, to attach to bug reports,
+    /// and exit
+    #[arg(long)]
+    pub probe: bool,
+    /// Pre-shared key of the device, answering the challenge of gateways
+    /// that authenticate leaves
+    #[arg(long)]
+    pub key: Option<String>,
+    /// Milliseconds encoder twists are summed for before being sent
+    #[arg(long)]
+    #[clap(default_value = "30")]
+    pub twist_window_ms: u64,
+    /// Only open a deck of this kind, such as "Plus" or "Mk2"
+    #[arg(long, value_parser = streamdeck::selector::parse_kind)]
+    pub deck_kind: Option<Kind>,
+    /// Only open a deck whose serial matches this glob, such as "CL31*"
+    #[arg(long)]
+    pub deck_serial: Option<String>,
+    /// Only open the deck plugged into this USB path, as listed by --probe,
+    /// to tell apart decks whose serials are alike
+    #[arg(long)]
+    pub deck_usb_path: Option<String>,
+    /// Write logs as JSON lines, for log collectors to ingest, rather than
+    /// as text
+    #[arg(long)]
+    pub log_json: bool,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    let args = Cli::parse_from(traits::config::args("leaf", std::env::args_os())?);
+    if args.log_json {
+        tracing_subscriber::fmt().json().init();
+    } else {
+        tracing_subscriber::fmt::init();
+    }
+    if args.probe {
+        let report = streamdeck::probe::probe().await?;
+        print!("{report}");
+        traits::anyhow::ensure!(report.healthy(), "The probe found problems");
+        return Ok(());
+    }
+    let gateway_address = args.gateway_host.zip(args.gateway_port);
 
-    let args = Cli::parse();
+    let selector = DeviceSelector {
+        kind: args.deck_kind,
+        serial_glob: args.deck_serial.clone(),
+        usb_path: args.deck_usb_path.clone(),
+    };
+    let (sender, receiver) = streamdeck::StreamDeck::open(&selector).await?;
+    let receiver = receiver.with_twist_window(Duration::from_millis(args.twist_window_ms));
 
-    pumps::create_and_run(streamdeck::StreamDeck::open_first, move |_| {
-        let hostport = (args.gateway_host.clone(), args.gateway_port);
-        async {
-            info!("Connecting to gateway: {}:{}", hostport.0, hostport.1);
-            let (leaf_sender, leaf_receiver) = gateway_devices::connect_to_gateway(hostport).await?;
-            info!("Connected to gateway");
-            Ok((leaf_sender, leaf_receiver))
-        }
-    })
+    // The deck stays open while the gateway comes and goes, and is blanked on
+    // ctrl-c
+    pumps::run_with_reconnect_until(
+        (sender, receiver),
+        move |config| {
+            let gateway_address = gateway_address.clone();
+            let secondary = args.secondary_gateway.clone();
+            let device_id = config.device_id.clone();
+            let key = args.key.clone();
+            async move {
+                let key = key.as_deref().map(str::as_bytes);
+                let hostport = match gateway_address {
+                    Some((host, port)) => format!("{host}:{port}"),
+                    #[cfg(feature = "discovery")]
+                    None => {
+                        use pumps::discovery::{discover, Role, DEFAULT_TIMEOUT};
+                        discover(Role::Gateway, DEFAULT_TIMEOUT).await?.to_string()
+                    }
+                    // clap requires both unless probing
+                    #[cfg(not(feature = "discovery"))]
+                    None => unreachable!("gateway address is required"),
+                };
+                info!("Connecting to gateway: {}", hostport);
+                let (leaf_sender, leaf_receiver) =
+                    match gateway_devices::connect_to_gateway_with_key(hostport, &device_id, key, CAPABILITIES)
+                        .await
+                    {
+                        Ok(connection) => connection,
+                        Err(e) => {
+                            let Some(secondary) = secondary else {
+                                return Err(e);
+                            };
+                            warn!("Gateway unreachable ({}), trying {}", e, secondary);
+                            gateway_devices::connect_to_gateway_with_key(secondary, &device_id, key, CAPABILITIES)
+                                .await?
+                        }
+                    };
+                info!("Connected to gateway");
+                Ok((leaf_sender, leaf_receiver))
+            }
+        },
+        Default::default(),
+        pumps::shutdown::ShutdownHandle::new().on_ctrl_c(),
+    )
     .await?;
 
     Ok(())
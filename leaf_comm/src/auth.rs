@@ -0,0 +1,171 @@
+//! Proof that a leaf holds the pre-shared key of its device id.
+//!
+//! Before a leaf sends its config, the gateway sends it a [Challenge] with a
+//! fresh nonce.  The leaf answers with an [Auth] naming its device id and
+//! carrying a fresh nonce of its own, and an HMAC-SHA256, keyed with the key
+//! of the device, over both nonces and the device id.  The gateway checks the
+//! MAC against the key it has for the device, so the key itself never
+//! crosses the network and a captured answer is no good for a later
+//! challenge.
+//!
+//! Proving the key once doesn't stop frames being injected afterwards, so
+//! both sides go on to derive [SessionKeys] from the key and both nonces,
+//! with which every later frame of the connection is sealed.
+//!
+//! Both messages are written with the [header](crate::wire::HEADER) of
+//! [PROTOCOL_VERSION], as only leaves built since versioning authenticate.
+
+use alloc::string::String;
+use core::fmt;
+
+use hmac::{Hmac, Mac as _};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::wire::{self, WireError, PROTOCOL_VERSION};
+
+/// Bytes of nonce in a [Challenge].
+pub const NONCE_LEN: usize = 16;
+
+/// An HMAC-SHA256.
+pub type Mac = [u8; 32];
+
+/// Sent by the gateway as a leaf connects, before anything else.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Challenge {
+    /// Random bytes, never reused by the gateway
+    pub nonce: [u8; NONCE_LEN],
+}
+
+/// The answer of a leaf to a [Challenge].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Auth {
+    /// The device id the leaf will send in its config
+    pub device_id: String,
+    /// Random bytes of the leaf, never reused by it
+    pub nonce: [u8; NONCE_LEN],
+    /// HMAC of both nonces and the device id, keyed with the key of the
+    /// device
+    pub mac: Mac,
+}
+
+/// The keys frames are sealed with once a leaf authenticated.  Each
+/// direction has a key of its own, so a frame can't be sent back to the side
+/// that sealed it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SessionKeys {
+    /// Binds the frames of a connection to it
+    pub session_nonce: u64,
+    /// Key of the frames the gateway sends
+    pub to_leaf: Mac,
+    /// Key of the frames the leaf sends
+    pub to_gateway: Mac,
+}
+
+/// Keys stay out of logs.
+impl fmt::Debug for SessionKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionKeys")
+            .field("session_nonce", &self.session_nonce)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Auth {
+    /// Answer challenge as device_id, holding key, with nonce of the leaf.
+    pub fn new(challenge: &Challenge, nonce: [u8; NONCE_LEN], device_id: &str, key: &[u8]) -> Self {
+        Self {
+            device_id: device_id.into(),
+            nonce,
+            mac: keyed(key, b"auth", challenge, &nonce, device_id),
+        }
+    }
+
+    /// Whether the answer was made for challenge with key.  The MAC is
+    /// compared in constant time.
+    pub fn verify(&self, challenge: &Challenge, key: &[u8]) -> bool {
+        hmac(key, b"auth", challenge, &self.nonce, &self.device_id)
+            .verify_slice(&self.mac)
+            .is_ok()
+    }
+
+    /// The keys of the session this answer to challenge opens, for both
+    /// sides holding key.  Only to be used once the answer verified.
+    pub fn session(&self, challenge: &Challenge, key: &[u8]) -> SessionKeys {
+        let session = |label| keyed(key, label, challenge, &self.nonce, &self.device_id);
+        let nonce = session(b"session");
+        SessionKeys {
+            session_nonce: u64::from_be_bytes(*nonce.first_chunk().unwrap()),
+            to_leaf: session(b"to leaf"),
+            to_gateway: session(b"to gateway"),
+        }
+    }
+}
+
+/// The HMAC of label, both nonces and device_id keyed with key.
+fn keyed(key: &[u8], label: &[u8], challenge: &Challenge, nonce: &[u8], device_id: &str) -> Mac {
+    hmac(key, label, challenge, nonce, device_id)
+        .finalize()
+        .into_bytes()
+        .into()
+}
+
+/// An HMAC keyed with key, fed label, both nonces and device_id.  The label
+/// keeps the MAC of an [Auth] and the keys of its session apart.
+fn hmac(
+    key: &[u8],
+    label: &[u8],
+    challenge: &Challenge,
+    nonce: &[u8],
+    device_id: &str,
+) -> Hmac<Sha256> {
+    // HMAC takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes any key");
+    mac.update(label);
+    mac.update(&challenge.nonce);
+    mac.update(nonce);
+    mac.update(device_id.as_bytes());
+    mac
+}
+
+/// Decode a [Challenge] or [Auth] frame.
+pub fn decode<'a, T: Deserialize<'a>>(frame: &'a [u8]) -> Result<T, WireError> {
+    match wire::split(frame) {
+        (PROTOCOL_VERSION, message) => Ok(postcard::from_bytes(message)?),
+        (version, _) => Err(WireError::Version(version)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_auth_only_verifies_with_its_key_and_challenge() {
+        let challenge = Challenge {
+            nonce: [7; NONCE_LEN],
+        };
+        let auth = Auth::new(&challenge, [9; NONCE_LEN], "deck", b"secret");
+        assert!(auth.verify(&challenge, b"secret"));
+        assert!(!auth.verify(&challenge, b"guess"));
+        let other = Challenge {
+            nonce: [8; NONCE_LEN],
+        };
+        assert!(!auth.verify(&other, b"secret"));
+
+        // The device id is covered by the MAC
+        let renamed = Auth {
+            device_id: "other".into(),
+            ..auth
+        };
+        assert!(!renamed.verify(&challenge, b"secret"));
+
+        // Sessions differ with either nonce, and keys with the direction
+        let keys = auth.session(&challenge, b"secret");
+        assert_ne!(keys.to_leaf, keys.to_gateway);
+        assert_ne!(auth.session(&other, b"secret"), keys);
+        let renonced = Auth::new(&challenge, [10; NONCE_LEN], "deck", b"secret");
+        assert_ne!(renonced.session(&challenge, b"secret"), keys);
+        assert_eq!(auth.session(&challenge, b"secret"), keys);
+    }
+}
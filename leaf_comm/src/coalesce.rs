@@ -0,0 +1,120 @@
+//! Coalescing of encoder twists.
+//!
+//! A knob spun quickly reports a tick at a time, each of which would become
+//! its own message on the leaf link.  A [TwistCoalescer] sends the first tick
+//! right away, so a single click still feels immediate, and then sums the
+//! ticks of every encoder for the rest of a short window before sending them
+//! as one [EncoderTwist].
+//!
+//! The coalescer doesn't read a clock itself, the caller passes the current
+//! time in milliseconds so it works the same on a host and a microcontroller.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::EncoderTwist;
+
+/// Sums encoder twists over a window of time.
+#[derive(Clone, Debug)]
+pub struct TwistCoalescer {
+    window_ms: u64,
+    pending: Vec<i32>,
+    window_end: Option<u64>,
+}
+
+impl TwistCoalescer {
+    /// Create a coalescer for encoder_count encoders summing twists for
+    /// window_ms.  A window of zero sends every twist as it arrives.
+    pub fn new(encoder_count: usize, window_ms: u64) -> Self {
+        Self {
+            window_ms,
+            pending: vec![0; encoder_count],
+            window_end: None,
+        }
+    }
+
+    /// Add twists that happened at now_ms, returning a twist to send right
+    /// away if the window allows it.  Encoders outside of the layout are
+    /// ignored.
+    pub fn add(
+        &mut self,
+        now_ms: u64,
+        twists: impl IntoIterator<Item = (u8, i8)>,
+    ) -> Option<EncoderTwist> {
+        for (index, delta) in twists {
+            if let Some(sum) = self.pending.get_mut(usize::from(index)) {
+                *sum += i32::from(delta);
+            }
+        }
+        match self.window_end {
+            Some(end) if now_ms < end => None,
+            _ => self.take(now_ms),
+        }
+    }
+
+    /// When the pending twists should be sent, if there are any.
+    pub fn flush_at(&self) -> Option<u64> {
+        self.window_end
+            .filter(|_| self.pending.iter().any(|sum| *sum != 0))
+    }
+
+    /// Return the pending twists if their window has ended.
+    pub fn poll(&mut self, now_ms: u64) -> Option<EncoderTwist> {
+        match self.window_end {
+            Some(end) if now_ms >= end => self.take(now_ms),
+            _ => None,
+        }
+    }
+
+    /// Take everything pending and start a new window.  Sums too large for a
+    /// single twist are sent over several windows.
+    fn take(&mut self, now_ms: u64) -> Option<EncoderTwist> {
+        let encoders: Vec<_> = self
+            .pending
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, sum)| **sum != 0)
+            .map(|(index, sum)| {
+                let delta = (*sum).clamp(i8::MIN.into(), i8::MAX.into());
+                *sum -= delta;
+                (index as u8, delta as i8)
+            })
+            .collect();
+        if encoders.is_empty() {
+            self.window_end = None;
+            return None;
+        }
+        self.window_end = Some(now_ms + self.window_ms);
+        Some(EncoderTwist { encoders })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_tick_sent_then_summed() {
+        let mut coalescer = TwistCoalescer::new(2, 30);
+        let first = coalescer.add(0, [(0, 1)]).unwrap();
+        assert_eq!(first.encoders, vec![(0, 1)]);
+
+        assert!(coalescer.add(10, [(0, 1)]).is_none());
+        assert!(coalescer.add(20, [(0, 1), (1, -1)]).is_none());
+        assert_eq!(coalescer.flush_at(), Some(30));
+        assert!(coalescer.poll(29).is_none());
+
+        let summed = coalescer.poll(30).unwrap();
+        assert_eq!(summed.encoders, vec![(0, 2), (1, -1)]);
+        // Nothing new, the window closes
+        assert!(coalescer.poll(60).is_none());
+        assert!(coalescer.add(61, [(1, 1)]).is_some());
+    }
+
+    #[test]
+    fn test_no_window_sends_everything() {
+        let mut coalescer = TwistCoalescer::new(1, 0);
+        assert!(coalescer.add(0, [(0, 1)]).is_some());
+        assert!(coalescer.add(0, [(0, 1)]).is_some());
+    }
+}
@@ -0,0 +1,103 @@
+//! LZ4 compression of frames carrying images.
+//!
+//! A page flip sends an image for every key at once, which saturates slow
+//! links to leaves.  Leaves that say they take
+//! [LZ4_IMAGES](crate::hello::Capabilities::LZ4_IMAGES) are sent frames of
+//! images compressed with the LZ4 block format, which decompresses quickly
+//! and without std on a microcontroller.
+//!
+//! A compressed frame starts with [MAGIC] and [COMPRESSED], a version no
+//! frame is ever written in, followed by the length of the frame inside as
+//! a little endian u32 and the compressed frame.  The frame inside is read
+//! as any other.  Images already compressed, like JPEG, shrink little, so a
+//! frame is only sent compressed if that makes it smaller.
+
+use alloc::vec::Vec;
+
+use crate::wire::{WireError, MAGIC};
+
+/// Second byte of a compressed frame, in place of a version.
+pub const COMPRESSED: u8 = 0xfd;
+/// The header starting a compressed frame.
+pub const COMPRESSED_HEADER: [u8; 2] = [MAGIC, COMPRESSED];
+/// Frames shorter than this aren't worth compressing.
+pub const MIN_LEN: usize = 64;
+
+/// Compress frame, or return it as it is if compressing doesn't make it
+/// smaller.
+pub fn compress(frame: Vec<u8>) -> Vec<u8> {
+    if frame.len() < MIN_LEN {
+        return frame;
+    }
+    let compressed = lz4_flex::compress_prepend_size(&frame);
+    if COMPRESSED_HEADER.len() + compressed.len() >= frame.len() {
+        return frame;
+    }
+    let mut out = Vec::with_capacity(COMPRESSED_HEADER.len() + compressed.len());
+    out.extend_from_slice(&COMPRESSED_HEADER);
+    out.extend_from_slice(&compressed);
+    out
+}
+
+/// Decompress a frame no longer than max, or None if the frame isn't
+/// compressed.
+pub fn decompress(frame: &[u8], max: usize) -> Option<Result<Vec<u8>, WireError>> {
+    let [MAGIC, COMPRESSED, message @ ..] = frame else {
+        return None;
+    };
+    let Some((len, compressed)) = message.split_first_chunk::<4>() else {
+        return Some(Err(WireError::Compression));
+    };
+    // Checked before allocating, as the length may be garbage
+    let len = u32::from_le_bytes(*len) as usize;
+    if len > max {
+        return Some(Err(WireError::Compression));
+    }
+    Some(lz4_flex::decompress(compressed, len).map_err(|_| WireError::Compression))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire::{self, PROTOCOL_VERSION};
+    use crate::{BorrowedDeviceActions, DeviceActions, SetBrightness, SetButtonImage};
+    use alloc::vec;
+
+    #[test]
+    fn test_only_smaller_frames_compressed() {
+        let action = DeviceActions::SetButtonImage(SetButtonImage {
+            button: 4,
+            image: vec![0x42; 72 * 72 * 3],
+        });
+        let frame = wire::encode(PROTOCOL_VERSION, &action).unwrap();
+        let compressed = compress(frame.clone());
+        assert!(compressed.len() < frame.len() / 10);
+        let inner = decompress(&compressed, frame.len()).unwrap().unwrap();
+        assert_eq!(inner, frame);
+        assert!(matches!(
+            wire::decode_actions(&inner).unwrap(),
+            BorrowedDeviceActions::SetButtonImage(image) if image.button == 4
+        ));
+        // Nor is a compressed frame any other frame
+        assert!(wire::decode_actions(&compressed).is_err());
+        // Lengths past the limit are refused before anything is allocated
+        assert!(matches!(
+            decompress(&compressed, frame.len() - 1),
+            Some(Err(WireError::Compression))
+        ));
+
+        let action = DeviceActions::SetBrightness(SetBrightness { brightness: 10 });
+        let frame = wire::encode(PROTOCOL_VERSION, &action).unwrap();
+        assert_eq!(compress(frame.clone()), frame);
+        assert!(decompress(&frame, 1024).is_none());
+        let noise: Vec<u8> = (0..1024u64)
+            .scan(1u64, |state, _| {
+                *state = state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1_442_695_040_888_963_407);
+                Some((*state >> 56) as u8)
+            })
+            .collect();
+        assert_eq!(compress(noise.clone()), noise);
+    }
+}
@@ -0,0 +1,86 @@
+//! A short description of the hardware behind a device id.
+//!
+//! A leaf can be plugged into a different deck and reconnect with the same
+//! device id.  The fingerprint lets the gateway notice, so it doesn't keep
+//! converting images for the hardware it saw last time.
+
+use core::hash::{Hash, Hasher};
+
+use serde::{Deserialize, Serialize};
+
+/// The capabilities of a device that images are converted for.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Fingerprint {
+    /// Number of keys on the device
+    pub key_count: u8,
+    /// Hash of the image format of the keys (mode, size, rotation, mirroring)
+    pub image_format: u32,
+}
+
+impl Fingerprint {
+    /// Describe a device with key_count keys taking images in image_format.
+    pub fn new(key_count: u8, image_format: &impl Hash) -> Self {
+        let mut hasher = Fnv::default();
+        image_format.hash(&mut hasher);
+        Self {
+            key_count,
+            image_format: hasher.finish() as u32,
+        }
+    }
+}
+
+/// FNV-1a, with every integer written as little endian 64 bits so a leaf on
+/// a 32 bit microcontroller computes the same value as a 64 bit host.
+struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write_u64(i.into());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write_u64(i.into());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_u64(i as i64 as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_independent_of_word_size() {
+        let wide = Fingerprint::new(15, &(72u64, 72u64));
+        let native = Fingerprint::new(15, &(72usize, 72usize));
+        assert_eq!(wide, native);
+        assert_ne!(wide, Fingerprint::new(15, &(96usize, 96usize)));
+    }
+}
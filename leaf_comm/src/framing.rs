@@ -0,0 +1,428 @@
+//! Length prefixed framing of messages on a byte stream.
+//!
+//! Every frame is sent as [PREAMBLE], its length as a big endian u32, that
+//! many bytes, and the CRC32 of those bytes, big endian.  This is the one
+//! implementation of it, used by the async streams of bin_comm and the byte
+//! at a time reads of teensy_lib alike, so the two can't drift apart.  Both
+//! sides refuse frames longer than they are willing to hold, rather than
+//! trusting a length that may be garbage.
+//!
+//! On a noisy serial or Wi-Fi link a byte may be lost or changed anywhere.  A
+//! frame whose checksum doesn't match is refused with [FrameError::Corrupt]
+//! rather than handed on to postcard, and reading carries on at the next
+//! preamble instead of taking whatever follows for a length.  A damaged frame
+//! so costs that frame, not the rest of the stream.
+//!
+//! Leaves and gateways from before the checksum send frames in the
+//! [Legacy](Format::Legacy) format, just the length in front of each.  A
+//! [Decoder] made [detecting](Decoder::detecting) the format tells the two
+//! apart by the first byte of the link, so a gateway keeps taking older
+//! leaves and answers each in the format it writes in.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Bytes starting every frame, for finding the next frame after corruption.
+pub const PREAMBLE: [u8; 2] = [0x5a, 0xa5];
+/// Bytes of the length in front of every frame.
+pub const LENGTH_LEN: usize = 4;
+/// Bytes in front of every frame: the preamble and the length.
+pub const HEADER_LEN: usize = PREAMBLE.len() + LENGTH_LEN;
+/// Bytes of the checksum after every frame.
+pub const CRC_LEN: usize = 4;
+/// Longest frame read or written unless told otherwise.
+pub const MAX_FRAME_LEN: usize = 4 * 1024 * 1024;
+
+/// How frames are laid out on a link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// The length in front of every frame and nothing else, as sent from
+    /// before frames were checksummed.  There is no preamble to find the
+    /// next frame by, so a refused frame leaves the link out of step.
+    Legacy,
+    /// [PREAMBLE] and the length in front of every frame, its CRC32 after
+    Checked,
+}
+
+impl Format {
+    /// The format of a link whose first byte is first.  Frames are far
+    /// shorter than 16 MiB, so a legacy length starts with 0, never with the
+    /// preamble.
+    pub fn detect(first: u8) -> Self {
+        if first == PREAMBLE[0] {
+            Format::Checked
+        } else {
+            Format::Legacy
+        }
+    }
+
+    /// Bytes in front of every frame.
+    pub fn header_len(self) -> usize {
+        match self {
+            Format::Legacy => LENGTH_LEN,
+            Format::Checked => HEADER_LEN,
+        }
+    }
+
+    /// Bytes after every frame.
+    pub fn trailer_len(self) -> usize {
+        match self {
+            Format::Legacy => 0,
+            Format::Checked => CRC_LEN,
+        }
+    }
+}
+
+/// A frame that can't be framed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// The frame is longer than allowed
+    TooLarge {
+        /// Length of the frame
+        len: usize,
+        /// Longest frame allowed
+        max: usize,
+    },
+    /// The frame was damaged on the way
+    Corrupt(FrameCorrupt),
+}
+
+/// A frame whose checksum doesn't match its bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameCorrupt {
+    /// The checksum sent after the frame
+    pub sent: u32,
+    /// The checksum of the bytes received
+    pub computed: u32,
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooLarge { len, max } => {
+                write!(f, "frame of {len} bytes is longer than {max}")
+            }
+            FrameError::Corrupt(corrupt) => write!(f, "{corrupt}"),
+        }
+    }
+}
+
+impl fmt::Display for FrameCorrupt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "corrupt frame: checksum {:08x} sent but {:08x} received",
+            self.sent, self.computed
+        )
+    }
+}
+
+impl core::error::Error for FrameError {}
+
+impl From<FrameCorrupt> for FrameError {
+    fn from(corrupt: FrameCorrupt) -> Self {
+        FrameError::Corrupt(corrupt)
+    }
+}
+
+/// The length to send in front of a frame of len bytes, if it is no longer
+/// than max.
+pub fn encode_length(len: usize, max: usize) -> Result<[u8; LENGTH_LEN], FrameError> {
+    match u32::try_from(len) {
+        Ok(prefix) if len <= max => Ok(prefix.to_be_bytes()),
+        _ => Err(FrameError::TooLarge { len, max }),
+    }
+}
+
+/// The preamble and length to send in front of a frame of len bytes, if it
+/// is no longer than max.
+pub fn encode_header(len: usize, max: usize) -> Result<[u8; HEADER_LEN], FrameError> {
+    let mut header = [0; HEADER_LEN];
+    header[..PREAMBLE.len()].copy_from_slice(&PREAMBLE);
+    header[PREAMBLE.len()..].copy_from_slice(&encode_length(len, max)?);
+    Ok(header)
+}
+
+/// The length of the frame following prefix, if it is no longer than max.
+pub fn decode_length(prefix: [u8; LENGTH_LEN], max: usize) -> Result<usize, FrameError> {
+    let len = u32::from_be_bytes(prefix) as usize;
+    if len > max {
+        return Err(FrameError::TooLarge { len, max });
+    }
+    Ok(len)
+}
+
+/// The checksum to send after a frame made of parts.
+pub fn checksum<'a>(parts: impl IntoIterator<Item = &'a [u8]>) -> [u8; CRC_LEN] {
+    let mut hasher = crc32fast::Hasher::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().to_be_bytes()
+}
+
+/// Check that trailer is the checksum of frame.
+pub fn verify(frame: &[u8], trailer: [u8; CRC_LEN]) -> Result<(), FrameCorrupt> {
+    let sent = u32::from_be_bytes(trailer);
+    let computed = u32::from_be_bytes(checksum([frame]));
+    if sent != computed {
+        return Err(FrameCorrupt { sent, computed });
+    }
+    Ok(())
+}
+
+/// Append frame, with its header in front and checksum after, to out.
+pub fn encode(frame: &[u8], out: &mut Vec<u8>) -> Result<(), FrameError> {
+    encode_as(frame, Format::Checked, out)
+}
+
+/// Append frame, laid out in format, to out.
+pub fn encode_as(frame: &[u8], format: Format, out: &mut Vec<u8>) -> Result<(), FrameError> {
+    match format {
+        Format::Legacy => out.extend_from_slice(&encode_length(frame.len(), MAX_FRAME_LEN)?),
+        Format::Checked => out.extend_from_slice(&encode_header(frame.len(), MAX_FRAME_LEN)?),
+    }
+    out.extend_from_slice(frame);
+    if format == Format::Checked {
+        out.extend_from_slice(&checksum([frame]));
+    }
+    Ok(())
+}
+
+/// Reassembles frames from bytes as they arrive, in pieces of any size.
+#[derive(Debug)]
+pub struct Decoder {
+    /// The frame being read, from its preamble on
+    buf: Vec<u8>,
+    /// Bytes to look through before any more data, after a frame was refused
+    backlog: Vec<u8>,
+    /// The frame in buf was returned, so the next byte starts another
+    complete: bool,
+    /// The format of the link, until the first byte tells it if detecting
+    format: Option<Format>,
+    max: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new(MAX_FRAME_LEN)
+    }
+}
+
+impl Decoder {
+    /// A decoder refusing frames longer than max.
+    pub fn new(max: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            backlog: Vec::new(),
+            complete: false,
+            format: Some(Format::Checked),
+            max,
+        }
+    }
+
+    /// A decoder refusing frames longer than max, taking frames in the
+    /// format told by the first byte it is given.
+    pub fn detecting(max: usize) -> Self {
+        Self {
+            format: None,
+            ..Self::new(max)
+        }
+    }
+
+    /// The format frames are taken in, unless it is yet to be detected.
+    pub fn format(&self) -> Option<Format> {
+        self.format
+    }
+
+    /// Take bytes from the front of data until a frame is complete, returning
+    /// the frame and leaving data with what follows it.  Returns None once
+    /// data runs out part way through a frame, which the next call carries
+    /// on with.  Bytes before a preamble are skipped.  After an error the
+    /// next call looks for a frame again from the byte after the preamble of
+    /// the one refused, so frames taken in by a damaged length are found.
+    pub fn decode<'a>(&'a mut self, data: &mut &[u8]) -> Result<Option<&'a [u8]>, FrameError> {
+        if self.complete {
+            self.clear();
+        }
+        loop {
+            let wanted = match self.wanted() {
+                Ok(wanted) => wanted,
+                Err(e) => {
+                    let refused = self.buf.drain(..).skip(1);
+                    // Legacy frames have no preamble to look for again
+                    if self.format == Some(Format::Checked) {
+                        self.backlog.splice(..0, refused);
+                    }
+                    return Err(e);
+                }
+            };
+            if self.buf.len() >= wanted {
+                // Complete frames have a format
+                let format = self.format.unwrap_or(Format::Checked);
+                self.complete = true;
+                return Ok(Some(
+                    &self.buf[format.header_len()..wanted - format.trailer_len()],
+                ));
+            }
+            let needed = wanted - self.buf.len();
+            if !self.backlog.is_empty() {
+                let taken = needed.min(self.backlog.len());
+                self.buf.extend(self.backlog.drain(..taken));
+                continue;
+            }
+            if data.is_empty() {
+                return Ok(None);
+            }
+            let (taken, rest) = data.split_at(data.len().min(needed));
+            self.buf.extend_from_slice(taken);
+            *data = rest;
+        }
+    }
+
+    /// Add one byte, returning the next frame if it is complete.
+    pub fn push(&mut self, byte: u8) -> Result<Option<&[u8]>, FrameError> {
+        self.backlog.push(byte);
+        self.decode(&mut &[][..])
+    }
+
+    /// Forget the frame read so far.
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.complete = false;
+    }
+
+    /// How long buf must be for the frame in it to be complete, dropping any
+    /// bytes in front of its preamble.  A buf that long holds a whole frame
+    /// that checks out.
+    fn wanted(&mut self) -> Result<usize, FrameError> {
+        let format = match (self.format, self.buf.first()) {
+            (Some(format), _) => format,
+            (None, Some(&first)) => *self.format.insert(Format::detect(first)),
+            // The first byte tells the format
+            (None, None) => return Ok(1),
+        };
+        if format == Format::Checked {
+            let start = (0..self.buf.len())
+                .find(|&at| {
+                    let rest = &self.buf[at..];
+                    PREAMBLE.starts_with(&rest[..rest.len().min(PREAMBLE.len())])
+                })
+                .unwrap_or(self.buf.len());
+            self.buf.drain(..start);
+        }
+        let header_len = format.header_len();
+        if self.buf.len() < header_len {
+            return Ok(header_len);
+        }
+        let mut prefix = [0; LENGTH_LEN];
+        prefix.copy_from_slice(&self.buf[header_len - LENGTH_LEN..header_len]);
+        let wanted = header_len + decode_length(prefix, self.max)? + format.trailer_len();
+        if self.buf.len() < wanted || format == Format::Legacy {
+            return Ok(wanted);
+        }
+        let mut trailer = [0; CRC_LEN];
+        trailer.copy_from_slice(&self.buf[wanted - CRC_LEN..wanted]);
+        verify(&self.buf[HEADER_LEN..wanted - CRC_LEN], trailer)?;
+        Ok(wanted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_frames_split_anywhere() {
+        let mut stream = Vec::new();
+        for frame in [&b"hello"[..], b"", b"world!"] {
+            encode(frame, &mut stream).unwrap();
+        }
+        for piece in 1..stream.len() {
+            let mut decoder = Decoder::default();
+            let mut frames = Vec::new();
+            for mut data in stream.chunks(piece) {
+                while let Some(frame) = decoder.decode(&mut data).unwrap() {
+                    frames.push(frame.to_vec());
+                }
+            }
+            assert_eq!(frames, [&b"hello"[..], b"", b"world!"]);
+        }
+    }
+
+    #[test]
+    fn test_long_frames_refused() {
+        let mut decoder = Decoder::new(4);
+        let mut stream = Vec::new();
+        encode(&[1, 2, 3, 4], &mut stream).unwrap();
+        let (last, mut data) = stream.split_last().unwrap();
+        assert_eq!(decoder.decode(&mut data), Ok(None));
+        assert_eq!(decoder.push(*last), Ok(Some(&[1, 2, 3, 4][..])));
+        assert_eq!(
+            decoder.decode(&mut &[0x5a, 0xa5, 0, 0, 0, 5][..]),
+            Err(FrameError::TooLarge { len: 5, max: 4 })
+        );
+        assert_eq!(
+            encode_header(5, 4),
+            Err(FrameError::TooLarge { len: 5, max: 4 })
+        );
+        let mut out = vec![];
+        assert!(encode(&vec![0; MAX_FRAME_LEN + 1], &mut out).is_err());
+    }
+
+    #[test]
+    fn test_corruption_resynchronized() {
+        let mut stream = vec![0x00, 0x5a, 0x13];
+        for frame in [&b"lost"[..], b"kept", b"also kept"] {
+            encode(frame, &mut stream).unwrap();
+        }
+        // A damaged length of the first frame takes in the second
+        stream[3 + HEADER_LEN - 1] += (CRC_LEN + HEADER_LEN + 4) as u8;
+        for piece in [1, stream.len()] {
+            let mut decoder = Decoder::default();
+            let mut frames = Vec::new();
+            let mut errors = 0;
+            for mut data in stream.chunks(piece) {
+                loop {
+                    match decoder.decode(&mut data) {
+                        Ok(Some(frame)) => frames.push(frame.to_vec()),
+                        Ok(None) => break,
+                        Err(e) => {
+                            assert!(matches!(e, FrameError::Corrupt(_)));
+                            errors += 1;
+                        }
+                    }
+                }
+            }
+            assert_eq!(errors, 1);
+            assert_eq!(frames, [&b"kept"[..], b"also kept"]);
+        }
+    }
+    #[test]
+    fn test_format_detected_from_first_byte() {
+        for format in [Format::Legacy, Format::Checked] {
+            let mut stream = Vec::new();
+            for frame in [&b"hello"[..], b"world!"] {
+                encode_as(frame, format, &mut stream).unwrap();
+            }
+            let mut decoder = Decoder::detecting(MAX_FRAME_LEN);
+            assert_eq!(decoder.format(), None);
+            let mut frames = Vec::new();
+            for byte in stream {
+                if let Some(frame) = decoder.push(byte).unwrap() {
+                    frames.push(frame.to_vec());
+                }
+            }
+            assert_eq!(decoder.format(), Some(format));
+            assert_eq!(frames, [&b"hello"[..], b"world!"]);
+        }
+
+        // Legacy frames are refused past the longest frame just the same
+        let mut decoder = Decoder::detecting(4);
+        assert_eq!(
+            decoder.decode(&mut &[0, 0, 0, 5][..]),
+            Err(FrameError::TooLarge { len: 5, max: 4 })
+        );
+    }
+}
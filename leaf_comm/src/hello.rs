@@ -0,0 +1,137 @@
+//! Negotiation of the wire version and capabilities of a connection.
+//!
+//! A leaf starts its connection, after answering any key challenge, with a
+//! [Hello] giving the newest [wire](crate::wire) version it speaks and the
+//! [Capabilities] it has.  The gateway answers with a Hello of its own.  From
+//! then on both write in the older of the two versions, and leave out the
+//! messages the other end can't take.
+//!
+//! Hello frames start with [MAGIC] and [HELLO], a version no frame is ever
+//! written in, so the gateway tells them apart from the config that leaves
+//! from before the hello start with.  Those leaves are assumed to have
+//! [Capabilities::LEGACY].  The layout of a Hello never changes: new
+//! capabilities are new bits, which ends that don't know them ignore.
+
+use alloc::vec::Vec;
+use core::ops::BitOr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::wire::{WireError, MAGIC, PROTOCOL_VERSION};
+
+/// Second byte of a hello frame, in place of a version.
+pub const HELLO: u8 = 0xfe;
+/// The header starting a hello frame.
+pub const HELLO_HEADER: [u8; 2] = [MAGIC, HELLO];
+
+/// What an end of a connection can take, as a set of bits.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// Nothing beyond key images and input
+    pub const NONE: Self = Self(0);
+    /// Shows images sent to its LCD strip
+    pub const LCD_IMAGES: Self = Self(1 << 0);
+    /// Sets its brightness when told to
+    pub const BRIGHTNESS: Self = Self(1 << 1);
+    /// Takes the power state of leaves
+    pub const POWER: Self = Self(1 << 2);
+    /// Takes the status images of leaves
+    pub const STATUS_IMAGES: Self = Self(1 << 3);
+    /// Takes frames of images [compressed](crate::compress)
+    pub const LZ4_IMAGES: Self = Self(1 << 4);
+    /// Holds key images sent as prepared until a commit, then shows them
+    /// all at once, so a page switch doesn't ripple across the keys
+    pub const COMMIT: Self = Self(1 << 5);
+    /// Takes why the other end is about to close the connection
+    pub const GOODBYE: Self = Self(1 << 6);
+    /// Blanks every key and its LCD when told, as when Companion locks it or
+    /// the gateway loses Companion
+    pub const CLEAR_ALL: Self = Self(1 << 7);
+    /// What ends from before the hello are assumed to have: everything
+    /// there was then
+    pub const LEGACY: Self =
+        Self(Self::LCD_IMAGES.0 | Self::BRIGHTNESS.0 | Self::POWER.0 | Self::STATUS_IMAGES.0);
+
+    /// Whether every capability of other is in self.
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+/// The first message of each end of a connection.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Hello {
+    /// Newest wire version the end speaks
+    pub protocol_version: u8,
+    /// What the end can take
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    /// The hello of this build, with capabilities.
+    pub fn new(capabilities: Capabilities) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+
+    /// The version to write in, once the other end said other.
+    pub fn negotiate(&self, other: &Hello) -> u8 {
+        self.protocol_version.min(other.protocol_version)
+    }
+
+    /// Encode the hello into a frame.
+    pub fn encode(&self) -> Result<Vec<u8>, WireError> {
+        Ok(postcard::to_extend(self, HELLO_HEADER.to_vec())?)
+    }
+}
+
+/// Decode a hello, or None if the frame isn't one, as the first frame of
+/// leaves from before the hello isn't.
+pub fn decode(frame: &[u8]) -> Option<Result<Hello, WireError>> {
+    match frame {
+        [MAGIC, HELLO, message @ ..] => Some(postcard::from_bytes(message).map_err(Into::into)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire;
+
+    #[test]
+    fn test_hello_told_from_other_frames() {
+        let leaf = Hello::new(Capabilities::BRIGHTNESS);
+        let frame = leaf.encode().unwrap();
+        assert_eq!(decode(&frame).unwrap().unwrap(), leaf);
+        // Neither a bare nor a versioned config is a hello
+        assert!(decode(&[0x00, 0x84, 0x01]).is_none());
+        assert!(decode(&[MAGIC, PROTOCOL_VERSION, 0x00]).is_none());
+        // Nor is a hello any other frame
+        assert!(matches!(
+            wire::decode_command(&frame),
+            Err(WireError::Version(HELLO))
+        ));
+
+        let older = Hello {
+            protocol_version: 1,
+            capabilities: Capabilities(0xffff_0000 | Capabilities::LCD_IMAGES.0),
+        };
+        assert_eq!(leaf.negotiate(&older), 1);
+        // Unknown bits are carried but never asked about
+        assert!(older.capabilities.contains(Capabilities::LCD_IMAGES));
+        assert!(!older.capabilities.contains(Capabilities::LEGACY));
+    }
+}
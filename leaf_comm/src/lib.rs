@@ -6,13 +6,48 @@ use alloc::vec::Vec;
 use alloc::string::String;
 use serde::{Serialize, Deserialize};
 
+/// Button change detection shared by every device backend.
+pub mod state;
+
+/// Coalescing of fast encoder twists.
+pub mod coalesce;
+
+/// Detection of a device id moving to different hardware.
+pub mod fingerprint;
+
+/// Versions of the wire format and migration of older ones.
+pub mod wire;
+
+/// Pre-shared key authentication of leaves.
+pub mod auth;
+
+/// Negotiation of the wire version and capabilities of a connection.
+pub mod hello;
+
+/// LZ4 compression of frames carrying images.
+pub mod compress;
+
+/// Length prefixed framing of messages on a byte stream.
+pub mod framing;
+
+pub use fingerprint::Fingerprint;
+
 /// The configuration of our device.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct RemoteConfig {
     /// the hardware product id of the device (usb vid/pid)
     pub pid: u16,
     /// the unique device id of the device stored in the device
-    pub device_id: String
+    pub device_id: String,
+    /// the capabilities of the hardware behind the device id
+    pub fingerprint: Fingerprint,
+}
+
+impl RemoteConfig {
+    /// Whether other describes the same hardware as this config.
+    pub fn same_hardware(&self, other: &RemoteConfig) -> bool {
+        self.pid == other.pid && self.fingerprint == other.fingerprint
+    }
 }
 
 /// The configuration of our device.
@@ -21,25 +56,140 @@ pub struct BorrowedRemoteConfig<'a> {
     /// the hardware product id of the device (usb vid/pid)
     pub pid: u16,
     /// the unique device id of the device stored in the device
-    pub device_id: &'a str
+    pub device_id: &'a str,
+    /// the capabilities of the hardware behind the device id
+    pub fingerprint: Fingerprint,
 }
 
 /// A button has changed state.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ButtonChange {
     /// List of button indicies and their current state
     pub buttons: Vec<(u8, bool)>,
 }
 
 /// An encoder has been twisted.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct EncoderTwist {
     /// List of encoder indicies and their current state
     pub encoders: Vec<(u8, i8)>,
 }
 
-/// All commands that can be received from the device
-#[derive(Serialize, Deserialize, Debug)]
+/// An encoder has been pressed or released.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EncoderPress {
+    /// List of encoder indicies and their current state
+    pub encoders: Vec<(u8, bool)>,
+}
+
+/// What was done on a touch screen.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TouchGesture {
+    /// A short press
+    Tap,
+    /// A long press
+    LongPress,
+    /// A swipe ending at the given position
+    Swipe(u16, u16),
+}
+
+/// The LCD strip has been touched.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Touch {
+    /// Horizontal position of the touch (start of a swipe)
+    pub x: u16,
+    /// Vertical position of the touch (start of a swipe)
+    pub y: u16,
+    /// What was done
+    pub gesture: TouchGesture,
+}
+
+/// Where a leaf is getting its power from.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PowerSource {
+    /// Plugged in, or not battery powered at all
+    #[default]
+    External,
+    /// Running on its battery
+    Battery,
+    /// Running on a battery that is nearly empty
+    LowBattery,
+}
+
+/// The power of a leaf has changed.  Leaves without a battery never send it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PowerState {
+    /// Where the power comes from
+    pub source: PowerSource,
+    /// Charge left in the battery in percent, if the leaf can tell
+    pub charge: Option<u8>,
+}
+
+/// A small image a leaf shows the gateway, such as the tally thumbnail of a
+/// camera it watches.  Companion never sees it, the gateway serves it to
+/// anyone asking.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct StatusImage {
+    /// Width in pixels
+    pub width: u16,
+    /// Height in pixels
+    pub height: u16,
+    /// Packed 8 bit RGB, row by row
+    pub rgb: Vec<u8>,
+}
+
+impl StatusImage {
+    /// Longest side of a status image.  Anything larger is rejected.
+    pub const MAX_SIDE: u16 = 128;
+
+    /// Whether the image is no larger than [Self::MAX_SIDE] and has as many
+    /// pixels as it says.
+    pub fn is_valid(&self) -> bool {
+        self.width <= Self::MAX_SIDE
+            && self.height <= Self::MAX_SIDE
+            && self.rgb.len() == usize::from(self.width) * usize::from(self.height) * 3
+    }
+}
+
+/// Why an end closed a connection on purpose, rather than crashing or
+/// losing it.
+///
+/// Variants are encoded by their position, so new variants go at the end.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShutdownReason {
+    /// The program is shutting down, such as on ctrl-c
+    Shutdown,
+    /// An operator disconnected the device
+    Kicked,
+    /// A change to the configuration no longer allows the device
+    ConfigReload,
+    /// The device is being moved to another Companion
+    Repointed,
+}
+
+impl ShutdownReason {
+    /// The reason as a single word, as it is logged and sent to Companion.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShutdownReason::Shutdown => "shutdown",
+            ShutdownReason::Kicked => "kicked",
+            ShutdownReason::ConfigReload => "config_reload",
+            ShutdownReason::Repointed => "repointed",
+        }
+    }
+}
+
+impl core::fmt::Display for ShutdownReason {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// All commands that can be received from the device.
+///
+/// Variants are encoded by their position, which leaves in the field depend
+/// on, so new variants go at the end and none are ever reordered or removed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Command {
     /// Configuration
     Config(RemoteConfig),
@@ -47,6 +197,17 @@ pub enum Command {
     ButtonChange(ButtonChange),
     /// Encoder changing state
     EncoderTwist(EncoderTwist),
+    /// Encoder being pressed or released
+    EncoderPress(EncoderPress),
+    /// Touch screen being touched
+    Touch(Touch),
+    /// Power of the leaf changing
+    Power(PowerState),
+    /// The status image of the leaf changing, an empty one clearing it
+    StatusImage(StatusImage),
+    /// The leaf is about to close the connection, and why.  Only sent to
+    /// gateways that take [GOODBYE](hello::Capabilities::GOODBYE).
+    Goodbye(ShutdownReason),
 }
 
 /// Action to set an LCD image
@@ -79,6 +240,10 @@ pub struct SetButtonImage {
 }
 
 /// All device actions that can be sent to the device.
+///
+/// Variants are encoded by their position, so new variants go at the end and
+/// none are ever reordered or removed.  Those a leaf may not take are only
+/// sent if its [Capabilities](hello::Capabilities) say it does.
 #[derive(Serialize, Clone, Deserialize, Debug)]
 pub enum DeviceActions {
     /// Set the image of a button.
@@ -87,4 +252,115 @@ pub enum DeviceActions {
     SetLCDImage(SetLCDImage),
     /// Set the brightness of the LCD screen
     SetBrightness(SetBrightness),
+    /// Hold the image of a button until the next [Commit](Self::Commit).
+    /// Only sent to leaves that take [COMMIT](hello::Capabilities::COMMIT).
+    PrepareButtonImage(SetButtonImage),
+    /// Show every image prepared since the last commit at once.
+    Commit,
+    /// Blank every key and the LCD, dropping any images prepared, and keep
+    /// them blank until images are set again.  Only sent to leaves that take
+    /// [CLEAR_ALL](hello::Capabilities::CLEAR_ALL).
+    ClearAll,
+}
+
+/// Action to set a button image, borrowing the image from the received
+/// frame.  Serialized the same as [SetButtonImage].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BorrowedSetButtonImage<'a> {
+    /// The index of the button to set
+    pub button: u8,
+    /// image is an image pre-formatted for the device
+    pub image: &'a [u8],
+}
+
+/// Action to set an LCD image, borrowing the image from the received frame.
+/// Serialized the same as [SetLCDImage].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BorrowedSetLCDImage<'a> {
+    /// The x offset of the image on the LCD
+    pub x_offset: u16,
+    /// A width of the image.
+    pub x_size: u16,
+    /// A height of the image.
+    pub y_size: u16,
+    /// image is an image pre-formatted for the device
+    pub image: &'a [u8],
+}
+
+/// All device actions that can be sent to the device, borrowing images from
+/// the received frame instead of copying them.  Serialized the same as
+/// [DeviceActions], so either can be read from the same stream.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum BorrowedDeviceActions<'a> {
+    /// Set the image of a button.
+    #[serde(borrow)]
+    SetButtonImage(BorrowedSetButtonImage<'a>),
+    /// Set the image of the LCD screen.
+    #[serde(borrow)]
+    SetLCDImage(BorrowedSetLCDImage<'a>),
+    /// Set the brightness of the LCD screen
+    SetBrightness(SetBrightness),
+    /// Hold the image of a button until the next commit.
+    #[serde(borrow)]
+    PrepareButtonImage(BorrowedSetButtonImage<'a>),
+    /// Show every image prepared since the last commit at once.
+    Commit,
+    /// Blank every key and the LCD until images are set again.
+    ClearAll,
+}
+
+impl From<BorrowedDeviceActions<'_>> for DeviceActions {
+    fn from(action: BorrowedDeviceActions<'_>) -> Self {
+        match action {
+            BorrowedDeviceActions::SetButtonImage(image) => {
+                DeviceActions::SetButtonImage(SetButtonImage {
+                    button: image.button,
+                    image: image.image.to_vec(),
+                })
+            }
+            BorrowedDeviceActions::SetLCDImage(image) => DeviceActions::SetLCDImage(SetLCDImage {
+                x_offset: image.x_offset,
+                x_size: image.x_size,
+                y_size: image.y_size,
+                image: image.image.to_vec(),
+            }),
+            BorrowedDeviceActions::SetBrightness(brightness) => {
+                DeviceActions::SetBrightness(brightness)
+            }
+            BorrowedDeviceActions::PrepareButtonImage(image) => {
+                DeviceActions::PrepareButtonImage(SetButtonImage {
+                    button: image.button,
+                    image: image.image.to_vec(),
+                })
+            }
+            BorrowedDeviceActions::Commit => DeviceActions::Commit,
+            BorrowedDeviceActions::ClearAll => DeviceActions::ClearAll,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_borrowed_actions_read_owned_frames() {
+        let action = DeviceActions::SetLCDImage(SetLCDImage {
+            x_offset: 200,
+            x_size: 100,
+            y_size: 100,
+            image: vec![1, 2, 3],
+        });
+        let frame = postcard::to_allocvec(&action).unwrap();
+        let borrowed: BorrowedDeviceActions = postcard::from_bytes(&frame).unwrap();
+        match &borrowed {
+            BorrowedDeviceActions::SetLCDImage(image) => {
+                assert_eq!(image.x_offset, 200);
+                assert_eq!(image.image, [1, 2, 3]);
+            }
+            action => panic!("Unexpected action {action:?}"),
+        }
+        assert_eq!(postcard::to_allocvec(&borrowed).unwrap(), frame);
+    }
 }
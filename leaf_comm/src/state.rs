@@ -0,0 +1,102 @@
+//! Button change detection shared by every device backend.
+//!
+//! Devices report the state of all of their buttons at once.  Companion only
+//! wants to hear about the buttons that changed, so each backend keeps an
+//! [InputState] and feeds it the reports.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::{ButtonChange, EncoderPress};
+
+/// The last known state of every button of a device.
+///
+/// The layout is the keys (including any virtual LCD keys) followed by the
+/// encoders, which can be pressed like buttons.
+#[derive(Clone, Debug)]
+pub struct InputState {
+    key_count: usize,
+    states: Vec<bool>,
+}
+
+impl InputState {
+    /// Create the state of a device with key_count keys and encoder_count
+    /// encoders, all released.
+    pub fn new(key_count: usize, encoder_count: usize) -> Self {
+        Self {
+            key_count,
+            states: vec![false; key_count + encoder_count],
+        }
+    }
+
+    /// Apply the state of some buttons, starting at button offset.  Returns
+    /// the buttons whose state changed, with their new state.  Buttons outside
+    /// of the layout are ignored.
+    pub fn update_state<'a>(
+        &'a mut self,
+        offset: usize,
+        changes: impl IntoIterator<Item = (usize, bool)> + 'a,
+    ) -> impl Iterator<Item = (u8, bool)> + 'a {
+        changes.into_iter().filter_map(move |(index, state)| {
+            let index = index + offset;
+            if *self.states.get(index)? == state {
+                None
+            } else {
+                self.states[index] = state;
+                Some((index as u8, state))
+            }
+        })
+    }
+
+    /// Apply a report of the state of every key, returning the change to send
+    /// to Companion if there is one.  States beyond the keys are ignored.
+    pub fn keys(&mut self, states: impl IntoIterator<Item = bool>) -> Option<ButtonChange> {
+        let key_count = self.key_count;
+        Self::change(self.update_state(0, states.into_iter().take(key_count).enumerate()))
+    }
+
+    /// Apply a report of the pressed state of every encoder, returning the
+    /// change to send to Companion if there is one.  The change is indexed by
+    /// encoder, not by button.
+    pub fn encoders(&mut self, states: impl IntoIterator<Item = bool>) -> Option<EncoderPress> {
+        let offset = self.key_count;
+        let encoders: Vec<_> = self
+            .update_state(offset, states.into_iter().enumerate())
+            .map(|(index, state)| (index - offset as u8, state))
+            .collect();
+        (!encoders.is_empty()).then_some(EncoderPress { encoders })
+    }
+
+    fn change(buttons: impl Iterator<Item = (u8, bool)>) -> Option<ButtonChange> {
+        let buttons: Vec<_> = buttons.collect();
+        (!buttons.is_empty()).then_some(ButtonChange { buttons })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_changes_reported() {
+        let mut state = InputState::new(3, 0);
+        assert!(state.keys([false, false, false]).is_none());
+
+        let change = state.keys([false, true, false]).unwrap();
+        assert_eq!(change.buttons, vec![(1, true)]);
+        assert!(state.keys([false, true, false]).is_none());
+
+        let change = state.keys([true, false, false]).unwrap();
+        assert_eq!(change.buttons, vec![(0, true), (1, false)]);
+    }
+
+    #[test]
+    fn test_encoders_follow_keys() {
+        let mut state = InputState::new(2, 2);
+        let change = state.encoders([false, true]).unwrap();
+        assert_eq!(change.encoders, vec![(1, true)]);
+        assert!(state.keys([false, false]).is_none());
+        // Out of range buttons are ignored
+        assert!(state.keys([false, false, true]).is_none());
+    }
+}
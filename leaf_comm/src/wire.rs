@@ -0,0 +1,151 @@
+//! Versions of the wire format between leaves and the gateway.
+//!
+//! Every frame holds a postcard encoded [Command] from a leaf or
+//! [DeviceActions](crate::DeviceActions) from the gateway.  Since version 1 a
+//! frame starts with a header of [MAGIC] and the version it was written in,
+//! so each side knows what it is talking to and older layouts of a message
+//! can be migrated into the current types.
+//!
+//! Leaves built before the header send bare frames.  Their first byte is the
+//! variant of the command, which is never [MAGIC], so they are read as
+//! [UNVERSIONED] and answered with bare frames too.  A version is read for at
+//! least one version after it is replaced.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use serde::Serialize;
+
+use crate::{BorrowedDeviceActions, Command};
+
+/// The version frames are written in.
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Frames without a header, from before versioning.
+pub const UNVERSIONED: u8 = 0;
+/// First byte of a frame with a header.
+pub const MAGIC: u8 = 0xff;
+/// The header starting a frame written in [PROTOCOL_VERSION].
+pub const HEADER: [u8; 2] = [MAGIC, PROTOCOL_VERSION];
+
+/// A frame that couldn't be read.
+#[derive(Debug)]
+pub enum WireError {
+    /// The frame was written in a version this build can't read
+    Version(u8),
+    /// The message didn't match the layout of its version
+    Postcard(postcard::Error),
+    /// A compressed frame couldn't be decompressed
+    Compression,
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireError::Version(version) => write!(f, "unsupported wire version {version}"),
+            WireError::Postcard(e) => write!(f, "malformed frame: {e}"),
+            WireError::Compression => write!(f, "malformed compressed frame"),
+        }
+    }
+}
+
+impl From<postcard::Error> for WireError {
+    fn from(e: postcard::Error) -> Self {
+        WireError::Postcard(e)
+    }
+}
+
+/// Split a frame into the version it was written in and its message.
+pub fn split(frame: &[u8]) -> (u8, &[u8]) {
+    match frame {
+        [MAGIC, version, message @ ..] => (*version, message),
+        _ => (UNVERSIONED, frame),
+    }
+}
+
+/// Encode a message into a frame for a peer speaking version.
+pub fn encode<T: Serialize>(version: u8, message: &T) -> Result<Vec<u8>, WireError> {
+    let frame = match version {
+        UNVERSIONED => Vec::new(),
+        PROTOCOL_VERSION => HEADER.to_vec(),
+        version => return Err(WireError::Version(version)),
+    };
+    Ok(postcard::to_extend(message, frame)?)
+}
+
+/// Decode a command from a leaf, migrating older layouts.  Also returns the
+/// version it was written in, which the leaf is answered in.
+pub fn decode_command(frame: &[u8]) -> Result<(u8, Command), WireError> {
+    let (version, message) = split(frame);
+    let command = match version {
+        // Bare frames may predate fingerprints
+        UNVERSIONED => postcard::from_bytes(message)
+            .or_else(|_| postcard::from_bytes::<v0::Command>(message).map(Command::from))?,
+        PROTOCOL_VERSION => postcard::from_bytes(message)?,
+        version => return Err(WireError::Version(version)),
+    };
+    Ok((version, command))
+}
+
+/// Decode actions from the gateway, borrowing images from the frame.
+pub fn decode_actions(frame: &[u8]) -> Result<BorrowedDeviceActions<'_>, WireError> {
+    match split(frame) {
+        (UNVERSIONED | PROTOCOL_VERSION, message) => Ok(postcard::from_bytes(message)?),
+        (version, _) => Err(WireError::Version(version)),
+    }
+}
+
+/// Layouts of the first leaves, before devices had fingerprints.
+mod v0 {
+    use alloc::string::String;
+
+    use serde::Deserialize;
+
+    use crate::{ButtonChange, EncoderTwist, RemoteConfig};
+
+    #[derive(Deserialize)]
+    pub struct Config {
+        pid: u16,
+        device_id: String,
+    }
+
+    #[derive(Deserialize)]
+    pub enum Command {
+        Config(Config),
+        ButtonChange(ButtonChange),
+        EncoderTwist(EncoderTwist),
+    }
+
+    impl From<Command> for crate::Command {
+        fn from(command: Command) -> Self {
+            match command {
+                Command::Config(config) => crate::Command::Config(RemoteConfig {
+                    pid: config.pid,
+                    device_id: config.device_id,
+                    fingerprint: Default::default(),
+                }),
+                Command::ButtonChange(change) => crate::Command::ButtonChange(change),
+                Command::EncoderTwist(twist) => crate::Command::EncoderTwist(twist),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeviceActions, SetBrightness};
+
+    #[test]
+    fn test_newer_versions_rejected() {
+        let action = DeviceActions::SetBrightness(SetBrightness { brightness: 60 });
+        assert!(matches!(encode(2, &action), Err(WireError::Version(2))));
+        assert!(matches!(
+            decode_actions(&[MAGIC, 2, 0x02, 0x3c]),
+            Err(WireError::Version(2))
+        ));
+        assert!(matches!(
+            decode_command(&[MAGIC, 2, 0x01, 0x00]),
+            Err(WireError::Version(2))
+        ));
+    }
+}
@@ -0,0 +1,43 @@
+//! # Companion traits
+//!
+//! The companion traits represent the link towards the companion app, which
+//! for a leaf is usually the connection to a gateway.  They mirror
+//! `traits::companion`.
+
+use crate::Result;
+
+use leaf_comm::{
+    ButtonChange, DeviceActions, EncoderPress, EncoderTwist, PowerState, RemoteConfig,
+    StatusImage, Touch,
+};
+
+/// Polls the link for actions to perform on the device.
+pub trait Receiver {
+    /// Return an action if one has been fully received, without blocking.
+    fn try_receive(&mut self) -> Result<Option<DeviceActions>>;
+}
+
+/// Notifies the companion app of events read from the device.
+pub trait Sender {
+    /// Configuration of the device.  This should be sent prior to any other
+    /// commands and only once.
+    fn config(&mut self, config: RemoteConfig) -> Result<()>;
+    /// A button has changed state.
+    fn button_change(&mut self, change: ButtonChange) -> Result<()>;
+    /// An encoder has been twisted.
+    fn encoder_twist(&mut self, twist: EncoderTwist) -> Result<()>;
+    /// An encoder has been pressed or released.
+    fn encoder_press(&mut self, press: EncoderPress) -> Result<()>;
+    /// The touch screen has been touched.
+    fn touch(&mut self, touch: Touch) -> Result<()>;
+    /// The power of the device has changed.  Only a gateway has a use for
+    /// it, so links to anything else may ignore it.
+    fn power(&mut self, _power: PowerState) -> Result<()> {
+        Ok(())
+    }
+    /// The status image of the device has changed.  Like power, links to
+    /// anything but a gateway may ignore it.
+    fn status_image(&mut self, _image: StatusImage) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,27 @@
+//! # Device traits
+//!
+//! The device traits represent the hardware attached to the leaf, mirroring
+//! `traits::device`.
+
+use crate::Result;
+
+pub use leaf_comm::{
+    ButtonChange, Command, DeviceActions, EncoderTwist, RemoteConfig, SetBrightness,
+    SetButtonImage, SetLCDImage,
+};
+
+/// Polls the device for input.
+pub trait Receiver {
+    /// Return a command if the device has one ready, without blocking.
+    fn try_receive(&mut self) -> Result<Option<Command>>;
+}
+
+/// Sends actions to the device.
+pub trait Sender {
+    /// Set the brightness of the device
+    fn set_brightness(&mut self, brightness: SetBrightness) -> Result<()>;
+    /// Set the image of a button
+    fn set_button_image(&mut self, image: SetButtonImage) -> Result<()>;
+    /// Set the image of the LCD panel
+    fn set_lcd_image(&mut self, image: SetLCDImage) -> Result<()>;
+}
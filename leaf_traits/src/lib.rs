@@ -0,0 +1,208 @@
+//! # Leaf traits
+//!
+//! A no_std mirror of the `traits` crate for leaf firmware.
+//!
+//! Microcontroller firmware has no async runtime, so the interfaces here are
+//! synchronous and the receivers are polled: they return `Ok(None)` when
+//! nothing is ready rather than blocking.  Firmware implements these over its
+//! hardware and then shares logic (pumping, state diffing, watchdogs) with
+//! every other leaf through this crate.
+
+#![no_std]
+#![cfg_attr(docsrs, feature(doc_cfg))]
+#![warn(missing_docs)]
+
+extern crate alloc;
+
+/// re-export anyhow
+pub use anyhow;
+/// re-export anyhow::Result
+pub use anyhow::Result;
+
+/// export the companion interface
+pub mod companion;
+/// export the device interface
+pub mod device;
+
+/// Move at most one message in each direction between the device and the
+/// companion side.  Returns true if anything was moved, so a firmware loop
+/// can decide to idle.
+pub fn pump_once(
+    device_sender: &mut impl device::Sender,
+    device_receiver: &mut impl device::Receiver,
+    companion_sender: &mut impl companion::Sender,
+    companion_receiver: &mut impl companion::Receiver,
+) -> Result<bool> {
+    let mut moved = false;
+
+    if let Some(action) = companion_receiver.try_receive()? {
+        match action {
+            // Firmware that doesn't hold prepared images shows them at once
+            device::DeviceActions::SetButtonImage(image)
+            | device::DeviceActions::PrepareButtonImage(image) => {
+                device_sender.set_button_image(image)?
+            }
+            device::DeviceActions::SetLCDImage(image) => device_sender.set_lcd_image(image)?,
+            device::DeviceActions::SetBrightness(brightness) => {
+                device_sender.set_brightness(brightness)?
+            }
+            device::DeviceActions::Commit => {}
+            // Only sent to leaves that say they take it, which firmware
+            // built on this crate doesn't
+            device::DeviceActions::ClearAll => {}
+        }
+        moved = true;
+    }
+
+    if let Some(command) = device_receiver.try_receive()? {
+        match command {
+            device::Command::Config(c) => companion_sender.config(c)?,
+            device::Command::ButtonChange(change) => companion_sender.button_change(change)?,
+            device::Command::EncoderTwist(twist) => companion_sender.encoder_twist(twist)?,
+            device::Command::EncoderPress(press) => companion_sender.encoder_press(press)?,
+            device::Command::Touch(touch) => companion_sender.touch(touch)?,
+            device::Command::Power(power) => companion_sender.power(power)?,
+            device::Command::StatusImage(image) => companion_sender.status_image(image)?,
+            // Only links to a gateway say goodbye, never the hardware itself
+            device::Command::Goodbye(_) => {}
+        }
+        moved = true;
+    }
+
+    Ok(moved)
+}
+
+/// message_pump polls both sides forever, moving data between them.  This is
+/// the synchronous counterpart of `pumps::message_pump` and only returns if
+/// either side fails.
+pub fn message_pump(
+    mut device_sender: impl device::Sender,
+    mut device_receiver: impl device::Receiver,
+    mut companion_sender: impl companion::Sender,
+    mut companion_receiver: impl companion::Receiver,
+) -> Result<()> {
+    loop {
+        pump_once(
+            &mut device_sender,
+            &mut device_receiver,
+            &mut companion_sender,
+            &mut companion_receiver,
+        )?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use alloc::{format, string::String, vec, vec::Vec};
+    use leaf_comm::{EncoderPress, ShutdownReason, Touch};
+
+    /// Everything done to either side, in order.
+    type Log = Vec<String>;
+
+    struct Device<'a>(&'a mut Log);
+
+    impl device::Sender for Device<'_> {
+        fn set_brightness(&mut self, brightness: device::SetBrightness) -> Result<()> {
+            self.0.push(format!("brightness {}", brightness.brightness));
+            Ok(())
+        }
+        fn set_button_image(&mut self, image: device::SetButtonImage) -> Result<()> {
+            self.0.push(format!("image {}", image.button));
+            Ok(())
+        }
+        fn set_lcd_image(&mut self, image: device::SetLCDImage) -> Result<()> {
+            self.0.push(format!("lcd {}", image.x_offset));
+            Ok(())
+        }
+    }
+
+    /// Input read from the device, last first.
+    struct Input(Vec<device::Command>);
+
+    impl device::Receiver for Input {
+        fn try_receive(&mut self) -> Result<Option<device::Command>> {
+            Ok(self.0.pop())
+        }
+    }
+
+    struct Companion<'a>(&'a mut Log);
+
+    impl companion::Sender for Companion<'_> {
+        fn config(&mut self, config: device::RemoteConfig) -> Result<()> {
+            self.0.push(format!("config {}", config.device_id));
+            Ok(())
+        }
+        fn button_change(&mut self, change: device::ButtonChange) -> Result<()> {
+            self.0.push(format!("buttons {:?}", change.buttons));
+            Ok(())
+        }
+        fn encoder_twist(&mut self, twist: device::EncoderTwist) -> Result<()> {
+            self.0.push(format!("twist {twist:?}"));
+            Ok(())
+        }
+        fn encoder_press(&mut self, press: EncoderPress) -> Result<()> {
+            self.0.push(format!("press {press:?}"));
+            Ok(())
+        }
+        fn touch(&mut self, touch: Touch) -> Result<()> {
+            self.0.push(format!("touch {touch:?}"));
+            Ok(())
+        }
+    }
+
+    /// Actions received from Companion, last first.
+    struct Actions(Vec<device::DeviceActions>);
+
+    impl companion::Receiver for Actions {
+        fn try_receive(&mut self) -> Result<Option<device::DeviceActions>> {
+            Ok(self.0.pop())
+        }
+    }
+
+    #[test]
+    fn test_pump_once_moves_one_message_each_way() {
+        let image = |button| device::SetButtonImage {
+            button,
+            image: Vec::new(),
+        };
+        let mut actions = Actions(vec![
+            device::DeviceActions::ClearAll,
+            device::DeviceActions::Commit,
+            device::DeviceActions::SetBrightness(device::SetBrightness { brightness: 40 }),
+            device::DeviceActions::PrepareButtonImage(image(2)),
+            device::DeviceActions::SetButtonImage(image(1)),
+        ]);
+        let mut input = Input(vec![
+            device::Command::Goodbye(ShutdownReason::Kicked),
+            device::Command::ButtonChange(device::ButtonChange {
+                buttons: vec![(1, true)],
+            }),
+            device::Command::Config(device::RemoteConfig {
+                pid: 0x80,
+                device_id: String::from("deck"),
+                fingerprint: Default::default(),
+            }),
+        ]);
+        let (mut shown, mut told) = (Log::new(), Log::new());
+
+        let mut rounds = 0;
+        while pump_once(
+            &mut Device(&mut shown),
+            &mut input,
+            &mut Companion(&mut told),
+            &mut actions,
+        )
+        .unwrap()
+        {
+            rounds += 1;
+        }
+        // Commits and clears are taken but mean nothing to firmware, and
+        // goodbyes aren't passed on
+        assert_eq!(rounds, 5);
+        assert_eq!(shown, ["image 1", "image 2", "brightness 40"]);
+        assert_eq!(told, ["config deck", "buttons [(1, true)]"]);
+    }
+}
@@ -0,0 +1,202 @@
+//! Falling back to colors for leaves starved of bandwidth.
+//!
+//! A leaf on a weak Wi-Fi or serial link can take images slower than
+//! Companion sends them, so every page change leaves it seconds behind.
+//! [ThroughputMonitor] wraps the device sender of a leaf and measures how
+//! fast it takes images.  Once that drops below what a [BandwidthPolicy]
+//! allows, it says the leaf is starved on a watch channel, which the
+//! Companion receiver of the leaf takes as the switch to draw keys from their
+//! color and text.  Once the leaf takes images quickly again, it goes back to
+//! images.
+//!
+//! Writes only take time once the link is backed up, so the rate measured is
+//! that of the link while it is busy.  A leaf is kept in either state for a
+//! while before switching back, so a leaf at the edge doesn't flip on every
+//! page change.  The policy is followed on a watch channel, so it can be
+//! changed while leaves are served.
+
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::info;
+use traits::{
+    async_trait,
+    device::{SetBrightness, SetButtonImage, SetLCDImage},
+    Result,
+};
+
+/// Weight kept of what was measured before each image, so the rate follows
+/// the last dozen or so images.
+const DECAY: f64 = 0.9;
+
+/// When a leaf is starved of bandwidth, and when it has recovered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BandwidthPolicy {
+    /// Bytes per second of images below which a leaf is starved
+    pub starved_bytes_per_sec: u64,
+    /// Bytes per second of images above which a starved leaf has recovered
+    pub recovered_bytes_per_sec: u64,
+    /// Least time a leaf is kept starved, or not, before switching back
+    pub hold: Duration,
+}
+
+impl BandwidthPolicy {
+    /// Starved below bytes_per_sec, recovered at twice that.  A policy of
+    /// 0 never starves a leaf.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            starved_bytes_per_sec: bytes_per_sec,
+            recovered_bytes_per_sec: bytes_per_sec.saturating_mul(2),
+            hold: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Create the channel whether a leaf is starved is published on.  Leaves
+/// start out with images.
+pub fn channel() -> (watch::Sender<bool>, watch::Receiver<bool>) {
+    watch::channel(false)
+}
+
+/// Wraps a device sender, measuring how fast it takes images and publishing
+/// whether it is starved of bandwidth.
+pub struct ThroughputMonitor<S> {
+    inner: S,
+    starved: watch::Sender<bool>,
+    policy: watch::Receiver<BandwidthPolicy>,
+    /// Bytes of images sent, decayed
+    bytes: f64,
+    /// Time spent sending them, decayed
+    seconds: f64,
+    /// When the leaf last switched
+    switched: Instant,
+}
+
+impl<S> ThroughputMonitor<S>
+where
+    S: traits::device::Sender + Send,
+{
+    /// Wrap a device sender, publishing on starved as the policy says.
+    pub fn new(
+        inner: S,
+        starved: watch::Sender<bool>,
+        policy: watch::Receiver<BandwidthPolicy>,
+    ) -> Self {
+        Self {
+            inner,
+            starved,
+            policy,
+            bytes: 0.0,
+            seconds: 0.0,
+            switched: Instant::now(),
+        }
+    }
+
+    /// Bytes per second images were taken at lately.
+    pub fn rate(&self) -> Option<f64> {
+        (self.seconds > 0.0).then(|| self.bytes / self.seconds)
+    }
+
+    /// Take in an image of len bytes taking elapsed to send.
+    fn record(&mut self, len: usize, elapsed: Duration) {
+        self.bytes = self.bytes * DECAY + len as f64;
+        self.seconds = self.seconds * DECAY + elapsed.as_secs_f64();
+        let Some(rate) = self.rate() else {
+            return;
+        };
+        let policy = *self.policy.borrow();
+        if self.switched.elapsed() < policy.hold {
+            return;
+        }
+        let starved = *self.starved.borrow();
+        let switch = if starved {
+            rate > policy.recovered_bytes_per_sec as f64
+        } else {
+            rate < policy.starved_bytes_per_sec as f64
+        };
+        if switch {
+            info!(
+                "Leaf takes images at {:.0} bytes per second, drawing keys from {}",
+                rate,
+                if starved { "images" } else { "colors" }
+            );
+            self.starved.send_replace(!starved);
+            self.switched = Instant::now();
+        }
+    }
+}
+
+#[async_trait]
+impl<S> traits::device::Sender for ThroughputMonitor<S>
+where
+    S: traits::device::Sender + Send,
+{
+    async fn set_brightness(&mut self, brightness: SetBrightness) -> Result<()> {
+        self.inner.set_brightness(brightness).await
+    }
+
+    async fn set_button_image(&mut self, image: SetButtonImage) -> Result<()> {
+        let (len, start) = (image.image.len(), Instant::now());
+        self.inner.set_button_image(image).await?;
+        self.record(len, start.elapsed());
+        Ok(())
+    }
+
+    async fn set_lcd_image(&mut self, image: SetLCDImage) -> Result<()> {
+        let (len, start) = (image.image.len(), Instant::now());
+        self.inner.set_lcd_image(image).await?;
+        self.record(len, start.elapsed());
+        Ok(())
+    }
+
+    async fn clear(&mut self) -> Result<()> {
+        self.inner.clear().await
+    }
+    async fn commit(&mut self) -> Result<()> {
+        self.inner.commit().await
+    }
+    async fn clear_all(&mut self) -> Result<()> {
+        self.inner.clear_all().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device::Device;
+    use traits::device::Sender as _;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_starved_leaf_switched_to_colors() {
+        let (starved, starved_receiver) = channel();
+        let policy = BandwidthPolicy::new(2_000_000);
+        let (_, policy_receiver) = watch::channel(policy);
+        // A leaf taking a byte every microsecond
+        let leaf = Device::default().with_byte_time(Duration::from_micros(1));
+        let mut monitor = ThroughputMonitor::new(leaf, starved, policy_receiver);
+        let image = |len| SetButtonImage {
+            button: 0,
+            image: vec![0; len],
+        };
+
+        // Not switched until the hold is over
+        monitor.set_button_image(image(10_000)).await.unwrap();
+        assert!(!*starved_receiver.borrow());
+        tokio::time::advance(policy.hold).await;
+        monitor.set_button_image(image(10_000)).await.unwrap();
+        assert!(*starved_receiver.borrow());
+        assert_eq!(monitor.rate().map(f64::round), Some(1_000_000.0));
+
+        // Only recovered at twice the rate it was starved at
+        tokio::time::advance(policy.hold).await;
+        for _ in 0..50 {
+            monitor.record(30_000, Duration::from_millis(10));
+        }
+        assert!(*starved_receiver.borrow());
+        for _ in 0..50 {
+            monitor.record(100_000, Duration::from_millis(10));
+        }
+        assert!(!*starved_receiver.borrow());
+    }
+}
@@ -0,0 +1,184 @@
+//! Middleware deduplicating brightness changes on the device side of a pump.
+//!
+//! Companion sends BRIGHTNESS whenever a page or variable changes, usually
+//! with the value the device already has.  Setting the same brightness again
+//! costs a round trip to the leaf for nothing, so a [BrightnessSender] drops
+//! it.  The value last asked for is kept in a [LastBrightness] that outlives
+//! the connection, and is applied to the device as soon as it connects again.
+//!
+//! Brightness is mapped through the [BrightnessPolicy] in the settings of the
+//! device on its way to it, so a deck that is blinding at 100 can be tamed
+//! without changing what Companion sends.
+
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::watch;
+use tracing::{debug, trace};
+use traits::{
+    async_trait,
+    device::{BrightnessPolicy, DeviceSettings, SetBrightness, SetButtonImage, SetLCDImage},
+    Result,
+};
+
+/// The brightness last asked for a device, shared by its connections.
+#[derive(Clone, Debug, Default)]
+pub struct LastBrightness(Arc<Mutex<Option<u8>>>);
+
+impl LastBrightness {
+    /// The brightness last asked for, if any was.
+    pub fn get(&self) -> Option<u8> {
+        *self.0.lock().unwrap()
+    }
+
+    /// Take on the brightness asked for elsewhere, such as on another
+    /// gateway.  Applied the next time the device connects.
+    pub fn restore(&self, brightness: Option<u8>) {
+        *self.0.lock().unwrap() = brightness;
+    }
+
+    fn set(&self, brightness: u8) {
+        *self.0.lock().unwrap() = Some(brightness);
+    }
+}
+
+/// Wraps a device sender, dropping brightness changes that wouldn't change
+/// anything and recording the last one asked for.
+pub struct BrightnessSender<S> {
+    inner: S,
+    last: LastBrightness,
+    settings: watch::Receiver<DeviceSettings>,
+    /// The brightness the device was last set to, after the policy
+    applied: Option<u8>,
+}
+
+impl<S> BrightnessSender<S>
+where
+    S: traits::device::Sender + Send,
+{
+    /// Wrap a device sender, first applying the brightness last asked for
+    /// on an earlier connection of the device.  Changes to the brightness
+    /// policy in settings take effect with the next brightness asked for.
+    pub async fn new(
+        mut inner: S,
+        last: LastBrightness,
+        settings: watch::Receiver<DeviceSettings>,
+    ) -> Result<Self> {
+        let applied = last
+            .get()
+            .map(|brightness| policy(&settings).apply(brightness));
+        if let Some(brightness) = applied {
+            debug!("Restoring brightness {}", brightness);
+            inner.set_brightness(SetBrightness { brightness }).await?;
+        }
+        Ok(Self {
+            inner,
+            last,
+            settings,
+            applied,
+        })
+    }
+}
+
+fn policy(settings: &watch::Receiver<DeviceSettings>) -> BrightnessPolicy {
+    settings.borrow().brightness_policy
+}
+
+#[async_trait]
+impl<S> traits::device::Sender for BrightnessSender<S>
+where
+    S: traits::device::Sender + Send,
+{
+    async fn set_brightness(&mut self, brightness: SetBrightness) -> Result<()> {
+        self.last.set(brightness.brightness);
+        let brightness = policy(&self.settings).apply(brightness.brightness);
+        if self.applied == Some(brightness) {
+            trace!("Dropping unchanged brightness {}", brightness);
+            return Ok(());
+        }
+        self.applied = Some(brightness);
+        self.inner
+            .set_brightness(SetBrightness { brightness })
+            .await
+    }
+    async fn set_button_image(&mut self, image: SetButtonImage) -> Result<()> {
+        self.inner.set_button_image(image).await
+    }
+    async fn set_lcd_image(&mut self, image: SetLCDImage) -> Result<()> {
+        self.inner.set_lcd_image(image).await
+    }
+    async fn clear(&mut self) -> Result<()> {
+        self.inner.clear().await
+    }
+    async fn commit(&mut self) -> Result<()> {
+        self.inner.commit().await
+    }
+    async fn clear_all(&mut self) -> Result<()> {
+        self.inner.clear_all().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device::{Device, Written::Brightness};
+
+    #[tokio::test]
+    async fn test_duplicates_dropped_and_restored() {
+        use traits::device::Sender;
+
+        let last = LastBrightness::default();
+        let (settings, settings_receiver) = watch::channel(DeviceSettings::default());
+        let device = Device::default();
+        let mut sender =
+            BrightnessSender::new(device.clone(), last.clone(), settings_receiver.clone())
+                .await
+                .unwrap();
+        for brightness in [50, 50, 80, 80] {
+            sender
+                .set_brightness(SetBrightness { brightness })
+                .await
+                .unwrap();
+        }
+        drop(sender);
+        assert_eq!(device.written(), [Brightness(50), Brightness(80)]);
+
+        // The device connects again and gets the last brightness straight away
+        let device = Device::default();
+        let mut sender =
+            BrightnessSender::new(device.clone(), last.clone(), settings_receiver.clone())
+                .await
+                .unwrap();
+        sender
+            .set_brightness(SetBrightness { brightness: 80 })
+            .await
+            .unwrap();
+        drop(sender);
+        assert_eq!(device.written(), [Brightness(80)]);
+        assert_eq!(last.get(), Some(80));
+
+        // A blinding deck is curved and clamped, Companion's value still
+        // being the one kept
+        let policy = BrightnessPolicy {
+            gamma: 2.0,
+            max: 60,
+            ..Default::default()
+        };
+        settings.send_modify(|settings| settings.brightness_policy = policy);
+        let device = Device::default();
+        let mut sender = BrightnessSender::new(device.clone(), last.clone(), settings_receiver)
+            .await
+            .unwrap();
+        for brightness in [50, 100, 90] {
+            sender
+                .set_brightness(SetBrightness { brightness })
+                .await
+                .unwrap();
+        }
+        drop(sender);
+        assert_eq!(
+            device.written(),
+            [Brightness(60), Brightness(25), Brightness(60)]
+        );
+        assert_eq!(last.get(), Some(90));
+    }
+}
@@ -0,0 +1,76 @@
+//! Showing the images of a page switch all at once.
+//!
+//! On a slow link the images of a page switch arrive one after another, and
+//! a deck showing each as it arrives ripples across its keys.  Leaves with
+//! the COMMIT capability are instead sent each image as prepared, and a
+//! commit once the gateway has no more images ready.  [Prepared] holds the
+//! images of a leaf until the commit and then hands them on together, so
+//! they are written to the deck back to back, as fast as it takes them.
+
+use std::collections::BTreeMap;
+
+use traits::device::{DeviceActions, SetButtonImage};
+
+/// Images prepared since the last commit.
+#[derive(Debug, Default)]
+pub struct Prepared {
+    /// The last image prepared for each button
+    images: BTreeMap<u8, SetButtonImage>,
+}
+
+impl Prepared {
+    /// The actions to schedule for action: none for an image being
+    /// prepared, every image prepared on a commit, and any other action as
+    /// it is.  Blanking the device drops the images prepared.
+    pub fn take(&mut self, action: DeviceActions) -> Vec<DeviceActions> {
+        match action {
+            DeviceActions::PrepareButtonImage(image) => {
+                self.images.insert(image.button, image);
+                Vec::new()
+            }
+            DeviceActions::Commit => std::mem::take(&mut self.images)
+                .into_values()
+                .map(DeviceActions::SetButtonImage)
+                .collect(),
+            DeviceActions::ClearAll => {
+                self.images.clear();
+                vec![DeviceActions::ClearAll]
+            }
+            action => vec![action],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traits::device::SetBrightness;
+
+    #[test]
+    fn test_images_held_until_commit() {
+        let prepare = |button, tag| {
+            DeviceActions::PrepareButtonImage(SetButtonImage {
+                button,
+                image: vec![tag],
+            })
+        };
+        let mut prepared = Prepared::default();
+        for (button, tag) in [(4, 1), (2, 1), (4, 2)] {
+            assert!(prepared.take(prepare(button, tag)).is_empty());
+        }
+        let brightness = DeviceActions::SetBrightness(SetBrightness { brightness: 10 });
+        assert_eq!(prepared.take(brightness).len(), 1);
+
+        let shown: Vec<_> = prepared
+            .take(DeviceActions::Commit)
+            .into_iter()
+            .map(|action| match action {
+                DeviceActions::SetButtonImage(image) => (image.button, image.image[0]),
+                other => panic!("Expected a button image, got {other:?}"),
+            })
+            .collect();
+        // Only the last image of a button is shown
+        assert_eq!(shown, [(2, 1), (4, 2)]);
+        assert!(prepared.take(DeviceActions::Commit).is_empty());
+    }
+}
@@ -0,0 +1,135 @@
+//! Finding Companion and gateways on the LAN over mDNS.
+//!
+//! Companion advertises its satellite API as a `_companion-satellite._tcp`
+//! service.  A gateway advertises the port it takes leaves on under the same
+//! type, with a `role=gateway` TXT record telling the two apart, so that a
+//! satellite looking for Companion skips gateways and a leaf looking for a
+//! gateway skips Companion.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use tracing::{debug, info};
+use traits::{anyhow, Result};
+
+/// The service type Companion and gateways are advertised under.
+pub const SERVICE_TYPE: &str = "_companion-satellite._tcp.local.";
+
+/// How long to browse for a service before giving up, by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// TXT property naming what a service is.  Only gateways set it.
+const ROLE_PROPERTY: &str = "role";
+const GATEWAY_ROLE: &str = "gateway";
+
+/// What a service found over mDNS is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Companion, taking satellites
+    Companion,
+    /// A gateway, taking leaves
+    Gateway,
+}
+
+impl Role {
+    fn of(info: &ServiceInfo) -> Self {
+        match info.get_property_val_str(ROLE_PROPERTY) {
+            Some(GATEWAY_ROLE) => Role::Gateway,
+            _ => Role::Companion,
+        }
+    }
+}
+
+/// Browse for a service in the role, returning the address of the first one
+/// to answer.  Fails if none does within timeout.
+pub async fn discover(role: Role, timeout: Duration) -> Result<SocketAddr> {
+    let daemon = ServiceDaemon::new()?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+    let found = tokio::time::timeout(timeout, async {
+        while let Ok(event) = events.recv_async().await {
+            let ServiceEvent::ServiceResolved(info) = event else {
+                continue;
+            };
+            if Role::of(&info) != role {
+                debug!("Skipping {} in the wrong role", info.get_fullname());
+                continue;
+            }
+            // IPv4 first, as IPv6 link-local addresses need a scope to be
+            // connected to
+            let Some(ip) = info.get_addresses().iter().min_by_key(|ip| ip.is_ipv6()) else {
+                continue;
+            };
+            info!("Discovered {:?} {}", role, info.get_fullname());
+            return Some(SocketAddr::new(*ip, info.get_port()));
+        }
+        None
+    })
+    .await;
+    if let Err(e) = daemon.shutdown() {
+        debug!("Error stopping mDNS: {}", e);
+    }
+    match found {
+        Ok(Some(addr)) => Ok(addr),
+        _ => Err(anyhow::anyhow!(
+            "No {:?} found over mDNS within {:?}",
+            role,
+            timeout
+        )),
+    }
+}
+
+/// A service advertised over mDNS until dropped.
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+/// Advertise a gateway taking leaves on port, as name.  The addresses of
+/// every interface are advertised, following them as they change.
+pub fn advertise_gateway(name: &str, port: u16) -> Result<Advertisement> {
+    let daemon = ServiceDaemon::new()?;
+    let properties = [(ROLE_PROPERTY, GATEWAY_ROLE)];
+    let host_name = format!("{name}.local.");
+    let info = ServiceInfo::new(SERVICE_TYPE, name, &host_name, (), port, &properties[..])?
+        .enable_addr_auto();
+    let fullname = info.get_fullname().to_string();
+    daemon.register(info)?;
+    info!("Advertising the gateway as {}", fullname);
+    Ok(Advertisement { daemon, fullname })
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        // Leaves hear the gateway is gone rather than waiting for it to
+        // expire
+        if let Err(e) = self.daemon.unregister(&self.fullname) {
+            debug!("Error unregistering {}: {}", self.fullname, e);
+        }
+        if let Err(e) = self.daemon.shutdown() {
+            debug!("Error stopping mDNS: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gateways_told_from_companion() {
+        let service = |properties: &[(&str, &str)]| {
+            ServiceInfo::new(SERVICE_TYPE, "a", "a.local.", "10.0.0.1", 16622, properties).unwrap()
+        };
+        assert_eq!(Role::of(&service(&[])), Role::Companion);
+        assert_eq!(
+            Role::of(&service(&[(ROLE_PROPERTY, GATEWAY_ROLE)])),
+            Role::Gateway
+        );
+        // Roles unknown to this version are not taken for gateways
+        assert_eq!(
+            Role::of(&service(&[(ROLE_PROPERTY, "relay")])),
+            Role::Companion
+        );
+    }
+}
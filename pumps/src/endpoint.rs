@@ -0,0 +1,61 @@
+//! Where Companion is, changeable while running.
+//!
+//! A [CompanionEndpoint] is shared by everything connecting to Companion.
+//! Setting it to another address repoints them: each closes its connection
+//! cleanly, removing its device, and adds the device again on a connection
+//! to the new address, without the devices themselves being disturbed.
+
+use std::sync::Arc;
+
+use tokio::sync::watch;
+use tracing::info;
+
+/// The host:port of Companion, shared by clones.
+#[derive(Clone, Debug)]
+pub struct CompanionEndpoint {
+    addr: Arc<watch::Sender<String>>,
+}
+
+impl CompanionEndpoint {
+    /// An endpoint at addr.
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: Arc::new(watch::channel(addr.into()).0),
+        }
+    }
+
+    /// The address connections should be made to.
+    pub fn get(&self) -> String {
+        self.addr.borrow().clone()
+    }
+
+    /// Repoint at addr.  Returns whether it changed anything.
+    pub fn set(&self, addr: impl Into<String>) -> bool {
+        let addr = addr.into();
+        self.addr.send_if_modified(|current| {
+            if *current == addr {
+                return false;
+            }
+            info!("Repointing Companion from {} to {}", current, addr);
+            *current = addr;
+            true
+        })
+    }
+
+    /// Watch for the endpoint being repointed.
+    pub fn subscribe(&self) -> watch::Receiver<String> {
+        self.addr.subscribe()
+    }
+}
+
+/// Shut connection down once repointed changes, then wait forever so the
+/// connection can close cleanly.
+pub async fn shutdown_when_repointed(
+    repointed: &mut watch::Receiver<String>,
+    connection: &crate::shutdown::ShutdownHandle,
+) {
+    if repointed.changed().await.is_ok() {
+        connection.shutdown_because(traits::device::ShutdownReason::Repointed);
+    }
+    std::future::pending().await
+}
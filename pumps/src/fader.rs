@@ -0,0 +1,409 @@
+//! Encoders acting as faders.
+//!
+//! Companion only hears about encoders as relative KEY-ROTATE steps, which
+//! is awkward for anything with a natural range, like a volume.  Encoders
+//! listed in the `faders` of the [DeviceSettings] instead add their ticks up
+//! into a value from 0 to [FADER_MAX], kept on this side in [Faders].
+//!
+//! [FaderReceiver] only passes on the ticks that actually move a fader, so a
+//! rotate action in Companion stops with the fader at either end instead of
+//! drifting away from it.  Companion may have counted anything by the time a
+//! device connects, so the first twist of each fader pushes its absolute
+//! value instead: a burst of [FADER_MAX] counterclockwise steps, taking the
+//! count down to 0, then the value in clockwise steps.  [FaderDisplay] draws
+//! the value as a bar on the LCD segment above the encoder, in place of the
+//! image from Companion.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::Arc;
+
+use tokio::sync::watch;
+use tracing::{trace, warn};
+use traits::{
+    async_trait,
+    device::{Command, DeviceActions, DeviceSettings, SetLCDImage},
+    Result,
+};
+
+use crate::video::EncodeLcd;
+
+/// The value of a fader turned all the way up.
+pub const FADER_MAX: u8 = 100;
+
+/// Color of the part of the bar up to the value.
+const FILL: [u8; 3] = [0x30, 0xc0, 0x60];
+/// Color of the rest of the bar.
+const TRACK: [u8; 3] = [0x40, 0x40, 0x40];
+
+/// The values of the faders of a device, shared by both sides of its pump
+/// and kept across its connections.  Faders never moved are at 0.
+#[derive(Clone, Debug)]
+pub struct Faders(Arc<watch::Sender<BTreeMap<u8, u8>>>);
+
+impl Default for Faders {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(BTreeMap::new()).0))
+    }
+}
+
+impl Faders {
+    /// The value of the fader of an encoder.
+    pub fn get(&self, encoder: u8) -> u8 {
+        self.0.borrow().get(&encoder).copied().unwrap_or(0)
+    }
+
+    /// The values of every fader that was moved.
+    pub fn values(&self) -> BTreeMap<u8, u8> {
+        self.0.borrow().clone()
+    }
+
+    /// Take on values set elsewhere, such as on another gateway.
+    pub fn restore(&self, values: BTreeMap<u8, u8>) {
+        self.0.send_if_modified(|current| {
+            let changed = *current != values;
+            *current = values;
+            changed
+        });
+    }
+
+    /// Move the fader of an encoder by ticks, returning how far it actually
+    /// moved before hitting either end.
+    fn twist(&self, encoder: u8, ticks: i8) -> i8 {
+        let mut moved = 0;
+        self.0.send_if_modified(|values| {
+            let value = values.entry(encoder).or_insert(0);
+            let target = (i16::from(*value) + i16::from(ticks)).clamp(0, FADER_MAX.into());
+            moved = (target - i16::from(*value)) as i8;
+            *value = target as u8;
+            moved != 0
+        });
+        moved
+    }
+}
+
+/// Wraps a device receiver, turning the twists of fader encoders into moves
+/// of their faders.
+pub struct FaderReceiver<R> {
+    inner: R,
+    faders: Faders,
+    settings: watch::Receiver<DeviceSettings>,
+    /// Faders whose absolute value Companion was sent
+    synced: BTreeSet<u8>,
+}
+
+impl<R> FaderReceiver<R>
+where
+    R: traits::device::Receiver + Send,
+{
+    /// Wrap a device receiver, moving faders of the encoders the settings
+    /// list.
+    pub fn new(inner: R, faders: Faders, settings: watch::Receiver<DeviceSettings>) -> Self {
+        Self {
+            inner,
+            faders,
+            settings,
+            synced: BTreeSet::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl<R> traits::device::Receiver for FaderReceiver<R>
+where
+    R: traits::device::Receiver + Send,
+{
+    async fn receive(&mut self) -> Result<Command> {
+        loop {
+            let mut command = self.inner.receive().await?;
+            if let Command::EncoderTwist(twist) = &mut command {
+                let fader_encoders = self.settings.borrow().faders.clone();
+                self.synced
+                    .retain(|encoder| fader_encoders.contains(encoder));
+                let mut encoders = Vec::with_capacity(twist.encoders.len());
+                for (encoder, ticks) in std::mem::take(&mut twist.encoders) {
+                    if !fader_encoders.contains(&encoder) {
+                        encoders.push((encoder, ticks));
+                        continue;
+                    }
+                    let moved = self.faders.twist(encoder, ticks);
+                    if self.synced.insert(encoder) {
+                        encoders.push((encoder, -(FADER_MAX as i8)));
+                        encoders.push((encoder, self.faders.get(encoder) as i8));
+                    } else {
+                        encoders.push((encoder, moved));
+                    }
+                }
+                encoders.retain(|&(_, ticks)| ticks != 0);
+                twist.encoders = encoders;
+                if twist.encoders.is_empty() {
+                    trace!("Fader twist didn't move anything");
+                    continue;
+                }
+            }
+            return Ok(command);
+        }
+    }
+}
+
+/// Wraps a companion receiver, adding the bars of the faders to the actions
+/// it receives.  Images from Companion for the segments above faders are
+/// dropped so the two don't fight over them.
+///
+/// The wrapped receiver must be cancel safe, as a fader moving interrupts
+/// waiting on it.
+pub struct FaderDisplay<R> {
+    inner: R,
+    values: watch::Receiver<BTreeMap<u8, u8>>,
+    settings: watch::Receiver<DeviceSettings>,
+    size: (u16, u16),
+    segments: u16,
+    encode: Option<EncodeLcd>,
+    drawn: BTreeMap<u8, u8>,
+    pending: VecDeque<DeviceActions>,
+}
+
+impl<R> FaderDisplay<R>
+where
+    R: traits::companion::Receiver + Send,
+{
+    /// Wrap a companion receiver.  size is the size of the LCD strip of the
+    /// device, split into one segment per encoder.
+    pub fn new(
+        inner: R,
+        faders: &Faders,
+        settings: watch::Receiver<DeviceSettings>,
+        size: (u16, u16),
+        segments: u8,
+    ) -> Self {
+        Self {
+            inner,
+            values: faders.0.subscribe(),
+            settings,
+            size,
+            segments: segments.max(1).into(),
+            encode: None,
+            drawn: BTreeMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Encode the bars for the LCD strip of the device with encode, rather
+    /// than sending them as packed RGB.
+    pub fn with_encoder(mut self, encode: EncodeLcd) -> Self {
+        self.encode = Some(encode);
+        self
+    }
+
+    /// Whether the LCD image at x_offset falls in the segment of a fader.
+    fn covers_fader(&self, x_offset: u16) -> bool {
+        let segment = u32::from(x_offset) * u32::from(self.segments) / u32::from(self.size.0);
+        let faders = &self.settings.borrow().faders;
+        u8::try_from(segment).is_ok_and(|segment| faders.contains(&segment))
+    }
+
+    /// Queue a bar for every fader whose value isn't the one drawn.
+    fn queue_changed(&mut self) {
+        let faders = self.settings.borrow_and_update().faders.clone();
+        let values = self.values.borrow_and_update().clone();
+        self.drawn.retain(|encoder, _| faders.contains(encoder));
+        for encoder in faders {
+            let value = values.get(&encoder).copied().unwrap_or(0);
+            if u16::from(encoder) >= self.segments || self.drawn.get(&encoder) == Some(&value) {
+                continue;
+            }
+            self.drawn.insert(encoder, value);
+            let width = self.size.0 / self.segments;
+            let bar = render_bar(width, self.size.1, value);
+            let image = match &self.encode {
+                Some(encode) => match encode(width, self.size.1, bar) {
+                    Ok(image) => image,
+                    Err(e) => {
+                        warn!("Couldn't encode the bar of fader {}: {:#}", encoder, e);
+                        continue;
+                    }
+                },
+                None => bar,
+            };
+            self.pending
+                .push_back(DeviceActions::SetLCDImage(SetLCDImage {
+                    x_offset: u16::from(encoder) * width,
+                    x_size: width,
+                    y_size: self.size.1,
+                    image,
+                }));
+        }
+    }
+}
+
+#[async_trait]
+impl<R> traits::companion::Receiver for FaderDisplay<R>
+where
+    R: traits::companion::Receiver + Send,
+{
+    async fn receive(&mut self) -> Result<DeviceActions> {
+        if self.size.0 == 0 {
+            return self.inner.receive().await;
+        }
+        loop {
+            self.queue_changed();
+            if let Some(action) = self.pending.pop_front() {
+                return Ok(action);
+            }
+            tokio::select! {
+                action = self.inner.receive() => {
+                    let action = action?;
+                    if let DeviceActions::SetLCDImage(image) = &action {
+                        if self.covers_fader(image.x_offset) {
+                            trace!("Ignoring LCD image from Companion above a fader");
+                            continue;
+                        }
+                    }
+                    return Ok(action);
+                }
+                _ = changed(&mut self.values) => {}
+                _ = changed(&mut self.settings) => {}
+            }
+        }
+    }
+}
+
+/// Wait for a watched value to change.  If it can no longer change, this
+/// never completes.
+async fn changed<T>(receiver: &mut watch::Receiver<T>) {
+    if receiver.changed().await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Draw a horizontal bar filled up to value, as packed 8 bit RGB.
+fn render_bar(width: u16, height: u16, value: u8) -> Vec<u8> {
+    let (width, height) = (usize::from(width), usize::from(height));
+    let margin = width / 10;
+    let filled = margin + (width - 2 * margin) * usize::from(value.min(FADER_MAX)) / 100;
+    let bar = height * 2 / 5..height * 3 / 5;
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            let color = if !bar.contains(&y) || x < margin || x >= width - margin {
+                [0; 3]
+            } else if x < filled {
+                FILL
+            } else {
+                TRACK
+            };
+            rgb.extend_from_slice(&color);
+        }
+    }
+    rgb
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traits::device::EncoderTwist;
+
+    struct Twists(Vec<Vec<(u8, i8)>>);
+
+    #[async_trait]
+    impl traits::device::Receiver for Twists {
+        async fn receive(&mut self) -> Result<Command> {
+            let encoders = self.0.remove(0);
+            Ok(Command::EncoderTwist(EncoderTwist { encoders }))
+        }
+    }
+
+    struct Quiet;
+
+    #[async_trait]
+    impl traits::companion::Receiver for Quiet {
+        async fn receive(&mut self) -> Result<DeviceActions> {
+            std::future::pending().await
+        }
+    }
+
+    fn received(command: Command) -> Vec<(u8, i8)> {
+        match command {
+            Command::EncoderTwist(twist) => twist.encoders,
+            command => panic!("Unexpected command {command:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fader_twists_clamped() {
+        use traits::companion::Receiver as _;
+        use traits::device::Receiver as _;
+
+        let settings = DeviceSettings {
+            faders: vec![0],
+            ..Default::default()
+        };
+        let (_settings, settings) = watch::channel(settings);
+        let faders = Faders::default();
+        let twists = Twists(vec![
+            vec![(0, -5), (1, -5)],
+            vec![(0, 90)],
+            vec![(0, 20)],
+            vec![(0, 20), (1, 1)],
+        ]);
+        let mut receiver = FaderReceiver::new(twists, faders.clone(), settings.clone());
+        let mut display = FaderDisplay::new(Quiet, &faders, settings, (800, 100), 4);
+
+        // The first twist takes Companion to 0 with the fader, other encoders
+        // are left alone
+        assert_eq!(
+            received(receiver.receive().await.unwrap()),
+            [(0, -100), (1, -5)]
+        );
+        assert_eq!(received(receiver.receive().await.unwrap()), [(0, 90)]);
+        assert_eq!(received(receiver.receive().await.unwrap()), [(0, 10)]);
+        // Twists of a full fader are swallowed
+        assert_eq!(received(receiver.receive().await.unwrap()), [(1, 1)]);
+        assert_eq!(faders.get(0), FADER_MAX);
+
+        match display.receive().await.unwrap() {
+            DeviceActions::SetLCDImage(image) => {
+                assert_eq!((image.x_offset, image.x_size, image.y_size), (0, 200, 100));
+                assert_eq!(image.image, render_bar(200, 100, FADER_MAX));
+            }
+            action => panic!("Unexpected action {action:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_absolute_value_pushed_on_first_twist() {
+        use traits::companion::Receiver as _;
+        use traits::device::Receiver as _;
+
+        let settings = DeviceSettings {
+            faders: vec![2],
+            ..Default::default()
+        };
+        let (_settings, settings) = watch::channel(settings);
+        // Left at 40 by an earlier connection
+        let faders = Faders::default();
+        faders.restore(BTreeMap::from([(2, 40)]));
+        let twists = Twists(vec![vec![(2, 5)], vec![(2, 5)]]);
+        let mut receiver = FaderReceiver::new(twists, faders.clone(), settings.clone());
+
+        assert_eq!(
+            received(receiver.receive().await.unwrap()),
+            [(2, -100), (2, 45)]
+        );
+        assert_eq!(received(receiver.receive().await.unwrap()), [(2, 5)]);
+
+        // Bars are encoded for the strip
+        let encode: EncodeLcd = Arc::new(|width, height, rgb| {
+            assert_eq!(rgb.len(), usize::from(width) * usize::from(height) * 3);
+            Ok(vec![0xff, 0xd8])
+        });
+        let mut display =
+            FaderDisplay::new(Quiet, &faders, settings, (800, 100), 4).with_encoder(encode);
+        match display.receive().await.unwrap() {
+            DeviceActions::SetLCDImage(image) => {
+                assert_eq!(image.x_offset, 400);
+                assert_eq!(image.image, [0xff, 0xd8]);
+            }
+            action => panic!("Unexpected action {action:?}"),
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Injection of synthetic device input.
+//!
+//! Button presses and encoder twists can be fed into a running pump as if
+//! they came from the device, which is used to replay scripted input when
+//! testing Companion configurations.  The admin sockets of the gateway and
+//! the satellite both take the input as lines read by [parse_input]:
+//!
+//! ```text
+//! PRESS <device_id> <key>
+//! RELEASE <device_id> <key>
+//! TWIST <device_id> <encoder> <delta>
+//! ```
+
+use tokio::sync::mpsc;
+use tracing::Instrument;
+use traits::{
+    anyhow, async_trait,
+    device::{ButtonChange, Command, EncoderTwist},
+    Result,
+};
+
+/// Number of injected commands that can be waiting to be forwarded.
+const INJECT_QUEUE: usize = 32;
+
+/// Create the channel synthetic commands are injected on.
+pub fn channel() -> (mpsc::Sender<Command>, mpsc::Receiver<Command>) {
+    mpsc::channel(INJECT_QUEUE)
+}
+
+/// Read a line of input to inject, returning the device id it is for and
+/// the command to inject.
+pub fn parse_input(line: &str) -> Result<(String, Command)> {
+    let mut words = line.split_whitespace();
+    let input = words.next().unwrap_or_default().to_ascii_uppercase();
+    let mut arg = |name: &str| {
+        words
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Missing argument: {name}"))
+    };
+    let device_id = arg("device_id")?.to_string();
+    let command = match input.as_str() {
+        "PRESS" | "RELEASE" => Command::ButtonChange(ButtonChange {
+            buttons: vec![(arg("key")?.parse()?, input == "PRESS")],
+        }),
+        "TWIST" => Command::EncoderTwist(EncoderTwist {
+            encoders: vec![(arg("encoder")?.parse()?, arg("delta")?.parse()?)],
+        }),
+        _ => anyhow::bail!("{input} isn't input"),
+    };
+    Ok((device_id, command))
+}
+
+/// Wraps a device receiver, merging injected commands with the ones read
+/// from the device.
+///
+/// Reading from the device isn't necessarily cancel safe, so it is done from
+/// a separate task that forwards what it reads.
+pub struct InjectingReceiver {
+    device: mpsc::Receiver<Result<Command>>,
+    injected: mpsc::Receiver<Command>,
+}
+
+impl InjectingReceiver {
+    /// Wrap a device receiver, also receiving whatever is sent on injected.
+    pub fn new<R>(mut inner: R, injected: mpsc::Receiver<Command>) -> Self
+    where
+        R: traits::device::Receiver + Send + 'static,
+    {
+        let (sender, device) = mpsc::channel(1);
+        tokio::spawn(
+            async move {
+                loop {
+                    let command = inner.receive().await;
+                    let failed = command.is_err();
+                    if sender.send(command).await.is_err() || failed {
+                        break;
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+        Self { device, injected }
+    }
+}
+
+#[async_trait]
+impl traits::device::Receiver for InjectingReceiver {
+    async fn receive(&mut self) -> Result<Command> {
+        tokio::select! {
+            command = self.device.recv() => {
+                command.unwrap_or_else(|| Err(traits::anyhow::anyhow!("Device receiver stopped")))
+            }
+            Some(command) = self.injected.recv() => Ok(command),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_input_parsed() {
+        let (device_id, command) = parse_input("press deck 3").unwrap();
+        assert_eq!(device_id, "deck");
+        assert!(matches!(
+            command,
+            Command::ButtonChange(ButtonChange { buttons }) if buttons == [(3, true)]
+        ));
+        let (_, command) = parse_input("TWIST deck 1 -2").unwrap();
+        assert!(matches!(
+            command,
+            Command::EncoderTwist(EncoderTwist { encoders }) if encoders == [(1, -2)]
+        ));
+        assert!(parse_input("RELEASE deck").is_err());
+        assert!(parse_input("KICK deck").is_err());
+    }
+    /// Answers with the name of the span it is read in.
+    struct SpanReporter;
+
+    #[async_trait]
+    impl traits::device::Receiver for SpanReporter {
+        async fn receive(&mut self) -> Result<Command> {
+            let span = tracing::Span::current();
+            let name = span.metadata().map(|m| m.name()).unwrap_or_default();
+            anyhow::bail!("read in {name:?}")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_device_read_in_span_of_wrapper() {
+        let _subscriber = tracing::subscriber::set_default(tracing_subscriber::registry());
+        let (_injected, injected_receiver) = channel();
+        let mut receiver = tracing::info_span!("leaf")
+            .in_scope(|| InjectingReceiver::new(SpanReporter, injected_receiver));
+
+        let err = traits::device::Receiver::receive(&mut receiver)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "read in \"leaf\"");
+    }
+}
@@ -12,10 +12,72 @@
 #![warn(missing_docs)]
 
 use std::future::Future;
+use std::sync::{Arc, Mutex};
 
-use tracing::trace;
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+use tracing::{info, trace};
+use traits::device::{DeviceSettings, ShutdownReason};
 use traits::Result;
 
+/// Health counters for message pumps.
+pub mod stats;
+/// Middleware applying runtime device settings.
+pub mod settings;
+/// Per zone scheduling of device updates.
+pub mod schedule;
+/// Injection of synthetic device input.
+pub mod inject;
+/// Experimental video streaming to the LCD strip.
+pub mod video;
+/// Reconnection to Companion with backoff.
+pub mod reconnect;
+/// Repointing of running connections at another Companion.
+pub mod endpoint;
+/// Deduplication of brightness changes.
+pub mod brightness;
+/// Showing the images of keys again on connect.
+pub mod panel;
+/// Encoders acting as faders.
+pub mod fader;
+/// Startup of device sessions.
+pub mod session;
+/// Repeating held keys and holding long presses.
+pub mod repeat;
+/// Saving the battery of leaves running on one.
+pub mod power;
+/// Falling back to colors for leaves starved of bandwidth.
+pub mod bandwidth;
+/// Stopping message pumps cleanly.
+pub mod shutdown;
+/// Showing the images of a page switch all at once.
+pub mod commit;
+/// Status images sent up by leaves.
+pub mod upstream;
+/// Transcripts of device traffic for bug reports.
+pub mod transcript;
+/// Sharing the CPU of the host with more important work.
+pub mod low_impact;
+/// Finding Companion and gateways over mDNS.
+#[cfg(feature = "discovery")]
+#[cfg_attr(docsrs, doc(cfg(feature = "discovery")))]
+pub mod discovery;
+/// A fake device for testing what is written to devices.
+#[cfg(test)]
+pub(crate) mod test_device;
+pub use reconnect::{run_with_reconnect, run_with_reconnect_until};
+use commit::Prepared;
+use schedule::{Next, Schedule};
+use shutdown::ShutdownHandle;
+use stats::PumpStats;
+
+/// Maximum number of device actions buffered between the companion app and
+/// the device.  Once reached, the companion side waits for the device.
+const MAX_PENDING: usize = 64;
+/// Most button images set before the device is told to show them, even if
+/// more are ready, so a steady stream of images is still shown.
+const MAX_UNCOMMITTED: usize = MAX_PENDING;
+
 /// Create devices and connect them together with a message pump.
 /// In the common case, this can create an entire application in
 /// a single call with provided factory functions.
@@ -52,20 +114,95 @@ where
 /// This function will return when either of the two operations returns an error or
 /// if they both succeed (using tokio::tryjoin!).
 pub async fn message_pump(
-    device_sender: impl traits::device::Sender,
+    device_sender: impl traits::device::Sender + Send,
+    device_receiver: impl traits::device::Receiver,
+    companion_sender: impl traits::companion::Sender + Send,
+    companion_receiver: impl traits::companion::Receiver,
+) -> Result<()> {
+    message_pump_with_stats(
+        device_sender,
+        device_receiver,
+        companion_sender,
+        companion_receiver,
+        Default::default(),
+    )
+    .await
+}
+
+/// Same as [message_pump], but records the traffic through the pump in the
+/// provided stats so it can be observed from other tasks.
+pub async fn message_pump_with_stats(
+    device_sender: impl traits::device::Sender + Send,
+    device_receiver: impl traits::device::Receiver,
+    companion_sender: impl traits::companion::Sender + Send,
+    companion_receiver: impl traits::companion::Receiver,
+    stats: Arc<PumpStats>,
+) -> Result<()> {
+    message_pump_with_settings(
+        device_sender,
+        device_receiver,
+        companion_sender,
+        companion_receiver,
+        stats,
+        watch::channel(Default::default()).1,
+    )
+    .await
+}
+
+/// Same as [message_pump_with_stats], but updates to the device are paced and
+/// coalesced according to the fps cap and zones of the provided settings.
+/// The settings may change while the pump is running.
+pub async fn message_pump_with_settings(
+    device_sender: impl traits::device::Sender + Send,
     device_receiver: impl traits::device::Receiver,
-    companion_sender: impl traits::companion::Sender,
+    companion_sender: impl traits::companion::Sender + Send,
     companion_receiver: impl traits::companion::Receiver,
+    stats: Arc<PumpStats>,
+    settings: watch::Receiver<DeviceSettings>,
 ) -> Result<()> {
-    let device_to_companion = handle_device_to_companion(device_receiver, companion_sender);
-    let companion_to_device = handle_companion_to_device(companion_receiver, device_sender);
+    message_pump_until(
+        device_sender,
+        device_receiver,
+        companion_sender,
+        companion_receiver,
+        stats,
+        settings,
+        ShutdownHandle::new(),
+    )
+    .await
+}
+
+/// Same as [message_pump_with_settings], but stops once shutdown is shut
+/// down.  Companion is then closed, after writing out any input still held,
+/// and the deck is blanked.  Returns Ok if nothing failed along the way.
+pub async fn message_pump_until(
+    device_sender: impl traits::device::Sender + Send,
+    device_receiver: impl traits::device::Receiver,
+    companion_sender: impl traits::companion::Sender + Send,
+    companion_receiver: impl traits::companion::Receiver,
+    stats: Arc<PumpStats>,
+    settings: watch::Receiver<DeviceSettings>,
+    shutdown: ShutdownHandle,
+) -> Result<()> {
+    let device_to_companion =
+        handle_device_to_companion(device_receiver, companion_sender, &stats, &shutdown);
+    let companion_to_device = handle_companion_to_device(
+        companion_receiver,
+        device_sender,
+        &stats,
+        settings,
+        &shutdown,
+    );
 
     // Wait for all tasks to complete.  If there is an error, abort early.
     let res = tokio::try_join!(device_to_companion, companion_to_device);
 
     match res {
         Ok(_) => Ok(()),
-        Err(e) => Err(e),
+        Err(e) => {
+            stats.record_error();
+            Err(e)
+        }
     }
 }
 
@@ -76,45 +213,212 @@ pub async fn message_pump(
 /// statement is updated.
 async fn handle_device_to_companion(
     mut device_receiver: impl traits::device::Receiver,
-    mut companion_sender: impl traits::companion::Sender,
+    mut companion_sender: impl traits::companion::Sender + Send,
+    stats: &PumpStats,
+    shutdown: &ShutdownHandle,
 ) -> Result<()> {
     loop {
-        let action = device_receiver.receive().await?;
+        let action = tokio::select! {
+            action = device_receiver.receive() => action?,
+            _ = shutdown.wait() => {
+                let reason = shutdown.reason().unwrap_or(ShutdownReason::Shutdown);
+                companion_sender.goodbye(reason).await?;
+                return companion_sender.close().await;
+            }
+        };
         trace!("handle_device_to_companion: {:?}", action);
         match action {
             traits::device::Command::Config(c) => companion_sender.config(c).await?,
             traits::device::Command::ButtonChange(change) => {
+                for &(key, pressed) in &change.buttons {
+                    stats.record_key(key, pressed);
+                }
                 companion_sender.button_change(change).await?
             }
             traits::device::Command::EncoderTwist(twist) => {
                 companion_sender.encoder_twist(twist).await?
             }
+            traits::device::Command::EncoderPress(press) => {
+                companion_sender.encoder_press(press).await?
+            }
+            traits::device::Command::Touch(touch) => companion_sender.touch(touch).await?,
+            traits::device::Command::Power(power) => companion_sender.power(power).await?,
+            traits::device::Command::StatusImage(image) => {
+                companion_sender.status_image(image).await?
+            }
+            // The device is leaving, which ends the pump as a shutdown
+            // would, passing its reason on
+            traits::device::Command::Goodbye(reason) => {
+                info!("Device is disconnecting: {}", reason);
+                shutdown.shutdown_because(reason);
+                continue;
+            }
         }
+        stats.record_to_companion();
     }
 }
 
 /// handle_companion_to_device takes a companion receiver and a device sender
-/// and asynchronously moves data between them.  A complete match statement
-/// is provided to handle all possible companion commands and any new commands
-/// added to the companion trait will be a compile time error until the match
-/// statement is updated.
+/// and asynchronously moves data between them.  Actions from the companion
+/// are queued in a [Schedule] which decides when each is delivered to the
+/// device, so zones with different update policies don't hold each other up.
 async fn handle_companion_to_device(
+    companion_receiver: impl traits::companion::Receiver,
+    device_sender: impl traits::device::Sender + Send,
+    stats: &PumpStats,
+    settings: watch::Receiver<DeviceSettings>,
+    shutdown: &ShutdownHandle,
+) -> Result<()> {
+    let schedule = Mutex::new(Schedule::new(settings.borrow().clone()));
+    let pending = Notify::new();
+    let space = Notify::new();
+
+    let receive = receive_from_companion(
+        companion_receiver,
+        &schedule,
+        &pending,
+        &space,
+        stats,
+        shutdown,
+    );
+    let deliver = deliver_to_device(
+        device_sender,
+        &schedule,
+        &pending,
+        &space,
+        stats,
+        settings,
+        shutdown,
+    );
+    tokio::try_join!(receive, deliver)?;
+    Ok(())
+}
+
+/// Receive actions from the companion and add them to the schedule.
+async fn receive_from_companion(
     mut companion_receiver: impl traits::companion::Receiver,
-    mut device_sender: impl traits::device::Sender,
+    schedule: &Mutex<Schedule>,
+    pending: &Notify,
+    space: &Notify,
+    stats: &PumpStats,
+    shutdown: &ShutdownHandle,
 ) -> Result<()> {
+    let mut prepared = Prepared::default();
     loop {
-        let action = companion_receiver.receive().await?;
-        trace!("handle_device_to_companion: {:?}", action);
-        match action {
-            traits::device::DeviceActions::SetButtonImage(image) => {
-                device_sender.set_button_image(image).await?
-            }
-            traits::device::DeviceActions::SetLCDImage(image) => {
-                device_sender.set_lcd_image(image).await?
+        let action = tokio::select! {
+            action = async {
+                while schedule.lock().unwrap().len() >= MAX_PENDING {
+                    space.notified().await;
+                }
+                companion_receiver.receive().await
+            } => action?,
+            _ = shutdown.wait() => return Ok(()),
+        };
+        trace!("handle_companion_to_device: {:?}", action);
+        // Prepared images are scheduled together once committed
+        let actions = prepared.take(action);
+        if actions.is_empty() {
+            continue;
+        }
+        let dropped = {
+            let mut schedule = schedule.lock().unwrap();
+            let dropped: u64 = actions.into_iter().map(|a| schedule.push(a)).sum();
+            stats.set_queue_depth(schedule.len());
+            dropped
+        };
+        if dropped > 0 {
+            stats.record_dropped(dropped);
+        }
+        pending.notify_one();
+    }
+}
+
+/// Deliver actions to the device as the schedule releases them, blanking the
+/// device once shut down.  The button images set are committed once no more
+/// are ready.  A complete match statement is provided to handle
+/// all possible companion commands and any new commands added to the
+/// companion trait will be a compile time error until the match statement is
+/// updated.
+async fn deliver_to_device(
+    mut device_sender: impl traits::device::Sender + Send,
+    schedule: &Mutex<Schedule>,
+    pending: &Notify,
+    space: &Notify,
+    stats: &PumpStats,
+    mut settings: watch::Receiver<DeviceSettings>,
+    shutdown: &ShutdownHandle,
+) -> Result<()> {
+    let mut uncommitted = 0;
+    loop {
+        if shutdown.is_shutdown() {
+            return device_sender.clear_all().await;
+        }
+        let next = {
+            let mut schedule = schedule.lock().unwrap();
+            let next = schedule.pop_ready(Instant::now());
+            stats.set_queue_depth(schedule.len());
+            next
+        };
+        let deadline = match next {
+            Next::Ready(action) => {
+                space.notify_one();
+                match action {
+                    traits::device::DeviceActions::SetButtonImage(image)
+                    | traits::device::DeviceActions::PrepareButtonImage(image) => {
+                        device_sender.set_button_image(image).await?;
+                        uncommitted += 1;
+                    }
+                    traits::device::DeviceActions::SetLCDImage(image) => {
+                        device_sender.set_lcd_image(image).await?
+                    }
+                    traits::device::DeviceActions::SetBrightness(brightness) => {
+                        device_sender.set_brightness(brightness).await?
+                    }
+                    traits::device::DeviceActions::Commit => uncommitted = MAX_UNCOMMITTED,
+                    traits::device::DeviceActions::ClearAll => {
+                        device_sender.clear_all().await?;
+                        uncommitted = 0;
+                    }
+                }
+                stats.record_to_device();
+                if uncommitted >= MAX_UNCOMMITTED {
+                    device_sender.commit().await?;
+                    uncommitted = 0;
+                }
+                continue;
             }
-            traits::device::DeviceActions::SetBrightness(brightness) => {
-                device_sender.set_brightness(brightness).await?
+            Next::Wait(deadline) => Some(deadline),
+            Next::Idle => None,
+        };
+        if uncommitted > 0 {
+            device_sender.commit().await?;
+            uncommitted = 0;
+        }
+
+        tokio::select! {
+            _ = sleep_until(deadline) => {}
+            _ = pending.notified() => {}
+            new_settings = settings_changed(&mut settings) => {
+                schedule.lock().unwrap().set_settings(new_settings);
             }
+            _ = shutdown.wait() => {}
         }
     }
 }
+
+/// Sleep until the deadline, or forever if there is none.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait for the settings to change.  If the settings can no longer change,
+/// this never completes.
+async fn settings_changed(settings: &mut watch::Receiver<DeviceSettings>) -> DeviceSettings {
+    if settings.changed().await.is_err() {
+        std::future::pending::<()>().await;
+    }
+    settings.borrow_and_update().clone()
+}
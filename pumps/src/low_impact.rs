@@ -0,0 +1,131 @@
+//! Sharing the CPU of the host with something that matters more.
+//!
+//! A satellite or gateway on the PC streaming a show competes with OBS for
+//! the CPU, and a page switch converting and writing every key at once can
+//! take a core for a moment right when OBS needs it.  The low impact profile
+//! trades latency for CPU instead: images are converted by
+//! [IMAGE_WORKERS] workers, decks are polled every [POLL_INTERVAL] and
+//! Companion pinged no more often than every [PING_INTERVAL], and writes of
+//! images are paced [WRITE_INTERVAL] apart by a [PacedSender], spreading a
+//! page switch over a moment.
+
+use std::num::NonZeroUsize;
+use std::time::Duration;
+
+use tokio::time::Instant;
+use traits::{
+    async_trait,
+    device::{SetBrightness, SetButtonImage, SetLCDImage},
+    Result,
+};
+
+/// Images of a device converted at once.
+pub const IMAGE_WORKERS: NonZeroUsize = NonZeroUsize::MIN;
+
+/// How often a deck is polled for input.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Least time between PINGs to Companion.
+pub const PING_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Least time between writing two images to a device.
+pub const WRITE_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Wraps a device sender, leaving at least an interval between the images
+/// written to it.  Everything else is passed on at once.
+pub struct PacedSender<S> {
+    inner: S,
+    interval: Duration,
+    /// When the next image may be written
+    next: Instant,
+}
+
+impl<S> PacedSender<S>
+where
+    S: traits::device::Sender + Send,
+{
+    /// Wrap a device sender, writing images to it interval apart.  An
+    /// interval of zero writes them as they come.
+    pub fn new(inner: S, interval: Duration) -> Self {
+        Self {
+            inner,
+            interval,
+            next: Instant::now(),
+        }
+    }
+
+    /// Wait until the next image may be written.
+    async fn pace(&mut self) {
+        if self.interval.is_zero() {
+            return;
+        }
+        tokio::time::sleep_until(self.next).await;
+        self.next = Instant::now() + self.interval;
+    }
+}
+
+#[async_trait]
+impl<S> traits::device::Sender for PacedSender<S>
+where
+    S: traits::device::Sender + Send,
+{
+    async fn set_brightness(&mut self, brightness: SetBrightness) -> Result<()> {
+        self.inner.set_brightness(brightness).await
+    }
+    async fn set_button_image(&mut self, image: SetButtonImage) -> Result<()> {
+        self.pace().await;
+        self.inner.set_button_image(image).await
+    }
+    async fn set_lcd_image(&mut self, image: SetLCDImage) -> Result<()> {
+        self.pace().await;
+        self.inner.set_lcd_image(image).await
+    }
+    async fn clear(&mut self) -> Result<()> {
+        self.inner.clear().await
+    }
+    async fn commit(&mut self) -> Result<()> {
+        self.inner.commit().await
+    }
+    async fn clear_all(&mut self) -> Result<()> {
+        self.inner.clear_all().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device::{Device, Written};
+    use traits::device::Sender as _;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_images_written_apart() {
+        let device = Device::default();
+        let mut sender = PacedSender::new(device.clone(), WRITE_INTERVAL);
+        let start = Instant::now();
+        for button in 0..3 {
+            let image = SetButtonImage {
+                button,
+                image: Vec::new(),
+            };
+            sender.set_button_image(image).await.unwrap();
+        }
+        // Brightness isn't held back by the images before it
+        let before = Instant::now();
+        sender
+            .set_brightness(SetBrightness { brightness: 10 })
+            .await
+            .unwrap();
+        assert_eq!(Instant::now(), before);
+
+        let offsets: Vec<_> = device
+            .written_at()
+            .into_iter()
+            .filter(|(_, written)| matches!(written, Written::ButtonImage(..)))
+            .map(|(at, _)| at - start)
+            .collect();
+        assert_eq!(
+            offsets,
+            [Duration::ZERO, WRITE_INTERVAL, WRITE_INTERVAL * 2]
+        );
+    }
+}
@@ -0,0 +1,146 @@
+//! Middleware remembering what the keys of a device show.
+//!
+//! Companion sends every key of a device again once it is added, but until
+//! then the deck of a leaf that connected again shows nothing.  A
+//! [PanelSender] records the image last sent to each key in a [LastImages]
+//! that outlives the connection, and shows them again as soon as the device
+//! connects, so the deck is back to what it showed before Companion catches
+//! up.  Kept by a gateway across restarts, they also restore the panels of
+//! leaves the moment the gateway is back.
+//!
+//! Clearing the device as the pump shuts down leaves the images remembered,
+//! as they are what the device is to show again.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use tracing::debug;
+use traits::{
+    async_trait,
+    device::{SetBrightness, SetButtonImage, SetLCDImage},
+    Result,
+};
+
+/// The image last sent to each key of a device, shared by its connections.
+#[derive(Clone, Debug, Default)]
+pub struct LastImages(Arc<Mutex<BTreeMap<u8, Vec<u8>>>>);
+
+impl LastImages {
+    /// The image last sent to each key that was sent any.
+    pub fn get(&self) -> BTreeMap<u8, Vec<u8>> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// Take on the images sent elsewhere, such as before the gateway
+    /// restarted.  Shown the next time the device connects.
+    pub fn restore(&self, images: BTreeMap<u8, Vec<u8>>) {
+        *self.0.lock().unwrap() = images;
+    }
+
+    /// Forget the images, as they are for other hardware.
+    pub fn forget(&self) {
+        self.0.lock().unwrap().clear();
+    }
+
+    fn set(&self, button: u8, image: &[u8]) {
+        self.0.lock().unwrap().insert(button, image.to_vec());
+    }
+}
+
+/// Wraps a device sender, recording the image last sent to each key.
+pub struct PanelSender<S> {
+    inner: S,
+    last: LastImages,
+}
+
+impl<S> PanelSender<S>
+where
+    S: traits::device::Sender + Send,
+{
+    /// Wrap a device sender, first showing the images sent to it on an
+    /// earlier connection of the device.
+    pub async fn new(mut inner: S, last: LastImages) -> Result<Self> {
+        let images = last.get();
+        if !images.is_empty() {
+            debug!("Restoring the images of {} keys", images.len());
+            for (button, image) in images {
+                inner
+                    .set_button_image(SetButtonImage { button, image })
+                    .await?;
+            }
+            inner.commit().await?;
+        }
+        Ok(Self { inner, last })
+    }
+}
+
+#[async_trait]
+impl<S> traits::device::Sender for PanelSender<S>
+where
+    S: traits::device::Sender + Send,
+{
+    async fn set_brightness(&mut self, brightness: SetBrightness) -> Result<()> {
+        self.inner.set_brightness(brightness).await
+    }
+    async fn set_button_image(&mut self, image: SetButtonImage) -> Result<()> {
+        self.last.set(image.button, &image.image);
+        self.inner.set_button_image(image).await
+    }
+    async fn set_lcd_image(&mut self, image: SetLCDImage) -> Result<()> {
+        self.inner.set_lcd_image(image).await
+    }
+    async fn clear(&mut self) -> Result<()> {
+        self.inner.clear().await
+    }
+    async fn commit(&mut self) -> Result<()> {
+        self.inner.commit().await
+    }
+    async fn clear_all(&mut self) -> Result<()> {
+        self.inner.clear_all().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device::{Device, Written};
+    use traits::device::Sender as _;
+
+    #[tokio::test]
+    async fn test_images_shown_again_on_connect() {
+        let last = LastImages::default();
+        let device = Device::default();
+        let mut sender = PanelSender::new(device.clone(), last.clone())
+            .await
+            .unwrap();
+        for (button, image) in [(0, 1), (1, 2), (0, 3)] {
+            let image = SetButtonImage {
+                button,
+                image: vec![image],
+            };
+            sender.set_button_image(image).await.unwrap();
+        }
+        // Blanking on shutdown is not what the device is to show again
+        sender.clear().await.unwrap();
+        drop(sender);
+        assert!(!device.written().contains(&Written::Commit));
+
+        let device = Device::default();
+        PanelSender::new(device.clone(), last.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            device.written(),
+            [
+                Written::ButtonImage(0, vec![3]),
+                Written::ButtonImage(1, vec![2]),
+                Written::Commit,
+            ]
+        );
+
+        last.forget();
+        let device = Device::default();
+        PanelSender::new(device.clone(), last).await.unwrap();
+        assert!(device.written().is_empty());
+    }
+}
@@ -0,0 +1,333 @@
+//! Saving the battery of leaves that run on one.
+//!
+//! A battery powered leaf reports a [PowerState] whenever it is unplugged,
+//! plugged in again or running low.  [PowerMonitor] takes these reports out
+//! of the input of the device, as they mean nothing to Companion, and
+//! publishes them for the rest of the gateway.  What is done about them is up
+//! to a [PowerPolicy]: [throttle] lowers the rate images are sent at, and a
+//! [PowerSaver] caps the brightness of a leaf low on battery, going back to
+//! what Companion asked for once it is plugged in again.  Both follow the
+//! policy on a watch channel, so it can be changed while leaves are served.
+
+use tokio::sync::watch;
+use tracing::debug;
+use traits::{
+    async_trait,
+    device::{Command, DeviceActions, DeviceSettings, PowerSource, PowerState, SetBrightness},
+    Result,
+};
+
+/// How a leaf saves power, going by where its power comes from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PowerPolicy {
+    /// Most images per second sent to a leaf on battery, if limited
+    pub battery_fps_cap: Option<f32>,
+    /// Most images per second sent to a leaf low on battery
+    pub low_battery_fps_cap: f32,
+    /// Highest brightness of a leaf low on battery
+    pub low_battery_brightness: u8,
+}
+
+impl Default for PowerPolicy {
+    fn default() -> Self {
+        Self {
+            battery_fps_cap: Some(15.0),
+            low_battery_fps_cap: 5.0,
+            low_battery_brightness: 20,
+        }
+    }
+}
+
+impl PowerPolicy {
+    fn fps_cap(&self, power: PowerState) -> Option<f32> {
+        match power.source {
+            PowerSource::External => None,
+            PowerSource::Battery => self.battery_fps_cap,
+            PowerSource::LowBattery => Some(self.low_battery_fps_cap),
+        }
+    }
+
+    fn brightness_cap(&self, power: PowerState) -> Option<u8> {
+        (power.source == PowerSource::LowBattery).then_some(self.low_battery_brightness)
+    }
+
+    /// The settings to serve a leaf with while it has power, the frame rate
+    /// being the lower of the two caps.
+    pub fn apply(&self, settings: &DeviceSettings, power: PowerState) -> DeviceSettings {
+        let fps_cap = match (settings.fps_cap, self.fps_cap(power)) {
+            (Some(cap), Some(power_cap)) => Some(cap.min(power_cap)),
+            (cap, power_cap) => cap.or(power_cap),
+        };
+        DeviceSettings {
+            fps_cap,
+            ..settings.clone()
+        }
+    }
+}
+
+/// Create the channel the power of a leaf is published on.  Leaves start out
+/// on external power until they say otherwise.
+pub fn channel() -> (watch::Sender<PowerState>, watch::Receiver<PowerState>) {
+    watch::channel(PowerState::default())
+}
+
+/// Wraps a device receiver, publishing the power reports of the leaf rather
+/// than passing them on.
+pub struct PowerMonitor<R> {
+    inner: R,
+    power: watch::Sender<PowerState>,
+}
+
+impl<R> PowerMonitor<R>
+where
+    R: traits::device::Receiver + Send,
+{
+    /// Wrap a device receiver, publishing its power on power.
+    pub fn new(inner: R, power: watch::Sender<PowerState>) -> Self {
+        Self { inner, power }
+    }
+}
+
+#[async_trait]
+impl<R> traits::device::Receiver for PowerMonitor<R>
+where
+    R: traits::device::Receiver + Send,
+{
+    async fn receive(&mut self) -> Result<Command> {
+        loop {
+            match self.inner.receive().await? {
+                Command::Power(power) => {
+                    debug!("Leaf power changed: {:?}", power);
+                    self.power.send_replace(power);
+                }
+                command => return Ok(command),
+            }
+        }
+    }
+}
+
+/// Settings following the provided ones, with the frame rate lowered by the
+/// policy while the leaf is on battery.  Kept up to date until the returned
+/// receiver is dropped.
+pub fn throttle(
+    mut settings: watch::Receiver<DeviceSettings>,
+    mut power: watch::Receiver<PowerState>,
+    mut policy: watch::Receiver<PowerPolicy>,
+) -> watch::Receiver<DeviceSettings> {
+    let initial = policy
+        .borrow_and_update()
+        .apply(&settings.borrow_and_update(), *power.borrow_and_update());
+    let (throttled, receiver) = watch::channel(initial);
+    tokio::spawn(async move {
+        let (mut powered, mut policed) = (true, true);
+        loop {
+            tokio::select! {
+                changed = settings.changed() => if changed.is_err() {
+                    return;
+                },
+                changed = power.changed(), if powered => if changed.is_err() {
+                    // The power stays as it was last reported
+                    powered = false;
+                    continue;
+                },
+                changed = policy.changed(), if policed => if changed.is_err() {
+                    // As does the policy
+                    policed = false;
+                    continue;
+                },
+                _ = throttled.closed() => return,
+            }
+            let settings = policy
+                .borrow_and_update()
+                .apply(&settings.borrow_and_update(), *power.borrow_and_update());
+            throttled.send_replace(settings);
+        }
+    });
+    receiver
+}
+
+/// Wraps a companion receiver, capping the brightness of a leaf low on
+/// battery.  The brightness Companion asked for is restored once the leaf
+/// has power again, or the policy no longer caps it.
+///
+/// The wrapped receiver must be cancel safe, as a change of power interrupts
+/// waiting on it.
+pub struct PowerSaver<R> {
+    inner: R,
+    power: watch::Receiver<PowerState>,
+    policy: watch::Receiver<PowerPolicy>,
+    /// Whether the power can still change
+    powered: bool,
+    /// Whether the policy can still change
+    policed: bool,
+    /// The brightness last asked for
+    requested: Option<u8>,
+    /// The most the brightness is allowed to be
+    cap: Option<u8>,
+}
+
+impl<R> PowerSaver<R>
+where
+    R: traits::companion::Receiver + Send,
+{
+    /// Wrap a companion receiver, saving power as the policy says.
+    pub fn new(
+        inner: R,
+        power: watch::Receiver<PowerState>,
+        policy: watch::Receiver<PowerPolicy>,
+    ) -> Self {
+        Self {
+            inner,
+            power,
+            policy,
+            powered: true,
+            policed: true,
+            requested: None,
+            cap: None,
+        }
+    }
+
+    /// The brightness to set when the power or policy changes, if any is
+    /// needed.
+    fn power_changed(&mut self) -> Option<SetBrightness> {
+        let cap = self
+            .policy
+            .borrow_and_update()
+            .brightness_cap(*self.power.borrow_and_update());
+        if cap == self.cap {
+            return None;
+        }
+        self.cap = cap;
+        let brightness = match (self.requested, cap) {
+            (Some(requested), Some(cap)) => requested.min(cap),
+            (requested, cap) => requested.or(cap)?,
+        };
+        debug!("Power changed, setting brightness {}", brightness);
+        Some(SetBrightness { brightness })
+    }
+}
+
+#[async_trait]
+impl<R> traits::companion::Receiver for PowerSaver<R>
+where
+    R: traits::companion::Receiver + Send,
+{
+    async fn receive(&mut self) -> Result<DeviceActions> {
+        loop {
+            let mut action = tokio::select! {
+                action = self.inner.receive() => action?,
+                changed = self.power.changed(), if self.powered => {
+                    if changed.is_err() {
+                        self.powered = false;
+                    } else if let Some(brightness) = self.power_changed() {
+                        return Ok(DeviceActions::SetBrightness(brightness));
+                    }
+                    continue;
+                }
+                changed = self.policy.changed(), if self.policed => {
+                    if changed.is_err() {
+                        self.policed = false;
+                    } else if let Some(brightness) = self.power_changed() {
+                        return Ok(DeviceActions::SetBrightness(brightness));
+                    }
+                    continue;
+                }
+            };
+            if let DeviceActions::SetBrightness(brightness) = &mut action {
+                self.requested = Some(brightness.brightness);
+                if let Some(cap) = self.cap {
+                    brightness.brightness = brightness.brightness.min(cap);
+                }
+            }
+            return Ok(action);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traits::companion::Receiver as _;
+    use traits::device::Receiver as _;
+
+    struct Device(Vec<Command>);
+
+    #[async_trait]
+    impl traits::device::Receiver for Device {
+        async fn receive(&mut self) -> Result<Command> {
+            match self.0.pop() {
+                Some(command) => Ok(command),
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    struct Companion(tokio::sync::mpsc::Receiver<u8>);
+
+    #[async_trait]
+    impl traits::companion::Receiver for Companion {
+        async fn receive(&mut self) -> Result<DeviceActions> {
+            let brightness = self.0.recv().await.unwrap();
+            Ok(DeviceActions::SetBrightness(SetBrightness { brightness }))
+        }
+    }
+
+    fn brightness(action: DeviceActions) -> u8 {
+        match action {
+            DeviceActions::SetBrightness(brightness) => brightness.brightness,
+            action => panic!("Unexpected action {action:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_low_battery_dims_and_throttles() {
+        let (policy_sender, policy) = watch::channel(PowerPolicy::default());
+        let (power, power_receiver) = channel();
+        let (settings, settings_receiver) = watch::channel(DeviceSettings {
+            fps_cap: Some(30.0),
+            ..Default::default()
+        });
+        let mut throttled = throttle(settings_receiver, power_receiver.clone(), policy.clone());
+        let (companion, companion_receiver) = tokio::sync::mpsc::channel(4);
+        let mut saver = PowerSaver::new(Companion(companion_receiver), power_receiver, policy);
+
+        // Power reports are taken out of the input
+        let low = PowerState {
+            source: PowerSource::LowBattery,
+            charge: Some(8),
+        };
+        let press = Command::ButtonChange(traits::device::ButtonChange { buttons: vec![] });
+        let mut monitor = PowerMonitor::new(Device(vec![press, Command::Power(low)]), power);
+        companion.send(80).await.unwrap();
+        assert_eq!(brightness(saver.receive().await.unwrap()), 80);
+        assert!(matches!(
+            monitor.receive().await.unwrap(),
+            Command::ButtonChange(_)
+        ));
+
+        // Running low dims the leaf at once, and caps what Companion asks for
+        assert_eq!(brightness(saver.receive().await.unwrap()), 20);
+        throttled.changed().await.unwrap();
+        assert_eq!(throttled.borrow().fps_cap, Some(5.0));
+        companion.send(60).await.unwrap();
+        assert_eq!(brightness(saver.receive().await.unwrap()), 20);
+
+        // A new policy is followed at once
+        policy_sender.send_modify(|policy| {
+            policy.low_battery_brightness = 40;
+            policy.low_battery_fps_cap = 2.0;
+        });
+        assert_eq!(brightness(saver.receive().await.unwrap()), 40);
+        throttled.changed().await.unwrap();
+        assert_eq!(throttled.borrow().fps_cap, Some(2.0));
+
+        // Plugged in, the leaf gets the brightness Companion asked for last
+        monitor.power.send_replace(PowerState::default());
+        assert_eq!(brightness(saver.receive().await.unwrap()), 60);
+        throttled.changed().await.unwrap();
+        assert_eq!(throttled.borrow().fps_cap, Some(30.0));
+        settings.send_modify(|settings| settings.fps_cap = None);
+        throttled.changed().await.unwrap();
+        assert_eq!(throttled.borrow().fps_cap, None);
+    }
+}
@@ -0,0 +1,420 @@
+//! Surviving the loss of the Companion connection.
+//!
+//! The device is usually local and stays put, while the link to Companion
+//! (or to a gateway) goes through the network and can drop at any time.
+//! [run_with_reconnect] keeps the device open and connects to Companion again,
+//! backing off between attempts, until the device itself fails or it is shut
+//! down.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tracing::{info, warn};
+use traits::anyhow::{self, Context};
+use traits::device::{
+    Command, RemoteConfig, Sender as _, SetBrightness, SetButtonImage, SetLCDImage,
+};
+use traits::{async_trait, Result};
+
+use crate::endpoint::{shutdown_when_repointed, CompanionEndpoint};
+use crate::session::Session;
+use crate::shutdown::ShutdownHandle;
+
+/// Delay between attempts to connect, doubling up to a maximum.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(500), Duration::from_secs(30))
+    }
+}
+
+impl Backoff {
+    /// Start waiting initial, doubling every attempt up to max.
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    /// Start over after a successful connection.
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+
+    /// How long to wait before the next attempt.  The delay is randomly cut
+    /// by up to half so many leaves losing the same gateway don't all come
+    /// back at once.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        let jitter = RandomState::new().build_hasher().finish() % 1000;
+        delay.mul_f64(1.0 - jitter as f64 / 2000.0)
+    }
+}
+
+/// Marks errors that came from the device rather than Companion.
+#[derive(Debug)]
+struct DeviceError;
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Device failed")
+    }
+}
+
+/// The device side lent to a single message pump, with its errors marked, so
+/// the device can outlive the connection to Companion.
+pub struct DeviceSide<'a, T>(pub &'a mut T);
+
+#[async_trait]
+impl<T> traits::device::Sender for DeviceSide<'_, T>
+where
+    T: traits::device::Sender + Send,
+{
+    async fn set_brightness(&mut self, brightness: SetBrightness) -> Result<()> {
+        self.0.set_brightness(brightness).await.context(DeviceError)
+    }
+    async fn set_button_image(&mut self, image: SetButtonImage) -> Result<()> {
+        self.0.set_button_image(image).await.context(DeviceError)
+    }
+    async fn set_lcd_image(&mut self, image: SetLCDImage) -> Result<()> {
+        self.0.set_lcd_image(image).await.context(DeviceError)
+    }
+    async fn clear(&mut self) -> Result<()> {
+        self.0.clear().await.context(DeviceError)
+    }
+    async fn commit(&mut self) -> Result<()> {
+        self.0.commit().await.context(DeviceError)
+    }
+    async fn clear_all(&mut self) -> Result<()> {
+        self.0.clear_all().await.context(DeviceError)
+    }
+}
+
+#[async_trait]
+impl<T> traits::device::Receiver for DeviceSide<'_, T>
+where
+    T: traits::device::Receiver + Send,
+{
+    async fn receive(&mut self) -> Result<Command> {
+        self.0.receive().await.context(DeviceError)
+    }
+}
+
+/// Pump messages between the device and Companion, connecting to Companion
+/// again with backoff whenever the connection is lost.  Nothing is sent until
+/// the device has sent its config, which is sent again on every connection.
+/// Input from the device before its config is delivered after it.
+/// Only returns when the device fails.
+pub async fn run_with_reconnect<DS, DR, CS, CR, CC, CCF>(
+    devices: (DS, DR),
+    create_companion: CC,
+    backoff: Backoff,
+) -> Result<()>
+where
+    DS: traits::device::Sender + Send,
+    DR: traits::device::Receiver + Send,
+    CS: traits::companion::Sender + Send,
+    CR: traits::companion::Receiver,
+    CC: FnMut(&RemoteConfig) -> CCF,
+    CCF: Future<Output = Result<(CS, CR)>>,
+{
+    run_with_reconnect_until(devices, create_companion, backoff, ShutdownHandle::new()).await
+}
+
+/// Same as [run_with_reconnect], but also returns once shutdown is shut
+/// down, after closing Companion and blanking the device.
+pub async fn run_with_reconnect_until<DS, DR, CS, CR, CC, CCF>(
+    devices: (DS, DR),
+    create_companion: CC,
+    backoff: Backoff,
+    shutdown: ShutdownHandle,
+) -> Result<()>
+where
+    DS: traits::device::Sender + Send,
+    DR: traits::device::Receiver + Send,
+    CS: traits::companion::Sender + Send,
+    CR: traits::companion::Receiver,
+    CC: FnMut(&RemoteConfig) -> CCF,
+    CCF: Future<Output = Result<(CS, CR)>>,
+{
+    reconnect(devices, create_companion, backoff, shutdown, None).await
+}
+
+/// Same as [run_with_reconnect_until], connecting to the address endpoint
+/// holds.  When it is repointed, the connection is closed, removing the
+/// device, and a new one is made to the new address straight away.
+pub async fn run_with_reconnect_to<DS, DR, CS, CR, CC, CCF>(
+    devices: (DS, DR),
+    endpoint: CompanionEndpoint,
+    mut create_companion: CC,
+    backoff: Backoff,
+    shutdown: ShutdownHandle,
+) -> Result<()>
+where
+    DS: traits::device::Sender + Send,
+    DR: traits::device::Receiver + Send,
+    CS: traits::companion::Sender + Send,
+    CR: traits::companion::Receiver,
+    CC: FnMut(&str, &RemoteConfig) -> CCF,
+    CCF: Future<Output = Result<(CS, CR)>>,
+{
+    let repointed = endpoint.subscribe();
+    reconnect(
+        devices,
+        |config| create_companion(&endpoint.get(), config),
+        backoff,
+        shutdown,
+        Some(repointed),
+    )
+    .await
+}
+
+/// Pump messages, connecting again as [run_with_reconnect_until] does.
+/// Connections are also closed when repointed changes.
+async fn reconnect<DS, DR, CS, CR, CC, CCF>(
+    (mut device_sender, device_receiver): (DS, DR),
+    mut create_companion: CC,
+    mut backoff: Backoff,
+    shutdown: ShutdownHandle,
+    mut repointed: Option<watch::Receiver<String>>,
+) -> Result<()>
+where
+    DS: traits::device::Sender + Send,
+    DR: traits::device::Receiver + Send,
+    CS: traits::companion::Sender + Send,
+    CR: traits::companion::Receiver,
+    CC: FnMut(&RemoteConfig) -> CCF,
+    CCF: Future<Output = Result<(CS, CR)>>,
+{
+    let mut device_receiver = Session::new(device_receiver);
+    let config = tokio::select! {
+        config = device_receiver.config() => config?,
+        _ = shutdown.wait() => return Ok(()),
+    };
+    device_receiver.activate()?;
+
+    loop {
+        let connection = shutdown.child();
+        let res = async {
+            if let Some(repointed) = &mut repointed {
+                repointed.mark_unchanged();
+            }
+            let (mut companion_sender, companion_receiver) = tokio::select! {
+                companion = create_companion(&config) => companion?,
+                _ = shutdown.wait() => return DeviceSide(&mut device_sender).clear_all().await,
+            };
+            companion_sender.config(config.clone()).await?;
+            info!("Connected to companion, pumping messages");
+            backoff.reset();
+            let pump = crate::message_pump_until(
+                DeviceSide(&mut device_sender),
+                DeviceSide(&mut device_receiver),
+                companion_sender,
+                companion_receiver,
+                Default::default(),
+                watch::channel(Default::default()).1,
+                connection.clone(),
+            );
+            match &mut repointed {
+                Some(repointed) => tokio::select! {
+                    res = pump => res,
+                    _ = shutdown_when_repointed(repointed, &connection) => unreachable!(),
+                },
+                None => pump.await,
+            }
+        }
+        .await;
+
+        let error = match res {
+            // The device has been blanked
+            Ok(()) if shutdown.is_shutdown() => return Ok(()),
+            // Companion was closed cleanly, to move to the new address
+            Ok(()) if connection.is_shutdown() => continue,
+            Ok(()) => anyhow::anyhow!("Connection closed"),
+            Err(e) if e.downcast_ref::<DeviceError>().is_some() => return Err(e),
+            Err(e) => e,
+        };
+        let delay = backoff.next_delay();
+        warn!("Companion connection lost ({error:#}), reconnecting in {delay:?}");
+        // What the device shows no longer follows Companion
+        DeviceSide(&mut device_sender).clear_all().await?;
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.wait() => return DeviceSide(&mut device_sender).clear_all().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device::{Device, Written};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use traits::device::{
+        ButtonChange, DeviceActions, EncoderPress, EncoderTwist, PowerState, StatusImage, Touch,
+    };
+
+    /// Input from a deck: its config, unless already sent, then nothing or,
+    /// if it unplugs, an error a moment later.
+    struct Input {
+        configured: bool,
+        unplugs: bool,
+    }
+
+    #[async_trait]
+    impl traits::device::Receiver for Input {
+        async fn receive(&mut self) -> Result<Command> {
+            if !self.configured {
+                self.configured = true;
+                return Ok(Command::Config(RemoteConfig {
+                    pid: 0x0080,
+                    device_id: String::from("deck"),
+                    fingerprint: Default::default(),
+                }));
+            }
+            if !self.unplugs {
+                return std::future::pending().await;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            anyhow::bail!("Unplugged")
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Companion {
+        configs: Arc<AtomicUsize>,
+        closes: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl traits::companion::Sender for Companion {
+        async fn config(&mut self, _: RemoteConfig) -> Result<()> {
+            self.configs.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+        async fn button_change(&mut self, _: ButtonChange) -> Result<()> {
+            Ok(())
+        }
+        async fn encoder_twist(&mut self, _: EncoderTwist) -> Result<()> {
+            Ok(())
+        }
+        async fn encoder_press(&mut self, _: EncoderPress) -> Result<()> {
+            Ok(())
+        }
+        async fn touch(&mut self, _: Touch) -> Result<()> {
+            Ok(())
+        }
+        async fn power(&mut self, _: PowerState) -> Result<()> {
+            Ok(())
+        }
+        async fn status_image(&mut self, _: StatusImage) -> Result<()> {
+            Ok(())
+        }
+        async fn close(&mut self) -> Result<()> {
+            self.closes.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl traits::companion::Receiver for Companion {
+        async fn receive(&mut self) -> Result<DeviceActions> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_until_device_fails() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let configs = Arc::new(AtomicUsize::new(0));
+        let device = Device::default();
+        let input = Input {
+            configured: false,
+            unplugs: true,
+        };
+        let res = run_with_reconnect(
+            (device.clone(), input),
+            |_| {
+                let attempt = attempts.fetch_add(1, Ordering::Relaxed);
+                let companion = Companion {
+                    configs: configs.clone(),
+                    ..Default::default()
+                };
+                async move {
+                    if attempt < 2 {
+                        anyhow::bail!("Refused");
+                    }
+                    Ok((companion.clone(), companion))
+                }
+            },
+            Backoff::new(Duration::from_millis(1), Duration::from_millis(2)),
+        )
+        .await;
+
+        assert!(res.unwrap_err().downcast_ref::<DeviceError>().is_some());
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+        assert_eq!(configs.load(Ordering::Relaxed), 1);
+        // Blanked each time the connection was lost, though never shown
+        // anything
+        assert_eq!(device.written(), [Written::ClearAll, Written::ClearAll]);
+    }
+
+    #[tokio::test]
+    async fn test_repointed_without_losing_device() {
+        let endpoint = CompanionEndpoint::new("a:1");
+        let connected = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let companion = Companion::default();
+        let shutdown = ShutdownHandle::new();
+        let input = Input {
+            configured: false,
+            unplugs: false,
+        };
+        let run = tokio::spawn(run_with_reconnect_to(
+            (Device::default(), input),
+            endpoint.clone(),
+            {
+                let (connected, companion) = (connected.clone(), companion.clone());
+                move |addr: &str, _: &RemoteConfig| {
+                    connected.lock().unwrap().push(addr.to_string());
+                    let companion = companion.clone();
+                    async move { Ok((companion.clone(), companion)) }
+                }
+            },
+            Backoff::default(),
+            shutdown.clone(),
+        ));
+        let configs_reach = |count| {
+            let configs = companion.configs.clone();
+            async move {
+                while configs.load(Ordering::Relaxed) < count {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            }
+        };
+        configs_reach(1).await;
+
+        // The old connection is closed and the device added on the new one
+        assert!(endpoint.set("b:2"));
+        configs_reach(2).await;
+        assert_eq!(companion.closes.load(Ordering::Relaxed), 1);
+        assert_eq!(*connected.lock().unwrap(), ["a:1", "b:2"]);
+        assert!(!endpoint.set("b:2"));
+
+        shutdown.shutdown();
+        run.await.unwrap().unwrap();
+    }
+}
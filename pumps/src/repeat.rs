@@ -0,0 +1,228 @@
+//! Held keys and long presses, synthesized on this side of Companion.
+//!
+//! Companion acts on a key once per press, so an action like a nudge needs the
+//! key tapped over and over.  Keys listed in the [KeyRepeat] of the
+//! [DeviceSettings] instead keep pressing themselves while held: after the
+//! delay, every interval they are released and pressed again.
+//!
+//! The touch strip reports a long press as a single event once the finger has
+//! been down for a while.  Companion only sees a long press when a key stays
+//! down, so with `long_press_ms` set the key under the finger is held for
+//! that long rather than tapped.
+//!
+//! [KeyRepeat]: traits::device::KeyRepeat
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::trace;
+use traits::{
+    async_trait,
+    device::{ButtonChange, Command, DeviceSettings, TouchGesture},
+    Result,
+};
+
+/// The Companion key of the touch strip at a horizontal position, if any.
+pub type TouchKey = Box<dyn Fn(u16) -> Option<u8> + Send + Sync>;
+
+/// Wraps a device receiver, adding the repeats of held keys and holding the
+/// keys of long presses on the touch strip.
+///
+/// The wrapped receiver must be cancel safe, as a repeat coming due
+/// interrupts waiting on it.
+pub struct RepeatReceiver<R> {
+    inner: R,
+    settings: watch::Receiver<DeviceSettings>,
+    touch_key: TouchKey,
+    /// Held keys that repeat, and when they next do
+    held: BTreeMap<u8, Instant>,
+    /// Keys of long presses, and when they are released
+    releases: BTreeMap<u8, Instant>,
+}
+
+impl<R> RepeatReceiver<R>
+where
+    R: traits::device::Receiver + Send,
+{
+    /// Wrap a device receiver.  touch_key finds the Companion key of a touch
+    /// on the strip.
+    pub fn new(inner: R, settings: watch::Receiver<DeviceSettings>, touch_key: TouchKey) -> Self {
+        Self {
+            inner,
+            settings,
+            touch_key,
+            held: BTreeMap::new(),
+            releases: BTreeMap::new(),
+        }
+    }
+
+    /// Track the keys that start or stop repeating.
+    fn track(&mut self, change: &ButtonChange) {
+        let settings = self.settings.borrow();
+        let Some(repeat) = &settings.key_repeat else {
+            self.held.clear();
+            return;
+        };
+        for (key, pressed) in &change.buttons {
+            if !pressed {
+                self.held.remove(key);
+            } else if repeat.keys.contains(key) {
+                let delay = Duration::from_millis(repeat.delay_ms.into());
+                self.held.insert(*key, Instant::now() + delay);
+            }
+        }
+    }
+
+    /// Hold the key under a long press, if long presses are held.
+    fn long_press(&mut self, x: u16) -> Option<Command> {
+        let hold = self.settings.borrow().long_press_ms?;
+        let key = (self.touch_key)(x)?;
+        let release = Instant::now() + Duration::from_millis(hold.into());
+        self.releases.insert(key, release);
+        Some(Command::ButtonChange(ButtonChange {
+            buttons: vec![(key, true)],
+        }))
+    }
+
+    /// The releases and repeats that are due, in one change.
+    fn due(&mut self, now: Instant) -> Option<ButtonChange> {
+        let mut buttons = Vec::new();
+        self.releases.retain(|key, release| {
+            let due = *release <= now;
+            if due {
+                buttons.push((*key, false));
+            }
+            !due
+        });
+        let interval = match &self.settings.borrow().key_repeat {
+            Some(repeat) => Duration::from_millis(repeat.interval_ms.max(1).into()),
+            None => {
+                self.held.clear();
+                Duration::ZERO
+            }
+        };
+        for (key, next) in self.held.iter_mut() {
+            if *next <= now {
+                buttons.extend([(*key, false), (*key, true)]);
+                *next = now + interval;
+            }
+        }
+        (!buttons.is_empty()).then_some(ButtonChange { buttons })
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.held
+            .values()
+            .chain(self.releases.values())
+            .min()
+            .copied()
+    }
+}
+
+#[async_trait]
+impl<R> traits::device::Receiver for RepeatReceiver<R>
+where
+    R: traits::device::Receiver + Send,
+{
+    async fn receive(&mut self) -> Result<Command> {
+        loop {
+            if let Some(change) = self.due(Instant::now()) {
+                trace!("Synthesized {:?}", change);
+                return Ok(Command::ButtonChange(change));
+            }
+            let command = match self.next_due() {
+                Some(deadline) => tokio::select! {
+                    command = self.inner.receive() => command?,
+                    _ = tokio::time::sleep_until(deadline) => continue,
+                },
+                None => self.inner.receive().await?,
+            };
+            match &command {
+                Command::ButtonChange(change) => self.track(change),
+                Command::Touch(touch) if touch.gesture == TouchGesture::LongPress => {
+                    if let Some(command) = self.long_press(touch.x) {
+                        return Ok(command);
+                    }
+                }
+                _ => {}
+            }
+            return Ok(command);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traits::device::{KeyRepeat, Receiver as _, Touch};
+
+    struct Script(Vec<Command>);
+
+    #[async_trait]
+    impl traits::device::Receiver for Script {
+        async fn receive(&mut self) -> Result<Command> {
+            if self.0.is_empty() {
+                std::future::pending().await
+            } else {
+                Ok(self.0.remove(0))
+            }
+        }
+    }
+
+    fn buttons(command: Command) -> Vec<(u8, bool)> {
+        match command {
+            Command::ButtonChange(change) => change.buttons,
+            command => panic!("Unexpected command {command:?}"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_held_key_repeats_and_long_press_held() {
+        let settings = DeviceSettings {
+            key_repeat: Some(KeyRepeat {
+                keys: vec![3],
+                delay_ms: 500,
+                interval_ms: 100,
+            }),
+            long_press_ms: Some(1000),
+            ..Default::default()
+        };
+        let (_settings, settings) = watch::channel(settings);
+        let script = Script(vec![
+            Command::ButtonChange(ButtonChange {
+                buttons: vec![(3, true), (4, true)],
+            }),
+            Command::Touch(Touch {
+                x: 0,
+                y: 0,
+                gesture: TouchGesture::LongPress,
+            }),
+        ]);
+        let mut receiver = RepeatReceiver::new(script, settings, Box::new(|_| Some(8)));
+
+        let start = Instant::now();
+        assert_eq!(
+            buttons(receiver.receive().await.unwrap()),
+            [(3, true), (4, true)]
+        );
+        assert_eq!(buttons(receiver.receive().await.unwrap()), [(8, true)]);
+
+        // Only the repeating key repeats, once the delay is over
+        assert_eq!(
+            buttons(receiver.receive().await.unwrap()),
+            [(3, false), (3, true)]
+        );
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+        for _ in 0..4 {
+            receiver.receive().await.unwrap();
+        }
+        assert_eq!(start.elapsed(), Duration::from_millis(900));
+        // The long press is released along with the repeat due at the time
+        assert_eq!(
+            buttons(receiver.receive().await.unwrap()),
+            [(8, false), (3, false), (3, true)]
+        );
+    }
+}
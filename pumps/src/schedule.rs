@@ -0,0 +1,317 @@
+//! Scheduling of device actions according to the update policy of each zone.
+//!
+//! Actions received from the companion app are pushed into a [Schedule] and
+//! popped out when the policy of the zone they belong to allows it.  Zones
+//! are paced independently, so a zone with a low FPS cap never holds back
+//! another zone, and within a zone actions for the same key keep their order.
+//! Blanking the device drops every image still pending, as it would only be
+//! blanked, and is sent at once ahead of whatever comes after it.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use tokio::time::Instant;
+use traits::device::{DeviceActions, DeviceSettings};
+
+/// What an action updates on the device.  Two actions with the same slot
+/// overwrite each other, so the older one can be coalesced away.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Slot {
+    Button(u8),
+    Lcd(u16),
+    Brightness,
+    Commit,
+    ClearAll,
+}
+
+impl Slot {
+    fn of(action: &DeviceActions) -> Self {
+        match action {
+            DeviceActions::SetButtonImage(image) => Slot::Button(image.button),
+            DeviceActions::SetLCDImage(image) => Slot::Lcd(image.x_offset),
+            DeviceActions::SetBrightness(_) => Slot::Brightness,
+            DeviceActions::PrepareButtonImage(image) => Slot::Button(image.button),
+            DeviceActions::Commit => Slot::Commit,
+            DeviceActions::ClearAll => Slot::ClearAll,
+        }
+    }
+}
+
+/// The zone an action is paced by.  None is the device wide policy.
+type ZoneId = Option<usize>;
+
+/// Result of asking the schedule for the next action.
+#[derive(Debug)]
+pub enum Next {
+    /// This action should be sent now.
+    Ready(DeviceActions),
+    /// Nothing can be sent until the deadline.
+    Wait(Instant),
+    /// Nothing is pending.
+    Idle,
+}
+
+/// Pending device actions, released according to the device settings.
+#[derive(Default)]
+pub struct Schedule {
+    settings: DeviceSettings,
+    pending: VecDeque<(Slot, ZoneId, DeviceActions)>,
+    next_release: HashMap<ZoneId, Instant>,
+}
+
+impl Schedule {
+    /// Create an empty schedule following the provided settings.
+    pub fn new(settings: DeviceSettings) -> Self {
+        Self {
+            settings,
+            ..Default::default()
+        }
+    }
+
+    /// Follow new settings from now on.  Pending actions are kept.
+    pub fn set_settings(&mut self, settings: DeviceSettings) {
+        self.settings = settings;
+        self.next_release.clear();
+        for (slot, zone, _) in self.pending.iter_mut() {
+            *zone = Self::zone_of(&self.settings, *slot);
+        }
+    }
+
+    /// Number of pending actions.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// True if nothing is pending.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    fn zone_of(settings: &DeviceSettings, slot: Slot) -> ZoneId {
+        match slot {
+            Slot::Button(key) => settings.zone_of(key).map(|(index, _)| index),
+            _ => None,
+        }
+    }
+
+    fn coalesces(&self, slot: Slot, zone: ZoneId) -> bool {
+        match (slot, zone) {
+            // Only the last brightness or commit ever matters
+            (Slot::Brightness | Slot::Commit, _) => true,
+            (_, Some(zone)) => self.settings.zones[zone].coalesce,
+            (_, None) => self.settings.coalesce,
+        }
+    }
+
+    fn fps_cap(&self, slot: Slot, zone: ZoneId) -> Option<f32> {
+        let fps_cap = match (slot, zone) {
+            (Slot::Brightness | Slot::Commit | Slot::ClearAll, _) => None,
+            (_, Some(zone)) => self.settings.zones[zone].fps_cap,
+            (_, None) => self.settings.fps_cap,
+        };
+        fps_cap.filter(|fps| *fps > 0.0)
+    }
+
+    /// Add an action to the schedule.  Returns the number of pending actions
+    /// that were dropped because the new action replaces them.
+    pub fn push(&mut self, action: DeviceActions) -> u64 {
+        let slot = Slot::of(&action);
+        let zone = Self::zone_of(&self.settings, slot);
+        if slot == Slot::ClearAll {
+            let before = self.pending.len();
+            self.pending
+                .retain(|(slot, _, _)| *slot == Slot::Brightness);
+            let dropped = (before - self.pending.len()) as u64;
+            self.pending.push_back((slot, zone, action));
+            return dropped;
+        }
+        if self.coalesces(slot, zone) {
+            if let Some(pending) = self.pending.iter_mut().find(|(s, _, _)| *s == slot) {
+                pending.2 = action;
+                return 1;
+            }
+        }
+        self.pending.push_back((slot, zone, action));
+        0
+    }
+
+    /// Take the oldest action whose zone is allowed to send at time now.
+    pub fn pop_ready(&mut self, now: Instant) -> Next {
+        let mut earliest: Option<Instant> = None;
+        let mut ready = None;
+        for (index, (slot, zone, _)) in self.pending.iter().enumerate() {
+            match self.next_release.get(zone) {
+                // Blanking is never held back, so nothing after it goes first
+                Some(release) if *release > now && *slot != Slot::ClearAll => {
+                    earliest = Some(earliest.map_or(*release, |e| e.min(*release)));
+                }
+                _ => {
+                    ready = Some(index);
+                    break;
+                }
+            }
+        }
+
+        match (ready, earliest) {
+            (Some(index), _) => {
+                let (slot, zone, action) = self
+                    .pending
+                    .remove(index)
+                    .expect("index came from iterating pending");
+                // A cap too small to give an interval, or one past any
+                // instant, is as good as none
+                let release = self
+                    .fps_cap(slot, zone)
+                    .and_then(|fps| Duration::try_from_secs_f32(fps.recip()).ok())
+                    .and_then(|interval| now.checked_add(interval));
+                match release {
+                    Some(release) => {
+                        self.next_release.insert(zone, release);
+                    }
+                    None => {
+                        self.next_release.remove(&zone);
+                    }
+                }
+                Next::Ready(action)
+            }
+            (None, Some(deadline)) => Next::Wait(deadline),
+            (None, None) => Next::Idle,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traits::device::{SetBrightness, SetButtonImage, Zone};
+
+    fn image(button: u8, tag: u8) -> DeviceActions {
+        DeviceActions::SetButtonImage(SetButtonImage {
+            button,
+            image: vec![tag],
+        })
+    }
+
+    fn tag_of(next: Next) -> (u8, u8) {
+        match next {
+            Next::Ready(DeviceActions::SetButtonImage(image)) => (image.button, image.image[0]),
+            other => panic!("Expected a button image, got {:?}", other),
+        }
+    }
+
+    fn status_row() -> DeviceSettings {
+        DeviceSettings {
+            zones: vec![Zone {
+                name: "status".into(),
+                keys: vec![0, 1, 2],
+                fps_cap: Some(2.0),
+                coalesce: true,
+                brightness: None,
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_unpaced_actions_flow_in_order() {
+        let mut schedule = Schedule::default();
+        let now = Instant::now();
+        schedule.push(image(3, 1));
+        schedule.push(image(3, 2));
+        assert_eq!(tag_of(schedule.pop_ready(now)), (3, 1));
+        assert_eq!(tag_of(schedule.pop_ready(now)), (3, 2));
+        assert!(matches!(schedule.pop_ready(now), Next::Idle));
+    }
+
+    #[test]
+    fn test_coalescing_zone_keeps_latest() {
+        let mut schedule = Schedule::new(status_row());
+        assert_eq!(schedule.push(image(0, 1)), 0);
+        assert_eq!(schedule.push(image(0, 2)), 1);
+        assert_eq!(schedule.len(), 1);
+        assert_eq!(tag_of(schedule.pop_ready(Instant::now())), (0, 2));
+    }
+
+    #[test]
+    fn test_device_wide_coalescing_leaves_zones_alone() {
+        let mut settings = status_row();
+        settings.coalesce = true;
+        settings.zones[0].coalesce = false;
+        let mut schedule = Schedule::new(settings);
+        schedule.push(image(5, 1));
+        assert_eq!(schedule.push(image(5, 2)), 1);
+        schedule.push(image(0, 1));
+        assert_eq!(schedule.push(image(0, 2)), 0);
+        assert_eq!(schedule.len(), 3);
+        assert_eq!(tag_of(schedule.pop_ready(Instant::now())), (5, 2));
+    }
+
+    #[test]
+    fn test_tiny_fps_caps_do_not_panic() {
+        let now = Instant::now();
+        for fps_cap in [f32::MIN_POSITIVE, 1e-30, f32::NAN] {
+            let mut settings = status_row();
+            settings.zones[0].fps_cap = Some(fps_cap);
+            let mut schedule = Schedule::new(settings);
+            schedule.push(image(0, 1));
+            schedule.push(image(1, 1));
+            assert_eq!(tag_of(schedule.pop_ready(now)), (0, 1));
+            assert_eq!(tag_of(schedule.pop_ready(now)), (1, 1));
+        }
+    }
+
+    #[test]
+    fn test_paced_zone_does_not_block_others() {
+        let mut schedule = Schedule::new(status_row());
+        let now = Instant::now();
+        schedule.push(image(0, 1));
+        schedule.push(image(1, 1));
+        schedule.push(image(5, 1));
+        assert_eq!(tag_of(schedule.pop_ready(now)), (0, 1));
+        // key 1 shares the paced zone with key 0, key 5 is free to go
+        assert_eq!(tag_of(schedule.pop_ready(now)), (5, 1));
+        match schedule.pop_ready(now) {
+            Next::Wait(deadline) => assert_eq!(deadline, now + Duration::from_millis(500)),
+            other => panic!("Expected to wait, got {:?}", other),
+        }
+        let later = now + Duration::from_millis(500);
+        assert_eq!(tag_of(schedule.pop_ready(later)), (1, 1));
+    }
+
+    #[test]
+    fn test_brightness_always_coalesces() {
+        let mut schedule = Schedule::default();
+        schedule.push(DeviceActions::SetBrightness(SetBrightness {
+            brightness: 10,
+        }));
+        assert_eq!(
+            schedule.push(DeviceActions::SetBrightness(SetBrightness {
+                brightness: 20
+            })),
+            1
+        );
+        match schedule.pop_ready(Instant::now()) {
+            Next::Ready(DeviceActions::SetBrightness(b)) => assert_eq!(b.brightness, 20),
+            other => panic!("Expected brightness, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_clear_all_drops_pending_images() {
+        let mut schedule = Schedule::new(status_row());
+        let now = Instant::now();
+        schedule.push(image(0, 1));
+        assert_eq!(tag_of(schedule.pop_ready(now)), (0, 1));
+        schedule.push(image(1, 1));
+        schedule.push(image(5, 1));
+        assert_eq!(schedule.push(DeviceActions::ClearAll), 2);
+        // Not held back by the paced zone, nor overtaken by what follows
+        schedule.push(image(6, 2));
+        assert!(matches!(
+            schedule.pop_ready(now),
+            Next::Ready(DeviceActions::ClearAll)
+        ));
+        assert_eq!(tag_of(schedule.pop_ready(now)), (6, 2));
+        assert!(schedule.is_empty());
+    }
+}
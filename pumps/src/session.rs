@@ -0,0 +1,162 @@
+//! Startup of the session of a device.
+//!
+//! The first thing a device sends is its config, and nothing can be done
+//! with its input until it has been registered with Companion.  A [Session]
+//! makes the steps explicit: it starts in [SessionState::Init] waiting for the
+//! config, is [SessionState::Registered] once it has it, and becomes
+//! [SessionState::Active] when the caller has finished registering the device.
+//!
+//! Input that races ahead of the config (a key held while the device
+//! connects, say) doesn't fail the session.  It is queued and delivered first
+//! once the session is active.
+
+use std::collections::VecDeque;
+
+use tracing::{debug, warn};
+use traits::{
+    anyhow, async_trait,
+    device::{Command, RemoteConfig},
+    Result,
+};
+
+/// Maximum number of inputs queued before the session is active.  The
+/// oldest are dropped beyond that.
+const MAX_EARLY_INPUT: usize = 64;
+
+/// Where a session is in its startup.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionState {
+    /// Waiting for the config of the device
+    Init,
+    /// The config has been read, the device is being registered
+    Registered,
+    /// Registration is complete, input flows
+    Active,
+}
+
+/// Wraps a device receiver, tracking the startup of its session.
+pub struct Session<R> {
+    inner: R,
+    state: SessionState,
+    early: VecDeque<Command>,
+}
+
+impl<R> Session<R>
+where
+    R: traits::device::Receiver + Send,
+{
+    /// Start the session of a newly connected device.
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            state: SessionState::Init,
+            early: VecDeque::new(),
+        }
+    }
+
+    /// Where the session is in its startup.
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    /// Wait for the config of the device, queuing any input that arrives
+    /// before it.  Moves the session from Init to Registered.
+    pub async fn config(&mut self) -> Result<RemoteConfig> {
+        self.expect(SessionState::Init)?;
+        loop {
+            match self.inner.receive().await? {
+                Command::Config(config) => {
+                    self.state = SessionState::Registered;
+                    return Ok(config);
+                }
+                command => {
+                    debug!("Queuing input received before the config: {:?}", command);
+                    if self.early.len() == MAX_EARLY_INPUT {
+                        warn!("Too much input before the config, dropping the oldest");
+                        self.early.pop_front();
+                    }
+                    self.early.push_back(command);
+                }
+            }
+        }
+    }
+
+    /// Registration is complete.  Moves the session from Registered to
+    /// Active, after which the queued input is received first.
+    pub fn activate(&mut self) -> Result<()> {
+        self.expect(SessionState::Registered)?;
+        self.state = SessionState::Active;
+        Ok(())
+    }
+
+    fn expect(&self, state: SessionState) -> Result<()> {
+        if self.state != state {
+            anyhow::bail!("Device session is {:?}, expected {:?}", self.state, state);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<R> traits::device::Receiver for Session<R>
+where
+    R: traits::device::Receiver + Send,
+{
+    async fn receive(&mut self) -> Result<Command> {
+        self.expect(SessionState::Active)?;
+        match self.early.pop_front() {
+            Some(command) => Ok(command),
+            None => self.inner.receive().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traits::device::{ButtonChange, Receiver};
+
+    struct Script(VecDeque<Command>);
+
+    #[async_trait]
+    impl traits::device::Receiver for Script {
+        async fn receive(&mut self) -> Result<Command> {
+            self.0
+                .pop_front()
+                .ok_or_else(|| anyhow::anyhow!("End of script"))
+        }
+    }
+
+    fn press(key: u8) -> Command {
+        Command::ButtonChange(ButtonChange {
+            buttons: vec![(key, true)],
+        })
+    }
+
+    fn pressed(command: Command) -> u8 {
+        match command {
+            Command::ButtonChange(change) => change.buttons[0].0,
+            command => panic!("Unexpected command {command:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_early_input_queued_until_active() {
+        let config = Command::Config(RemoteConfig {
+            pid: 0x0080,
+            device_id: String::from("deck"),
+            fingerprint: Default::default(),
+        });
+        let script = Script([press(1), config, press(2)].into());
+        let mut session = Session::new(script);
+
+        assert!(session.activate().is_err());
+        assert_eq!(session.config().await.unwrap().device_id, "deck");
+        assert_eq!(session.state(), SessionState::Registered);
+        assert!(session.receive().await.is_err());
+
+        session.activate().unwrap();
+        assert_eq!(pressed(session.receive().await.unwrap()), 1);
+        assert_eq!(pressed(session.receive().await.unwrap()), 2);
+    }
+}
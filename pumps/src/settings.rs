@@ -0,0 +1,170 @@
+//! Middleware applying runtime [DeviceSettings] on the device side of a pump.
+//!
+//! The settings are read from a watch channel so they can be changed while
+//! the pump is running.  Settings that affect image conversion (orientation)
+//! and Companion originated actions (brightness) are applied by the companion
+//! receiver, and pacing is applied by the [schedule](crate::schedule) of the
+//! message pump; this layer remaps keys.  Images and input are numbered as
+//! Companion numbers the keys, and mapped to and from the physical keys of
+//! the device through the [KeyTransform] of its orientation, so a device
+//! mounted rotated shows and reports every key where the user sees it.
+
+use tokio::sync::watch;
+use traits::{
+    async_trait,
+    device::{Command, DeviceSettings, KeyTransform, SetBrightness, SetButtonImage, SetLCDImage},
+    Result,
+};
+
+/// The transform of the keys of a device of rows and columns as settings
+/// orient it now.
+fn key_transform(
+    settings: &watch::Receiver<DeviceSettings>,
+    rows: u8,
+    columns: u8,
+) -> KeyTransform {
+    KeyTransform::new(settings.borrow().orientation, rows, columns)
+}
+
+/// Wraps a device receiver, remapping the keys of a rotated device so
+/// Companion sees presses at the position the user sees them.
+pub struct SettingsReceiver<R> {
+    inner: R,
+    settings: watch::Receiver<DeviceSettings>,
+    rows: u8,
+    columns: u8,
+}
+
+impl<R> SettingsReceiver<R>
+where
+    R: traits::device::Receiver + Send,
+{
+    /// Wrap a device receiver with the provided settings.  rows and columns
+    /// are those of the physical keys of the device.
+    pub fn new(inner: R, settings: watch::Receiver<DeviceSettings>, rows: u8, columns: u8) -> Self {
+        Self {
+            inner,
+            settings,
+            rows,
+            columns,
+        }
+    }
+}
+
+#[async_trait]
+impl<R> traits::device::Receiver for SettingsReceiver<R>
+where
+    R: traits::device::Receiver + Send,
+{
+    async fn receive(&mut self) -> Result<Command> {
+        let mut command = self.inner.receive().await?;
+        let transform = key_transform(&self.settings, self.rows, self.columns);
+        if let Command::ButtonChange(change) = &mut command {
+            for (index, _) in change.buttons.iter_mut() {
+                *index = transform.to_companion(*index);
+            }
+        }
+        Ok(command)
+    }
+}
+
+/// Wraps a device sender, writing the image of each key to the physical key
+/// of a rotated device the user sees it at.
+pub struct KeyTransformSender<S> {
+    inner: S,
+    settings: watch::Receiver<DeviceSettings>,
+    rows: u8,
+    columns: u8,
+}
+
+impl<S> KeyTransformSender<S>
+where
+    S: traits::device::Sender + Send,
+{
+    /// Wrap a device sender with the provided settings.  rows and columns
+    /// are those of the physical keys of the device.
+    pub fn new(inner: S, settings: watch::Receiver<DeviceSettings>, rows: u8, columns: u8) -> Self {
+        Self {
+            inner,
+            settings,
+            rows,
+            columns,
+        }
+    }
+}
+
+#[async_trait]
+impl<S> traits::device::Sender for KeyTransformSender<S>
+where
+    S: traits::device::Sender + Send,
+{
+    async fn set_brightness(&mut self, brightness: SetBrightness) -> Result<()> {
+        self.inner.set_brightness(brightness).await
+    }
+    async fn set_button_image(&mut self, mut image: SetButtonImage) -> Result<()> {
+        let transform = key_transform(&self.settings, self.rows, self.columns);
+        image.button = transform.to_device(image.button);
+        self.inner.set_button_image(image).await
+    }
+    async fn set_lcd_image(&mut self, image: SetLCDImage) -> Result<()> {
+        self.inner.set_lcd_image(image).await
+    }
+    async fn clear(&mut self) -> Result<()> {
+        self.inner.clear().await
+    }
+    async fn commit(&mut self) -> Result<()> {
+        self.inner.commit().await
+    }
+    async fn clear_all(&mut self) -> Result<()> {
+        self.inner.clear_all().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device::{Device, Written::ButtonImage};
+    use traits::device::{Orientation, Sender as _};
+
+    #[tokio::test]
+    async fn test_keys_of_rotated_device_remapped() {
+        // A deck of 3 rows of 5 turned clockwise shows 5 rows of 3, its
+        // bottom left key at the top left
+        let transform = KeyTransform::new(Orientation::Rotated90, 3, 5);
+        assert_eq!(transform.to_device(0), 10);
+        assert_eq!(transform.to_device(2), 0);
+        assert_eq!(transform.to_device(14), 4);
+        for orientation in [
+            Orientation::Normal,
+            Orientation::Rotated90,
+            Orientation::Rotated180,
+            Orientation::Rotated270,
+        ] {
+            let transform = KeyTransform::new(orientation, 3, 5);
+            for key in 0..20 {
+                assert_eq!(transform.to_companion(transform.to_device(key)), key);
+            }
+        }
+
+        let (changed, settings) = watch::channel(DeviceSettings::default());
+        let device = Device::default();
+        let mut sender = KeyTransformSender::new(device.clone(), settings, 3, 5);
+        let image = |button| SetButtonImage {
+            button,
+            image: Vec::new(),
+        };
+        sender.set_button_image(image(0)).await.unwrap();
+        changed.send_modify(|settings| settings.orientation = Orientation::Rotated180);
+        sender.set_button_image(image(0)).await.unwrap();
+        // Keys of the LCD strip aren't moved
+        sender.set_button_image(image(15)).await.unwrap();
+        assert_eq!(
+            device.written(),
+            [
+                ButtonImage(0, vec![]),
+                ButtonImage(14, vec![]),
+                ButtonImage(15, vec![])
+            ]
+        );
+    }
+}
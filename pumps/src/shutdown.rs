@@ -0,0 +1,303 @@
+//! Stopping message pumps cleanly.
+//!
+//! A [ShutdownHandle] is shared by everything that should stop together.
+//! Once it is shut down, both halves of a pump stop taking new messages: the
+//! device side closes Companion, writing out any input it was still holding
+//! and removing the device, and the Companion side blanks the deck so it
+//! doesn't keep showing buttons that no longer do anything.
+//!
+//! A handle is shut down for a [ShutdownReason], which is passed on to the
+//! other end before closing, so an intentional restart can be told from a
+//! crash.
+//!
+//! Devices that take encoded images, like the leaves of a gateway, don't know
+//! how to blank themselves.  A [BlankingSender] does it for them with an image
+//! encoded for their kind.
+
+use std::sync::{Arc, OnceLock};
+
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+use traits::{
+    async_trait,
+    device::{SetBrightness, SetButtonImage, SetLCDImage, ShutdownReason},
+    Result,
+};
+
+/// Shared switch stopping the pumps that watch it.  Clones shut down
+/// together.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    token: CancellationToken,
+    /// Why the handle and each of its parents was shut down, its own first
+    reasons: Vec<Arc<OnceLock<ShutdownReason>>>,
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        Self {
+            token: CancellationToken::new(),
+            reasons: vec![Default::default()],
+        }
+    }
+}
+
+impl ShutdownHandle {
+    /// A handle that is not shut down yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Shut down on ctrl-c.
+    pub fn on_ctrl_c(self) -> Self {
+        let handle = self.clone();
+        tokio::spawn(async move {
+            match tokio::signal::ctrl_c().await {
+                Ok(()) => info!("Shutting down"),
+                Err(e) => warn!("Can't listen for ctrl-c, shutting down: {}", e),
+            }
+            handle.shutdown();
+        });
+        self
+    }
+
+    /// A handle shut down along with this one, that can also be shut down on
+    /// its own, such as for a single connection.
+    pub fn child(&self) -> Self {
+        let mut reasons = vec![Default::default()];
+        reasons.extend(self.reasons.iter().cloned());
+        Self {
+            token: self.token.child_token(),
+            reasons,
+        }
+    }
+
+    /// Stop everything watching this handle, as the program is shutting
+    /// down.
+    pub fn shutdown(&self) {
+        self.shutdown_because(ShutdownReason::Shutdown);
+    }
+
+    /// Stop everything watching this handle for reason.  A handle already
+    /// shut down, or shut down along with a parent, keeps the reason it was
+    /// first shut down for.
+    pub fn shutdown_because(&self, reason: ShutdownReason) {
+        if !self.is_shutdown() {
+            let _ = self.reasons[0].set(reason);
+        }
+        self.token.cancel();
+    }
+
+    /// Why the handle was shut down, or the parent it was shut down along
+    /// with, if it has been.
+    pub fn reason(&self) -> Option<ShutdownReason> {
+        self.reasons.iter().find_map(|reason| reason.get().copied())
+    }
+
+    /// Whether the handle has been shut down.
+    pub fn is_shutdown(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Wait for the handle to be shut down.
+    pub async fn wait(&self) {
+        self.token.cancelled().await
+    }
+}
+
+/// Wraps a device sender, blanking every key with an image encoded for the
+/// device when cleared.
+pub struct BlankingSender<S> {
+    inner: S,
+    keys: u8,
+    blank: Vec<u8>,
+}
+
+impl<S> BlankingSender<S>
+where
+    S: traits::device::Sender + Send,
+{
+    /// Wrap a device sender of keys keys, blank being a black image in the
+    /// format the device takes.
+    pub fn new(inner: S, keys: u8, blank: Vec<u8>) -> Self {
+        Self { inner, keys, blank }
+    }
+
+    /// Set the blank image on every key.
+    async fn blank(&mut self) -> Result<()> {
+        debug!("Blanking {} keys", self.keys);
+        for button in 0..self.keys {
+            let image = SetButtonImage {
+                button,
+                image: self.blank.clone(),
+            };
+            self.inner.set_button_image(image).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<S> traits::device::Sender for BlankingSender<S>
+where
+    S: traits::device::Sender + Send,
+{
+    async fn set_brightness(&mut self, brightness: SetBrightness) -> Result<()> {
+        self.inner.set_brightness(brightness).await
+    }
+    async fn set_button_image(&mut self, image: SetButtonImage) -> Result<()> {
+        self.inner.set_button_image(image).await
+    }
+    async fn set_lcd_image(&mut self, image: SetLCDImage) -> Result<()> {
+        self.inner.set_lcd_image(image).await
+    }
+    async fn clear(&mut self) -> Result<()> {
+        self.blank().await?;
+        self.inner.clear().await?;
+        self.inner.commit().await
+    }
+    async fn commit(&mut self) -> Result<()> {
+        self.inner.commit().await
+    }
+    async fn clear_all(&mut self) -> Result<()> {
+        self.blank().await?;
+        self.inner.clear_all().await?;
+        self.inner.commit().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device::{Device, Written};
+    use std::sync::{Arc, Mutex};
+    use traits::device::{
+        ButtonChange, DeviceActions, EncoderPress, EncoderTwist, PowerState, RemoteConfig,
+        StatusImage, Touch,
+    };
+
+    /// What the pump did to Companion.
+    type Log = Arc<Mutex<Vec<String>>>;
+
+    struct Companion(Log);
+
+    #[async_trait]
+    impl traits::companion::Sender for Companion {
+        async fn config(&mut self, _: RemoteConfig) -> Result<()> {
+            Ok(())
+        }
+        async fn button_change(&mut self, _: ButtonChange) -> Result<()> {
+            Ok(())
+        }
+        async fn encoder_twist(&mut self, _: EncoderTwist) -> Result<()> {
+            Ok(())
+        }
+        async fn encoder_press(&mut self, _: EncoderPress) -> Result<()> {
+            Ok(())
+        }
+        async fn touch(&mut self, _: Touch) -> Result<()> {
+            Ok(())
+        }
+        async fn power(&mut self, _: PowerState) -> Result<()> {
+            Ok(())
+        }
+        async fn status_image(&mut self, _: StatusImage) -> Result<()> {
+            Ok(())
+        }
+        async fn goodbye(&mut self, reason: ShutdownReason) -> Result<()> {
+            self.0.lock().unwrap().push(format!("goodbye {reason}"));
+            Ok(())
+        }
+        async fn close(&mut self) -> Result<()> {
+            self.0.lock().unwrap().push(String::from("close"));
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl traits::companion::Receiver for Companion {
+        async fn receive(&mut self) -> Result<DeviceActions> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_companion_and_blanks_device() {
+        let log = Log::default();
+        let device = Device::default();
+        let shutdown = ShutdownHandle::new();
+        let connection = shutdown.child();
+        let pump = tokio::spawn(crate::message_pump_until(
+            BlankingSender::new(device.clone(), 2, vec![0]),
+            device.clone(),
+            Companion(log.clone()),
+            Companion(log.clone()),
+            Default::default(),
+            tokio::sync::watch::channel(Default::default()).1,
+            connection.clone(),
+        ));
+        tokio::task::yield_now().await;
+        assert!(log.lock().unwrap().is_empty());
+        assert!(device.written().is_empty());
+        assert_eq!(connection.reason(), None);
+
+        shutdown.shutdown_because(ShutdownReason::Kicked);
+        pump.await.unwrap().unwrap();
+        // The halves stop side by side, Companion told why first
+        assert_eq!(*log.lock().unwrap(), ["goodbye kicked", "close"]);
+        assert_eq!(
+            device.written(),
+            [
+                Written::ButtonImage(0, vec![0]),
+                Written::ButtonImage(1, vec![0]),
+                Written::ClearAll,
+                Written::Commit,
+            ]
+        );
+        // Shut down along with its parent, for the parent's reason
+        assert_eq!(connection.reason(), Some(ShutdownReason::Kicked));
+        connection.shutdown_because(ShutdownReason::Repointed);
+        assert_eq!(connection.reason(), Some(ShutdownReason::Kicked));
+
+        // Shut down on its own, for its own reason
+        let shutdown = ShutdownHandle::new();
+        let connection = shutdown.child();
+        connection.shutdown_because(ShutdownReason::Repointed);
+        shutdown.shutdown();
+        assert_eq!(connection.reason(), Some(ShutdownReason::Repointed));
+        assert_eq!(shutdown.reason(), Some(ShutdownReason::Shutdown));
+    }
+    /// A leaf that says goodbye as soon as it is read.
+    struct Leaving(Option<ShutdownReason>);
+
+    #[async_trait]
+    impl traits::device::Receiver for Leaving {
+        async fn receive(&mut self) -> Result<traits::device::Command> {
+            match self.0.take() {
+                Some(reason) => Ok(traits::device::Command::Goodbye(reason)),
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_goodbye_of_device_passed_to_companion() {
+        let log = Log::default();
+        let shutdown = ShutdownHandle::new();
+        crate::message_pump_until(
+            Device::default(),
+            Leaving(Some(ShutdownReason::ConfigReload)),
+            Companion(log.clone()),
+            Companion(log.clone()),
+            Default::default(),
+            tokio::sync::watch::channel(Default::default()).1,
+            shutdown.clone(),
+        )
+        .await
+        .unwrap();
+
+        // The pump stops as if shut down for the reason the device gave
+        assert_eq!(*log.lock().unwrap(), ["goodbye config_reload", "close"]);
+        assert_eq!(shutdown.reason(), Some(ShutdownReason::ConfigReload));
+    }
+}
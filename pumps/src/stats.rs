@@ -0,0 +1,294 @@
+//! Lightweight health counters for a message pump.
+//!
+//! The counters are updated lock-free from the pump tasks and can be read at
+//! any time from other tasks (status endpoints, PING payloads, logging) by
+//! taking a [StatsSnapshot].
+//!
+//! How often each key is pressed and how long it is held are counted too,
+//! for seeing how shared panels are used, and read with [PumpStats::keys].
+//! A key held for longer than [STUCK_AFTER] is reported as stuck, as no one
+//! holds a key that long on purpose.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::time::Instant;
+
+/// How long a key is held before it is reported as stuck.
+pub const STUCK_AFTER: Duration = Duration::from_secs(60);
+
+/// Health counters shared between a message pump and whoever reports on it.
+#[derive(Debug, Default)]
+pub struct PumpStats {
+    frames_to_device: AtomicU64,
+    frames_to_companion: AtomicU64,
+    dropped_frames: AtomicU64,
+    errors: AtomicU64,
+    queue_depth: AtomicUsize,
+    /// When a frame was last forwarded each way, in milliseconds since the
+    /// Unix epoch, or 0 if none has been
+    last_to_device: AtomicU64,
+    last_to_companion: AtomicU64,
+    /// Shared with the sender writing to the device, which knows how many
+    /// bytes it writes
+    bytes_to_device: Arc<AtomicU64>,
+    /// Shared with the receiver reading from Companion, which knows how many
+    /// actions it has queued
+    companion_queue_depth: Arc<AtomicUsize>,
+    keys: Mutex<BTreeMap<u8, KeyUsage>>,
+}
+
+/// Presses of a key counted so far.
+#[derive(Debug, Default)]
+struct KeyUsage {
+    presses: u64,
+    held: Duration,
+    longest: Duration,
+    /// When the key was pressed, if it still is
+    pressed_at: Option<Instant>,
+}
+
+impl PumpStats {
+    /// A frame was forwarded from the companion app to the device.
+    pub fn record_to_device(&self) {
+        self.frames_to_device.fetch_add(1, Ordering::Relaxed);
+        self.last_to_device.store(unix_ms(), Ordering::Relaxed);
+    }
+
+    /// A frame was forwarded from the device to the companion app.
+    pub fn record_to_companion(&self) {
+        self.frames_to_companion.fetch_add(1, Ordering::Relaxed);
+        self.last_to_companion.store(unix_ms(), Ordering::Relaxed);
+    }
+
+    /// Frames were intentionally discarded (coalesced, rate limited, etc).
+    pub fn record_dropped(&self, count: u64) {
+        self.dropped_frames.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// An operation in the pump failed.
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Update the number of frames waiting to be delivered to the device.
+    pub fn set_queue_depth(&self, depth: usize) {
+        self.queue_depth.store(depth, Ordering::Relaxed);
+    }
+
+    /// The counter of bytes written to the device, for a sender that knows
+    /// how many bytes it writes to add to.
+    pub fn bytes_to_device(&self) -> Arc<AtomicU64> {
+        self.bytes_to_device.clone()
+    }
+
+    /// The gauge of actions read from Companion but not yet taken by the
+    /// pump, for the receiver queueing them to keep up to date.
+    pub fn companion_queue_depth(&self) -> Arc<AtomicUsize> {
+        self.companion_queue_depth.clone()
+    }
+
+    /// A key was pressed or released on the device.  Reports of a key in the
+    /// state it is already in are ignored, so devices reporting every key on
+    /// each change are counted right.
+    pub fn record_key(&self, key: u8, pressed: bool) {
+        let now = Instant::now();
+        let mut keys = self.keys.lock().unwrap();
+        let usage = keys.entry(key).or_default();
+        match (usage.pressed_at, pressed) {
+            (None, true) => {
+                usage.presses += 1;
+                usage.pressed_at = Some(now);
+            }
+            (Some(pressed_at), false) => {
+                let held = now - pressed_at;
+                usage.held += held;
+                usage.longest = usage.longest.max(held);
+                usage.pressed_at = None;
+            }
+            _ => {}
+        }
+    }
+
+    /// Presses and hold times of every key pressed so far, by key.
+    pub fn keys(&self) -> Vec<KeyStats> {
+        let now = Instant::now();
+        self.keys
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&key, usage)| {
+                let holding = usage.pressed_at.map(|pressed_at| now - pressed_at);
+                KeyStats {
+                    key,
+                    presses: usage.presses,
+                    held_ms: usage.held.as_millis() as u64,
+                    longest_ms: usage.longest.max(holding.unwrap_or_default()).as_millis() as u64,
+                    holding_ms: holding.map(|holding| holding.as_millis() as u64),
+                    stuck: holding.is_some_and(|holding| holding > STUCK_AFTER),
+                }
+            })
+            .collect()
+    }
+
+    /// Take a point in time copy of the counters.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            frames_to_device: self.frames_to_device.load(Ordering::Relaxed),
+            frames_to_companion: self.frames_to_companion.load(Ordering::Relaxed),
+            dropped_frames: self.dropped_frames.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            queue_depth: self.queue_depth.load(Ordering::Relaxed),
+            companion_queue_depth: self.companion_queue_depth.load(Ordering::Relaxed),
+            bytes_to_device: self.bytes_to_device.load(Ordering::Relaxed),
+            last_to_device_unix_ms: nonzero(self.last_to_device.load(Ordering::Relaxed)),
+            last_to_companion_unix_ms: nonzero(self.last_to_companion.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// A point in time copy of [PumpStats].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct StatsSnapshot {
+    /// Frames forwarded from the companion app to the device
+    pub frames_to_device: u64,
+    /// Frames forwarded from the device to the companion app
+    pub frames_to_companion: u64,
+    /// Frames intentionally discarded
+    pub dropped_frames: u64,
+    /// Failed operations
+    pub errors: u64,
+    /// Frames waiting to be delivered to the device
+    pub queue_depth: usize,
+    /// Actions read from Companion waiting to be converted or taken by the
+    /// pump
+    pub companion_queue_depth: usize,
+    /// Bytes written to the device, if its sender counts them
+    pub bytes_to_device: u64,
+    /// When a frame was last forwarded to the device, in milliseconds since
+    /// the Unix epoch
+    pub last_to_device_unix_ms: Option<u64>,
+    /// When a frame was last forwarded to the companion app, in milliseconds
+    /// since the Unix epoch
+    pub last_to_companion_unix_ms: Option<u64>,
+}
+
+/// Milliseconds since the Unix epoch.
+fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// None for a timestamp that was never set.
+fn nonzero(unix_ms: u64) -> Option<u64> {
+    (unix_ms != 0).then_some(unix_ms)
+}
+
+/// How a key of a device has been used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct KeyStats {
+    /// The key
+    pub key: u8,
+    /// Times the key was pressed
+    pub presses: u64,
+    /// Milliseconds the key was held for, over the presses released
+    pub held_ms: u64,
+    /// Milliseconds of the longest press, including one still held
+    pub longest_ms: u64,
+    /// Milliseconds the key has been held for, if it is held now
+    pub holding_ms: Option<u64>,
+    /// Whether the key has been held for longer than [STUCK_AFTER]
+    pub stuck: bool,
+}
+
+/// Formats the snapshot as space separated key=value pairs, suitable for
+/// log lines and protocol payloads.
+impl std::fmt::Display for StatsSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "TO_DEVICE={} TO_COMPANION={} DROPPED={} ERRORS={} QUEUE={} COMPANION_QUEUE={} BYTES={}",
+            self.frames_to_device,
+            self.frames_to_companion,
+            self.dropped_frames,
+            self.errors,
+            self.queue_depth,
+            self.companion_queue_depth,
+            self.bytes_to_device
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters_reported() {
+        let stats = PumpStats::default();
+        stats.record_to_device();
+        stats.record_to_device();
+        stats.record_to_companion();
+        stats.record_dropped(3);
+        stats.record_error();
+        stats.set_queue_depth(4);
+        stats.companion_queue_depth().store(5, Ordering::Relaxed);
+        stats.bytes_to_device().fetch_add(600, Ordering::Relaxed);
+
+        let snapshot = stats.snapshot();
+        // As in the PING payload
+        assert_eq!(
+            snapshot.to_string(),
+            "TO_DEVICE=2 TO_COMPANION=1 DROPPED=3 ERRORS=1 QUEUE=4 COMPANION_QUEUE=5 BYTES=600"
+        );
+        // As in the status endpoint
+        let json = serde_json::to_value(snapshot).unwrap();
+        assert_eq!(json["frames_to_device"], 2);
+        assert_eq!(json["frames_to_companion"], 1);
+        assert_eq!(json["dropped_frames"], 3);
+        assert_eq!(json["errors"], 1);
+        assert_eq!(json["queue_depth"], 4);
+        assert_eq!(json["companion_queue_depth"], 5);
+        assert_eq!(json["bytes_to_device"], 600);
+        assert!(json["last_to_device_unix_ms"].as_u64().unwrap() > 0);
+
+        let json = serde_json::to_value(PumpStats::default().snapshot()).unwrap();
+        assert!(json["last_to_companion_unix_ms"].is_null());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_key_presses_timed() {
+        let stats = PumpStats::default();
+        stats.record_key(3, true);
+        tokio::time::advance(Duration::from_millis(200)).await;
+        // Repeated reports of the same state change nothing
+        stats.record_key(3, true);
+        stats.record_key(3, false);
+        stats.record_key(3, false);
+        stats.record_key(3, true);
+        tokio::time::advance(Duration::from_millis(100)).await;
+        stats.record_key(3, false);
+        stats.record_key(7, true);
+        tokio::time::advance(STUCK_AFTER + Duration::from_secs(1)).await;
+
+        let keys = stats.keys();
+        assert_eq!(
+            keys[0],
+            KeyStats {
+                key: 3,
+                presses: 2,
+                held_ms: 300,
+                longest_ms: 200,
+                holding_ms: None,
+                stuck: false,
+            }
+        );
+        assert_eq!(keys[1].holding_ms, Some(61_000));
+        assert_eq!(keys[1].longest_ms, 61_000);
+        assert!(keys[1].stuck);
+    }
+}
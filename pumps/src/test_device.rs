@@ -0,0 +1,99 @@
+//! A fake device recording what is written to it, shared by the tests of
+//! the senders wrapping devices.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use traits::{
+    async_trait,
+    device::{Command, SetBrightness, SetButtonImage, SetLCDImage},
+    Result,
+};
+
+/// Something written to a [Device].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Written {
+    Brightness(u8),
+    ButtonImage(u8, Vec<u8>),
+    LcdImage(u16, Vec<u8>),
+    Clear,
+    ClearAll,
+    Commit,
+}
+
+/// Records everything written to it.  Clones share the record, so a test
+/// hands a clone to the sender under test and checks the record after.
+#[derive(Clone, Default)]
+pub(crate) struct Device {
+    written: Arc<Mutex<Vec<(Instant, Written)>>>,
+    byte_time: Duration,
+}
+
+impl Device {
+    /// Take byte_time to write each byte of an image, as a slow link would.
+    pub(crate) fn with_byte_time(mut self, byte_time: Duration) -> Self {
+        self.byte_time = byte_time;
+        self
+    }
+
+    /// Everything written so far, in order.
+    pub(crate) fn written(&self) -> Vec<Written> {
+        let written = self.written.lock().unwrap();
+        written.iter().map(|(_, written)| written.clone()).collect()
+    }
+
+    /// Everything written so far, in order, with when it was written.
+    pub(crate) fn written_at(&self) -> Vec<(Instant, Written)> {
+        self.written.lock().unwrap().clone()
+    }
+
+    fn record(&self, written: Written) {
+        self.written.lock().unwrap().push((Instant::now(), written));
+    }
+
+    async fn transfer(&self, image: &[u8]) {
+        if !self.byte_time.is_zero() {
+            tokio::time::sleep(self.byte_time * image.len() as u32).await;
+        }
+    }
+}
+
+#[async_trait]
+impl traits::device::Sender for Device {
+    async fn set_brightness(&mut self, brightness: SetBrightness) -> Result<()> {
+        self.record(Written::Brightness(brightness.brightness));
+        Ok(())
+    }
+    async fn set_button_image(&mut self, image: SetButtonImage) -> Result<()> {
+        self.transfer(&image.image).await;
+        self.record(Written::ButtonImage(image.button, image.image));
+        Ok(())
+    }
+    async fn set_lcd_image(&mut self, image: SetLCDImage) -> Result<()> {
+        self.transfer(&image.image).await;
+        self.record(Written::LcdImage(image.x_offset, image.image));
+        Ok(())
+    }
+    async fn clear(&mut self) -> Result<()> {
+        self.record(Written::Clear);
+        Ok(())
+    }
+    async fn clear_all(&mut self) -> Result<()> {
+        self.record(Written::ClearAll);
+        Ok(())
+    }
+    async fn commit(&mut self) -> Result<()> {
+        self.record(Written::Commit);
+        Ok(())
+    }
+}
+
+/// Nothing is ever pressed.
+#[async_trait]
+impl traits::device::Receiver for Device {
+    async fn receive(&mut self) -> Result<Command> {
+        std::future::pending().await
+    }
+}
@@ -0,0 +1,392 @@
+//! Transcripts of the traffic of devices, to attach to bug reports.
+//!
+//! A [Transcript] is a file of JSON lines, one [Entry] per protocol event:
+//! input from a device, and what Companion had shown on it.  Images are
+//! summed up by their size and a hash rather than kept, so a transcript is
+//! small and shows nothing that was on the keys, while still telling
+//! whether the same image was sent twice.
+//!
+//! Devices are recorded by wrapping their sender and receiver in a
+//! [TranscriptSender] and [TranscriptReceiver] sharing one [DeviceTranscript].
+//! The default [Transcript] records nothing, so the wrappers can stay in
+//! place when no transcript was asked for.  The entries of a transcript are
+//! read back with [load] or [parse].
+
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use traits::{
+    async_trait,
+    device::{Command, Fingerprint, SetBrightness, SetButtonImage, SetLCDImage},
+    Result,
+};
+
+/// One protocol event of a device.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    /// Milliseconds since the transcript was started
+    pub at_ms: u64,
+    /// The device, once it has said which it is
+    pub device_id: Option<String>,
+    /// What happened
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// Something a device or Companion did.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The device said what it is
+    Config {
+        /// Product id of the device
+        pid: u16,
+        /// The capabilities images are converted for
+        fingerprint: Fingerprint,
+    },
+    /// Input from the device, other than its config and status images
+    Input {
+        /// The input as the device sent it
+        command: Command,
+    },
+    /// The device sent a status image
+    StatusImage {
+        /// Width in pixels
+        width: u16,
+        /// Height in pixels
+        height: u16,
+        /// The pixels
+        image: ImageSummary,
+    },
+    /// An image was set on a key
+    ButtonImage {
+        /// The key
+        button: u8,
+        /// The image, encoded for the device
+        image: ImageSummary,
+    },
+    /// An image was set on the LCD strip
+    LcdImage {
+        /// Left edge of the image
+        x_offset: u16,
+        /// Width in pixels
+        width: u16,
+        /// Height in pixels
+        height: u16,
+        /// The image, encoded for the device
+        image: ImageSummary,
+    },
+    /// The brightness was set
+    Brightness {
+        /// Brightness in percent
+        brightness: u8,
+    },
+    /// Every key was blanked
+    Clear,
+    /// Every key and the LCD were blanked as what they showed was stale
+    ClearAll,
+    /// Talking to the device failed
+    Error {
+        /// What went wrong
+        message: String,
+    },
+}
+
+/// An image, without its pixels.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ImageSummary {
+    /// Size in bytes
+    pub bytes: usize,
+    /// 64 bit FNV-1a of the bytes, in hex
+    pub hash: String,
+}
+
+impl ImageSummary {
+    /// Sum up data.
+    pub fn of(data: &[u8]) -> Self {
+        // FNV-1a, unlike the hasher of std, is the same in every build
+        let hash = data.iter().fold(0xcbf2_9ce4_8422_2325, |hash: u64, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+        Self {
+            bytes: data.len(),
+            hash: format!("{hash:016x}"),
+        }
+    }
+}
+
+impl From<&Command> for Event {
+    fn from(command: &Command) -> Self {
+        match command {
+            Command::Config(config) => Event::Config {
+                pid: config.pid,
+                fingerprint: config.fingerprint,
+            },
+            Command::StatusImage(image) => Event::StatusImage {
+                width: image.width,
+                height: image.height,
+                image: ImageSummary::of(&image.rgb),
+            },
+            command => Event::Input {
+                command: command.clone(),
+            },
+        }
+    }
+}
+
+/// A transcript being written, shared by every device recorded in it.
+#[derive(Clone)]
+pub struct Transcript {
+    file: Option<Arc<Mutex<LineWriter<File>>>>,
+    started: Instant,
+}
+
+/// A transcript recording nothing.
+impl Default for Transcript {
+    fn default() -> Self {
+        Self {
+            file: None,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Transcript {
+    /// Start a transcript at path, replacing any file there.
+    pub fn create(path: &Path) -> Result<Self> {
+        info!("Recording a transcript to {}", path.display());
+        Ok(Self {
+            file: Some(Arc::new(Mutex::new(LineWriter::new(File::create(path)?)))),
+            started: Instant::now(),
+        })
+    }
+
+    /// Record the events of a device, known as device_id if it is known yet.
+    pub fn device(&self, device_id: Option<&str>) -> DeviceTranscript {
+        DeviceTranscript {
+            transcript: self.clone(),
+            device_id: Arc::new(Mutex::new(device_id.map(String::from))),
+        }
+    }
+
+    /// Write an entry.  A transcript that can't be written is only warned
+    /// about, so it never gets in the way of the device.
+    fn write(&self, file: &Mutex<LineWriter<File>>, entry: &Entry) {
+        let res = serde_json::to_string(entry)
+            .map_err(std::io::Error::from)
+            .and_then(|line| writeln!(file.lock().unwrap(), "{line}"));
+        if let Err(e) = res {
+            warn!("Couldn't write to the transcript: {}", e);
+        }
+    }
+}
+
+/// The events of one device in a [Transcript].
+#[derive(Clone)]
+pub struct DeviceTranscript {
+    transcript: Transcript,
+    device_id: Arc<Mutex<Option<String>>>,
+}
+
+impl DeviceTranscript {
+    /// Record that the event made by event happened now.  It is only made
+    /// if the transcript is recording.
+    pub fn record(&self, event: impl FnOnce() -> Event) {
+        let Some(file) = &self.transcript.file else {
+            return;
+        };
+        let entry = Entry {
+            at_ms: self.transcript.started.elapsed().as_millis() as u64,
+            device_id: self.device_id.lock().unwrap().clone(),
+            event: event(),
+        };
+        self.transcript.write(file, &entry);
+    }
+
+    /// Record a command from the device, taking on its id if it is a config.
+    pub fn record_command(&self, command: &Command) {
+        if let Command::Config(config) = command {
+            *self.device_id.lock().unwrap() = Some(config.device_id.clone());
+        }
+        self.record(|| command.into());
+    }
+
+    /// Record the error of a failed call, passing the result on.
+    fn record_result<T>(&self, result: Result<T>) -> Result<T> {
+        if let Err(e) = &result {
+            self.record(|| Event::Error {
+                message: format!("{e:#}"),
+            });
+        }
+        result
+    }
+}
+
+/// Wraps a device sender, recording what Companion shows on the device.
+pub struct TranscriptSender<S> {
+    inner: S,
+    transcript: DeviceTranscript,
+}
+
+impl<S> TranscriptSender<S> {
+    /// Record what is sent through inner in transcript.
+    pub fn new(inner: S, transcript: DeviceTranscript) -> Self {
+        Self { inner, transcript }
+    }
+}
+
+#[async_trait]
+impl<S> traits::device::Sender for TranscriptSender<S>
+where
+    S: traits::device::Sender + Send,
+{
+    async fn set_brightness(&mut self, brightness: SetBrightness) -> Result<()> {
+        self.transcript.record(|| Event::Brightness {
+            brightness: brightness.brightness,
+        });
+        let res = self.inner.set_brightness(brightness).await;
+        self.transcript.record_result(res)
+    }
+    async fn set_button_image(&mut self, image: SetButtonImage) -> Result<()> {
+        self.transcript.record(|| Event::ButtonImage {
+            button: image.button,
+            image: ImageSummary::of(&image.image),
+        });
+        let res = self.inner.set_button_image(image).await;
+        self.transcript.record_result(res)
+    }
+    async fn set_lcd_image(&mut self, image: SetLCDImage) -> Result<()> {
+        self.transcript.record(|| Event::LcdImage {
+            x_offset: image.x_offset,
+            width: image.x_size,
+            height: image.y_size,
+            image: ImageSummary::of(&image.image),
+        });
+        let res = self.inner.set_lcd_image(image).await;
+        self.transcript.record_result(res)
+    }
+    async fn clear(&mut self) -> Result<()> {
+        self.transcript.record(|| Event::Clear);
+        let res = self.inner.clear().await;
+        self.transcript.record_result(res)
+    }
+    async fn commit(&mut self) -> Result<()> {
+        self.inner.commit().await
+    }
+    async fn clear_all(&mut self) -> Result<()> {
+        self.transcript.record(|| Event::ClearAll);
+        let res = self.inner.clear_all().await;
+        self.transcript.record_result(res)
+    }
+}
+
+/// Wraps a device receiver, recording the input of the device.
+pub struct TranscriptReceiver<R> {
+    inner: R,
+    transcript: DeviceTranscript,
+}
+
+impl<R> TranscriptReceiver<R> {
+    /// Record what is received through inner in transcript.
+    pub fn new(inner: R, transcript: DeviceTranscript) -> Self {
+        Self { inner, transcript }
+    }
+}
+
+#[async_trait]
+impl<R> traits::device::Receiver for TranscriptReceiver<R>
+where
+    R: traits::device::Receiver + Send,
+{
+    async fn receive(&mut self) -> Result<Command> {
+        let command = self.transcript.record_result(self.inner.receive().await)?;
+        self.transcript.record_command(&command);
+        Ok(command)
+    }
+}
+
+/// Read the entries of the transcript at path.
+pub fn load(path: &Path) -> Result<Vec<Entry>> {
+    parse(&std::fs::read_to_string(path)?)
+}
+
+/// Read the entries of a transcript.
+pub fn parse(text: &str) -> Result<Vec<Entry>> {
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Ok(serde_json::from_str(line)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_device::Device;
+    use traits::device::{ButtonChange, Receiver as _, RemoteConfig, Sender as _};
+
+    /// Input from a deck, unplugged once it runs out.
+    struct Input(Vec<Command>);
+
+    #[async_trait]
+    impl traits::device::Receiver for Input {
+        async fn receive(&mut self) -> Result<Command> {
+            self.0
+                .pop()
+                .ok_or_else(|| traits::anyhow::anyhow!("unplugged"))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transcript_round_trips() {
+        let path = std::env::temp_dir().join(format!("transcript-{}.jsonl", std::process::id()));
+        let transcript = Transcript::create(&path).unwrap().device(None);
+        let config = RemoteConfig {
+            pid: 0x80,
+            device_id: "deck".into(),
+            fingerprint: Default::default(),
+        };
+        let press = ButtonChange {
+            buttons: vec![(3, true)],
+        };
+        let mut receiver = TranscriptReceiver::new(
+            Input(vec![Command::ButtonChange(press), Command::Config(config)]),
+            transcript.clone(),
+        );
+        let mut sender = TranscriptSender::new(Device::default(), transcript);
+        receiver.receive().await.unwrap();
+        let image = vec![0x42; 10_000];
+        sender
+            .set_button_image(SetButtonImage { button: 3, image })
+            .await
+            .unwrap();
+        receiver.receive().await.unwrap();
+        assert!(receiver.receive().await.is_err());
+
+        let entries = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let events: Vec<_> = entries.iter().map(|entry| &entry.event).collect();
+        assert!(matches!(events[0], Event::Config { pid: 0x80, .. }));
+        // Images are only summed up
+        let Event::ButtonImage { button: 3, image } = events[1] else {
+            panic!("Expected a button image, got {:?}", events[1]);
+        };
+        assert_eq!(*image, ImageSummary::of(&[0x42; 10_000]));
+        assert_eq!(image.bytes, 10_000);
+        assert!(matches!(
+            events[2],
+            Event::Input {
+                command: Command::ButtonChange(_)
+            }
+        ));
+        assert!(matches!(events[3], Event::Error { .. }));
+        // Entries after the config carry its device id
+        assert!(entries[1..]
+            .iter()
+            .all(|entry| entry.device_id.as_deref() == Some("deck")));
+    }
+}
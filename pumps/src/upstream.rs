@@ -0,0 +1,119 @@
+//! Images sent up by leaves.
+//!
+//! Leaves with something worth showing, such as a DIY device watching the
+//! tally of a camera, send a small [StatusImage] of it now and then.
+//! Companion has nowhere to show it, so a [StatusImageMonitor] takes it out
+//! of the input of the device and publishes the latest one for the gateway
+//! to serve.
+
+use tokio::sync::watch;
+use tracing::{debug, warn};
+use traits::{
+    async_trait,
+    device::{Command, StatusImage},
+    Result,
+};
+
+/// Create the channel the status image of a leaf is published on.  Leaves
+/// have none until they send one.
+pub fn channel() -> (
+    watch::Sender<Option<StatusImage>>,
+    watch::Receiver<Option<StatusImage>>,
+) {
+    watch::channel(None)
+}
+
+/// Wraps a device receiver, publishing the status images of the leaf rather
+/// than passing them on.  Images that are too large or don't have as many
+/// pixels as they say are dropped, and empty ones clear the last image.
+pub struct StatusImageMonitor<R> {
+    inner: R,
+    images: watch::Sender<Option<StatusImage>>,
+}
+
+impl<R> StatusImageMonitor<R>
+where
+    R: traits::device::Receiver + Send,
+{
+    /// Wrap a device receiver, publishing its status images on images.
+    pub fn new(inner: R, images: watch::Sender<Option<StatusImage>>) -> Self {
+        Self { inner, images }
+    }
+}
+
+#[async_trait]
+impl<R> traits::device::Receiver for StatusImageMonitor<R>
+where
+    R: traits::device::Receiver + Send,
+{
+    async fn receive(&mut self) -> Result<Command> {
+        loop {
+            match self.inner.receive().await? {
+                Command::StatusImage(image) if image.is_valid() => {
+                    debug!("Status image {}x{}", image.width, image.height);
+                    // An empty image takes the last one down
+                    self.images
+                        .send_replace((!image.rgb.is_empty()).then_some(image));
+                }
+                Command::StatusImage(image) => warn!(
+                    "Dropping a status image of {}x{} with {} bytes",
+                    image.width,
+                    image.height,
+                    image.rgb.len()
+                ),
+                command => return Ok(command),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traits::device::{ButtonChange, Receiver as _};
+
+    struct Device(Vec<Command>);
+
+    #[async_trait]
+    impl traits::device::Receiver for Device {
+        async fn receive(&mut self) -> Result<Command> {
+            Ok(self.0.pop().unwrap())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_status_images_published_not_passed_on() {
+        let image = |width, height, len| {
+            Command::StatusImage(StatusImage {
+                width,
+                height,
+                rgb: vec![0x80; len],
+            })
+        };
+        let press = || Command::ButtonChange(ButtonChange { buttons: vec![] });
+        let (images, mut published) = channel();
+        let mut monitor = StatusImageMonitor::new(
+            Device(vec![
+                press(),
+                image(0, 0, 0),
+                press(),
+                image(200, 1, 600),
+                image(2, 2, 11),
+                image(2, 1, 6),
+            ]),
+            images,
+        );
+
+        assert!(matches!(
+            monitor.receive().await.unwrap(),
+            Command::ButtonChange(_)
+        ));
+        // Broken images are dropped, leaving the last good one
+        let latest = published.borrow_and_update().clone().unwrap();
+        assert_eq!((latest.width, latest.height), (2, 1));
+
+        // An empty image clears it
+        monitor.receive().await.unwrap();
+        assert!(published.borrow().is_none());
+    }
+}
@@ -0,0 +1,299 @@
+//! Experimental streaming of video frames to the LCD strip of a Stream Deck
+//! Plus.
+//!
+//! Frames are published to a watch channel by a producer (the status endpoint
+//! of the gateway, an animation, ...).  [LcdVideo] wraps a companion receiver
+//! and interleaves the frames with the actions coming from Companion, at no
+//! more than the configured frame rate.  Producers faster than that simply
+//! have their intermediate frames skipped, and only the columns of the strip
+//! that changed since the previous frame are sent to the device.
+//!
+//! While a stream is active, LCD images from Companion are ignored so the two
+//! don't fight over the strip.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+use traits::{
+    anyhow, async_trait,
+    device::{DeviceActions, SetLCDImage},
+    Result,
+};
+
+/// A full frame for the LCD strip as packed 8 bit RGB.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LcdFrame {
+    /// Width of the frame in pixels
+    pub width: u16,
+    /// Height of the frame in pixels
+    pub height: u16,
+    /// width * height pixels of RGB, row by row
+    pub rgb: Vec<u8>,
+}
+
+impl LcdFrame {
+    /// Create a frame, checking the data is the right size.
+    pub fn new(width: u16, height: u16, rgb: Vec<u8>) -> Result<Self> {
+        let expected = usize::from(width) * usize::from(height) * 3;
+        if rgb.len() != expected {
+            anyhow::bail!(
+                "Expected a {}x{} frame to be {} bytes, but was {}",
+                width,
+                height,
+                expected,
+                rgb.len()
+            );
+        }
+        Ok(Self { width, height, rgb })
+    }
+
+    /// The range of columns that differ from the previous frame, or None if
+    /// nothing changed.  Every column differs if the frames aren't the same
+    /// size.
+    pub fn dirty_columns(&self, previous: Option<&LcdFrame>) -> Option<(u16, u16)> {
+        let previous = match previous {
+            Some(previous) if previous.width == self.width && previous.height == self.height => {
+                previous
+            }
+            _ => return (self.width > 0).then_some((0, self.width)),
+        };
+
+        let row_len = usize::from(self.width) * 3;
+        let mut dirty: Option<(usize, usize)> = None;
+        for (row, previous_row) in self
+            .rgb
+            .chunks_exact(row_len)
+            .zip(previous.rgb.chunks_exact(row_len))
+        {
+            let first = row
+                .chunks_exact(3)
+                .zip(previous_row.chunks_exact(3))
+                .position(|(a, b)| a != b);
+            let Some(first) = first else {
+                continue;
+            };
+            let last = row
+                .chunks_exact(3)
+                .zip(previous_row.chunks_exact(3))
+                .rposition(|(a, b)| a != b)
+                .unwrap_or(first);
+            dirty = Some(match dirty {
+                Some((start, end)) => (start.min(first), end.max(last + 1)),
+                None => (first, last + 1),
+            });
+        }
+        dirty.map(|(start, end)| (start as u16, end as u16))
+    }
+
+    /// Cut the columns start..end out of the frame.
+    fn crop(&self, start: u16, end: u16) -> Vec<u8> {
+        let row_len = usize::from(self.width) * 3;
+        let (start, end) = (usize::from(start) * 3, usize::from(end) * 3);
+        self.rgb
+            .chunks_exact(row_len)
+            .flat_map(|row| &row[start..end])
+            .copied()
+            .collect()
+    }
+}
+
+/// Encodes width by height pixels of packed 8 bit RGB into what the LCD strip
+/// of the device takes, as the `convert_lcd` of its image converter does.
+pub type EncodeLcd = Arc<dyn Fn(u16, u16, Vec<u8>) -> Result<Vec<u8>> + Send + Sync>;
+
+/// Create the channel producers publish LCD frames on.  Publishing None ends
+/// the stream and hands the strip back to Companion.
+pub fn channel() -> (
+    watch::Sender<Option<LcdFrame>>,
+    watch::Receiver<Option<LcdFrame>>,
+) {
+    watch::channel(None)
+}
+
+/// Wraps a companion receiver, adding the frames of an LCD video stream to
+/// the actions it receives.
+///
+/// The wrapped receiver must be cancel safe, as a frame becoming ready
+/// interrupts waiting on it.
+pub struct LcdVideo<R> {
+    inner: R,
+    frames: watch::Receiver<Option<LcdFrame>>,
+    size: (u16, u16),
+    frame_interval: Duration,
+    next_frame: Instant,
+    last_frame: Option<LcdFrame>,
+    encode: Option<EncodeLcd>,
+}
+
+impl<R> LcdVideo<R>
+where
+    R: traits::companion::Receiver + Send,
+{
+    /// Wrap a companion receiver.  size is the size of the LCD strip of the
+    /// device and fps the maximum number of frames per second streamed to it.
+    pub fn new(
+        inner: R,
+        frames: watch::Receiver<Option<LcdFrame>>,
+        size: (u16, u16),
+        fps: f32,
+    ) -> Self {
+        Self {
+            inner,
+            frames,
+            size,
+            frame_interval: Duration::from_secs_f32(1.0 / fps.max(1.0)),
+            next_frame: Instant::now(),
+            last_frame: None,
+            encode: None,
+        }
+    }
+
+    /// Encode the changed columns for the LCD strip of the device with
+    /// encode, rather than sending them as packed RGB.
+    pub fn with_encoder(mut self, encode: EncodeLcd) -> Self {
+        self.encode = Some(encode);
+        self
+    }
+
+    fn streaming(&self) -> bool {
+        self.frames.borrow().is_some()
+    }
+
+    /// Turn the latest published frame into an update for the device, if
+    /// anything changed.
+    fn take_frame(&mut self) -> Option<DeviceActions> {
+        let frame = self.frames.borrow_and_update().clone();
+        let Some(frame) = frame else {
+            debug!("LCD video stream ended");
+            self.last_frame = None;
+            return None;
+        };
+        if (frame.width, frame.height) != self.size {
+            warn!(
+                "Ignoring {}x{} LCD frame, the strip is {}x{}",
+                frame.width, frame.height, self.size.0, self.size.1
+            );
+            return None;
+        }
+
+        let (start, end) = frame.dirty_columns(self.last_frame.as_ref())?;
+        self.next_frame = Instant::now() + self.frame_interval;
+        let image = frame.crop(start, end);
+        let image = match &self.encode {
+            Some(encode) => match encode(end - start, frame.height, image) {
+                Ok(image) => image,
+                Err(e) => {
+                    warn!("Couldn't encode LCD frame: {:#}", e);
+                    return None;
+                }
+            },
+            None => image,
+        };
+        let action = DeviceActions::SetLCDImage(SetLCDImage {
+            x_offset: start,
+            x_size: end - start,
+            y_size: frame.height,
+            image,
+        });
+        self.last_frame = Some(frame);
+        Some(action)
+    }
+}
+
+#[async_trait]
+impl<R> traits::companion::Receiver for LcdVideo<R>
+where
+    R: traits::companion::Receiver + Send,
+{
+    async fn receive(&mut self) -> Result<DeviceActions> {
+        loop {
+            tokio::select! {
+                action = self.inner.receive() => {
+                    let action = action?;
+                    if matches!(action, DeviceActions::SetLCDImage(_)) && self.streaming() {
+                        debug!("Ignoring LCD image from Companion while streaming video");
+                        continue;
+                    }
+                    return Ok(action);
+                }
+                _ = frame_ready(&mut self.frames, self.next_frame) => {
+                    if let Some(action) = self.take_frame() {
+                        return Ok(action);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Wait until a new frame may be sent and one has been published.  Cancel
+/// safe: the frame stays published until it is taken.
+async fn frame_ready(frames: &mut watch::Receiver<Option<LcdFrame>>, next_frame: Instant) {
+    tokio::time::sleep_until(next_frame).await;
+    if frames.changed().await.is_err() {
+        std::future::pending::<()>().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(pixels: &[u8]) -> LcdFrame {
+        // 4x2 frame, each pixel gray
+        let rgb = pixels.iter().flat_map(|p| [*p, *p, *p]).collect();
+        LcdFrame::new(4, 2, rgb).unwrap()
+    }
+
+    #[test]
+    fn test_frame_size_checked() {
+        assert!(LcdFrame::new(4, 2, vec![0; 24]).is_ok());
+        assert!(LcdFrame::new(4, 2, vec![0; 23]).is_err());
+    }
+
+    #[test]
+    fn test_dirty_columns() {
+        let a = frame(&[0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(a.dirty_columns(None), Some((0, 4)));
+        assert_eq!(a.dirty_columns(Some(&a)), None);
+
+        // Changes in different rows are merged into one span of columns
+        let b = frame(&[0, 1, 0, 0, 0, 0, 1, 0]);
+        assert_eq!(b.dirty_columns(Some(&a)), Some((1, 3)));
+        assert_eq!(b.crop(1, 3), vec![1, 1, 1, 0, 0, 0, 0, 0, 0, 1, 1, 1]);
+    }
+
+    struct Quiet;
+
+    #[async_trait]
+    impl traits::companion::Receiver for Quiet {
+        async fn receive(&mut self) -> Result<DeviceActions> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_changed_columns_encoded() {
+        use traits::companion::Receiver as _;
+
+        let (frames, frames_receiver) = channel();
+        // Encodes the columns as their sizes followed by their pixels
+        let encode: EncodeLcd =
+            Arc::new(|width, height, rgb| Ok([vec![width as u8, height as u8], rgb].concat()));
+        let mut video = LcdVideo::new(Quiet, frames_receiver, (4, 2), 1000.0).with_encoder(encode);
+
+        frames.send_replace(Some(frame(&[0, 0, 0, 0, 0, 0, 0, 0])));
+        video.receive().await.unwrap();
+        frames.send_replace(Some(frame(&[0, 1, 0, 0, 0, 0, 1, 0])));
+        match video.receive().await.unwrap() {
+            DeviceActions::SetLCDImage(image) => {
+                assert_eq!((image.x_offset, image.x_size, image.y_size), (1, 2, 2));
+                assert_eq!(image.image, [2, 2, 1, 1, 1, 0, 0, 0, 0, 0, 0, 1, 1, 1]);
+            }
+            action => panic!("Unexpected action {action:?}"),
+        }
+    }
+}
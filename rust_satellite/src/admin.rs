@@ -0,0 +1,111 @@
+//! Line oriented admin socket for injecting input.
+//!
+//! Takes the input commands of the admin socket of the gateway, so scripts
+//! are replayed into decks attached here with `satellite-play` just as into
+//! leaves of a gateway.  Every command is answered with a single line
+//! starting with `OK` or `ERR`.
+//!
+//! ```text
+//! PRESS <device_id> <key>
+//! RELEASE <device_id> <key>
+//! TWIST <device_id> <encoder> <delta>
+//! ```
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tracing::{debug, info};
+use traits::{anyhow, device::Command, Result};
+
+/// Where input is injected into each deck served, by device id.
+#[derive(Clone, Default)]
+pub struct Injectors(Arc<Mutex<BTreeMap<String, mpsc::Sender<Command>>>>);
+
+impl Injectors {
+    /// Take input for device_id, replacing whoever took it before.
+    pub fn register(&self, device_id: &str) -> mpsc::Receiver<Command> {
+        let (injected, receiver) = pumps::inject::channel();
+        self.0
+            .lock()
+            .unwrap()
+            .insert(device_id.to_string(), injected);
+        receiver
+    }
+
+    /// Inject a command into the deck of device_id.
+    pub fn inject(&self, device_id: &str, command: Command) -> Result<()> {
+        let devices = self.0.lock().unwrap();
+        let injected = devices
+            .get(device_id)
+            .ok_or_else(|| anyhow::anyhow!("Device {device_id} is not served"))?;
+        injected
+            .try_send(command)
+            .map_err(|e| anyhow::anyhow!("Couldn't inject input: {e}"))
+    }
+}
+
+/// Accept admin connections forever.
+pub async fn serve(listener: TcpListener, injectors: Injectors) -> Result<()> {
+    info!("Admin socket listening on {:?}", listener.local_addr()?);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let injectors = injectors.clone();
+        tokio::spawn(async move {
+            let res = handle_connection(stream, &injectors).await;
+            debug!("Admin connection from {:?} closed: {:?}", peer, res);
+        });
+    }
+}
+
+async fn handle_connection(stream: TcpStream, injectors: &Injectors) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let response = match handle_command(line, injectors) {
+            Ok(response) => format!("OK {response}\n"),
+            Err(e) => format!("ERR {e}\n"),
+        };
+        writer.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Execute a single admin command, returning the text of the response.
+fn handle_command(line: &str, injectors: &Injectors) -> Result<String> {
+    let command = line.split_whitespace().next().unwrap_or_default();
+    match command.to_ascii_uppercase().as_str() {
+        "PRESS" | "RELEASE" | "TWIST" => {
+            let (device_id, input) = pumps::inject::parse_input(line)?;
+            injectors.inject(&device_id, input)?;
+            Ok(String::from("{}"))
+        }
+        _ => anyhow::bail!("Unknown command {command}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traits::device::ButtonChange;
+
+    #[test]
+    fn test_input_injected_into_its_deck() {
+        let injectors = Injectors::default();
+        let mut deck = injectors.register("deck");
+
+        assert_eq!(handle_command("PRESS deck 4", &injectors).unwrap(), "{}");
+        assert!(matches!(
+            deck.try_recv().unwrap(),
+            Command::ButtonChange(ButtonChange { buttons }) if buttons == [(4, true)]
+        ));
+        assert!(handle_command("PRESS other 4", &injectors).is_err());
+        assert!(handle_command("KICK deck", &injectors).is_err());
+    }
+}
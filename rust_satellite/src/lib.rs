@@ -10,17 +10,428 @@
 
 pub use traits::Result;
 use clap::Parser;
+use companion::sender::{FlushPolicy, SenderConfig};
+use elgato_streamdeck::info::Kind;
+use pumps::low_impact;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use streamdeck::bindings::Bindings;
+use streamdeck::feedback::PressFeedback;
+use streamdeck::gestures::KeyGestures;
+use streamdeck::idle::{IdleAction, IdlePolicy};
+use streamdeck::selector::DeviceSelector;
+use traits::device::BrightnessPolicy;
+
+pub mod admin;
 
 /// Command line argument for the satellite program
 #[derive(Parser)]
+#[command(args_override_self = true)]
 pub struct Cli {
-    /// hostname of the companion app
+    /// TOML file of options, keyed by their long names, for the command
+    /// line to override
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// hostname of the companion app.  Found over mDNS if built with
+    /// discovery and not provided.
     #[arg(long)]
-    pub companion_host: String,
+    #[cfg_attr(not(feature = "discovery"), arg(required_unless_present_any = ["probe", "capture", "companions", "companion_pipe"]))]
+    #[cfg_attr(feature = "discovery", arg(requires = "companion_port"))]
+    pub companion_host: Option<String>,
     /// port number of the companion app (usually 16622)
     #[arg(short, long)]
-    pub companion_port: u16,
+    #[cfg_attr(not(feature = "discovery"), arg(required_unless_present_any = ["probe", "capture", "companions", "companion_pipe"]))]
+    #[cfg_attr(feature = "discovery", arg(requires = "companion_host"))]
+    pub companion_port: Option<u16>,
+    /// Companion to connect decks to, as `host:port`, or as
+    /// `host:port=deck,...` to connect only the decks with those serials or
+    /// device ids.  Given more than once, each deck attached runs to the
+    /// first Companion that takes it, independently of the other decks.
+    /// Decks no Companion takes are left alone.
+    #[arg(
+        long = "companion",
+        value_name = "HOST:PORT[=DECKS]",
+        conflicts_with_all = ["companion_host", "companion_port"]
+    )]
+    pub companions: Vec<CompanionTarget>,
+    /// Connect to a Companion on this host through the named pipe served by
+    /// --pipe-proxy, such as `\\.\pipe\companion-satellite`, rather than
+    /// over TCP.  Windows only.
+    #[arg(
+        long,
+        value_name = "PIPE",
+        conflicts_with_all = ["companion_host", "companion_port", "companions"]
+    )]
+    pub companion_pipe: Option<String>,
+    /// Serve this named pipe for satellites given --companion-pipe, passing
+    /// each connection on to the Companion at --companion-host and
+    /// --companion-port, instead of serving any decks.  Windows only.
+    #[arg(
+        long,
+        value_name = "PIPE",
+        requires_all = ["companion_host", "companion_port"]
+    )]
+    pub pipe_proxy: Option<String>,
+    /// Print a report of the attached devices, to attach to bug reports,
+    /// and exit
+    #[arg(long)]
+    pub probe: bool,
+    /// Print every input report of the first deck as JSON lines, with the
+    /// event it decodes into, until ctrl-c, to map new hardware
+    #[arg(long)]
+    pub capture: bool,
+    /// Capture the HID device with this vendor and product id, as
+    /// `vid:pid` in hex, rather than the first deck
+    #[arg(long, requires = "capture", value_parser = parse_hid_id)]
+    pub capture_hid: Option<(u16, u16)>,
+    /// Write a draft mapping of the keys and encoders used while capturing
+    /// to this file
+    #[arg(long, requires = "capture")]
+    pub capture_mapping: Option<PathBuf>,
     /// Device id to open
     #[arg(short, long)]
     pub device_id: Option<String>,
+    /// TOML file binding decks, by serial, to device ids, kinds and
+    /// orientations.  Checked against the attached decks before connecting
+    /// to Companion, and the first bound deck found is opened.
+    #[arg(long)]
+    pub decks: Option<PathBuf>,
+    /// Only serve decks of this kind, such as "Plus" or "Mk2"
+    #[arg(long, value_parser = streamdeck::selector::parse_kind)]
+    pub deck_kind: Option<Kind>,
+    /// Only serve decks whose serial matches this glob, such as "CL31*"
+    #[arg(long)]
+    pub deck_serial: Option<String>,
+    /// Only serve the deck plugged into this USB path, as listed by
+    /// --probe, to tell apart decks whose serials are alike
+    #[arg(long)]
+    pub deck_usb_path: Option<String>,
+    /// Milliseconds encoder twists are summed for before being sent
+    #[arg(long)]
+    #[clap(default_value = "30")]
+    pub twist_window_ms: u64,
+    /// Milliseconds between PINGs to Companion
+    #[arg(long)]
+    #[clap(default_value = "10")]
+    pub ping_interval_ms: u64,
+    /// Fail on any line from Companion that strays from the grammar of the
+    /// satellite API rather than passing over commands it doesn't know, for
+    /// testing against new builds of Companion
+    #[arg(long)]
+    pub strict_companion: bool,
+    /// Hold input for up to this many milliseconds so it is written to
+    /// Companion in batches.  Written immediately if not provided.
+    #[arg(long)]
+    pub input_batch_ms: Option<u64>,
+    /// Directory to keep converted images in across restarts.  Only kept in
+    /// memory if not provided.
+    #[arg(long)]
+    pub image_cache_dir: Option<PathBuf>,
+    /// Exponent of the curve brightness goes through, above 1 dimming the
+    /// lower values most
+    #[arg(long)]
+    #[clap(default_value = "1.0")]
+    pub brightness_gamma: f32,
+    /// Added to the brightness after the curve
+    #[arg(long, allow_hyphen_values = true)]
+    #[clap(default_value = "0")]
+    pub brightness_offset: i8,
+    /// Lowest brightness the deck is set to
+    #[arg(long)]
+    #[clap(default_value = "0")]
+    pub brightness_min: u8,
+    /// Highest brightness the deck is set to, for decks that are blinding at
+    /// full brightness
+    #[arg(long)]
+    #[clap(default_value = "100")]
+    pub brightness_max: u8,
+    /// Seconds the deck goes without input before it is dimmed to
+    /// --idle-brightness, or blanked with --idle-blank.  The next input
+    /// restores it.  Never dimmed if not provided.
+    #[arg(long)]
+    pub idle_after_s: Option<u64>,
+    /// Brightness an idle deck is dimmed to
+    #[arg(long)]
+    #[clap(default_value = "10")]
+    pub idle_brightness: u8,
+    /// Blank the keys of an idle deck rather than dimming it
+    #[arg(long, requires = "idle_after_s")]
+    pub idle_blank: bool,
+    /// Report a long press of a key as another key, as `KEY=VIRTUAL`, for
+    /// Companion to bind actions to.  Given once for each key.  Keys are
+    /// numbered from 0 as the deck numbers them.
+    #[arg(long, value_name = "KEY=VIRTUAL", value_parser = parse_key_pair)]
+    pub long_press: Vec<(u8, u8)>,
+    /// Report a key pressed twice in quick succession as another key, as
+    /// `KEY=VIRTUAL`.  Given once for each key.
+    #[arg(long, value_name = "KEY=VIRTUAL", value_parser = parse_key_pair)]
+    pub double_press: Vec<(u8, u8)>,
+    /// Milliseconds a key given --long-press is held for a long press
+    #[arg(long)]
+    #[clap(default_value = "500")]
+    pub long_press_threshold_ms: u64,
+    /// Milliseconds after being released that a key given --double-press
+    /// is pressed again within for a double press
+    #[arg(long)]
+    #[clap(default_value = "300")]
+    pub double_press_window_ms: u64,
+    /// Darken or invert the image of a key as soon as it is pressed, for
+    /// links slow enough that waiting for Companion to show it pressed makes
+    /// the key feel dead
+    #[arg(long, value_name = "darken|invert")]
+    pub press_feedback: Option<PressFeedback>,
+    /// Take as little CPU as the deck can run on, for a host busy with
+    /// something that matters more, such as streaming a show.  Images are
+    /// converted one at a time and written paced, and the deck is polled and
+    /// Companion pinged less often, at the cost of latency.
+    #[arg(long)]
+    pub low_impact: bool,
+    /// Record a transcript of the traffic of the deck to this file, as JSON
+    /// lines with images reduced to their size and hash, to attach to bug
+    /// reports
+    #[arg(long)]
+    pub transcript: Option<PathBuf>,
+    /// Port to accept input to inject into the decks on, as the admin socket
+    /// of the gateway does, for replaying scripts with satellite-play.
+    /// Disabled if not provided.
+    #[arg(long)]
+    pub admin_port: Option<u16>,
+    /// Address to listen on for admin connections
+    #[arg(long)]
+    #[clap(default_value = "127.0.0.1")]
+    pub admin_address: String,
+    /// Write logs as JSON lines, for log collectors to ingest, rather than
+    /// as text
+    #[arg(long)]
+    pub log_json: bool,
+}
+
+impl Cli {
+    /// How the Companion sender should ping and flush.
+    pub fn sender_config(&self) -> SenderConfig {
+        let mut ping_interval = Duration::from_millis(self.ping_interval_ms);
+        if self.low_impact {
+            ping_interval = ping_interval.max(low_impact::PING_INTERVAL);
+        }
+        SenderConfig {
+            ping_interval,
+            flush_policy: match self.input_batch_ms {
+                Some(window) => FlushPolicy::Batched(Duration::from_millis(window)),
+                None => FlushPolicy::Immediate,
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Images of the deck to convert at once, or None for one per core.
+    pub fn image_workers(&self) -> Option<NonZeroUsize> {
+        self.low_impact.then_some(low_impact::IMAGE_WORKERS)
+    }
+
+    /// How often the deck is polled for input.
+    pub fn poll_interval(&self) -> Duration {
+        if self.low_impact {
+            low_impact::POLL_INTERVAL
+        } else {
+            streamdeck::DEFAULT_POLL_INTERVAL
+        }
+    }
+
+    /// Least time between the images written to the deck, zero if they
+    /// aren't paced.
+    pub fn write_interval(&self) -> Duration {
+        if self.low_impact {
+            low_impact::WRITE_INTERVAL
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    /// When the deck is idle and what is done to it then, if ever.
+    pub fn idle_policy(&self) -> Option<IdlePolicy> {
+        let after = Duration::from_secs(self.idle_after_s?);
+        let action = if self.idle_blank {
+            IdleAction::Blank
+        } else {
+            IdleAction::Dim(self.idle_brightness)
+        };
+        Some(IdlePolicy { after, action })
+    }
+
+    /// Which keys have long and double presses, if any do.
+    pub fn key_gestures(&self) -> Option<KeyGestures> {
+        if self.long_press.is_empty() && self.double_press.is_empty() {
+            return None;
+        }
+        Some(KeyGestures {
+            long_press: Duration::from_millis(self.long_press_threshold_ms),
+            double_press: Duration::from_millis(self.double_press_window_ms),
+            long_press_keys: self.long_press.iter().copied().collect(),
+            double_press_keys: self.double_press.iter().copied().collect(),
+        })
+    }
+
+    /// Which of the attached decks are served.
+    pub fn deck_selector(&self) -> DeviceSelector {
+        DeviceSelector {
+            kind: self.deck_kind,
+            serial_glob: self.deck_serial.clone(),
+            usb_path: self.deck_usb_path.clone(),
+        }
+    }
+
+    /// How the brightness Companion asks for is mapped onto the deck.
+    pub fn brightness_policy(&self) -> BrightnessPolicy {
+        BrightnessPolicy {
+            gamma: self.brightness_gamma,
+            offset: self.brightness_offset,
+            min: self.brightness_min,
+            max: self.brightness_max,
+        }
+    }
+}
+
+/// A Companion decks are connected to, and which decks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompanionTarget {
+    /// Host of the Companion
+    pub host: String,
+    /// Port of the satellite API of the Companion
+    pub port: u16,
+    /// Serials or device ids of the decks it takes, or none for every deck
+    /// no other Companion takes
+    pub decks: Vec<String>,
+}
+
+impl CompanionTarget {
+    /// Whether the deck with serial, known to Companion as device_id, is
+    /// named by this Companion.
+    fn names(&self, serial: &str, device_id: &str) -> bool {
+        self.decks.iter().any(|deck| deck == serial || deck == device_id)
+    }
+}
+
+impl FromStr for CompanionTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (address, decks) = s.split_once('=').unwrap_or((s, ""));
+        let (host, port) = address
+            .rsplit_once(':')
+            .ok_or_else(|| format!("Expected host:port, got {address}"))?;
+        Ok(Self {
+            host: host.to_string(),
+            port: port.parse().map_err(|e| format!("Bad port {port}: {e}"))?,
+            decks: decks
+                .split(',')
+                .filter(|deck| !deck.is_empty())
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+}
+
+/// The Companion each attached deck runs to, in the order the decks are
+/// attached.  A deck runs to the first Companion naming it, or else to the
+/// first that names no decks.  Decks neither takes are left out.
+pub fn assign<'a>(
+    targets: &'a [CompanionTarget],
+    attached: &'a [(Kind, String)],
+    bindings: &Bindings,
+) -> Vec<(&'a CompanionTarget, &'a (Kind, String))> {
+    attached
+        .iter()
+        .filter_map(|deck| {
+            let device_id = bindings.device_id(&deck.1);
+            let target = targets
+                .iter()
+                .find(|target| target.names(&deck.1, device_id))
+                .or_else(|| targets.iter().find(|target| target.decks.is_empty()))?;
+            Some((target, deck))
+        })
+        .collect()
+}
+
+/// Parse a `key=virtual` pair of key indices.
+fn parse_key_pair(s: &str) -> std::result::Result<(u8, u8), String> {
+    let (key, virtual_key) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Expected key=virtual, got {s}"))?;
+    let index = |index: &str| index.parse().map_err(|e| format!("Bad key {index}: {e}"));
+    Ok((index(key)?, index(virtual_key)?))
+}
+
+/// Parse a `vid:pid` pair of hex USB ids.
+fn parse_hid_id(s: &str) -> std::result::Result<(u16, u16), String> {
+    let (vid, pid) = s
+        .split_once(':')
+        .ok_or_else(|| format!("Expected vid:pid, got {s}"))?;
+    let id = |id: &str| u16::from_str_radix(id, 16).map_err(|e| format!("Bad id {id}: {e}"));
+    Ok((id(vid)?, id(pid)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decks_assigned_to_companions() {
+        let targets: Vec<CompanionTarget> = ["studio:16622=desk-left,CL2", "[::1]:16622"]
+            .iter()
+            .map(|target| target.parse().unwrap())
+            .collect();
+        assert_eq!(targets[0].host, "studio");
+        assert_eq!(targets[0].decks, ["desk-left", "CL2"]);
+        assert_eq!((targets[1].host.as_str(), targets[1].port), ("[::1]", 16622));
+        assert!("studio".parse::<CompanionTarget>().is_err());
+
+        let bindings = Bindings::parse("[CL1]\ndevice_id = \"desk-left\"\n").unwrap();
+        let attached = [
+            (Kind::Plus, String::from("CL1")),
+            (Kind::Mk2, String::from("CL2")),
+            (Kind::Mini, String::from("CL3")),
+        ];
+        let assigned: Vec<_> = assign(&targets, &attached, &bindings)
+            .into_iter()
+            .map(|(target, (_, serial))| (target.host.as_str(), serial.as_str()))
+            .collect();
+        assert_eq!(
+            assigned,
+            [("studio", "CL1"), ("studio", "CL2"), ("[::1]", "CL3")]
+        );
+        // Without a Companion taking the rest, they are left alone
+        assert_eq!(assign(&targets[..1], &attached, &bindings).len(), 2);
+    }
+    #[test]
+    fn test_pipe_taken_instead_of_companion_address() {
+        let pipe = r"\\.\pipe\companion-satellite";
+        let args = Cli::try_parse_from(["rust_satellite", "--companion-pipe", pipe]).unwrap();
+        assert_eq!(args.companion_pipe.as_deref(), Some(pipe));
+        assert!(Cli::try_parse_from([
+            "rust_satellite",
+            "--companion-pipe",
+            pipe,
+            "--companion-host",
+            "studio",
+            "--companion-port",
+            "16622",
+        ])
+        .is_err());
+
+        // The proxy passes the pipe on to a Companion it is given
+        assert!(Cli::try_parse_from(["rust_satellite", "--pipe-proxy", pipe]).is_err());
+        let args = Cli::try_parse_from([
+            "rust_satellite",
+            "--pipe-proxy",
+            pipe,
+            "--companion-host",
+            "studio",
+            "--companion-port",
+            "16622",
+        ])
+        .unwrap();
+        assert_eq!(args.pipe_proxy.as_deref(), Some(pipe));
+    }
 }
@@ -1,42 +1,345 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
 use clap::Parser;
+use companion::{cache::ImageCache, disk_cache::DiskCache};
+use elgato_streamdeck::info::Kind;
+use pumps::inject::InjectingReceiver;
+use pumps::low_impact::PacedSender;
+use pumps::settings::{KeyTransformSender, SettingsReceiver};
+use pumps::shutdown::ShutdownHandle;
+use pumps::transcript::{Transcript, TranscriptReceiver, TranscriptSender};
+use rust_satellite::admin::Injectors;
 use rust_satellite::{Cli, Result};
+use streamdeck::bindings::{Binding, Bindings};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::watch;
+use traits::device::DeviceSettings;
 
-use tracing::info;
-use traits::device::Receiver;
+use tracing::{info, warn};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-
-    let args = Cli::parse();
-
+    let args = Cli::parse_from(traits::config::args("rust_satellite", std::env::args_os())?);
+    if args.log_json {
+        tracing_subscriber::fmt().json().init();
+    } else {
+        tracing_subscriber::fmt::init();
+    }
+    if args.probe {
+        let report = streamdeck::probe::probe().await?;
+        print!("{report}");
+        traits::anyhow::ensure!(report.healthy(), "The probe found problems");
+        return Ok(());
+    }
+    if args.capture {
+        return capture(&args).await;
+    }
+    if let Some(pipe) = &args.pipe_proxy {
+        return pipe_proxy(pipe, &args).await;
+    }
     info!("Starting native satellite application");
 
-    let mut streamdeck = streamdeck::StreamDeck::open_first().await?;
-    let first_msg = streamdeck.0.receive().await?;
-    let first_msg = match first_msg {
-        traits::device::Command::Config(c) => traits::device::RemoteConfig {
-            pid: c.pid.try_into()?,
-            device_id: c.device_id,
-        },
-        _ => anyhow::bail!("Expected config msg to be first"),
+    let bindings = match &args.decks {
+        Some(path) => Bindings::load(path)?,
+        None => Bindings::default(),
     };
+    let attached = streamdeck::list_attached()?;
+    if args.decks.is_some() {
+        check_bindings(&bindings, &attached)?;
+    }
+    // Bindings are checked against every deck, but only those selected are
+    // served
+    let attached = streamdeck::list_selected(&args.deck_selector())?;
+    // Shared by every deck and connection, so reconnecting doesn't convert
+    // everything again
+    let mut image_cache = ImageCache::default();
+    if let Some(dir) = &args.image_cache_dir {
+        image_cache = image_cache.with_disk(DiskCache::new(dir)?);
+    }
+    let transcript = match &args.transcript {
+        Some(path) => Transcript::create(path)?,
+        None => Transcript::default(),
+    };
+    let shutdown = ShutdownHandle::new().on_ctrl_c();
+    let injectors = Injectors::default();
+    if let Some(port) = args.admin_port {
+        let listener = tokio::net::TcpListener::bind((args.admin_address.as_str(), port)).await?;
+        tokio::spawn(rust_satellite::admin::serve(listener, injectors.clone()));
+    }
+    let args = Arc::new(args);
 
-    pumps::create_and_run(
-        move || {
-            let streamdeck = streamdeck.clone();
-            async move { Ok(streamdeck) }
-        },
-        move |_| {
-            let hostport = (args.companion_host.clone(), args.companion_port);
-            let first_msg = first_msg.clone();
-            async {
-                info!("Connecting to companion: {}:{}", hostport.0, hostport.1);
-                companion::connect(hostport, first_msg).await
+    if args.companions.is_empty() {
+        let companion_address = args.companion_host.clone().zip(args.companion_port);
+        // The first bound deck, or the first deck if none are bound
+        let deck = attached
+            .iter()
+            .find(|(_, serial)| bindings.get(serial).is_some())
+            .or(attached.first())
+            .ok_or_else(|| traits::anyhow::anyhow!("No decks found"))?;
+        let binding = bindings.get(&deck.1).cloned().unwrap_or_default();
+        let shared = (image_cache, transcript, shutdown, injectors);
+        return serve_deck(args, deck.clone(), binding, companion_address, shared).await;
+    }
+
+    // Every deck runs to its own Companion, and fails on its own
+    let assigned = rust_satellite::assign(&args.companions, &attached, &bindings);
+    traits::anyhow::ensure!(!assigned.is_empty(), "No decks found for any Companion");
+    let mut decks = tokio::task::JoinSet::new();
+    for (target, deck) in assigned {
+        info!(
+            "Running {} to Companion at {}:{}",
+            deck.1, target.host, target.port
+        );
+        let binding = bindings.get(&deck.1).cloned().unwrap_or_default();
+        let companion_address = Some((target.host.clone(), target.port));
+        let shared = (
+            image_cache.clone(),
+            transcript.clone(),
+            shutdown.clone(),
+            injectors.clone(),
+        );
+        let serial = deck.1.clone();
+        let deck = serve_deck(
+            args.clone(),
+            deck.clone(),
+            binding,
+            companion_address,
+            shared,
+        );
+        decks.spawn(async move { (serial, deck.await) });
+    }
+    let mut failed = None;
+    while let Some(joined) = decks.join_next().await {
+        let (serial, res) = joined?;
+        if let Err(e) = res {
+            warn!("Deck {} stopped: {}", serial, e);
+            failed.get_or_insert(e);
+        }
+    }
+    failed.map_or(Ok(()), Err)
+}
+
+/// Run the deck to Companion at companion_address, or the one found over
+/// mDNS, until it fails or the satellite shuts down.  Everything logged for
+/// the deck carries its serial and kind.
+#[tracing::instrument(name = "deck", skip_all, fields(%serial, ?kind))]
+async fn serve_deck(
+    args: Arc<Cli>,
+    (kind, serial): (Kind, String),
+    binding: Binding,
+    companion_address: Option<(String, u16)>,
+    (image_cache, transcript, shutdown, injectors): (
+        ImageCache,
+        Transcript,
+        ShutdownHandle,
+        Injectors,
+    ),
+) -> Result<()> {
+    let (sender, mut receiver) = streamdeck::StreamDeck::open_serial(&serial).await?;
+    // Input is injected by the device id Companion knows the deck by
+    let injected = injectors.register(binding.device_id.as_deref().unwrap_or(&serial));
+    if let Some(device_id) = binding.device_id {
+        receiver = receiver.with_device_id(device_id);
+    }
+    if let Some(policy) = args.idle_policy() {
+        receiver = receiver.with_idle_policy(policy);
+    }
+    if let Some(gestures) = args.key_gestures() {
+        receiver = receiver.with_key_gestures(gestures);
+    }
+    if let Some(feedback) = args.press_feedback {
+        receiver = receiver.with_press_feedback(feedback);
+    }
+    // Fixed for as long as the satellite runs
+    let (_, settings) = watch::channel(DeviceSettings {
+        orientation: binding.orientation,
+        ..Default::default()
+    });
+    let twist_window = std::time::Duration::from_millis(args.twist_window_ms);
+    // The deck says which it is in the config it sends first
+    let transcript = transcript.device(None);
+    let streamdeck = (
+        PacedSender::new(
+            TranscriptSender::new(
+                KeyTransformSender::new(
+                    sender.with_brightness_policy(args.brightness_policy()),
+                    settings.clone(),
+                    kind.row_count(),
+                    kind.column_count(),
+                ),
+                transcript.clone(),
+            ),
+            args.write_interval(),
+        ),
+        InjectingReceiver::new(
+            TranscriptReceiver::new(
+                SettingsReceiver::new(
+                    receiver
+                        .with_twist_window(twist_window)
+                        .with_poll_interval(args.poll_interval()),
+                    settings.clone(),
+                    kind.row_count(),
+                    kind.column_count(),
+                ),
+                transcript,
+            ),
+            injected,
+        ),
+    );
+
+    let sender_config = args.sender_config();
+    let image_workers = args.image_workers();
+    let strict_companion = args.strict_companion;
+    let pipe = args.companion_pipe.clone();
+    // The deck stays open while Companion comes and goes, and is blanked on
+    // ctrl-c
+    pumps::run_with_reconnect_until(
+        streamdeck,
+        move |config| {
+            let companion_address = companion_address.clone();
+            let pipe = pipe.clone();
+            let config = config.clone();
+            let image_cache = image_cache.clone();
+            let settings = settings.clone();
+            async move {
+                let (reader, writer) = match pipe {
+                    Some(pipe) => connect_pipe(&pipe).await?,
+                    None => connect_tcp(companion_address).await?,
+                };
+                let (sender, receiver) = companion::connect_over(
+                    reader,
+                    writer,
+                    config,
+                    sender_config,
+                    image_cache,
+                    Some(settings),
+                )
+                .await?;
+                let receiver = match image_workers {
+                    Some(workers) => receiver.with_parallelism(workers),
+                    None => receiver,
+                };
+                let receiver = if strict_companion {
+                    receiver.with_strict()
+                } else {
+                    receiver
+                };
+                Ok((sender, receiver))
             }
         },
+        Default::default(),
+        shutdown,
     )
-    .await?;
+    .await
+}
+
+/// The two ends of a connection to Companion, whatever it goes over.
+type Connection = (
+    Box<dyn AsyncRead + Unpin + Send>,
+    Box<dyn AsyncWrite + Unpin + Send>,
+);
+
+/// Connect to Companion at companion_address, or the one found over mDNS.
+async fn connect_tcp(companion_address: Option<(String, u16)>) -> Result<Connection> {
+    // Looked for again every time, in case Companion moved
+    let hostport = match companion_address {
+        Some(hostport) => hostport,
+        #[cfg(feature = "discovery")]
+        None => {
+            use pumps::discovery::{discover, Role, DEFAULT_TIMEOUT};
+            let addr = discover(Role::Companion, DEFAULT_TIMEOUT).await?;
+            (addr.ip().to_string(), addr.port())
+        }
+        // clap requires both unless probing
+        #[cfg(not(feature = "discovery"))]
+        None => unreachable!("companion address is required"),
+    };
+    info!("Connecting to companion: {}:{}", hostport.0, hostport.1);
+    let (reader, writer) = tokio::net::TcpStream::connect(hostport).await?.into_split();
+    Ok((Box::new(reader), Box::new(writer)))
+}
+
+/// Connect to Companion through the named pipe served for it on this host.
+#[cfg(windows)]
+async fn connect_pipe(pipe: &str) -> Result<Connection> {
+    info!("Connecting to companion through {}", pipe);
+    let (reader, writer) = companion::pipe::connect(pipe).await?;
+    Ok((Box::new(reader), Box::new(writer)))
+}
+
+#[cfg(not(windows))]
+async fn connect_pipe(_pipe: &str) -> Result<Connection> {
+    traits::anyhow::bail!("Named pipes are only supported on Windows")
+}
+
+/// Serve pipe for the satellites on this host, passing each on to the
+/// Companion given, until it fails.
+#[cfg(windows)]
+async fn pipe_proxy(pipe: &str, args: &Cli) -> Result<()> {
+    let (Some(host), Some(port)) = (&args.companion_host, args.companion_port) else {
+        unreachable!("clap requires the companion address with --pipe-proxy");
+    };
+    companion::pipe::proxy(pipe, format!("{host}:{port}")).await
+}
+
+#[cfg(not(windows))]
+async fn pipe_proxy(_pipe: &str, _args: &Cli) -> Result<()> {
+    traits::anyhow::bail!("Named pipes are only supported on Windows")
+}
 
+/// Warn about the problems of bindings with the attached decks, failing if
+/// any of them would leave a deck shown wrongly in Companion.
+fn check_bindings(bindings: &Bindings, attached: &[(Kind, String)]) -> Result<()> {
+    let (errors, warnings): (Vec<_>, Vec<_>) = bindings
+        .validate(attached)
+        .into_iter()
+        .partition(|problem| problem.is_error());
+    for problem in warnings {
+        warn!("{}", problem);
+    }
+    let errors: Vec<_> = errors
+        .iter()
+        .map(|problem| format!("  {problem}"))
+        .collect();
+    traits::anyhow::ensure!(
+        errors.is_empty(),
+        "The deck bindings don't match the attached decks:\n{}",
+        errors.join("\n")
+    );
+    Ok(())
+}
+
+/// Print the input of a device until ctrl-c, then write the mapping learned
+/// if asked to.
+async fn capture(args: &Cli) -> Result<()> {
+    let (vendor_id, product_id, serial) = match args.capture_hid {
+        Some((vendor_id, product_id)) => (vendor_id, product_id, None),
+        None => {
+            let (kind, serial) = streamdeck::list_attached()?
+                .into_iter()
+                .next()
+                .ok_or_else(|| traits::anyhow::anyhow!("No decks found"))?;
+            (kind.vendor_id(), kind.product_id(), Some(serial))
+        }
+    };
+    let running = Arc::new(AtomicBool::new(true));
+    tokio::spawn({
+        let running = running.clone();
+        async move {
+            let _ = tokio::signal::ctrl_c().await;
+            running.store(false, Ordering::Relaxed);
+        }
+    });
+    info!("Capturing {vendor_id:04x}:{product_id:04x} until ctrl-c");
+    let mapping = tokio::task::spawn_blocking(move || {
+        let mut out = std::io::stdout();
+        streamdeck::capture::capture(vendor_id, product_id, serial.as_deref(), &mut out, &running)
+    })
+    .await??;
+    if let Some(path) = &args.capture_mapping {
+        std::fs::write(path, mapping.to_toml()?)?;
+        info!("Wrote a draft mapping to {}", path.display());
+    }
     Ok(())
 }
@@ -0,0 +1,268 @@
+//! Decks bound to device ids, kinds and orientations by their serials.
+//!
+//! Bindings are read from a TOML file with a table per serial number:
+//!
+//! ```toml
+//! [CL12345]
+//! device_id = "desk-left"
+//! kind = "Plus"
+//! orientation = "Rotated180"
+//! ```
+//!
+//! Every field is optional.  A deck without a device id is known to Companion
+//! by its serial, and the kind is only checked, so a deck swapped for another
+//! of a different kind is caught before it shows the wrong layout.
+//!
+//! [Bindings::validate] compares the file with the decks that are actually
+//! attached, so mistakes in it are reported once at startup, saying what to
+//! change, rather than showing up later as a deck that never appears in
+//! Companion.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use elgato_streamdeck::info::Kind;
+use serde::Deserialize;
+use tracing::info;
+use traits::{device::Orientation, Result};
+
+/// What a deck is bound to.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Binding {
+    /// Device id the deck is known to Companion by, instead of its serial
+    pub device_id: Option<String>,
+    /// Kind the deck must be, such as "Plus" or "XlV2"
+    pub kind: Option<String>,
+    /// Physical orientation of the deck
+    #[serde(default)]
+    pub orientation: Orientation,
+}
+
+/// The binding of every deck in the file, by serial.
+#[derive(Debug, Clone, Default)]
+pub struct Bindings {
+    decks: BTreeMap<String, Binding>,
+}
+
+impl Bindings {
+    /// Load the bindings from the file at path.
+    pub fn load(path: &Path) -> Result<Self> {
+        info!("Loading deck bindings from {}", path.display());
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Read bindings from the text of a file.
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(Self {
+            decks: toml::from_str(text)?,
+        })
+    }
+
+    /// The binding of the deck with serial, if it has one.
+    pub fn get(&self, serial: &str) -> Option<&Binding> {
+        self.decks.get(serial)
+    }
+
+    /// The device id the deck with serial is known by.
+    pub fn device_id<'a>(&'a self, serial: &'a str) -> &'a str {
+        self.get(serial)
+            .and_then(|binding| binding.device_id.as_deref())
+            .unwrap_or(serial)
+    }
+
+    /// Check the bindings against the attached decks, as listed by
+    /// [list_devices](elgato_streamdeck::list_devices).  Problems are
+    /// returned in the order of the file, then of the attached decks.
+    pub fn validate(&self, attached: &[(Kind, String)]) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        for (serial, binding) in &self.decks {
+            let Some((kind, _)) = attached.iter().find(|(_, found)| found == serial) else {
+                problems.push(Problem::Missing {
+                    serial: serial.clone(),
+                });
+                continue;
+            };
+            if let Some(bound) = &binding.kind {
+                if !bound.eq_ignore_ascii_case(&kind.to_string()) {
+                    problems.push(Problem::WrongKind {
+                        serial: serial.clone(),
+                        bound: bound.clone(),
+                        found: *kind,
+                    });
+                }
+            }
+        }
+
+        // Ids are checked across the attached decks too, as a deck without
+        // a binding is known by its serial
+        let mut serials_of: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        let attached_serials = attached.iter().map(|(_, serial)| serial);
+        for serial in self.decks.keys().chain(attached_serials) {
+            let serials = serials_of.entry(self.device_id(serial)).or_default();
+            if !serials.contains(serial) {
+                serials.push(serial.clone());
+            }
+        }
+        for (device_id, serials) in serials_of {
+            if serials.len() > 1 {
+                problems.push(Problem::DuplicateDeviceId {
+                    device_id: device_id.to_string(),
+                    serials,
+                });
+            }
+        }
+
+        for (kind, serial) in attached {
+            if !self.decks.contains_key(serial) {
+                problems.push(Problem::Unbound {
+                    serial: serial.clone(),
+                    kind: *kind,
+                });
+            }
+        }
+        problems
+    }
+}
+
+/// A difference between the bindings and the attached decks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// A bound deck isn't attached
+    Missing {
+        /// Serial of the deck
+        serial: String,
+    },
+    /// A deck is bound as a different kind than it is
+    WrongKind {
+        /// Serial of the deck
+        serial: String,
+        /// The kind in the bindings
+        bound: String,
+        /// The kind of the attached deck
+        found: Kind,
+    },
+    /// Decks would be known to Companion by the same device id
+    DuplicateDeviceId {
+        /// The device id
+        device_id: String,
+        /// Serials of the decks
+        serials: Vec<String>,
+    },
+    /// An attached deck has no binding
+    Unbound {
+        /// Serial of the deck
+        serial: String,
+        /// Kind of the deck
+        kind: Kind,
+    },
+}
+
+impl Problem {
+    /// Whether the problem stops the decks being served.  Other problems are
+    /// only warned about.
+    pub fn is_error(&self) -> bool {
+        matches!(
+            self,
+            Problem::WrongKind { .. } | Problem::DuplicateDeviceId { .. }
+        )
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Missing { serial } => write!(
+                f,
+                "deck {serial} is bound but not attached; check it is plugged in, \
+                 or remove its binding"
+            ),
+            Problem::WrongKind {
+                serial,
+                bound,
+                found,
+            } => write!(
+                f,
+                "deck {serial} is bound as kind {bound} but is a {}; set its kind to \"{}\" \
+                 if it was replaced on purpose",
+                found.to_string(),
+                found.to_string()
+            ),
+            Problem::DuplicateDeviceId { device_id, serials } => write!(
+                f,
+                "decks {} would all be known as device id {device_id}; give each its own \
+                 device_id",
+                serials.join(", ")
+            ),
+            Problem::Unbound { serial, kind } => write!(
+                f,
+                "{} deck {serial} has no binding and is known by its serial",
+                kind.to_string()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_problems_found() {
+        let bindings = Bindings::parse(
+            r#"
+            [AAA]
+            device_id = "desk"
+            kind = "plus"
+            orientation = "Rotated180"
+
+            [BBB]
+            kind = "Mk2"
+
+            [CCC]
+            device_id = "desk"
+
+            [DDD]
+            device_id = "EEE"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            bindings.get("AAA").unwrap().orientation,
+            Orientation::Rotated180
+        );
+        let attached = [
+            (Kind::Plus, String::from("AAA")),
+            (Kind::Xl, String::from("BBB")),
+            (Kind::Mini, String::from("DDD")),
+            (Kind::Mini, String::from("EEE")),
+        ];
+        assert_eq!(
+            bindings.validate(&attached),
+            [
+                Problem::WrongKind {
+                    serial: "BBB".into(),
+                    bound: "Mk2".into(),
+                    found: Kind::Xl,
+                },
+                Problem::Missing {
+                    serial: "CCC".into(),
+                },
+                Problem::DuplicateDeviceId {
+                    device_id: "EEE".into(),
+                    serials: vec!["DDD".into(), "EEE".into()],
+                },
+                Problem::DuplicateDeviceId {
+                    device_id: "desk".into(),
+                    serials: vec!["AAA".into(), "CCC".into()],
+                },
+                Problem::Unbound {
+                    serial: "EEE".into(),
+                    kind: Kind::Mini,
+                },
+            ]
+        );
+        assert!(Bindings::parse("[AAA]\nkinds = \"Plus\"").is_err());
+    }
+}
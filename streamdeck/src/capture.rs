@@ -0,0 +1,243 @@
+//! Capture of the raw input of a device, for mapping new hardware.
+//!
+//! Supporting a new revision of a deck starts with finding out what it
+//! reports when each key, encoder and the touch strip is used.  [capture]
+//! reads every input report of a device and prints it as a JSON [Report],
+//! with the event the report decodes into if the device is a kind of deck
+//! already known.  What is seen is gathered into a draft [Mapping], listing
+//! keys and encoders in the order they were first used, so pressing every
+//! key left to right and top to bottom gives the order the device numbers
+//! them in.
+
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use elgato_streamdeck_local::info::{Kind, ELGATO_VENDOR_ID};
+use elgato_streamdeck_local::{HidDevice, HidError, StreamDeckInput};
+use serde::Serialize;
+use traits::Result;
+
+/// Longest input report read.
+const REPORT_LEN: usize = 1024;
+/// Milliseconds a read waits for a report before checking whether to stop.
+const READ_TIMEOUT_MS: i32 = 100;
+
+/// One input report, as printed while capturing.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Report {
+    /// Milliseconds since the capture started
+    pub at_ms: u64,
+    /// The bytes of the report in hex, report id first
+    pub raw: String,
+    /// What the report decodes into, for kinds of deck already known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<String>,
+}
+
+/// What was learned about a device while capturing, as a starting point for
+/// supporting it.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Mapping {
+    /// USB vendor id of the device
+    pub vendor_id: u16,
+    /// USB product id of the device
+    pub product_id: u16,
+    /// The kind of deck it was decoded as, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    /// Lengths of the reports seen
+    pub report_lengths: Vec<usize>,
+    /// Index of each key as reported, in the order they were first pressed
+    pub keys: Vec<u8>,
+    /// Index of each encoder as reported, in the order they were first used
+    pub encoders: Vec<u8>,
+    /// Whether the touch strip was used
+    pub touch: bool,
+}
+
+impl Mapping {
+    /// The mapping as a TOML file.
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(format!(
+            "# Draft mapping of {:04x}:{:04x}, captured by using every key and encoder in order\n{}",
+            self.vendor_id,
+            self.product_id,
+            toml::to_string(self)?
+        ))
+    }
+
+    /// Take in what an event says about the device.
+    fn learn(&mut self, input: &StreamDeckInput) {
+        match input {
+            StreamDeckInput::ButtonStateChange(keys) => {
+                first_use(&mut self.keys, keys.iter().copied())
+            }
+            StreamDeckInput::EncoderStateChange(encoders) => {
+                first_use(&mut self.encoders, encoders.iter().copied())
+            }
+            StreamDeckInput::EncoderTwist(twists) => {
+                first_use(&mut self.encoders, twists.iter().map(|twist| *twist != 0))
+            }
+            StreamDeckInput::TouchScreenPress(..)
+            | StreamDeckInput::TouchScreenLongPress(..)
+            | StreamDeckInput::TouchScreenSwipe(..) => self.touch = true,
+            StreamDeckInput::NoData => {}
+        }
+    }
+}
+
+/// Add the indices used to seen, unless they already are.
+fn first_use(seen: &mut Vec<u8>, used: impl Iterator<Item = bool>) {
+    for (index, _) in used.enumerate().filter(|(_, used)| *used) {
+        if !seen.contains(&(index as u8)) {
+            seen.push(index as u8);
+        }
+    }
+}
+
+/// Turns the input reports of a device into [Report]s, learning its
+/// [Mapping] as it goes.
+pub struct Capture {
+    kind: Option<Kind>,
+    started: Instant,
+    mapping: Mapping,
+}
+
+impl Capture {
+    /// Capture the device with vendor_id and product_id, decoding its reports
+    /// if it is a kind of deck already known.
+    pub fn new(vendor_id: u16, product_id: u16) -> Self {
+        let kind = match vendor_id {
+            ELGATO_VENDOR_ID => Kind::from_pid(product_id),
+            _ => None,
+        };
+        Self {
+            kind,
+            started: Instant::now(),
+            mapping: Mapping {
+                vendor_id,
+                product_id,
+                kind: kind.map(|kind| kind.to_string()),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// The report for the bytes of an input report.
+    pub fn report(&mut self, raw: &[u8]) -> Report {
+        if let Err(at) = self.mapping.report_lengths.binary_search(&raw.len()) {
+            self.mapping.report_lengths.insert(at, raw.len());
+        }
+        let event = self.kind.map(|kind| {
+            // Decoded by the driver itself, reading the report back
+            let deck = elgato_streamdeck_local::StreamDeck::new(Replay(raw), kind);
+            match deck.read_input_poll(true) {
+                Ok(input) => {
+                    self.mapping.learn(&input);
+                    format!("{input:?}")
+                }
+                Err(e) => format!("undecodable: {e}"),
+            }
+        });
+        Report {
+            at_ms: self.started.elapsed().as_millis() as u64,
+            raw: raw
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<Vec<_>>()
+                .join(" "),
+            event,
+        }
+    }
+
+    /// What was learned so far.
+    pub fn mapping(&self) -> &Mapping {
+        &self.mapping
+    }
+}
+
+/// A device whose only input is one report already read.
+struct Replay<'a>(&'a [u8]);
+
+impl HidDevice for Replay<'_> {
+    fn read_timeout(&self, buf: &mut [u8], _timeout: i32) -> Result<(), HidError> {
+        self.read(buf)
+    }
+    fn read(&self, buf: &mut [u8]) -> Result<(), HidError> {
+        // Reports shorter than the driver reads are padded, as hidapi does
+        let len = buf.len().min(self.0.len());
+        buf[..len].copy_from_slice(&self.0[..len]);
+        buf[len..].fill(0);
+        Ok(())
+    }
+    fn write(&self, _payload: &[u8]) -> Result<usize, HidError> {
+        Err(HidError {})
+    }
+    fn get_feature_report(&self, _buf: &mut [u8]) -> Result<(), HidError> {
+        Err(HidError {})
+    }
+    fn send_feature_report(&self, _payload: &[u8]) -> Result<(), HidError> {
+        Err(HidError {})
+    }
+}
+
+/// Print every input report of the device with vendor_id and product_id, and
+/// serial if given, to out as JSON lines, until running is cleared.  Blocks
+/// the thread it is called on, returning what was learned about the device.
+pub fn capture(
+    vendor_id: u16,
+    product_id: u16,
+    serial: Option<&str>,
+    out: &mut impl Write,
+    running: &AtomicBool,
+) -> Result<Mapping> {
+    let hid = elgato_streamdeck::new_hidapi()?;
+    let device = match serial {
+        Some(serial) => hid.open_serial(vendor_id, product_id, serial)?,
+        None => hid.open(vendor_id, product_id)?,
+    };
+    let mut capture = Capture::new(vendor_id, product_id);
+    let mut buf = vec![0; REPORT_LEN];
+    while running.load(Ordering::Relaxed) {
+        let len = device.read_timeout(&mut buf, READ_TIMEOUT_MS)?;
+        if len > 0 {
+            let report = capture.report(&buf[..len]);
+            writeln!(out, "{}", serde_json::to_string(&report)?)?;
+        }
+    }
+    Ok(capture.mapping)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plus_reports_mapped() {
+        let mut capture = Capture::new(ELGATO_VENDOR_ID, Kind::Plus.product_id());
+        let mut key = |index: usize| {
+            let mut raw = vec![0x01, 0x00, 0x08, 0x00];
+            raw.extend((0..8).map(|key| u8::from(key == index)));
+            capture.report(&raw)
+        };
+        let report = key(5);
+        assert_eq!(report.raw, "01 00 08 00 00 00 00 00 00 01 00 00");
+        assert!(report.event.unwrap().starts_with("ButtonStateChange"));
+        key(2);
+        key(5);
+        // Twisting the second encoder one step left
+        capture.report(&[0x01, 0x03, 0x05, 0x00, 0x01, 0x00, 0xff, 0x00, 0x00]);
+
+        let mapping = capture.mapping();
+        assert_eq!(mapping.kind.as_deref(), Some("Plus"));
+        assert_eq!(mapping.keys, [5, 2]);
+        assert_eq!(mapping.encoders, [1]);
+        assert_eq!(mapping.report_lengths, [9, 12]);
+        assert!(mapping.to_toml().unwrap().contains("keys = [5, 2]"));
+
+        // Devices that aren't decks are only captured raw
+        let mut capture = Capture::new(0x1234, 0x0001);
+        assert_eq!(capture.report(&[0xab]).event, None);
+    }
+}
@@ -0,0 +1,197 @@
+//! What each kind of deck expects, as data.
+//!
+//! Someone building a leaf for hardware the satellite doesn't drive yet has
+//! to produce the same payloads a deck of the kind it stands in for takes.
+//! [Describe::describe] sums a [Kind] up as a [KindDescription]: how its key
+//! and LCD images are encoded, sized, rotated and mirrored, and how large the
+//! HID reports carrying its input and images are.  It serializes as JSON for
+//! the status endpoint of the gateway, and is printed by the probe.
+
+use elgato_streamdeck::info::{ImageMirroring, ImageMode, ImageRotation, Kind};
+use serde::Serialize;
+
+use crate::selector::KINDS;
+
+/// How an image is encoded.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageEncoding {
+    /// A JPEG
+    Jpeg,
+    /// A BMP of 24 bit pixels
+    Bmp,
+}
+
+/// What an image shown on a deck has to be.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ImageDescription {
+    /// How the image is encoded
+    pub encoding: ImageEncoding,
+    /// Width in pixels
+    pub width: usize,
+    /// Height in pixels
+    pub height: usize,
+    /// Degrees the image is rotated clockwise before it is encoded
+    pub rotation: u16,
+    /// Whether the image is flipped left to right before it is encoded
+    pub mirror_x: bool,
+    /// Whether the image is flipped top to bottom before it is encoded
+    pub mirror_y: bool,
+}
+
+/// How images are split into HID reports to be written.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReportDescription {
+    /// Bytes of each report, header included
+    pub len: usize,
+    /// Bytes of the header starting each report
+    pub header_len: usize,
+}
+
+/// Everything a leaf standing in for a kind of deck needs to know about it.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct KindDescription {
+    /// Name of the kind, as `--deck-kind` takes it
+    pub name: String,
+    /// USB vendor id
+    pub vendor_id: u16,
+    /// USB product id, the pid a leaf reports in its config
+    pub product_id: u16,
+    /// Keys, counted row by row from the top left
+    pub keys: u8,
+    /// Rows of keys
+    pub rows: u8,
+    /// Keys in each row
+    pub columns: u8,
+    /// Encoders
+    pub encoders: u8,
+    /// Images of keys, if the keys have screens
+    pub key_image: Option<ImageDescription>,
+    /// Images of the whole LCD strip, if there is one
+    pub lcd_image: Option<ImageDescription>,
+    /// Bytes of each input report read from the deck
+    pub input_report_len: usize,
+    /// Reports key images are written in, if the keys have screens
+    pub key_image_reports: Option<ReportDescription>,
+    /// Reports LCD images are written in, if there is an LCD strip
+    pub lcd_image_reports: Option<ReportDescription>,
+}
+
+/// Describing a kind of deck.
+pub trait Describe {
+    /// What the kind of deck expects.
+    fn describe(&self) -> KindDescription;
+}
+
+impl Describe for Kind {
+    fn describe(&self) -> KindDescription {
+        let format = self.key_image_format();
+        let key_image = image_encoding(format.mode).map(|encoding| {
+            let (mirror_x, mirror_y) = match format.mirror {
+                ImageMirroring::None => (false, false),
+                ImageMirroring::X => (true, false),
+                ImageMirroring::Y => (false, true),
+                ImageMirroring::Both => (true, true),
+            };
+            ImageDescription {
+                encoding,
+                width: format.size.0,
+                height: format.size.1,
+                rotation: match format.rotation {
+                    ImageRotation::Rot0 => 0,
+                    ImageRotation::Rot90 => 90,
+                    ImageRotation::Rot180 => 180,
+                    ImageRotation::Rot270 => 270,
+                },
+                mirror_x,
+                mirror_y,
+            }
+        });
+        // The strip takes JPEGs as they are
+        let lcd_image = self
+            .lcd_strip_size()
+            .map(|(width, height)| ImageDescription {
+                encoding: ImageEncoding::Jpeg,
+                width,
+                height,
+                rotation: 0,
+                mirror_x: false,
+                mirror_y: false,
+            });
+        let input_report_len = match self {
+            Kind::Original | Kind::Mini | Kind::MiniMk2 => 1 + usize::from(self.key_count()),
+            Kind::Plus => 14.max(5 + usize::from(self.encoder_count())),
+            Kind::OriginalV2 | Kind::Mk2 | Kind::Xl | Kind::XlV2 | Kind::Pedal => {
+                4 + usize::from(self.key_count())
+            }
+        };
+        let key_image_reports = key_image.as_ref().map(|_| ReportDescription {
+            len: match self {
+                Kind::Original => 8191,
+                _ => 1024,
+            },
+            header_len: match self {
+                Kind::Original | Kind::Mini | Kind::MiniMk2 => 16,
+                _ => 8,
+            },
+        });
+        let lcd_image_reports = lcd_image.as_ref().map(|_| ReportDescription {
+            len: 1024,
+            header_len: 16,
+        });
+        KindDescription {
+            name: self.to_string(),
+            vendor_id: self.vendor_id(),
+            product_id: self.product_id(),
+            keys: self.key_count(),
+            rows: self.row_count(),
+            columns: self.column_count(),
+            encoders: self.encoder_count(),
+            key_image,
+            lcd_image,
+            input_report_len,
+            key_image_reports,
+            lcd_image_reports,
+        }
+    }
+}
+
+/// How images of mode are encoded, if there are any.
+fn image_encoding(mode: ImageMode) -> Option<ImageEncoding> {
+    match mode {
+        ImageMode::None => None,
+        ImageMode::BMP => Some(ImageEncoding::Bmp),
+        ImageMode::JPEG => Some(ImageEncoding::Jpeg),
+    }
+}
+
+/// What every kind of deck expects.
+pub fn describe_all() -> Vec<KindDescription> {
+    KINDS.iter().map(Describe::describe).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kinds_described() {
+        let mk2 = Kind::Mk2.describe();
+        assert_eq!(mk2.product_id, Kind::Mk2.product_id());
+        let key_image = mk2.key_image.unwrap();
+        assert_eq!(key_image.encoding, ImageEncoding::Jpeg);
+        assert_eq!((key_image.width, key_image.height), (72, 72));
+        assert!(key_image.mirror_x && key_image.mirror_y);
+        assert_eq!(mk2.input_report_len, 4 + 15);
+        assert!(mk2.lcd_image.is_none());
+
+        // A pedal has no screens to describe
+        let pedal = Kind::Pedal.describe();
+        assert!(pedal.key_image.is_none() && pedal.key_image_reports.is_none());
+
+        let plus = Kind::Plus.describe();
+        assert!(plus.lcd_image.is_some());
+        assert_eq!(plus.input_report_len, 14);
+        assert_eq!(describe_all().len(), KINDS.len());
+    }
+}
@@ -0,0 +1,94 @@
+//! Showing a key pressed before Companion does.
+//!
+//! Companion shows a key pressed by sending it a new image, which over a slow
+//! link takes long enough that the key feels dead.  With [PressFeedback] set,
+//! the receiver of the deck darkens or inverts the image a key shows as soon
+//! as it is pressed, from the render cache, without waiting for Companion.
+//! The next image Companion sends for the key replaces it as usual.  Should
+//! Companion not change the key for a press, the image it showed is put back
+//! after [RESTORE_AFTER].
+
+use std::time::Duration;
+
+use elgato_streamdeck::info::{ImageMode, Kind};
+use image::codecs::bmp::BmpEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::ColorType;
+use traits::Result;
+
+/// How long a pressed key waits for Companion to send it an image before the
+/// image it showed is put back.
+pub const RESTORE_AFTER: Duration = Duration::from_secs(1);
+
+/// What is done to the image of a key while it waits for Companion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PressFeedback {
+    /// Show the image at half brightness
+    Darken,
+    /// Show the image with its colors inverted
+    Invert,
+}
+
+impl std::str::FromStr for PressFeedback {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "darken" => Ok(PressFeedback::Darken),
+            "invert" => Ok(PressFeedback::Invert),
+            _ => Err(format!("Press feedback must be darken or invert, got {s}")),
+        }
+    }
+}
+
+impl PressFeedback {
+    /// The image a key of a deck of kind shows while pressed, given the one
+    /// it showed, both as the deck takes them.  Images are already rotated
+    /// and mirrored for the deck, which doesn't change their pixels.
+    pub(crate) fn pressed_image(self, kind: Kind, image: &[u8]) -> Result<Vec<u8>> {
+        let mut rgb = image::load_from_memory(image)?.into_rgb8();
+        for value in rgb.iter_mut() {
+            *value = match self {
+                PressFeedback::Darken => *value / 2,
+                PressFeedback::Invert => u8::MAX - *value,
+            };
+        }
+        let (width, height) = rgb.dimensions();
+        let mut buf = Vec::new();
+        match kind.key_image_format().mode {
+            ImageMode::None => {}
+            ImageMode::BMP => {
+                BmpEncoder::new(&mut buf).encode(&rgb, width, height, ColorType::Rgb8)?
+            }
+            ImageMode::JPEG => JpegEncoder::new_with_quality(&mut buf, 90).encode(
+                &rgb,
+                width,
+                height,
+                ColorType::Rgb8,
+            )?,
+        }
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pressed_image_darkened() {
+        let mut bmp = Vec::new();
+        BmpEncoder::new(&mut bmp)
+            .encode(&[200, 100, 0].repeat(4), 2, 2, ColorType::Rgb8)
+            .unwrap();
+        let pressed = PressFeedback::Darken
+            .pressed_image(Kind::Original, &bmp)
+            .unwrap();
+        let rgb = image::load_from_memory(&pressed).unwrap().into_rgb8();
+        assert_eq!(rgb.dimensions(), (2, 2));
+        assert_eq!(rgb.get_pixel(1, 1).0, [100, 50, 0]);
+
+        assert_eq!("invert".parse(), Ok(PressFeedback::Invert));
+        assert!("flash".parse::<PressFeedback>().is_err());
+    }
+}
@@ -0,0 +1,229 @@
+//! Long and double presses of keys, told apart on this side of Companion.
+//!
+//! The satellite API only tells Companion that keys went down and up, so
+//! holding a key or pressing it twice can only do what pressing it once does.
+//! Keys given [KeyGestures] are watched by the receiver of the deck instead.
+//! A key held for long enough is reported as its long press key, and a key
+//! pressed again soon after being released as its double press key, each a
+//! key of its own that Companion binds actions to.
+//!
+//! A press that turns out to be neither is reported as the key itself going
+//! down and up once that is known, so keys with gestures react a moment later
+//! than those without.  Keys are numbered as the deck numbers them, before
+//! any orientation is applied.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use leaf_comm::ButtonChange;
+
+/// How long a key is held for a long press unless told otherwise.
+pub const DEFAULT_LONG_PRESS: Duration = Duration::from_millis(500);
+/// How soon a key is pressed again for a double press unless told otherwise.
+pub const DEFAULT_DOUBLE_PRESS: Duration = Duration::from_millis(300);
+
+/// Which keys have long and double presses, and how they are told apart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyGestures {
+    /// How long a key is held for a long press
+    pub long_press: Duration,
+    /// How soon after being released a key is pressed again for a double
+    /// press
+    pub double_press: Duration,
+    /// Keys with a long press, and the key each long press is reported as
+    pub long_press_keys: BTreeMap<u8, u8>,
+    /// Keys with a double press, and the key each double press is reported
+    /// as
+    pub double_press_keys: BTreeMap<u8, u8>,
+}
+
+impl Default for KeyGestures {
+    fn default() -> Self {
+        Self {
+            long_press: DEFAULT_LONG_PRESS,
+            double_press: DEFAULT_DOUBLE_PRESS,
+            long_press_keys: BTreeMap::new(),
+            double_press_keys: BTreeMap::new(),
+        }
+    }
+}
+
+/// Where a key with gestures is in telling them apart.
+#[derive(Debug, Clone, Copy)]
+enum Gesture {
+    /// Down since, not held long enough for a long press yet
+    Pressed(Instant),
+    /// Held down as its long press key
+    Long,
+    /// Released at, waiting for a second press
+    Released(Instant),
+    /// Pressed a second time, down as its double press key
+    Double,
+}
+
+/// The gestures of the keys of a deck, as the presses come in.
+#[derive(Debug, Clone)]
+pub(crate) struct GestureTracker {
+    gestures: KeyGestures,
+    keys: BTreeMap<u8, Gesture>,
+}
+
+impl GestureTracker {
+    pub(crate) fn new(gestures: KeyGestures) -> Self {
+        Self {
+            gestures,
+            keys: BTreeMap::new(),
+        }
+    }
+
+    /// When a key waiting on its gesture is next due to be told apart.
+    pub(crate) fn due_at(&self) -> Option<Instant> {
+        self.keys
+            .values()
+            .filter_map(|gesture| match gesture {
+                Gesture::Pressed(at) => Some(*at + self.gestures.long_press),
+                Gesture::Released(at) => Some(*at + self.gestures.double_press),
+                Gesture::Long | Gesture::Double => None,
+            })
+            .min()
+    }
+
+    /// The presses of keys told apart by now, if any were.
+    pub(crate) fn poll(&mut self, now: Instant) -> Option<ButtonChange> {
+        let mut buttons = Vec::new();
+        self.timed_out(now, &mut buttons);
+        (!buttons.is_empty()).then_some(ButtonChange { buttons })
+    }
+
+    /// The presses to report for a change of the keys of the deck at now.
+    pub(crate) fn input(&mut self, change: ButtonChange, now: Instant) -> Option<ButtonChange> {
+        let mut buttons = Vec::new();
+        // Gestures due before this input are told apart first
+        self.timed_out(now, &mut buttons);
+        for (key, pressed) in change.buttons {
+            self.key(key, pressed, now, &mut buttons);
+        }
+        (!buttons.is_empty()).then_some(ButtonChange { buttons })
+    }
+
+    /// Report the gestures that are told apart by now to buttons.
+    fn timed_out(&mut self, now: Instant, buttons: &mut Vec<(u8, bool)>) {
+        let KeyGestures {
+            long_press,
+            double_press,
+            ..
+        } = self.gestures;
+        let long_press_keys = &self.gestures.long_press_keys;
+        self.keys.retain(|&key, gesture| match *gesture {
+            Gesture::Pressed(at) if now.duration_since(at) >= long_press => {
+                // A key only waited on for a double press stays down
+                if let Some(&long) = long_press_keys.get(&key) {
+                    buttons.push((long, true));
+                    *gesture = Gesture::Long;
+                }
+                true
+            }
+            Gesture::Released(at) if now.duration_since(at) >= double_press => {
+                // Pressed once, after all
+                buttons.extend([(key, true), (key, false)]);
+                false
+            }
+            _ => true,
+        });
+    }
+
+    /// Report key going down or up to buttons.
+    fn key(&mut self, key: u8, pressed: bool, now: Instant, buttons: &mut Vec<(u8, bool)>) {
+        let long = self.gestures.long_press_keys.get(&key).copied();
+        let double = self.gestures.double_press_keys.get(&key).copied();
+        if long.is_none() && double.is_none() {
+            buttons.push((key, pressed));
+            return;
+        }
+        match (self.keys.get(&key).copied(), pressed) {
+            (None, true) => {
+                self.keys.insert(key, Gesture::Pressed(now));
+            }
+            (Some(Gesture::Released(_)), true) => {
+                if let Some(double) = double {
+                    buttons.push((double, true));
+                    self.keys.insert(key, Gesture::Double);
+                }
+            }
+            (Some(Gesture::Pressed(_)), false) => match double {
+                Some(_) => {
+                    self.keys.insert(key, Gesture::Released(now));
+                }
+                None => {
+                    self.keys.remove(&key);
+                    buttons.extend([(key, true), (key, false)]);
+                }
+            },
+            (Some(Gesture::Long), false) => {
+                self.keys.remove(&key);
+                buttons.extend(long.map(|long| (long, false)));
+            }
+            (Some(Gesture::Double), false) => {
+                self.keys.remove(&key);
+                buttons.extend(double.map(|double| (double, false)));
+            }
+            // Down or up already, or went down before it was watched
+            _ => buttons.push((key, pressed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_long_and_double_presses_told_apart() {
+        let gestures = KeyGestures {
+            long_press_keys: [(0, 20)].into(),
+            double_press_keys: [(0, 21)].into(),
+            ..Default::default()
+        };
+        let mut tracker = GestureTracker::new(gestures);
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut input = |key, pressed, ms| {
+            let change = ButtonChange {
+                buttons: vec![(key, pressed)],
+            };
+            tracker.input(change, at(ms)).map(|change| change.buttons)
+        };
+
+        // Keys without gestures pass straight through
+        assert_eq!(input(1, true, 0), Some(vec![(1, true)]));
+        // A single press is reported once no second press came
+        assert_eq!(input(0, true, 0), None);
+        assert_eq!(input(0, false, 100), None);
+        assert_eq!(
+            input(1, false, 500),
+            Some(vec![(0, true), (0, false), (1, false)])
+        );
+        // A double press
+        assert_eq!(input(0, true, 1000), None);
+        assert_eq!(input(0, false, 1100), None);
+        assert_eq!(input(0, true, 1200), Some(vec![(21, true)]));
+        assert_eq!(input(0, false, 1300), Some(vec![(21, false)]));
+        // A long press
+        assert_eq!(input(0, true, 2000), None);
+        assert_eq!(tracker.due_at(), Some(at(2500)));
+        assert_eq!(
+            tracker.poll(at(2500)).map(|change| change.buttons),
+            Some(vec![(20, true)])
+        );
+        assert_eq!(tracker.due_at(), None);
+        let release = ButtonChange {
+            buttons: vec![(0, false)],
+        };
+        assert_eq!(
+            tracker
+                .input(release, at(3000))
+                .map(|change| change.buttons),
+            Some(vec![(20, false)])
+        );
+    }
+}
@@ -0,0 +1,133 @@
+//! Blocking hidapi calls kept off the async runtime.
+//!
+//! Every call into hidapi blocks until its USB transfer is done.
+//! [AsyncStreamDeck](elgato_streamdeck::AsyncStreamDeck) makes these calls
+//! with block_in_place, which holds a worker of the runtime for the whole
+//! transfer.  On a single core Pi that is the only worker, so under load
+//! everything else waits behind image writes.  A [HidThread] instead owns the
+//! device on a thread of its own and runs the calls sent to it there, in
+//! order, from a bounded queue.  When the device falls behind, callers wait
+//! for room in the queue without holding up the runtime.
+//!
+//! How long each call waited in the queue and ran on the device is traced,
+//! and calls slower than [SLOW_CALL] are logged at debug, so the latency on a
+//! given machine can be measured with `RUST_LOG=streamdeck=debug`.
+
+use std::time::{Duration, Instant};
+
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, trace};
+use traits::anyhow::{self, anyhow};
+use traits::Result;
+
+/// Calls queued for a device before callers wait for it to catch up.
+const QUEUED_CALLS: usize = 16;
+/// Calls taking longer than this from being queued to finishing are logged.
+const SLOW_CALL: Duration = Duration::from_millis(20);
+
+/// A call waiting to run on the thread of a device.
+type Call<D> = Box<dyn FnOnce(&mut D) + Send>;
+
+/// Handle to a thread owning a device of type D.  The thread exits once every
+/// handle is dropped.
+pub(crate) struct HidThread<D> {
+    calls: mpsc::Sender<Call<D>>,
+}
+
+impl<D> Clone for HidThread<D> {
+    fn clone(&self) -> Self {
+        Self {
+            calls: self.calls.clone(),
+        }
+    }
+}
+
+impl<D: Send + 'static> HidThread<D> {
+    /// Move device to a new thread called name.
+    pub(crate) fn spawn(name: String, mut device: D) -> Result<Self> {
+        let (calls, mut queue) = mpsc::channel::<Call<D>>(QUEUED_CALLS);
+        std::thread::Builder::new().name(name).spawn(move || {
+            while let Some(call) = queue.blocking_recv() {
+                call(&mut device);
+            }
+            debug!("HID thread closed");
+        })?;
+        Ok(Self { calls })
+    }
+
+    /// Run f with the device on its thread, after every call queued before
+    /// it.  If the caller stops waiting, f still runs and its result is
+    /// dropped.
+    pub(crate) async fn call<R, E>(
+        &self,
+        f: impl FnOnce(&D) -> std::result::Result<R, E> + Send + 'static,
+    ) -> Result<R>
+    where
+        R: Send + 'static,
+        E: Into<anyhow::Error> + Send + 'static,
+    {
+        self.call_mut(move |device| f(device)).await
+    }
+
+    /// Run f with the device on its thread as [call](Self::call) does, able
+    /// to replace the device, as a deck plugged back in is opened again.
+    pub(crate) async fn call_mut<R, E>(
+        &self,
+        f: impl FnOnce(&mut D) -> std::result::Result<R, E> + Send + 'static,
+    ) -> Result<R>
+    where
+        R: Send + 'static,
+        E: Into<anyhow::Error> + Send + 'static,
+    {
+        let queued = Instant::now();
+        let (reply, result) = oneshot::channel();
+        let call: Call<D> = Box::new(move |device| {
+            let started = Instant::now();
+            let res = f(device);
+            let (waited, ran) = (started - queued, started.elapsed());
+            trace!("HID call waited {:?}, ran {:?}", waited, ran);
+            if waited + ran > SLOW_CALL {
+                debug!("Slow HID call: waited {:?}, ran {:?}", waited, ran);
+            }
+            // The caller may have stopped waiting
+            let _ = reply.send(res);
+        });
+        self.calls
+            .send(call)
+            .await
+            .map_err(|_| anyhow!("HID thread closed"))?;
+        let res = result.await.map_err(|_| anyhow!("HID thread closed"))?;
+        res.map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[tokio::test]
+    async fn test_calls_run_in_order_on_the_device_thread() {
+        let device = RefCell::new(Vec::new());
+        let thread = HidThread::spawn("test".into(), device).unwrap();
+        let runtime = std::thread::current().id();
+        for i in 0..3 {
+            let pushed = thread.call(move |device: &RefCell<Vec<i32>>| {
+                assert_ne!(std::thread::current().id(), runtime);
+                device.borrow_mut().push(i);
+                Ok::<_, anyhow::Error>(device.borrow().len())
+            });
+            assert_eq!(pushed.await.unwrap(), i as usize + 1);
+        }
+        let failed = thread.call(|_| Err::<(), _>(anyhow!("unplugged")));
+        assert_eq!(failed.await.unwrap_err().to_string(), "unplugged");
+        // Replaced, as a deck plugged back in is
+        let replaced = thread.call_mut(|device: &mut RefCell<Vec<i32>>| {
+            *device = RefCell::new(vec![7]);
+            Ok::<_, anyhow::Error>(())
+        });
+        replaced.await.unwrap();
+        let len = thread.call(|device| Ok::<_, anyhow::Error>(device.borrow().len()));
+        assert_eq!(len.await.unwrap(), 1);
+    }
+}
@@ -0,0 +1,93 @@
+//! Dimming or blanking a deck nobody is using.
+//!
+//! A deck left on overnight shows the same images at full brightness for
+//! hours, lighting up a dark room and wearing its screens.  Given an
+//! [IdlePolicy], the receiver of a deck counts the time since its last
+//! button, encoder or touch input, and once that is longer than the policy
+//! allows dims the deck, or blanks its keys.  The next input of any kind
+//! restores the deck, and is passed on to Companion as usual.
+//!
+//! What Companion sends meanwhile only goes to the render cache, so the deck
+//! wakes showing what Companion sent last rather than what it showed when it
+//! went idle.
+
+use std::time::{Duration, Instant};
+
+/// What is done to a deck once it is idle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleAction {
+    /// Set the brightness of the deck to this, as set on the deck
+    Dim(u8),
+    /// Clear the images of every key
+    Blank,
+}
+
+/// When a deck is idle and what is done to it then.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdlePolicy {
+    /// How long the deck goes without input before it is idle
+    pub after: Duration,
+    /// What is done to the deck once it is idle
+    pub action: IdleAction,
+}
+
+/// Time since the last input of a deck, against its policy.
+#[derive(Debug, Clone)]
+pub(crate) struct IdleTimer {
+    policy: IdlePolicy,
+    last_input: Instant,
+    idle: bool,
+}
+
+impl IdleTimer {
+    /// Start timing at now, as if there was input.
+    pub(crate) fn new(policy: IdlePolicy, now: Instant) -> Self {
+        Self {
+            policy,
+            last_input: now,
+            idle: false,
+        }
+    }
+
+    /// What to do to the deck if it went idle by now, only once for every
+    /// time it does.
+    pub(crate) fn poll(&mut self, now: Instant) -> Option<IdleAction> {
+        if self.idle || now.duration_since(self.last_input) < self.policy.after {
+            return None;
+        }
+        self.idle = true;
+        Some(self.policy.action)
+    }
+
+    /// There was input at now.  Returns whether the deck was idle, so has
+    /// to be restored.
+    pub(crate) fn input(&mut self, now: Instant) -> bool {
+        self.last_input = now;
+        std::mem::take(&mut self.idle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idle_until_input() {
+        let policy = IdlePolicy {
+            after: Duration::from_secs(60),
+            action: IdleAction::Dim(5),
+        };
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut timer = IdleTimer::new(policy, start);
+
+        assert_eq!(timer.poll(at(59)), None);
+        assert!(!timer.input(at(59)));
+        // Input put off going idle
+        assert_eq!(timer.poll(at(60)), None);
+        assert_eq!(timer.poll(at(119)), Some(IdleAction::Dim(5)));
+        assert_eq!(timer.poll(at(200)), None);
+        assert!(timer.input(at(201)));
+        assert_eq!(timer.poll(at(261)), Some(IdleAction::Dim(5)));
+    }
+}
@@ -7,113 +7,403 @@
 #![cfg_attr(docsrs, feature(doc_cfg))]
 #![warn(missing_docs)]
 
+mod hid_thread;
+mod watchdog;
+/// Decks bound to device ids, kinds and orientations by their serials.
+pub mod bindings;
+/// Capture of the raw input of a device, for mapping new hardware.
+pub mod capture;
+/// What each kind of deck expects, as data.
+pub mod describe;
+/// Showing a key pressed before Companion does.
+pub mod feedback;
+/// Long and double presses of keys, told apart on this side of Companion.
+pub mod gestures;
+/// Dimming or blanking a deck nobody is using.
+pub mod idle;
+/// Hardware probe for support requests.
+pub mod probe;
+/// Choosing which of the attached decks to open.
+pub mod selector;
+
+use std::time::{Duration, Instant};
+
+use elgato_streamdeck::images::ImageRect;
 use elgato_streamdeck::info::Kind;
-use elgato_streamdeck::AsyncStreamDeck;
+use feedback::{PressFeedback, RESTORE_AFTER};
+use gestures::{GestureTracker, KeyGestures};
+use hid_thread::HidThread;
+use idle::{IdleAction, IdlePolicy, IdleTimer};
 use tracing::{debug, info, trace};
+use leaf_comm::coalesce::TwistCoalescer;
+use leaf_comm::state::InputState;
+use selector::DeviceSelector;
 use traits::Result;
+use watchdog::{SharedCache, Watchdog};
 use traits::anyhow;
 use traits::{
     async_trait,
-    device::{SetBrightness, SetButtonImage, SetLCDImage},
+    device::{BrightnessPolicy, SetBrightness, SetButtonImage, SetLCDImage},
 };
 
-#[derive(Clone)]
-struct KeyState {
-    states: Vec<bool>,
+/// How long encoder twists are summed for unless told otherwise.
+pub const DEFAULT_TWIST_WINDOW: Duration = Duration::from_millis(30);
+/// How often the device is polled for input unless told otherwise.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(1000 / 60);
+/// Brightness a deck is set to when opened, until Companion sets its own.
+const OPEN_BRIGHTNESS: u8 = 35;
+
+/// The device, on the thread that makes its blocking hidapi calls.
+type Device = HidThread<elgato_streamdeck::StreamDeck>;
+
+/// The kind and serial number of every attached StreamDeck.
+pub fn list_attached() -> Result<Vec<(Kind, String)>> {
+    let hid = elgato_streamdeck::new_hidapi()?;
+    Ok(elgato_streamdeck::list_devices(&hid))
 }
-impl KeyState {
-    fn update_state<'a>(
-        &'a mut self,
-        offset: usize,
-        changes: impl IntoIterator<Item = (usize, bool)> + 'a,
-    ) -> impl Iterator<Item = (u8, bool)> + 'a {
-        changes.into_iter().filter_map(move |(index, state)| {
-            let index = index + offset;
-            if *self.states.get(index)? == state {
-                None
-            } else {
-                self.states[index] = state;
-                Some((index as u8, state))
-            }
+
+/// The kind and serial number of every attached StreamDeck selector selects.
+pub fn list_selected(selector: &DeviceSelector) -> Result<Vec<(Kind, String)>> {
+    Ok(attached_with_paths()?
+        .into_iter()
+        .filter(|(kind, serial, path)| selector.matches(kind, serial, path))
+        .map(|(kind, serial, _)| (kind, serial))
+        .collect())
+}
+
+/// The kind, serial number and USB path of every attached StreamDeck.
+pub(crate) fn attached_with_paths() -> Result<Vec<(Kind, String, String)>> {
+    let hid = elgato_streamdeck::new_hidapi()?;
+    let mut attached: Vec<_> = hid
+        .device_list()
+        .filter(|info| info.vendor_id() == elgato_streamdeck::info::ELGATO_VENDOR_ID)
+        .filter_map(|info| {
+            let kind = Kind::from_pid(info.product_id())?;
+            let serial = info.serial_number()?.to_string();
+            Some((kind, serial, info.path().to_string_lossy().into_owned()))
         })
-    }
+        .collect();
+    // A deck with several interfaces is listed once for each
+    attached.dedup();
+    Ok(attached)
 }
 
 /// StreamDeck implements the device::Sender and device::Receiver traits for the Elgato StreamDeck.
 ///
 /// A single StreamDeck implements both the sender and receiver traits and can be cloned to
-/// create multiple instances of the same device.  The device is shared properly in the underlying
-/// elgato_streamdeck::AsyncStreamDeck implementation.
+/// create multiple instances of the same device.  The device is owned by a thread of its own,
+/// which every clone sends its calls to, so hidapi never blocks the async runtime.  A deck
+/// that is unplugged is opened again once it is plugged back in, and told to Companion again.
 #[derive(Clone)]
 pub struct StreamDeck {
-    keystate: KeyState,
-    device: AsyncStreamDeck,
+    kind: Kind,
+    keystate: InputState,
+    device: Device,
     first: bool,
+    cache: SharedCache,
+    watchdog: Watchdog,
+    twists: TwistCoalescer,
+    epoch: Instant,
+    brightness_policy: BrightnessPolicy,
+    device_id: Option<String>,
+    poll_interval: Duration,
+    idle: Option<IdleTimer>,
+    gestures: Option<GestureTracker>,
+    feedback: Option<PressFeedback>,
 }
 impl StreamDeck {
     /// Get the kind of device this is.
     pub fn kind(&self) -> elgato_streamdeck::info::Kind {
-        self.device.kind()
+        self.kind
     }
-    /// Create a new StreamDeck from the provided AsyncStreamDeck.
-    pub fn new(device: AsyncStreamDeck) -> Self {
+    /// Create a new StreamDeck from the provided elgato_streamdeck::StreamDeck,
+    /// moving it to a thread of its own.
+    pub fn new(device: elgato_streamdeck::StreamDeck) -> Result<Self> {
         let kind = device.kind();
+        // What the deck is opened again by once it is plugged back in
+        let serial = device.serial_number()?;
         // Our key layout is the hardware keys, followed by virtual LCD keys, followed by encoders.
         let keycount = kind.key_count()
             + if kind.lcd_strip_size().is_some() {
                 kind.column_count()
             } else {
                 0
-            }
-            + kind.encoder_count();
-        let keystate = KeyState {
-            states: vec![false; keycount as usize],
-        };
-        Self {
+            };
+        let keystate = InputState::new(keycount.into(), kind.encoder_count().into());
+        let device = HidThread::spawn(format!("hid {}", kind.to_string()), device)?;
+        Ok(Self {
+            kind,
             keystate,
             device,
             first: true,
+            cache: Default::default(),
+            watchdog: Watchdog::new(serial),
+            twists: TwistCoalescer::new(
+                kind.encoder_count().into(),
+                DEFAULT_TWIST_WINDOW.as_millis() as u64,
+            ),
+            epoch: Instant::now(),
+            brightness_policy: Default::default(),
+            device_id: None,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            idle: None,
+            gestures: None,
+            feedback: None,
+        })
+    }
+
+    /// Sum encoder twists over window before sending them, a window of zero
+    /// sends every twist as it arrives.
+    pub fn with_twist_window(mut self, window: Duration) -> Self {
+        self.twists = TwistCoalescer::new(
+            self.kind().encoder_count().into(),
+            window.as_millis() as u64,
+        );
+        self
+    }
+
+    /// Map the brightness Companion asks for through policy before setting
+    /// it on the device.
+    pub fn with_brightness_policy(mut self, policy: BrightnessPolicy) -> Self {
+        self.brightness_policy = policy;
+        self
+    }
+
+    /// Poll the device for input every interval, longer intervals taking
+    /// less CPU but adding to the latency of input.
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Dim or blank the device as policy says once it goes without input
+    /// for long enough, until the next input.  Only the receiver times
+    /// input, so this is set on the receiver.
+    pub fn with_idle_policy(mut self, policy: IdlePolicy) -> Self {
+        self.idle = Some(IdleTimer::new(policy, Instant::now()));
+        self
+    }
+
+    /// Report long and double presses of keys as gestures says, as keys of
+    /// their own.  Set on the receiver.
+    pub fn with_key_gestures(mut self, gestures: KeyGestures) -> Self {
+        self.gestures = Some(GestureTracker::new(gestures));
+        self
+    }
+
+    /// Show keys pressed with feedback as soon as they are, rather than once
+    /// Companion sends them an image.  Set on the receiver.
+    pub fn with_press_feedback(mut self, feedback: PressFeedback) -> Self {
+        self.feedback = Some(feedback);
+        self
+    }
+
+    /// Tell Companion the device is device_id rather than its serial.
+    pub fn with_device_id(mut self, device_id: String) -> Self {
+        self.device_id = Some(device_id);
+        self
+    }
+
+    /// Milliseconds since the device was opened, the clock of the coalescer.
+    fn now_ms(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
+    /// Run f on the device, unless it is detached.  A write that fails
+    /// leaves the device detached for the watchdog to open again, the cache
+    /// already holding what it should show.
+    async fn write<E>(
+        &self,
+        f: impl FnOnce(&elgato_streamdeck::StreamDeck) -> std::result::Result<(), E> + Send + 'static,
+    ) -> Result<()>
+    where
+        E: Into<anyhow::Error> + Send + 'static,
+    {
+        if self.cache.lock().unwrap().detached {
+            return Ok(());
         }
+        if let Err(e) = self.device.call(f).await {
+            debug!("Writing to the Stream Deck failed: {:#}", e);
+            self.cache.lock().unwrap().detached = true;
+        }
+        Ok(())
+    }
+
+    /// Dim or blank the device if it went idle.
+    async fn check_idle(&mut self) -> Result<()> {
+        let Some(action) = self
+            .idle
+            .as_mut()
+            .and_then(|idle| idle.poll(Instant::now()))
+        else {
+            return Ok(());
+        };
+        if !self.kind.is_visual() {
+            return Ok(());
+        }
+        debug!("Deck idle, {:?}", action);
+        // Set first, so the sender holds back what would undo it
+        self.cache.lock().unwrap().idle = Some(action);
+        match action {
+            IdleAction::Dim(brightness) => self.write(move |d| d.set_brightness(brightness)).await,
+            IdleAction::Blank => {
+                for key in 0..self.kind.key_count() {
+                    self.write(move |d| d.clear_button_image(key)).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// There was input, so restore the device if it was idle.
+    async fn wake(&mut self) -> Result<()> {
+        let woken = self
+            .idle
+            .as_mut()
+            .is_some_and(|idle| idle.input(Instant::now()));
+        if !woken {
+            return Ok(());
+        }
+        let (action, brightness, images) = {
+            let mut cache = self.cache.lock().unwrap();
+            (cache.idle.take(), cache.brightness(), cache.images())
+        };
+        debug!("Deck woken from {:?}", action);
+        match action {
+            Some(IdleAction::Dim(_)) => {
+                let brightness = brightness.unwrap_or(OPEN_BRIGHTNESS);
+                self.write(move |d| d.set_brightness(brightness)).await
+            }
+            Some(IdleAction::Blank) => {
+                for (key, image) in images {
+                    self.write(move |d| d.write_image(key, &image)).await?;
+                }
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Show the keys pressed in change as pressed, if asked to.
+    async fn show_pressed(&mut self, change: &leaf_comm::ButtonChange) -> Result<()> {
+        let Some(feedback) = self.feedback else {
+            return Ok(());
+        };
+        for &(key, pressed) in &change.buttons {
+            if !pressed {
+                continue;
+            }
+            // Keys Companion never sent an image have nothing to change
+            let Some(image) = self.cache.lock().unwrap().image(key) else {
+                continue;
+            };
+            let image = match feedback.pressed_image(self.kind, &image) {
+                Ok(image) => image,
+                Err(e) => {
+                    debug!("Couldn't show key {} pressed: {:#}", key, e);
+                    continue;
+                }
+            };
+            let restore_at = Instant::now() + RESTORE_AFTER;
+            self.cache.lock().unwrap().pressed.insert(key, restore_at);
+            self.write(move |d| d.write_image(key, &image)).await?;
+        }
+        Ok(())
+    }
+
+    /// Put back the images of pressed keys Companion didn't send an image
+    /// for in time.
+    async fn restore_pressed(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let restored: Vec<_> = {
+            let mut cache = self.cache.lock().unwrap();
+            let mut due = Vec::new();
+            cache.pressed.retain(|&key, at| {
+                let waiting = *at > now;
+                if !waiting {
+                    due.push(key);
+                }
+                waiting
+            });
+            due.into_iter()
+                .filter_map(|key| Some((key, cache.image(key)?)))
+                .collect()
+        };
+        for (key, image) in restored {
+            self.write(move |d| d.write_image(key, &image)).await?;
+        }
+        Ok(())
+    }
+
+    /// The config Companion is told of the device with.
+    async fn config(&self) -> Result<leaf_comm::Command> {
+        let kind = self.kind;
+        Ok(leaf_comm::Command::Config(leaf_comm::RemoteConfig {
+            pid: kind.product_id(),
+            device_id: match &self.device_id {
+                Some(device_id) => device_id.clone(),
+                None => self.device.call(|d| d.serial_number()).await?,
+            },
+            fingerprint: leaf_comm::Fingerprint::new(kind.key_count(), &kind.key_image_format()),
+        }))
     }
 
     /// Opens the first StreamDeck found.
     pub async fn open_first() -> Result<(StreamDeck, StreamDeck)> {
-        Self::open(|_| true).await
+        Self::open(&DeviceSelector::default()).await
     }
 
-    /// Constructor to create a new StreamDeck according to the predicate
-    /// provided.
-    pub async fn open(mut filter: impl FnMut(&Kind) -> bool) -> Result<(StreamDeck, StreamDeck)> {
-        // Create instance of HidApi
-        let hid = elgato_streamdeck::new_hidapi().unwrap();
+    /// Opens the StreamDeck with the serial number provided.
+    pub async fn open_serial(serial: &str) -> Result<(StreamDeck, StreamDeck)> {
+        Self::open_matching(|_, found, _| found == serial).await
+    }
+
+    /// Constructor to create a new StreamDeck, the first the selector
+    /// provided selects.
+    pub async fn open(selector: &DeviceSelector) -> Result<(StreamDeck, StreamDeck)> {
+        debug!("Opening the first deck selected by {:?}", selector);
+        Self::open_matching(|kind, serial, path| selector.matches(kind, serial, path)).await
+    }
 
-        // List devices and unsafely take first one
-        let (kind, serial) = elgato_streamdeck::list_devices(&hid)
+    /// Open the first StreamDeck whose kind, serial and USB path match
+    /// filter.
+    async fn open_matching(
+        mut filter: impl FnMut(&Kind, &str, &str) -> bool,
+    ) -> Result<(StreamDeck, StreamDeck)> {
+        // List devices and take the first that matches
+        let (kind, serial, _) = attached_with_paths()?
             .into_iter()
-            .find(|(kind,_)| filter(kind))
+            .find(|(kind, serial, path)| filter(kind, serial, path))
             .ok_or_else(|| anyhow::anyhow!("No matching devices found"))?;
 
+        // Create instance of HidApi
+        let hid = elgato_streamdeck::new_hidapi().unwrap();
+
         let image_format = kind.key_image_format();
         info!("Found kind {:?} with image format {:?}", kind, image_format);
 
-        // Connect to the device
-        let device =
-            elgato_streamdeck::asynchronous::AsyncStreamDeck::connect(&hid, kind, &serial)?;
+        // Connect to the device, only blocking while starting up
+        let device = Self::new(elgato_streamdeck::StreamDeck::connect(&hid, kind, &serial)?)?;
 
         // Print out some info from the device
         info!(
             "Connected to '{}' with version '{}'",
-            device.serial_number().await?,
-            device.firmware_version().await?
+            device.device.call(|d| d.serial_number()).await?,
+            device.device.call(|d| d.firmware_version()).await?
         );
 
-        device.reset().await?;
+        device.device.call(|d| d.reset()).await?;
 
         // Set device brightness
-        device.set_brightness(35).await?;
+        if kind.is_visual() {
+            device
+                .device
+                .call(|d| d.set_brightness(OPEN_BRIGHTNESS))
+                .await?;
+        }
 
-        let device_sender = Self::new(device.clone());
-        let device_receiver = device_sender.clone();
+        let device_receiver = device.clone();
+        let device_sender = device;
         Ok((device_sender, device_receiver))
     }
 }
@@ -121,14 +411,58 @@ impl StreamDeck {
 #[async_trait]
 impl traits::device::Sender for StreamDeck {
     async fn set_brightness(&mut self, brightness: SetBrightness) -> Result<()> {
-        Ok(self.device.set_brightness(brightness.brightness).await?)
+        // A Pedal has switches but no screen
+        if !self.kind().is_visual() {
+            return Ok(());
+        }
+        let brightness = self.brightness_policy.apply(brightness.brightness);
+        let idle = {
+            let mut cache = self.cache.lock().unwrap();
+            cache.set_brightness(brightness);
+            cache.idle
+        };
+        // Set once the deck wakes
+        if idle.is_some() {
+            return Ok(());
+        }
+        self.write(move |d| d.set_brightness(brightness)).await
     }
     async fn set_button_image(&mut self, image: SetButtonImage) -> Result<()> {
         debug!("set_button_image: {:?}", image);
-        Ok(self.device.write_image(image.button, &image.image).await?)
+        if !self.kind().is_visual() {
+            return Ok(());
+        }
+        let idle = {
+            let mut cache = self.cache.lock().unwrap();
+            cache.set_image(image.button, &image.image);
+            cache.idle
+        };
+        // Shown once the deck wakes
+        if idle == Some(IdleAction::Blank) {
+            return Ok(());
+        }
+        self.write(move |d| d.write_image(image.button, &image.image))
+            .await
+    }
+    async fn set_lcd_image(&mut self, image: SetLCDImage) -> Result<()> {
+        debug!("set_lcd_image at {}", image.x_offset);
+        // Only the Plus has an LCD strip
+        if self.kind().lcd_strip_size().is_none() {
+            return Ok(());
+        }
+        // Already encoded for the strip by the image converter
+        let rect = ImageRect::from_device_image(image.x_size, image.y_size, image.image);
+        self.write(move |d| d.write_lcd(image.x_offset, 0, &rect))
+            .await
     }
-    async fn set_lcd_image(&mut self, _image: SetLCDImage) -> Result<()> {
-        // Ok(self.device.write_lcd(image.x_offset, 0, image.image).await?)
+    async fn clear(&mut self) -> Result<()> {
+        if !self.kind().is_visual() {
+            return Ok(());
+        }
+        self.cache.lock().unwrap().clear_images();
+        for key in 0..self.kind().key_count() {
+            self.write(move |d| d.clear_button_image(key)).await?;
+        }
         Ok(())
     }
 }
@@ -141,44 +475,102 @@ impl traits::device::Receiver for StreamDeck {
         if self.first {
             trace!("First read");
             self.first = false;
-            return Ok(leaf_comm::Command::Config(
-                leaf_comm::RemoteConfig {
-                    pid: self.device.kind().product_id(),
-                    device_id: self.device.serial_number().await?,
-                },
-            ));
+            return self.config().await;
         }
         loop {
-            let buttons = self.device.read_input(60.0).await?;
+            if self.watchdog.check(&self.device, &self.cache).await? {
+                // Plugged back in, so Companion is told of it again
+                return self.config().await;
+            }
+            if self.watchdog.is_unplugged() {
+                tokio::time::sleep(watchdog::RETRY_DELAY).await;
+                continue;
+            }
+            // A read is never abandoned part way, or its input would be lost
+            let input = self.device.call(|d| d.read_input(None)).await;
+            let buttons = match input {
+                Ok(buttons) => buttons,
+                Err(e) => {
+                    self.watchdog.device_error(e)?;
+                    tokio::time::sleep(watchdog::RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            if !matches!(buttons, elgato_streamdeck::StreamDeckInput::NoData) {
+                self.wake().await?;
+            }
             match buttons {
-                elgato_streamdeck::StreamDeckInput::NoData => {}
+                elgato_streamdeck::StreamDeckInput::NoData => {
+                    // Poll again later, sooner if coalesced twists or
+                    // gestures fall due
+                    let mut wake = Instant::now() + self.poll_interval;
+                    if let Some(at) = self.twists.flush_at() {
+                        wake = wake.min(self.epoch + Duration::from_millis(at));
+                    }
+                    if let Some(at) = self.gestures.as_ref().and_then(GestureTracker::due_at) {
+                        wake = wake.min(at);
+                    }
+                    tokio::time::sleep_until(wake.into()).await;
+                    self.check_idle().await?;
+                    self.restore_pressed().await?;
+                    if let Some(twist) = self.twists.poll(self.now_ms()) {
+                        return Ok(leaf_comm::Command::EncoderTwist(twist));
+                    }
+                    let now = Instant::now();
+                    if let Some(change) = self.gestures.as_mut().and_then(|g| g.poll(now)) {
+                        return Ok(leaf_comm::Command::ButtonChange(change));
+                    }
+                }
                 elgato_streamdeck::StreamDeckInput::ButtonStateChange(buttons) => {
-                    return Ok(leaf_comm::Command::ButtonChange(
-                        leaf_comm::ButtonChange {
-                            buttons: self
-                                .keystate
-                                .update_state(0, buttons.into_iter().enumerate())
-                                .collect(),
-                        },
-                    ))
+                    let change = self.keystate.keys(buttons);
+                    if let Some(change) = &change {
+                        self.show_pressed(change).await?;
+                    }
+                    let change = match (change, &mut self.gestures) {
+                        (Some(change), Some(gestures)) => gestures.input(change, Instant::now()),
+                        (change, _) => change,
+                    };
+                    if let Some(change) = change {
+                        return Ok(leaf_comm::Command::ButtonChange(change));
+                    }
                 }
                 elgato_streamdeck::StreamDeckInput::EncoderTwist(twist) => {
                     let twists = twist
                         .into_iter()
-                        .take(self.device.kind().key_count() as usize)
                         .enumerate()
                         .filter(|(_i, v)| *v != 0)
                         .map(|(i, v)| (i as u8, v));
-                    return Ok(leaf_comm::Command::EncoderTwist(
-                        leaf_comm::EncoderTwist {
-                            encoders: twists.collect(),
-                        },
-                    ));
+                    let now = self.now_ms();
+                    if let Some(twist) = self.twists.add(now, twists) {
+                        return Ok(leaf_comm::Command::EncoderTwist(twist));
+                    }
+                }
+                elgato_streamdeck::StreamDeckInput::EncoderStateChange(encoders) => {
+                    if let Some(press) = self.keystate.encoders(encoders) {
+                        return Ok(leaf_comm::Command::EncoderPress(press));
+                    }
+                }
+                elgato_streamdeck::StreamDeckInput::TouchScreenPress(x, y) => {
+                    return Ok(leaf_comm::Command::Touch(leaf_comm::Touch {
+                        x,
+                        y,
+                        gesture: leaf_comm::TouchGesture::Tap,
+                    }));
+                }
+                elgato_streamdeck::StreamDeckInput::TouchScreenLongPress(x, y) => {
+                    return Ok(leaf_comm::Command::Touch(leaf_comm::Touch {
+                        x,
+                        y,
+                        gesture: leaf_comm::TouchGesture::LongPress,
+                    }));
+                }
+                elgato_streamdeck::StreamDeckInput::TouchScreenSwipe((x, y), (to_x, to_y)) => {
+                    return Ok(leaf_comm::Command::Touch(leaf_comm::Touch {
+                        x,
+                        y,
+                        gesture: leaf_comm::TouchGesture::Swipe(to_x, to_y),
+                    }));
                 }
-                elgato_streamdeck::StreamDeckInput::EncoderStateChange(_) => {}
-                elgato_streamdeck::StreamDeckInput::TouchScreenPress(_, _) => {}
-                elgato_streamdeck::StreamDeckInput::TouchScreenLongPress(_, _) => {}
-                elgato_streamdeck::StreamDeckInput::TouchScreenSwipe(_, _) => {}
             }
         }
     }
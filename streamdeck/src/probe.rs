@@ -0,0 +1,259 @@
+//! Hardware probe for support requests.
+//!
+//! [probe] opens every attached Stream Deck in turn, reads what it reports
+//! about itself and exercises it briefly.  The [ProbeReport] prints as plain
+//! text a user can attach to a bug report, so nothing in it should need
+//! interpreting on their side.
+
+use std::fmt;
+use std::time::Duration;
+
+use elgato_streamdeck::info::Kind;
+use elgato_streamdeck::AsyncStreamDeck;
+use traits::Result;
+
+use crate::describe::{Describe, ImageDescription};
+
+/// How long to wait for input when checking the device can be read.
+const READ_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// The outcome of one check, with the error it failed with.
+pub type CheckResult = std::result::Result<String, String>;
+
+/// What was learned about one attached device.
+#[derive(Debug, Clone)]
+pub struct DeviceProbe {
+    /// The kind of device found
+    pub kind: Kind,
+    /// The serial number it was listed with
+    pub serial: String,
+    /// The USB path it is plugged into, if it was listed with one
+    pub usb_path: Option<String>,
+    /// Every check run, in order, by name
+    pub checks: Vec<(&'static str, CheckResult)>,
+}
+
+impl DeviceProbe {
+    /// Whether every check passed.
+    pub fn healthy(&self) -> bool {
+        self.checks.iter().all(|(_, result)| result.is_ok())
+    }
+}
+
+/// Everything found by a probe.
+#[derive(Debug, Clone)]
+pub struct ProbeReport {
+    /// One entry per device found
+    pub devices: Vec<DeviceProbe>,
+}
+
+impl ProbeReport {
+    /// Whether devices were found and every check passed.
+    pub fn healthy(&self) -> bool {
+        !self.devices.is_empty() && self.devices.iter().all(DeviceProbe::healthy)
+    }
+}
+
+/// Find and exercise every attached device.  Only fails if devices can't be
+/// listed at all, problems with a device are recorded in its checks.
+pub async fn probe() -> Result<ProbeReport> {
+    let paths = crate::attached_with_paths()?;
+    let hid = elgato_streamdeck::new_hidapi()?;
+    let mut devices = Vec::new();
+    for (kind, serial) in elgato_streamdeck::list_devices(&hid) {
+        let mut checks = Vec::new();
+        let connected = AsyncStreamDeck::connect(&hid, kind, &serial);
+        checks.push(("open", outcome(connected.as_ref().map(|_| String::new()))));
+        if let Ok(device) = connected {
+            check_device(&device, &mut checks).await;
+        }
+        let usb_path = paths
+            .iter()
+            .find(|(_, listed, _)| *listed == serial)
+            .map(|(_, _, path)| path.clone());
+        devices.push(DeviceProbe {
+            kind,
+            serial,
+            usb_path,
+            checks,
+        });
+    }
+    Ok(ProbeReport { devices })
+}
+
+/// Run the checks of an opened device.
+async fn check_device(device: &AsyncStreamDeck, checks: &mut Vec<(&'static str, CheckResult)>) {
+    checks.push(("serial number", outcome(device.serial_number().await)));
+    checks.push(("firmware version", outcome(device.firmware_version().await)));
+    checks.push((
+        "reset",
+        outcome(device.reset().await.map(|_| String::new())),
+    ));
+    checks.push((
+        "set brightness",
+        outcome(device.set_brightness(35).await.map(|_| String::new())),
+    ));
+    if device.kind().is_visual() {
+        checks.push((
+            "write image",
+            outcome(device.clear_button_image(0).await.map(|_| String::new())),
+        ));
+    }
+    // Nobody is pressing anything, so no input in time is a pass
+    let read = match tokio::time::timeout(READ_TIMEOUT, device.read_input(60.0)).await {
+        Ok(input) => outcome(input.map(|input| format!("{input:?}"))),
+        Err(_) => Ok(String::from("no input")),
+    };
+    checks.push(("read input", read));
+}
+
+/// Keep the value or the error of a check as text for the report.
+fn outcome<T, E>(result: std::result::Result<T, E>) -> CheckResult
+where
+    T: fmt::Display,
+    E: fmt::Display,
+{
+    result
+        .map(|value| value.to_string())
+        .map_err(|e| e.to_string())
+}
+
+impl fmt::Display for DeviceProbe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = self.kind;
+        writeln!(
+            f,
+            "{} (vid {:04x} pid {:04x}) serial {}",
+            kind.to_string(),
+            kind.vendor_id(),
+            kind.product_id(),
+            self.serial
+        )?;
+        if let Some(path) = &self.usb_path {
+            writeln!(f, "  usb path: {path}")?;
+        }
+        writeln!(
+            f,
+            "  keys: {} ({} rows of {})",
+            kind.key_count(),
+            kind.row_count(),
+            kind.column_count()
+        )?;
+        writeln!(f, "  encoders: {}", kind.encoder_count())?;
+        match kind.lcd_strip_size() {
+            Some((width, height)) => writeln!(f, "  lcd strip: {width}x{height}")?,
+            None => writeln!(f, "  lcd strip: none")?,
+        }
+        let description = kind.describe();
+        if let Some(image) = &description.key_image {
+            writeln!(f, "  key images: {}", describe_image(image))?;
+        }
+        if let Some(image) = &description.lcd_image {
+            writeln!(f, "  lcd images: {}", describe_image(image))?;
+        }
+        writeln!(f, "  input report: {} bytes", description.input_report_len)?;
+        if let Some(reports) = &description.key_image_reports {
+            writeln!(
+                f,
+                "  key image reports: {} bytes, {} byte header",
+                reports.len, reports.header_len
+            )?;
+        }
+        if let Some(reports) = &description.lcd_image_reports {
+            writeln!(
+                f,
+                "  lcd image reports: {} bytes, {} byte header",
+                reports.len, reports.header_len
+            )?;
+        }
+        for (name, result) in &self.checks {
+            match result {
+                Ok(value) if value.is_empty() => writeln!(f, "  {name}: ok")?,
+                Ok(value) => writeln!(f, "  {name}: ok ({value})")?,
+                Err(e) => writeln!(f, "  {name}: FAILED ({e})")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An image description on one line, such as "Jpeg 72x72, rotated 0, mirrored x y".
+fn describe_image(image: &ImageDescription) -> String {
+    let mirrored = match (image.mirror_x, image.mirror_y) {
+        (false, false) => "",
+        (true, false) => ", mirrored x",
+        (false, true) => ", mirrored y",
+        (true, true) => ", mirrored x y",
+    };
+    format!(
+        "{:?} {}x{}, rotated {}{}",
+        image.encoding, image.width, image.height, image.rotation, mirrored
+    )
+}
+
+impl fmt::Display for ProbeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Stream Deck probe, streamdeck {} on {}/{}",
+            env!("CARGO_PKG_VERSION"),
+            std::env::consts::OS,
+            std::env::consts::ARCH
+        )?;
+        if self.devices.is_empty() {
+            return writeln!(f, "No devices found");
+        }
+        for device in &self.devices {
+            writeln!(f)?;
+            write!(f, "{device}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_lists_what_was_found() {
+        let device = DeviceProbe {
+            kind: Kind::Plus,
+            serial: String::from("CL12"),
+            usb_path: Some(String::from("1-1.2")),
+            checks: vec![
+                ("open", Ok(String::new())),
+                ("serial number", Ok(String::from("CL12"))),
+                ("read input", Err(String::from("Timed out"))),
+            ],
+        };
+        let text = device.to_string();
+        let lines: Vec<_> = text.lines().collect();
+        assert!(lines[0].ends_with("(vid 0fd9 pid 0084) serial CL12"));
+        for line in [
+            "  usb path: 1-1.2",
+            "  keys: 8 (2 rows of 4)",
+            "  encoders: 4",
+            "  lcd strip: 800x100",
+            "  open: ok",
+            "  serial number: ok (CL12)",
+            "  read input: FAILED (Timed out)",
+        ] {
+            assert!(lines.contains(&line), "{line:?} missing from {text}");
+        }
+        assert!(!device.healthy());
+
+        // A report of nothing found is not a healthy one
+        let report = ProbeReport {
+            devices: Vec::new(),
+        };
+        assert!(report.to_string().ends_with("No devices found\n"));
+        assert!(!report.healthy());
+        let mut device = device;
+        device.checks.pop();
+        let report = ProbeReport {
+            devices: vec![device],
+        };
+        assert!(report.healthy());
+    }
+}
@@ -0,0 +1,141 @@
+//! Choosing which of the attached decks to open.
+//!
+//! Two decks of the same kind can't be told apart by kind alone, so a
+//! [DeviceSelector] also matches on the serial number, as a glob where `*`
+//! stands for any run of characters and `?` for any one, and on the USB path
+//! the deck is plugged into.  A path stays the same for as long as the deck
+//! stays in the same port, so even decks whose serials can't be told apart
+//! each go to the same satellite on every start.  A selector with nothing set
+//! matches every deck.
+
+pub use elgato_streamdeck::info::Kind;
+
+/// Every kind of deck, as named on the command line.
+pub(crate) const KINDS: [Kind; 9] = [
+    Kind::Original,
+    Kind::OriginalV2,
+    Kind::Mini,
+    Kind::Xl,
+    Kind::XlV2,
+    Kind::Mk2,
+    Kind::MiniMk2,
+    Kind::Pedal,
+    Kind::Plus,
+];
+
+/// Which attached decks to open.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceSelector {
+    /// Kind the deck must be
+    pub kind: Option<Kind>,
+    /// Glob the serial number of the deck must match
+    pub serial_glob: Option<String>,
+    /// USB path the deck must be plugged into, as hidapi lists it
+    pub usb_path: Option<String>,
+}
+
+impl DeviceSelector {
+    /// Only select decks of kind.
+    pub fn with_kind(mut self, kind: Kind) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+
+    /// Only select decks whose serial matches glob.
+    pub fn with_serial_glob(mut self, glob: &str) -> Self {
+        self.serial_glob = Some(glob.to_string());
+        self
+    }
+
+    /// Only select the deck plugged into path.
+    pub fn with_usb_path(mut self, path: &str) -> Self {
+        self.usb_path = Some(path.to_string());
+        self
+    }
+
+    /// Whether the deck of kind with serial, plugged into usb_path, is
+    /// selected.
+    pub fn matches(&self, kind: &Kind, serial: &str, usb_path: &str) -> bool {
+        self.kind.is_none_or(|wanted| wanted == *kind)
+            && self
+                .serial_glob
+                .as_deref()
+                .is_none_or(|glob| glob_matches(glob, serial))
+            && self.usb_path.as_deref().is_none_or(|path| path == usb_path)
+    }
+}
+
+/// Parse the name of a kind of deck, such as "Plus" or "XlV2", in any case.
+pub fn parse_kind(name: &str) -> std::result::Result<Kind, String> {
+    KINDS
+        .into_iter()
+        .find(|kind| kind.to_string().eq_ignore_ascii_case(name))
+        .ok_or_else(|| {
+            let names: Vec<_> = KINDS.iter().map(Kind::to_string).collect();
+            format!("Unknown kind {name}, expected one of {}", names.join(", "))
+        })
+}
+
+/// Whether text matches glob, where `*` matches any run of characters and
+/// `?` any one character.
+fn glob_matches(glob: &str, text: &str) -> bool {
+    let (glob, text): (Vec<char>, Vec<char>) = (glob.chars().collect(), text.chars().collect());
+    let (mut g, mut t) = (0, 0);
+    // Where the glob resumes, and the text it resumes against, if the last
+    // star is to take in one more character
+    let mut star = None;
+    while t < text.len() {
+        match glob.get(g) {
+            Some('*') => {
+                star = Some((g + 1, t));
+                g += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                g += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((resume, taken)) => {
+                    g = resume;
+                    t = taken + 1;
+                    star = Some((resume, taken + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identical_decks_told_apart() {
+        let left = (Kind::Mk2, "CL31K1A01234", "1-1.2:1.0");
+        let right = (Kind::Mk2, "CL31K1A05678", "1-1.3:1.0");
+        let selected = |selector: &DeviceSelector| {
+            [left, right]
+                .into_iter()
+                .filter(|(kind, serial, path)| selector.matches(kind, serial, path))
+                .map(|(_, serial, _)| serial)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(selected(&DeviceSelector::default()).len(), 2);
+        assert!(selected(&DeviceSelector::default().with_kind(Kind::Plus)).is_empty());
+        let by_serial = DeviceSelector::default()
+            .with_kind(Kind::Mk2)
+            .with_serial_glob("CL31*5?78");
+        assert_eq!(selected(&by_serial), ["CL31K1A05678"]);
+        let by_path = DeviceSelector::default().with_usb_path("1-1.2:1.0");
+        assert_eq!(selected(&by_path), ["CL31K1A01234"]);
+
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("a*b*c", "aXbYbZc"));
+        assert!(!glob_matches("a*b", "aXbY"));
+        assert_eq!(parse_kind("xlv2"), Ok(Kind::XlV2));
+        assert!(parse_kind("Huge").is_err());
+    }
+}
@@ -0,0 +1,459 @@
+//! Recovery from a deck resetting or being unplugged underneath us.
+//!
+//! A deck that loses power for a moment comes back blank, while Companion
+//! believes every key still shows the image it last sent.  The sender keeps
+//! the last image of every key (and the brightness) in a [RenderCache], and
+//! the receiver periodically checks the deck is still the one we talked to.
+//! When the deck answers with a different serial number, the cache is
+//! replayed to it.
+//!
+//! A deck that stops answering is opened again by its serial number, as the
+//! handle to a deck that was unplugged never answers again.  While it is
+//! gone from the bus it is looked for every [HOTPLUG_INTERVAL], for however
+//! long it takes, and what Companion sends meanwhile only goes to the cache.
+//! Once it is back, the cache is replayed to it and the receiver sends its
+//! config to Companion again.  A deck still attached that doesn't answer is
+//! given up on after a grace period.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+use traits::Result;
+
+use crate::hid_thread::HidThread;
+use crate::idle::IdleAction;
+
+/// How often the deck is checked for a reset.
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+/// How long the deck may fail before giving up on it coming back.
+const GRACE_PERIOD: Duration = Duration::from_secs(10);
+/// How long to wait before retrying a failed read.
+pub(crate) const RETRY_DELAY: Duration = Duration::from_millis(100);
+/// How often an unplugged deck is looked for.
+const HOTPLUG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What the deck should be showing, shared between the sender and receiver.
+#[derive(Default)]
+pub(crate) struct RenderCache {
+    images: BTreeMap<u8, Vec<u8>>,
+    brightness: Option<u8>,
+    /// Whether the deck is gone, so writes only go to the cache until the
+    /// watchdog opens it again
+    pub(crate) detached: bool,
+    /// What was done to the deck if it is idle, holding back the writes it
+    /// would undo until the deck wakes
+    pub(crate) idle: Option<IdleAction>,
+    /// Keys showing they were pressed before Companion did, and when the
+    /// image they showed is put back
+    pub(crate) pressed: BTreeMap<u8, Instant>,
+}
+
+/// Shared handle to a [RenderCache].
+pub(crate) type SharedCache = Arc<Mutex<RenderCache>>;
+
+impl RenderCache {
+    pub(crate) fn set_image(&mut self, key: u8, image: &[u8]) {
+        self.images.insert(key, image.to_vec());
+        self.pressed.remove(&key);
+    }
+
+    pub(crate) fn clear_images(&mut self) {
+        self.images.clear();
+        self.pressed.clear();
+    }
+
+    pub(crate) fn image(&self, key: u8) -> Option<Vec<u8>> {
+        self.images.get(&key).cloned()
+    }
+
+    pub(crate) fn set_brightness(&mut self, brightness: u8) {
+        self.brightness = Some(brightness);
+    }
+
+    pub(crate) fn brightness(&self) -> Option<u8> {
+        self.brightness
+    }
+
+    pub(crate) fn images(&self) -> BTreeMap<u8, Vec<u8>> {
+        self.images.clone()
+    }
+}
+
+/// What the watchdog does with a deck, on the thread of the deck.
+pub(crate) trait Deck: Send + 'static {
+    /// The serial number the deck answers with.
+    fn serial_number(&self) -> Result<String>;
+    /// Set the brightness of the deck.
+    fn set_brightness(&self, brightness: u8) -> Result<()>;
+    /// Show image on key.
+    fn write_image(&self, key: u8, image: &[u8]) -> Result<()>;
+    /// Open the deck with serial in place of this one, returning whether it
+    /// is attached.
+    fn reopen(&mut self, serial: &str) -> Result<bool>;
+}
+
+impl Deck for elgato_streamdeck::StreamDeck {
+    fn serial_number(&self) -> Result<String> {
+        Ok(self.serial_number()?)
+    }
+
+    fn set_brightness(&self, brightness: u8) -> Result<()> {
+        Ok(self.set_brightness(brightness)?)
+    }
+
+    fn write_image(&self, key: u8, image: &[u8]) -> Result<()> {
+        Ok(self.write_image(key, image)?)
+    }
+
+    fn reopen(&mut self, serial: &str) -> Result<bool> {
+        let hid = elgato_streamdeck::new_hidapi()?;
+        let Some((kind, _)) = elgato_streamdeck::list_devices(&hid)
+            .into_iter()
+            .find(|(_, found)| found == serial)
+        else {
+            return Ok(false);
+        };
+        *self = elgato_streamdeck::StreamDeck::connect(&hid, kind, serial)?;
+        self.reset()?;
+        Ok(true)
+    }
+}
+
+/// Periodic check that the deck hasn't reset or been unplugged.
+#[derive(Clone)]
+pub(crate) struct Watchdog {
+    next_check: Instant,
+    serial: String,
+    lost: Option<Instant>,
+    /// Whether the deck is gone from the bus, which is waited out
+    unplugged: bool,
+}
+
+impl Watchdog {
+    /// Watch the deck with serial.
+    pub(crate) fn new(serial: String) -> Self {
+        Self {
+            next_check: Instant::now() + CHECK_INTERVAL,
+            serial,
+            lost: None,
+            unplugged: false,
+        }
+    }
+
+    /// Whether the deck is unplugged, so there is nothing to read.
+    pub(crate) fn is_unplugged(&self) -> bool {
+        self.unplugged
+    }
+
+    /// Talking to the deck failed.  This is tolerated for a while in case the
+    /// deck is resetting, after which the error is returned.  A deck that was
+    /// unplugged is waited for however long it takes.
+    pub(crate) fn device_error(&mut self, error: traits::anyhow::Error) -> Result<()> {
+        if self.unplugged {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let lost = *self.lost.get_or_insert_with(|| {
+            warn!("Stream Deck stopped answering: {}", error);
+            now
+        });
+        if now.duration_since(lost) > GRACE_PERIOD {
+            return Err(error);
+        }
+        // Look for the deck coming back as soon as possible
+        self.next_check = now;
+        Ok(())
+    }
+
+    /// Check on the deck if it is time to, replaying the cache if it reset.
+    /// Returns whether the deck was opened again, so Companion is told of it
+    /// again.
+    pub(crate) async fn check<D: Deck>(
+        &mut self,
+        device: &HidThread<D>,
+        cache: &SharedCache,
+    ) -> Result<bool> {
+        let now = Instant::now();
+        if now < self.next_check {
+            return Ok(false);
+        }
+        self.next_check = now + CHECK_INTERVAL;
+
+        let detached = cache.lock().unwrap().detached;
+        if self.lost.is_some() || detached {
+            return self.reattach(device, cache).await;
+        }
+
+        let serial = match device.call(|d| d.serial_number()).await {
+            Ok(serial) => serial,
+            Err(e) => {
+                self.device_error(e)?;
+                self.next_check = now + RETRY_DELAY;
+                return Ok(false);
+            }
+        };
+
+        if self.serial != serial {
+            info!("Stream Deck {} was reset, restoring its images", serial);
+            replay(device, cache).await?;
+            self.serial = serial;
+        }
+        Ok(false)
+    }
+
+    /// Open the deck again if it is attached, replaying the cache to it, or
+    /// wait for it to be plugged back in.
+    async fn reattach<D: Deck>(
+        &mut self,
+        device: &HidThread<D>,
+        cache: &SharedCache,
+    ) -> Result<bool> {
+        let now = Instant::now();
+        let serial = self.serial.clone();
+        match device.call_mut(move |d| d.reopen(&serial)).await {
+            Ok(true) => {
+                info!("Stream Deck {} is back, restoring its images", self.serial);
+                self.lost = None;
+                self.unplugged = false;
+                replay(device, cache).await?;
+                Ok(true)
+            }
+            Ok(false) => {
+                if !self.unplugged {
+                    warn!("Stream Deck {} was unplugged, waiting for it", self.serial);
+                }
+                self.unplugged = true;
+                self.lost.get_or_insert(now);
+                cache.lock().unwrap().detached = true;
+                self.next_check = now + HOTPLUG_INTERVAL;
+                Ok(false)
+            }
+            Err(e) => {
+                self.device_error(e)?;
+                self.next_check = now + RETRY_DELAY;
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Write everything in the cache to the deck.
+async fn replay<D: Deck>(device: &HidThread<D>, cache: &SharedCache) -> Result<()> {
+    // Don't hold the lock while talking to the device, taking what it shows
+    // once writes go to it again
+    let (mut images, mut brightness, idle) = {
+        let mut cache = cache.lock().unwrap();
+        cache.detached = false;
+        (cache.images.clone(), cache.brightness, cache.idle)
+    };
+    // An idle deck stays idle
+    match idle {
+        Some(IdleAction::Dim(dimmed)) => brightness = Some(dimmed),
+        Some(IdleAction::Blank) => images.clear(),
+        None => {}
+    }
+    if let Some(brightness) = brightness {
+        device.call(move |d| d.set_brightness(brightness)).await?;
+    }
+    for (key, image) in images {
+        device.call(move |d| d.write_image(key, &image)).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traits::anyhow::anyhow;
+
+    /// What the fake deck is doing, shared with the test.
+    #[derive(Default)]
+    struct State {
+        serial: String,
+        /// Whether the deck is on the bus, to be opened again
+        attached: bool,
+        /// Whether the handle to the deck answers
+        answers: bool,
+        /// Whether the deck opens again while attached
+        opens: bool,
+        written: Vec<String>,
+    }
+
+    #[derive(Clone)]
+    struct Fake(Arc<Mutex<State>>);
+
+    impl Fake {
+        fn new(serial: &str) -> Self {
+            Self(Arc::new(Mutex::new(State {
+                serial: serial.to_string(),
+                attached: true,
+                answers: true,
+                opens: true,
+                written: Vec::new(),
+            })))
+        }
+
+        fn answering(&self) -> Result<std::sync::MutexGuard<'_, State>> {
+            let state = self.0.lock().unwrap();
+            if !state.answers {
+                return Err(anyhow!("Not answering"));
+            }
+            Ok(state)
+        }
+
+        fn written(&self) -> Vec<String> {
+            std::mem::take(&mut self.0.lock().unwrap().written)
+        }
+    }
+
+    impl Deck for Fake {
+        fn serial_number(&self) -> Result<String> {
+            Ok(self.answering()?.serial.clone())
+        }
+
+        fn set_brightness(&self, brightness: u8) -> Result<()> {
+            let entry = format!("brightness {brightness}");
+            self.answering()?.written.push(entry);
+            Ok(())
+        }
+
+        fn write_image(&self, key: u8, image: &[u8]) -> Result<()> {
+            let entry = format!("image {key} {image:?}");
+            self.answering()?.written.push(entry);
+            Ok(())
+        }
+
+        fn reopen(&mut self, serial: &str) -> Result<bool> {
+            let mut state = self.0.lock().unwrap();
+            if !state.attached || state.serial != serial {
+                return Ok(false);
+            }
+            if !state.opens {
+                return Err(anyhow!("Couldn't open"));
+            }
+            state.answers = true;
+            Ok(true)
+        }
+    }
+
+    /// Check on the deck now, rather than once it is time to.
+    async fn check_now(
+        watchdog: &mut Watchdog,
+        device: &HidThread<Fake>,
+        cache: &SharedCache,
+    ) -> Result<bool> {
+        watchdog.next_check = Instant::now();
+        watchdog.check(device, cache).await
+    }
+
+    fn shown(brightness: u8, key: u8, image: u8) -> SharedCache {
+        let cache = SharedCache::default();
+        let mut locked = cache.lock().unwrap();
+        locked.set_brightness(brightness);
+        locked.set_image(key, &[image]);
+        drop(locked);
+        cache
+    }
+
+    #[test]
+    fn test_errors_tolerated_for_grace_period() {
+        let mut watchdog = Watchdog::new(String::from("A"));
+        let far_off = watchdog.next_check;
+        watchdog.device_error(anyhow!("Timed out")).unwrap();
+        // The deck is checked on at once rather than at the next interval
+        assert!(watchdog.next_check < far_off);
+        watchdog.device_error(anyhow!("Timed out")).unwrap();
+
+        // Still failing past the grace period of the first error
+        let lost = Instant::now() - GRACE_PERIOD - Duration::from_secs(1);
+        watchdog.lost = Some(lost);
+        let error = watchdog.device_error(anyhow!("Timed out")).unwrap_err();
+        assert_eq!(error.to_string(), "Timed out");
+
+        // An unplugged deck is waited for however long it takes
+        watchdog.unplugged = true;
+        watchdog.device_error(anyhow!("Timed out")).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reset_deck_replayed() {
+        let fake = Fake::new("A");
+        let device = HidThread::spawn("test".into(), fake.clone()).unwrap();
+        let cache = shown(40, 2, 7);
+        let mut watchdog = Watchdog::new(String::from("A"));
+
+        // Not checked before it is time to
+        fake.0.lock().unwrap().serial = String::from("B");
+        assert!(!watchdog.check(&device, &cache).await.unwrap());
+        assert!(fake.written().is_empty());
+
+        // Answering with another serial, it reset and is shown everything
+        // again without being opened again
+        assert!(!check_now(&mut watchdog, &device, &cache).await.unwrap());
+        assert_eq!(fake.written(), ["brightness 40", "image 2 [7]"]);
+        assert!(!check_now(&mut watchdog, &device, &cache).await.unwrap());
+        assert!(fake.written().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unplugged_deck_waited_for_and_replayed() {
+        let fake = Fake::new("A");
+        let device = HidThread::spawn("test".into(), fake.clone()).unwrap();
+        let cache = shown(40, 2, 7);
+        let mut watchdog = Watchdog::new(String::from("A"));
+
+        // The deck stops answering and is lost
+        fake.0.lock().unwrap().answers = false;
+        fake.0.lock().unwrap().attached = false;
+        assert!(!check_now(&mut watchdog, &device, &cache).await.unwrap());
+        assert!(watchdog.lost.is_some());
+        assert!(!watchdog.is_unplugged());
+
+        // Not found on the bus, it is unplugged, and images only go to the
+        // cache until it is back
+        assert!(!check_now(&mut watchdog, &device, &cache).await.unwrap());
+        assert!(watchdog.is_unplugged());
+        assert!(cache.lock().unwrap().detached);
+        cache.lock().unwrap().set_image(3, &[8]);
+
+        // However long it takes
+        watchdog.lost = Some(Instant::now() - GRACE_PERIOD - Duration::from_secs(1));
+        watchdog.device_error(anyhow!("Not answering")).unwrap();
+        assert!(!check_now(&mut watchdog, &device, &cache).await.unwrap());
+        assert!(fake.written().is_empty());
+
+        // Plugged back in, it is opened again and shown everything, dimmed
+        // as it was while idle
+        fake.0.lock().unwrap().attached = true;
+        cache.lock().unwrap().idle = Some(IdleAction::Dim(10));
+        assert!(check_now(&mut watchdog, &device, &cache).await.unwrap());
+        assert_eq!(
+            fake.written(),
+            ["brightness 10", "image 2 [7]", "image 3 [8]"]
+        );
+        assert!(!watchdog.is_unplugged());
+        assert!(watchdog.lost.is_none());
+        assert!(!cache.lock().unwrap().detached);
+    }
+
+    #[tokio::test]
+    async fn test_attached_deck_not_answering_given_up() {
+        let fake = Fake::new("A");
+        let device = HidThread::spawn("test".into(), fake.clone()).unwrap();
+        let cache = shown(40, 2, 7);
+        let mut watchdog = Watchdog::new(String::from("A"));
+
+        // Still on the bus, but failing to open
+        fake.0.lock().unwrap().answers = false;
+        fake.0.lock().unwrap().opens = false;
+        assert!(!check_now(&mut watchdog, &device, &cache).await.unwrap());
+        assert!(!check_now(&mut watchdog, &device, &cache).await.unwrap());
+        assert!(!watchdog.is_unplugged());
+
+        watchdog.lost = Some(Instant::now() - GRACE_PERIOD - Duration::from_secs(1));
+        let error = check_now(&mut watchdog, &device, &cache).await.unwrap_err();
+        assert_eq!(error.to_string(), "Couldn't open");
+    }
+}
@@ -128,6 +128,10 @@ fn main() -> Result<()> {
             Ok(())
         },
         stream,
+        {
+            let start = std::time::Instant::now();
+            move || start.elapsed().as_millis() as u64
+        },
     )?;
 
     Ok(())
@@ -1,11 +1,20 @@
 #![no_std]
 
 use anyhow::Result;
-use elgato_streamdeck_local::HidDevice;
+use elgato_streamdeck_local::{HidDevice, ImageRect, StreamDeckInput};
 
 extern crate alloc;
-use alloc::vec::Vec;
-use leaf_comm::{Command, DeviceActions, RemoteConfig};
+use leaf_comm::{
+    ButtonChange, Command, DeviceActions, EncoderPress, EncoderTwist, Fingerprint, PowerState,
+    RemoteConfig, SetBrightness, SetButtonImage, SetLCDImage, StatusImage, Touch, TouchGesture,
+};
+use leaf_comm::coalesce::TwistCoalescer;
+use leaf_comm::compress;
+use leaf_comm::framing::{self, Decoder};
+use leaf_comm::hello::{self, Capabilities, Hello};
+use leaf_comm::state::InputState;
+use leaf_comm::wire::{self, PROTOCOL_VERSION};
+use leaf_traits::companion::Sender;
 
 fn rust_try_read_network() -> Result<Option<u8>> {
     let mut buf = [0u8; 1];
@@ -26,10 +35,43 @@ fn rust_write_network(buf: &[u8]) -> Result<()> {
     }
 }
 
+/// Milliseconds encoder twists are summed for before being sent.
+const TWIST_WINDOW_MS: u64 = 30;
+
+/// Longest frame taken from the gateway, as the teensy has little memory to
+/// spare.  Room for an image of the whole LCD strip of a Plus.
+const MAX_FRAME_LEN: usize = 256 * 1024;
+
+/// What the teensy takes from the gateway.  Images are taken compressed, to
+/// get them across the serial link sooner.
+const CAPABILITIES: Capabilities = Capabilities(
+    Capabilities::LCD_IMAGES.0 | Capabilities::BRIGHTNESS.0 | Capabilities::LZ4_IMAGES.0,
+);
+
+/// Milliseconds since boot, extended past the 49 days the Arduino counter
+/// takes to wrap.
+fn rust_millis() -> impl FnMut() -> u64 {
+    let mut last = 0u32;
+    let mut wraps = 0u64;
+    move || {
+        let now = unsafe { arduino_millis() };
+        if now < last {
+            wraps += 1;
+        }
+        last = now;
+        (wraps << 32) + u64::from(now)
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn run_rust() {
     let usb = ArduinoUSB {};
-    _ = run_teensy(rust_try_read_network, rust_write_network, usb);
+    _ = run_teensy(
+        rust_try_read_network,
+        rust_write_network,
+        usb,
+        rust_millis(),
+    );
 }
 
 #[no_mangle]
@@ -155,13 +197,15 @@ extern "C" {
     fn arduino_free(ptr: *mut u8);
 
     fn arduino_led(on: bool);
+    fn arduino_millis() -> u32;
     fn arduino_sleep_seconds(seconds: u32);
 }
 
 pub fn run_teensy(
-    mut try_read_network: impl FnMut() -> Result<Option<u8>>,
-    mut write_network: impl FnMut(&[u8]) -> Result<()>,
+    try_read_network: impl FnMut() -> Result<Option<u8>>,
+    write_network: impl FnMut(&[u8]) -> Result<()>,
     usb: impl HidDevice,
+    millis: impl FnMut() -> u64,
 ) -> Result<()> {
     // Connect to the device
     let device =
@@ -178,32 +222,15 @@ pub fn run_teensy(
     let pid = 0x0080;
 
     // Send config to companion
+    let kind = device.kind();
     let config = RemoteConfig {
         pid,
         device_id: serial_number,
+        fingerprint: Fingerprint::new(kind.key_count(), &kind.key_image_format()),
     };
-    // Write this to the network
-    frame_write(&Command::Config(config), &mut write_network)?;
-
-    // write_network(
-    //     format!(
-    //         "ADD-DEVICE {}\n",
-    //         companion::DeviceMsg {
-    //             device_id: serial_number,
-    //             product_name: format!("TeensySatellite StreamDeck: {}", kind.to_string()),
-    //             keys_total: kind.key_count(),
-    //             keys_per_row: kind.column_count(),
-    //             resolution: kind
-    //                 .key_image_format()
-    //                 .size
-    //                 .0
-    //                 .try_into()
-    //                 .map_err(|_| anyhow::anyhow!("Cannot convert resolution"))?,
-    //         }
-    //         .device_msg()
-    //     )
-    //     .as_bytes(),
-    // )?;
+    let mut network_sender = NetworkSender { write_network };
+    network_sender.hello(Hello::new(CAPABILITIES))?;
+    network_sender.config(config)?;
 
     // do something with device
     device
@@ -214,95 +241,258 @@ pub fn run_teensy(
         .map_err(|_| anyhow::anyhow!("Could not set brightness"))?;
 
     // loop forever
-    let mut frame_accumulator = FrameAccumulator::default();
-    loop {
-        // Try reading from socket
-        let value = try_read_network()?;
-        match value {
-            None => {}
-            Some(value) => {
-                if let Some(frame) = frame_accumulator.add_char(value) {
-                    //println!("Got frame size: {}", frame.len());
-                    let action: DeviceActions = postcard::from_bytes(frame)
-                        .map_err(|_| anyhow::anyhow!("Cannot generate from bytes"))?;
-                    match action {
-                        DeviceActions::SetButtonImage(b) => {
-                            //println!("Set button image: {:?}", b.button);
-                            device
-                                .write_image(b.button, &b.image)
-                                .map_err(|_| anyhow::anyhow!("Could not write image"))?;
-                        }
-                        DeviceActions::SetLCDImage(_l) => {
-                            //println!("Set LCD image: {:?}", l);
-                        }
-                        DeviceActions::SetBrightness(b) => {
-                            //println!("Set brightness: {:?}", b);
-                            device
-                                .set_brightness(b.brightness)
-                                .map_err(|_| anyhow::anyhow!("Could not set brightness"))?;
-                        }
-                    }
-                    frame_accumulator.clear();
-                }
-            }
+    leaf_traits::message_pump(
+        DeckSender { device: &device },
+        DeckReceiver {
+            device: &device,
+            state: InputState::new(
+                device.kind().key_count().into(),
+                device.kind().encoder_count().into(),
+            ),
+            twists: TwistCoalescer::new(device.kind().encoder_count().into(), TWIST_WINDOW_MS),
+            millis,
+        },
+        network_sender,
+        NetworkReceiver {
+            try_read_network,
+            frames: Decoder::new(MAX_FRAME_LEN),
+        },
+    )
+}
+
+/// The Stream Deck attached to the teensy, as seen by the message pump.
+struct DeckSender<'a, D: HidDevice> {
+    device: &'a elgato_streamdeck_local::StreamDeck<D>,
+}
+
+impl<D: HidDevice> leaf_traits::device::Sender for DeckSender<'_, D> {
+    fn set_brightness(&mut self, brightness: SetBrightness) -> Result<()> {
+        self.device
+            .set_brightness(brightness.brightness)
+            .map_err(|_| anyhow::anyhow!("Could not set brightness"))
+    }
+
+    fn set_button_image(&mut self, image: SetButtonImage) -> Result<()> {
+        // A Pedal has switches but nothing to show images on
+        if !self.device.kind().is_visual() {
+            return Ok(());
         }
+        self.device
+            .write_image(image.button, &image.image)
+            .map_err(|_| anyhow::anyhow!("Could not write image"))
     }
 
-    #[allow(unreachable_code)]
-    Ok(())
+    fn set_lcd_image(&mut self, image: SetLCDImage) -> Result<()> {
+        // Only the Plus has an LCD strip, ignore images for anything else
+        if self.device.kind() != elgato_streamdeck_local::info::Kind::Plus {
+            return Ok(());
+        }
+        // Encoded as JPEG by the image converter of the gateway
+        let rect = ImageRect::from_device_image(image.x_size, image.y_size, image.image);
+        self.device
+            .write_lcd(image.x_offset, 0, &rect)
+            .map_err(|_| anyhow::anyhow!("Could not write LCD image"))
+    }
 }
 
-#[derive(Default)]
-struct FrameAccumulator {
-    buf: Vec<u8>,
-    size: Option<usize>,
+/// Input from the Stream Deck attached to the teensy.
+struct DeckReceiver<'a, D: HidDevice, M> {
+    device: &'a elgato_streamdeck_local::StreamDeck<D>,
+    state: InputState,
+    twists: TwistCoalescer,
+    /// Milliseconds since some fixed point, the clock of the coalescer
+    millis: M,
 }
-impl FrameAccumulator {
-    fn clear(&mut self) {
-        self.buf.clear();
-        self.size = None;
-    }
-    fn add_char(&mut self, c: u8) -> Option<&[u8]> {
-        self.buf.push(c);
-        match self.size {
-            Some(size) => {
-                if self.buf.len() == size {
-                    Some(self.buf.as_slice())
-                } else {
-                    None
-                }
+
+impl<D: HidDevice, M: FnMut() -> u64> leaf_traits::device::Receiver for DeckReceiver<'_, D, M> {
+    fn try_receive(&mut self) -> Result<Option<Command>> {
+        let now = (self.millis)();
+        if let Some(twist) = self.twists.poll(now) {
+            return Ok(Some(Command::EncoderTwist(twist)));
+        }
+        let input = self
+            .device
+            .read_input_poll(true)
+            .map_err(|_| anyhow::anyhow!("Could not read input"))?;
+        Ok(match input {
+            StreamDeckInput::ButtonStateChange(buttons) => {
+                self.state.keys(buttons).map(Command::ButtonChange)
             }
-            None => {
-                if self.buf.len() == 4 {
-                    let size =
-                        u32::from_be_bytes([self.buf[0], self.buf[1], self.buf[2], self.buf[3]]);
-                    if size != 0 {
-                        self.size = Some(size as usize);
-                        self.buf.clear();
-                        None
-                    } else {
-                        Some(&[])
-                    }
-                } else {
-                    None
-                }
+            StreamDeckInput::EncoderStateChange(encoders) => {
+                self.state.encoders(encoders).map(Command::EncoderPress)
+            }
+            StreamDeckInput::EncoderTwist(twist) => {
+                let encoders = twist
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(_, delta)| *delta != 0)
+                    .map(|(index, delta)| (index as u8, delta));
+                self.twists.add(now, encoders).map(Command::EncoderTwist)
+            }
+            StreamDeckInput::TouchScreenPress(x, y) => Some(Command::Touch(Touch {
+                x,
+                y,
+                gesture: TouchGesture::Tap,
+            })),
+            StreamDeckInput::TouchScreenLongPress(x, y) => Some(Command::Touch(Touch {
+                x,
+                y,
+                gesture: TouchGesture::LongPress,
+            })),
+            StreamDeckInput::TouchScreenSwipe((x, y), (to_x, to_y)) => {
+                Some(Command::Touch(Touch {
+                    x,
+                    y,
+                    gesture: TouchGesture::Swipe(to_x, to_y),
+                }))
+            }
+            _ => None,
+        })
+    }
+}
+
+/// Framed postcard messages received from the gateway.
+struct NetworkReceiver<R> {
+    try_read_network: R,
+    frames: Decoder,
+}
+
+impl<R> leaf_traits::companion::Receiver for NetworkReceiver<R>
+where
+    R: FnMut() -> Result<Option<u8>>,
+{
+    fn try_receive(&mut self) -> Result<Option<DeviceActions>> {
+        while let Some(value) = (self.try_read_network)()? {
+            // A frame refused as corrupt or too large is dropped, and the
+            // decoder finds the next one by itself
+            let Ok(Some(frame)) = self.frames.push(value) else {
+                continue;
+            };
+            // The gateway answers the hello, always writing in our version
+            if hello::decode(frame).is_some() {
+                continue;
             }
+            let inner = compress::decompress(frame, MAX_FRAME_LEN)
+                .transpose()
+                .map_err(|_| anyhow::anyhow!("Cannot decompress frame"))?;
+            let frame = inner.as_deref().unwrap_or(frame);
+            let action: DeviceActions = wire::decode_actions(frame)
+                .map_err(|_| anyhow::anyhow!("Cannot generate from bytes"))?
+                .into();
+            return Ok(Some(action));
         }
+        Ok(None)
+    }
+}
+
+/// Framed postcard messages sent to the gateway.
+struct NetworkSender<W> {
+    write_network: W,
+}
+
+impl<W> NetworkSender<W>
+where
+    W: FnMut(&[u8]) -> Result<()>,
+{
+    /// Send the hello the connection starts with.
+    fn hello(&mut self, hello: Hello) -> Result<()> {
+        let frame = hello
+            .encode()
+            .map_err(|_| anyhow::anyhow!("Cannot serialize hello"))?;
+        write_frame(&[&frame], &mut self.write_network)
     }
 }
 
-fn frame_write<D>(data: &D, mut write_network: impl FnMut(&[u8]) -> Result<()>) -> Result<()>
+impl<W> leaf_traits::companion::Sender for NetworkSender<W>
+where
+    W: FnMut(&[u8]) -> Result<()>,
+{
+    fn config(&mut self, config: RemoteConfig) -> Result<()> {
+        frame_write(&Command::Config(config), &mut self.write_network)
+    }
+
+    fn button_change(&mut self, change: ButtonChange) -> Result<()> {
+        frame_write(&Command::ButtonChange(change), &mut self.write_network)
+    }
+
+    fn encoder_twist(&mut self, twist: EncoderTwist) -> Result<()> {
+        frame_write(&Command::EncoderTwist(twist), &mut self.write_network)
+    }
+
+    fn encoder_press(&mut self, press: EncoderPress) -> Result<()> {
+        frame_write(&Command::EncoderPress(press), &mut self.write_network)
+    }
+
+    fn touch(&mut self, touch: Touch) -> Result<()> {
+        frame_write(&Command::Touch(touch), &mut self.write_network)
+    }
+
+    fn power(&mut self, power: PowerState) -> Result<()> {
+        frame_write(&Command::Power(power), &mut self.write_network)
+    }
+
+    /// Far larger than the other commands, up to [StatusImage::MAX_SIDE]
+    /// square, so encoded into a frame sized for the image.
+    fn status_image(&mut self, image: StatusImage) -> Result<()> {
+        let frame = wire::encode(PROTOCOL_VERSION, &Command::StatusImage(image))
+            .map_err(|_| anyhow::anyhow!("Cannot serialize status image"))?;
+        write_frame(&[&frame], &mut self.write_network)
+    }
+}
+
+fn frame_write<D>(data: &D, write_network: impl FnMut(&[u8]) -> Result<()>) -> Result<()>
 where
     D: serde::Serialize,
 {
     let data =
         postcard::to_vec::<_, 128>(data).map_err(|_| anyhow::anyhow!("Cannot serialize data"))?;
-    let size: u32 = data
-        .len()
-        .try_into()
+    write_frame(&[&wire::HEADER, &data], write_network)
+}
+
+/// Write a frame made of parts, with its header in front and checksum after.
+fn write_frame(parts: &[&[u8]], mut write_network: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+    let len = parts.iter().map(|part| part.len()).sum();
+    let size = framing::encode_header(len, MAX_FRAME_LEN)
         .map_err(|_| anyhow::anyhow!("data len too big"))?;
-    let size = size.to_be_bytes();
     write_network(&size)?;
-    write_network(&data)?;
+    for part in parts {
+        write_network(part)?;
+    }
+    write_network(&framing::checksum(parts.iter().copied()))?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn test_largest_status_image_reaches_gateway() {
+        let side = StatusImage::MAX_SIDE;
+        let image = StatusImage {
+            width: side,
+            height: side,
+            rgb: (0..usize::from(side) * usize::from(side) * 3)
+                .map(|at| at as u8)
+                .collect(),
+        };
+        let mut written = Vec::new();
+        NetworkSender {
+            write_network: |bytes: &[u8]| {
+                written.extend_from_slice(bytes);
+                Ok(())
+            },
+        }
+        .status_image(image.clone())
+        .unwrap();
+
+        // Read as the gateway reads leaves
+        let mut decoder = Decoder::detecting(framing::MAX_FRAME_LEN);
+        let frame = decoder.decode(&mut &written[..]).unwrap().unwrap();
+        match wire::decode_command(frame).unwrap() {
+            (PROTOCOL_VERSION, Command::StatusImage(read)) => assert_eq!(read, image),
+            command => panic!("Unexpected command {command:?}"),
+        }
+    }
+}
@@ -7,7 +7,7 @@
 
 use crate::Result;
 use async_trait::async_trait;
-use leaf_comm::{DeviceActions, RemoteConfig, ButtonChange, EncoderTwist};
+use leaf_comm::{DeviceActions, RemoteConfig, ButtonChange, EncoderPress, EncoderTwist, PowerState, ShutdownReason, StatusImage, Touch};
 
 /// Receiver trait receives data from the companion app and
 /// converts it into commands for the device.
@@ -30,4 +30,24 @@ pub trait Sender {
     /// An encoder has been twisted.  The EncoderTwist object has a list of encoders
     /// that have changed.
     async fn encoder_twist(&mut self, twist: EncoderTwist) -> Result<()>;
+    /// An encoder has been pressed or released.  The EncoderPress object has a
+    /// list of encoders that have changed.
+    async fn encoder_press(&mut self, press: EncoderPress) -> Result<()>;
+    /// The touch screen has been touched.
+    async fn touch(&mut self, touch: Touch) -> Result<()>;
+    /// The power of the device has changed.  Companion has no use for it, so
+    /// only links to a gateway pass it on.
+    async fn power(&mut self, power: PowerState) -> Result<()>;
+    /// The status image of the device has changed.  Like power, only links
+    /// to a gateway pass it on.
+    async fn status_image(&mut self, image: StatusImage) -> Result<()>;
+    /// The connection is about to be closed on purpose, and why, so the
+    /// other end can tell it from a crash.  Called just before close, it
+    /// does nothing unless the other end takes the reason.
+    async fn goodbye(&mut self, _reason: ShutdownReason) -> Result<()> {
+        Ok(())
+    }
+    /// Write out anything still pending and close the connection cleanly,
+    /// such as when shutting down.  Nothing is sent afterwards.
+    async fn close(&mut self) -> Result<()>;
 }
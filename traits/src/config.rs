@@ -0,0 +1,155 @@
+//! Configuration files shared by the satellite, the gateway and the leaf.
+//!
+//! Every option on the command line can also be given in a TOML file named
+//! by `--config`, keyed by the long name of its flag.  Options for one
+//! program go in a table named after it, and options outside any table are
+//! for every program reading the file, so a single file can configure the
+//! whole of a deployment:
+//!
+//! ```toml
+//! log_json = true
+//!
+//! [rust_satellite]
+//! companion_host = "10.0.0.5"
+//! companion_port = 16622
+//! brightness_max = 80
+//! idle_after_s = 600
+//! idle_brightness = 5
+//!
+//! [gateway]
+//! companion_host = "10.0.0.5"
+//! companion_port = 16622
+//! font = ["/usr/share/fonts/DejaVuSans.ttf", "/usr/share/fonts/NotoSansCJK.ttc"]
+//!
+//! [leaf]
+//! gateway_host = "10.0.0.2"
+//! gateway_port = 9123
+//! ```
+//!
+//! The file is read as if its options were given on the command line ahead
+//! of those that were, so options on the command line override the file.
+//! Switches set to true are given, those set to false are left out, and
+//! lists give the option once for each of their values, adding to those on
+//! the command line.  Options a program doesn't have are refused, as on the
+//! command line.  Tables not named after a program are left to whoever reads
+//! them, such as the `[device_settings]` the gateway keeps.
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, bail, Context};
+
+use crate::Result;
+
+/// The flag naming the configuration file.
+pub const CONFIG_FLAG: &str = "--config";
+
+/// The arguments of program, with the options of the configuration file
+/// named in them, if any, put ahead of those given.  Parse the result with
+/// clap, letting options override themselves.
+pub fn args(program: &str, args: impl IntoIterator<Item = OsString>) -> Result<Vec<OsString>> {
+    let mut args: Vec<OsString> = args.into_iter().collect();
+    let Some(path) = config_path(&args) else {
+        return Ok(args);
+    };
+    let text = std::fs::read_to_string(&path)
+        .with_context(|| format!("Can't read config {}", path.display()))?;
+    let options =
+        from_toml(program, &text).with_context(|| format!("Bad config {}", path.display()))?;
+    let rest = args.split_off(args.len().min(1));
+    Ok(args.into_iter().chain(options).chain(rest).collect())
+}
+
+/// The configuration file named in args.
+fn config_path(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == CONFIG_FLAG {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg
+            .strip_prefix(CONFIG_FLAG)
+            .and_then(|a| a.strip_prefix('='))
+        {
+            return Some(path.into());
+        }
+    }
+    None
+}
+
+/// The options the configuration in text gives program, as arguments.
+fn from_toml(program: &str, text: &str) -> Result<Vec<OsString>> {
+    let table: toml::Table = toml::from_str(text)?;
+    let mut options = Vec::new();
+    for (key, value) in &table {
+        if !value.is_table() {
+            push_option(key, value, &mut options)?;
+        }
+    }
+    if let Some(section) = table.get(program) {
+        let section = section
+            .as_table()
+            .ok_or_else(|| anyhow!("[{program}] isn't a table"))?;
+        for (key, value) in section {
+            push_option(key, value, &mut options)?;
+        }
+    }
+    Ok(options)
+}
+
+/// Add the arguments giving option key the value to options.
+fn push_option(key: &str, value: &toml::Value, options: &mut Vec<OsString>) -> Result<()> {
+    let flag = format!("--{}", key.replace('_', "-"));
+    match value {
+        toml::Value::Boolean(true) => options.push(flag.into()),
+        toml::Value::Boolean(false) => {}
+        toml::Value::String(value) => options.push(format!("{flag}={value}").into()),
+        toml::Value::Integer(value) => options.push(format!("{flag}={value}").into()),
+        toml::Value::Float(value) => options.push(format!("{flag}={value}").into()),
+        toml::Value::Array(values) => {
+            for value in values {
+                if value.is_array() || value.is_table() {
+                    bail!("{key} can only list plain values");
+                }
+                push_option(key, value, options)?;
+            }
+        }
+        toml::Value::Datetime(_) | toml::Value::Table(_) => {
+            bail!("{key} isn't a string, number, switch or list")
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_program_options_ahead_of_given() {
+        let text = "log_json = true\nprobe = false\n\
+                    [gateway]\ncompanion_port = 16622\nfont = [\"a.ttf\", \"b.ttf\"]\n\
+                    [leaf]\ngateway_port = 9123\n";
+        assert_eq!(
+            from_toml("gateway", text).unwrap(),
+            [
+                "--log-json",
+                "--companion-port=16622",
+                "--font=a.ttf",
+                "--font=b.ttf"
+            ]
+        );
+        assert_eq!(
+            from_toml("leaf", text).unwrap(),
+            ["--log-json", "--gateway-port=9123"]
+        );
+        assert!(from_toml("leaf", "[leaf]\nnested = [[1]]").is_err());
+
+        let given = ["gateway", "--config=missing.toml", "-p", "1"].map(OsString::from);
+        assert_eq!(config_path(&given), Some("missing.toml".into()));
+        assert!(args("gateway", given).is_err());
+        let given = ["gateway", "-p", "1"].map(OsString::from);
+        assert_eq!(args("gateway", given.clone()).unwrap(), given);
+    }
+}
@@ -1,9 +1,10 @@
 use crate::Result;
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 
 // make Command, SetBrightness, SetButtonImage, and SetLCDImage available
 // for other crates to use.
-pub use leaf_comm::{Command, RemoteConfig,DeviceActions,SetBrightness, SetButtonImage, SetLCDImage};
+pub use leaf_comm::{Command, Fingerprint, RemoteConfig,DeviceActions,SetBrightness, BorrowedDeviceActions, SetButtonImage, SetLCDImage, ButtonChange, EncoderPress, EncoderTwist, Touch, TouchGesture, PowerSource, PowerState, ShutdownReason, StatusImage};
 
 extern crate alloc;
 
@@ -23,4 +24,273 @@ pub trait Sender {
     async fn set_button_image(&mut self, image: SetButtonImage) -> Result<()>;
     /// Set the image of the LCD screen.
     async fn set_lcd_image(&mut self, image: SetLCDImage) -> Result<()>;
-}
\ No newline at end of file
+    /// Blank every key, such as when shutting down.  Devices that can't
+    /// blank themselves do nothing.
+    async fn clear(&mut self) -> Result<()>;
+    /// Show the button images set since the last commit, for devices that
+    /// hold them until told so a page switch appears all at once.  Called
+    /// whenever no more images are ready to be set.  Devices that show
+    /// images as they are set do nothing.
+    async fn commit(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Blank every key and the LCD because what they show is stale: Companion
+    /// locked or blanked the device, the connection to Companion was lost,
+    /// or the satellite is shutting down.  Images held for a commit are
+    /// dropped too.  The device stays blank until images are set again.
+    /// Blanks as [clear](Self::clear) does unless a device knows better.
+    async fn clear_all(&mut self) -> Result<()> {
+        self.clear().await
+    }
+}
+
+/// Physical orientation of a device, applied to the images shown on it and
+/// through a [KeyTransform] to the numbering of its keys.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Orientation {
+    /// Mounted the way the manufacturer intended
+    #[default]
+    Normal,
+    /// Rotated 90 degrees clockwise
+    Rotated90,
+    /// Upside down
+    Rotated180,
+    /// Rotated 90 degrees counter-clockwise
+    Rotated270,
+}
+
+impl std::str::FromStr for Orientation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "0" => Ok(Orientation::Normal),
+            "90" => Ok(Orientation::Rotated90),
+            "180" => Ok(Orientation::Rotated180),
+            "270" => Ok(Orientation::Rotated270),
+            _ => anyhow::bail!("Orientation must be one of 0, 90, 180, 270, got {s}"),
+        }
+    }
+}
+
+/// Keys of a device mounted in an orientation, mapped between the physical
+/// keys of the device and how Companion numbers them, which is in reading
+/// order as the user sees the keys.  A device rotated a quarter turn shows
+/// its rows as columns, so Companion's grid is read into it row by row.
+/// Keys past the grid, such as those of an LCD strip, are left alone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyTransform {
+    /// How the device is mounted
+    pub orientation: Orientation,
+    /// Rows of keys of the device
+    pub rows: u8,
+    /// Keys in each row of the device
+    pub columns: u8,
+}
+
+impl KeyTransform {
+    /// The keys of a device of rows and columns mounted in orientation.
+    pub fn new(orientation: Orientation, rows: u8, columns: u8) -> Self {
+        Self {
+            orientation,
+            rows,
+            columns,
+        }
+    }
+
+    /// Whether key is one of the grid of keys.
+    fn in_grid(self, key: u8) -> bool {
+        u16::from(key) < u16::from(self.rows) * u16::from(self.columns)
+    }
+
+    /// The physical key that shows key as Companion numbers it.
+    pub fn to_device(self, key: u8) -> u8 {
+        if !self.in_grid(key) {
+            return key;
+        }
+        let (rows, columns) = (self.rows, self.columns);
+        // Row and column as the user sees them, rows long
+        let (seen_row, seen_column) = (key / rows, key % rows);
+        match self.orientation {
+            Orientation::Normal => key,
+            Orientation::Rotated90 => (rows - 1 - seen_column) * columns + seen_row,
+            Orientation::Rotated180 => rows * columns - 1 - key,
+            Orientation::Rotated270 => seen_column * columns + (columns - 1 - seen_row),
+        }
+    }
+
+    /// The key as Companion numbers it that physical key shows.
+    pub fn to_companion(self, key: u8) -> u8 {
+        if !self.in_grid(key) {
+            return key;
+        }
+        let (rows, columns) = (self.rows, self.columns);
+        let (row, column) = (key / columns, key % columns);
+        match self.orientation {
+            Orientation::Normal => key,
+            Orientation::Rotated90 => column * rows + (rows - 1 - row),
+            Orientation::Rotated180 => rows * columns - 1 - key,
+            Orientation::Rotated270 => (columns - 1 - column) * rows + row,
+        }
+    }
+}
+
+/// A group of keys that share an update policy, for example a row of status
+/// keys that should never hog the link from the control keys.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Zone {
+    /// Name of the zone, for diagnostics
+    pub name: String,
+    /// The keys in the zone
+    pub keys: Vec<u8>,
+    /// Maximum number of images per second written to keys in this zone
+    pub fps_cap: Option<f32>,
+    /// Only keep the latest pending image for a key, dropping older ones
+    #[serde(default)]
+    pub coalesce: bool,
+    /// Brightness (0-100) the images of this zone are scaled to
+    pub brightness: Option<u8>,
+}
+
+/// Keys that keep pressing themselves while held, for Companion actions like
+/// nudges that act once per press.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct KeyRepeat {
+    /// The Companion keys that repeat
+    pub keys: Vec<u8>,
+    /// Milliseconds a key is held before it starts repeating
+    pub delay_ms: u32,
+    /// Milliseconds between repeats
+    pub interval_ms: u32,
+}
+
+/// How the 0-100 brightness Companion asks for is mapped onto a device, for
+/// devices that are too bright or too dim at the same value as the others.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct BrightnessPolicy {
+    /// Exponent of the curve brightness goes through, above 1 dimming the
+    /// lower values most
+    pub gamma: f32,
+    /// Added to the brightness after the curve
+    pub offset: i8,
+    /// Lowest brightness the device is set to
+    pub min: u8,
+    /// Highest brightness the device is set to
+    pub max: u8,
+}
+
+impl Default for BrightnessPolicy {
+    /// Brightness passed through unchanged.
+    fn default() -> Self {
+        Self {
+            gamma: 1.0,
+            offset: 0,
+            min: 0,
+            max: 100,
+        }
+    }
+}
+
+impl BrightnessPolicy {
+    /// The brightness to set the device to when Companion asks for
+    /// brightness.
+    pub fn apply(&self, brightness: u8) -> u8 {
+        let level = f32::from(brightness.min(100)) / 100.0;
+        let curved = 100.0 * level.powf(self.gamma) + f32::from(self.offset);
+        let max = self.max.min(100);
+        (curved.round().clamp(0.0, 100.0) as u8).clamp(self.min.min(max), max)
+    }
+}
+
+/// Settings of a device that can be changed while it is running.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct DeviceSettings {
+    /// Brightness applied when the device connects or when this changes.
+    /// Companion may still change the brightness afterwards.
+    pub brightness: Option<u8>,
+    /// Physical orientation of the device
+    #[serde(default)]
+    pub orientation: Orientation,
+    /// Maximum number of images per second written to the device
+    pub fps_cap: Option<f32>,
+    /// Only keep the latest pending image for a key while the device is
+    /// backed up, so an animation skips frames rather than falling behind.
+    /// Keys in a zone follow the zone instead.
+    #[serde(default)]
+    pub coalesce: bool,
+    /// Groups of keys with their own update policy.  Keys that are not in a
+    /// zone use the device wide policy.
+    #[serde(default)]
+    pub zones: Vec<Zone>,
+    /// Encoders acting as faders from 0 to 100 rather than sending relative
+    /// steps
+    #[serde(default)]
+    pub faders: Vec<u8>,
+    /// Keys repeating while held
+    #[serde(default)]
+    pub key_repeat: Option<KeyRepeat>,
+    /// Milliseconds a long press on the touch strip holds its key down, long
+    /// enough for the long press actions of Companion.  Released at once if
+    /// not set.
+    #[serde(default)]
+    pub long_press_ms: Option<u32>,
+    /// How brightness is mapped onto the device
+    #[serde(default)]
+    pub brightness_policy: BrightnessPolicy,
+    /// Label every key image with the index of its key, to debug how keys
+    /// are mapped and rotated
+    #[serde(default)]
+    pub key_overlay: bool,
+}
+
+impl DeviceSettings {
+    /// The index and definition of the zone a key belongs to, if any.
+    pub fn zone_of(&self, key: u8) -> Option<(usize, &Zone)> {
+        self.zones
+            .iter()
+            .enumerate()
+            .find(|(_, zone)| zone.keys.contains(&key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_brightness_mapped_through_policy() {
+        let apply = |policy: BrightnessPolicy, levels: [u8; 4]| levels.map(|b| policy.apply(b));
+        let levels = [0, 20, 50, 100];
+        assert_eq!(apply(BrightnessPolicy::default(), levels), levels);
+
+        // Curved, lower levels dimmed most
+        let policy = BrightnessPolicy {
+            gamma: 2.0,
+            ..Default::default()
+        };
+        assert_eq!(apply(policy, levels), [0, 4, 25, 100]);
+
+        // Shifted, then kept in range
+        let policy = BrightnessPolicy {
+            offset: -10,
+            min: 5,
+            max: 80,
+            ..Default::default()
+        };
+        assert_eq!(apply(policy, levels), [5, 10, 40, 80]);
+        // More than 100 is taken as 100, and a minimum above the maximum
+        // as the maximum
+        let policy = BrightnessPolicy {
+            min: 90,
+            max: 60,
+            ..Default::default()
+        };
+        assert_eq!(apply(policy, [0, 50, 100, 200]), [60, 60, 60, 60]);
+        assert_eq!(BrightnessPolicy::default().apply(200), 100);
+
+        // What isn't set in a policy passes brightness through
+        let policy: BrightnessPolicy = toml::from_str("gamma = 2.0").unwrap();
+        assert_eq!((policy.offset, policy.min, policy.max), (0, 0, 100));
+    }
+}
@@ -14,5 +14,8 @@ pub use async_trait::async_trait;
 /// export the companion interface
 pub mod companion;
 
+/// export the configuration files of the programs
+pub mod config;
+
 /// export the device interface
 pub mod device;